@@ -0,0 +1,21 @@
+//! The kernel modules that do not touch the hardware, compiled for the host.
+//!
+//! The files are included from the kernel tree as they are, so they must keep
+//! to `core` and stay free of inline assembly and `crate::` paths. The kernel's
+//! library features they use are enabled here as well.
+
+#![feature(step_trait)]
+
+// `ENTRY_COUNT` is only shared with `paging` in the kernel.
+#[allow(dead_code)]
+#[path = "../../src/memory/addr.rs"]
+pub mod addr;
+
+#[path = "../../src/tables/rflags.rs"]
+pub mod rflags;
+
+#[path = "../../src/tables/selectors.rs"]
+pub mod selectors;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,387 @@
+use crate::addr::{
+    canonical_range, canonicalize, huge_page_offset, is_canonical, Page, PageOffset, PageRange, PageRangeInclusive, PageTableIndex, PageTableLevel, PhysAddr,
+    PhysFrame, Size1GiB, Size2MiB, Size4KiB, VirtAddr, ENTRY_COUNT, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K,
+};
+use crate::rflags::RFlags;
+use crate::selectors::{PrivilegeLevel, SegmentSelector, TableIndicator};
+
+#[test]
+fn page_table_index_bounds() {
+    assert_eq!(u16::from(PageTableIndex::new(0)), 0);
+    assert_eq!(usize::from(PageTableIndex::new(ENTRY_COUNT as u16 - 1)), 511);
+    assert_eq!(u64::from(PageTableIndex::new_truncate(513)), 1);
+    assert_eq!(u32::from(PageTableIndex::new_truncate(0xFFFF)), 511);
+}
+
+#[test]
+#[should_panic]
+fn page_table_index_out_of_range() {
+    PageTableIndex::new(512);
+}
+
+#[test]
+fn page_offset_bounds() {
+    assert_eq!(u16::from(PageOffset::new(4095)), 4095);
+    assert_eq!(u64::from(PageOffset::new_truncate(4096)), 0);
+    assert_eq!(usize::from(PageOffset::new_truncate(0x1234)), 0x234);
+}
+
+#[test]
+#[should_panic]
+fn page_offset_out_of_range() {
+    PageOffset::new(4096);
+}
+
+#[test]
+fn virt_addr_indices() {
+    let addr: u64 = 0xFFFF_8123_4567_89AB;
+    assert_eq!(u16::from(addr.page_offset()), 0x9AB);
+    assert_eq!(u16::from(addr.p1_index()), (addr >> 12) as u16 & 0x1FF);
+    assert_eq!(u16::from(addr.p2_index()), (addr >> 21) as u16 & 0x1FF);
+    assert_eq!(u16::from(addr.p3_index()), (addr >> 30) as u16 & 0x1FF);
+    assert_eq!(u16::from(addr.p4_index()), 258);
+    assert_eq!(addr.page_table_index(PageTableLevel::Four), addr.p4_index());
+    assert_eq!(addr.page_table_index(PageTableLevel::One), addr.p1_index());
+}
+
+#[test]
+fn virt_addr_sign_extension() {
+    assert_eq!(u64::new_virt_truncate(0x0000_7FFF_FFFF_FFFF), 0x0000_7FFF_FFFF_FFFF);
+    assert_eq!(u64::new_virt_truncate(0x0000_8000_0000_0000), 0xFFFF_8000_0000_0000);
+    assert_eq!(u64::new_virt_truncate(0x1234_8000_0000_0000), 0xFFFF_8000_0000_0000);
+}
+
+#[test]
+fn canonical_boundaries() {
+    assert!(is_canonical(0));
+    assert!(is_canonical(0x0000_7FFF_FFFF_FFFF));
+    assert!(!is_canonical(0x0000_8000_0000_0000));
+    assert!(!is_canonical(0x7FFF_FFFF_FFFF_FFFF));
+    assert!(!is_canonical(0x8000_0000_0000_0000));
+    assert!(!is_canonical(0xFFFF_7FFF_FFFF_FFFF));
+    assert!(is_canonical(0xFFFF_8000_0000_0000));
+    assert!(is_canonical(u64::MAX));
+
+    assert_eq!(canonicalize(0x0000_8000_0000_0000), 0xFFFF_8000_0000_0000);
+    assert_eq!(canonicalize(0x7FFF_FFFF_FFFF_FFFF), 0xFFFF_FFFF_FFFF_FFFF);
+    assert_eq!(canonicalize(0x8000_0000_0000_0000), 0);
+
+    let [lower, higher] = canonical_range();
+    for addr in [0, 0x0000_7FFF_FFFF_FFFF, 0x0000_8000_0000_0000, 0xFFFF_7FFF_FFFF_FFFF, 0xFFFF_8000_0000_0000, u64::MAX] {
+        assert_eq!(lower.contains(&addr) || higher.contains(&addr), is_canonical(addr), "{:#x}", addr);
+    }
+}
+
+#[test]
+fn phys_addr_alignment() {
+    assert_eq!(0x1234u64.align_down(0x1000), 0x1000);
+    assert_eq!(0x1234u64.align_up(0x1000), 0x2000);
+    assert_eq!(0x2000u64.align_up(0x1000), 0x2000);
+    assert_eq!(0u64.align_up(0x20_0000), 0);
+    assert!(0x4000u64.is_aligned(0x1000));
+    assert!(!0x4010u64.is_aligned(0x1000));
+    assert_eq!(u64::new_truncate(0xFFF0_0000_0000_1000), 0x1000);
+}
+
+#[test]
+#[should_panic]
+fn phys_addr_align_up_overflow() {
+    u64::MAX.align_up(0x1000);
+}
+
+#[test]
+#[should_panic]
+fn phys_addr_align_not_power_of_two() {
+    0x1234u64.align_down(0x300);
+}
+
+#[test]
+fn page_construction() {
+    assert!(Page::<Size4KiB>::from_start_address(0x1001).is_err());
+    assert!(Page::<Size2MiB>::from_start_address_2mib(0x1000).is_err());
+    assert!(Page::<Size1GiB>::from_start_address_1gib(0x4000_0000).is_ok());
+
+    let page = Page::<Size4KiB>::containing_address(0x5FFF);
+    assert_eq!(page.start_address(), 0x5000);
+    assert_eq!((page + 3).start_address(), 0x8000);
+    assert_eq!((page - 5).start_address(), 0);
+    assert_eq!((page + 3) - page, 3);
+
+    let indices = Page::<Size4KiB>::from_page_table_indices(
+        PageTableIndex::new(256), PageTableIndex::new(1), PageTableIndex::new(2), PageTableIndex::new(3));
+    assert_eq!(indices.start_address(), 0xFFFF_8000_4040_3000);
+    assert_eq!(indices.p4_index(), PageTableIndex::new(256));
+    assert_eq!(indices.p1_index(), PageTableIndex::new(3));
+}
+
+/// Canonical addresses from a fixed xorshift sequence, both halves.
+fn sample_addresses() -> impl Iterator<Item = u64> {
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    (0..10_000).map(move |_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        canonicalize(state)
+    })
+}
+
+#[test]
+fn huge_pages_rebuild_from_indices() {
+    for addr in sample_addresses() {
+        let page = Page::<Size2MiB>::containing_address(addr);
+        assert_eq!(page.start_address() & (PAGE_SIZE_2M - 1), 0);
+        let rebuilt = Page::<Size2MiB>::from_page_table_indices_2mib(page.p4_index(), page.p3_index(), page.p2_index());
+        assert_eq!(rebuilt, page, "{:#x}", addr);
+        assert_eq!(page.start_address() + huge_page_offset(addr, PAGE_SIZE_2M), addr);
+
+        let page = Page::<Size1GiB>::containing_address(addr);
+        assert_eq!(page.start_address() & (PAGE_SIZE_1G - 1), 0);
+        let rebuilt = Page::<Size1GiB>::from_page_table_indices_1gib(page.p4_index(), page.p3_index());
+        assert_eq!(rebuilt, page, "{:#x}", addr);
+        assert_eq!(page.start_address() + huge_page_offset(addr, PAGE_SIZE_1G), addr);
+    }
+}
+
+#[test]
+fn huge_page_offsets() {
+    assert_eq!(huge_page_offset(0x20_1234, PAGE_SIZE_2M), 0x1234);
+    assert_eq!(huge_page_offset(0x4123_4567, PAGE_SIZE_1G), 0x0123_4567);
+    assert_eq!(huge_page_offset(0xFFFF_8000_0020_0000, PAGE_SIZE_2M), 0);
+    assert_eq!(huge_page_offset(0x5FFF, PAGE_SIZE_4K), 0xFFF);
+    assert!(Page::<Size2MiB>::from_start_address(0x20_0000).is_ok());
+    assert!(Page::<Size1GiB>::from_start_address(0x20_0000).is_err());
+}
+
+#[test]
+fn page_ranges() {
+    let start = Page::<Size4KiB>::containing_address(0x1000);
+    let end = Page::<Size4KiB>::containing_address(0x5000);
+
+    let range = Page::range(start, end);
+    assert_eq!(range.len(), 4);
+    assert_eq!(range.size(), 0x4000);
+    assert_eq!(range.last(), Some(end - 1));
+    assert!(Page::range(end, start).is_empty());
+
+    let inclusive: PageRangeInclusive = Page::range_inclusive(start, end);
+    assert_eq!(inclusive.len(), 5);
+    assert_eq!(inclusive.count(), 5);
+    assert_eq!(Page::range_inclusive(start, start).len(), 1);
+
+    let huge = PageRange::<Size2MiB> {
+        start: Page::containing_address(0),
+        end: Page::containing_address(0x40_0000),
+    };
+    assert_eq!(huge.as_4kib_page_range().len(), 1024);
+
+    let frames = PhysFrame::<Size4KiB>::range(PhysFrame::containing_address(0), PhysFrame::containing_address(0x3000));
+    assert_eq!(frames.map(PhysFrame::start_address).collect::<Vec<_>>(), [0, 0x1000, 0x2000]);
+}
+
+#[test]
+fn range_sizes_overflow() {
+    // Every 4KiB page of the address space: 2^52 pages, 2^64 bytes.
+    let all = Page::<Size4KiB>::range_inclusive(Page::containing_address(0), Page::containing_address(u64::MAX));
+    assert_eq!(all.len(), 1 << 52);
+    assert_eq!(all.checked_size(), None);
+    let frames = PhysFrame::<Size4KiB>::range_inclusive(PhysFrame::containing_address(0), PhysFrame::containing_address(u64::MAX));
+    assert_eq!(frames.checked_size(), None);
+
+    let almost_all = Page::<Size4KiB>::range(Page::containing_address(0), Page::containing_address(u64::MAX));
+    assert_eq!(almost_all.checked_size(), Some(u64::MAX - 0xFFF));
+    assert_eq!(almost_all.size(), u64::MAX - 0xFFF);
+    let giant = Page::<Size1GiB>::range_inclusive(Page::containing_address(0), Page::containing_address(0x4000_0000));
+    assert_eq!(giant.checked_size(), Some(0x8000_0000));
+}
+
+#[test]
+#[should_panic]
+fn range_size_overflow_panics_in_debug() {
+    let all = PhysFrame::<Size4KiB>::range_inclusive(PhysFrame::containing_address(0), PhysFrame::containing_address(u64::MAX));
+    all.size();
+}
+
+#[test]
+fn ranges_from_count() {
+    let start = Page::<Size4KiB>::containing_address(0x20_0000);
+    assert_eq!(Page::range_from_count(start, 0).len(), 0);
+    assert!(Page::range_from_count(start, 0).is_empty());
+    assert_eq!(Page::range_from_count(start, 1).len(), 1);
+    assert_eq!(Page::range_from_count(start, 512).len(), 512);
+    assert_eq!(Page::range_from_count(start, 512).end.start_address(), 0x40_0000);
+
+    assert_eq!(Page::range_inclusive_from_count(start, 1).len(), 1);
+    assert_eq!(Page::range_inclusive_from_count(start, 512).len(), 512);
+    assert_eq!(Page::range_inclusive_from_count(start, 512).end.start_address(), 0x3F_F000);
+
+    let frame = PhysFrame::<Size2MiB>::containing_address(0);
+    assert_eq!(PhysFrame::range_from_count(frame, 0).len(), 0);
+    assert_eq!(PhysFrame::range_from_count(frame, 1).len(), 1);
+    assert_eq!(PhysFrame::range_from_count(frame, 512).len(), 512);
+}
+
+#[test]
+fn range_syntax() {
+    let p0 = Page::<Size4KiB>::containing_address(0x1000);
+    let p4 = p0 + 4;
+    assert_eq!((p0..p4).count(), 4);
+    assert_eq!((p0..=p4).count(), 5);
+    assert_eq!((p4..p0).count(), 0);
+    assert_eq!((p0..p4).next_back(), Some(p0 + 3));
+
+    let f0 = PhysFrame::<Size2MiB>::containing_address(0);
+    assert_eq!((f0..f0 + 3).map(PhysFrame::start_address).collect::<Vec<_>>(), [0, 0x20_0000, 0x40_0000]);
+}
+
+#[test]
+fn range_syntax_across_the_gap() {
+    let start = Page::<Size4KiB>::containing_address(0x0000_7FFF_FFFF_E000);
+    let end = Page::<Size4KiB>::containing_address(0xFFFF_8000_0000_1000);
+    let pages = (start..=end).map(Page::start_address).collect::<Vec<_>>();
+    assert_eq!(pages, [0x0000_7FFF_FFFF_E000, 0x0000_7FFF_FFFF_F000, 0xFFFF_8000_0000_0000, 0xFFFF_8000_0000_1000]);
+    assert_eq!((start..end).count(), 3);
+    assert_eq!((start..end).rev().map(Page::start_address).nth(1), Some(0x0000_7FFF_FFFF_F000));
+
+    let last = Page::<Size4KiB>::containing_address(0xFFFF_FFFF_FFFF_F000);
+    assert_eq!((last..=last).count(), 1);
+}
+
+#[test]
+#[should_panic]
+fn inclusive_range_from_zero_count() {
+    Page::<Size4KiB>::range_inclusive_from_count(Page::containing_address(0), 0);
+}
+
+#[test]
+fn page_table_level_alignments() {
+    assert_eq!(PageTableLevel::One.entry_address_space_alignment(), 0x1000);
+    assert_eq!(PageTableLevel::One.table_address_space_alignment(), 0x20_0000);
+    assert_eq!(PageTableLevel::Four.entry_address_space_alignment(), 1 << 39);
+    assert_eq!(PageTableLevel::Four.next_higher_level(), None);
+    assert_eq!(PageTableLevel::Two.next_lower_level(), Some(PageTableLevel::One));
+}
+
+#[test]
+fn forward_checked_below_the_gap() {
+    assert_eq!(u64::forward_checked_u64(0x1000, 0x1000), Some(0x2000));
+    assert_eq!(u64::forward_checked_u64(0x0000_7FFF_FFFF_E000, 0x1000), Some(0x0000_7FFF_FFFF_F000));
+    assert_eq!(u64::forward_checked_impl(0x1000, 0), Some(0x1000));
+}
+
+#[test]
+fn forward_checked_overflow() {
+    assert_eq!(u64::forward_checked_u64(0, 0x1_0000_0000_0001), None);
+    assert_eq!(u64::forward_checked_u64(0xFFFF_FFFF_FFFF_F000, 0x1000), None);
+}
+
+#[test]
+fn forward_checked_across_the_gap() {
+    // The first address past the lower half is the start of the higher half.
+    assert_eq!(u64::forward_checked_u64(0x0000_7FFF_FFFF_F000, 0x1000), Some(0xFFFF_8000_0000_0000));
+    assert_eq!(u64::forward_checked_u64(0x0000_7FFF_FFFF_F000, 0x2000), Some(0xFFFF_8000_0000_1000));
+    assert_eq!(u64::forward_checked_u64(0x0000_7FFF_FFFF_FFFF, 1), Some(0xFFFF_8000_0000_0000));
+    for count in [1, 0x1000, 0x10_0000, 0x7FFF_FFFF_FFFF] {
+        let addr = u64::forward_checked_u64(0x0000_7FFF_FFFF_F000, count).unwrap();
+        assert!(is_canonical(addr), "{:#x} is not canonical", addr);
+    }
+}
+
+#[test]
+fn forward_checked_past_the_higher_half() {
+    // Stepping over both halves from the lower one cannot land on a valid address.
+    assert_eq!(u64::forward_checked_u64(0, 0x1_0000_0000_0000), None);
+    assert_eq!(u64::forward_checked_u64(0x0000_7FFF_FFFF_F000, 0x1_0000_0000_0000), None);
+    assert_eq!(u64::forward_checked_u64(0xFFFF_FFFF_FFFF_F000, 0x1000), None);
+}
+
+#[test]
+fn forward_checked_just_below_the_gap() {
+    assert_eq!(u64::forward_checked_u64(0x0000_7FFF_FFFF_FFFE, 1), Some(0x0000_7FFF_FFFF_FFFF));
+    assert_eq!(u64::forward_checked_u64(0x0000_7FFF_FFFF_FFFF, 0), Some(0x0000_7FFF_FFFF_FFFF));
+    assert_eq!(u64::forward_checked_u64(0x0000_7FFF_FFFF_FFFF, 2), Some(0xFFFF_8000_0000_0001));
+    // From the bottom, the whole lower half and one more.
+    assert_eq!(u64::forward_checked_u64(0, 0x0000_7FFF_FFFF_FFFF), Some(0x0000_7FFF_FFFF_FFFF));
+    assert_eq!(u64::forward_checked_u64(0, 0x0000_8000_0000_0000), Some(0xFFFF_8000_0000_0000));
+}
+
+#[test]
+fn forward_checked_just_above_the_gap() {
+    assert_eq!(u64::forward_checked_u64(0xFFFF_8000_0000_0000, 0), Some(0xFFFF_8000_0000_0000));
+    assert_eq!(u64::forward_checked_u64(0xFFFF_8000_0000_0000, 1), Some(0xFFFF_8000_0000_0001));
+    assert_eq!(u64::forward_checked_u64(0xFFFF_8000_0000_0000, 0x7FFF_FFFF_FFFF), Some(u64::MAX));
+    assert_eq!(u64::forward_checked_u64(0xFFFF_8000_0000_0000, 0x8000_0000_0000), None);
+}
+
+#[test]
+fn forward_checked_near_the_top() {
+    assert_eq!(u64::forward_checked_u64(u64::MAX, 0), Some(u64::MAX));
+    assert_eq!(u64::forward_checked_u64(u64::MAX, 1), None);
+    assert_eq!(u64::forward_checked_u64(u64::MAX - 1, 1), Some(u64::MAX));
+    assert_eq!(u64::forward_checked_u64(0xFFFF_FFFF_FFFF_F000, u64::MAX), None);
+    assert_eq!(u64::forward_checked_u64(0x1000, u64::MAX), None);
+}
+
+#[test]
+fn forward_checked_zero_count() {
+    for addr in [0, 0x1000, 0x0000_7FFF_FFFF_FFFF, 0xFFFF_8000_0000_0000, u64::MAX] {
+        assert_eq!(u64::forward_checked_u64(addr, 0), Some(addr));
+        assert_eq!(u64::forward_checked_impl(addr, 0), Some(addr));
+    }
+}
+
+#[test]
+fn forward_checked_from_non_canonical() {
+    // Inside the gap, even with nothing to add.
+    for addr in [0x0000_8000_0000_0000, 0x0001_0000_0000_0000, 0x00FF_0000_0000_0000, 0xFFFF_7FFF_FFFF_FFFF] {
+        assert_eq!(u64::forward_checked_u64(addr, 0), None, "{:#x}", addr);
+        assert_eq!(u64::forward_checked_u64(addr, 0x1000), None, "{:#x}", addr);
+    }
+}
+
+#[test]
+fn rflags_bits() {
+    // Bit 1 is reserved and always set in the register.
+    let flags = RFlags::from_bits_truncate(0x246);
+    assert_eq!(flags, RFlags::INTERRUPT_FLAG | RFlags::ZERO_FLAG | RFlags::PARITY_FLAG);
+    assert_eq!(RFlags::from_bits_retain(0x3002).bits() & !RFlags::all().bits(), 0x2);
+    assert!(RFlags::from_bits(0x2).is_none());
+    assert_eq!((RFlags::IOPL_HIGH | RFlags::IOPL_LOW).bits() >> 12, 3);
+}
+
+#[test]
+fn rflags_summary() {
+    let summary = |bits| RFlags::from_bits_retain(bits).summary().to_string();
+    assert_eq!(summary(0x2), "[]");
+    assert_eq!(summary(0x247), "[IF CF ZF]");
+    // Parity, trap and direction are left out.
+    assert_eq!(summary(0x546), "[ZF]");
+    assert_eq!(summary(0x3082), "[SF IOPL=3]");
+    assert_eq!(summary(0x1A83), "[IF CF SF OF IOPL=1]");
+    assert_eq!(RFlags::from_bits_retain(0x2202).iopl(), 2);
+}
+
+#[test]
+fn segment_selector_packing() {
+    assert_eq!(SegmentSelector::new(1, 0, 0), SegmentSelector(0x08));
+    assert_eq!(SegmentSelector::new(2, 0, 3), SegmentSelector(0x13));
+    assert_eq!(SegmentSelector::new(5, 1, 3), SegmentSelector(0x2F));
+}
+
+#[test]
+#[should_panic]
+fn segment_selector_rejects_rpl() {
+    SegmentSelector::new(1, 0, 4);
+}
+
+#[test]
+fn segment_selector_formatting() {
+    let user_data = SegmentSelector(0x13);
+    assert_eq!(user_data.index(), 2);
+    assert_eq!(user_data.rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(user_data.ti(), TableIndicator::GDT);
+    assert_eq!(format!("{:?}", user_data), "SegmentSelector { index: 2, rpl: Ring3, ti: GDT }");
+    assert_eq!(format!("{}", user_data), "0x13 (GDT index 2, RPL 3)");
+
+    assert_eq!(format!("{:?}", SegmentSelector(0x08)), "SegmentSelector { index: 1, rpl: Ring0, ti: GDT }");
+    assert_eq!(format!("{}", SegmentSelector::new(5, 1, 1)), "0x2d (LDT index 5, RPL 1)");
+}
@@ -0,0 +1,673 @@
+#!/usr/bin/env python3
+"""Builds the filesystem images the kernel tests read with include_bytes!.
+
+Usage: scripts/mkfixtures.py [output directory, default fixtures/]
+
+The FAT, USTAR and ISO 9660 images are written byte by byte rather than with
+mkfs, mtools and mkisofs, so that they come out the same on every machine and
+can lay files out on purpose, like fragmenting one. The ext2 images need
+mke2fs, debugfs and e2fsck from e2fsprogs, and the user programs of user/ as
+and ld from binutils. The output is committed; run this again after changing
+it.
+"""
+
+import io
+import os
+import struct
+import subprocess
+import sys
+import tarfile
+import tempfile
+
+SECTOR = 512
+
+
+# FAT32: 512 byte sectors, one sector per cluster, clusters 2 to 121.
+
+FAT_RESERVED = 8
+FAT_COUNT = 2
+FAT_SECTORS = 1
+FAT_CLUSTERS = 120
+FAT_DATA_START = FAT_RESERVED + FAT_COUNT * FAT_SECTORS
+FAT_TOTAL = FAT_DATA_START + FAT_CLUSTERS
+END_OF_CHAIN = 0x0FFFFFFF
+
+ATTR_DIRECTORY = 0x10
+ATTR_ARCHIVE = 0x20
+ATTR_LFN = 0x0F
+# NT reserved byte: base name and extension stored upper case but shown lower case.
+LOWER_BASE = 0x08
+LOWER_EXT = 0x10
+
+
+def fat_date(year, month, day):
+    return (year - 1980) << 9 | month << 5 | day
+
+
+def fat_time(hour, minute, second):
+    return hour << 11 | minute << 5 | second // 2
+
+
+MODIFIED = (fat_date(2024, 5, 17), fat_time(13, 45, 30))
+CREATED = (fat_date(2023, 1, 2), fat_time(3, 4, 4))
+
+
+def short_checksum(short):
+    total = 0
+    for byte in short:
+        total = ((total >> 1) | (total << 7 & 0x80)) + byte & 0xFF
+    return total
+
+
+def dir_entry(short, attr, cluster, size, nt=0):
+    assert len(short) == 11
+    return struct.pack(
+        "<11sBBBHHHHHHHI",
+        short, attr, nt,
+        1,  # creation time, the 10 ms units past the two second step
+        CREATED[1], CREATED[0],
+        fat_date(2024, 6, 1),  # last access date
+        cluster >> 16,
+        MODIFIED[1], MODIFIED[0],
+        cluster & 0xFFFF,
+        size,
+    )
+
+
+def lfn_entries(name, short, bad_checksum=False):
+    units = [unit for (unit,) in struct.iter_unpack("<H", name.encode("utf-16-le"))]
+    units.append(0)
+    while len(units) % 13:
+        units.append(0xFFFF)
+    checksum = short_checksum(short) ^ (0xFF if bad_checksum else 0)
+    count = len(units) // 13
+    entries = []
+    for seq in range(count, 0, -1):
+        part = units[(seq - 1) * 13:seq * 13]
+        order = seq | (0x40 if seq == count else 0)
+        entries.append(struct.pack(
+            "<B5HBBB6HH2H",
+            order, *part[:5], ATTR_LFN, 0, checksum, *part[5:11], 0, *part[11:],
+        ))
+    return b"".join(entries)
+
+
+class Fat32:
+    def __init__(self):
+        self.image = bytearray(FAT_TOTAL * SECTOR)
+        self.fat = [0] * (SECTOR // 4)
+        self.fat[0] = 0x0FFFFFF8
+        self.fat[1] = END_OF_CHAIN
+        self.next_cluster = 2
+
+    def take(self, count):
+        clusters = list(range(self.next_cluster, self.next_cluster + count))
+        self.next_cluster += count
+        return clusters
+
+    def write_chain(self, clusters, data):
+        for i, cluster in enumerate(clusters):
+            self.fat[cluster] = clusters[i + 1] if i + 1 < len(clusters) else END_OF_CHAIN
+            chunk = data[i * SECTOR:(i + 1) * SECTOR]
+            offset = (FAT_DATA_START + cluster - 2) * SECTOR
+            self.image[offset:offset + len(chunk)] = chunk
+
+    def finish(self, free_hint):
+        boot = bytearray(SECTOR)
+        boot[0:3] = b"\xEB\x58\x90"
+        boot[3:11] = b"KRABBOS "
+        struct.pack_into("<HBHBHHBHHHII", boot, 11,
+                         SECTOR, 1, FAT_RESERVED, FAT_COUNT, 0, 0, 0xF8, 0, 32, 2, 0, FAT_TOTAL)
+        struct.pack_into("<IHHIHH", boot, 36, FAT_SECTORS, 0, 0, 2, 1, 6)
+        struct.pack_into("<BBBI11s8s", boot, 64, 0x80, 0, 0x29, 0x12345678, b"KRABBOSTEST", b"FAT32   ")
+        boot[510:512] = b"\x55\xAA"
+        used = sum(1 for entry in self.fat[2:FAT_CLUSTERS + 2] if entry)
+        fsinfo = bytearray(SECTOR)
+        struct.pack_into("<I", fsinfo, 0, 0x41615252)
+        struct.pack_into("<III", fsinfo, 484, 0x61417272, FAT_CLUSTERS - used, free_hint)
+        struct.pack_into("<I", fsinfo, 508, 0xAA550000)
+        for sector, data in [(0, boot), (1, fsinfo), (6, boot), (7, fsinfo)]:
+            self.image[sector * SECTOR:(sector + 1) * SECTOR] = data
+        table = struct.pack("<%dI" % len(self.fat), *self.fat)
+        for copy in range(FAT_COUNT):
+            offset = (FAT_RESERVED + copy * FAT_SECTORS) * SECTOR
+            self.image[offset:offset + SECTOR] = table
+        return bytes(self.image)
+
+
+def pattern(length, seed):
+    return bytes((i * seed + i // 251) & 0xFF for i in range(length))
+
+
+def fat32_image():
+    fs = Fat32()
+    root = fs.take(2)
+    docs, nested, deep = fs.take(1), fs.take(1), fs.take(1)
+
+    hello = b"Hello, FAT32!\n"
+    hello_clusters = fs.take(1)
+    fs.write_chain(hello_clusters, hello)
+
+    readme = b"lower case through the NT flags\n"
+    readme_clusters = fs.take(1)
+    fs.write_chain(readme_clusters, readme)
+
+    long_text = b"a file with a long name\n"
+    long_clusters = fs.take(1)
+    fs.write_chain(long_clusters, long_text)
+
+    unicode_text = "Grüße\n".encode()
+    unicode_clusters = fs.take(1)
+    fs.write_chain(unicode_clusters, unicode_text)
+
+    bad_clusters = fs.take(1)
+    fs.write_chain(bad_clusters, b"bad checksum\n")
+
+    # Six clusters in a row, the last one partly used.
+    big = pattern(3000, 7)
+    big_clusters = fs.take(6)
+    fs.write_chain(big_clusters, big)
+
+    # Three clusters scattered backwards, with other files in between.
+    fragmented = pattern(1400, 13)
+    fs.take(20)
+    frag_clusters = [80, 60, 45]
+    fs.write_chain(frag_clusters, fragmented)
+
+    deep_text = b"three directories down\n"
+    deep_clusters = [100]
+    fs.write_chain(deep_clusters, deep_text)
+
+    # The root spans two clusters: sixteen entries each.
+    entries = b""
+    entries += dir_entry(b"KRABBOSTEST", 0x08, 0, 0)
+    entries += dir_entry(b"HELLO   TXT", ATTR_ARCHIVE, hello_clusters[0], len(hello))
+    entries += dir_entry(b"README  TXT", ATTR_ARCHIVE, readme_clusters[0], len(readme), LOWER_BASE | LOWER_EXT)
+    entries += lfn_entries("A long file name.txt", b"ALONGF~1TXT")
+    entries += dir_entry(b"ALONGF~1TXT", ATTR_ARCHIVE, long_clusters[0], len(long_text))
+    entries += lfn_entries("Ünïcödé näme.txt", b"NCD~1   TXT")
+    entries += dir_entry(b"NCD~1   TXT", ATTR_ARCHIVE, unicode_clusters[0], len(unicode_text))
+    entries += lfn_entries("never shown.txt", b"BADSUM~1TXT", bad_checksum=True)
+    entries += dir_entry(b"BADSUM~1TXT", ATTR_ARCHIVE, bad_clusters[0], 13)
+    # A deleted file.
+    entries += b"\xE5" + dir_entry(b"GONE    TXT", ATTR_ARCHIVE, 0, 0)[1:]
+    entries += dir_entry(b"DOCS       ", ATTR_DIRECTORY, docs[0], 0, LOWER_BASE)
+    entries += lfn_entries("Fragmented file.bin", b"FRAGME~1BIN")
+    entries += dir_entry(b"FRAGME~1BIN", ATTR_ARCHIVE, frag_clusters[0], len(fragmented))
+    assert len(entries) > SECTOR, "the root should span two clusters"
+    fs.write_chain(root, entries)
+
+    def subdir(cluster, parent, children):
+        data = dir_entry(b".          ", ATTR_DIRECTORY, cluster, 0)
+        data += dir_entry(b"..         ", ATTR_DIRECTORY, parent, 0)
+        fs.write_chain([cluster], data + children)
+
+    subdir(docs[0], 0, lfn_entries("Big file.bin", b"BIGFIL~1BIN")
+           + dir_entry(b"BIGFIL~1BIN", ATTR_ARCHIVE, big_clusters[0], len(big))
+           + dir_entry(b"NESTED     ", ATTR_DIRECTORY, nested[0], 0, LOWER_BASE))
+    subdir(nested[0], docs[0], dir_entry(b"DEEP       ", ATTR_DIRECTORY, deep[0], 0, LOWER_BASE))
+    subdir(deep[0], nested[0], dir_entry(b"FILE    TXT", ATTR_ARCHIVE, deep_clusters[0], len(deep_text),
+                                         LOWER_BASE | LOWER_EXT))
+    return fs.finish(fs.next_cluster)
+
+
+# USTAR: the initrd the tests use as their root filesystem.
+
+TAR_MTIME = 1715953530  # 2024-05-17 13:45:30 UTC
+TAR_FILES = 300
+
+
+def tar_image():
+    out = io.BytesIO()
+    # USTAR proper, so that long paths go through the prefix field.
+    with tarfile.open(fileobj=out, mode="w", format=tarfile.USTAR_FORMAT) as tar:
+        def add(name, kind=tarfile.REGTYPE, data=b"", mode=0o644, target=""):
+            info = tarfile.TarInfo(name)
+            info.type = kind
+            info.mode = mode
+            info.mtime = TAR_MTIME
+            info.uname = info.gname = "root"
+            info.size = len(data)
+            info.linkname = target
+            tar.addfile(info, io.BytesIO(data) if data else None)
+
+        add("hello.txt", data=b"Hello from the initrd!\n")
+        add("etc/", tarfile.DIRTYPE, mode=0o755)
+        add("etc/motd", data=b"Welcome to krabbos.\n")
+        add("etc/hostname", data=b"krabbos\n", mode=0o600)
+        add("etc/greeting", tarfile.SYMTYPE, target="../hello.txt", mode=0o777)
+        add("motd", tarfile.SYMTYPE, target="etc/motd", mode=0o777)
+        add("big.bin", data=pattern(3000, 11))
+        # A path over the 100 bytes of the name field, split into the prefix.
+        long_dir = "deep/" + "a-directory-with-a-rather-long-name/" * 3
+        add(long_dir, tarfile.DIRTYPE, mode=0o755)
+        add(long_dir + "and-a-file-at-the-end-of-it.txt", data=b"long path\n")
+        add("files/", tarfile.DIRTYPE, mode=0o755)
+        for i in range(TAR_FILES):
+            add("files/%03d.txt" % i, data=b"file %d\n" % i)
+        add("data", tarfile.SYMTYPE, target="/files", mode=0o777)
+        add("loop", tarfile.SYMTYPE, target="loop", mode=0o777)
+        # Where the kernel mounts its ramfs.
+        add("tmp/", tarfile.DIRTYPE, mode=0o777)
+    return out.getvalue()
+
+
+# ext2: made by mke2fs from a directory, once per block size. Its times, the
+# UUID and the directory hash seed are pinned so the images come out the same.
+
+EXT2_IMAGES = [
+    # Name, block size, blocks, blocks per group, extra mke2fs options.
+    ("ext2-1k.img", 1024, 640, 256, []),
+    # Directory entries without the file type byte.
+    ("ext2-2k.img", 2048, 256, 256, ["-O", "^filetype"]),
+    ("ext2-4k.img", 4096, 192, 256, []),
+]
+EXT2_FILES = 50
+# Past the double indirect block with any block size.
+SPARSE_END = 6 << 20
+
+
+def ext2_tree(root):
+    """Fills the directory `root`, returning the paths in it."""
+    def write(path, data, mode=0o644):
+        with open(os.path.join(root, path), "wb") as out:
+            out.write(data)
+        os.chmod(os.path.join(root, path), mode)
+
+    for directory in ["etc", "files", "deep/a/b/c/d/e/f/g/h"]:
+        os.makedirs(os.path.join(root, directory))
+    write("hello.txt", b"Hello from ext2!\n")
+    write("etc/motd", b"Welcome to krabbos.\n")
+    write("etc/hostname", b"krabbos\n", 0o600)
+    write("deep/a/b/c/d/e/f/g/h/file.txt", b"nine directories down\n")
+    # Through the double indirect block with 1 KiB blocks. No block is all
+    # zeroes, which mke2fs would leave out.
+    write("big.bin", pattern(280 << 10, 5))
+    with open(os.path.join(root, "sparse.bin"), "wb") as out:
+        out.write(b"start")
+        out.seek(SPARSE_END - 3)
+        out.write(b"end")
+    for i in range(EXT2_FILES):
+        write("files/file-number-%02d.txt" % i, b"file %d\n" % i)
+    # Short targets are kept in the inode, long ones in a block.
+    os.symlink("etc/motd", os.path.join(root, "motd"))
+    os.symlink("./deep/a/b/c/d/e/f/g/h/../../../../../../../../../deep/a/b/c/d/e/f/g/h/file.txt",
+               os.path.join(root, "deep-file"))
+    os.symlink("/hello.txt", os.path.join(root, "abs"))
+    os.symlink("loop", os.path.join(root, "loop"))
+
+    paths = ["/", "/lost+found"]
+    for directory, dirs, files in os.walk(root):
+        for name in dirs + files:
+            path = os.path.join(directory, name)
+            os.utime(path, (TAR_MTIME, TAR_MTIME), follow_symlinks=False)
+            paths.append("/" + os.path.relpath(path, root))
+    return paths
+
+
+def ext2_image(block_size, blocks, blocks_per_group, options):
+    with tempfile.TemporaryDirectory() as tmp:
+        root = os.path.join(tmp, "root")
+        image = os.path.join(tmp, "image")
+        paths = ext2_tree(root)
+        subprocess.run(
+            ["mke2fs", "-q", "-F", "-t", "ext2", "-b", str(block_size), "-g", str(blocks_per_group),
+             "-I", "128", "-N", "128", "-m", "0", "-L", "krabbos",
+             "-U", "6b7261bb-626f-7300-0000-000000000001",
+             "-E", "hash_seed=6b7261bb-626f-7300-0000-000000000002,root_owner=0:0",
+             *options, "-d", root, image, str(blocks)],
+            env=dict(os.environ, E2FSPROGS_FAKE_TIME=str(TAR_MTIME)), check=True, capture_output=True)
+        # The change times come from copying the files, now.
+        fixes = "".join('set_inode_field "%s" %s @%d\n' % (path, field, TAR_MTIME)
+                        for path in paths for field in ["atime", "ctime", "mtime"])
+        subprocess.run(["debugfs", "-w", "-f", "-", image], input=fixes.encode(), check=True, capture_output=True)
+        subprocess.run(["e2fsck", "-fn", image], check=True, capture_output=True)
+        with open(image, "rb") as data:
+            return data.read()
+
+# ISO 9660: 2048 byte sectors, a primary tree with Rock Ridge names, modes,
+# times and symbolic links, and a Joliet tree beside it. Written byte by byte
+# like FAT, so that a file can be recorded in two extents without being 4 GiB.
+
+ISO_SECTOR = 2048
+# After the system area: the primary and Joliet descriptors and the
+# terminator, then the four path tables, a sector each. Each directory is
+# followed by the continuation areas of its Rock Ridge entries, as mkisofs lays
+# them out for readers going through the image in order.
+ISO_DESCRIPTORS = 16
+ISO_PATH_TABLES = 19
+ISO_FIRST_FREE = 23
+# 15:45:30 at UTC+02:00, in 15 minute units: 13:45:30 UTC like the other images.
+ISO_RECORDED = (2024, 5, 17, 15, 45, 30, 8)
+ISO_ACCESSED = (2024, 6, 1, 8, 0, 0, 0)
+ISO_FILES = 40
+# Too long to fit in its directory record, so its NM entry is continued.
+ISO_LONG_NAME = "A file name much too long for ISO 9660, " * 4 + "kept whole by Rock Ridge.txt"
+
+FLAG_DIRECTORY = 0x02
+FLAG_MULTI_EXTENT = 0x80
+S_IFDIR, S_IFREG, S_IFLNK = 0o040000, 0o100000, 0o120000
+SL_CURRENT, SL_PARENT, SL_ROOT = 0x02, 0x04, 0x08
+RRIP_ID = b"RRIP_1991A"
+RRIP_DESCRIPTION = b"THE ROCK RIDGE INTERCHANGE PROTOCOL PROVIDES SUPPORT FOR POSIX FILE SYSTEM SEMANTICS"
+RRIP_SOURCE = (b"PLEASE CONTACT DISC PUBLISHER FOR SPECIFICATION SOURCE.  SEE PUBLISHER IDENTIFIER IN "
+               b"PRIMARY VOLUME DESCRIPTOR FOR CONTACT INFORMATION.")
+
+
+def both16(value):
+    return struct.pack("<H", value) + struct.pack(">H", value)
+
+
+def both32(value):
+    return struct.pack("<I", value) + struct.pack(">I", value)
+
+
+def iso_date(year, month, day, hour, minute, second, offset):
+    return bytes([year - 1900, month, day, hour, minute, second]) + struct.pack("b", offset)
+
+
+def iso_long_date(year, month, day, hour, minute, second, offset):
+    return b"%04d%02d%02d%02d%02d%02d00" % (year, month, day, hour, minute, second) + struct.pack("b", offset)
+
+
+class IsoNode:
+    """A file, a directory with `children` or a symbolic link to `target`. A
+    file in `sections` is recorded in that many extents, all but the last one
+    sector long."""
+
+    def __init__(self, name, data=b"", mode=None, children=None, target=None, sections=1):
+        self.name, self.data, self.children, self.target, self.sections = name, data, children, target, sections
+        self.mode = mode if mode is not None else 0o777 if target else 0o755 if children is not None else 0o644
+        self.parent = self
+        self.names = {}
+        self.extent = 0
+        # Directories have an extent in each tree.
+        self.dir_extent, self.dir_size = {}, {}
+
+    def is_dir(self):
+        return self.children is not None
+
+    def posix_mode(self):
+        return (S_IFDIR if self.is_dir() else S_IFLNK if self.target else S_IFREG) | self.mode
+
+    def links(self):
+        return 2 + sum(child.is_dir() for child in self.children) if self.is_dir() else 1
+
+
+def name_trees(directory):
+    """Names the children of `directory` in both trees: 8.3 upper case names,
+    numbered where they would clash, and up to 64 UCS-2 characters."""
+    def mangle(text, length):
+        return "".join(c if c.isascii() and c.isalnum() else "_" for c in text.upper())[:length]
+
+    clashes = {}
+    for child in directory.children:
+        child.parent = directory
+        if child.is_dir():
+            short = (mangle(child.name, 8), None)
+        else:
+            base, dot, extension = child.name.rpartition(".")
+            short = (mangle(base, 8), mangle(extension, 3)) if dot else (mangle(child.name, 8), "")
+        clashes.setdefault(short, []).append(child)
+        version = b"" if child.is_dir() else ";1".encode("utf-16-be")
+        child.names["joliet"] = child.name[:64].encode("utf-16-be") + version
+        if child.is_dir():
+            name_trees(child)
+    for (base, extension), children in clashes.items():
+        for i, child in enumerate(children):
+            short = base[:5] + "%03d" % i if len(children) > 1 else base
+            child.names["rr"] = short.encode() if extension is None else b"%s.%s;1" % (short.encode(), extension.encode())
+
+
+def iso_record(identifier, extent, size, flags, system_use=b""):
+    pad = b"\0" if len(identifier) % 2 == 0 else b""
+    record = (bytes([0, 0]) + both32(extent) + both32(size) + iso_date(*ISO_RECORDED) + bytes([flags, 0, 0])
+              + both16(1) + bytes([len(identifier)]) + identifier + pad + system_use)
+    record += b"\0" * (len(record) % 2)
+    assert len(record) <= 255
+    return bytes([len(record)]) + record[1:]
+
+
+class Continuation:
+    """The continuation areas of a directory, in the sector `sector`."""
+
+    def __init__(self, sector):
+        self.sector, self.data = sector, b""
+
+    def entry(self, entries):
+        offset, self.data = len(self.data), self.data + entries
+        assert len(self.data) <= ISO_SECTOR
+        return b"CE" + bytes([28, 1]) + both32(self.sector) + both32(offset) + both32(len(entries))
+
+
+def system_use(identifier, entries, continuation):
+    """The System Use field of a record named `identifier`, continuing the
+    entries that do not fit in the record."""
+    room = 255 - 34 - len(identifier)
+    if sum(map(len, entries)) <= room:
+        return b"".join(entries)
+    kept = b""
+    for i, entry in enumerate(entries):
+        if len(kept) + len(entry) + 28 > room:
+            return kept + continuation.entry(b"".join(entries[i:]))
+        kept += entry
+
+
+def rock_ridge(node, name=None):
+    entries = [b"PX" + bytes([36, 1]) + both32(node.posix_mode()) + both32(node.links()) + both32(0) + both32(0),
+               b"TF" + bytes([19, 1, 0x06]) + iso_date(*ISO_RECORDED) + iso_date(*ISO_ACCESSED)]
+    if name is not None:
+        entries.append(b"NM" + bytes([5 + len(name), 1, 0]) + name)
+    if node.target:
+        components = bytes([SL_ROOT, 0]) if node.target.startswith("/") else b""
+        for part in node.target.strip("/").split("/"):
+            flags, text = {".": (SL_CURRENT, b""), "..": (SL_PARENT, b"")}.get(part, (0, part.encode()))
+            components += bytes([flags, len(text)]) + text
+        entries.append(b"SL" + bytes([5 + len(components), 1, 0]) + components)
+    return entries
+
+
+def directory(node, tree, continuation_sector=0):
+    """The sectors of the directory `node` in `tree`, "rr" or "joliet", and
+    its continuation area, to be written in `continuation_sector`."""
+    continuation = Continuation(continuation_sector)
+    records = []
+    for identifier, target in [(b"\0", node), (b"\1", node.parent)]:
+        entries = []
+        if tree == "rr":
+            entries = rock_ridge(target)
+            if node.parent is node and identifier == b"\0":
+                entries.insert(0, b"SP" + bytes([7, 1, 0xBE, 0xEF, 0]))
+                entries.append(b"ER" + bytes([8 + len(RRIP_ID) + len(RRIP_DESCRIPTION) + len(RRIP_SOURCE), 1,
+                                              len(RRIP_ID), len(RRIP_DESCRIPTION), len(RRIP_SOURCE), 1])
+                               + RRIP_ID + RRIP_DESCRIPTION + RRIP_SOURCE)
+        use = system_use(identifier, entries, continuation)
+        records.append(iso_record(identifier, target.dir_extent[tree], target.dir_size[tree], FLAG_DIRECTORY, use))
+    for child in sorted(node.children, key=lambda child: child.names[tree]):
+        identifier = child.names[tree]
+        if child.is_dir():
+            sections = [(child.dir_extent[tree], child.dir_size[tree], FLAG_DIRECTORY)]
+        else:
+            sections = [(child.extent + i, ISO_SECTOR, FLAG_MULTI_EXTENT) for i in range(child.sections - 1)]
+            sections.append((child.extent + child.sections - 1, len(child.data) - ISO_SECTOR * (child.sections - 1), 0))
+        for extent, size, flags in sections:
+            entries = rock_ridge(child, child.name.encode()) if tree == "rr" else []
+            records.append(iso_record(identifier, extent if size else 0, size, flags,
+                                      system_use(identifier, entries, continuation)))
+    # Records never cross a sector.
+    sectors = [b""]
+    for record in records:
+        if len(sectors[-1]) + len(record) > ISO_SECTOR:
+            sectors.append(b"")
+        sectors[-1] += record
+    return b"".join(sector.ljust(ISO_SECTOR, b"\0") for sector in sectors), continuation.data
+
+
+def directories(root, tree):
+    """The directories breadth first, as the path tables number them."""
+    order = [root]
+    for node in order:
+        order += sorted((child for child in node.children if child.is_dir()), key=lambda child: child.names[tree])
+    return order
+
+
+def path_table(root, tree, fmt):
+    dirs = directories(root, tree)
+    numbers = {id(node): i + 1 for i, node in enumerate(dirs)}
+    table = b""
+    for node in dirs:
+        identifier = b"\0" if node is root else node.names[tree]
+        table += (bytes([len(identifier), 0]) + struct.pack(fmt, node.dir_extent[tree], numbers[id(node.parent)])
+                  + identifier + b"\0" * (len(identifier) % 2))
+    return table
+
+
+def volume_descriptor(kind, root, tree, total, path_table_size, path_tables):
+    def text(string, length):
+        if tree == "joliet":
+            return string.ljust(length // 2).encode("utf-16-be")
+        return string.ljust(length).encode()
+
+    descriptor = bytearray(ISO_SECTOR)
+    descriptor[0:7] = bytes([kind]) + b"CD001" + bytes([1])
+    descriptor[8:72] = text("KRABBOS", 32) + text("KRABBOS", 32)
+    descriptor[80:88] = both32(total)
+    if tree == "joliet":
+        # UCS-2 level 3.
+        descriptor[88:91] = b"%/E"
+    descriptor[120:132] = both16(1) + both16(1) + both16(ISO_SECTOR)
+    descriptor[132:140] = both32(path_table_size)
+    descriptor[140:144] = struct.pack("<I", path_tables)
+    descriptor[148:152] = struct.pack(">I", path_tables + 1)
+    descriptor[156:190] = iso_record(b"\0", root.dir_extent[tree], root.dir_size[tree], FLAG_DIRECTORY)
+    descriptor[190:813] = b" " * (813 - 190)
+    descriptor[813:847] = iso_long_date(*ISO_RECORDED) * 2
+    descriptor[847:881] = (b"0" * 16 + b"\0") * 2
+    descriptor[881] = 1
+    return bytes(descriptor)
+
+
+def iso_tree():
+    return IsoNode("", children=[
+        IsoNode("hello.txt", b"Hello from ISO 9660!\n"),
+        IsoNode("README", b"Read me first.\n"),
+        IsoNode("big.bin", pattern(20000, 7)),
+        IsoNode("split.bin", pattern(ISO_SECTOR + 100, 3), sections=2),
+        IsoNode("etc", children=[
+            IsoNode("motd", b"Welcome to krabbos.\n"),
+            IsoNode("hostname", b"krabbos\n", 0o600),
+        ]),
+        IsoNode("deep", children=[IsoNode("a", children=[
+            IsoNode("b", children=[IsoNode("c", children=[IsoNode("file.txt", b"four directories down\n")])]),
+            IsoNode("up", target="../../hello.txt"),
+        ])]),
+        IsoNode("docs", children=[IsoNode(ISO_LONG_NAME, b"long name\n")]),
+        IsoNode("files", children=[IsoNode("file-number-%02d.txt" % i, b"file %d\n" % i) for i in range(ISO_FILES)]),
+        IsoNode("motd", target="etc/motd"),
+        IsoNode("abs", target="/hello.txt"),
+        IsoNode("loop", target="loop"),
+    ])
+
+
+def iso9660_image():
+    root = iso_tree()
+    name_trees(root)
+    trees = ["rr", "joliet"]
+    # The records are as long whatever extents they point to: lay the
+    # directories out from a first pass, then write them.
+    for tree in trees:
+        for node in directories(root, tree):
+            node.dir_extent[tree], node.dir_size[tree] = 0, 0
+    next_free = ISO_FIRST_FREE
+    for tree in trees:
+        for node in directories(root, tree):
+            records, continued = directory(node, tree)
+            node.dir_extent[tree], node.dir_size[tree] = next_free, len(records)
+            next_free += len(records) // ISO_SECTOR + (len(continued) != 0)
+
+    def files(node):
+        for child in node.children:
+            if child.is_dir():
+                yield from files(child)
+            else:
+                yield child
+    data = []
+    for node in files(root):
+        if node.data:
+            node.extent = next_free
+            data.append((next_free, node.data))
+            next_free += -(-len(node.data) // ISO_SECTOR)
+
+    image = bytearray(next_free * ISO_SECTOR)
+    def write(sector, contents):
+        image[sector * ISO_SECTOR:sector * ISO_SECTOR + len(contents)] = contents
+
+    for tree in trees:
+        for node in directories(root, tree):
+            end = node.dir_extent[tree] + node.dir_size[tree] // ISO_SECTOR
+            records, continued = directory(node, tree, end)
+            write(node.dir_extent[tree], records)
+            write(end, continued)
+    for i, tree in enumerate(trees):
+        tables = ISO_PATH_TABLES + 2 * i
+        write(tables, path_table(root, tree, "<IH"))
+        write(tables + 1, path_table(root, tree, ">IH"))
+        write(ISO_DESCRIPTORS + i, volume_descriptor(1 + i, root, tree, next_free, len(path_table(root, tree, "<IH")), tables))
+    write(ISO_DESCRIPTORS + 2, bytes([255]) + b"CD001" + bytes([1]))
+    for sector, contents in data:
+        write(sector, contents)
+    return bytes(image)
+
+
+# User programs: static executables run by the process tests, with the base
+# each is linked at. peek reads the first page of hello, so they differ.
+
+USER_PROGRAMS = [
+    ("hello", 0x400000),
+    ("peek", 0x800000),
+    ("fork", 0x400000),
+    ("pids", 0x400000),
+    ("illegal", 0x400000),
+    ("privileged", 0x400000),
+    ("mmap", 0x400000),
+    ("copy", 0x400000),
+    ("sleep", 0x400000),
+    ("read", 0x400000),
+    ("div0", 0x400000),
+]
+
+
+def user_program(name, base):
+    source = os.path.join(os.path.dirname(__file__), "..", "user", name + ".s")
+    with tempfile.TemporaryDirectory() as tmp:
+        obj = os.path.join(tmp, name + ".o")
+        exe = os.path.join(tmp, name)
+        subprocess.run(["as", "--64", "-o", obj, source], check=True)
+        # One segment per page, so that each gets its own permissions.
+        subprocess.run(
+            ["ld", "-static", "-nostdlib", "-s", "--build-id=none", "-z", "separate-code",
+             "-z", "max-page-size=0x1000", "-z", "noexecstack", "-Ttext-segment=%#x" % base, "-o", exe, obj],
+            check=True)
+        with open(exe, "rb") as f:
+            return f.read()
+
+
+def main():
+    out = sys.argv[1] if len(sys.argv) > 1 else os.path.join(os.path.dirname(__file__), "..", "fixtures")
+    os.makedirs(out, exist_ok=True)
+    with open(os.path.join(out, "fat32.img"), "wb") as image:
+        image.write(fat32_image())
+    with open(os.path.join(out, "initrd.tar"), "wb") as image:
+        image.write(tar_image())
+    for name, block_size, blocks, blocks_per_group, options in EXT2_IMAGES:
+        with open(os.path.join(out, name), "wb") as image:
+            image.write(ext2_image(block_size, blocks, blocks_per_group, options))
+    with open(os.path.join(out, "iso9660.iso"), "wb") as image:
+        image.write(iso9660_image())
+    for name, base in USER_PROGRAMS:
+        with open(os.path.join(out, name + ".elf"), "wb") as image:
+            image.write(user_program(name, base))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,52 @@
+#!/bin/sh
+# Runs the kernel test suite headless and turns the serial markers written by
+# `test_runner` into an exit code, so CI does not depend on QEMU exit codes alone.
+#
+# Usage: scripts/run-tests.sh [extra `cargo test` arguments]
+#
+# Every test kernel built by `cargo test --no-run` is handed to `bootimage runner`,
+# which wraps it in a disk image and boots it in qemu-system-x86_64 with the
+# `test-args` from Cargo.toml (COM1 on stdio, no display, isa-debug-exit).
+# A kernel passes when its serial output contains `TEST_SUITE:OK`.
+set -eu
+
+cd "$(dirname "$0")/.."
+mkdir -p target
+
+# Scratch disks attached as the IDE primary slave, to the AHCI controller and
+# as a virtio-blk device (see `test-args` in Cargo.toml). The disk tests expect this marker in sector 0
+# and overwrite the sectors after it.
+for disk in target/test-disk.img target/test-disk-ahci.img target/test-disk-virtio.img; do
+    printf 'KRABBOS TEST DISK' > "$disk"
+    truncate -s 1M "$disk"
+done
+
+# The initrd, embedded as ram0 and mounted as the root filesystem.
+export KRABBOS_RAMDISK="$PWD/fixtures/initrd.tar"
+
+kernels=$(cargo test --no-run --features ramdisk --message-format=json "$@" \
+    | sed -n 's/.*"executable":"\([^"]*\)".*/\1/p')
+if [ -z "$kernels" ]; then
+    echo "run-tests: cargo test --no-run produced no test kernel" >&2
+    exit 1
+fi
+
+status=0
+for kernel in $kernels; do
+    log="target/$(basename "$kernel").serial.log"
+    echo "run-tests: booting $kernel"
+    # The runner's own exit code is not trusted: a triple fault also ends QEMU.
+    bootimage runner "$kernel" | tee "$log" || true
+
+    if grep -q "TEST_SUITE:OK" "$log"; then
+        echo "run-tests: $(basename "$kernel") passed"
+    elif grep -q "TEST_SUITE:FAIL" "$log"; then
+        echo "run-tests: $(basename "$kernel") failed, see $log" >&2
+        status=1
+    else
+        echo "run-tests: $(basename "$kernel") printed no TEST_SUITE marker (crash or timeout), see $log" >&2
+        status=1
+    fi
+done
+
+exit $status
@@ -0,0 +1,243 @@
+//! The fixed ACPI description table, and the `\_S5` sleep type from the DSDT.
+
+use bitflags::bitflags;
+use spin::Once;
+use super::{find_table, SdtHeader, TABLES};
+
+/// Offsets into the FADT, header included.
+const FADT_DSDT: usize = 40;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+/// The IA-PC boot architecture flags, reserved before revision 2.
+const FADT_BOOT_FLAGS: usize = 109;
+const FADT_BOOT_FLAGS_REVISION: u8 = 2;
+/// ACPI 2.0 fields, used when the table is long enough and they are set.
+const FADT_X_DSDT: usize = 140;
+const FADT_X_PM1A_CONTROL: usize = 172;
+const FADT_X_PM1B_CONTROL: usize = 184;
+/// Generic address structure space ID of I/O ports.
+const GAS_SYSTEM_IO: u8 = 1;
+
+/// AML opcodes met when scanning for `\_S5`.
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_PREFIX: u8 = 0x5C;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_WORD_PREFIX: u8 = 0x0B;
+const AML_DWORD_PREFIX: u8 = 0x0C;
+
+bitflags! {
+    /// The IA-PC boot architecture flags: which legacy devices the firmware
+    /// says are there.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct BootFlags: u16 {
+        /// Devices on the LPC or ISA bus that need no enumeration, such as
+        /// the serial ports.
+        const LEGACY_DEVICES = 1 << 0;
+        /// An 8042 PS/2 controller, or something answering on its ports.
+        const PS2_8042 = 1 << 1;
+        /// Probing the VGA ports and memory is not safe.
+        const VGA_NOT_PRESENT = 1 << 2;
+        /// MSIs must not be enabled.
+        const MSI_NOT_SUPPORTED = 1 << 3;
+        /// PCIe ASPM must not be enabled.
+        const PCIE_ASPM_CONTROLS = 1 << 4;
+        /// There is no CMOS RTC at the legacy ports.
+        const CMOS_RTC_NOT_PRESENT = 1 << 5;
+    }
+}
+
+impl BootFlags {
+    /// What a PC has when the FADT is too old to say.
+    pub const LEGACY_PC: BootFlags = BootFlags::LEGACY_DEVICES.union(BootFlags::PS2_8042);
+}
+
+/// The FADT fields needed to enter a sleep state, and the boot flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// Physical address of the DSDT.
+    pub dsdt: u64,
+    /// Port taking [`Fadt::acpi_enable`] to switch from legacy to ACPI mode, 0 if
+    /// the system is always in ACPI mode.
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    pub pm1a_control: u16,
+    /// 0 if there is no second PM1 register block.
+    pub pm1b_control: u16,
+    /// [`BootFlags::LEGACY_PC`] before revision 2.
+    pub boot_flags: BootFlags,
+}
+
+fn bytes_at<const N: usize>(bytes: &[u8], at: usize) -> Option<[u8; N]> {
+    bytes.get(at..at + N)?.try_into().ok()
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    bytes_at(bytes, at).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    bytes_at(bytes, at).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    bytes_at(bytes, at).map(u64::from_le_bytes)
+}
+
+/// Reads the I/O port of the generic address structure at `at`, if it is one.
+fn gas_port(bytes: &[u8], at: usize) -> Option<u16> {
+    let gas: [u8; 12] = bytes_at(bytes, at)?;
+    let address = u64::from_le_bytes(gas[4..].try_into().unwrap());
+    (gas[0] == GAS_SYSTEM_IO && address != 0).then_some(address as u16)
+}
+
+impl Fadt {
+    /// Parses the whole table, header included.
+    pub fn parse(bytes: &[u8]) -> Option<Fadt> {
+        let dsdt = u32_at(bytes, FADT_DSDT)? as u64;
+        let revision = *bytes.get(8)?;
+        let boot_flags = u16_at(bytes, FADT_BOOT_FLAGS)
+            .filter(|_| revision >= FADT_BOOT_FLAGS_REVISION)
+            .map_or(BootFlags::LEGACY_PC, BootFlags::from_bits_retain);
+        Some(Fadt {
+            dsdt: u64_at(bytes, FADT_X_DSDT).filter(|&x_dsdt| x_dsdt != 0).unwrap_or(dsdt),
+            smi_command: u32_at(bytes, FADT_SMI_COMMAND)? as u16,
+            acpi_enable: *bytes.get(FADT_ACPI_ENABLE)?,
+            pm1a_control: gas_port(bytes, FADT_X_PM1A_CONTROL)
+                .unwrap_or(u32_at(bytes, FADT_PM1A_CONTROL)? as u16),
+            pm1b_control: gas_port(bytes, FADT_X_PM1B_CONTROL)
+                .unwrap_or(u32_at(bytes, FADT_PM1B_CONTROL)? as u16),
+            boot_flags,
+        })
+    }
+}
+
+/// Reads one integer element of a package, returning it and the bytes after it.
+fn aml_integer(aml: &[u8]) -> Option<(u64, &[u8])> {
+    let (&op, rest) = aml.split_first()?;
+    let width = match op {
+        AML_ZERO_OP => return Some((0, rest)),
+        AML_ONE_OP => return Some((1, rest)),
+        AML_BYTE_PREFIX => 1,
+        AML_WORD_PREFIX => 2,
+        AML_DWORD_PREFIX => 4,
+        _ => return None,
+    };
+    let value = rest.get(..width)?.iter().rev().fold(0u64, |value, &b| value << 8 | b as u64);
+    Some((value, &rest[width..]))
+}
+
+/// Finds `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })` in the AML of the
+/// DSDT and returns the two sleep types.
+///
+/// Only a scan for that one definition, not an interpreter: an `_S5` built by a
+/// method or inside a conditional is not found.
+pub fn s5_sleep_types(aml: &[u8]) -> Option<(u8, u8)> {
+    let name = aml.windows(4).enumerate().position(|(i, window)| {
+        window == b"_S5_"
+            && ((i >= 1 && aml[i - 1] == AML_NAME_OP)
+                || (i >= 2 && aml[i - 1] == AML_ROOT_PREFIX && aml[i - 2] == AML_NAME_OP))
+    })?;
+    let package = &aml[name + 4..];
+    if *package.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLength: bits 7-6 of the lead byte count the bytes that follow it.
+    let length_bytes = 1 + (*package.get(1)? >> 6) as usize;
+    let elements = package.get(1 + length_bytes + 1..)?;
+    let (slp_typ_a, elements) = aml_integer(elements)?;
+    let (slp_typ_b, _) = aml_integer(elements)?;
+    Some((slp_typ_a as u8, slp_typ_b as u8))
+}
+
+static FADT: Once<Option<Fadt>> = Once::new();
+
+/// Returns the parsed FADT, `None` before [`super::init`] or if there is none.
+pub fn fadt() -> Option<&'static Fadt> {
+    TABLES.get()?;
+    FADT.call_once(|| find_table(*b"FACP").and_then(|table| Fadt::parse(table.bytes()))).as_ref()
+}
+
+/// Returns the boot flags of the FADT, [`BootFlags::LEGACY_PC`] if there is
+/// none or it is too old to have them.
+pub fn boot_flags() -> BootFlags {
+    fadt().map_or(BootFlags::LEGACY_PC, |fadt| fadt.boot_flags)
+}
+
+/// Returns the DSDT named by the FADT, after verifying its checksum.
+pub fn dsdt() -> Option<&'static SdtHeader> {
+    let tables = TABLES.get()?;
+    let fadt = fadt()?;
+    unsafe { tables.load(fadt.dsdt) }.ok()
+}
+
+#[test_case]
+fn fadt_fields() {
+    let mut fadt = [0u8; 244];
+    fadt[FADT_DSDT..FADT_DSDT + 4].copy_from_slice(&0x7FE_0040u32.to_le_bytes());
+    fadt[FADT_SMI_COMMAND..FADT_SMI_COMMAND + 4].copy_from_slice(&0xB2u32.to_le_bytes());
+    fadt[FADT_ACPI_ENABLE] = 0xF1;
+    fadt[FADT_PM1A_CONTROL..FADT_PM1A_CONTROL + 4].copy_from_slice(&0x604u32.to_le_bytes());
+    let parsed = Fadt::parse(&fadt).unwrap();
+    assert_eq!(parsed, Fadt { dsdt: 0x7FE_0040, smi_command: 0xB2, acpi_enable: 0xF1, pm1a_control: 0x604, pm1b_control: 0,
+        boot_flags: BootFlags::LEGACY_PC });
+
+    // The extended fields win when set.
+    fadt[FADT_X_DSDT..FADT_X_DSDT + 8].copy_from_slice(&0x1_0000_0000u64.to_le_bytes());
+    fadt[FADT_X_PM1A_CONTROL] = GAS_SYSTEM_IO;
+    fadt[FADT_X_PM1A_CONTROL + 4..FADT_X_PM1A_CONTROL + 12].copy_from_slice(&0xB004u64.to_le_bytes());
+    let parsed = Fadt::parse(&fadt).unwrap();
+    assert_eq!((parsed.dsdt, parsed.pm1a_control), (0x1_0000_0000, 0xB004));
+
+    // An ACPI 1.0 FADT is only 116 bytes long.
+    assert_eq!(Fadt::parse(&fadt[..116]).unwrap().dsdt, 0x7FE_0040);
+    assert!(Fadt::parse(&fadt[..60]).is_none());
+}
+
+#[test_case]
+fn boot_flags_by_revision() {
+    let mut fadt = [0u8; 244];
+    fadt[FADT_BOOT_FLAGS] = 0b1100;
+    // Revision 1: the field is reserved, whatever it holds.
+    fadt[8] = 1;
+    assert_eq!(Fadt::parse(&fadt).unwrap().boot_flags, BootFlags::LEGACY_PC);
+
+    // Revision 2 on: no 8042, as on a machine without legacy devices.
+    for revision in [2, 3, 6] {
+        fadt[8] = revision;
+        let flags = Fadt::parse(&fadt).unwrap().boot_flags;
+        assert_eq!(flags, BootFlags::VGA_NOT_PRESENT | BootFlags::MSI_NOT_SUPPORTED);
+        assert!(!flags.contains(BootFlags::PS2_8042));
+    }
+    // QEMU's q35: legacy devices and an 8042.
+    fadt[FADT_BOOT_FLAGS] = 0b11;
+    assert_eq!(Fadt::parse(&fadt).unwrap().boot_flags, BootFlags::LEGACY_PC);
+    // A revision 2 table cut before the field.
+    assert_eq!(Fadt::parse(&fadt[..FADT_BOOT_FLAGS + 1]).unwrap().boot_flags, BootFlags::LEGACY_PC);
+    // Unknown bits are kept.
+    fadt[FADT_BOOT_FLAGS + 1] = 0x80;
+    assert_eq!(Fadt::parse(&fadt).unwrap().boot_flags.bits(), 0x8003);
+}
+
+#[test_case]
+fn s5_from_dsdt_fragments() {
+    // QEMU: Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+    let qemu = [0x10, 0x08, b'_', b'S', b'4', b'_', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00];
+    assert_eq!(s5_sleep_types(&qemu), Some((0, 0)));
+
+    // VirtualBox: Name (_S5, Package (0x02) { 0x05, 0x05 })
+    let vbox = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0A, 0x05, 0x0A, 0x05];
+    assert_eq!(s5_sleep_types(&vbox), Some((5, 5)));
+
+    // Rooted name, one byte and one word element: Name (\_S5, Package (0x04) { 0x07, 0x0007, Zero, Zero })
+    let rooted = [0x08, 0x5C, b'_', b'S', b'5', b'_', 0x12, 0x09, 0x04, 0x0A, 0x07, 0x0B, 0x07, 0x00, 0x00, 0x00];
+    assert_eq!(s5_sleep_types(&rooted), Some((7, 7)));
+
+    // A method named _S5_ or a truncated package is not a sleep type.
+    assert_eq!(s5_sleep_types(&[0x14, 0x06, b'_', b'S', b'5', b'_', 0x00, 0xA4, 0x00]), None);
+    assert_eq!(s5_sleep_types(&vbox[..10]), None);
+}
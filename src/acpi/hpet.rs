@@ -0,0 +1,100 @@
+//! The HPET description table: where the event timer block is and what it has.
+
+use spin::Once;
+use super::{find_table, TABLES};
+
+/// Offsets into the HPET table, header included.
+const HPET_BLOCK_ID: usize = 36;
+const HPET_ADDRESS: usize = 40;
+const HPET_NUMBER: usize = 52;
+const HPET_MIN_TICK: usize = 53;
+const HPET_LENGTH: usize = 56;
+/// Generic address structure space ID of system memory.
+const GAS_SYSTEM_MEMORY: u8 = 0;
+
+/// Event timer block ID: the comparators less one, the 64-bit main counter and
+/// the legacy replacement route. The general capabilities register of the
+/// block has the same layout in its low half.
+const BLOCK_ID_COMPARATORS: u32 = 0x1F << 8;
+const BLOCK_ID_COUNTER_64: u32 = 1 << 13;
+const BLOCK_ID_LEGACY_ROUTE: u32 = 1 << 15;
+
+/// The event timer block described by the HPET table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    /// The raw event timer block ID, see [`Hpet::comparators`] and the rest.
+    pub block_id: u32,
+    /// Physical address of the registers.
+    pub address: u64,
+    /// Which HPET of the system this is, 0 for the first.
+    pub number: u8,
+    /// The smallest period in main counter ticks the periodic mode is good for.
+    pub min_tick: u16,
+}
+
+impl Hpet {
+    /// Parses the whole table, header included. Only registers in memory are
+    /// taken, which is where every HPET has them.
+    pub fn parse(bytes: &[u8]) -> Option<Hpet> {
+        let bytes = bytes.get(..HPET_LENGTH)?;
+        let address = &bytes[HPET_ADDRESS..HPET_ADDRESS + 12];
+        if address[0] != GAS_SYSTEM_MEMORY {
+            return None;
+        }
+        Some(Hpet {
+            block_id: u32::from_le_bytes(bytes[HPET_BLOCK_ID..HPET_BLOCK_ID + 4].try_into().unwrap()),
+            address: u64::from_le_bytes(address[4..].try_into().unwrap()),
+            number: bytes[HPET_NUMBER],
+            min_tick: u16::from_le_bytes([bytes[HPET_MIN_TICK], bytes[HPET_MIN_TICK + 1]]),
+        })
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        (self.block_id >> 16) as u16
+    }
+
+    pub fn comparators(&self) -> u8 {
+        ((self.block_id & BLOCK_ID_COMPARATORS) >> 8) as u8 + 1
+    }
+
+    pub fn counter_64bit(&self) -> bool {
+        self.block_id & BLOCK_ID_COUNTER_64 != 0
+    }
+
+    /// Whether comparators 0 and 1 can take over IRQ 0 and IRQ 8.
+    pub fn legacy_route(&self) -> bool {
+        self.block_id & BLOCK_ID_LEGACY_ROUTE != 0
+    }
+}
+
+static HPET: Once<Option<Hpet>> = Once::new();
+
+/// Returns the parsed HPET table, `None` before [`super::init`] or if there is none.
+pub fn hpet() -> Option<&'static Hpet> {
+    TABLES.get()?;
+    HPET.call_once(|| find_table(*b"HPET").and_then(|table| Hpet::parse(table.bytes()))).as_ref()
+}
+
+#[test_case]
+fn qemu_hpet_table() {
+    // QEMU's, after the header: 3 comparators, 64-bit, legacy capable, vendor 8086.
+    let mut table = [0u8; HPET_LENGTH];
+    table[HPET_BLOCK_ID..].copy_from_slice(&[
+        0x01, 0xA2, 0x86, 0x80,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xD0, 0xFE, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ]);
+    let hpet = Hpet::parse(&table).unwrap();
+    assert_eq!(hpet.address, 0xFED0_0000);
+    assert_eq!((hpet.vendor_id(), hpet.comparators()), (0x8086, 3));
+    assert!(hpet.counter_64bit() && hpet.legacy_route());
+    assert_eq!((hpet.number, hpet.min_tick), (0, 0));
+
+    table[HPET_MIN_TICK..HPET_MIN_TICK + 2].copy_from_slice(&0x80u16.to_le_bytes());
+    assert_eq!(Hpet::parse(&table).unwrap().min_tick, 0x80);
+    // Registers in I/O space, or a table cut short.
+    table[HPET_ADDRESS] = 1;
+    assert!(Hpet::parse(&table).is_none());
+    table[HPET_ADDRESS] = GAS_SYSTEM_MEMORY;
+    assert!(Hpet::parse(&table[..HPET_LENGTH - 1]).is_none());
+}
@@ -0,0 +1,246 @@
+//! The multiple APIC description table: local APICs, I/O APICs and how the
+//! legacy ISA IRQs are wired to global system interrupts.
+
+use spin::Once;
+use super::{find_table, TABLES};
+
+/// Entries past these counts are ignored.
+const MAX_CPUS: usize = 64;
+const MAX_IO_APICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+
+/// Local APIC flags: the processor is usable.
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+/// Local APIC flags: the processor can be brought online even though it is not enabled.
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+/// MADT flags: the system also has the two 8259 PICs.
+const PCAT_COMPAT: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    /// The processor's ACPI ID, as used in the DSDT.
+    pub id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    /// Physical address of the register window.
+    pub address: u32,
+    /// First global system interrupt of its redirection entries.
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// An ISA IRQ wired to another GSI, or with other electrical characteristics,
+/// than the identity default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// `None` when the bus default applies.
+    pub polarity: Option<Polarity>,
+    pub trigger: Option<Trigger>,
+}
+
+/// Where an ISA IRQ arrives at the I/O APICs, see [`Madt::irq_route`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// What the MADT says, fixed size so that it can live in a static.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    /// The 8259 PICs are present too and must be masked before using the APICs.
+    pub has_8259: bool,
+    cpus: [Option<Cpu>; MAX_CPUS],
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+fn push<T: Copy>(slots: &mut [Option<T>], value: T) {
+    if let Some(slot) = slots.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(value);
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+impl Madt {
+    /// Parses the table contents after the header. Parsing stops at the first
+    /// entry running past the end, and entries too short for their type are skipped.
+    pub fn parse(data: &[u8]) -> Option<Madt> {
+        if data.len() < 8 {
+            return None;
+        }
+        let mut madt = Madt {
+            local_apic_address: u32_at(data, 0) as u64,
+            has_8259: u32_at(data, 4) & PCAT_COMPAT != 0,
+            cpus: [None; MAX_CPUS],
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+        };
+        let mut rest = &data[8..];
+        while let [kind, length, ..] = *rest {
+            let length = length as usize;
+            if length < 2 || length > rest.len() {
+                break;
+            }
+            let entry = &rest[..length];
+            rest = &rest[length..];
+            match kind {
+                // Neither enabled nor online capable: the slot has no processor.
+                ENTRY_LOCAL_APIC if length >= 8 && u32_at(entry, 4) & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 => {
+                    push(&mut madt.cpus, Cpu {
+                        id: entry[2],
+                        apic_id: entry[3],
+                        enabled: u32_at(entry, 4) & LOCAL_APIC_ENABLED != 0,
+                    });
+                },
+                ENTRY_IO_APIC if length >= 12 => push(&mut madt.io_apics, IoApic {
+                    id: entry[2],
+                    address: u32_at(entry, 4),
+                    gsi_base: u32_at(entry, 8),
+                }),
+                ENTRY_INTERRUPT_OVERRIDE if length >= 10 => {
+                    let flags = u16_at(entry, 8);
+                    push(&mut madt.overrides, InterruptOverride {
+                        irq: entry[3],
+                        gsi: u32_at(entry, 4),
+                        polarity: match flags & 0x3 {
+                            0b01 => Some(Polarity::ActiveHigh),
+                            0b11 => Some(Polarity::ActiveLow),
+                            _ => None,
+                        },
+                        trigger: match (flags >> 2) & 0x3 {
+                            0b01 => Some(Trigger::Edge),
+                            0b11 => Some(Trigger::Level),
+                            _ => None,
+                        },
+                    });
+                },
+                ENTRY_LOCAL_APIC_ADDRESS if length >= 12 => {
+                    madt.local_apic_address = u64::from_le_bytes(entry[4..12].try_into().unwrap());
+                },
+                _ => {},
+            }
+        }
+        Some(madt)
+    }
+
+    /// The processors, enabled or able to be brought online.
+    pub fn cpu_list(&self) -> impl Iterator<Item = Cpu> + '_ {
+        self.cpus.iter().map_while(|cpu| *cpu)
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApic> + '_ {
+        self.io_apics.iter().map_while(|io_apic| *io_apic)
+    }
+
+    pub fn overrides(&self) -> impl Iterator<Item = InterruptOverride> + '_ {
+        self.overrides.iter().map_while(|o| *o)
+    }
+
+    /// Returns where ISA `irq` arrives: its override if there is one, else the
+    /// GSI of the same number. ISA interrupts default to active high, edge triggered.
+    pub fn irq_route(&self, irq: u8) -> IrqRoute {
+        let entry = self.overrides().find(|o| o.irq == irq);
+        IrqRoute {
+            gsi: entry.map_or(irq as u32, |o| o.gsi),
+            polarity: entry.and_then(|o| o.polarity).unwrap_or(Polarity::ActiveHigh),
+            trigger: entry.and_then(|o| o.trigger).unwrap_or(Trigger::Edge),
+        }
+    }
+}
+
+static MADT: Once<Option<Madt>> = Once::new();
+
+/// Returns the parsed MADT, `None` before [`super::init`] or if there is none.
+pub fn madt() -> Option<&'static Madt> {
+    TABLES.get()?;
+    MADT.call_once(|| find_table(*b"APIC").and_then(|table| Madt::parse(table.data()))).as_ref()
+}
+
+/// The MADT body of QEMU's i440fx machine with one CPU, after the 36 byte header.
+#[cfg(test)]
+const QEMU_MADT: [u8; 84] = [
+    0x00, 0x00, 0xE0, 0xFE, 0x01, 0x00, 0x00, 0x00,
+    0x00, 0x08, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+    0x01, 0x0C, 0x00, 0x00, 0x00, 0x00, 0xC0, 0xFE, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x0A, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x02, 0x0A, 0x00, 0x05, 0x05, 0x00, 0x00, 0x00, 0x0D, 0x00,
+    0x02, 0x0A, 0x00, 0x09, 0x09, 0x00, 0x00, 0x00, 0x0D, 0x00,
+    0x02, 0x0A, 0x00, 0x0A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x00,
+    0x02, 0x0A, 0x00, 0x0B, 0x0B, 0x00, 0x00, 0x00, 0x0D, 0x00,
+    0x04, 0x06, 0xFF, 0x00, 0x00, 0x01,
+];
+
+#[test_case]
+fn qemu_madt_parsing() {
+    let madt = Madt::parse(&QEMU_MADT).unwrap();
+    assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+    assert!(madt.has_8259);
+    assert!(madt.cpu_list().eq([Cpu { id: 0, apic_id: 0, enabled: true }]));
+    assert!(madt.io_apics().eq([IoApic { id: 0, address: 0xFEC0_0000, gsi_base: 0 }]));
+    assert_eq!(madt.overrides().count(), 5);
+
+    // The PIT is wired to GSI 2, the keyboard keeps GSI 1, the ACPI SCI is level triggered.
+    assert_eq!(madt.irq_route(0), IrqRoute { gsi: 2, polarity: Polarity::ActiveHigh, trigger: Trigger::Edge });
+    assert_eq!(madt.irq_route(1), IrqRoute { gsi: 1, polarity: Polarity::ActiveHigh, trigger: Trigger::Edge });
+    assert_eq!(madt.irq_route(9), IrqRoute { gsi: 9, polarity: Polarity::ActiveHigh, trigger: Trigger::Level });
+}
+
+#[test_case]
+fn hand_built_madt_overrides() {
+    let mut data = [0u8; 8 + 8 + 8 + 12 + 12 + 10];
+    data[..8].copy_from_slice(&[0x00, 0x00, 0xE0, 0xFE, 0x00, 0x00, 0x00, 0x00]);
+    // An enabled CPU and an absent one, which is left out.
+    data[8..16].copy_from_slice(&[ENTRY_LOCAL_APIC, 8, 0, 0, 0x01, 0, 0, 0]);
+    data[16..24].copy_from_slice(&[ENTRY_LOCAL_APIC, 8, 1, 1, 0x00, 0, 0, 0]);
+    data[24..36].copy_from_slice(&[ENTRY_LOCAL_APIC_ADDRESS, 12, 0, 0, 0x00, 0x00, 0xE0, 0xFE, 0x01, 0, 0, 0]);
+    data[36..48].copy_from_slice(&[ENTRY_IO_APIC, 12, 2, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+
+    let identity = Madt::parse(&data[..48]).unwrap();
+    assert!(!identity.has_8259);
+    assert_eq!(identity.local_apic_address, 0x1_FEE0_0000);
+    assert_eq!(identity.cpu_list().count(), 1);
+    assert_eq!(identity.irq_route(0).gsi, 0);
+
+    // IRQ 0 to GSI 2, active low and level triggered.
+    data[48..58].copy_from_slice(&[ENTRY_INTERRUPT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0x0F, 0x00]);
+    let remapped = Madt::parse(&data).unwrap();
+    assert_eq!(remapped.irq_route(0), IrqRoute { gsi: 2, polarity: Polarity::ActiveLow, trigger: Trigger::Level });
+    assert_eq!(remapped.irq_route(2).gsi, 2);
+
+    // An entry claiming to run past the end stops the parse.
+    data[37] = 200;
+    assert_eq!(Madt::parse(&data).unwrap().io_apics().count(), 0);
+}
@@ -0,0 +1,377 @@
+//! ACPI table discovery: the RSDP, the RSDT or XSDT, and the tables they list.
+//!
+//! Every table is read through the physical memory window and handed out only
+//! after its length and checksum have been verified, so later parsers can trust
+//! [`SdtHeader::length`] to bound their accesses.
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+use core::fmt;
+use spin::Once;
+use crate::{
+    memory::{paging::{map_physical_region, PageTableFlags}, FRAME_ALLOCATOR, MAPPER},
+    print, println,
+};
+pub use fadt::{boot_flags, dsdt, fadt, BootFlags};
+pub use hpet::hpet;
+pub use madt::madt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Bytes of the ACPI 1.0 RSDP covered by its checksum.
+const RSDP_V1_LENGTH: usize = 20;
+/// Bytes of the ACPI 2.0 RSDP, covered by the extended checksum.
+const RSDP_V2_LENGTH: usize = 36;
+
+/// Physical address of the BIOS data area word holding the EBDA segment.
+const EBDA_POINTER: u64 = 0x40E;
+/// Only the first KiB of the EBDA is searched.
+const EBDA_SEARCH_LENGTH: u64 = 0x400;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// Tables longer than this are taken as corrupted rather than checksummed.
+const MAX_TABLE_LENGTH: u32 = 1 << 20;
+/// Tables past this many root table entries are ignored.
+const MAX_TABLES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    /// The physical memory could not be mapped.
+    Unmapped,
+    /// The length is shorter than the header, or implausibly long.
+    BadLength,
+    BadChecksum,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "no RSDP found"),
+            AcpiError::Unmapped => write!(f, "table memory cannot be mapped"),
+            AcpiError::BadLength => write!(f, "bad table length"),
+            AcpiError::BadChecksum => write!(f, "bad checksum"),
+        }
+    }
+}
+
+/// The header shared by all system description tables.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const SDT_HEADER_LENGTH: usize = core::mem::size_of::<SdtHeader>();
+
+impl SdtHeader {
+    pub fn signature_str(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    /// The whole table, header included, `length` bytes.
+    pub fn bytes(&self) -> &[u8] {
+        // Headers are only handed out after the table was checked to be mapped
+        // and checksummed over `length` bytes.
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, self.length as usize) }
+    }
+
+    /// The table contents after the header.
+    pub fn data(&self) -> &[u8] {
+        &self.bytes()[SDT_HEADER_LENGTH..]
+    }
+}
+
+/// Where the physical memory window is, and whether missing pages of it may be
+/// mapped on demand.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    offset: u64,
+    map_missing: bool,
+}
+
+impl Window {
+    /// Returns `len` bytes of physical memory at `phys`.
+    ///
+    /// ## Safety
+    ///
+    /// With `map_missing` unset, the bytes must be mapped at `phys + offset`.
+    unsafe fn bytes(&self, phys: u64, len: usize) -> Result<&'static [u8], AcpiError> {
+        if self.map_missing {
+            let flags = PageTableFlags::PRESENT;
+            let mut mapper = MAPPER.lock();
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+                return Err(AcpiError::Unmapped);
+            };
+            unsafe { map_physical_region(phys, len as u64, flags, mapper, allocator) }
+                .map_err(|_| AcpiError::Unmapped)?;
+        }
+        Ok(unsafe { core::slice::from_raw_parts(phys.wrapping_add(self.offset) as *const u8, len) })
+    }
+
+    /// Checks the table at `phys` and returns its header.
+    unsafe fn table(&self, phys: u64) -> Result<&'static SdtHeader, AcpiError> {
+        let header = unsafe { self.bytes(phys, SDT_HEADER_LENGTH)? };
+        let length = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if length < SDT_HEADER_LENGTH as u32 || length > MAX_TABLE_LENGTH {
+            return Err(AcpiError::BadLength);
+        }
+        let table = unsafe { self.bytes(phys, length as usize)? };
+        if !checksum_ok(table) {
+            return Err(AcpiError::BadChecksum);
+        }
+        Ok(unsafe { &*(table.as_ptr() as *const SdtHeader) })
+    }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// The root table named by the RSDP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RootTable {
+    address: u64,
+    /// 4 for the RSDT, 8 for the XSDT.
+    entry_size: usize,
+}
+
+/// Validates an RSDP candidate, which may be shorter than a 2.0 RSDP at the
+/// end of the search area.
+fn parse_rsdp(bytes: &[u8]) -> Option<(u8, RootTable)> {
+    if bytes.len() < RSDP_V1_LENGTH || &bytes[..8] != RSDP_SIGNATURE || !checksum_ok(&bytes[..RSDP_V1_LENGTH]) {
+        return None;
+    }
+    let revision = bytes[15];
+    let rsdt = RootTable {
+        address: u32::from_le_bytes(bytes[16..20].try_into().unwrap()) as u64,
+        entry_size: 4,
+    };
+    if revision < 2 {
+        return Some((revision, rsdt));
+    }
+    let extended = bytes.get(..RSDP_V2_LENGTH)?;
+    if !checksum_ok(extended) {
+        return None;
+    }
+    let xsdt = u64::from_le_bytes(extended[24..32].try_into().unwrap());
+    // Some firmware has an ACPI 2.0 RSDP but only an RSDT.
+    Some((revision, if xsdt != 0 { RootTable { address: xsdt, entry_size: 8 } } else { rsdt }))
+}
+
+/// Scans `start..end` on 16 byte boundaries for the RSDP.
+unsafe fn search_rsdp(window: &Window, start: u64, end: u64) -> Option<(u64, u8, RootTable)> {
+    let area = unsafe { window.bytes(start, (end - start) as usize) }.ok()?;
+    (0..area.len()).step_by(16).find_map(|i| {
+        let candidate = &area[i..(i + RSDP_V2_LENGTH).min(area.len())];
+        parse_rsdp(candidate).map(|(revision, root)| (start + i as u64, revision, root))
+    })
+}
+
+/// Finds the RSDP in the first KiB of the EBDA or in the BIOS read-only area.
+unsafe fn find_rsdp(window: &Window) -> Option<(u64, u8, RootTable)> {
+    let ebda_segment = unsafe { window.bytes(EBDA_POINTER, 2) }.ok()?;
+    let ebda = (u16::from_le_bytes([ebda_segment[0], ebda_segment[1]]) as u64) << 4;
+    let in_ebda = if ebda >= 0x400 && ebda < BIOS_AREA_START {
+        unsafe { search_rsdp(window, ebda, ebda + EBDA_SEARCH_LENGTH) }
+    } else {
+        None
+    };
+    in_ebda.or_else(|| unsafe { search_rsdp(window, BIOS_AREA_START, BIOS_AREA_END) })
+}
+
+/// The verified tables listed by the root table.
+pub struct Tables {
+    /// Revision of the RSDP, 0 for ACPI 1.0.
+    pub revision: u8,
+    pub root: &'static SdtHeader,
+    tables: [Option<&'static SdtHeader>; MAX_TABLES],
+    /// For tables found later, like the DSDT named by the FADT.
+    window: Window,
+}
+
+impl Tables {
+    pub fn iter(&self) -> impl Iterator<Item = &'static SdtHeader> + '_ {
+        self.tables.iter().map_while(|table| *table)
+    }
+
+    pub fn find(&self, signature: [u8; 4]) -> Option<&'static SdtHeader> {
+        self.iter().find(|table| table.signature == signature)
+    }
+
+    /// Verifies and returns a table that is not listed by the root table.
+    ///
+    /// ## Safety
+    ///
+    /// `address` must come from another table, not be made up.
+    unsafe fn load(&self, address: u64) -> Result<&'static SdtHeader, AcpiError> {
+        unsafe { self.window.table(address) }
+    }
+}
+
+/// Reads the root table and verifies every table it lists, skipping bad ones
+/// with a warning.
+unsafe fn parse_root(window: &Window, revision: u8, root: RootTable) -> Result<Tables, AcpiError> {
+    let header = unsafe { window.table(root.address)? };
+    let mut tables = Tables { revision, root: header, tables: [None; MAX_TABLES], window: *window };
+    // A trailing partial entry is ignored.
+    let entries = header.data().chunks_exact(root.entry_size);
+    let mut slots = tables.tables.iter_mut();
+    for entry in entries {
+        let address = match *entry {
+            [a, b, c, d] => u32::from_le_bytes([a, b, c, d]) as u64,
+            _ => u64::from_le_bytes(entry.try_into().unwrap()),
+        };
+        match unsafe { window.table(address) } {
+            Ok(table) => match slots.next() {
+                Some(slot) => *slot = Some(table),
+                None => {
+                    println!("ACPI: more than {} tables, ignoring the rest", MAX_TABLES);
+                    break;
+                },
+            },
+            Err(err) => println!("ACPI: skipping table at {:#x}: {}", address, err),
+        }
+    }
+    Ok(tables)
+}
+
+static TABLES: Once<Tables> = Once::new();
+
+/// Finds the RSDP, or takes `rsdp_address` when the bootloader reported one,
+/// and verifies the tables of the RSDT or XSDT. Prints what was found.
+///
+/// ## Safety
+///
+/// [`crate::memory::init`] must have run, with the physical memory window at
+/// `physical_memory_offset`.
+pub unsafe fn init(physical_memory_offset: u64, rsdp_address: Option<u64>) -> Result<&'static Tables, AcpiError> {
+    let window = Window { offset: physical_memory_offset, map_missing: true };
+    let rsdp = match rsdp_address {
+        Some(address) => unsafe { window.bytes(address, RSDP_V2_LENGTH) }
+            .ok()
+            .and_then(parse_rsdp)
+            .map(|(revision, root)| (address, revision, root)),
+        None => unsafe { find_rsdp(&window) },
+    };
+    let (address, revision, root) = rsdp.ok_or(AcpiError::NoRsdp)?;
+    let tables = unsafe { parse_root(&window, revision, root)? };
+    let tables = TABLES.call_once(|| tables);
+
+    print!("ACPI: RSDP revision {} at {:#x}, {}:", revision, address, tables.root.signature_str());
+    for table in tables.iter() {
+        print!(" {}", table.signature_str());
+    }
+    println!();
+    Ok(tables)
+}
+
+/// Returns the first table with `signature`, `None` before [`init`].
+pub fn find_table(signature: [u8; 4]) -> Option<&'static SdtHeader> {
+    TABLES.get()?.find(signature)
+}
+
+/// Where the synthetic tables pretend to be in physical memory.
+#[cfg(test)]
+const BLOB_BASE: u64 = 0x7FE_0000;
+
+#[cfg(test)]
+fn blob_window(blob: &[u8]) -> Window {
+    Window { offset: (blob.as_ptr() as u64).wrapping_sub(BLOB_BASE), map_missing: false }
+}
+
+/// Writes a table header with `length` at `at`, followed by `entries`, and fixes its checksum.
+#[cfg(test)]
+fn put_table(blob: &mut [u8], at: usize, signature: &[u8; 4], length: u32, entries: &[u8]) {
+    blob[at..at + 4].copy_from_slice(signature);
+    blob[at + 4..at + 8].copy_from_slice(&length.to_le_bytes());
+    blob[at + SDT_HEADER_LENGTH..at + SDT_HEADER_LENGTH + entries.len()].copy_from_slice(entries);
+    let end = at + (length as usize).clamp(SDT_HEADER_LENGTH, blob.len() - at);
+    let sum = blob[at..end].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    blob[at + 9] = 0u8.wrapping_sub(sum);
+}
+
+/// An RSDP of `revision` at 0 with an RSDT at 0x40 and an XSDT at 0x80, both
+/// listing an `APIC` and an `HPET` table, one with a bad checksum and one
+/// shorter than its header.
+#[cfg(test)]
+fn acpi_blob(revision: u8) -> [u8; 0x200] {
+    let mut blob = [0u8; 0x200];
+    let table = |offset: u64| BLOB_BASE + offset;
+    let addresses = [table(0x100), table(0x140), table(0x180), table(0x1C0)];
+
+    blob[..8].copy_from_slice(RSDP_SIGNATURE);
+    blob[15] = revision;
+    blob[16..20].copy_from_slice(&(table(0x40) as u32).to_le_bytes());
+    blob[20..24].copy_from_slice(&(RSDP_V2_LENGTH as u32).to_le_bytes());
+    blob[24..32].copy_from_slice(&table(0x80).to_le_bytes());
+    blob[8] = 0u8.wrapping_sub(blob[..RSDP_V1_LENGTH].iter().fold(0u8, |s, &b| s.wrapping_add(b)));
+    blob[32] = 0u8.wrapping_sub(blob[..RSDP_V2_LENGTH].iter().fold(0u8, |s, &b| s.wrapping_add(b)));
+
+    let mut rsdt = [0u8; 16];
+    let mut xsdt = [0u8; 32];
+    for (i, &address) in addresses.iter().enumerate() {
+        rsdt[i * 4..i * 4 + 4].copy_from_slice(&(address as u32).to_le_bytes());
+        xsdt[i * 8..i * 8 + 8].copy_from_slice(&address.to_le_bytes());
+    }
+    put_table(&mut blob, 0x40, b"RSDT", 36 + 16, &rsdt);
+    put_table(&mut blob, 0x80, b"XSDT", 36 + 32, &xsdt);
+    put_table(&mut blob, 0x100, b"APIC", 44, &[0xAA; 8]);
+    put_table(&mut blob, 0x140, b"HPET", 56, &[0x55; 20]);
+    put_table(&mut blob, 0x180, b"FACP", 36, &[]);
+    blob[0x180 + 20] ^= 1;
+    put_table(&mut blob, 0x1C0, b"SSDT", 20, &[]);
+    blob
+}
+
+#[test_case]
+fn rsdt_and_xsdt_parsing() {
+    for (revision, root_signature) in [(0, b"RSDT"), (2, b"XSDT")] {
+        let blob = acpi_blob(revision);
+        let window = blob_window(&blob);
+        let (rsdp_revision, root) = parse_rsdp(&blob[..RSDP_V2_LENGTH]).unwrap();
+        assert_eq!(rsdp_revision, revision);
+        assert_eq!(root.entry_size, if revision == 0 { 4 } else { 8 });
+
+        let tables = unsafe { parse_root(&window, revision, root) }.unwrap();
+        assert_eq!(&tables.root.signature, root_signature);
+        // The bad FACP and the truncated SSDT are skipped.
+        assert_eq!(tables.iter().count(), 2);
+        let apic = tables.find(*b"APIC").unwrap();
+        assert_eq!((apic.length, apic.data()), (44, &[0xAA; 8][..]));
+        assert_eq!(tables.find(*b"HPET").unwrap().data().len(), 20);
+        assert!(tables.find(*b"FACP").is_none());
+        assert!(tables.find(*b"SSDT").is_none());
+    }
+}
+
+#[test_case]
+fn rsdp_search_and_checksums() {
+    let mut blob = acpi_blob(2);
+    let window = blob_window(&blob);
+    let found = unsafe { search_rsdp(&window, BLOB_BASE, BLOB_BASE + 0x40) };
+    assert_eq!(found.map(|(address, revision, _)| (address, revision)), Some((BLOB_BASE, 2)));
+
+    // A 2.0 RSDP must pass the extended checksum too, and must not be cut short.
+    blob[30] ^= 1;
+    assert!(parse_rsdp(&blob[..RSDP_V2_LENGTH]).is_none());
+    assert!(parse_rsdp(&blob[..RSDP_V1_LENGTH]).is_none());
+    blob[8] ^= 1;
+    assert!(parse_rsdp(&blob[..RSDP_V2_LENGTH]).is_none());
+
+    let blob = acpi_blob(0);
+    let window = blob_window(&blob);
+    assert_eq!(unsafe { window.table(BLOB_BASE + 0x180) }.unwrap_err(), AcpiError::BadChecksum);
+    assert_eq!(unsafe { window.table(BLOB_BASE + 0x1C0) }.unwrap_err(), AcpiError::BadLength);
+}
@@ -0,0 +1,42 @@
+//! Adapter between the `bootloader` crate and [`crate::kernel_main_raw`].
+//!
+//! Everything that knows the layout of `bootloader::BootInfo` lives here, so
+//! booting through another bootloader only needs its own adapter.
+
+use bootloader::{bootinfo::MemoryRegionType, BootInfo};
+use spin::Once;
+use crate::memory::{MemoryRegion, MemoryRegionKind};
+
+/// The bootloader passes at most 64 regions.
+const MAX_MEMORY_REGIONS: usize = 64;
+
+static MEMORY_MAP: Once<([MemoryRegion; MAX_MEMORY_REGIONS], usize)> = Once::new();
+
+/// Converts the bootloader's memory map, regions past `MAX_MEMORY_REGIONS` are dropped.
+pub fn memory_map(boot_info: &'static BootInfo) -> &'static [MemoryRegion] {
+    let (regions, len) = MEMORY_MAP.call_once(|| {
+        let mut regions = [MemoryRegion::EMPTY; MAX_MEMORY_REGIONS];
+        let mut len = 0;
+        for (region, boot_region) in regions.iter_mut().zip(boot_info.memory_map.iter()) {
+            *region = MemoryRegion {
+                start: boot_region.range.start_addr(),
+                end: boot_region.range.end_addr(),
+                kind: region_kind(boot_region.region_type),
+            };
+            len += 1;
+        }
+        (regions, len)
+    });
+    &regions[..*len]
+}
+
+fn region_kind(region_type: MemoryRegionType) -> MemoryRegionKind {
+    match region_type {
+        MemoryRegionType::Usable => MemoryRegionKind::Usable,
+        MemoryRegionType::AcpiReclaimable => MemoryRegionKind::AcpiReclaimable,
+        MemoryRegionType::AcpiNvs => MemoryRegionKind::AcpiNvs,
+        MemoryRegionType::BadMemory => MemoryRegionKind::BadMemory,
+        // The kernel, its stack, page tables, boot information, ...
+        _ => MemoryRegionKind::Reserved,
+    }
+}
@@ -0,0 +1,266 @@
+//! Idling the CPU until there is something to do, in the deepest C-state
+//! worth it.
+//!
+//! With MONITOR/MWAIT, [`idle_once`] arms the monitor on the work pending line
+//! of the CPU and waits there, so that [`wake`] only has to write that line;
+//! waking a CPU that idles on `hlt` would take an IPI as well. The C-state is
+//! picked from what `cpuid` leaf 5 lists and how long the last idle periods
+//! of the CPU lasted.
+
+use core::{arch::asm, sync::atomic::{AtomicU64, Ordering}};
+use spin::Once;
+use crate::{smp::cpu_index, tables::gdt::MAX_CPUS};
+use super::{cpuid, max_leaf, rdtsc, tsc_frequency};
+
+/// `cpuid` leaf 1, ECX.
+const CPUID_MONITOR: u32 = 1 << 3;
+/// `cpuid` leaf 5, ECX: the sub-states in EDX are valid, and an interrupt
+/// breaks MWAIT even while masked.
+const CPUID_MWAIT_EXTENSIONS: u32 = 1 << 0;
+const CPUID_MWAIT_INTERRUPT_BREAK: u32 = 1 << 1;
+
+/// C0, where nothing waits, to C7, the most the MWAIT hints name.
+pub const C_STATES: usize = 8;
+/// How long an idle period has to last for each C-state to save more than
+/// entering and leaving it costs, in nanoseconds. The figures are in the
+/// range of what Intel lists for its cores.
+const TARGET_RESIDENCY_NS: [u64; C_STATES] = [0, 0, 20_000, 100_000, 200_000, 400_000, 800_000, 1_600_000];
+/// The clock assumed when `cpuid` does not tell the TSC frequency. Only the
+/// C-state choice and the times in [`IdleStats`] depend on it.
+const ASSUMED_TSC_HZ: u64 = 1_000_000_000;
+
+/// Cached, `cpuid` traps to the hypervisor.
+static MWAIT: Once<Option<MwaitInfo>> = Once::new();
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// A flag on its own cache line, so that the monitor only fires when it is written.
+#[repr(C, align(64))]
+struct WorkLine {
+    pending: AtomicU64,
+}
+
+static WORK: [WorkLine; MAX_CPUS] = [const { WorkLine { pending: AtomicU64::new(0) } }; MAX_CPUS];
+/// The expected length of the next idle period of each CPU, in nanoseconds.
+static PREDICTED_NS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Idle periods by the C-state they were spent in, 0 counting `hlt`.
+static STATE_COUNTS: [AtomicU64; C_STATES] = [const { AtomicU64::new(0) }; C_STATES];
+static IDLE_PERIODS: AtomicU64 = AtomicU64::new(0);
+static IDLE_NS: AtomicU64 = AtomicU64::new(0);
+static LAST_IDLE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// What `cpuid` leaf 5 says about MONITOR/MWAIT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MwaitInfo {
+    /// The smallest and largest monitor line sizes, in bytes.
+    pub smallest_line: u16,
+    pub largest_line: u16,
+    /// An interrupt ends MWAIT even with interrupts disabled.
+    pub interrupt_break: bool,
+    /// The number of sub-states of each C-state, C0 first. All zero if the
+    /// CPU does not list them, C1 can still be asked for.
+    pub sub_states: [u8; C_STATES],
+}
+
+impl MwaitInfo {
+    /// Decodes the registers of `cpuid` leaf 5.
+    pub fn from_cpuid(eax: u32, ebx: u32, ecx: u32, edx: u32) -> Self {
+        let extensions = ecx & CPUID_MWAIT_EXTENSIONS != 0;
+        MwaitInfo {
+            smallest_line: eax as u16,
+            largest_line: ebx as u16,
+            interrupt_break: extensions && ecx & CPUID_MWAIT_INTERRUPT_BREAK != 0,
+            sub_states: core::array::from_fn(|state| if extensions { (edx >> (state * 4) & 0xF) as u8 } else { 0 }),
+        }
+    }
+
+    /// Returns the deepest C-state with sub-states whose target residency
+    /// fits in `predicted_ns`, and the MWAIT hint for its first sub-state.
+    /// C1 when none does.
+    pub fn choose(&self, predicted_ns: u64) -> (usize, u32) {
+        let state = (2..C_STATES).rev()
+            .find(|&state| self.sub_states[state] != 0 && TARGET_RESIDENCY_NS[state] <= predicted_ns)
+            .unwrap_or(1);
+        (state, ((state - 1) as u32) << 4)
+    }
+}
+
+/// Returns the MONITOR/MWAIT details, `None` if the CPU has no MWAIT.
+pub fn mwait_info() -> Option<MwaitInfo> {
+    *MWAIT.call_once(|| {
+        if cpuid(1, 0).ecx & CPUID_MONITOR == 0 || max_leaf() < 5 {
+            return None;
+        }
+        let leaf = cpuid(5, 0);
+        Some(MwaitInfo::from_cpuid(leaf.eax, leaf.ebx, leaf.ecx, leaf.edx))
+    })
+}
+
+/// The TSC frequency, [`ASSUMED_TSC_HZ`] if `cpuid` does not tell.
+pub(crate) fn tsc_hz() -> u64 {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => {
+            let hz = tsc_frequency().unwrap_or(ASSUMED_TSC_HZ);
+            TSC_HZ.store(hz, Ordering::Relaxed);
+            hz
+        },
+        hz => hz,
+    }
+}
+
+/// Marks work pending for CPU `cpu`, ending its MWAIT.
+///
+/// A CPU idling on `hlt` only notices at its next interrupt.
+pub fn wake(cpu: usize) {
+    WORK[cpu].pending.store(1, Ordering::Release);
+}
+
+/// Takes the work pending mark of the current CPU, returning whether it was set.
+pub fn take_work() -> bool {
+    WORK[cpu_index()].pending.swap(0, Ordering::AcqRel) != 0
+}
+
+/// Waits for an interrupt or for work to be marked pending, unless it already
+/// is, and returns whether it is. The mark is left for [`take_work`].
+///
+/// Interrupts must be enabled, and are on return.
+pub fn idle_once() -> bool {
+    let cpu = cpu_index();
+    let pending = &WORK[cpu].pending;
+    let info = mwait_info();
+    let (state, hint) = match &info {
+        Some(info) => info.choose(PREDICTED_NS[cpu].load(Ordering::Relaxed)),
+        None => (0, 0),
+    };
+    let start = rdtsc();
+    // With interrupts disabled from the check to the wait, one queueing work
+    // in between is only taken after `sti`, whose shadow covers the next
+    // instruction: it ends the wait rather than come before it.
+    unsafe { asm!("cli", options(nomem, nostack)); }
+    if pending.load(Ordering::Acquire) != 0 {
+        unsafe { asm!("sti", options(nomem, nostack)); }
+        return true;
+    }
+    if info.is_some() {
+        unsafe {
+            asm!("monitor", in("rax") pending.as_ptr(), in("ecx") 0u32, in("edx") 0u32, options(nostack, readonly, preserves_flags));
+        }
+        // A write between the check and the monitor would not end the wait.
+        if pending.load(Ordering::Acquire) != 0 {
+            unsafe { asm!("sti", options(nomem, nostack)); }
+            return true;
+        }
+        unsafe { asm!("sti; mwait", in("eax") hint, in("ecx") 0u32, options(nomem, nostack)); }
+    } else {
+        unsafe { asm!("sti; hlt", options(nomem, nostack)); }
+    }
+    record(cpu, state, rdtsc().wrapping_sub(start));
+    pending.load(Ordering::Acquire) != 0
+}
+
+/// Counts an idle period of `cycles` TSC cycles in `state`, and moves the
+/// prediction of the CPU an eighth of the way towards it.
+fn record(cpu: usize, state: usize, cycles: u64) {
+    let ns = (cycles as u128 * 1_000_000_000 / tsc_hz() as u128) as u64;
+    STATE_COUNTS[state].fetch_add(1, Ordering::Relaxed);
+    IDLE_PERIODS.fetch_add(1, Ordering::Relaxed);
+    IDLE_NS.fetch_add(ns, Ordering::Relaxed);
+    LAST_IDLE_NS.store(ns, Ordering::Relaxed);
+    let predicted = PREDICTED_NS[cpu].load(Ordering::Relaxed);
+    PREDICTED_NS[cpu].store(predicted - predicted / 8 + ns / 8, Ordering::Relaxed);
+}
+
+/// Idle periods of all CPUs since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleStats {
+    pub periods: u64,
+    pub idle_ns: u64,
+    pub last_ns: u64,
+    /// The periods spent in each C-state, `hlt` counted as C0.
+    pub states: [u64; C_STATES],
+}
+
+pub fn stats() -> IdleStats {
+    IdleStats {
+        periods: IDLE_PERIODS.load(Ordering::Relaxed),
+        idle_ns: IDLE_NS.load(Ordering::Relaxed),
+        last_ns: LAST_IDLE_NS.load(Ordering::Relaxed),
+        states: core::array::from_fn(|state| STATE_COUNTS[state].load(Ordering::Relaxed)),
+    }
+}
+
+#[test_case]
+fn leaf_5_decoding_and_choice() {
+    // A client core: 64 byte lines, C1 to C3 and C6 with two, one, one and
+    // four sub-states.
+    let info = MwaitInfo::from_cpuid(0x40, 0x40, 0b11, 0x0400_1120);
+    assert_eq!((info.smallest_line, info.largest_line, info.interrupt_break), (64, 64, true));
+    assert_eq!(info.sub_states, [0, 2, 1, 1, 0, 0, 4, 0]);
+    assert_eq!(info.choose(0), (1, 0x00));
+    assert_eq!(info.choose(50_000), (2, 0x10));
+    assert_eq!(info.choose(150_000), (3, 0x20));
+    // C4 and C5 have no sub-states.
+    assert_eq!(info.choose(500_000), (3, 0x20));
+    assert_eq!(info.choose(u64::MAX), (6, 0x50));
+
+    // Without the extensions EDX means nothing, only C1 is asked for.
+    let info = MwaitInfo::from_cpuid(0x40, 0x40, 0, 0x0004_1120);
+    assert_eq!((info.interrupt_break, info.sub_states), (false, [0; C_STATES]));
+    assert_eq!(info.choose(u64::MAX), (1, 0x00));
+}
+
+/// Work queued by the tick hook: the tick it was queued in, 0 when taken.
+#[cfg(test)]
+static QUEUED_AT: AtomicU64 = AtomicU64::new(0);
+#[cfg(test)]
+static NEXT_QUEUE: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(test)]
+fn queue_work_now_and_then() {
+    use crate::pic::timer::ticks;
+
+    let now = ticks();
+    if now < NEXT_QUEUE.load(Ordering::Relaxed) || QUEUED_AT.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+    // The TSC is random enough for this, and takes no lock in the interrupt.
+    NEXT_QUEUE.store(now + 1 + rdtsc() % 3, Ordering::Relaxed);
+    QUEUED_AT.store(now, Ordering::Relaxed);
+    wake(0);
+}
+
+#[test_case]
+fn work_queued_by_interrupts_is_not_missed() {
+    use crate::pic::timer::{set_tick_hook, ticks};
+
+    const ROUNDS: u64 = 20;
+    let before = stats();
+    NEXT_QUEUE.store(0, Ordering::Relaxed);
+    let previous = set_tick_hook(Some(queue_work_now_and_then));
+    let deadline = ticks() + ROUNDS * 6;
+    let mut taken = 0;
+    while taken < ROUNDS && ticks() < deadline {
+        if !idle_once() {
+            continue;
+        }
+        // The interrupt that queued the work ended the wait: taking it a tick
+        // later means it was queued between the check and the wait.
+        let queued_at = QUEUED_AT.load(Ordering::Relaxed);
+        assert_eq!(ticks(), queued_at, "work from tick {} taken late", queued_at);
+        assert!(take_work());
+        QUEUED_AT.store(0, Ordering::Relaxed);
+        taken += 1;
+    }
+    set_tick_hook(previous);
+    assert_eq!(taken, ROUNDS, "work taken {} times", taken);
+    assert!(!take_work());
+
+    let after = stats();
+    let periods = after.periods - before.periods;
+    assert!(periods >= ROUNDS, "{} idle periods", periods);
+    let counted: u64 = (0..C_STATES).map(|state| after.states[state] - before.states[state]).sum();
+    assert_eq!(counted, periods);
+    if mwait_info().is_none() {
+        assert_eq!(after.states[0] - before.states[0], periods);
+    }
+}
@@ -0,0 +1,172 @@
+//! CPU identification, the timestamp counter and model specific registers.
+
+pub mod idle;
+pub mod random;
+pub mod thermal;
+
+use core::{arch::asm, sync::atomic::AtomicU64};
+use crate::tables::without_interrupts;
+
+/// Where the general protection fault handler resumes when an MSR access of
+/// [`Msr::read_safe`] or [`Msr::write_safe`] faults, 0 outside of one.
+pub(crate) static MSR_FIXUP: AtomicU64 = AtomicU64::new(0);
+
+/// The registers returned by `cpuid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Runs `cpuid` for `leaf` and `subleaf`.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        // RBX cannot be an asm operand, save it around the instruction.
+        asm!(
+            "mov {rbx_save:r}, rbx",
+            "cpuid",
+            "xchg {rbx_save:r}, rbx",
+            rbx_save = out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+/// Returns the highest basic `cpuid` leaf.
+pub fn max_leaf() -> u32 {
+    cpuid(0, 0).eax
+}
+
+/// Reads the timestamp counter.
+#[inline]
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Returns the TSC frequency in Hz as reported by `cpuid`, if the CPU tells.
+///
+/// Leaf 0x15 gives the exact ratio to the core crystal clock, leaf 0x16 only the
+/// nominal base frequency in MHz. Many virtual CPUs report neither.
+pub fn tsc_frequency() -> Option<u64> {
+    let max_leaf = max_leaf();
+    if max_leaf >= 0x15 {
+        let CpuidResult { eax: denominator, ebx: numerator, ecx: crystal_hz, .. } = cpuid(0x15, 0);
+        if denominator != 0 && numerator != 0 && crystal_hz != 0 {
+            return Some(crystal_hz as u64 * numerator as u64 / denominator as u64);
+        }
+    }
+    if max_leaf >= 0x16 {
+        let base_mhz = cpuid(0x16, 0).eax & 0xFFFF;
+        if base_mhz != 0 {
+            return Some(base_mhz as u64 * 1_000_000);
+        }
+    }
+    None
+}
+
+/// A model specific register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(u32);
+
+impl Msr {
+    pub const fn new(reg: u32) -> Self {
+        Msr(reg)
+    }
+
+    /// ## Safety
+    ///
+    /// The register must exist on this CPU, `rdmsr` faults otherwise.
+    #[inline]
+    pub unsafe fn read(&self) -> u64 {
+        let (low, high): (u32, u32);
+        unsafe {
+            asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        }
+        (high as u64) << 32 | low as u64
+    }
+
+    /// ## Safety
+    ///
+    /// The register must exist on this CPU, and writing it can change how any
+    /// code or memory behaves.
+    #[inline]
+    pub unsafe fn write(&mut self, value: u64) {
+        let low = value as u32;
+        let high = (value >> 32) as u32;
+        unsafe {
+            asm!("wrmsr", in("ecx") self.0, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+        }
+    }
+
+    /// Reads the register, or returns `None` if `rdmsr` faults because the
+    /// CPU or the hypervisor does not implement it.
+    pub fn read_safe(&self) -> Option<u64> {
+        // An interrupt handler faulting in the middle would take the fixup.
+        let (low, high, ok): (u32, u32, u32) = without_interrupts(|| unsafe {
+            let (low, high, ok): (u32, u32, u32);
+            asm!(
+                "lea {tmp}, [rip + 2f]",
+                "mov [{fixup}], {tmp}",
+                "rdmsr",
+                "mov {ok:e}, 1",
+                "jmp 3f",
+                "2:",
+                "xor {ok:e}, {ok:e}",
+                "3:",
+                "mov qword ptr [{fixup}], 0",
+                fixup = in(reg) MSR_FIXUP.as_ptr(),
+                tmp = out(reg) _,
+                ok = out(reg) ok,
+                in("ecx") self.0,
+                out("eax") low,
+                out("edx") high,
+                options(nostack),
+            );
+            (low, high, ok)
+        });
+        (ok != 0).then_some((high as u64) << 32 | low as u64)
+    }
+
+    /// Writes the register, returning `false` if `wrmsr` faults because the
+    /// register does not exist or does not take `value`.
+    ///
+    /// ## Safety
+    ///
+    /// As for [`Msr::write`], short of the register having to exist.
+    pub unsafe fn write_safe(&mut self, value: u64) -> bool {
+        let ok: u32 = without_interrupts(|| unsafe {
+            let ok: u32;
+            asm!(
+                "lea {tmp}, [rip + 2f]",
+                "mov [{fixup}], {tmp}",
+                "wrmsr",
+                "mov {ok:e}, 1",
+                "jmp 3f",
+                "2:",
+                "xor {ok:e}, {ok:e}",
+                "3:",
+                "mov qword ptr [{fixup}], 0",
+                fixup = in(reg) MSR_FIXUP.as_ptr(),
+                tmp = out(reg) _,
+                ok = out(reg) ok,
+                in("ecx") self.0,
+                in("eax") value as u32,
+                in("edx") (value >> 32) as u32,
+                options(nostack),
+            );
+            ok
+        });
+        ok != 0
+    }
+}
@@ -0,0 +1,375 @@
+//! Random numbers: RDRAND when the CPU has it, and otherwise a ChaCha20
+//! generator seeded from whatever entropy there is, so that [`u64`],
+//! [`fill`] and [`range`] always work.
+//!
+//! The generator mixes in RDSEED or RDRAND output when it can, the TSC, the
+//! RTC and the timing of keyboard interrupts. [`health`] tells how much of
+//! that came from the hardware generator.
+
+use core::{arch::asm, fmt, sync::atomic::{AtomicU64, AtomicU8, Ordering}};
+use crate::{sync::Mutex, tables::{port::Port, without_interrupts}};
+use super::{cpuid, max_leaf, rdtsc};
+
+/// `cpuid` leaf 1, ECX, and leaf 7, EBX.
+const CPUID_RDRAND: u32 = 1 << 30;
+const CPUID_RDSEED: u32 = 1 << 18;
+
+/// Intel's DRNG guide: RDRAND only fails repeatedly when the hardware is
+/// broken, 10 tries are enough. RDSEED runs dry under load and needs more.
+const RDRAND_RETRIES: u32 = 10;
+const RDSEED_RETRIES: u32 = 100;
+
+/// Cached `cpuid` bits, `cpuid` traps to the hypervisor.
+const FEATURES_KNOWN: u8 = 1 << 0;
+const FEATURE_RDRAND: u8 = 1 << 1;
+const FEATURE_RDSEED: u8 = 1 << 2;
+static FEATURES: AtomicU8 = AtomicU8::new(0);
+
+/// Hardware values taken for every reseed.
+const SEED_WORDS: usize = 4;
+/// Outputs of the generator before it is reseeded.
+const RESEED_INTERVAL: u64 = 1 << 16;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// RTC seconds, minutes, hours, day, month and year.
+const RTC_REGISTERS: [u8; 6] = [0x00, 0x02, 0x04, 0x07, 0x08, 0x09];
+
+/// TSC values of keyboard interrupts folded together, and how many there were.
+static JITTER: AtomicU64 = AtomicU64::new(0);
+static JITTER_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+static GENERATOR: Mutex<Generator> = Mutex::new("RANDOM", Generator::new());
+
+fn features() -> u8 {
+    let features = FEATURES.load(Ordering::Relaxed);
+    if features & FEATURES_KNOWN != 0 {
+        return features;
+    }
+    let mut features = FEATURES_KNOWN;
+    if cpuid(1, 0).ecx & CPUID_RDRAND != 0 {
+        features |= FEATURE_RDRAND;
+    }
+    if max_leaf() >= 7 && cpuid(7, 0).ebx & CPUID_RDSEED != 0 {
+        features |= FEATURE_RDSEED;
+    }
+    FEATURES.store(features, Ordering::Relaxed);
+    features
+}
+
+pub fn has_rdrand() -> bool {
+    features() & FEATURE_RDRAND != 0
+}
+
+pub fn has_rdseed() -> bool {
+    features() & FEATURE_RDSEED != 0
+}
+
+/// A value from the DRNG, `None` without RDRAND or if it keeps failing.
+pub fn rdrand64() -> Option<u64> {
+    if !has_rdrand() {
+        return None;
+    }
+    for _ in 0..RDRAND_RETRIES {
+        let (value, ok): (u64, u8);
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A value straight from the entropy source, `None` without RDSEED or if it
+/// stays exhausted.
+pub fn rdseed64() -> Option<u64> {
+    if !has_rdseed() {
+        return None;
+    }
+    for _ in 0..RDSEED_RETRIES {
+        let (value, ok): (u64, u8);
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// Records the time of an interrupt whose timing depends on the outside
+/// world. Lock free, for interrupt handlers.
+pub fn add_event_jitter() {
+    let events = JITTER_EVENTS.fetch_add(1, Ordering::Relaxed);
+    JITTER.fetch_xor(rdtsc().rotate_left(events as u32 % 64), Ordering::Relaxed);
+}
+
+/// The RTC registers packed together. Not much entropy, but it differs
+/// between boots, unlike the rest on a CPU without RDRAND.
+fn read_rtc() -> u64 {
+    let (address, data) = (Port::new(CMOS_ADDRESS), Port::new(CMOS_DATA));
+    without_interrupts(|| {
+        RTC_REGISTERS.iter().fold(0, |packed, &register| {
+            unsafe { address.write(register); }
+            packed << 8 | unsafe { data.read(0u8) } as u64
+        })
+    })
+}
+
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function of RFC 7539 on a full input state.
+fn chacha20_block(input: &[u32; 16]) -> [u32; 16] {
+    let mut state = *input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(*input);
+    }
+    state
+}
+
+/// The ChaCha20 keystream as a generator, with a 64 bit block counter.
+pub struct ChaCha20 {
+    key: [u32; 8],
+    counter: u64,
+    block: [u32; 16],
+    /// Words of `block` already handed out.
+    used: usize,
+}
+
+impl ChaCha20 {
+    pub const fn new(key: [u32; 8]) -> Self {
+        ChaCha20 { key, counter: 0, block: [0; 16], used: 16 }
+    }
+
+    fn refill(&mut self) {
+        let mut input = [0u32; 16];
+        input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter as u32;
+        input[13] = (self.counter >> 32) as u32;
+        self.block = chacha20_block(&input);
+        self.counter = self.counter.wrapping_add(1);
+        self.used = 0;
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        if self.used == self.block.len() {
+            self.refill();
+        }
+        self.used += 1;
+        self.block[self.used - 1]
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.next_u32() as u64 | (self.next_u32() as u64) << 32
+    }
+
+    /// Mixes `value` into the key, then replaces the key with keystream so
+    /// that earlier output cannot be recovered from the new state.
+    pub fn mix(&mut self, value: u64) {
+        self.key[0] ^= value as u32;
+        self.key[1] ^= (value >> 32) as u32;
+        self.refill();
+        self.key.copy_from_slice(&self.block[..8]);
+        self.used = self.block.len();
+    }
+}
+
+/// How the generator was seeded, see [`health`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeedHealth {
+    pub rdrand: bool,
+    pub rdseed: bool,
+    /// Bits from RDSEED or RDRAND mixed into the generator.
+    pub hardware_bits: u64,
+    /// Keyboard interrupts whose timing was mixed in.
+    pub jitter_events: u64,
+    pub reseeds: u64,
+}
+
+impl fmt::Display for SeedHealth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RDRAND {}, RDSEED {}, {} hardware bits and {} keyboard events over {} seedings",
+            if self.rdrand { "yes" } else { "no" },
+            if self.rdseed { "yes" } else { "no" },
+            self.hardware_bits, self.jitter_events, self.reseeds)
+    }
+}
+
+struct Generator {
+    chacha: ChaCha20,
+    /// Outputs since the last reseed, `None` before the first one.
+    outputs: Option<u64>,
+    hardware_bits: u64,
+    jitter_events: u64,
+    reseeds: u64,
+}
+
+impl Generator {
+    const fn new() -> Self {
+        Generator { chacha: ChaCha20::new([0; 8]), outputs: None, hardware_bits: 0, jitter_events: 0, reseeds: 0 }
+    }
+
+    fn reseed(&mut self) {
+        for _ in 0..SEED_WORDS {
+            if let Some(value) = rdseed64().or_else(rdrand64) {
+                self.chacha.mix(value);
+                self.hardware_bits += 64;
+            }
+        }
+        self.chacha.mix(rdtsc());
+        self.chacha.mix(read_rtc());
+        let events = JITTER_EVENTS.swap(0, Ordering::Relaxed);
+        if events != 0 {
+            self.chacha.mix(JITTER.load(Ordering::Relaxed));
+            self.jitter_events += events;
+        }
+        // How long all of the above took.
+        self.chacha.mix(rdtsc());
+        self.outputs = Some(0);
+        self.reseeds += 1;
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self.outputs {
+            Some(outputs) if outputs < RESEED_INTERVAL => self.outputs = Some(outputs + 1),
+            _ => self.reseed(),
+        }
+        self.chacha.next_u64()
+    }
+}
+
+/// A random value, from RDRAND if it works and from the generator otherwise.
+pub fn u64() -> u64 {
+    if let Some(value) = rdrand64() {
+        return value;
+    }
+    without_interrupts(|| GENERATOR.lock().next_u64())
+}
+
+pub fn fill(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        chunk.copy_from_slice(&u64().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+/// A uniformly distributed value below `n`, which must not be 0.
+pub fn range(n: u64) -> u64 {
+    assert!(n != 0, "empty range");
+    // 2^64 mod n: dropping the values below it leaves a multiple of n values.
+    let threshold = n.wrapping_neg() % n;
+    loop {
+        let value = u64();
+        if value >= threshold {
+            return value % n;
+        }
+    }
+}
+
+/// How the generator was seeded so far.
+pub fn health() -> SeedHealth {
+    let generator = without_interrupts(|| {
+        let generator = GENERATOR.lock();
+        (generator.hardware_bits, generator.jitter_events, generator.reseeds)
+    });
+    SeedHealth {
+        rdrand: has_rdrand(),
+        rdseed: has_rdseed(),
+        hardware_bits: generator.0,
+        jitter_events: generator.1,
+        reseeds: generator.2,
+    }
+}
+
+#[test_case]
+fn rdrand_values_vary() {
+    // QEMU only has RDRAND with `-cpu host` or a model that includes it.
+    let Some(first) = rdrand64() else {
+        return;
+    };
+    assert!((0..8).filter_map(|_| rdrand64()).any(|value| value != first));
+}
+
+#[test_case]
+fn chacha20_block_test_vector() {
+    // RFC 7539, 2.3.2.
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    for (i, word) in input[4..12].iter_mut().enumerate() {
+        let byte = 4 * i as u32;
+        *word = u32::from_le_bytes([byte as u8, byte as u8 + 1, byte as u8 + 2, byte as u8 + 3]);
+    }
+    input[12..16].copy_from_slice(&[1, 0x0900_0000, 0x4A00_0000, 0]);
+    assert_eq!(chacha20_block(&input), [
+        0xE4E7_F110, 0x1559_3BD1, 0x1FDD_0F50, 0xC471_20A3,
+        0xC7F4_D1C7, 0x0368_C033, 0x9AAA_2204, 0x4E6C_D4C3,
+        0x4664_82D2, 0x09AA_9F07, 0x05D7_C214, 0xA202_8BD9,
+        0xD19C_12B5, 0xB94E_16DE, 0xE883_D0CB, 0x4E3C_50A2,
+    ]);
+}
+
+#[test_case]
+fn fallback_generator_sanity() {
+    // The monobit and runs tests of FIPS 140-2 on 20000 bits.
+    let mut chacha = ChaCha20::new([0, 1, 2, 3, 4, 5, 6, 7]);
+    let (mut ones, mut runs, mut run, mut longest_run) = (0, 0, 0, 0);
+    let mut previous = None;
+    for _ in 0..625 {
+        let word = chacha.next_u32();
+        for i in 0..32 {
+            let bit = word >> i & 1;
+            ones += bit;
+            if previous == Some(bit) {
+                run += 1;
+            } else {
+                runs += 1;
+                run = 1;
+            }
+            longest_run = longest_run.max(run);
+            previous = Some(bit);
+        }
+    }
+    assert!((9725..10275).contains(&ones), "{} ones", ones);
+    assert!((9650..10350).contains(&runs), "{} runs", runs);
+    assert!(longest_run < 26, "run of {}", longest_run);
+
+    // Mixing changes the key, and the stream with it.
+    let mut mixed = ChaCha20::new([0, 1, 2, 3, 4, 5, 6, 7]);
+    mixed.mix(0);
+    assert_ne!(mixed.next_u64(), ChaCha20::new([0, 1, 2, 3, 4, 5, 6, 7]).next_u64());
+}
+
+#[test_case]
+fn public_api_always_works() {
+    assert_eq!(range(1), 0);
+    assert!((0..100).all(|_| range(10) < 10));
+    let mut bytes = [0u8; 61];
+    fill(&mut bytes);
+    assert!(bytes.iter().any(|&byte| byte != 0));
+    assert!(health().reseeds > 0 || has_rdrand());
+}
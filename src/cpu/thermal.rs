@@ -0,0 +1,217 @@
+//! CPU temperature and frequency, as far as the model specific registers tell.
+//!
+//! Everything is read on the calling CPU. The registers are Intel's; what
+//! `cpuid` does not vouch for is read with [`Msr::read_safe`], so a CPU or a
+//! hypervisor that lacks one makes the field `None` instead of faulting.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::{pic::timer, tables::RFlags};
+use super::{cpuid, max_leaf, tsc_frequency, Msr};
+
+const IA32_MPERF: u32 = 0xE7;
+const IA32_APERF: u32 = 0xE8;
+const MSR_PLATFORM_INFO: u32 = 0xCE;
+const IA32_PERF_STATUS: u32 = 0x198;
+const IA32_THERM_STATUS: u32 = 0x19C;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+
+/// `cpuid` leaf 1, ECX: Enhanced SpeedStep, which IA32_PERF_STATUS comes with.
+const CPUID_EST: u32 = 1 << 7;
+/// `cpuid` leaf 6, EAX: the digital thermal sensor behind IA32_THERM_STATUS.
+const CPUID_DTS: u32 = 1 << 0;
+/// `cpuid` leaf 6, ECX: IA32_APERF and IA32_MPERF.
+const CPUID_APERF_MPERF: u32 = 1 << 0;
+
+/// IA32_THERM_STATUS: the readout in bits 22:16 is valid.
+const THERM_READING_VALID: u64 = 1 << 31;
+/// IA32_THERM_STATUS: the sticky logs of the core having been throttled for
+/// its temperature and of PROCHOT# having been asserted, cleared by writing 0.
+const THERM_STATUS_LOG: u64 = 1 << 1;
+const THERM_PROCHOT_LOG: u64 = 1 << 3;
+/// IA32_THERM_STATUS: all the log bits, which are the only writable ones.
+const THERM_LOGS: u64 = 0xAAAA;
+
+/// The bus clock the ratios multiply on every core since Sandy Bridge.
+pub const BUS_CLOCK_HZ: u64 = 100_000_000;
+/// How long [`snapshot`] counts APERF and MPERF for.
+pub const SAMPLE_WINDOW_MS: u64 = 10;
+
+static THERMAL_EVENTS: AtomicU64 = AtomicU64::new(0);
+static PROCHOT_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// What the sensors said at one point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The core temperature in degrees Celsius.
+    pub temperature: Option<u8>,
+    /// The temperature at which the core starts throttling itself.
+    pub tj_max: Option<u8>,
+    /// The frequency the core runs at without turbo, in Hz.
+    pub base_hz: Option<u64>,
+    /// The frequency last requested of the core, in Hz.
+    pub current_hz: Option<u64>,
+    /// The average frequency over the sampling window while not idle, in Hz.
+    pub effective_hz: Option<u64>,
+    /// How often the core was seen to have throttled for its temperature, and
+    /// PROCHOT# to have been asserted, since boot.
+    pub thermal_events: u64,
+    pub prochot_events: u64,
+}
+
+/// Which of the registers `cpuid` says are there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Support {
+    perf_status: bool,
+    thermal_sensor: bool,
+    aperf_mperf: bool,
+}
+
+fn support() -> Support {
+    let est = cpuid(1, 0).ecx & CPUID_EST != 0;
+    let (dts, aperf_mperf) = if max_leaf() >= 6 {
+        let leaf = cpuid(6, 0);
+        (leaf.eax & CPUID_DTS != 0, leaf.ecx & CPUID_APERF_MPERF != 0)
+    } else {
+        (false, false)
+    };
+    Support { perf_status: est, thermal_sensor: dts, aperf_mperf }
+}
+
+/// Returns the temperature in degrees Celsius from the IA32_THERM_STATUS
+/// value `status`, whose readout counts down from `tj_max`.
+pub fn temperature(status: u64, tj_max: u8) -> Option<u8> {
+    if status & THERM_READING_VALID == 0 {
+        return None;
+    }
+    let below = (status >> 16) as u8 & 0x7F;
+    tj_max.checked_sub(below)
+}
+
+/// Returns TjMax in degrees Celsius from the MSR_TEMPERATURE_TARGET value
+/// `target`, or `None` if the field is not filled in.
+pub fn tj_max(target: u64) -> Option<u8> {
+    Some((target >> 16) as u8).filter(|&tj_max| tj_max != 0)
+}
+
+/// Returns the frequency for the ratio in bits 15:8 of `value`, which is the
+/// layout of both MSR_PLATFORM_INFO and IA32_PERF_STATUS.
+pub fn ratio_hz(value: u64) -> Option<u64> {
+    Some((value >> 8) & 0xFF).filter(|&ratio| ratio != 0).map(|ratio| ratio * BUS_CLOCK_HZ)
+}
+
+/// Returns the effective frequency from APERF and MPERF read at the start and
+/// the end of a window. MPERF counts at `base_hz` and APERF at the actual
+/// frequency, both only while the core is not idle.
+pub fn effective_hz(base_hz: u64, (aperf_start, mperf_start): (u64, u64), (aperf_end, mperf_end): (u64, u64)) -> Option<u64> {
+    let aperf = aperf_end.wrapping_sub(aperf_start) as u128;
+    let mperf = mperf_end.wrapping_sub(mperf_start) as u128;
+    if mperf == 0 {
+        return None;
+    }
+    u64::try_from(base_hz as u128 * aperf / mperf).ok()
+}
+
+fn aperf_mperf() -> Option<(u64, u64)> {
+    Some((Msr::new(IA32_APERF).read_safe()?, Msr::new(IA32_MPERF).read_safe()?))
+}
+
+/// Counts the throttling logged in IA32_THERM_STATUS since the last call and
+/// clears the logs.
+fn count_throttling(status: u64) {
+    let logged = status & (THERM_STATUS_LOG | THERM_PROCHOT_LOG);
+    if logged == 0 {
+        return;
+    }
+    // Writing 1 leaves the other logs as they are.
+    if unsafe { !Msr::new(IA32_THERM_STATUS).write_safe(status & THERM_LOGS & !logged) } {
+        return;
+    }
+    if logged & THERM_STATUS_LOG != 0 {
+        THERMAL_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+    if logged & THERM_PROCHOT_LOG != 0 {
+        PROCHOT_EVENTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Reads the sensors of the current CPU. The effective frequency takes
+/// [`SAMPLE_WINDOW_MS`] to measure and is only measured with interrupts enabled.
+pub fn snapshot() -> Snapshot {
+    let support = support();
+
+    let base_hz = Msr::new(MSR_PLATFORM_INFO).read_safe().and_then(ratio_hz);
+    let current_hz = match support.perf_status {
+        true => Msr::new(IA32_PERF_STATUS).read_safe().and_then(ratio_hz),
+        false => None,
+    };
+
+    let (mut temperature, mut tj) = (None, None);
+    if support.thermal_sensor {
+        tj = Msr::new(MSR_TEMPERATURE_TARGET).read_safe().and_then(tj_max);
+        if let Some(status) = Msr::new(IA32_THERM_STATUS).read_safe() {
+            temperature = tj.and_then(|tj| self::temperature(status, tj));
+            count_throttling(status);
+        }
+    }
+
+    let mut effective = None;
+    // MPERF runs at the TSC frequency where there is no base ratio to go by.
+    let mperf_hz = base_hz.or_else(tsc_frequency);
+    if let (true, true, Some(mperf_hz)) = (support.aperf_mperf, RFlags::read().contains(RFlags::INTERRUPT_FLAG), mperf_hz) {
+        if let Some(start) = aperf_mperf() {
+            timer::delay_ms(SAMPLE_WINDOW_MS);
+            effective = aperf_mperf().and_then(|end| effective_hz(mperf_hz, start, end));
+        }
+    }
+
+    Snapshot {
+        temperature,
+        tj_max: tj,
+        base_hz,
+        current_hz,
+        effective_hz: effective,
+        thermal_events: THERMAL_EVENTS.load(Ordering::Relaxed),
+        prochot_events: PROCHOT_EVENTS.load(Ordering::Relaxed),
+    }
+}
+
+#[test_case]
+fn ratio_math() {
+    // 35 degrees below a TjMax of 100, then the readout not valid.
+    let status = THERM_READING_VALID | 35 << 16 | THERM_STATUS_LOG;
+    assert_eq!(temperature(status, 100), Some(65));
+    assert_eq!(temperature(status & !THERM_READING_VALID, 100), None);
+    assert_eq!(temperature(status, 30), None);
+    assert_eq!(tj_max(0x0064_0000), Some(100));
+    assert_eq!(tj_max(0), None);
+
+    // A 3.4 GHz part, the ratio in bits 15:8 and something else around it.
+    assert_eq!(ratio_hz(0x8008_2200_0000_22FF), Some(3_400_000_000));
+    assert_eq!(ratio_hz(0x00FF), None);
+
+    // Running at 1.5 times the base frequency, across an APERF wraparound.
+    let base = 2_000_000_000;
+    assert_eq!(effective_hz(base, (u64::MAX - 99, 1000), (200, 1200)), Some(3_000_000_000));
+    assert_eq!(effective_hz(base, (0, 1000), (500, 1000)), None);
+    assert_eq!(effective_hz(u64::MAX, (0, 0), (u64::MAX, 1)), None);
+}
+
+#[test_case]
+fn msrs_that_are_not_there_do_not_fault() {
+    // Outside of every MSR range that has ever been defined.
+    assert_eq!(Msr::new(0x4000_F000).read_safe(), None);
+    // The APIC base is there on every x86_64 CPU.
+    assert!(Msr::new(0x1B).read_safe().is_some());
+}
+
+#[test_case]
+fn snapshot_is_plausible() {
+    // Whatever the machine implements, which under QEMU is little.
+    let snapshot = snapshot();
+    if let (Some(temperature), Some(tj_max)) = (snapshot.temperature, snapshot.tj_max) {
+        assert!(temperature <= tj_max);
+    }
+    for hz in [snapshot.base_hz, snapshot.current_hz, snapshot.effective_hz].into_iter().flatten() {
+        assert!((100_000_000..=10_000_000_000).contains(&hz), "{} Hz", hz);
+    }
+}
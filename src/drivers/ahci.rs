@@ -0,0 +1,544 @@
+//! AHCI driver for SATA disks.
+//!
+//! Each implemented port with an ATA disk gets one 4KiB frame holding its
+//! command list, received FIS area and a single command table, plus
+//! `BOUNCE_FRAMES` frames that data is copied through, so callers can pass any
+//! buffer. Only command slot 0 is used and completion is polled.
+
+use core::fmt;
+use crate::{
+    memory::{frame_allocator::FrameAllocator, FRAME_ALLOCATOR, MAPPER},
+    pci::{self, bar::{BarRegion, MmioRegion, PciError}, PciDevice},
+    pic::timer::delay_ms,
+    println,
+    storage::{BlockDevice, IoError},
+    sync::Mutex,
+};
+use super::ata::{Identity, SECTOR_SIZE};
+
+const CLASS_MASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+/// ABAR, the HBA registers, is BAR 5.
+const ABAR: u8 = 5;
+
+/// Generic host control registers.
+const HBA_CAP: u64 = 0x00;
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0C;
+const HBA_VS: u64 = 0x10;
+const HBA_CAP2: u64 = 0x24;
+const HBA_BOHC: u64 = 0x28;
+
+const CAP_S64A: u32 = 1 << 31;
+const GHC_AE: u32 = 1 << 31;
+const CAP2_BOH: u32 = 1 << 0;
+/// BIOS/OS handoff: BIOS owned, OS owned, BIOS busy.
+const BOHC_BOS: u32 = 1 << 0;
+const BOHC_OOS: u32 = 1 << 1;
+const BOHC_BB: u32 = 1 << 4;
+
+/// Port registers, from `PORT_BASE + port * PORT_SIZE`.
+const PORT_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0C;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SCTL: u64 = 0x2C;
+const PX_SERR: u64 = 0x30;
+const PX_CI: u64 = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+/// Task file error interrupt status.
+const IS_TFES: u32 = 1 << 30;
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+/// SSTS.DET: device present and communication established.
+const SSTS_DET_PRESENT: u32 = 3;
+/// SCTL.DET: perform interface initialization (COMRESET).
+const SCTL_DET_INIT: u32 = 1;
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+/// Layout of the per-port frame.
+const COMMAND_LIST: u64 = 0x000;
+const RECEIVED_FIS: u64 = 0x400;
+const COMMAND_TABLE: u64 = 0x500;
+const PRDT: u64 = COMMAND_TABLE + 0x80;
+const FRAME_SIZE: usize = crate::memory::PAGE_SIZE_4K as usize;
+/// Data is transferred through this many frames, one PRDT entry each.
+const BOUNCE_FRAMES: usize = 8;
+const MAX_SECTORS_PER_COMMAND: usize = BOUNCE_FRAMES * FRAME_SIZE / SECTOR_SIZE;
+
+/// Command header: FIS length in dwords and the write direction bit.
+const HEADER_CFL_H2D: u32 = 5;
+const HEADER_WRITE: u32 = 1 << 6;
+const FIS_TYPE_H2D: u8 = 0x27;
+/// H2D FIS byte 1: the FIS carries a command.
+const FIS_COMMAND: u8 = 0x80;
+const DEVICE_LBA: u8 = 1 << 6;
+/// PRDT entry: interrupt on completion, left off since completion is polled.
+const PRD_BYTE_COUNT: u32 = 0x3F_FFFF;
+
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xEA;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+/// Register reads before a command or state change is given up on.
+const POLLS: u32 = 1_000_000;
+/// BIOS/OS handoff: the BIOS gets 25ms to release the controller, or 2s more once busy.
+const HANDOFF_MS: u64 = 25;
+const HANDOFF_BUSY_MS: u64 = 2000;
+
+#[derive(Debug)]
+pub enum AhciError {
+    NoController,
+    Pci(PciError),
+    /// No frame for the DMA structures, or only one the HBA cannot address.
+    NoMemory,
+    Timeout,
+    /// The command failed, with the status and error registers of the task file.
+    TaskFile { status: u8, error: u8 },
+    OutOfRange,
+    /// The buffer is not a whole number of sectors.
+    BadBuffer,
+}
+
+impl fmt::Display for AhciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AhciError::NoController => write!(f, "no AHCI controller"),
+            AhciError::Pci(err) => write!(f, "{}", err),
+            AhciError::NoMemory => write!(f, "no DMA memory"),
+            AhciError::Timeout => write!(f, "port timed out"),
+            AhciError::TaskFile { status, error } => write!(f, "task file error, status {:#04x} error {:#04x}", status, error),
+            AhciError::OutOfRange => write!(f, "sector out of range"),
+            AhciError::BadBuffer => write!(f, "buffer is not a whole number of sectors"),
+        }
+    }
+}
+
+impl From<AhciError> for IoError {
+    fn from(err: AhciError) -> Self {
+        match err {
+            AhciError::Timeout => IoError::Timeout,
+            AhciError::TaskFile { status, error } => IoError::DeviceError { code: (status as u32) << 8 | error as u32 },
+            AhciError::OutOfRange => IoError::OutOfRange,
+            AhciError::BadBuffer => IoError::BadBuffer,
+            AhciError::NoController | AhciError::Pci(_) | AhciError::NoMemory => IoError::DeviceError { code: 0 },
+        }
+    }
+}
+
+impl From<PciError> for AhciError {
+    fn from(err: PciError) -> Self {
+        AhciError::Pci(err)
+    }
+}
+
+/// Spins until `done` holds, up to [`POLLS`] times.
+fn poll(mut done: impl FnMut() -> bool) -> Result<(), AhciError> {
+    for _ in 0..POLLS {
+        if done() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(AhciError::Timeout)
+}
+
+/// The DMA memory of a port: physical address and where it is mapped.
+struct Dma {
+    phys: u64,
+    virt: u64,
+    bounce: [u64; BOUNCE_FRAMES],
+    offset: u64,
+}
+
+impl Dma {
+    fn allocate(addresses_64_bit: bool) -> Result<Dma, AhciError> {
+        let offset = MAPPER.lock().as_ref().ok_or(AhciError::NoMemory)?.phys_offset();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().ok_or(AhciError::NoMemory)?;
+        let mut frame = || {
+            let phys = allocator.allocate_frame().ok_or(AhciError::NoMemory)?.start_address();
+            if !addresses_64_bit && phys >= 1 << 32 {
+                return Err(AhciError::NoMemory);
+            }
+            unsafe { core::ptr::write_bytes((phys + offset) as *mut u8, 0, FRAME_SIZE) };
+            Ok(phys)
+        };
+        let phys = frame()?;
+        let mut bounce = [0; BOUNCE_FRAMES];
+        for address in bounce.iter_mut() {
+            *address = frame()?;
+        }
+        Ok(Dma { phys, virt: phys + offset, bounce, offset })
+    }
+
+    fn ptr<T>(&self, offset: u64) -> *mut T {
+        (self.virt + offset) as *mut T
+    }
+
+    fn bounce_frame(&self, index: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts((self.bounce[index] + self.offset) as *const u8, FRAME_SIZE) }
+    }
+
+    fn bounce_frame_mut(&mut self, index: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut((self.bounce[index] + self.offset) as *mut u8, FRAME_SIZE) }
+    }
+}
+
+/// One port of the HBA.
+struct Port {
+    hba: MmioRegion,
+    base: u64,
+}
+
+impl Port {
+    fn read(&self, register: u64) -> u32 {
+        self.hba.read::<u32>(self.base + register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe { self.hba.write::<u32>(self.base + register, value) }
+    }
+
+    fn device_present(&self) -> bool {
+        self.read(PX_SSTS) & 0xF == SSTS_DET_PRESENT
+    }
+
+    /// Stops command processing and FIS reception, the state needed to change
+    /// the command list or FIS addresses.
+    fn stop(&self) -> Result<(), AhciError> {
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_ST);
+        poll(|| self.read(PX_CMD) & CMD_CR == 0)?;
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_FRE);
+        poll(|| self.read(PX_CMD) & CMD_FR == 0)
+    }
+
+    fn start(&self) -> Result<(), AhciError> {
+        poll(|| self.read(PX_CMD) & CMD_CR == 0)?;
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_FRE);
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_ST);
+        Ok(())
+    }
+
+    /// COMRESET, for a device stuck busy. The port must be stopped.
+    fn reset(&self) -> Result<(), AhciError> {
+        let control = self.read(PX_SCTL) & !0xF;
+        self.write(PX_SCTL, control | SCTL_DET_INIT);
+        // The reset must be held for at least 1ms.
+        delay_ms(1);
+        self.write(PX_SCTL, control);
+        poll(|| self.device_present())?;
+        self.write(PX_SERR, !0);
+        Ok(())
+    }
+
+    /// Gets a port out of an error: restarting it clears the error state, and
+    /// a device still busy is reset.
+    fn recover(&self) -> Result<(), AhciError> {
+        self.stop()?;
+        self.write(PX_SERR, !0);
+        self.write(PX_IS, !0);
+        if self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) != 0 {
+            self.reset()?;
+        }
+        self.start()
+    }
+
+    /// Points the port at `dma` and starts it.
+    fn setup(&self, dma: &Dma) -> Result<(), AhciError> {
+        self.stop()?;
+        let command_list = dma.phys + COMMAND_LIST;
+        let received_fis = dma.phys + RECEIVED_FIS;
+        self.write(PX_CLB, command_list as u32);
+        self.write(PX_CLBU, (command_list >> 32) as u32);
+        self.write(PX_FB, received_fis as u32);
+        self.write(PX_FBU, (received_fis >> 32) as u32);
+        self.write(PX_SERR, !0);
+        self.write(PX_IS, !0);
+        self.write(PX_IE, 0);
+        if self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) != 0 {
+            self.reset()?;
+        }
+        self.start()
+    }
+
+    /// Runs `command` on `sectors` sectors from `lba`, through the first
+    /// `bytes` bytes of the bounce frames.
+    fn issue(&self, dma: &Dma, command: u8, lba: u64, sectors: u16, bytes: usize, write: bool) -> Result<(), AhciError> {
+        poll(|| self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+        self.write(PX_IS, !0);
+
+        let entries = bytes.div_ceil(FRAME_SIZE);
+        let table = dma.phys + COMMAND_TABLE;
+        let header = dma.ptr::<u32>(COMMAND_LIST);
+        let flags = HEADER_CFL_H2D | if write { HEADER_WRITE } else { 0 } | (entries as u32) << 16;
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_H2D;
+        fis[1] = FIS_COMMAND;
+        fis[2] = command;
+        fis[4..7].copy_from_slice(&lba.to_le_bytes()[..3]);
+        fis[7] = DEVICE_LBA;
+        fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+        fis[12..14].copy_from_slice(&sectors.to_le_bytes());
+        unsafe {
+            header.write_volatile(flags);
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(table as u32);
+            header.add(3).write_volatile((table >> 32) as u32);
+            core::ptr::copy_nonoverlapping(fis.as_ptr(), dma.ptr::<u8>(COMMAND_TABLE), fis.len());
+            for i in 0..entries {
+                let prd = dma.ptr::<u32>(PRDT + 16 * i as u64);
+                let length = (bytes - i * FRAME_SIZE).min(FRAME_SIZE);
+                prd.write_volatile(dma.bounce[i] as u32);
+                prd.add(1).write_volatile((dma.bounce[i] >> 32) as u32);
+                prd.add(2).write_volatile(0);
+                prd.add(3).write_volatile((length as u32 - 1) & PRD_BYTE_COUNT);
+            }
+        }
+
+        self.write(PX_CI, 1);
+        let mut failed = false;
+        let done = poll(|| {
+            failed = self.read(PX_IS) & IS_TFES != 0;
+            failed || self.read(PX_CI) & 1 == 0
+        });
+        let task_file = self.read(PX_TFD);
+        if failed || task_file & TFD_ERR != 0 {
+            self.recover()?;
+            return Err(AhciError::TaskFile { status: task_file as u8, error: (task_file >> 8) as u8 });
+        }
+        if done.is_err() {
+            self.recover()?;
+        }
+        done
+    }
+}
+
+struct PortState {
+    port: Port,
+    dma: Dma,
+}
+
+/// An ATA disk on an AHCI port.
+pub struct AhciDisk {
+    number: u8,
+    identity: Identity,
+    state: Mutex<PortState>,
+}
+
+impl AhciDisk {
+    /// The port number on the HBA.
+    pub fn port(&self) -> u8 {
+        self.number
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.identity.sectors_lba48.max(self.identity.sectors as u64)
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), AhciError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(AhciError::BadBuffer);
+        }
+        match lba.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors() => Ok(()),
+            _ => Err(AhciError::OutOfRange),
+        }
+    }
+
+    /// Reads whole sectors from `lba` into `buffer` with READ DMA EXT.
+    pub fn read_dma(&self, lba: u64, buffer: &mut [u8]) -> Result<(), AhciError> {
+        self.check_range(lba, buffer.len())?;
+        let state = self.state.lock();
+        for (i, chunk) in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let sectors = (chunk.len() / SECTOR_SIZE) as u16;
+            state.port.issue(&state.dma, COMMAND_READ_DMA_EXT, lba, sectors, chunk.len(), false)?;
+            for (j, part) in chunk.chunks_mut(FRAME_SIZE).enumerate() {
+                part.copy_from_slice(&state.dma.bounce_frame(j)[..part.len()]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes whole sectors from `buffer` to `lba` with WRITE DMA EXT, then
+    /// flushes the disk cache.
+    pub fn write_dma(&self, lba: u64, buffer: &[u8]) -> Result<(), AhciError> {
+        self.check_range(lba, buffer.len())?;
+        let mut state = self.state.lock();
+        for (i, chunk) in buffer.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            for (j, part) in chunk.chunks(FRAME_SIZE).enumerate() {
+                state.dma.bounce_frame_mut(j)[..part.len()].copy_from_slice(part);
+            }
+            let sectors = (chunk.len() / SECTOR_SIZE) as u16;
+            state.port.issue(&state.dma, COMMAND_WRITE_DMA_EXT, lba, sectors, chunk.len(), true)?;
+        }
+        state.port.issue(&state.dma, COMMAND_FLUSH_CACHE_EXT, 0, 0, 0, false)
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.sectors()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        Ok(self.read_dma(lba, buffer)?)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError> {
+        Ok(self.write_dma(lba, buffer)?)
+    }
+}
+
+/// An AHCI controller and the disks found on its ports.
+pub struct Ahci {
+    pub device: PciDevice,
+    /// Major and minor AHCI version.
+    pub version: (u16, u16),
+    disks: [Option<AhciDisk>; 32],
+}
+
+impl Ahci {
+    /// Takes over the first AHCI controller on the PCI bus and identifies the
+    /// disks on its implemented ports.
+    ///
+    /// Interrupts must be enabled, port resets and the BIOS handoff wait on the timer.
+    pub fn probe() -> Result<Ahci, AhciError> {
+        let device = pci::devices()
+            .find(|d| (d.class, d.subclass) == (CLASS_MASS_STORAGE, SUBCLASS_SATA))
+            .ok_or(AhciError::NoController)?;
+        let hba = {
+            let mut mapper = MAPPER.lock();
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+                return Err(AhciError::NoMemory);
+            };
+            match unsafe { device.map_bar(ABAR, mapper, allocator)? } {
+                BarRegion::Mmio(region) => region,
+                BarRegion::Io(_) => return Err(AhciError::Pci(PciError::NoSuchBar)),
+            }
+        };
+        unsafe { device.enable_bus_master(); }
+
+        bios_handoff(&hba);
+        unsafe {
+            hba.write::<u32>(HBA_GHC, hba.read::<u32>(HBA_GHC) | GHC_AE);
+            hba.write::<u32>(HBA_IS, !0);
+        }
+        let version = hba.read::<u32>(HBA_VS);
+        let implemented = hba.read::<u32>(HBA_PI);
+
+        let mut disks = [const { None }; 32];
+        for number in (0..32u8).filter(|n| implemented & (1 << n) != 0) {
+            let port = Port { hba, base: PORT_BASE + number as u64 * PORT_SIZE };
+            if !port.device_present() || port.read(PX_SIG) != SIGNATURE_ATA {
+                continue;
+            }
+            match identify(port) {
+                Ok((identity, state)) => {
+                    disks[number as usize] = Some(AhciDisk { number, identity, state: Mutex::new("AHCI_PORT", state) });
+                },
+                Err(err) => println!("AHCI port {}: {}", number, err),
+            }
+        }
+        Ok(Ahci { device, version: ((version >> 16) as u16, version as u16), disks })
+    }
+
+    pub fn disks(&self) -> impl Iterator<Item = &AhciDisk> {
+        self.disks.iter().flatten()
+    }
+}
+
+/// Asks the BIOS to release the controller, if it supports the handoff.
+fn bios_handoff(hba: &MmioRegion) {
+    if hba.read::<u32>(HBA_CAP2) & CAP2_BOH == 0 {
+        return;
+    }
+    unsafe { hba.write::<u32>(HBA_BOHC, hba.read::<u32>(HBA_BOHC) | BOHC_OOS); }
+    let mut waited = 0;
+    while hba.read::<u32>(HBA_BOHC) & BOHC_BOS != 0 && waited < HANDOFF_MS {
+        delay_ms(1);
+        waited += 1;
+    }
+    if hba.read::<u32>(HBA_BOHC) & BOHC_BB != 0 {
+        delay_ms(HANDOFF_BUSY_MS);
+    }
+}
+
+fn identify(port: Port) -> Result<(Identity, PortState), AhciError> {
+    let addresses_64_bit = port.hba.read::<u32>(HBA_CAP) & CAP_S64A != 0;
+    let dma = Dma::allocate(addresses_64_bit)?;
+    port.setup(&dma)?;
+    port.issue(&dma, COMMAND_IDENTIFY, 0, 0, SECTOR_SIZE, false)?;
+    let mut words = [0u16; 256];
+    for (word, bytes) in words.iter_mut().zip(dma.bounce_frame(0).chunks_exact(2)) {
+        *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    Ok((Identity::parse(&words), PortState { port, dma }))
+}
+
+/// Probes for an AHCI controller and prints its disks.
+pub fn init() -> Option<Ahci> {
+    match Ahci::probe() {
+        Ok(ahci) => {
+            println!("AHCI {}.{} at {}", ahci.version.0, ahci.version.1, ahci.device.address);
+            for disk in ahci.disks() {
+                println!("AHCI port {}: {} ({} MiB)", disk.port(), disk.identity().model(),
+                    (disk.sectors() * SECTOR_SIZE as u64) >> 20);
+            }
+            Some(ahci)
+        },
+        Err(AhciError::NoController) => None,
+        Err(err) => {
+            println!("AHCI: {}", err);
+            None
+        },
+    }
+}
+
+#[test_case]
+fn ahci_disk_read_write() {
+    // scripts/run-tests.sh attaches target/test-disk-ahci.img to an ich9-ahci controller.
+    let ahci = Ahci::probe().expect("no AHCI controller");
+    let disk = ahci.disks().next().expect("no disk on the AHCI controller");
+    let mut sectors = [0u8; 17 * SECTOR_SIZE];
+    disk.read_blocks(0, &mut sectors[..SECTOR_SIZE]).unwrap();
+    assert!(sectors.starts_with(b"KRABBOS TEST DISK"));
+
+    // 17 sectors take three bounce frames, the last one partly.
+    for (i, byte) in sectors.iter_mut().enumerate() {
+        *byte = (i * 13) as u8;
+    }
+    disk.write_blocks(1, &sectors).unwrap();
+    let mut read_back = [0u8; 17 * SECTOR_SIZE];
+    disk.read_blocks(1, &mut read_back).unwrap();
+    assert!(read_back == sectors);
+
+    assert!(matches!(disk.read_blocks(disk.sectors(), &mut read_back[..SECTOR_SIZE]), Err(IoError::OutOfRange)));
+    assert!(matches!(disk.read_blocks(0, &mut read_back[..100]), Err(IoError::BadBuffer)));
+}
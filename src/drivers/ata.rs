@@ -0,0 +1,525 @@
+//! PIO driver for the ATA drives on the primary IDE channel.
+//!
+//! Every command is polled: the status register is read until the drive is no
+//! longer busy and asks for data, and each 512 byte sector is moved with
+//! `rep insw`/`rep outsw`. IRQ 14 stays masked.
+
+use core::fmt;
+use bitflags::bitflags;
+use spin::Once;
+use crate::{
+    storage::{self, check_request, BlockDevice, IoError},
+    sync::Mutex,
+    tables::port::Port,
+};
+
+pub const SECTOR_SIZE: usize = 512;
+/// Highest sector count of a 28-bit LBA command, written as 0.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+/// The addressable range of 28-bit LBA commands.
+const LBA28_LIMIT: u64 = 1 << 28;
+
+const PRIMARY_IO: u16 = 0x1F0;
+const PRIMARY_CONTROL: u16 = 0x3F6;
+
+/// Registers as offsets from the I/O base.
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+/// Reads as the status register, which is polled through the alternate one instead.
+const REG_COMMAND: u16 = 7;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+/// Drive register: LBA addressing, plus the two bits that must be set.
+const DRIVE_LBA: u8 = 0xE0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+/// Device control register: masks the drive's interrupt.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+/// Status reads before a busy or silent drive is given up on. A port read takes
+/// about a microsecond, so this is roughly a second.
+const STATUS_POLLS: u32 = 1_000_000;
+
+bitflags! {
+    /// The status register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Status: u8 {
+        const ERR = 1 << 0;
+        /// Data request: the drive is ready to transfer a sector.
+        const DRQ = 1 << 3;
+        /// Drive fault, not reported through the error register.
+        const DF = 1 << 5;
+        const RDY = 1 << 6;
+        const BSY = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// The error register, valid when the status has `ERR` set.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ErrorRegister: u8 {
+        /// Address mark not found.
+        const AMNF = 1 << 0;
+        /// Track zero not found.
+        const TKZNF = 1 << 1;
+        /// Command aborted, e.g. unsupported or out of range.
+        const ABRT = 1 << 2;
+        /// Media change request.
+        const MCR = 1 << 3;
+        /// Sector ID not found.
+        const IDNF = 1 << 4;
+        /// Media changed.
+        const MC = 1 << 5;
+        /// Uncorrectable data error.
+        const UNC = 1 << 6;
+        /// Bad block.
+        const BBK = 1 << 7;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// Nothing answered at this position.
+    NoDevice,
+    /// The device is ATAPI or SATA and does not take ATA commands.
+    NotAta,
+    Timeout,
+    DeviceFault,
+    /// The drive set `ERR`, with what the error register says.
+    Device(ErrorRegister),
+    /// The sectors are past the end of the drive or of 28-bit LBA.
+    OutOfRange,
+    /// The buffer is not a whole number of sectors.
+    BadBuffer,
+}
+
+impl fmt::Display for AtaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtaError::NoDevice => write!(f, "no drive"),
+            AtaError::NotAta => write!(f, "not an ATA drive"),
+            AtaError::Timeout => write!(f, "drive timed out"),
+            AtaError::DeviceFault => write!(f, "drive fault"),
+            AtaError::Device(error) => {
+                write!(f, "drive error")?;
+                for (name, _) in error.iter_names() {
+                    write!(f, " {}", name)?;
+                }
+                Ok(())
+            },
+            AtaError::OutOfRange => write!(f, "sector out of range"),
+            AtaError::BadBuffer => write!(f, "buffer is not a whole number of sectors"),
+        }
+    }
+}
+
+impl From<AtaError> for IoError {
+    fn from(err: AtaError) -> Self {
+        match err {
+            AtaError::Timeout => IoError::Timeout,
+            AtaError::Device(error) => IoError::DeviceError { code: error.bits() as u32 },
+            AtaError::OutOfRange => IoError::OutOfRange,
+            AtaError::BadBuffer => IoError::BadBuffer,
+            AtaError::NoDevice | AtaError::NotAta | AtaError::DeviceFault => IoError::DeviceError { code: 0 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+/// The useful part of the IDENTIFY DEVICE data.
+#[derive(Debug, Clone, Copy)]
+pub struct Identity {
+    model: [u8; 40],
+    serial: [u8; 20],
+    firmware: [u8; 8],
+    /// Sectors addressable with 28-bit LBA.
+    pub sectors: u32,
+    /// Sectors addressable with 48-bit LBA, 0 if it is not supported.
+    pub sectors_lba48: u64,
+}
+
+impl Identity {
+    pub fn parse(words: &[u16; 256]) -> Self {
+        let mut identity = Identity {
+            model: [0; 40],
+            serial: [0; 20],
+            firmware: [0; 8],
+            sectors: words[60] as u32 | (words[61] as u32) << 16,
+            sectors_lba48: 0,
+        };
+        ata_string(&words[27..47], &mut identity.model);
+        ata_string(&words[10..20], &mut identity.serial);
+        ata_string(&words[23..27], &mut identity.firmware);
+        if words[83] & (1 << 10) != 0 {
+            identity.sectors_lba48 = (0..4).fold(0, |sectors, i| sectors | (words[100 + i] as u64) << (16 * i));
+        }
+        identity
+    }
+
+    pub fn model(&self) -> &str {
+        trimmed(&self.model)
+    }
+
+    pub fn serial(&self) -> &str {
+        trimmed(&self.serial)
+    }
+
+    pub fn firmware(&self) -> &str {
+        trimmed(&self.firmware)
+    }
+}
+
+/// IDENTIFY strings store the first character of each pair in the high byte.
+fn ata_string(words: &[u16], out: &mut [u8]) {
+    for (pair, word) in out.chunks_exact_mut(2).zip(words) {
+        pair.copy_from_slice(&word.to_be_bytes());
+    }
+}
+
+/// The strings are padded with spaces.
+fn trimmed(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("").trim()
+}
+
+struct Channel {
+    io_base: u16,
+    control: Port,
+}
+
+impl Channel {
+    const fn new(io_base: u16, control: u16) -> Self {
+        Channel { io_base, control: Port::new(control) }
+    }
+
+    fn register(&self, offset: u16) -> Port {
+        Port::new(self.io_base + offset)
+    }
+
+    /// Reads the alternate status, which does not acknowledge an interrupt.
+    fn alt_status(&self) -> Status {
+        Status::from_bits_retain(unsafe { self.control.read(0u8) })
+    }
+
+    /// Gives the drive the 400ns it needs to put its status on the bus after a
+    /// drive select or command.
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn select(&self, drive: Drive, lba_high_nibble: u8) {
+        let slave = if drive == Drive::Slave { DRIVE_SLAVE } else { 0 };
+        unsafe { self.register(REG_DRIVE).write(DRIVE_LBA | slave | (lba_high_nibble & 0x0F)); }
+        self.delay_400ns();
+    }
+
+    fn wait_not_busy(&self) -> Result<Status, AtaError> {
+        for _ in 0..STATUS_POLLS {
+            let status = self.alt_status();
+            if !status.contains(Status::BSY) {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(AtaError::Timeout)
+    }
+
+    /// Waits until the drive asks for a sector, or reports an error.
+    fn wait_data_request(&self) -> Result<(), AtaError> {
+        for _ in 0..STATUS_POLLS {
+            let status = self.wait_not_busy()?;
+            self.check(status)?;
+            if status.contains(Status::DRQ) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(AtaError::Timeout)
+    }
+
+    fn check(&self, status: Status) -> Result<(), AtaError> {
+        if status.contains(Status::ERR) {
+            let error = unsafe { self.register(REG_ERROR).read(0u8) };
+            Err(AtaError::Device(ErrorRegister::from_bits_retain(error)))
+        } else if status.contains(Status::DF) {
+            Err(AtaError::DeviceFault)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn identify(&self, drive: Drive) -> Result<Identity, AtaError> {
+        unsafe { self.control.write(CONTROL_NIEN); }
+        // Nothing pulls the lines of an empty channel, so it reads as all ones.
+        if self.alt_status().bits() == 0xFF {
+            return Err(AtaError::NoDevice);
+        }
+        self.select(drive, 0);
+        unsafe {
+            for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+                self.register(register).write(0u8);
+            }
+            self.register(REG_COMMAND).write(COMMAND_IDENTIFY);
+        }
+        self.delay_400ns();
+        if self.alt_status().is_empty() {
+            return Err(AtaError::NoDevice);
+        }
+        self.wait_not_busy()?;
+        // ATAPI and SATA devices abort IDENTIFY and leave their signature here.
+        let signature = unsafe { (self.register(REG_LBA_MID).read(0u8), self.register(REG_LBA_HIGH).read(0u8)) };
+        if signature != (0, 0) {
+            return Err(AtaError::NotAta);
+        }
+        self.wait_data_request()?;
+        let mut words = [0u16; 256];
+        unsafe { self.register(REG_DATA).read_u16s(&mut words); }
+        Ok(Identity::parse(&words))
+    }
+
+    /// Sends a 28-bit LBA command for `count` sectors, 1 to 256.
+    fn command_lba28(&self, drive: Drive, lba: u32, count: usize, command: u8) -> Result<(), AtaError> {
+        self.select(drive, (lba >> 24) as u8);
+        self.wait_not_busy()?;
+        unsafe {
+            // A count of 256 is written as 0.
+            self.register(REG_SECTOR_COUNT).write(count as u8);
+            self.register(REG_LBA_LOW).write(lba as u8);
+            self.register(REG_LBA_MID).write((lba >> 8) as u8);
+            self.register(REG_LBA_HIGH).write((lba >> 16) as u8);
+            self.register(REG_COMMAND).write(command);
+        }
+        self.delay_400ns();
+        Ok(())
+    }
+
+    fn read(&self, drive: Drive, lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+        self.command_lba28(drive, lba, buffer.len() / SECTOR_SIZE, COMMAND_READ_SECTORS)?;
+        let mut words = [0u16; SECTOR_SIZE / 2];
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE) {
+            self.wait_data_request()?;
+            unsafe { self.register(REG_DATA).read_u16s(&mut words); }
+            for (bytes, word) in sector.chunks_exact_mut(2).zip(words) {
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, drive: Drive, lba: u32, buffer: &[u8]) -> Result<(), AtaError> {
+        self.command_lba28(drive, lba, buffer.len() / SECTOR_SIZE, COMMAND_WRITE_SECTORS)?;
+        let mut words = [0u16; SECTOR_SIZE / 2];
+        for sector in buffer.chunks_exact(SECTOR_SIZE) {
+            self.wait_data_request()?;
+            for (word, bytes) in words.iter_mut().zip(sector.chunks_exact(2)) {
+                *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+            }
+            unsafe { self.register(REG_DATA).write_u16s(&words); }
+        }
+        // The data only reaches the medium after a flush.
+        self.flush(drive)
+    }
+
+    fn flush(&self, drive: Drive) -> Result<(), AtaError> {
+        self.select(drive, 0);
+        self.wait_not_busy()?;
+        unsafe { self.register(REG_COMMAND).write(COMMAND_CACHE_FLUSH); }
+        self.delay_400ns();
+        let status = self.wait_not_busy()?;
+        self.check(status)
+    }
+}
+
+static PRIMARY: Mutex<Channel> = Mutex::new("ATA_PRIMARY", Channel::new(PRIMARY_IO, PRIMARY_CONTROL));
+/// The drives found by [`init`], registered as `ata0` and `ata1`.
+static DRIVES: Once<[Option<AtaDrive>; 2]> = Once::new();
+
+/// A drive on the primary channel, found by [`AtaDrive::probe`].
+#[derive(Debug, Clone, Copy)]
+pub struct AtaDrive {
+    drive: Drive,
+    identity: Identity,
+}
+
+impl AtaDrive {
+    /// Sends IDENTIFY DEVICE to the drive.
+    pub fn probe(drive: Drive) -> Result<Self, AtaError> {
+        let identity = PRIMARY.lock().identify(drive)?;
+        Ok(AtaDrive { drive, identity })
+    }
+
+    pub fn drive(&self) -> Drive {
+        self.drive
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Returns the capacity usable by this driver, which only speaks 28-bit LBA.
+    pub fn sectors(&self) -> u64 {
+        (self.identity.sectors as u64).min(LBA28_LIMIT)
+    }
+
+    /// Checks that `len` bytes from `lba` are whole sectors on the drive.
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), AtaError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(AtaError::BadBuffer);
+        }
+        match lba.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors() => Ok(()),
+            _ => Err(AtaError::OutOfRange),
+        }
+    }
+
+    /// Reads `count` sectors from `lba` into the start of `buffer`.
+    pub fn read_sectors(&self, lba: u64, count: usize, buffer: &mut [u8]) -> Result<(), AtaError> {
+        let buffer = buffer.get_mut(..count * SECTOR_SIZE).ok_or(AtaError::BadBuffer)?;
+        self.check_range(lba, buffer.len())?;
+        let channel = PRIMARY.lock();
+        for (i, chunk) in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            channel.read(self.drive, (lba + (i * MAX_SECTORS_PER_COMMAND) as u64) as u32, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `count` sectors from the start of `buffer` to `lba`.
+    pub fn write_sectors(&self, lba: u64, count: usize, buffer: &[u8]) -> Result<(), AtaError> {
+        let buffer = buffer.get(..count * SECTOR_SIZE).ok_or(AtaError::BadBuffer)?;
+        self.check_range(lba, buffer.len())?;
+        let channel = PRIMARY.lock();
+        for (i, chunk) in buffer.chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            channel.write(self.drive, (lba + (i * MAX_SECTORS_PER_COMMAND) as u64) as u32, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.sectors()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        check_request(self, lba, buffer.len())?;
+        Ok(self.read_sectors(lba, buffer.len() / SECTOR_SIZE, buffer)?)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError> {
+        check_request(self, lba, buffer.len())?;
+        Ok(self.write_sectors(lba, buffer.len() / SECTOR_SIZE, buffer)?)
+    }
+
+    fn flush(&self) -> Result<(), IoError> {
+        Ok(PRIMARY.lock().flush(self.drive)?)
+    }
+}
+
+/// Probes both drives of the primary channel, prints what answered and
+/// registers it as `ata0` and `ata1`. Only the first call probes.
+pub fn init() -> &'static [Option<AtaDrive>; 2] {
+    let mut probed = false;
+    let drives = DRIVES.call_once(|| {
+        probed = true;
+        [Drive::Master, Drive::Slave].map(|drive| match AtaDrive::probe(drive) {
+            Ok(ata) => {
+                crate::println!("ATA {:?}: {} ({} MiB)", drive, ata.identity.model(),
+                    (ata.sectors() * SECTOR_SIZE as u64) >> 20);
+                Some(ata)
+            },
+            Err(AtaError::NoDevice) => None,
+            Err(err) => {
+                crate::println!("ATA {:?}: {}", drive, err);
+                None
+            },
+        })
+    });
+    if probed {
+        for (name, drive) in ["ata0", "ata1"].into_iter().zip(drives) {
+            if let Some(drive) = drive {
+                if let Err(err) = storage::register_device(name, drive) {
+                    crate::println!("ATA {:?}: {}", drive.drive, err);
+                }
+            }
+        }
+    }
+    drives
+}
+
+#[test_case]
+fn identify_strings_and_capacity() {
+    let mut words = [0u16; 256];
+    // "QEMU HARDDISK" padded with spaces, two characters per word, high byte first.
+    for (word, pair) in words[27..47].iter_mut().zip(b"QEMU HARDDISK                           ".chunks(2)) {
+        *word = u16::from_be_bytes([pair[0], pair[1]]);
+    }
+    for (word, pair) in words[23..27].iter_mut().zip(b"2.5+    ".chunks(2)) {
+        *word = u16::from_be_bytes([pair[0], pair[1]]);
+    }
+    words[60] = 0x0000;
+    words[61] = 0x0020;
+    let identity = Identity::parse(&words);
+    assert_eq!(identity.model(), "QEMU HARDDISK");
+    assert_eq!(identity.firmware(), "2.5+");
+    assert_eq!(identity.serial(), "");
+    assert_eq!(identity.sectors, 0x20_0000);
+    assert_eq!(identity.sectors_lba48, 0);
+
+    words[83] = 1 << 10;
+    words[100] = 0x0000;
+    words[102] = 0x0001;
+    assert_eq!(Identity::parse(&words).sectors_lba48, 1 << 32);
+}
+
+#[test_case]
+fn boot_disk_has_mbr_signature() {
+    // QEMU attaches the boot image as the primary master.
+    let disk = AtaDrive::probe(Drive::Master).expect("no primary master");
+    let mut sector = [0u8; SECTOR_SIZE];
+    disk.read_sectors(0, 1, &mut sector).unwrap();
+    assert_eq!(sector[510..], [0x55, 0xAA]);
+    assert_eq!(disk.read_sectors(disk.sectors(), 1, &mut sector), Err(AtaError::OutOfRange));
+    assert_eq!(disk.read_blocks(0, &mut sector[..100]), Err(IoError::BadBuffer));
+    assert_eq!(disk.read_blocks(disk.sectors(), &mut sector), Err(IoError::OutOfRange));
+
+    // kernel_main ran init, which registered the drive.
+    let registered = storage::device("ata0").expect("primary master not registered");
+    assert_eq!(registered.num_blocks(), disk.sectors());
+    registered.read_blocks(0, &mut sector).unwrap();
+    assert_eq!(sector[510..], [0x55, 0xAA]);
+}
+
+#[test_case]
+fn test_disk_read_write() {
+    // scripts/run-tests.sh attaches target/test-disk.img as the primary slave.
+    let disk = AtaDrive::probe(Drive::Slave).expect("no test disk as primary slave");
+    let mut sectors = [0u8; 2 * SECTOR_SIZE];
+    disk.read_sectors(0, 1, &mut sectors).unwrap();
+    assert!(sectors.starts_with(b"KRABBOS TEST DISK"));
+
+    for (i, byte) in sectors.iter_mut().enumerate() {
+        *byte = (i * 7) as u8;
+    }
+    disk.write_blocks(1, &sectors).unwrap();
+    let mut read_back = [0u8; 2 * SECTOR_SIZE];
+    disk.read_blocks(1, &mut read_back).unwrap();
+    assert_eq!(read_back, sectors);
+}
@@ -0,0 +1,502 @@
+//! Intel 8254x (e1000) Ethernet driver.
+//!
+//! Receive and transmit use rings of legacy descriptors, each with a 2KiB
+//! buffer. The interrupt handler reclaims sent descriptors and moves received
+//! frames to a queue read with [`receive`], which also polls the ring, so
+//! frames still arrive when the firmware routed the NIC to a line without a
+//! shared handler.
+
+use core::fmt;
+use crate::{
+    memory::{FRAME_ALLOCATOR, MAPPER},
+    pci::{self, bar::{BarRegion, MmioRegion, PciError}, PciDevice, CONFIG_INTERRUPT_LINE},
+    pic::{register_irq_handler, SHARED_IRQS},
+    println,
+    sync::Mutex,
+    tables::without_interrupts,
+};
+use super::allocate_dma;
+
+const VENDOR_INTEL: u16 = 0x8086;
+/// 82540EM (QEMU's default), 82545EM and 82543GC, which share the register layout used here.
+const DEVICE_IDS: [u16; 3] = [0x100E, 0x100F, 0x1004];
+
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00C0;
+const REG_IMS: u64 = 0x00D0;
+const REG_IMC: u64 = 0x00D8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+/// Multicast table array, 128 dwords.
+const REG_MTA: u64 = 0x5200;
+const REG_RAL0: u64 = 0x5400;
+const REG_RAH0: u64 = 0x5404;
+
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+/// RAH: the address in RAL0/RAH0 is valid.
+const RAH_AV: u32 = 1 << 31;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_UPE: u32 = 1 << 3;
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip the Ethernet CRC from received frames. The buffer size bits are left
+/// at 0, for 2048 byte buffers.
+const RCTL_SECRC: u32 = 1 << 26;
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+/// IPGT 10, IPGR1 8 and IPGR2 6, the IEEE 802.3 values for copper.
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+/// Interrupt causes.
+const INT_TXDW: u32 = 1 << 0;
+const INT_LSC: u32 = 1 << 2;
+const INT_RXT0: u32 = 1 << 7;
+
+const RX_DESCRIPTORS: usize = 32;
+const TX_DESCRIPTORS: usize = 32;
+const DESCRIPTOR_SIZE: usize = 16;
+const BUFFER_SIZE: usize = 2048;
+const FRAME_SIZE: usize = crate::memory::PAGE_SIZE_4K as usize;
+const BUFFERS_PER_FRAME: usize = FRAME_SIZE / BUFFER_SIZE;
+
+const RX_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const TX_STATUS_DD: u8 = 1 << 0;
+
+/// Largest frame sent or received, without the CRC.
+pub const MAX_FRAME: usize = 1514;
+/// Received frames kept until read, newer ones are dropped when it is full.
+const RECEIVE_QUEUE: usize = 16;
+
+/// Register reads before a reset or EEPROM read is given up on.
+const POLLS: u32 = 1_000_000;
+
+static NIC: Mutex<Option<E1000>> = Mutex::new("E1000", None);
+static RECEIVED: Mutex<FrameQueue> = Mutex::new("E1000_RECEIVED", FrameQueue::new());
+
+#[derive(Debug)]
+pub enum E1000Error {
+    NoDevice,
+    Pci(PciError),
+    NoMemory,
+    Timeout,
+}
+
+impl fmt::Display for E1000Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E1000Error::NoDevice => write!(f, "no e1000 NIC"),
+            E1000Error::Pci(err) => write!(f, "{}", err),
+            E1000Error::NoMemory => write!(f, "no DMA memory"),
+            E1000Error::Timeout => write!(f, "device timed out"),
+        }
+    }
+}
+
+impl From<PciError> for E1000Error {
+    fn from(err: PciError) -> Self {
+        E1000Error::Pci(err)
+    }
+}
+
+/// Every transmit descriptor is still owned by the NIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxFull;
+
+/// Received frames between the interrupt handler and their reader.
+struct FrameQueue {
+    frames: [[u8; MAX_FRAME]; RECEIVE_QUEUE],
+    lens: [usize; RECEIVE_QUEUE],
+    head: usize,
+    len: usize,
+}
+
+impl FrameQueue {
+    const fn new() -> Self {
+        FrameQueue { frames: [[0; MAX_FRAME]; RECEIVE_QUEUE], lens: [0; RECEIVE_QUEUE], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, frame: &[u8]) {
+        if self.len == RECEIVE_QUEUE {
+            return;
+        }
+        let slot = (self.head + self.len) % RECEIVE_QUEUE;
+        let len = frame.len().min(MAX_FRAME);
+        self.frames[slot][..len].copy_from_slice(&frame[..len]);
+        self.lens[slot] = len;
+        self.len += 1;
+    }
+
+    /// Copies the oldest frame into `buffer`, truncated to its length, and
+    /// returns the frame length.
+    fn pop(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let len = self.lens[self.head];
+        let copied = len.min(buffer.len());
+        buffer[..copied].copy_from_slice(&self.frames[self.head][..copied]);
+        self.head = (self.head + 1) % RECEIVE_QUEUE;
+        self.len -= 1;
+        Some(len)
+    }
+}
+
+/// A descriptor ring and its buffers: physical address and where it is mapped.
+struct Ring<const N: usize> {
+    descriptors: (u64, u64),
+    buffers: [(u64, u64); N],
+    /// Receive: next descriptor to check. Transmit: next descriptor to fill, the tail.
+    next: usize,
+    /// Transmit: next descriptor to reclaim.
+    clean: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    fn allocate() -> Result<Self, E1000Error> {
+        let descriptors = allocate_dma(1).ok_or(E1000Error::NoMemory)?;
+        let mut buffers = [(0, 0); N];
+        for frame in buffers.chunks_mut(BUFFERS_PER_FRAME) {
+            let (phys, virt) = allocate_dma(1).ok_or(E1000Error::NoMemory)?;
+            for (i, buffer) in frame.iter_mut().enumerate() {
+                *buffer = (phys + (i * BUFFER_SIZE) as u64, virt + (i * BUFFER_SIZE) as u64);
+            }
+        }
+        Ok(Ring { descriptors, buffers, next: 0, clean: 0 })
+    }
+
+    fn descriptor<T>(&self, index: usize, offset: usize) -> *mut T {
+        (self.descriptors.1 + (index * DESCRIPTOR_SIZE + offset) as u64) as *mut T
+    }
+
+    fn buffer(&self, index: usize) -> *mut u8 {
+        self.buffers[index].1 as *mut u8
+    }
+}
+
+/// An e1000 NIC.
+pub struct E1000 {
+    pub device: PciDevice,
+    regs: MmioRegion,
+    mac: [u8; 6],
+    rx: Ring<RX_DESCRIPTORS>,
+    tx: Ring<TX_DESCRIPTORS>,
+}
+
+impl E1000 {
+    /// Resets the first e1000 on the PCI bus and starts receiving and transmitting.
+    pub fn probe() -> Result<Self, E1000Error> {
+        let device = pci::devices()
+            .find(|d| d.vendor_id == VENDOR_INTEL && DEVICE_IDS.contains(&d.device_id))
+            .ok_or(E1000Error::NoDevice)?;
+        let regs = {
+            let mut mapper = MAPPER.lock();
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+                return Err(E1000Error::NoMemory);
+            };
+            match unsafe { device.map_bar(0, mapper, allocator)? } {
+                BarRegion::Mmio(region) => region,
+                BarRegion::Io(_) => return Err(E1000Error::Pci(PciError::NoSuchBar)),
+            }
+        };
+        unsafe { device.enable_bus_master(); }
+
+        let mut nic = E1000 { device, regs, mac: [0; 6], rx: Ring::allocate()?, tx: Ring::allocate()? };
+        nic.reset()?;
+        nic.mac = nic.read_mac();
+        nic.setup_rx();
+        nic.setup_tx();
+        nic.write(REG_CTRL, nic.read(REG_CTRL) | CTRL_SLU);
+        // Reading ICR clears what was pending from before the reset.
+        nic.read(REG_ICR);
+        nic.write(REG_IMS, INT_RXT0 | INT_TXDW | INT_LSC);
+        Ok(nic)
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        self.regs.read::<u32>(register)
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe { self.regs.write::<u32>(register, value) }
+    }
+
+    fn reset(&self) -> Result<(), E1000Error> {
+        self.write(REG_IMC, !0);
+        self.write(REG_CTRL, self.read(REG_CTRL) | CTRL_RST);
+        for _ in 0..POLLS {
+            if self.read(REG_CTRL) & CTRL_RST == 0 {
+                self.write(REG_IMC, !0);
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(E1000Error::Timeout)
+    }
+
+    fn read_eeprom(&self, word: u8) -> Option<u16> {
+        self.write(REG_EERD, EERD_START | (word as u32) << 8);
+        for _ in 0..POLLS {
+            let value = self.read(REG_EERD);
+            if value & EERD_DONE != 0 {
+                return Some((value >> 16) as u16);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    /// Reads the MAC address from the EEPROM, or from receive address 0 if the
+    /// EEPROM does not answer.
+    fn read_mac(&self) -> [u8; 6] {
+        let mut mac = [0; 6];
+        if let (Some(a), Some(b), Some(c)) = (self.read_eeprom(0), self.read_eeprom(1), self.read_eeprom(2)) {
+            for (bytes, word) in mac.chunks_mut(2).zip([a, b, c]) {
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+        } else {
+            mac[..4].copy_from_slice(&self.read(REG_RAL0).to_le_bytes());
+            mac[4..].copy_from_slice(&self.read(REG_RAH0).to_le_bytes()[..2]);
+        }
+        mac
+    }
+
+    fn setup_rx(&mut self) {
+        self.write(REG_RAL0, u32::from_le_bytes([self.mac[0], self.mac[1], self.mac[2], self.mac[3]]));
+        self.write(REG_RAH0, u16::from_le_bytes([self.mac[4], self.mac[5]]) as u32 | RAH_AV);
+        for i in 0..128 {
+            self.write(REG_MTA + i * 4, 0);
+        }
+        for i in 0..RX_DESCRIPTORS {
+            unsafe {
+                self.rx.descriptor::<u64>(i, 0).write_volatile(self.rx.buffers[i].0);
+                self.rx.descriptor::<u8>(i, 12).write_volatile(0);
+            }
+        }
+        let (phys, _) = self.rx.descriptors;
+        self.write(REG_RDBAL, phys as u32);
+        self.write(REG_RDBAH, (phys >> 32) as u32);
+        self.write(REG_RDLEN, (RX_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(REG_RDH, 0);
+        // Every descriptor but one is handed to the NIC, head == tail means none.
+        self.write(REG_RDT, RX_DESCRIPTORS as u32 - 1);
+        self.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+    }
+
+    fn setup_tx(&mut self) {
+        for i in 0..TX_DESCRIPTORS {
+            unsafe {
+                self.tx.descriptor::<u64>(i, 0).write_volatile(self.tx.buffers[i].0);
+                // Free descriptors are marked done, so reclaiming skips over them.
+                self.tx.descriptor::<u8>(i, 12).write_volatile(TX_STATUS_DD);
+            }
+        }
+        let (phys, _) = self.tx.descriptors;
+        self.write(REG_TDBAL, phys as u32);
+        self.write(REG_TDBAH, (phys >> 32) as u32);
+        self.write(REG_TDLEN, (TX_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(REG_TDH, 0);
+        self.write(REG_TDT, 0);
+        self.write(REG_TIPG, TIPG_DEFAULT);
+        self.write(REG_TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.read(REG_STATUS) & STATUS_LU != 0
+    }
+
+    /// Receives every unicast and multicast frame, not only those for this NIC.
+    pub fn set_promiscuous(&self, promiscuous: bool) {
+        let rctl = self.read(REG_RCTL);
+        let rctl = if promiscuous { rctl | RCTL_UPE | RCTL_MPE } else { rctl & !(RCTL_UPE | RCTL_MPE) };
+        self.write(REG_RCTL, rctl);
+    }
+
+    /// Queues `frame`, a complete Ethernet frame without its CRC, which the
+    /// NIC appends. Frames longer than [`MAX_FRAME`] panic.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), TxFull> {
+        assert!(frame.len() <= MAX_FRAME, "frame of {} bytes", frame.len());
+        self.reclaim_tx();
+        let index = self.tx.next;
+        let next = (index + 1) % TX_DESCRIPTORS;
+        // One descriptor stays unused, the ring would look empty otherwise.
+        if next == self.tx.clean {
+            return Err(TxFull);
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), self.tx.buffer(index), frame.len());
+            self.tx.descriptor::<u16>(index, 8).write_volatile(frame.len() as u16);
+            self.tx.descriptor::<u8>(index, 11).write_volatile(TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS);
+            self.tx.descriptor::<u8>(index, 12).write_volatile(0);
+        }
+        self.tx.next = next;
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        self.write(REG_TDT, next as u32);
+        Ok(())
+    }
+
+    /// Takes back the transmit descriptors the NIC is done with.
+    fn reclaim_tx(&mut self) {
+        while self.tx.clean != self.tx.next {
+            let status = unsafe { self.tx.descriptor::<u8>(self.tx.clean, 12).read_volatile() };
+            if status & TX_STATUS_DD == 0 {
+                break;
+            }
+            self.tx.clean = (self.tx.clean + 1) % TX_DESCRIPTORS;
+        }
+    }
+
+    /// Moves the received frames to `queue` and gives their descriptors back.
+    fn drain_rx(&mut self, queue: &mut FrameQueue) {
+        loop {
+            let index = self.rx.next;
+            let status = unsafe { self.rx.descriptor::<u8>(index, 12).read_volatile() };
+            if status & RX_STATUS_DD == 0 {
+                break;
+            }
+            let len = unsafe { self.rx.descriptor::<u16>(index, 8).read_volatile() } as usize;
+            // Frames spanning several buffers are longer than MAX_FRAME and dropped.
+            if status & RX_STATUS_EOP != 0 {
+                let frame = unsafe { core::slice::from_raw_parts(self.rx.buffer(index), len.min(BUFFER_SIZE)) };
+                queue.push(frame);
+            }
+            unsafe { self.rx.descriptor::<u8>(index, 12).write_volatile(0); }
+            self.rx.next = (index + 1) % RX_DESCRIPTORS;
+            self.write(REG_RDT, index as u32);
+        }
+    }
+
+    /// Acknowledges the pending interrupt causes and handles them.
+    pub fn handle_interrupt(&mut self) -> u32 {
+        let causes = self.read(REG_ICR);
+        if causes & INT_TXDW != 0 {
+            self.reclaim_tx();
+        }
+        if causes & INT_RXT0 != 0 {
+            self.drain_rx(&mut RECEIVED.lock());
+        }
+        causes
+    }
+}
+
+fn interrupt_handler() {
+    if let Some(nic) = NIC.lock().as_mut() {
+        let causes = nic.handle_interrupt();
+        if causes & INT_LSC != 0 {
+            println!("e1000: link {}", if nic.link_up() { "up" } else { "down" });
+        }
+    }
+}
+
+/// Runs `f` on the NIC set up by [`init`], with interrupts disabled.
+pub fn with_nic<R>(f: impl FnOnce(&mut E1000) -> R) -> Option<R> {
+    without_interrupts(|| NIC.lock().as_mut().map(f))
+}
+
+/// Sends `frame` on the NIC set up by [`init`]. Without a NIC the frame is
+/// dropped as if the ring were full.
+pub fn send(frame: &[u8]) -> Result<(), TxFull> {
+    with_nic(|nic| nic.send(frame)).unwrap_or(Err(TxFull))
+}
+
+/// Takes the oldest received frame, copying it into `buffer` (truncated to its
+/// length), and returns its length.
+pub fn receive(buffer: &mut [u8]) -> Option<usize> {
+    without_interrupts(|| {
+        // Same lock order as the interrupt handler.
+        let mut nic = NIC.lock();
+        let mut queue = RECEIVED.lock();
+        if let Some(nic) = nic.as_mut() {
+            nic.drain_rx(&mut queue);
+        }
+        queue.pop(buffer)
+    })
+}
+
+/// Sets up the first e1000 and its interrupt, returning its MAC address.
+pub fn init() -> Option<[u8; 6]> {
+    let nic = match E1000::probe() {
+        Ok(nic) => nic,
+        Err(E1000Error::NoDevice) => return None,
+        Err(err) => {
+            println!("e1000: {}", err);
+            return None;
+        },
+    };
+    let mac = nic.mac();
+    let line = nic.device.address.read_u8(CONFIG_INTERRUPT_LINE);
+    println!("e1000 at {}: MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}, IRQ {}, link {}",
+        nic.device.address, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5], line,
+        if nic.link_up() { "up" } else { "down" });
+    without_interrupts(|| *NIC.lock() = Some(nic));
+    if SHARED_IRQS.contains(&line) {
+        register_irq_handler(line, interrupt_handler);
+    }
+    Some(mac)
+}
+
+#[test_case]
+fn arp_request_to_the_gateway() {
+    use crate::pic::timer::delay_ms;
+
+    // QEMU user networking: the guest is 10.0.2.15, the gateway 10.0.2.2.
+    const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+    const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+
+    if with_nic(|_| ()).is_none() {
+        init().expect("no e1000 NIC");
+    }
+    let mac = with_nic(|nic| nic.mac()).unwrap();
+    let mut request = [0u8; 42];
+    request[0..6].copy_from_slice(&[0xFF; 6]);
+    request[6..12].copy_from_slice(&mac);
+    request[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+    // Ethernet, IPv4, 6 and 4 byte addresses, request.
+    request[14..22].copy_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01]);
+    request[22..28].copy_from_slice(&mac);
+    request[28..32].copy_from_slice(&GUEST_IP);
+    request[38..42].copy_from_slice(&GATEWAY_IP);
+    send(&request).unwrap();
+
+    let mut frame = [0u8; MAX_FRAME];
+    for _ in 0..100 {
+        while let Some(len) = receive(&mut frame) {
+            let is_arp_reply = len >= 42 && frame[12..14] == [0x08, 0x06] && frame[20..22] == [0x00, 0x02];
+            if is_arp_reply && frame[28..32] == GATEWAY_IP {
+                assert_eq!(&frame[0..6], &mac);
+                assert_eq!(&frame[38..42], &GUEST_IP);
+                return;
+            }
+        }
+        delay_ms(10);
+    }
+    panic!("no ARP reply from the gateway");
+}
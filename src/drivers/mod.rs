@@ -0,0 +1,44 @@
+//! Device drivers.
+
+pub mod ahci;
+pub mod ata;
+pub mod e1000;
+pub mod smbios;
+pub mod vbe;
+pub mod virtio;
+
+use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+
+const FRAME_SIZE: u64 = crate::memory::PAGE_SIZE_4K;
+/// Frames skipped looking for a contiguous run before giving up.
+const CONTIGUOUS_ATTEMPTS: usize = 64;
+
+/// Allocates `count` physically contiguous zeroed frames for DMA, returning
+/// the physical address of the first and where it is mapped.
+///
+/// The frames are taken in memory map order, past the free list, so a run only
+/// breaks at the end of a memory region; the frames before the break are given up.
+pub fn allocate_dma(count: usize) -> Option<(u64, u64)> {
+    let offset = MAPPER.lock().as_ref()?.phys_offset();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut()?;
+    let mut frame = || allocator.allocate_frame_in_order().map(|frame| frame.start_address());
+
+    let mut start = frame()?;
+    let mut len = 1;
+    let mut attempts = 0;
+    while len < count {
+        let next = frame()?;
+        if next == start + len as u64 * FRAME_SIZE {
+            len += 1;
+        } else if attempts < CONTIGUOUS_ATTEMPTS {
+            attempts += len;
+            start = next;
+            len = 1;
+        } else {
+            return None;
+        }
+    }
+    unsafe { core::ptr::write_bytes((start + offset) as *mut u8, 0, count * FRAME_SIZE as usize) };
+    Some((start, start + offset))
+}
@@ -0,0 +1,976 @@
+//! SMBIOS, the firmware's description of the machine: BIOS, system, board,
+//! processors and memory devices.
+//!
+//! The entry point, `_SM3_` for SMBIOS 3 and `_SM_` before, is found on a 16
+//! byte boundary in the BIOS area and names the structure table. Each structure
+//! is a formatted area, whose length is in its header, followed by its strings,
+//! each ended by a NUL and the whole set by one more. Formatted fields refer to
+//! the strings by a 1-based index, 0 meaning no string.
+//!
+//! Firmware gets these tables wrong often enough that nothing here trusts them:
+//! the walk stops at the first structure that does not fit the table, fields
+//! past a structure's length read as missing, and so do strings past its set.
+
+use core::fmt;
+use spin::Once;
+use crate::{
+    memory::{paging::{map_physical_region, PageTableFlags}, FRAME_ALLOCATOR, MAPPER},
+    print, println,
+};
+
+const BIOS_AREA_START: u64 = 0xF0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+const ANCHOR_2: &[u8; 4] = b"_SM_";
+const INTERMEDIATE_ANCHOR: &[u8; 5] = b"_DMI_";
+const ANCHOR_3: &[u8; 5] = b"_SM3_";
+/// The 2.1 specification gave the entry point length as 0x1E, and firmware
+/// following it still does, though the structure is 0x1F bytes.
+const ENTRY_2_LENGTHS: core::ops::RangeInclusive<usize> = 0x1E..=0x20;
+const ENTRY_2_INTERMEDIATE: core::ops::Range<usize> = 0x10..0x1F;
+const ENTRY_3_LENGTH: usize = 0x18;
+
+/// SMBIOS 3 only gives a maximum size, tables longer than this are cut there.
+const MAX_TABLE_LENGTH: u32 = 1 << 20;
+const HEADER_LENGTH: usize = 4;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BASEBOARD: u8 = 2;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END_OF_TABLE: u8 = 127;
+
+/// Structures of a type past this many are counted but not kept.
+const MAX_PROCESSORS: usize = 16;
+const MAX_MEMORY_DEVICES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmbiosError {
+    NoEntryPoint,
+    /// The physical memory could not be mapped.
+    Unmapped,
+    /// The entry point names an empty table.
+    EmptyTable,
+}
+
+impl fmt::Display for SmbiosError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmbiosError::NoEntryPoint => write!(f, "no entry point found"),
+            SmbiosError::Unmapped => write!(f, "table memory cannot be mapped"),
+            SmbiosError::EmptyTable => write!(f, "empty structure table"),
+        }
+    }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// What an entry point says about the structure table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryPoint {
+    pub major: u8,
+    pub minor: u8,
+    pub table_address: u64,
+    /// The exact length before SMBIOS 3, the maximum since.
+    pub table_length: u32,
+    /// Only given before SMBIOS 3, where the table has no end structure to rely on.
+    pub structure_count: Option<u16>,
+}
+
+/// Validates an entry point candidate, either version.
+fn parse_entry_point(bytes: &[u8]) -> Option<EntryPoint> {
+    if bytes.starts_with(ANCHOR_3) {
+        let length = *bytes.get(6)? as usize;
+        let entry = bytes.get(..length.max(ENTRY_3_LENGTH))?;
+        if length < ENTRY_3_LENGTH || !checksum_ok(&entry[..length]) {
+            return None;
+        }
+        return Some(EntryPoint {
+            major: entry[7],
+            minor: entry[8],
+            table_address: u64::from_le_bytes(entry[0x10..0x18].try_into().unwrap()),
+            table_length: u32::from_le_bytes(entry[0x0C..0x10].try_into().unwrap()).min(MAX_TABLE_LENGTH),
+            structure_count: None,
+        });
+    }
+    if !bytes.starts_with(ANCHOR_2) {
+        return None;
+    }
+    let length = *bytes.get(5)? as usize;
+    let entry = bytes.get(..*ENTRY_2_LENGTHS.end())?;
+    if !ENTRY_2_LENGTHS.contains(&length)
+        || !checksum_ok(&entry[..length])
+        || &entry[ENTRY_2_INTERMEDIATE.start..ENTRY_2_INTERMEDIATE.start + 5] != INTERMEDIATE_ANCHOR
+        || !checksum_ok(&entry[ENTRY_2_INTERMEDIATE])
+    {
+        return None;
+    }
+    Some(EntryPoint {
+        major: entry[6],
+        minor: entry[7],
+        table_address: u32::from_le_bytes(entry[0x18..0x1C].try_into().unwrap()) as u64,
+        table_length: u16::from_le_bytes([entry[0x16], entry[0x17]]) as u32,
+        structure_count: Some(u16::from_le_bytes([entry[0x1C], entry[0x1D]])),
+    })
+}
+
+/// Scans `area` on 16 byte boundaries for an entry point, preferring SMBIOS 3
+/// when the firmware has both. Returns it and its offset in `area`.
+fn search_entry_point(area: &[u8]) -> Option<(usize, EntryPoint)> {
+    let mut found = None;
+    for i in (0..area.len()).step_by(16) {
+        if let Some(entry) = parse_entry_point(&area[i..]) {
+            if entry.structure_count.is_none() {
+                return Some((i, entry));
+            }
+            found = found.or(Some((i, entry)));
+        }
+    }
+    found
+}
+
+/// One structure of the table.
+#[derive(Debug, Clone, Copy)]
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    /// The formatted area, header included.
+    formatted: &'a [u8],
+    /// The strings, each ended by a NUL but the last, without the final NUL pair.
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(self.formatted.get(offset..offset + 2)?.try_into().unwrap()))
+    }
+
+    pub fn dword(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.formatted.get(offset..offset + 4)?.try_into().unwrap()))
+    }
+
+    /// Returns string `index`, counted from 1, without the padding some
+    /// firmware adds. `None` for index 0, a missing or blank string, or one
+    /// that is not ASCII.
+    pub fn string(&self, index: u8) -> Option<&'a str> {
+        let index = (index as usize).checked_sub(1)?;
+        let string = self.strings.split(|&b| b == 0).nth(index)?;
+        let string = core::str::from_utf8(string).ok().filter(|s| s.is_ascii())?.trim();
+        (!string.is_empty()).then_some(string)
+    }
+
+    /// Returns the string whose index is at `offset` in the formatted area.
+    pub fn string_at(&self, offset: usize) -> Option<&'a str> {
+        self.string(self.byte(offset)?)
+    }
+}
+
+/// Walks the structures of a table, see [`Table::structures`].
+pub struct Structures<'a> {
+    table: &'a [u8],
+    offset: usize,
+    /// Structures left before SMBIOS 3, which gives their count.
+    remaining: Option<u16>,
+    done: bool,
+    malformed: bool,
+}
+
+impl<'a> Structures<'a> {
+    /// Whether the walk stopped at a structure that does not fit the table,
+    /// rather than at the end structure, the structure count or the table end.
+    pub fn malformed(&self) -> bool {
+        self.malformed
+    }
+
+    fn stop(&mut self, malformed: bool) -> Option<Structure<'a>> {
+        self.done = true;
+        self.malformed = malformed;
+        None
+    }
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Structure<'a>> {
+        if self.done || self.remaining == Some(0) {
+            return None;
+        }
+        let rest = &self.table[self.offset..];
+        if rest.is_empty() {
+            return self.stop(false);
+        }
+        let Some(&[kind, length, handle_low, handle_high]) = rest.get(..HEADER_LENGTH) else {
+            return self.stop(true);
+        };
+        let length = length as usize;
+        if length < HEADER_LENGTH || length > rest.len() {
+            return self.stop(true);
+        }
+        // A structure without strings still ends with the NUL pair.
+        let Some(strings_length) = rest[length..].windows(2).position(|pair| pair == [0, 0]) else {
+            return self.stop(true);
+        };
+        if kind == TYPE_END_OF_TABLE {
+            return self.stop(false);
+        }
+        self.offset += length + strings_length + 2;
+        self.remaining = self.remaining.map(|count| count - 1);
+        Some(Structure {
+            kind,
+            handle: u16::from_le_bytes([handle_low, handle_high]),
+            formatted: &rest[..length],
+            strings: &rest[length..length + strings_length],
+        })
+    }
+}
+
+/// A structure table and the version of the entry point naming it.
+#[derive(Debug, Clone, Copy)]
+pub struct Table<'a> {
+    pub major: u8,
+    pub minor: u8,
+    bytes: &'a [u8],
+    structure_count: Option<u16>,
+}
+
+impl<'a> Table<'a> {
+    pub fn new(entry: &EntryPoint, bytes: &'a [u8]) -> Self {
+        Table { major: entry.major, minor: entry.minor, bytes, structure_count: entry.structure_count }
+    }
+
+    pub fn structures(&self) -> Structures<'a> {
+        Structures { table: self.bytes, offset: 0, remaining: self.structure_count, done: false, malformed: false }
+    }
+}
+
+/// A UUID, with the first three fields little endian as SMBIOS 2.6 has them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uuid(pub [u8; 16]);
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(f, "{:08x}-{:04x}-{:04x}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]))?;
+        write!(f, "{:02x}{:02x}-", b[8], b[9])?;
+        b[10..].iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// Type 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BiosInfo<'a> {
+    pub vendor: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub release_date: Option<&'a str>,
+    /// The ROM size in bytes.
+    pub rom_size: Option<u64>,
+    /// The BIOS major and minor release, since SMBIOS 2.4.
+    pub release: Option<(u8, u8)>,
+}
+
+impl<'a> BiosInfo<'a> {
+    fn parse(structure: &Structure<'a>) -> Option<Self> {
+        let rom_size = match structure.byte(0x09)? {
+            0xFF => structure.word(0x18).and_then(|extended| {
+                let size = (extended & 0x3FFF) as u64;
+                match extended >> 14 {
+                    0 => Some(size << 20),
+                    1 => Some(size << 30),
+                    _ => None,
+                }
+            }),
+            blocks => Some((blocks as u64 + 1) * 0x10000),
+        };
+        let release = structure.byte(0x14).zip(structure.byte(0x15)).filter(|&release| release != (0xFF, 0xFF));
+        Some(BiosInfo {
+            vendor: structure.string_at(0x04),
+            version: structure.string_at(0x05),
+            release_date: structure.string_at(0x08),
+            rom_size,
+            release,
+        })
+    }
+}
+
+/// Type 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemInfo<'a> {
+    pub manufacturer: Option<&'a str>,
+    pub product: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub serial_number: Option<&'a str>,
+    /// `None` when all zeros, not set, or all ones, not present.
+    pub uuid: Option<Uuid>,
+    pub sku: Option<&'a str>,
+    pub family: Option<&'a str>,
+}
+
+impl<'a> SystemInfo<'a> {
+    fn parse(structure: &Structure<'a>) -> Option<Self> {
+        structure.byte(0x07)?;
+        let uuid = structure.formatted.get(0x08..0x18)
+            .map(|bytes| Uuid(bytes.try_into().unwrap()))
+            .filter(|uuid| uuid.0 != [0; 16] && uuid.0 != [0xFF; 16]);
+        Some(SystemInfo {
+            manufacturer: structure.string_at(0x04),
+            product: structure.string_at(0x05),
+            version: structure.string_at(0x06),
+            serial_number: structure.string_at(0x07),
+            uuid,
+            sku: structure.string_at(0x19),
+            family: structure.string_at(0x1A),
+        })
+    }
+}
+
+/// Type 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Baseboard<'a> {
+    pub manufacturer: Option<&'a str>,
+    pub product: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub serial_number: Option<&'a str>,
+    pub asset_tag: Option<&'a str>,
+}
+
+impl<'a> Baseboard<'a> {
+    fn parse(structure: &Structure<'a>) -> Option<Self> {
+        structure.byte(0x07)?;
+        Some(Baseboard {
+            manufacturer: structure.string_at(0x04),
+            product: structure.string_at(0x05),
+            version: structure.string_at(0x06),
+            serial_number: structure.string_at(0x07),
+            asset_tag: structure.string_at(0x08),
+        })
+    }
+}
+
+/// Type 4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor<'a> {
+    pub socket: Option<&'a str>,
+    pub manufacturer: Option<&'a str>,
+    pub version: Option<&'a str>,
+    /// The processor family, from the second family field when the first says so.
+    pub family: u16,
+    /// The speeds in MHz, `None` when unknown.
+    pub max_speed: Option<u16>,
+    pub current_speed: Option<u16>,
+    pub populated: bool,
+    /// Since SMBIOS 2.5, from the 3.0 fields past 255.
+    pub cores: Option<u16>,
+    pub threads: Option<u16>,
+}
+
+impl<'a> Processor<'a> {
+    fn parse(structure: &Structure<'a>) -> Option<Self> {
+        let status = structure.byte(0x18)?;
+        let family = match structure.byte(0x06)? {
+            0xFE => structure.word(0x28).unwrap_or(0xFE),
+            family => family as u16,
+        };
+        let speed = |offset| structure.word(offset).filter(|&mhz| mhz != 0);
+        // A count of 0xFF points to the 16 bit field, which 0 or 0xFFFF leave unknown.
+        let count = |offset, extended_offset| match structure.byte(offset)? {
+            0 => None,
+            0xFF => structure.word(extended_offset).filter(|&count| count != 0 && count != 0xFFFF).or(Some(0xFF)),
+            count => Some(count as u16),
+        };
+        Some(Processor {
+            socket: structure.string_at(0x04),
+            manufacturer: structure.string_at(0x07),
+            version: structure.string_at(0x10),
+            family,
+            max_speed: speed(0x14),
+            current_speed: speed(0x16),
+            populated: status & 1 << 6 != 0,
+            cores: count(0x23, 0x2A),
+            threads: count(0x25, 0x2E),
+        })
+    }
+}
+
+/// The size of a memory device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemorySize {
+    /// An empty slot.
+    Empty,
+    Unknown,
+    Bytes(u64),
+}
+
+/// Type 17.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDevice<'a> {
+    pub locator: Option<&'a str>,
+    pub bank: Option<&'a str>,
+    pub size: MemorySize,
+    pub memory_type: u8,
+    /// In MT/s, since SMBIOS 2.3.
+    pub speed: Option<u32>,
+    pub manufacturer: Option<&'a str>,
+    pub serial_number: Option<&'a str>,
+    pub part_number: Option<&'a str>,
+}
+
+impl<'a> MemoryDevice<'a> {
+    fn parse(structure: &Structure<'a>) -> Option<Self> {
+        let memory_type = structure.byte(0x12)?;
+        let size = match structure.word(0x0C)? {
+            0 => MemorySize::Empty,
+            0xFFFF => MemorySize::Unknown,
+            0x7FFF => structure.dword(0x1C)
+                .map_or(MemorySize::Unknown, |mib| MemorySize::Bytes(((mib & 0x7FFF_FFFF) as u64) << 20)),
+            size if size & 0x8000 != 0 => MemorySize::Bytes(((size & 0x7FFF) as u64) << 10),
+            size => MemorySize::Bytes((size as u64) << 20),
+        };
+        let speed = match structure.word(0x15) {
+            Some(0xFFFF) => structure.dword(0x54),
+            speed => speed.map(u32::from),
+        };
+        Some(MemoryDevice {
+            locator: structure.string_at(0x10),
+            bank: structure.string_at(0x11),
+            size,
+            memory_type,
+            speed: speed.filter(|&speed| speed != 0),
+            manufacturer: structure.string_at(0x17),
+            serial_number: structure.string_at(0x18),
+            part_number: structure.string_at(0x1A),
+        })
+    }
+
+    pub fn is_installed(&self) -> bool {
+        self.size != MemorySize::Empty
+    }
+
+    /// The name of the memory type, `None` for the ones not listed here.
+    pub fn type_name(&self) -> Option<&'static str> {
+        Some(match self.memory_type {
+            0x01 => "other",
+            0x02 => "unknown",
+            0x03 => "DRAM",
+            0x07 => "RAM",
+            0x0F => "SDRAM",
+            0x12 => "DDR",
+            0x13 => "DDR2",
+            0x18 => "DDR3",
+            0x1A => "DDR4",
+            0x1B => "LPDDR",
+            0x1C => "LPDDR2",
+            0x1D => "LPDDR3",
+            0x1E => "LPDDR4",
+            0x22 => "DDR5",
+            0x23 => "LPDDR5",
+            _ => return None,
+        })
+    }
+}
+
+/// The decoded structures of a table. The first structure of the types that
+/// should have one is kept, the others skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct Info<'a> {
+    pub table: Table<'a>,
+    pub bios: Option<BiosInfo<'a>>,
+    pub system: Option<SystemInfo<'a>>,
+    pub baseboard: Option<Baseboard<'a>>,
+    processors: [Option<Processor<'a>>; MAX_PROCESSORS],
+    memory_devices: [Option<MemoryDevice<'a>>; MAX_MEMORY_DEVICES],
+    /// How many structures the table has, decoded or not.
+    pub structures: usize,
+    /// Whether the walk stopped early, see [`Structures::malformed`].
+    pub malformed: bool,
+}
+
+impl<'a> Info<'a> {
+    /// Decodes the structures of `table`, skipping the ones too short to decode.
+    pub fn parse(table: Table<'a>) -> Self {
+        let mut info = Info {
+            table,
+            bios: None,
+            system: None,
+            baseboard: None,
+            processors: [None; MAX_PROCESSORS],
+            memory_devices: [None; MAX_MEMORY_DEVICES],
+            structures: 0,
+            malformed: false,
+        };
+        let (mut processors, mut memory_devices) = (info.processors.iter_mut(), info.memory_devices.iter_mut());
+        let mut structures = table.structures();
+        for structure in &mut structures {
+            info.structures += 1;
+            match structure.kind {
+                TYPE_BIOS if info.bios.is_none() => info.bios = BiosInfo::parse(&structure),
+                TYPE_SYSTEM if info.system.is_none() => info.system = SystemInfo::parse(&structure),
+                TYPE_BASEBOARD if info.baseboard.is_none() => info.baseboard = Baseboard::parse(&structure),
+                TYPE_PROCESSOR => if let (Some(processor), Some(slot)) = (Processor::parse(&structure), processors.next()) {
+                    *slot = Some(processor);
+                },
+                TYPE_MEMORY_DEVICE => if let (Some(device), Some(slot)) = (MemoryDevice::parse(&structure), memory_devices.next()) {
+                    *slot = Some(device);
+                },
+                _ => {},
+            }
+        }
+        info.malformed = structures.malformed();
+        info
+    }
+
+    pub fn processors(&self) -> impl Iterator<Item = &Processor<'a>> {
+        self.processors.iter().map_while(Option::as_ref)
+    }
+
+    pub fn memory_devices(&self) -> impl Iterator<Item = &MemoryDevice<'a>> {
+        self.memory_devices.iter().map_while(Option::as_ref)
+    }
+
+    /// The size of the installed memory devices of known size, in bytes.
+    pub fn memory_size(&self) -> u64 {
+        self.memory_devices().filter_map(|device| match device.size {
+            MemorySize::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }).sum()
+    }
+}
+
+/// Returns `len` bytes of physical memory at `phys`, mapping them if needed.
+unsafe fn physical_bytes(phys_offset: u64, phys: u64, len: usize) -> Result<&'static [u8], SmbiosError> {
+    let mut mapper = MAPPER.lock();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+        return Err(SmbiosError::Unmapped);
+    };
+    unsafe { map_physical_region(phys, len as u64, PageTableFlags::PRESENT, mapper, allocator) }
+        .map_err(|_| SmbiosError::Unmapped)?;
+    Ok(unsafe { core::slice::from_raw_parts(phys.wrapping_add(phys_offset) as *const u8, len) })
+}
+
+static INFO: Once<Info<'static>> = Once::new();
+
+/// Finds the entry point, or takes `entry_address` when the bootloader
+/// reported one, decodes the structure table and prints a summary of it.
+///
+/// ## Safety
+///
+/// [`crate::memory::init`] must have run, with the physical memory window at
+/// `physical_memory_offset`.
+pub unsafe fn init(physical_memory_offset: u64, entry_address: Option<u64>) -> Result<&'static Info<'static>, SmbiosError> {
+    let entry = match entry_address {
+        Some(address) => {
+            let bytes = unsafe { physical_bytes(physical_memory_offset, address, *ENTRY_2_LENGTHS.end())? };
+            parse_entry_point(bytes)
+        },
+        None => {
+            let area = unsafe {
+                physical_bytes(physical_memory_offset, BIOS_AREA_START, (BIOS_AREA_END - BIOS_AREA_START) as usize)?
+            };
+            search_entry_point(area).map(|(_, entry)| entry)
+        },
+    };
+    let entry = entry.ok_or(SmbiosError::NoEntryPoint)?;
+    if entry.table_length == 0 || entry.table_address == 0 {
+        return Err(SmbiosError::EmptyTable);
+    }
+    let bytes = unsafe { physical_bytes(physical_memory_offset, entry.table_address, entry.table_length as usize)? };
+    let info = INFO.call_once(|| Info::parse(Table::new(&entry, bytes)));
+    print_summary(info);
+    Ok(info)
+}
+
+/// Returns the decoded table, `None` before [`init`] or without one.
+pub fn info() -> Option<&'static Info<'static>> {
+    INFO.get()
+}
+
+fn or_unknown(string: Option<&str>) -> &str {
+    string.unwrap_or("unknown")
+}
+
+fn print_summary(info: &Info) {
+    print!("SMBIOS {}.{}:", info.table.major, info.table.minor);
+    if let Some(system) = &info.system {
+        print!(" {} {},", or_unknown(system.manufacturer), or_unknown(system.product));
+    }
+    if let Some(bios) = &info.bios {
+        print!(" BIOS {} {}", or_unknown(bios.version), or_unknown(bios.release_date));
+    }
+    println!();
+    let installed = info.memory_devices().filter(|device| device.is_installed()).count();
+    println!("SMBIOS: {} processors, {} of {} memory devices with {} MiB{}",
+        info.processors().count(), installed, info.memory_devices().count(), info.memory_size() >> 20,
+        if info.malformed { ", table cut short" } else { "" });
+}
+
+/// Prints every decoded structure, then the type and handle of the rest.
+pub fn dump() {
+    let Some(info) = info() else {
+        println!("SMBIOS: no table");
+        return;
+    };
+    println!("SMBIOS {}.{}, {} structures", info.table.major, info.table.minor, info.structures);
+    if let Some(bios) = &info.bios {
+        print!("BIOS: {} {} {}", or_unknown(bios.vendor), or_unknown(bios.version), or_unknown(bios.release_date));
+        if let Some((major, minor)) = bios.release {
+            print!(", release {}.{}", major, minor);
+        }
+        if let Some(size) = bios.rom_size {
+            print!(", {} KiB ROM", size >> 10);
+        }
+        println!();
+    }
+    if let Some(system) = &info.system {
+        println!("System: {} {} {}, serial {}, family {}, SKU {}",
+            or_unknown(system.manufacturer), or_unknown(system.product), or_unknown(system.version),
+            or_unknown(system.serial_number), or_unknown(system.family), or_unknown(system.sku));
+        if let Some(uuid) = system.uuid {
+            println!("  UUID {}", uuid);
+        }
+    }
+    if let Some(board) = &info.baseboard {
+        println!("Board: {} {} {}, serial {}, asset tag {}",
+            or_unknown(board.manufacturer), or_unknown(board.product), or_unknown(board.version),
+            or_unknown(board.serial_number), or_unknown(board.asset_tag));
+    }
+    for processor in info.processors() {
+        print!("Processor {}: ", or_unknown(processor.socket));
+        if !processor.populated {
+            println!("empty");
+            continue;
+        }
+        print!("{} {}, family {:#x}", or_unknown(processor.manufacturer), or_unknown(processor.version), processor.family);
+        if let Some(mhz) = processor.current_speed {
+            print!(", {} of {} MHz", mhz, processor.max_speed.unwrap_or(mhz));
+        }
+        if let (Some(cores), Some(threads)) = (processor.cores, processor.threads) {
+            print!(", {} cores, {} threads", cores, threads);
+        }
+        println!();
+    }
+    for device in info.memory_devices() {
+        print!("Memory {} {}: ", or_unknown(device.locator), device.bank.unwrap_or(""));
+        match device.size {
+            MemorySize::Empty => {
+                println!("empty");
+                continue;
+            },
+            MemorySize::Unknown => print!("unknown size"),
+            MemorySize::Bytes(bytes) => print!("{} MiB", bytes >> 20),
+        }
+        match device.type_name() {
+            Some(name) => print!(" {}", name),
+            None => print!(" type {:#x}", device.memory_type),
+        }
+        if let Some(speed) = device.speed {
+            print!(" {} MT/s", speed);
+        }
+        println!(", {} {}", or_unknown(device.manufacturer), or_unknown(device.part_number));
+    }
+    let decoded = [TYPE_BIOS, TYPE_SYSTEM, TYPE_BASEBOARD, TYPE_PROCESSOR, TYPE_MEMORY_DEVICE];
+    print!("Other:");
+    for structure in info.table.structures().filter(|structure| !decoded.contains(&structure.kind)) {
+        print!(" {}@{:#x}", structure.kind, structure.handle);
+    }
+    println!();
+    if info.malformed {
+        println!("The table is cut short after {} structures", info.structures);
+    }
+}
+
+/// A structure table built for the tests.
+#[cfg(test)]
+struct Blob {
+    bytes: [u8; 1024],
+    len: usize,
+}
+
+#[cfg(test)]
+impl Blob {
+    fn new() -> Self {
+        Blob { bytes: [0; 1024], len: 0 }
+    }
+
+    fn raw(&mut self, bytes: &[u8]) -> &mut Self {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        self
+    }
+
+    /// Appends a structure of `kind` with the formatted area `fields` after
+    /// the header, and `strings`.
+    fn structure(&mut self, kind: u8, handle: u16, fields: &[u8], strings: &[&str]) -> &mut Self {
+        let [low, high] = handle.to_le_bytes();
+        self.raw(&[kind, (HEADER_LENGTH + fields.len()) as u8, low, high]).raw(fields);
+        for string in strings {
+            self.raw(string.as_bytes()).raw(&[0]);
+        }
+        if strings.is_empty() {
+            self.raw(&[0]);
+        }
+        self.raw(&[0])
+    }
+
+    fn end(&mut self) -> &mut Self {
+        self.structure(TYPE_END_OF_TABLE, 0xFEFF, &[], &[])
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// A table laid out like the one QEMU 8 gives SeaBIOS for `-M pc -m 128`:
+/// SMBIOS 2.8, no baseboard, one CPU and one DIMM.
+#[cfg(test)]
+fn qemu_table() -> Blob {
+    let mut blob = Blob::new();
+    blob.structure(TYPE_BIOS, 0, &[
+        1, 2, 0x00, 0xE8, 3, 0x00, 0x08, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x04, 0xFF, 0xFF, 0xFF, 0xFF,
+    ], &["SeaBIOS", "rel-1.16.3-0-ga6ed6b701f0a-prebuilt.qemu.org", "04/01/2014"]);
+    blob.structure(TYPE_SYSTEM, 0x100, &[
+        1, 2, 3, 0,
+        0x78, 0x56, 0x34, 0x12, 0x34, 0x12, 0x78, 0x56, 0x9A, 0xBC, 0xDE, 0xF0, 0x12, 0x34, 0x56, 0x78,
+        0x06, 0, 0,
+    ], &["QEMU", "Standard PC (i440FX + PIIX, 1996)", "pc-i440fx-8.2"]);
+    blob.structure(3, 0x300, &[1, 0x01, 0, 0, 0, 0x03, 0x03, 0x03, 0x02, 0, 0, 0, 0, 0, 0, 0, 0], &["QEMU"]);
+    blob.structure(TYPE_PROCESSOR, 0x400, &[
+        1, 0x03, 0x01, 2, 0x63, 0x06, 0, 0, 0xFF, 0xFB, 0x8B, 0x07, 3, 0, 0, 0, 0xD0, 0x07, 0xD0, 0x07,
+        0x41, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 1, 0, 1, 0x02, 0x00, 0x01, 0x00,
+        1, 0, 1, 0, 1, 0,
+    ], &["CPU 0", "QEMU", "pc-i440fx-8.2"]);
+    blob.structure(16, 0x1000, &[0x01, 0x03, 0x06, 0x00, 0x00, 0x02, 0x00, 0xFE, 0xFF, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0], &[]);
+    blob.structure(TYPE_MEMORY_DEVICE, 0x1100, &[
+        0x00, 0x10, 0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x80, 0x00, 0x09, 0x00, 1, 0, 0x07, 0x02, 0x00, 0, 0,
+        2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ], &["DIMM 0", "QEMU"]);
+    blob.structure(32, 0x2000, &[0, 0, 0, 0, 0, 0, 0], &[]);
+    blob.end();
+    blob
+}
+
+/// A table laid out like a desktop board's: SMBIOS 3.3, padded part
+/// numbers, two of four slots empty, and an eight core CPU.
+#[cfg(test)]
+fn desktop_table() -> Blob {
+    let mut blob = Blob::new();
+    blob.structure(TYPE_BIOS, 0, &[
+        1, 2, 0x00, 0xF0, 3, 0xFF, 0x80, 0x98, 0x8B, 0x3F, 0x01, 0, 0, 0, 0xFF, 0x0D, 5, 23, 0xFF, 0xFF,
+        0x20, 0x00,
+    ], &["American Megatrends International, LLC.", "F20", "11/29/2023"]);
+    blob.structure(TYPE_SYSTEM, 1, &[
+        1, 2, 3, 4,
+        0x03, 0x00, 0x02, 0x00, 0x04, 0x00, 0x05, 0x00, 0x00, 0x06, 0x00, 0x07, 0x00, 0x08, 0x00, 0x09,
+        0x06, 5, 6,
+    ], &["Gigabyte Technology Co., Ltd.", "B550 AORUS ELITE V2", "Default string", "Default string",
+        "Default string", "B550 MB"]);
+    blob.structure(TYPE_BASEBOARD, 2, &[1, 2, 3, 4, 5, 0x09, 5, 0x03, 0x00, 0x0A, 0], &[
+        "Gigabyte Technology Co., Ltd.", "B550 AORUS ELITE V2", "x.x", "Default string", "Default string",
+    ]);
+    blob.structure(TYPE_PROCESSOR, 0x25, &[
+        1, 0x03, 0x6B, 2, 0x10, 0x0F, 0xA2, 0x00, 0xFF, 0xFB, 0x8B, 0x17, 3, 0x8B, 0x64, 0x00, 0xB8, 0x11,
+        0x76, 0x0F, 0x41, 0x04, 0x22, 0x00, 0x23, 0x00, 0x24, 0x00, 4, 5, 6, 8, 8, 16, 0xFC, 0x00,
+        0x6B, 0x00, 8, 0, 8, 0, 16, 0,
+    ], &["AM4", "Advanced Micro Devices, Inc.", "AMD Ryzen 7 5800X 8-Core Processor            ",
+        "Unknown", "Unknown", "Unknown"]);
+    let dimm = |populated: bool, locator: u8| {
+        let (size, speed) = if populated { ([0x00, 0x40], [0x80, 0x0C]) } else { ([0, 0], [0, 0]) };
+        let mut fields = [0u8; 0x54 - HEADER_LENGTH];
+        fields[..0x1C - HEADER_LENGTH].copy_from_slice(&[
+            0x0A, 0x00, 0xFE, 0xFF, 0x40, 0x00, 0x40, 0x00, size[0], size[1], 0x09, 0x00, locator, locator + 1, 0x1A,
+            0x80, 0x00, speed[0], speed[1], 3, 4, 5, 6, 0x02,
+        ]);
+        fields
+    };
+    blob.structure(TYPE_MEMORY_DEVICE, 0x2A, &dimm(false, 1), &["DIMM 0", "P0 CHANNEL A", "Unknown", "Unknown", "Unknown", "Unknown"]);
+    blob.structure(TYPE_MEMORY_DEVICE, 0x2B, &dimm(true, 1), &["DIMM 1", "P0 CHANNEL A", "G Skill Intl", "00000000", "Unknown",
+        "F4-3200C16-16GVK    "]);
+    blob.structure(TYPE_MEMORY_DEVICE, 0x2C, &dimm(false, 1), &["DIMM 0", "P0 CHANNEL B", "Unknown", "Unknown", "Unknown", "Unknown"]);
+    blob.structure(TYPE_MEMORY_DEVICE, 0x2D, &dimm(true, 1), &["DIMM 1", "P0 CHANNEL B", "G Skill Intl", "00000000", "Unknown",
+        "F4-3200C16-16GVK    "]);
+    blob.end();
+    blob
+}
+
+#[cfg(test)]
+fn fix_checksum(entry: &mut [u8], range: core::ops::Range<usize>, at: usize) {
+    entry[at] = 0;
+    let sum = entry[range].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    entry[at] = 0u8.wrapping_sub(sum);
+}
+
+#[test_case]
+fn entry_points() {
+    let mut area = [0u8; 0x60];
+    // An SMBIOS 2.8 entry point at 0x10, with the 2.1 length.
+    let entry = &mut area[0x10..0x30];
+    entry[..4].copy_from_slice(ANCHOR_2);
+    entry[5..8].copy_from_slice(&[0x1E, 2, 8]);
+    entry[0x10..0x15].copy_from_slice(INTERMEDIATE_ANCHOR);
+    entry[0x16..0x18].copy_from_slice(&0x1A8u16.to_le_bytes());
+    entry[0x18..0x1C].copy_from_slice(&0xF_5A60u32.to_le_bytes());
+    entry[0x1C..0x1E].copy_from_slice(&9u16.to_le_bytes());
+    fix_checksum(entry, 0x10..0x1F, 0x15);
+    fix_checksum(entry, 0..0x1E, 4);
+    let smbios_2 = EntryPoint { major: 2, minor: 8, table_address: 0xF_5A60, table_length: 0x1A8, structure_count: Some(9) };
+    assert_eq!(search_entry_point(&area), Some((0x10, smbios_2)));
+
+    // A bad intermediate checksum.
+    area[0x10 + 0x1B] ^= 1;
+    area[0x10 + 4] = area[0x10 + 4].wrapping_sub(1);
+    assert_eq!(search_entry_point(&area), None);
+    area[0x10 + 0x1B] ^= 1;
+    area[0x10 + 4] = area[0x10 + 4].wrapping_add(1);
+
+    // An SMBIOS 3 entry point after it is taken first.
+    let entry = &mut area[0x40..0x58];
+    entry[..5].copy_from_slice(ANCHOR_3);
+    entry[6..10].copy_from_slice(&[0x18, 3, 3, 0]);
+    entry[0x0C..0x10].copy_from_slice(&0x4000_0000u32.to_le_bytes());
+    entry[0x10..0x18].copy_from_slice(&0x7_BFA0_0000u64.to_le_bytes());
+    fix_checksum(entry, 0..0x18, 5);
+    let smbios_3 = EntryPoint {
+        major: 3, minor: 3, table_address: 0x7_BFA0_0000, table_length: MAX_TABLE_LENGTH, structure_count: None,
+    };
+    assert_eq!(search_entry_point(&area), Some((0x40, smbios_3)));
+    // Cut off by the end of the area.
+    assert_eq!(search_entry_point(&area[..0x50]), Some((0x10, smbios_2)));
+    area[0x40 + 0x11] ^= 1;
+    assert_eq!(search_entry_point(&area), Some((0x10, smbios_2)));
+}
+
+#[test_case]
+fn qemu_tables() {
+    use crate::pci::ids::StackString;
+
+    let blob = qemu_table();
+    let entry = EntryPoint { major: 2, minor: 8, table_address: 0, table_length: blob.len as u32, structure_count: Some(8) };
+    let info = Info::parse(Table::new(&entry, blob.bytes()));
+    assert_eq!(info.structures, 7);
+    assert!(!info.malformed);
+
+    let bios = info.bios.unwrap();
+    assert_eq!(bios.vendor, Some("SeaBIOS"));
+    assert_eq!(bios.release_date, Some("04/01/2014"));
+    assert_eq!(bios.rom_size, Some(64 * 1024));
+    assert_eq!(bios.release, None);
+    let system = info.system.unwrap();
+    assert_eq!((system.manufacturer, system.product), (Some("QEMU"), Some("Standard PC (i440FX + PIIX, 1996)")));
+    assert_eq!(system.serial_number, None);
+    assert_eq!(system.family, None);
+    let uuid = StackString::format(format_args!("{}", system.uuid.unwrap()));
+    assert_eq!(uuid.as_str(), "12345678-1234-5678-9abc-def012345678");
+    assert!(info.baseboard.is_none());
+
+    let processors = [*info.processors().next().unwrap()];
+    assert_eq!(info.processors().count(), 1);
+    assert_eq!(processors[0].socket, Some("CPU 0"));
+    assert_eq!(processors[0].version, Some("pc-i440fx-8.2"));
+    assert_eq!(processors[0].family, 1);
+    assert_eq!((processors[0].max_speed, processors[0].current_speed), (Some(2000), Some(2000)));
+    assert!(processors[0].populated);
+    assert_eq!((processors[0].cores, processors[0].threads), (Some(1), Some(1)));
+
+    let dimm = info.memory_devices().next().unwrap();
+    assert_eq!(info.memory_devices().count(), 1);
+    assert_eq!((dimm.locator, dimm.bank, dimm.manufacturer), (Some("DIMM 0"), None, Some("QEMU")));
+    assert_eq!(dimm.size, MemorySize::Bytes(128 << 20));
+    assert_eq!(dimm.type_name(), Some("RAM"));
+    assert_eq!(dimm.speed, None);
+    assert_eq!(info.memory_size(), 128 << 20);
+
+    // The structure count ends the walk without an end structure.
+    let entry = EntryPoint { structure_count: Some(3), ..entry };
+    assert_eq!(Info::parse(Table::new(&entry, blob.bytes())).structures, 3);
+}
+
+#[test_case]
+fn desktop_tables() {
+    let blob = desktop_table();
+    let entry = EntryPoint { major: 3, minor: 3, table_address: 0, table_length: MAX_TABLE_LENGTH, structure_count: None };
+    let info = Info::parse(Table::new(&entry, blob.bytes()));
+    assert_eq!(info.structures, 8);
+    assert!(!info.malformed);
+
+    let bios = info.bios.unwrap();
+    assert_eq!(bios.vendor, Some("American Megatrends International, LLC."));
+    assert_eq!(bios.rom_size, Some(32 << 20));
+    assert_eq!(bios.release, Some((5, 23)));
+    let system = info.system.unwrap();
+    assert_eq!(system.sku, Some("Default string"));
+    assert_eq!(system.family, Some("B550 MB"));
+    let board = info.baseboard.unwrap();
+    assert_eq!(board.product, Some("B550 AORUS ELITE V2"));
+    assert_eq!(board.asset_tag, Some("Default string"));
+
+    let cpu = info.processors().next().unwrap();
+    assert_eq!(cpu.version, Some("AMD Ryzen 7 5800X 8-Core Processor"));
+    assert_eq!(cpu.family, 0x6B);
+    assert_eq!((cpu.max_speed, cpu.current_speed), (Some(4536), Some(3958)));
+    assert_eq!((cpu.cores, cpu.threads), (Some(8), Some(16)));
+
+    let installed = info.memory_devices().filter(|device| device.is_installed()).count();
+    assert_eq!((installed, info.memory_devices().count()), (2, 4));
+    let dimm = info.memory_devices().nth(1).unwrap();
+    assert_eq!((dimm.locator, dimm.bank), (Some("DIMM 1"), Some("P0 CHANNEL A")));
+    assert_eq!(dimm.part_number, Some("F4-3200C16-16GVK"));
+    assert_eq!(dimm.type_name(), Some("DDR4"));
+    assert_eq!(dimm.speed, Some(3200));
+    assert_eq!(info.memory_size(), 32 << 30);
+}
+
+#[test_case]
+fn malformed_tables() {
+    let entry = EntryPoint { major: 3, minor: 0, table_address: 0, table_length: MAX_TABLE_LENGTH, structure_count: None };
+    let walk = |bytes: &[u8]| {
+        let mut structures = Table::new(&entry, bytes).structures();
+        let count = (&mut structures).count();
+        (count, structures.malformed())
+    };
+
+    // String indices past the set, a string that is not ASCII, and a type 4
+    // too short to decode.
+    let mut blob = Blob::new();
+    blob.structure(TYPE_BASEBOARD, 0, &[1, 2, 9, 0], &["Board", "\u{e9}"]);
+    blob.structure(TYPE_PROCESSOR, 1, &[1, 3, 1], &["CPU"]);
+    blob.end();
+    let info = Info::parse(Table::new(&entry, blob.bytes()));
+    let board = info.baseboard.unwrap();
+    assert_eq!((board.manufacturer, board.product, board.version, board.serial_number), (Some("Board"), None, None, None));
+    assert_eq!(info.processors().count(), 0);
+    assert_eq!(walk(blob.bytes()), (2, false));
+
+    // A structure shorter than its header stops the walk.
+    let mut blob = Blob::new();
+    blob.structure(TYPE_BIOS, 0, &[], &[]).raw(&[TYPE_SYSTEM, 2, 0, 0, 0, 0]);
+    assert_eq!(walk(blob.bytes()), (1, true));
+    // So does one longer than the table, and a string set without its NUL pair.
+    let mut blob = Blob::new();
+    blob.structure(TYPE_BIOS, 0, &[], &[]).raw(&[TYPE_SYSTEM, 0x40, 0, 0]);
+    assert_eq!(walk(blob.bytes()), (1, true));
+    let mut blob = Blob::new();
+    blob.structure(TYPE_BIOS, 0, &[], &["a"]).raw(&[TYPE_SYSTEM, 4, 0, 0, b'b', 0]);
+    assert_eq!(walk(blob.bytes()), (1, true));
+    // A table ending without an end structure is fine.
+    let mut blob = Blob::new();
+    blob.structure(TYPE_BIOS, 0, &[], &[]);
+    assert_eq!(walk(blob.bytes()), (1, false));
+
+    // A table cut inside a structure keeps what came before.
+    let table = qemu_table();
+    let info = Info::parse(Table::new(&entry, &table.bytes()[..0x100]));
+    assert!(info.malformed);
+    assert!(info.bios.is_some() && info.system.is_some());
+    assert!(info.structures < 7);
+}
@@ -0,0 +1,210 @@
+//! The text screen drawn on the framebuffer, so that `println!` keeps working
+//! once the screen is in a graphics mode.
+//!
+//! The VGA writer keeps writing to its 80x25 character grid, moved to RAM by
+//! [`VGAWriter::detach_text_buffer`], and the cells that changed are drawn
+//! after each write. The glyphs are the 8x16 font the VGA BIOS loaded for the
+//! text mode, copied out of plane 2 of the VGA memory before the switch.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+use crate::{
+    sync::Mutex,
+    tables::{port::Port, without_interrupts},
+    vga::{VGAColor, VGAColorCode, VGAWriter, VGA_BUFFER_HEIGHT, VGA_BUFFER_WIDTH},
+};
+use super::framebuffer::{rgb, Framebuffer};
+
+pub const GLYPH_WIDTH: usize = 8;
+pub const GLYPH_HEIGHT: usize = 16;
+const GLYPHS: usize = 256;
+/// Plane 2 holds a glyph every 32 bytes, of which an 8x16 font uses 16.
+const PLANE_GLYPH_STRIDE: usize = 32;
+const VGA_WINDOW: u64 = 0xA0000;
+
+/// Index ports, each followed by its data port.
+const SEQUENCER_INDEX: u16 = 0x3C4;
+const GRAPHICS_INDEX: u16 = 0x3CE;
+/// Sequencer map mask and memory mode, graphics controller read map select,
+/// mode and miscellaneous registers, as an 80x25 text mode has them and as
+/// reading plane 2 needs them.
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+const GC_READ_MAP: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+const TEXT_MODE_REGISTERS: [(u16, u8, u8); 5] = [
+    (SEQUENCER_INDEX, SEQ_MAP_MASK, 0x03),
+    (SEQUENCER_INDEX, SEQ_MEMORY_MODE, 0x03),
+    (GRAPHICS_INDEX, GC_READ_MAP, 0x00),
+    (GRAPHICS_INDEX, GC_MODE, 0x10),
+    (GRAPHICS_INDEX, GC_MISC, 0x0E),
+];
+const PLANE_2_REGISTERS: [(u16, u8, u8); 5] = [
+    (SEQUENCER_INDEX, SEQ_MAP_MASK, 0x04),
+    (SEQUENCER_INDEX, SEQ_MEMORY_MODE, 0x07),
+    (GRAPHICS_INDEX, GC_READ_MAP, 0x02),
+    (GRAPHICS_INDEX, GC_MODE, 0x00),
+    (GRAPHICS_INDEX, GC_MISC, 0x04),
+];
+
+/// The 16 text mode colors, as the default VGA palette has them.
+const PALETTE: [u32; 16] = [
+    rgb(0x00, 0x00, 0x00), rgb(0x00, 0x00, 0xAA), rgb(0x00, 0xAA, 0x00), rgb(0x00, 0xAA, 0xAA),
+    rgb(0xAA, 0x00, 0x00), rgb(0xAA, 0x00, 0xAA), rgb(0xAA, 0x55, 0x00), rgb(0xAA, 0xAA, 0xAA),
+    rgb(0x55, 0x55, 0x55), rgb(0x55, 0x55, 0xFF), rgb(0x55, 0xFF, 0x55), rgb(0x55, 0xFF, 0xFF),
+    rgb(0xFF, 0x55, 0x55), rgb(0xFF, 0x55, 0xFF), rgb(0xFF, 0xFF, 0x55), rgb(0xFF, 0xFF, 0xFF),
+];
+
+static FONT: Once<[[u8; GLYPH_HEIGHT]; GLYPHS]> = Once::new();
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Taken while holding `VGA_WRITER`, never the other way around.
+static CONSOLE: Mutex<Option<Console>> = Mutex::new("VBE_CONSOLE", None);
+
+/// Returns the RGB color of a text mode color.
+pub fn color(color: VGAColor) -> u32 {
+    PALETTE[color as usize]
+}
+
+fn write_registers(registers: &[(u16, u8, u8)]) {
+    for &(index_port, index, value) in registers {
+        unsafe {
+            Port::new(index_port).write(index);
+            Port::new(index_port + 1).write(value);
+        }
+    }
+}
+
+/// Copies the 8x16 font out of plane 2 of the VGA memory, where the BIOS put it
+/// for the text mode. `phys_offset` is where physical memory is mapped.
+///
+/// Must run while the screen is still in an 80x25 text mode. Returns `None`
+/// if the font is blank, i.e. there is no VGA memory to read it from.
+pub fn capture_font(phys_offset: u64) -> Option<&'static [[u8; GLYPH_HEIGHT]; GLYPHS]> {
+    if let Some(font) = FONT.get() {
+        return Some(font);
+    }
+    let mut font = [[0; GLYPH_HEIGHT]; GLYPHS];
+    without_interrupts(|| {
+        write_registers(&PLANE_2_REGISTERS);
+        let plane = (phys_offset + VGA_WINDOW) as *const u8;
+        for (index, glyph) in font.iter_mut().enumerate() {
+            for (row, line) in glyph.iter_mut().enumerate() {
+                *line = unsafe { plane.add(index * PLANE_GLYPH_STRIDE + row).read_volatile() };
+            }
+        }
+        write_registers(&TEXT_MODE_REGISTERS);
+    });
+    // An 'A' has pixels, an unmapped window reads the same byte everywhere.
+    let a = font[b'A' as usize];
+    if a.iter().all(|&line| line == a[0]) {
+        return None;
+    }
+    Some(FONT.call_once(|| font))
+}
+
+/// The text screen on a framebuffer.
+pub struct Console {
+    framebuffer: Framebuffer<'static>,
+    font: &'static [[u8; GLYPH_HEIGHT]; GLYPHS],
+    /// Where the character grid starts, centered on the screen.
+    origin: (isize, isize),
+    /// The cells as drawn, character and color code, `None` before the first draw.
+    drawn: [[Option<(u8, VGAColorCode)>; VGA_BUFFER_WIDTH]; VGA_BUFFER_HEIGHT],
+    /// The cell the cursor was drawn under.
+    cursor: (usize, usize),
+}
+
+impl Console {
+    pub fn new(framebuffer: Framebuffer<'static>, font: &'static [[u8; GLYPH_HEIGHT]; GLYPHS]) -> Self {
+        let origin = (
+            (framebuffer.width() as isize - (VGA_BUFFER_WIDTH * GLYPH_WIDTH) as isize) / 2,
+            (framebuffer.height() as isize - (VGA_BUFFER_HEIGHT * GLYPH_HEIGHT) as isize) / 2,
+        );
+        Console { framebuffer, font, origin, drawn: [[None; VGA_BUFFER_WIDTH]; VGA_BUFFER_HEIGHT], cursor: (0, 0) }
+    }
+
+    pub fn framebuffer(&mut self) -> &mut Framebuffer<'static> {
+        &mut self.framebuffer
+    }
+
+    /// Makes the next `render` draw every cell, after something drew over them.
+    pub fn invalidate(&mut self) {
+        self.drawn = [[None; VGA_BUFFER_WIDTH]; VGA_BUFFER_HEIGHT];
+    }
+
+    /// Draws `byte` with its glyph at `x`, `y` in pixels.
+    pub fn draw_char(&mut self, x: isize, y: isize, byte: u8, foreground: u32, background: u32) {
+        let mut pixels = [background; GLYPH_WIDTH * GLYPH_HEIGHT];
+        for (row, line) in self.font[byte as usize].iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if line & (0x80 >> column) != 0 {
+                    pixels[row * GLYPH_WIDTH + column] = foreground;
+                }
+            }
+        }
+        self.framebuffer.blit(x, y, GLYPH_WIDTH, &pixels);
+    }
+
+    /// Draws `text` from `x`, `y` in pixels, one glyph per byte.
+    pub fn draw_text(&mut self, x: isize, y: isize, text: &str, foreground: u32, background: u32) {
+        for (i, byte) in text.bytes().enumerate() {
+            self.draw_char(x + (i * GLYPH_WIDTH) as isize, y, byte, foreground, background);
+        }
+    }
+
+    fn draw_cell(&mut self, row: usize, column: usize, (byte, color_code): (u8, VGAColorCode), cursor: bool) {
+        let x = self.origin.0 + (column * GLYPH_WIDTH) as isize;
+        let y = self.origin.1 + (row * GLYPH_HEIGHT) as isize;
+        let (foreground, background) = (color(color_code.foreground()), color(color_code.background()));
+        self.draw_char(x, y, byte, foreground, background);
+        if cursor {
+            self.framebuffer.fill_rect(x, y + GLYPH_HEIGHT as isize - 2, GLYPH_WIDTH, 2, foreground);
+        }
+        self.drawn[row][column] = Some((byte, color_code));
+    }
+
+    /// Draws the cells of `writer` that changed since the last call, and the
+    /// cursor, then presents them.
+    pub fn render(&mut self, writer: &VGAWriter) {
+        let (old_row, old_column) = self.cursor;
+        let (row, column) = writer.cursor_position();
+        for r in 0..VGA_BUFFER_HEIGHT {
+            for c in 0..VGA_BUFFER_WIDTH {
+                let cell = writer.char_at(r, c);
+                let moved = (r, c) == (old_row, old_column) || (r, c) == (row, column);
+                if moved || self.drawn[r][c] != Some(cell) {
+                    self.draw_cell(r, c, cell, (r, c) == (row, column));
+                }
+            }
+        }
+        self.cursor = (row, column);
+        self.framebuffer.present();
+    }
+}
+
+/// Whether the text screen is drawn on the framebuffer.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Makes `console` show the text screen from now on, starting with what is on it.
+pub fn activate(mut console: Console, writer: &mut VGAWriter) {
+    writer.detach_text_buffer();
+    console.render(writer);
+    *CONSOLE.lock() = Some(console);
+    ACTIVE.store(true, Ordering::Release);
+}
+
+/// Draws what changed on the text screen, see [`Console::render`].
+pub fn render(writer: &VGAWriter) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.render(writer);
+    }
+}
+
+/// Runs `f` on the console, `None` in text mode. `f` must not print, which
+/// would take the VGA writer's lock while holding the console's.
+pub fn with_console<R>(f: impl FnOnce(&mut Console) -> R) -> Option<R> {
+    without_interrupts(|| CONSOLE.lock().as_mut().map(f))
+}
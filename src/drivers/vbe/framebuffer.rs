@@ -0,0 +1,202 @@
+//! A 32-bpp framebuffer drawn to in RAM and copied to the screen by
+//! [`Framebuffer::present`], so a frame is never seen half drawn.
+//!
+//! Drawing is clipped to the screen: coordinates are signed, and what falls
+//! outside is dropped rather than wrapped onto the next row.
+
+/// A color as the framebuffer stores it, `0x00RRGGBB`.
+pub const fn rgb(red: u8, green: u8, blue: u8) -> u32 {
+    (red as u32) << 16 | (green as u32) << 8 | blue as u32
+}
+
+/// A rectangle in pixels, already clipped to a surface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Clips the `width` by `height` rectangle at `x`, `y` to a surface of
+/// `surface_width` by `surface_height`. Returns `None` if nothing is left.
+pub fn clip(x: isize, y: isize, width: usize, height: usize, surface_width: usize, surface_height: usize) -> Option<Rect> {
+    let clip_axis = |start: isize, len: usize, limit: usize| {
+        let end = start.saturating_add(len.min(isize::MAX as usize) as isize).min(limit as isize);
+        let start = start.max(0);
+        (start < end).then(|| (start as usize, (end - start) as usize))
+    };
+    let (x, width) = clip_axis(x, width, surface_width)?;
+    let (y, height) = clip_axis(y, height, surface_height)?;
+    Some(Rect { x, y, width, height })
+}
+
+pub struct Framebuffer<'a> {
+    width: usize,
+    height: usize,
+    /// Pixels from one row of the screen to the next, at least `width`.
+    stride: usize,
+    /// What is drawn to, `width` pixels per row.
+    back: &'a mut [u32],
+    /// The linear framebuffer, `stride * height` pixels, `None` for a surface
+    /// only in memory.
+    front: Option<*mut u32>,
+    /// The rows drawn to since the last `present`, first and past the last.
+    dirty: Option<(usize, usize)>,
+}
+
+// The linear framebuffer belongs to the one `Framebuffer` mapping it.
+unsafe impl Send for Framebuffer<'_> {}
+
+impl<'a> Framebuffer<'a> {
+    /// A framebuffer only drawn in memory, into `back`, which must hold
+    /// `width * height` pixels.
+    pub fn in_memory(width: usize, height: usize, back: &'a mut [u32]) -> Self {
+        assert!(back.len() >= width * height, "back buffer of {} pixels for {}x{}", back.len(), width, height);
+        Framebuffer { width, height, stride: width, back, front: None, dirty: None }
+    }
+
+    /// A framebuffer drawn into `back` and presented at `front`.
+    ///
+    /// ## Safety
+    ///
+    /// `front` must point at `stride * height` writable pixels, used by nothing else.
+    pub unsafe fn new(width: usize, height: usize, stride: usize, back: &'a mut [u32], front: *mut u32) -> Self {
+        assert!(stride >= width);
+        let mut framebuffer = Self::in_memory(width, height, back);
+        framebuffer.stride = stride;
+        framebuffer.front = Some(front);
+        framebuffer
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Pixels per row of the screen, which may have padding past `width`.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    fn mark_dirty(&mut self, first_row: usize, rows: usize) {
+        let end = first_row + rows;
+        self.dirty = Some(match self.dirty {
+            Some((first, last)) => (first.min(first_row), last.max(end)),
+            None => (first_row, end),
+        });
+    }
+
+    /// Returns the pixel at `x`, `y` as drawn, `None` off the screen.
+    pub fn pixel(&self, x: usize, y: usize) -> Option<u32> {
+        (x < self.width && y < self.height).then(|| self.back[y * self.width + x])
+    }
+
+    pub fn put_pixel(&mut self, x: isize, y: isize, color: u32) {
+        self.fill_rect(x, y, 1, 1, color);
+    }
+
+    pub fn fill_rect(&mut self, x: isize, y: isize, width: usize, height: usize, color: u32) {
+        let Some(rect) = clip(x, y, width, height, self.width, self.height) else {
+            return;
+        };
+        for row in rect.y..rect.y + rect.height {
+            let start = row * self.width + rect.x;
+            self.back[start..start + rect.width].fill(color);
+        }
+        self.mark_dirty(rect.y, rect.height);
+    }
+
+    /// Copies the `width` pixels wide image `pixels` to `x`, `y`.
+    pub fn blit(&mut self, x: isize, y: isize, width: usize, pixels: &[u32]) {
+        if width == 0 {
+            return;
+        }
+        let height = pixels.len() / width;
+        let Some(rect) = clip(x, y, width, height, self.width, self.height) else {
+            return;
+        };
+        // Where the clipped rectangle starts in the image.
+        let (skip_x, skip_y) = ((rect.x as isize - x) as usize, (rect.y as isize - y) as usize);
+        for row in 0..rect.height {
+            let source = (skip_y + row) * width + skip_x;
+            let target = (rect.y + row) * self.width + rect.x;
+            self.back[target..target + rect.width].copy_from_slice(&pixels[source..source + rect.width]);
+        }
+        self.mark_dirty(rect.y, rect.height);
+    }
+
+    pub fn clear(&mut self, color: u32) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Copies the rows drawn to since the last call to the screen.
+    pub fn present(&mut self) {
+        let (Some(front), Some((first, last))) = (self.front, self.dirty.take()) else {
+            return;
+        };
+        for row in first..last {
+            let source = &self.back[row * self.width..(row + 1) * self.width];
+            unsafe { core::ptr::copy_nonoverlapping(source.as_ptr(), front.add(row * self.stride), self.width); }
+        }
+    }
+}
+
+#[test_case]
+fn clipping() {
+    assert_eq!(clip(2, 3, 4, 5, 10, 10), Some(Rect { x: 2, y: 3, width: 4, height: 5 }));
+    assert_eq!(clip(-2, -3, 4, 5, 10, 10), Some(Rect { x: 0, y: 0, width: 2, height: 2 }));
+    assert_eq!(clip(8, 9, 4, 5, 10, 10), Some(Rect { x: 8, y: 9, width: 2, height: 1 }));
+    assert_eq!(clip(-4, 0, 4, 5, 10, 10), None);
+    assert_eq!(clip(10, 0, 1, 1, 10, 10), None);
+    assert_eq!(clip(0, 0, 0, 1, 10, 10), None);
+    assert_eq!(clip(isize::MIN, isize::MAX, usize::MAX, usize::MAX, 10, 10), None);
+    assert_eq!(clip(-5, -5, usize::MAX, usize::MAX, 10, 10), Some(Rect { x: 0, y: 0, width: 10, height: 10 }));
+}
+
+#[test_case]
+fn drawing_is_clipped() {
+    let mut pixels = [0u32; 8 * 4];
+    let mut framebuffer = Framebuffer::in_memory(8, 4, &mut pixels);
+    framebuffer.clear(rgb(0, 0, 1));
+    framebuffer.fill_rect(6, -1, 4, 3, rgb(0xFF, 0, 0));
+    framebuffer.put_pixel(-1, 0, rgb(0, 0xFF, 0));
+    framebuffer.put_pixel(0, 4, rgb(0, 0xFF, 0));
+    framebuffer.put_pixel(3, 3, rgb(0, 0, 0xFF));
+
+    assert_eq!(rgb(0x12, 0x34, 0x56), 0x0012_3456);
+    assert_eq!(framebuffer.pixel(5, 0), Some(rgb(0, 0, 1)));
+    assert_eq!(framebuffer.pixel(6, 0), Some(rgb(0xFF, 0, 0)));
+    assert_eq!(framebuffer.pixel(7, 1), Some(rgb(0xFF, 0, 0)));
+    assert_eq!(framebuffer.pixel(7, 2), Some(rgb(0, 0, 1)));
+    assert_eq!(framebuffer.pixel(3, 3), Some(rgb(0, 0, 0xFF)));
+    assert_eq!(framebuffer.pixel(8, 0), None);
+    // Nothing wrapped onto the next row or past the end.
+    let red = pixels.iter().filter(|&&p| p == rgb(0xFF, 0, 0)).count();
+    assert_eq!(red, 4);
+    assert!(!pixels.contains(&rgb(0, 0xFF, 0)));
+}
+
+#[test_case]
+fn blit_and_present() {
+    let image: [u32; 6] = [1, 2, 3, 4, 5, 6];
+    let mut back = [0u32; 4 * 3];
+    let mut front = [0u32; 6 * 3];
+    let mut framebuffer = unsafe { Framebuffer::new(4, 3, 6, &mut back, front.as_mut_ptr()) };
+    // 3x2 image at -1, 2: only its bottom right corner is past the screen.
+    framebuffer.blit(-1, 2, 3, &image);
+    framebuffer.blit(3, 0, 3, &image);
+    assert_eq!(framebuffer.pixel(0, 2), Some(2));
+    assert_eq!(framebuffer.pixel(1, 2), Some(3));
+    assert_eq!(framebuffer.pixel(3, 0), Some(1));
+    assert_eq!(framebuffer.pixel(3, 1), Some(4));
+    framebuffer.present();
+    framebuffer.present();
+    // Rows land `stride` apart, the padding is left alone.
+    assert_eq!(&front[..6], &[0, 0, 0, 1, 0, 0]);
+    assert_eq!(&front[6..12], &[0, 0, 0, 4, 0, 0]);
+    assert_eq!(&front[12..], &[2, 3, 0, 0, 0, 0]);
+}
@@ -0,0 +1,204 @@
+//! Graphics modes through the Bochs VBE extensions ("dispi"), which QEMU's
+//! `-vga std`, Bochs and VirtualBox implement, with a linear framebuffer at
+//! BAR 0 of the display controller.
+//!
+//! Everything that can make a mode unusable is checked before the switch, so
+//! a failed [`init`] leaves the text mode as it was.
+
+pub mod console;
+pub mod framebuffer;
+
+use core::fmt;
+use crate::{
+    memory::{FRAME_ALLOCATOR, MAPPER},
+    pci::{self, bar::{BarRegion, PciError}, PciDevice},
+    tables::{port::Port, without_interrupts},
+    vga::VGA_WRITER,
+};
+use super::allocate_dma;
+use console::Console;
+use framebuffer::{rgb, Framebuffer};
+
+const DISPI_INDEX: u16 = 0x1CE;
+const DISPI_DATA: u16 = 0x1CF;
+
+const INDEX_ID: u16 = 0x0;
+const INDEX_XRES: u16 = 0x1;
+const INDEX_YRES: u16 = 0x2;
+const INDEX_BPP: u16 = 0x3;
+const INDEX_ENABLE: u16 = 0x4;
+const INDEX_VIRT_WIDTH: u16 = 0x6;
+const INDEX_X_OFFSET: u16 = 0x8;
+const INDEX_Y_OFFSET: u16 = 0x9;
+const INDEX_VIDEO_MEMORY_64K: u16 = 0xA;
+
+/// The first interface version with 32 bpp and the linear framebuffer.
+const ID_32BPP_LFB: u16 = 0xB0C2;
+/// Any later version reads back in this range.
+const ID_MAX: u16 = 0xB0CF;
+
+const ENABLE_DISABLED: u16 = 0x00;
+const ENABLE_ENABLED: u16 = 0x01;
+/// Makes XRES, YRES and BPP read back the largest supported values.
+const ENABLE_GETCAPS: u16 = 0x02;
+const ENABLE_LFB: u16 = 0x40;
+
+const BPP: u16 = 32;
+const BYTES_PER_PIXEL: u64 = 4;
+
+/// Display controllers with the dispi interface: QEMU and Bochs, VirtualBox.
+const DISPLAY_IDS: [(u16, u16); 2] = [(0x1234, 0x1111), (0x80EE, 0xBEEF)];
+const CLASS_DISPLAY: u8 = 0x03;
+const SUBCLASS_VGA: u8 = 0x00;
+
+const FRAME_SIZE: usize = crate::memory::PAGE_SIZE_4K as usize;
+
+#[derive(Debug)]
+pub enum VbeError {
+    /// No dispi interface with 32 bpp, or no display controller with it.
+    NoDevice,
+    /// The resolution is larger than the adapter or its memory allow.
+    Unsupported { width: u16, height: u16 },
+    /// The text mode font could not be read for the console.
+    NoFont,
+    NoMemory,
+    /// The adapter did not take the mode after all.
+    ModeSetFailed,
+    Pci(PciError),
+}
+
+impl fmt::Display for VbeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VbeError::NoDevice => write!(f, "no Bochs VBE display adapter"),
+            VbeError::Unsupported { width, height } => write!(f, "{}x{} not supported", width, height),
+            VbeError::NoFont => write!(f, "no VGA font to draw text with"),
+            VbeError::NoMemory => write!(f, "no memory for the back buffer"),
+            VbeError::ModeSetFailed => write!(f, "the adapter rejected the mode"),
+            VbeError::Pci(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<PciError> for VbeError {
+    fn from(err: PciError) -> Self {
+        VbeError::Pci(err)
+    }
+}
+
+fn read_register(index: u16) -> u16 {
+    unsafe {
+        Port::new(DISPI_INDEX).write(index);
+        Port::new(DISPI_DATA).read(0u16)
+    }
+}
+
+fn write_register(index: u16, value: u16) {
+    unsafe {
+        Port::new(DISPI_INDEX).write(index);
+        Port::new(DISPI_DATA).write(value);
+    }
+}
+
+/// Returns the dispi interface version, `None` without one new enough.
+pub fn version() -> Option<u16> {
+    let id = without_interrupts(|| read_register(INDEX_ID));
+    (ID_32BPP_LFB..=ID_MAX).contains(&id).then_some(id)
+}
+
+/// Returns the largest resolution the adapter supports, and its memory in bytes.
+fn capabilities() -> (u16, u16, u64) {
+    without_interrupts(|| {
+        let enable = read_register(INDEX_ENABLE);
+        write_register(INDEX_ENABLE, enable | ENABLE_GETCAPS);
+        let (width, height) = (read_register(INDEX_XRES), read_register(INDEX_YRES));
+        write_register(INDEX_ENABLE, enable);
+        (width, height, read_register(INDEX_VIDEO_MEMORY_64K) as u64 * 0x10000)
+    })
+}
+
+/// Sets `width` by `height` at 32 bpp with the linear framebuffer on, and
+/// returns the stride in pixels. Disables the mode again if it does not read
+/// back as set.
+fn set_mode(width: u16, height: u16) -> Result<u16, VbeError> {
+    without_interrupts(|| {
+        write_register(INDEX_ENABLE, ENABLE_DISABLED);
+        write_register(INDEX_XRES, width);
+        write_register(INDEX_YRES, height);
+        write_register(INDEX_BPP, BPP);
+        write_register(INDEX_VIRT_WIDTH, width);
+        write_register(INDEX_X_OFFSET, 0);
+        write_register(INDEX_Y_OFFSET, 0);
+        write_register(INDEX_ENABLE, ENABLE_ENABLED | ENABLE_LFB);
+        let set = (read_register(INDEX_XRES), read_register(INDEX_YRES), read_register(INDEX_BPP));
+        if set != (width, height, BPP) || read_register(INDEX_ENABLE) & ENABLE_ENABLED == 0 {
+            write_register(INDEX_ENABLE, ENABLE_DISABLED);
+            return Err(VbeError::ModeSetFailed);
+        }
+        Ok(read_register(INDEX_VIRT_WIDTH).max(width))
+    })
+}
+
+fn display_controller() -> Option<PciDevice> {
+    pci::devices().find(|d| {
+        (d.class, d.subclass) == (CLASS_DISPLAY, SUBCLASS_VGA) && DISPLAY_IDS.contains(&(d.vendor_id, d.device_id))
+    })
+}
+
+/// Switches the screen to `width` by `height` at 32 bpp and moves the text
+/// output onto it, see [`console`].
+///
+/// The linear framebuffer is mapped uncached for now, write-combining would
+/// be faster. Drawing goes to a back buffer in RAM and shows on `present`.
+pub fn init(width: u16, height: u16) -> Result<(), VbeError> {
+    version().ok_or(VbeError::NoDevice)?;
+    let device = display_controller().ok_or(VbeError::NoDevice)?;
+    let (max_width, max_height, memory) = capabilities();
+    let size = width as u64 * height as u64 * BYTES_PER_PIXEL;
+    if width == 0 || height == 0 || width > max_width || height > max_height || size > memory {
+        return Err(VbeError::Unsupported { width, height });
+    }
+
+    let (front, phys_offset) = {
+        let mut mapper = MAPPER.lock();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+            return Err(VbeError::NoMemory);
+        };
+        let BarRegion::Mmio(region) = (unsafe { device.map_bar(0, mapper, allocator)? }) else {
+            return Err(VbeError::Pci(PciError::NoSuchBar));
+        };
+        if region.size() < size {
+            return Err(VbeError::Unsupported { width, height });
+        }
+        (region.base(), mapper.phys_offset())
+    };
+    let font = console::capture_font(phys_offset).ok_or(VbeError::NoFont)?;
+    // Physically contiguous, so that the physical memory window maps it in one piece.
+    let (_, back) = allocate_dma((size as usize).div_ceil(FRAME_SIZE)).ok_or(VbeError::NoMemory)?;
+    let pixels = width as usize * height as usize;
+    let back = unsafe { core::slice::from_raw_parts_mut(back as *mut u32, pixels) };
+
+    let stride = set_mode(width, height)?;
+    let framebuffer = unsafe { Framebuffer::new(width as usize, height as usize, stride as usize, back, front as *mut u32) };
+    without_interrupts(|| console::activate(Console::new(framebuffer, font), &mut VGA_WRITER.lock()));
+    Ok(())
+}
+
+/// Draws a gradient behind the text screen and the kernel banner above it.
+pub fn demo() {
+    console::with_console(|console| {
+        let framebuffer = console.framebuffer();
+        let (width, height) = (framebuffer.width(), framebuffer.height());
+        for y in 0..height {
+            for x in 0..width {
+                let (red, blue) = ((x * 255 / width) as u8, (y * 255 / height) as u8);
+                framebuffer.put_pixel(x as isize, y as isize, rgb(red, 0x20, blue));
+            }
+        }
+        console.draw_text(8, 8, "krabbos", rgb(0xFF, 0xFF, 0xFF), rgb(0, 0x20, 0));
+        console.invalidate();
+    });
+    // Brings the text screen back on top of the gradient, and presents it all.
+    without_interrupts(|| console::render(&VGA_WRITER.lock()));
+}
@@ -0,0 +1,237 @@
+//! virtio-blk: a disk behind a single request queue.
+//!
+//! Each request is a chain of a header the device reads, the data and a status
+//! byte it writes. Like the AHCI driver, data goes through `BOUNCE_FRAMES`
+//! frames so callers can pass any buffer, and completion is polled.
+
+use crate::{println, sync::Mutex};
+use super::{queue::{Buffer, Virtqueue}, VirtioDevice, VirtioError, DEVICE_TYPE_BLOCK};
+use crate::{drivers::allocate_dma, storage::{BlockDevice, IoError}};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Feature bits.
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+/// Configuration: the capacity in sectors.
+const CONFIG_CAPACITY: u16 = 0x00;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const STATUS_OK: u8 = 0;
+/// Written to the status byte before a request, the device overwrites it.
+const STATUS_PENDING: u8 = 0xFF;
+
+/// Layout of the request frame: the 16 byte header, then the status byte.
+const HEADER_OFFSET: u64 = 0;
+const HEADER_SIZE: u32 = 16;
+const STATUS_OFFSET: u64 = 16;
+
+const FRAME_SIZE: usize = crate::memory::PAGE_SIZE_4K as usize;
+/// Data is transferred through this many frames, one descriptor each.
+const BOUNCE_FRAMES: usize = 8;
+const MAX_BYTES_PER_REQUEST: usize = BOUNCE_FRAMES * FRAME_SIZE;
+
+/// Used ring polls before a request is given up on.
+const POLLS: u32 = 10_000_000;
+
+struct BlkState {
+    queue: Virtqueue,
+    /// Physical and virtual address of the request frame.
+    request: (u64, u64),
+    bounce: [(u64, u64); BOUNCE_FRAMES],
+}
+
+impl BlkState {
+    fn bounce_frame(&self, index: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.bounce[index].1 as *const u8, FRAME_SIZE) }
+    }
+
+    fn bounce_frame_mut(&mut self, index: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.bounce[index].1 as *mut u8, FRAME_SIZE) }
+    }
+}
+
+/// A virtio block device.
+pub struct VirtioBlk {
+    device: VirtioDevice,
+    capacity: u64,
+    read_only: bool,
+    state: Mutex<BlkState>,
+}
+
+impl VirtioBlk {
+    /// Sets up the first virtio block device on the PCI bus.
+    pub fn probe() -> Result<Self, VirtioError> {
+        let pci = VirtioDevice::find(DEVICE_TYPE_BLOCK).ok_or(VirtioError::NoDevice)?;
+        let mut device = VirtioDevice::open(pci)?;
+        let features = device.initialize(F_RO | F_FLUSH)?;
+        let queue = device.setup_queue(0)?;
+        let request = allocate_dma(1).ok_or(VirtioError::NoMemory)?;
+        let mut bounce = [(0, 0); BOUNCE_FRAMES];
+        for frame in bounce.iter_mut() {
+            *frame = allocate_dma(1).ok_or(VirtioError::NoMemory)?;
+        }
+        device.driver_ok();
+        let capacity = device.read_config_u64(CONFIG_CAPACITY);
+        Ok(VirtioBlk {
+            device,
+            capacity,
+            read_only: features & F_RO != 0,
+            state: Mutex::new("VIRTIO_BLK", BlkState { queue, request, bounce }),
+        })
+    }
+
+    /// The capacity in sectors.
+    pub fn sectors(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn is_modern(&self) -> bool {
+        self.device.is_modern()
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), VirtioError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(VirtioError::BadBuffer);
+        }
+        match lba.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(VirtioError::OutOfRange),
+        }
+    }
+
+    /// Queues a request on `bytes` bytes of the bounce frames and polls for its completion.
+    fn request(&self, state: &mut BlkState, kind: u32, sector: u64, bytes: usize) -> Result<(), VirtioError> {
+        let (request_phys, request_virt) = state.request;
+        unsafe {
+            ((request_virt + HEADER_OFFSET) as *mut u32).write_volatile(kind);
+            ((request_virt + HEADER_OFFSET + 4) as *mut u32).write_volatile(0);
+            ((request_virt + HEADER_OFFSET + 8) as *mut u64).write_volatile(sector);
+            ((request_virt + STATUS_OFFSET) as *mut u8).write_volatile(STATUS_PENDING);
+        }
+
+        let mut chain = [Buffer { phys: 0, len: 0, writable: false }; BOUNCE_FRAMES + 2];
+        chain[0] = Buffer { phys: request_phys + HEADER_OFFSET, len: HEADER_SIZE, writable: false };
+        let data_frames = bytes.div_ceil(FRAME_SIZE);
+        for (i, buffer) in chain[1..=data_frames].iter_mut().enumerate() {
+            let len = (bytes - i * FRAME_SIZE).min(FRAME_SIZE);
+            *buffer = Buffer { phys: state.bounce[i].0, len: len as u32, writable: kind == REQUEST_IN };
+        }
+        chain[data_frames + 1] = Buffer { phys: request_phys + STATUS_OFFSET, len: 1, writable: true };
+
+        let head = state.queue.add(&chain[..data_frames + 2])?;
+        self.device.notify(&state.queue);
+        for _ in 0..POLLS {
+            match state.queue.pop_used() {
+                Some((used, _)) if used == head => {
+                    let status = unsafe { ((request_virt + STATUS_OFFSET) as *const u8).read_volatile() };
+                    return if status == STATUS_OK { Ok(()) } else { Err(VirtioError::Request(status)) };
+                },
+                // Only one request is in flight at a time.
+                Some(_) => {},
+                None => core::hint::spin_loop(),
+            }
+        }
+        Err(VirtioError::Timeout)
+    }
+
+    /// Reads whole sectors from `lba` into `buffer`.
+    pub fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), VirtioError> {
+        self.check_range(lba, buffer.len())?;
+        let mut state = self.state.lock();
+        for (i, chunk) in buffer.chunks_mut(MAX_BYTES_PER_REQUEST).enumerate() {
+            let lba = lba + (i * MAX_BYTES_PER_REQUEST / SECTOR_SIZE) as u64;
+            self.request(&mut state, REQUEST_IN, lba, chunk.len())?;
+            for (j, part) in chunk.chunks_mut(FRAME_SIZE).enumerate() {
+                part.copy_from_slice(&state.bounce_frame(j)[..part.len()]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes whole sectors from `buffer` to `lba`, then flushes the device
+    /// cache if it has one.
+    pub fn write(&self, lba: u64, buffer: &[u8]) -> Result<(), VirtioError> {
+        if self.read_only {
+            return Err(VirtioError::ReadOnly);
+        }
+        self.check_range(lba, buffer.len())?;
+        let mut state = self.state.lock();
+        for (i, chunk) in buffer.chunks(MAX_BYTES_PER_REQUEST).enumerate() {
+            let lba = lba + (i * MAX_BYTES_PER_REQUEST / SECTOR_SIZE) as u64;
+            for (j, part) in chunk.chunks(FRAME_SIZE).enumerate() {
+                state.bounce_frame_mut(j)[..part.len()].copy_from_slice(part);
+            }
+            self.request(&mut state, REQUEST_OUT, lba, chunk.len())?;
+        }
+        if self.device.features() & F_FLUSH != 0 {
+            self.request(&mut state, REQUEST_FLUSH, 0, 0)?;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        Ok(self.read(lba, buffer)?)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError> {
+        Ok(self.write(lba, buffer)?)
+    }
+}
+
+/// Probes for a virtio block device and prints its size.
+pub fn init() -> Option<VirtioBlk> {
+    match VirtioBlk::probe() {
+        Ok(blk) => {
+            println!("virtio-blk at {} ({}): {} MiB{}", blk.device.pci.address,
+                if blk.is_modern() { "modern" } else { "legacy" },
+                (blk.sectors() * SECTOR_SIZE as u64) >> 20,
+                if blk.is_read_only() { ", read-only" } else { "" });
+            Some(blk)
+        },
+        Err(VirtioError::NoDevice) => None,
+        Err(err) => {
+            println!("virtio-blk: {}", err);
+            None
+        },
+    }
+}
+
+#[test_case]
+fn virtio_blk_read_write() {
+    // scripts/run-tests.sh attaches target/test-disk-virtio.img as a virtio-blk-pci device.
+    let blk = VirtioBlk::probe().expect("no virtio-blk device");
+    assert_eq!(blk.sectors(), (1 << 20) / SECTOR_SIZE as u64);
+    let mut sectors = [0u8; 17 * SECTOR_SIZE];
+    blk.read_blocks(0, &mut sectors[..SECTOR_SIZE]).unwrap();
+    assert!(sectors.starts_with(b"KRABBOS TEST DISK"));
+
+    // 17 sectors take three bounce frames, the last one partly.
+    for (i, byte) in sectors.iter_mut().enumerate() {
+        *byte = (i * 7) as u8;
+    }
+    blk.write_blocks(1, &sectors).unwrap();
+    let mut read_back = [0u8; 17 * SECTOR_SIZE];
+    blk.read_blocks(1, &mut read_back).unwrap();
+    assert!(read_back == sectors);
+
+    assert!(matches!(blk.read_blocks(blk.sectors(), &mut read_back[..SECTOR_SIZE]), Err(IoError::OutOfRange)));
+    assert!(matches!(blk.read_blocks(0, &mut read_back[..100]), Err(IoError::BadBuffer)));
+}
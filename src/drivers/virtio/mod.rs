@@ -0,0 +1,414 @@
+//! Virtio over PCI: device discovery, the modern capability-based transport
+//! with a fallback to the legacy I/O port one, feature negotiation and
+//! virtqueue setup. The device drivers build on [`VirtioDevice`].
+
+pub mod blk;
+pub mod queue;
+
+use core::fmt;
+use crate::{
+    memory::{FRAME_ALLOCATOR, MAPPER},
+    pci::{
+        self,
+        bar::{BarRegion, IoRegion, MmioRegion, PciError},
+        msi::CAPABILITY_VENDOR_SPECIFIC,
+        PciDevice,
+    },
+    storage::IoError,
+};
+use super::allocate_dma;
+use queue::{QueueLayout, Virtqueue, MAX_QUEUE_SIZE};
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+/// Transitional devices use IDs 0x1000 to 0x103F and give the type as their
+/// subsystem ID, modern ones use 0x1040 plus the type.
+const LEGACY_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+const CONFIG_SUBSYSTEM_ID: u8 = 0x2E;
+
+pub const DEVICE_TYPE_NET: u16 = 1;
+pub const DEVICE_TYPE_BLOCK: u16 = 2;
+
+/// Device status bits.
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// Required by the modern transport, and only offered by it.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+/// `cfg_type` of the virtio vendor specific capabilities.
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+/// Modern common configuration registers.
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// Legacy registers in I/O BAR 0.
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+/// The device configuration follows, as long as MSI-X is off.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+const FRAME_SIZE: u64 = crate::memory::PAGE_SIZE_4K;
+
+#[derive(Debug)]
+pub enum VirtioError {
+    NoDevice,
+    Pci(PciError),
+    /// No frames for the queues or the driver's buffers.
+    NoMemory,
+    /// The device rejected the features, or lacks ones the driver needs.
+    Features,
+    /// The queue does not exist or is larger than [`MAX_QUEUE_SIZE`].
+    QueueUnavailable,
+    QueueFull,
+    Timeout,
+    /// The device completed a request with this status.
+    Request(u8),
+    OutOfRange,
+    /// The buffer is not a whole number of blocks.
+    BadBuffer,
+    ReadOnly,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VirtioError::NoDevice => write!(f, "no virtio device"),
+            VirtioError::Pci(err) => write!(f, "{}", err),
+            VirtioError::NoMemory => write!(f, "no DMA memory"),
+            VirtioError::Features => write!(f, "feature negotiation failed"),
+            VirtioError::QueueUnavailable => write!(f, "queue unavailable"),
+            VirtioError::QueueFull => write!(f, "queue full"),
+            VirtioError::Timeout => write!(f, "request timed out"),
+            VirtioError::Request(status) => write!(f, "request failed with status {}", status),
+            VirtioError::OutOfRange => write!(f, "block out of range"),
+            VirtioError::BadBuffer => write!(f, "buffer is not a whole number of blocks"),
+            VirtioError::ReadOnly => write!(f, "device is read-only"),
+        }
+    }
+}
+
+impl From<VirtioError> for IoError {
+    fn from(err: VirtioError) -> Self {
+        match err {
+            VirtioError::Timeout => IoError::Timeout,
+            VirtioError::Request(status) => IoError::DeviceError { code: status as u32 },
+            VirtioError::OutOfRange => IoError::OutOfRange,
+            VirtioError::BadBuffer => IoError::BadBuffer,
+            VirtioError::ReadOnly => IoError::ReadOnly,
+            _ => IoError::DeviceError { code: 0 },
+        }
+    }
+}
+
+impl From<PciError> for VirtioError {
+    fn from(err: PciError) -> Self {
+        VirtioError::Pci(err)
+    }
+}
+
+/// A virtio capability from the PCI capability list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioCapability {
+    pub cfg_type: u8,
+    pub bar: u8,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl VirtioCapability {
+    /// Decodes the first four dwords of the capability.
+    pub fn parse(dwords: [u32; 4]) -> Self {
+        VirtioCapability {
+            cfg_type: (dwords[0] >> 24) as u8,
+            bar: dwords[1] as u8,
+            offset: dwords[2],
+            length: dwords[3],
+        }
+    }
+}
+
+enum Transport {
+    Modern {
+        common: MmioRegion,
+        notify: MmioRegion,
+        notify_multiplier: u32,
+        isr: MmioRegion,
+        device: MmioRegion,
+    },
+    Legacy(IoRegion),
+}
+
+/// A virtio device on the PCI bus, between discovery and its driver.
+pub struct VirtioDevice {
+    pub pci: PciDevice,
+    transport: Transport,
+    features: u64,
+}
+
+impl VirtioDevice {
+    /// Finds the first virtio function of `device_type`.
+    pub fn find(device_type: u16) -> Option<PciDevice> {
+        pci::devices().filter(|d| d.vendor_id == VENDOR_ID).find(|d| {
+            d.device_id == MODERN_DEVICE_ID_BASE + device_type
+                || LEGACY_DEVICE_IDS.contains(&d.device_id) && d.address.read_u16(CONFIG_SUBSYSTEM_ID) == device_type
+        })
+    }
+
+    /// Maps the registers of `pci`, through the virtio capabilities if it has
+    /// them and through its legacy I/O BAR otherwise, and lets it do DMA.
+    pub fn open(pci: PciDevice) -> Result<Self, VirtioError> {
+        let transport = match Self::modern_transport(&pci)? {
+            Some(transport) => transport,
+            None => match map_bar(&pci, 0)? {
+                BarRegion::Io(io) => Transport::Legacy(io),
+                BarRegion::Mmio(_) => return Err(VirtioError::Pci(PciError::NoSuchBar)),
+            },
+        };
+        unsafe { pci.enable_bus_master(); }
+        Ok(VirtioDevice { pci, transport, features: 0 })
+    }
+
+    fn modern_transport(pci: &PciDevice) -> Result<Option<Transport>, VirtioError> {
+        let mut regions = [None; 5];
+        let mut notify_multiplier = 0;
+        for capability in pci.capabilities().filter(|c| c.id == CAPABILITY_VENDOR_SPECIFIC) {
+            let read = |i: u8| pci.address.read_u32(capability.offset + 4 * i);
+            let virtio = VirtioCapability::parse([read(0), read(1), read(2), read(3)]);
+            // The first capability of each type is the preferred one.
+            let kind = virtio.cfg_type as usize;
+            if !(CAP_COMMON_CFG..=CAP_DEVICE_CFG).contains(&virtio.cfg_type) || regions[kind].is_some() {
+                continue;
+            }
+            let BarRegion::Mmio(bar) = map_bar(pci, virtio.bar)? else {
+                continue;
+            };
+            if virtio.offset as u64 + virtio.length as u64 > bar.size() {
+                continue;
+            }
+            if virtio.cfg_type == CAP_NOTIFY_CFG {
+                notify_multiplier = read(4);
+            }
+            regions[kind] = Some(unsafe { MmioRegion::new(bar.base() + virtio.offset as u64, virtio.length as u64) });
+        }
+        let [_, Some(common), Some(notify), Some(isr), Some(device)] = regions else {
+            return Ok(None);
+        };
+        Ok(Some(Transport::Modern { common, notify, notify_multiplier, isr, device }))
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self.transport, Transport::Modern { .. })
+    }
+
+    /// The features both sides agreed on in [`VirtioDevice::initialize`].
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn status(&self) -> u8 {
+        match &self.transport {
+            Transport::Modern { common, .. } => common.read::<u8>(COMMON_DEVICE_STATUS),
+            Transport::Legacy(io) => unsafe { io.read::<u8>(LEGACY_DEVICE_STATUS) },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match &self.transport {
+            Transport::Modern { common, .. } => unsafe { common.write::<u8>(COMMON_DEVICE_STATUS, status) },
+            Transport::Legacy(io) => unsafe { io.write::<u8>(LEGACY_DEVICE_STATUS, status) },
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match &self.transport {
+            Transport::Modern { common, .. } => unsafe {
+                common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low = common.read::<u32>(COMMON_DEVICE_FEATURE);
+                common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1);
+                (common.read::<u32>(COMMON_DEVICE_FEATURE) as u64) << 32 | low as u64
+            },
+            Transport::Legacy(io) => unsafe { io.read::<u32>(LEGACY_DEVICE_FEATURES) as u64 },
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match &self.transport {
+            Transport::Modern { common, .. } => unsafe {
+                common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0);
+                common.write::<u32>(COMMON_DRIVER_FEATURE, features as u32);
+                common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1);
+                common.write::<u32>(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            },
+            Transport::Legacy(io) => unsafe { io.write::<u32>(LEGACY_DRIVER_FEATURES, features as u32) },
+        }
+    }
+
+    /// Resets the device and negotiates the features in `supported` it offers,
+    /// plus [`F_VERSION_1`] on the modern transport. Queues are set up next,
+    /// then [`VirtioDevice::driver_ok`] starts the device.
+    pub fn initialize(&mut self, supported: u64) -> Result<u64, VirtioError> {
+        self.set_status(0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let offered = self.device_features();
+        let required = if self.is_modern() { F_VERSION_1 } else { 0 };
+        if offered & required != required {
+            self.set_status(STATUS_FAILED);
+            return Err(VirtioError::Features);
+        }
+        let features = offered & (supported | required);
+        self.set_driver_features(features);
+        if self.is_modern() {
+            self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(VirtioError::Features);
+            }
+        }
+        self.features = features;
+        Ok(features)
+    }
+
+    /// Allocates queue `index` and hands it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        let size = match &self.transport {
+            Transport::Modern { common, .. } => unsafe {
+                common.write::<u16>(COMMON_QUEUE_SELECT, index);
+                let max = common.read::<u16>(COMMON_QUEUE_SIZE);
+                if max == 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+                // Any size up to the maximum works, a power of two keeps the ring math simple.
+                let size = 1 << max.min(MAX_QUEUE_SIZE).ilog2();
+                common.write::<u16>(COMMON_QUEUE_SIZE, size);
+                size
+            },
+            Transport::Legacy(io) => unsafe {
+                io.write::<u16>(LEGACY_QUEUE_SELECT, index);
+                // The legacy queue size is fixed by the device.
+                match io.read::<u16>(LEGACY_QUEUE_SIZE) {
+                    size if size.is_power_of_two() && size <= MAX_QUEUE_SIZE => size,
+                    _ => return Err(VirtioError::QueueUnavailable),
+                }
+            },
+        };
+        let frames = QueueLayout::new(size).size.div_ceil(FRAME_SIZE as usize);
+        let (phys, virt) = allocate_dma(frames).ok_or(VirtioError::NoMemory)?;
+        let queue = unsafe { Virtqueue::new(index, size, phys, virt) };
+
+        match &self.transport {
+            Transport::Modern { common, .. } => unsafe {
+                for (register, address) in [
+                    (COMMON_QUEUE_DESC, queue.descriptors_phys()),
+                    (COMMON_QUEUE_DRIVER, queue.avail_phys()),
+                    (COMMON_QUEUE_DEVICE, queue.used_phys()),
+                ] {
+                    common.write::<u32>(register, address as u32);
+                    common.write::<u32>(register + 4, (address >> 32) as u32);
+                }
+                common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+            },
+            Transport::Legacy(io) => unsafe {
+                io.write::<u32>(LEGACY_QUEUE_PFN, (phys / FRAME_SIZE) as u32);
+            },
+        }
+        Ok(queue)
+    }
+
+    /// Tells the device the queues are set up and it can start.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device new buffers are available in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        match &self.transport {
+            Transport::Modern { common, notify, notify_multiplier, .. } => unsafe {
+                common.write::<u16>(COMMON_QUEUE_SELECT, queue.index());
+                let offset = common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as u64 * *notify_multiplier as u64;
+                notify.write::<u16>(offset, queue.index());
+            },
+            Transport::Legacy(io) => unsafe { io.write::<u16>(LEGACY_QUEUE_NOTIFY, queue.index()) },
+        }
+    }
+
+    /// Reads and so clears the interrupt status, for an INTx handler. Bit 0
+    /// means a queue was used, bit 1 that the configuration changed.
+    pub fn acknowledge_interrupt(&self) -> u8 {
+        match &self.transport {
+            Transport::Modern { isr, .. } => isr.read::<u8>(0),
+            Transport::Legacy(io) => unsafe { io.read::<u8>(LEGACY_ISR) },
+        }
+    }
+
+    /// Reads the device specific configuration dword at `offset`.
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        match &self.transport {
+            Transport::Modern { device, .. } => device.read::<u32>(offset as u64),
+            Transport::Legacy(io) => unsafe { io.read::<u32>(LEGACY_DEVICE_CONFIG + offset) },
+        }
+    }
+
+    /// Reads a 64-bit configuration field as two dwords, again if the device
+    /// changed it in between.
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        loop {
+            let generation = self.config_generation();
+            let low = self.read_config_u32(offset);
+            let high = self.read_config_u32(offset + 4);
+            if self.config_generation() == generation {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+
+    fn config_generation(&self) -> u8 {
+        match &self.transport {
+            Transport::Modern { common, .. } => common.read::<u8>(COMMON_CONFIG_GENERATION),
+            // No generation counter, the field is read once.
+            Transport::Legacy(_) => 0,
+        }
+    }
+}
+
+fn map_bar(pci: &PciDevice, index: u8) -> Result<BarRegion, VirtioError> {
+    let mut mapper = MAPPER.lock();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+        return Err(VirtioError::NoMemory);
+    };
+    Ok(unsafe { pci.map_bar(index, mapper, allocator)? })
+}
+
+#[test_case]
+fn virtio_capability_parsing() {
+    // QEMU's virtio-blk notify capability: BAR 4, offset 0x3000, 4KiB.
+    let capability = VirtioCapability::parse([0x0214_7009, 0x0000_0004, 0x0000_3000, 0x0000_1000]);
+    assert_eq!(capability, VirtioCapability { cfg_type: CAP_NOTIFY_CFG, bar: 4, offset: 0x3000, length: 0x1000 });
+}
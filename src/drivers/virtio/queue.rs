@@ -0,0 +1,270 @@
+//! Split virtqueues: a descriptor table, the available ring the driver fills
+//! and the used ring the device returns buffers through.
+//!
+//! The three parts are laid out contiguously as the legacy transport requires,
+//! the used ring on the next 4KiB boundary, and the modern transport is given
+//! the same addresses.
+
+use core::sync::atomic::{fence, Ordering};
+use super::VirtioError;
+
+/// Largest queue used, whose layout takes 3 frames.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+/// Alignment of the used ring in the legacy layout.
+const USED_ALIGN: usize = 4096;
+
+const DESCRIPTOR_SIZE: usize = 16;
+/// Descriptor flags.
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+
+/// Offsets of the parts of a queue of `size` entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLayout {
+    pub avail: usize,
+    pub used: usize,
+    pub size: usize,
+}
+
+impl QueueLayout {
+    pub const fn new(size: u16) -> Self {
+        let size = size as usize;
+        // Flags, index, the ring and the used event.
+        let avail = DESCRIPTOR_SIZE * size;
+        let used = (avail + 6 + 2 * size).next_multiple_of(USED_ALIGN);
+        QueueLayout { avail, used, size: used + 6 + 8 * size }
+    }
+}
+
+/// Ring slot of the free running index `index`. Indices wrap at 65536, which
+/// `size` divides since it is a power of two.
+pub fn ring_slot(index: u16, size: u16) -> usize {
+    (index % size) as usize
+}
+
+/// Number of entries between the `last` index seen and the `current` one.
+pub fn ring_distance(current: u16, last: u16) -> u16 {
+    current.wrapping_sub(last)
+}
+
+/// A buffer handed to the device, by physical address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub phys: u64,
+    pub len: u32,
+    /// The device writes the buffer rather than reading it.
+    pub writable: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    layout: QueueLayout,
+    phys: u64,
+    virt: u64,
+    /// Head of the list of free descriptors, chained through their next field.
+    free_head: u16,
+    free: u16,
+    /// The available index as last published.
+    avail_index: u16,
+    /// The used index up to which buffers were taken back.
+    last_used: u16,
+}
+
+impl Virtqueue {
+    /// Sets up queue `index` of `size` entries in zeroed memory at `phys`,
+    /// mapped at `virt`.
+    ///
+    /// ## Safety
+    ///
+    /// The memory must be [`QueueLayout::size`] bytes, zeroed and used by
+    /// nothing else, and `size` a power of two up to [`MAX_QUEUE_SIZE`].
+    pub unsafe fn new(index: u16, size: u16, phys: u64, virt: u64) -> Self {
+        assert!(size.is_power_of_two() && size <= MAX_QUEUE_SIZE, "bad queue size {}", size);
+        let mut queue = Virtqueue {
+            index,
+            size,
+            layout: QueueLayout::new(size),
+            phys,
+            virt,
+            free_head: 0,
+            free: size,
+            avail_index: 0,
+            last_used: 0,
+        };
+        for i in 0..size {
+            queue.write_next(i, i.wrapping_add(1));
+        }
+        queue
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptors_phys(&self) -> u64 {
+        self.phys
+    }
+
+    pub fn avail_phys(&self) -> u64 {
+        self.phys + self.layout.avail as u64
+    }
+
+    pub fn used_phys(&self) -> u64 {
+        self.phys + self.layout.used as u64
+    }
+
+    /// Free descriptors left.
+    pub fn free(&self) -> u16 {
+        self.free
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        (self.virt + offset as u64) as *mut T
+    }
+
+    fn descriptor(&self, i: u16) -> usize {
+        i as usize * DESCRIPTOR_SIZE
+    }
+
+    fn read_next(&self, i: u16) -> (u16, u16) {
+        let offset = self.descriptor(i);
+        unsafe { (self.ptr::<u16>(offset + 12).read_volatile(), self.ptr::<u16>(offset + 14).read_volatile()) }
+    }
+
+    fn write_next(&mut self, i: u16, next: u16) {
+        unsafe { self.ptr::<u16>(self.descriptor(i) + 14).write_volatile(next) }
+    }
+
+    /// Chains `buffers` and makes them available to the device, returning the
+    /// head descriptor that [`Virtqueue::pop_used`] gives back. The device still
+    /// has to be notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.free as usize {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut current = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let (_, next) = self.read_next(current);
+            let last = i + 1 == buffers.len();
+            let flags = if buffer.writable { DESC_F_WRITE } else { 0 } | if last { 0 } else { DESC_F_NEXT };
+            let offset = self.descriptor(current);
+            unsafe {
+                self.ptr::<u64>(offset).write_volatile(buffer.phys);
+                self.ptr::<u32>(offset + 8).write_volatile(buffer.len);
+                self.ptr::<u16>(offset + 12).write_volatile(flags);
+            }
+            if last {
+                self.free_head = next;
+            } else {
+                current = next;
+            }
+        }
+        self.free -= buffers.len() as u16;
+
+        let slot = ring_slot(self.avail_index, self.size);
+        unsafe { self.ptr::<u16>(self.layout.avail + 4 + 2 * slot).write_volatile(head); }
+        // The descriptors and the ring entry must be visible before the index.
+        fence(Ordering::SeqCst);
+        self.avail_index = self.avail_index.wrapping_add(1);
+        unsafe { self.ptr::<u16>(self.layout.avail + 2).write_volatile(self.avail_index); }
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Takes back the next chain the device is done with, returning its head
+    /// descriptor and the number of bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = unsafe { self.ptr::<u16>(self.layout.used + 2).read_volatile() };
+        if ring_distance(used_index, self.last_used) == 0 {
+            return None;
+        }
+        // The entry is only valid once the index is seen.
+        fence(Ordering::SeqCst);
+        let entry = self.layout.used + 4 + 8 * ring_slot(self.last_used, self.size);
+        let (head, len) = unsafe {
+            (self.ptr::<u32>(entry).read_volatile() as u16, self.ptr::<u32>(entry + 4).read_volatile())
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut last = head;
+        let mut count = 1;
+        loop {
+            let (flags, next) = self.read_next(last);
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            last = next;
+            count += 1;
+        }
+        self.write_next(last, self.free_head);
+        self.free_head = head;
+        self.free += count;
+        Some((head, len))
+    }
+}
+
+#[test_case]
+fn queue_layouts() {
+    assert_eq!(QueueLayout::new(256), QueueLayout { avail: 4096, used: 8192, size: 8192 + 6 + 2048 });
+    assert_eq!(QueueLayout::new(128), QueueLayout { avail: 2048, used: 4096, size: 4096 + 6 + 1024 });
+    assert_eq!(QueueLayout::new(8).used, 4096);
+}
+
+#[test_case]
+fn ring_index_wrap_around() {
+    assert_eq!(ring_slot(0, 8), 0);
+    assert_eq!(ring_slot(9, 8), 1);
+    assert_eq!(ring_slot(u16::MAX, 256), 255);
+    assert_eq!(ring_slot(u16::MAX.wrapping_add(1), 256), 0);
+    assert_eq!(ring_distance(5, 3), 2);
+    assert_eq!(ring_distance(2, u16::MAX - 1), 4);
+    assert_eq!(ring_distance(7, 7), 0);
+}
+
+#[test_case]
+fn virtqueue_add_and_pop_across_wrap() {
+    #[repr(C, align(4096))]
+    struct Memory([u8; 2 * 4096]);
+    static mut MEMORY: Memory = Memory([0; 2 * 4096]);
+
+    let virt = core::ptr::addr_of_mut!(MEMORY) as u64;
+    let mut queue = unsafe { Virtqueue::new(0, 8, virt, virt) };
+    let layout = QueueLayout::new(8);
+    let mut device_used: u16 = 0;
+
+    // Enough rounds for both free running indices to wrap.
+    for round in 0..70_000u32 {
+        let buffers = [
+            Buffer { phys: 0x1000, len: 16, writable: false },
+            Buffer { phys: 0x2000, len: 512, writable: true },
+            Buffer { phys: 0x3000, len: 1, writable: true },
+        ];
+        let head = queue.add(&buffers).unwrap();
+        assert_eq!(queue.free(), 5);
+        let avail_index = unsafe { queue.ptr::<u16>(layout.avail + 2).read_volatile() };
+        assert_eq!(avail_index, (round + 1) as u16);
+        let published = unsafe { queue.ptr::<u16>(layout.avail + 4 + 2 * ring_slot(round as u16, 8)).read_volatile() };
+        assert_eq!(published, head);
+        assert_eq!(queue.pop_used(), None);
+
+        // What the device does with the chain.
+        let entry = layout.used + 4 + 8 * ring_slot(device_used, 8);
+        device_used = device_used.wrapping_add(1);
+        unsafe {
+            queue.ptr::<u32>(entry).write_volatile(head as u32);
+            queue.ptr::<u32>(entry + 4).write_volatile(513);
+            queue.ptr::<u16>(layout.used + 2).write_volatile(device_used);
+        }
+        assert_eq!(queue.pop_used(), Some((head, 513)));
+        assert_eq!(queue.free(), 8);
+    }
+
+    let too_many = [Buffer { phys: 0, len: 0, writable: false }; 9];
+    assert!(matches!(queue.add(&too_many), Err(VirtioError::QueueFull)));
+}
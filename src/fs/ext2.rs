@@ -0,0 +1,898 @@
+//! A read-only ext2 driver, which reads ext3 volumes too while their journal
+//! is clean.
+//!
+//! [`Ext2Fs::mount`] checks the superblock, and refuses volumes with
+//! incompatible features other than file types in directory entries with
+//! [`Ext2Error::Unsupported`]: ext4 volumes, with their extents, fail there.
+//! Files are read through their direct and indirect block pointers, holes
+//! reading as zeroes, and paths are looked up by the VFS, see [`super::vfs`].
+//!
+//! Blocks of 1, 2 and 4 KiB are read, from devices whose blocks are no
+//! larger. Inodes, group descriptors and indirect blocks are read a device
+//! block at a time through the stack, each time they are needed: mount the
+//! volume over a [`crate::storage::cache::BlockCache`] to keep them in memory.
+
+use core::fmt;
+use crate::storage::{u16_at, u32_at, BlockDevice, IoError};
+use super::{vfs::{self, FileSystem, FsError, Metadata, NodeKind, NodeRef}, DateTime, NameBuf};
+
+/// The largest block size read.
+pub const MAX_BLOCK_SIZE: usize = 4096;
+/// The longest name in a directory, in bytes on the volume.
+pub const MAX_NAME: usize = 255;
+/// The longest name in UTF-8, where each byte that is not UTF-8 takes three.
+pub const MAX_NAME_BYTES: usize = MAX_NAME * 3;
+/// The inode of the root directory.
+pub const ROOT_INODE: u32 = 2;
+
+/// The superblock is 1 KiB at 1 KiB into the volume, whatever the block size.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+/// Superblock fields.
+const SB_INODES_COUNT: usize = 0;
+const SB_BLOCKS_COUNT: usize = 4;
+const SB_FIRST_DATA_BLOCK: usize = 20;
+const SB_LOG_BLOCK_SIZE: usize = 24;
+const SB_BLOCKS_PER_GROUP: usize = 32;
+const SB_INODES_PER_GROUP: usize = 40;
+const SB_MAGIC: usize = 56;
+const SB_REV_LEVEL: usize = 76;
+const SB_INODE_SIZE: usize = 88;
+const SB_FEATURE_INCOMPAT: usize = 96;
+const SB_VOLUME_NAME: usize = 120;
+/// Revision 0 volumes have no features and 128 byte inodes.
+const GOOD_OLD_REV: u32 = 0;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Incompatible features: directory entries carry the type of their inode.
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+
+/// Group descriptor fields.
+const GROUP_DESC_SIZE: usize = 32;
+const GD_INODE_TABLE: usize = 8;
+
+/// Inode fields.
+const I_MODE: usize = 0;
+const I_UID: usize = 2;
+const I_SIZE: usize = 4;
+const I_ATIME: usize = 8;
+const I_CTIME: usize = 12;
+const I_MTIME: usize = 16;
+const I_GID: usize = 24;
+const I_LINKS_COUNT: usize = 26;
+const I_BLOCKS: usize = 28;
+const I_BLOCK: usize = 40;
+const I_FILE_ACL: usize = 104;
+/// The upper half of the size of regular files, past revision 0.
+const I_SIZE_HIGH: usize = 108;
+/// Block pointers: twelve direct ones, then a single, a double and a triple
+/// indirect one.
+const DIRECT_BLOCKS: usize = 12;
+const BLOCK_POINTERS: usize = 15;
+/// Symbolic links this short keep their target in the block pointers.
+const FAST_LINK_MAX: usize = BLOCK_POINTERS * 4;
+
+/// The type bits of the mode.
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+
+/// Directory entry fields. Without [`INCOMPAT_FILETYPE`], the file type
+/// byte is the upper half of the name length, always 0.
+const DE_INODE: usize = 0;
+const DE_REC_LEN: usize = 4;
+const DE_NAME_LEN: usize = 6;
+const DE_FILE_TYPE: usize = 7;
+const DE_NAME: usize = 8;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    Io(IoError),
+    /// The superblock is not one of an ext2 volume, or does not add up.
+    NotExt2,
+    /// The volume has incompatible features this driver does not read, the
+    /// ones in the mask.
+    Unsupported(u32),
+    /// The block size is over [`MAX_BLOCK_SIZE`] or smaller than a block of the device.
+    BlockSize,
+    /// The volume is larger than the device.
+    Truncated,
+    /// An inode or a directory points outside the volume, or does not add up.
+    Corrupt,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NotALink,
+    NameTooLong,
+}
+
+impl fmt::Display for Ext2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ext2Error::Io(err) => write!(f, "{}", err),
+            Ext2Error::NotExt2 => write!(f, "not an ext2 filesystem"),
+            Ext2Error::Unsupported(features) => write!(f, "unsupported features {:#x}", features),
+            Ext2Error::BlockSize => write!(f, "unsupported block size"),
+            Ext2Error::Truncated => write!(f, "filesystem larger than the device"),
+            Ext2Error::Corrupt => write!(f, "filesystem corrupt"),
+            Ext2Error::NotFound => write!(f, "no such file or directory"),
+            Ext2Error::NotADirectory => write!(f, "not a directory"),
+            Ext2Error::IsADirectory => write!(f, "is a directory"),
+            Ext2Error::NotALink => write!(f, "not a symbolic link"),
+            Ext2Error::NameTooLong => write!(f, "file name too long"),
+        }
+    }
+}
+
+impl From<IoError> for Ext2Error {
+    fn from(err: IoError) -> Self {
+        Ext2Error::Io(err)
+    }
+}
+
+impl From<Ext2Error> for FsError {
+    fn from(err: Ext2Error) -> Self {
+        match err {
+            Ext2Error::Io(err) => FsError::Io(err),
+            Ext2Error::NotFound => FsError::NotFound,
+            Ext2Error::NotADirectory => FsError::NotADirectory,
+            Ext2Error::IsADirectory => FsError::IsADirectory,
+            Ext2Error::NotALink => FsError::NotALink,
+            Ext2Error::NameTooLong => FsError::NameTooLong,
+            _ => FsError::Corrupt,
+        }
+    }
+}
+
+/// Reads `out.len()` bytes from byte `offset` of `device`, a block at a time
+/// through the stack.
+fn read_bytes(device: &dyn BlockDevice, offset: u64, out: &mut [u8]) -> Result<(), IoError> {
+    let block_size = device.block_size();
+    let mut bounce = [0u8; MAX_BLOCK_SIZE];
+    let mut done = 0;
+    while done < out.len() {
+        let position = offset + done as u64;
+        let in_block = (position % block_size as u64) as usize;
+        let count = (block_size - in_block).min(out.len() - done);
+        device.read_blocks(position / block_size as u64, &mut bounce[..block_size])?;
+        out[done..done + count].copy_from_slice(&bounce[in_block..in_block + count]);
+        done += count;
+    }
+    Ok(())
+}
+
+/// A file, directory or symbolic link.
+#[derive(Debug, Clone, Copy)]
+pub struct Inode {
+    number: u32,
+    mode: u16,
+    uid: u16,
+    gid: u16,
+    size: u64,
+    links: u16,
+    /// The 512 byte sectors taken, with the extended attribute block.
+    sectors: u32,
+    file_acl: u32,
+    atime: u32,
+    ctime: u32,
+    mtime: u32,
+    block: [u32; BLOCK_POINTERS],
+}
+
+impl Inode {
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// The kind of the inode. Devices, pipes and sockets are taken for empty files.
+    pub fn kind(&self) -> NodeKind {
+        match self.mode & S_IFMT {
+            S_IFDIR => NodeKind::Directory,
+            S_IFLNK => NodeKind::Symlink,
+            _ => NodeKind::File,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind() == NodeKind::Directory
+    }
+
+    /// The permission bits, like 0o644.
+    pub fn mode(&self) -> u32 {
+        (self.mode & !S_IFMT) as u32
+    }
+
+    pub fn uid(&self) -> u16 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u16 {
+        self.gid
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn links(&self) -> u16 {
+        self.links
+    }
+
+    /// The last change of the contents.
+    pub fn modified(&self) -> DateTime {
+        DateTime::from_unix(self.mtime as u64)
+    }
+
+    pub fn accessed(&self) -> DateTime {
+        DateTime::from_unix(self.atime as u64)
+    }
+
+    /// The last change of the inode, its mode or links for instance.
+    pub fn changed(&self) -> DateTime {
+        DateTime::from_unix(self.ctime as u64)
+    }
+}
+
+/// An entry of a directory.
+#[derive(Clone)]
+pub struct DirEntry {
+    name: NameBuf<MAX_NAME_BYTES>,
+    inode: u32,
+    kind: Option<NodeKind>,
+}
+
+impl DirEntry {
+    /// The name, with U+FFFD for bytes that are not UTF-8.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn inode(&self) -> u32 {
+        self.inode
+    }
+
+    /// The kind of the inode, `None` on volumes whose entries do not say,
+    /// which only reading it tells.
+    pub fn kind(&self) -> Option<NodeKind> {
+        self.kind
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (inode {}, {:?})", self.name(), self.inode, self.kind)
+    }
+}
+
+/// A mounted ext2 volume.
+pub struct Ext2Fs {
+    device: &'static dyn BlockDevice,
+    block_size: usize,
+    /// Device blocks per block of the volume.
+    blocks_per_block: u64,
+    blocks: u32,
+    inodes: u32,
+    first_data_block: u32,
+    inodes_per_group: u32,
+    groups: u32,
+    inode_size: usize,
+    revision: u32,
+    /// Whether directory entries carry the type of their inode.
+    file_types: bool,
+    label: NameBuf<{ 16 * 3 }>,
+}
+
+impl Ext2Fs {
+    /// Reads the superblock on `device`, usually a partition.
+    pub fn mount(device: &'static dyn BlockDevice) -> Result<Self, Ext2Error> {
+        let device_block_size = device.block_size();
+        if device_block_size == 0 || device_block_size > MAX_BLOCK_SIZE {
+            return Err(Ext2Error::BlockSize);
+        }
+        let mut superblock = [0u8; SUPERBLOCK_SIZE];
+        read_bytes(device, SUPERBLOCK_OFFSET, &mut superblock)?;
+        if u16_at(&superblock, SB_MAGIC) != MAGIC {
+            return Err(Ext2Error::NotExt2);
+        }
+        let revision = u32_at(&superblock, SB_REV_LEVEL);
+        let (incompat, inode_size) = match revision {
+            GOOD_OLD_REV => (0, GOOD_OLD_INODE_SIZE),
+            _ => (u32_at(&superblock, SB_FEATURE_INCOMPAT), u16_at(&superblock, SB_INODE_SIZE) as usize),
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(Ext2Error::Unsupported(incompat & !SUPPORTED_INCOMPAT));
+        }
+
+        let log_block_size = u32_at(&superblock, SB_LOG_BLOCK_SIZE);
+        if log_block_size > MAX_BLOCK_SIZE.trailing_zeros() - 10 {
+            return Err(Ext2Error::BlockSize);
+        }
+        let block_size = 1024 << log_block_size;
+        let blocks = u32_at(&superblock, SB_BLOCKS_COUNT);
+        let inodes = u32_at(&superblock, SB_INODES_COUNT);
+        let first_data_block = u32_at(&superblock, SB_FIRST_DATA_BLOCK);
+        let blocks_per_group = u32_at(&superblock, SB_BLOCKS_PER_GROUP);
+        let inodes_per_group = u32_at(&superblock, SB_INODES_PER_GROUP);
+        if blocks_per_group == 0 || inodes_per_group == 0 || first_data_block >= blocks
+            || !inode_size.is_power_of_two() || !(GOOD_OLD_INODE_SIZE..=block_size).contains(&inode_size) {
+            return Err(Ext2Error::NotExt2);
+        }
+        let groups = (blocks - first_data_block).div_ceil(blocks_per_group);
+        if (groups as u64 * inodes_per_group as u64) < inodes as u64 {
+            return Err(Ext2Error::NotExt2);
+        }
+        if block_size % device_block_size != 0 {
+            return Err(Ext2Error::BlockSize);
+        }
+        let blocks_per_block = (block_size / device_block_size) as u64;
+        if blocks as u64 * blocks_per_block > device.num_blocks() {
+            return Err(Ext2Error::Truncated);
+        }
+
+        let mut label = NameBuf::new();
+        let name = &superblock[SB_VOLUME_NAME..SB_VOLUME_NAME + 16];
+        label.push_lossy(&name[..name.iter().position(|&byte| byte == 0).unwrap_or(16)]);
+        Ok(Ext2Fs {
+            device,
+            block_size,
+            blocks_per_block,
+            blocks,
+            inodes,
+            first_data_block,
+            inodes_per_group,
+            groups,
+            inode_size,
+            revision,
+            file_types: incompat & INCOMPAT_FILETYPE != 0,
+            label,
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        self.label.as_str()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn blocks(&self) -> u32 {
+        self.blocks
+    }
+
+    pub fn root(&self) -> Result<Inode, Ext2Error> {
+        self.inode(ROOT_INODE)
+    }
+
+    /// Reads block `block` of the volume into `buffer`, which is one block long.
+    fn read_block(&self, block: u32, buffer: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks {
+            return Err(Ext2Error::Corrupt);
+        }
+        self.device.read_blocks(block as u64 * self.blocks_per_block, buffer)?;
+        Ok(())
+    }
+
+    /// Reads the bytes from `offset` in block `block` of the volume into `out`.
+    fn read_in_block(&self, block: u32, offset: usize, out: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks {
+            return Err(Ext2Error::Corrupt);
+        }
+        read_bytes(self.device, block as u64 * self.block_size as u64 + offset as u64, out)?;
+        Ok(())
+    }
+
+    /// Reads the inode numbered `number`, counting from 1.
+    pub fn inode(&self, number: u32) -> Result<Inode, Ext2Error> {
+        if number == 0 || number > self.inodes {
+            return Err(Ext2Error::NotFound);
+        }
+        let (group, index) = ((number - 1) / self.inodes_per_group, (number - 1) % self.inodes_per_group);
+        if group >= self.groups {
+            return Err(Ext2Error::Corrupt);
+        }
+        // The descriptors start in the block after the superblock.
+        let descriptors = self.first_data_block + 1;
+        let descriptor_offset = group as usize * GROUP_DESC_SIZE;
+        let mut descriptor = [0u8; GROUP_DESC_SIZE];
+        let block = descriptors + (descriptor_offset / self.block_size) as u32;
+        self.read_in_block(block, descriptor_offset % self.block_size, &mut descriptor)?;
+        let inode_offset = index as usize * self.inode_size;
+        let block = u32_at(&descriptor, GD_INODE_TABLE)
+            .checked_add((inode_offset / self.block_size) as u32)
+            .filter(|&block| block < self.blocks)
+            .ok_or(Ext2Error::Corrupt)?;
+        let mut raw = [0u8; GOOD_OLD_INODE_SIZE];
+        self.read_in_block(block, inode_offset % self.block_size, &mut raw)?;
+
+        let mode = u16_at(&raw, I_MODE);
+        let mut size = u32_at(&raw, I_SIZE) as u64;
+        if self.revision != GOOD_OLD_REV && mode & S_IFMT == S_IFREG {
+            size |= (u32_at(&raw, I_SIZE_HIGH) as u64) << 32;
+        }
+        let mut block = [0; BLOCK_POINTERS];
+        for (i, pointer) in block.iter_mut().enumerate() {
+            *pointer = u32_at(&raw, I_BLOCK + 4 * i);
+        }
+        Ok(Inode {
+            number,
+            mode,
+            uid: u16_at(&raw, I_UID),
+            gid: u16_at(&raw, I_GID),
+            size,
+            links: u16_at(&raw, I_LINKS_COUNT),
+            sectors: u32_at(&raw, I_BLOCKS),
+            file_acl: u32_at(&raw, I_FILE_ACL),
+            atime: u32_at(&raw, I_ATIME),
+            ctime: u32_at(&raw, I_CTIME),
+            mtime: u32_at(&raw, I_MTIME),
+            block,
+        })
+    }
+
+    /// Returns the block of the volume holding block `index` of `inode`, 0 for a hole.
+    fn block_of(&self, inode: &Inode, index: u64) -> Result<u32, Ext2Error> {
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.block[index as usize]);
+        }
+        let per_block = (self.block_size / 4) as u64;
+        let mut index = index - DIRECT_BLOCKS as u64;
+        // The blocks under a pointer of the indirect block at this level.
+        let mut span = 1;
+        for level in 0..BLOCK_POINTERS - DIRECT_BLOCKS {
+            if index >= span * per_block {
+                index -= span * per_block;
+                span *= per_block;
+                continue;
+            }
+            let mut block = inode.block[DIRECT_BLOCKS + level];
+            for _ in 0..=level {
+                if block == 0 {
+                    return Ok(0);
+                }
+                let mut pointer = [0u8; 4];
+                self.read_in_block(block, (index / span) as usize * 4, &mut pointer)?;
+                block = u32::from_le_bytes(pointer);
+                index %= span;
+                span /= per_block;
+            }
+            return Ok(block);
+        }
+        Err(Ext2Error::Corrupt)
+    }
+
+    /// Reads the data of `inode` from `offset` on into `buffer`, returning how
+    /// many bytes there were, 0 at the end.
+    fn read_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, Ext2Error> {
+        let Some(left) = inode.size.checked_sub(offset).filter(|&left| left != 0) else {
+            return Ok(0);
+        };
+        let len = buffer.len().min(left.min(usize::MAX as u64) as usize);
+        let block_size = self.block_size as u64;
+        let mut bounce = [0u8; MAX_BLOCK_SIZE];
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let in_block = (position % block_size) as usize;
+            let count = (self.block_size - in_block).min(len - done);
+            let out = &mut buffer[done..done + count];
+            match self.block_of(inode, position / block_size)? {
+                0 => out.fill(0),
+                block if count == self.block_size => self.read_block(block, out)?,
+                block => {
+                    self.read_block(block, &mut bounce[..self.block_size])?;
+                    out.copy_from_slice(&bounce[in_block..in_block + count]);
+                }
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Reads the file `inode` from `offset` on into `buffer`, returning how
+    /// many bytes there were, 0 at the end of the file.
+    pub fn read(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, Ext2Error> {
+        match inode.kind() {
+            NodeKind::File => self.read_data(inode, offset, buffer),
+            NodeKind::Directory => Err(Ext2Error::IsADirectory),
+            NodeKind::Symlink => Err(Ext2Error::NotFound),
+        }
+    }
+
+    /// Writes where the symbolic link `inode` points into `target`, returning
+    /// the length.
+    pub fn read_link(&self, inode: &Inode, target: &mut [u8]) -> Result<usize, Ext2Error> {
+        if inode.kind() != NodeKind::Symlink {
+            return Err(Ext2Error::NotALink);
+        }
+        let len = inode.size as usize;
+        let target = target.get_mut(..len).ok_or(Ext2Error::NameTooLong)?;
+        // Fast links take no block but for their extended attributes, as Linux has it.
+        let attribute_sectors = if inode.file_acl != 0 { self.block_size as u32 / 512 } else { 0 };
+        if inode.sectors == attribute_sectors {
+            if len > FAST_LINK_MAX {
+                return Err(Ext2Error::Corrupt);
+            }
+            let mut bytes = [0u8; FAST_LINK_MAX];
+            for (chunk, pointer) in bytes.chunks_exact_mut(4).zip(inode.block) {
+                chunk.copy_from_slice(&pointer.to_le_bytes());
+            }
+            target.copy_from_slice(&bytes[..len]);
+            return Ok(len);
+        }
+        match self.read_data(inode, 0, target)? {
+            read if read == len => Ok(len),
+            _ => Err(Ext2Error::Corrupt),
+        }
+    }
+
+    /// Iterates over the entries of the directory `dir`, `.` and `..` included.
+    pub fn read_dir(&self, dir: &Inode) -> Result<DirIter<'_>, Ext2Error> {
+        self.read_dir_at(dir, 0)
+    }
+
+    /// Iterates over the entries of `dir` from byte `position` on, which
+    /// [`DirIter::position`] returned.
+    pub fn read_dir_at(&self, dir: &Inode, position: u64) -> Result<DirIter<'_>, Ext2Error> {
+        if !dir.is_dir() {
+            return Err(Ext2Error::NotADirectory);
+        }
+        Ok(DirIter { fs: self, dir: *dir, position, block: [0; MAX_BLOCK_SIZE], loaded: None })
+    }
+
+    /// Returns the inode named `name` in the directory `dir`.
+    pub fn lookup(&self, dir: &Inode, name: &str) -> Result<Inode, Ext2Error> {
+        for entry in self.read_dir(dir)? {
+            let entry = entry?;
+            if entry.name() == name {
+                return self.inode(entry.inode);
+            }
+        }
+        Err(Ext2Error::NotFound)
+    }
+}
+
+impl fmt::Debug for Ext2Fs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ext2 {:?}: {} blocks of {} bytes", self.label(), self.blocks, self.block_size)
+    }
+}
+
+/// The entries of a directory, see [`Ext2Fs::read_dir`]. An error ends the
+/// iteration.
+pub struct DirIter<'a> {
+    fs: &'a Ext2Fs,
+    dir: Inode,
+    /// The byte of the directory the next entry starts at.
+    position: u64,
+    block: [u8; MAX_BLOCK_SIZE],
+    /// The block of the directory in `block`.
+    loaded: Option<u64>,
+}
+
+impl DirIter<'_> {
+    /// Where the iteration is in the directory, to carry on from with
+    /// [`Ext2Fs::read_dir_at`].
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, Ext2Error> {
+        let fs = self.fs;
+        let block_size = fs.block_size as u64;
+        while self.position < self.dir.size {
+            let index = self.position / block_size;
+            if self.loaded != Some(index) {
+                self.loaded = None;
+                // Directories have no holes.
+                match fs.block_of(&self.dir, index)? {
+                    0 => return Err(Ext2Error::Corrupt),
+                    block => fs.read_block(block, &mut self.block[..fs.block_size])?,
+                }
+                self.loaded = Some(index);
+            }
+            let raw = &self.block[(self.position % block_size) as usize..fs.block_size];
+            if raw.len() < DE_NAME {
+                return Err(Ext2Error::Corrupt);
+            }
+            // Entries never cross a block, the last one is stretched to its end.
+            let rec_len = u16_at(raw, DE_REC_LEN) as usize;
+            let name_len = raw[DE_NAME_LEN] as usize;
+            if rec_len % 4 != 0 || rec_len < DE_NAME + name_len || rec_len > raw.len() {
+                return Err(Ext2Error::Corrupt);
+            }
+            self.position += rec_len as u64;
+            let inode = u32_at(raw, DE_INODE);
+            if inode == 0 {
+                continue;
+            }
+            let mut name = NameBuf::new();
+            name.push_lossy(&raw[DE_NAME..DE_NAME + name_len]);
+            let kind = fs.file_types.then(|| match raw[DE_FILE_TYPE] {
+                FT_DIR => NodeKind::Directory,
+                FT_SYMLINK => NodeKind::Symlink,
+                _ => NodeKind::File,
+            });
+            return Ok(Some(DirEntry { name, inode, kind }));
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for DirIter<'_> {
+    type Item = Result<DirEntry, Ext2Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            self.position = self.dir.size;
+        }
+        entry.transpose()
+    }
+}
+
+/// A node keeps the inode number, and its kind, which the VFS needs first.
+impl FileSystem for Ext2Fs {
+    fn root(&self) -> NodeRef {
+        NodeRef { kind: NodeKind::Directory, ino: ROOT_INODE as u64, data: 0 }
+    }
+
+    fn lookup(&self, dir: NodeRef, name: &str) -> Result<NodeRef, FsError> {
+        let inode = Ext2Fs::lookup(self, &self.inode(dir.ino as u32)?, name)?;
+        Ok(NodeRef { kind: inode.kind(), ino: inode.number as u64, data: 0 })
+    }
+
+    fn read_dir(&self, dir: NodeRef, cookie: u64) -> Result<Option<(vfs::DirEntry, u64)>, FsError> {
+        let mut entries = self.read_dir_at(&self.inode(dir.ino as u32)?, cookie)?;
+        let Some(entry) = entries.next().transpose()? else {
+            return Ok(None);
+        };
+        let kind = match entry.kind {
+            Some(kind) => kind,
+            None => self.inode(entry.inode)?.kind(),
+        };
+        let node = NodeRef { kind, ino: entry.inode as u64, data: 0 };
+        Ok(Some((vfs::DirEntry::new(entry.name(), node)?, entries.position())))
+    }
+
+    fn read_at(&self, node: NodeRef, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match node.kind {
+            NodeKind::Symlink => Ok(0),
+            _ => Ok(self.read(&self.inode(node.ino as u32)?, offset, buffer)?),
+        }
+    }
+
+    fn metadata(&self, node: NodeRef) -> Result<Metadata, FsError> {
+        let inode = self.inode(node.ino as u32)?;
+        Ok(Metadata {
+            kind: inode.kind(),
+            size: inode.size,
+            mode: inode.mode(),
+            inode: node.ino,
+            modified: Some(inode.modified()),
+            accessed: Some(inode.accessed()),
+            created: None,
+        })
+    }
+
+    fn read_link(&self, link: NodeRef, target: &mut [u8]) -> Result<usize, FsError> {
+        Ok(Ext2Fs::read_link(self, &self.inode(link.ino as u32)?, target)?)
+    }
+}
+
+#[cfg(test)]
+static mut FIXTURE_1K: [u8; include_bytes!("../../fixtures/ext2-1k.img").len()] = *include_bytes!("../../fixtures/ext2-1k.img");
+#[cfg(test)]
+static mut FIXTURE_2K: [u8; include_bytes!("../../fixtures/ext2-2k.img").len()] = *include_bytes!("../../fixtures/ext2-2k.img");
+#[cfg(test)]
+static mut FIXTURE_4K: [u8; include_bytes!("../../fixtures/ext2-4k.img").len()] = *include_bytes!("../../fixtures/ext2-4k.img");
+
+/// The volumes `scripts/mkfixtures.py` builds with 1, 2 and 4 KiB blocks,
+/// mounted from RAM disks. The 2 KiB one, without file types in its
+/// directories, is read through a block cache.
+#[cfg(test)]
+pub(super) fn fixtures() -> [&'static Ext2Fs; 3] {
+    use spin::Once;
+    use crate::storage::{cache::{BlockCache, WritePolicy}, ramdisk::RamDisk};
+    static DISKS: Once<[RamDisk; 3]> = Once::new();
+    static CACHE: Once<BlockCache<8>> = Once::new();
+    static FS: Once<[Ext2Fs; 3]> = Once::new();
+    FS.call_once(|| {
+        // Only `DISKS` ever takes the buffers, and only once.
+        let [disk_1k, disk_2k, disk_4k] = DISKS.call_once(|| unsafe {[
+            RamDisk::new(&mut *core::ptr::addr_of_mut!(FIXTURE_1K), true).unwrap(),
+            RamDisk::new(&mut *core::ptr::addr_of_mut!(FIXTURE_2K), true).unwrap(),
+            RamDisk::new(&mut *core::ptr::addr_of_mut!(FIXTURE_4K), true).unwrap(),
+        ]});
+        let cache = CACHE.call_once(|| BlockCache::new(disk_2k, WritePolicy::WriteThrough));
+        [Ext2Fs::mount(disk_1k).unwrap(), Ext2Fs::mount(cache).unwrap(), Ext2Fs::mount(disk_4k).unwrap()]
+    })
+    .each_ref()
+}
+
+#[cfg(test)]
+fn read_all<'a>(fs: &Ext2Fs, path: &str, buffer: &'a mut [u8]) -> &'a [u8] {
+    let mut inode = fs.root().unwrap();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        inode = fs.lookup(&inode, name).unwrap();
+    }
+    let len = fs.read(&inode, 0, buffer).unwrap();
+    assert_eq!(len as u64, inode.size());
+    &buffer[..len]
+}
+
+#[test_case]
+fn ext2_volumes_mount() {
+    for (fs, block_size) in fixtures().into_iter().zip([1024, 2048, 4096]) {
+        assert_eq!(fs.label(), "krabbos");
+        assert_eq!(fs.block_size(), block_size);
+        assert_eq!(fs.blocks() as usize * block_size, [640 << 10, 512 << 10, 768 << 10][block_size.trailing_zeros() as usize - 10]);
+        assert!(fs.root().unwrap().is_dir());
+    }
+    // Three groups of 48 inodes, and `hello.txt` in the second one.
+    let fs = fixtures()[0];
+    assert_eq!(fs.groups, 3);
+    assert_eq!(fs.lookup(&fs.root().unwrap(), "hello.txt").unwrap().number(), 79);
+}
+
+#[test_case]
+fn ext2_reads_files_through_indirect_blocks() {
+    use super::fat::pattern;
+    let mut buffer = [0u8; 4096];
+    for fs in fixtures() {
+        assert_eq!(read_all(fs, "/hello.txt", &mut buffer), b"Hello from ext2!\n");
+        assert_eq!(read_all(fs, "deep/a/b/c/d/e/f/g/h/file.txt", &mut buffer), b"nine directories down\n");
+        assert_eq!(read_all(fs, "files/file-number-42.txt", &mut buffer), b"file 42\n");
+
+        // Past the single indirect block with 1 KiB blocks, in odd chunks.
+        let big = fs.lookup(&fs.root().unwrap(), "big.bin").unwrap();
+        let mut offset = 0;
+        loop {
+            let len = fs.read(&big, offset as u64, &mut buffer[..1000]).unwrap();
+            if len == 0 {
+                break;
+            }
+            assert!(buffer[..len].iter().enumerate().all(|(i, &byte)| byte == pattern(offset + i, 5)));
+            offset += len;
+        }
+        assert_eq!(offset, 280 << 10);
+
+        // Holes all the way through the double indirect block.
+        let sparse = fs.lookup(&fs.root().unwrap(), "sparse.bin").unwrap();
+        assert_eq!(sparse.size(), 6 << 20);
+        assert_eq!(fs.read(&sparse, 0, &mut buffer[..5]), Ok(5));
+        assert_eq!(&buffer[..5], b"start");
+        for offset in [5, 4096 * 13 - 100, 3 << 20] {
+            buffer.fill(0xFF);
+            assert_eq!(fs.read(&sparse, offset, &mut buffer), Ok(4096));
+            assert!(buffer.iter().all(|&byte| byte == 0));
+        }
+        assert_eq!(fs.read(&sparse, (6 << 20) - 5, &mut buffer), Ok(5));
+        assert_eq!(&buffer[..5], b"\0\0end");
+        assert_eq!(fs.read(&sparse, 6 << 20, &mut buffer), Ok(0));
+    }
+}
+
+#[test_case]
+fn ext2_lists_directories() {
+    let names = [".", "..", "lost+found", "abs", "big.bin", "deep", "deep-file", "etc", "files", "hello.txt", "loop", "motd", "sparse.bin"];
+    for fs in fixtures() {
+        let mut entries = fs.read_dir(&fs.root().unwrap()).unwrap();
+        for name in names {
+            let entry = entries.next().unwrap().unwrap();
+            assert_eq!(entry.name(), name);
+            match fs.file_types {
+                true => assert_eq!(entry.kind(), Some(fs.inode(entry.inode()).unwrap().kind())),
+                false => assert_eq!(entry.kind(), None),
+            }
+        }
+        assert!(entries.next().is_none());
+
+        // Over more than one block with 1 KiB blocks, from where it stopped.
+        let files = fs.lookup(&fs.root().unwrap(), "files").unwrap();
+        let mut entries = fs.read_dir(&files).unwrap();
+        assert_eq!(entries.nth(2).unwrap().unwrap().name(), "file-number-00.txt");
+        assert_eq!(entries.nth(26).unwrap().unwrap().name(), "file-number-27.txt");
+        let mut rest = fs.read_dir_at(&files, entries.position()).unwrap();
+        assert_eq!(rest.next().unwrap().unwrap().name(), "file-number-28.txt");
+        assert_eq!(rest.count(), 21);
+
+        let hello = fs.lookup(&fs.root().unwrap(), "hello.txt").unwrap();
+        assert!(matches!(fs.read_dir(&hello), Err(Ext2Error::NotADirectory)));
+        assert!(matches!(fs.lookup(&files, "file-number-50.txt"), Err(Ext2Error::NotFound)));
+        assert!(matches!(fs.read(&files, 0, &mut [0; 16]), Err(Ext2Error::IsADirectory)));
+    }
+}
+
+#[test_case]
+fn ext2_follows_symbolic_links_through_the_vfs() {
+    use super::vfs::Vfs;
+    static TABLES: [Vfs; 3] = [Vfs::new(), Vfs::new(), Vfs::new()];
+    let mut buffer = [0u8; 64];
+    for (fs, table) in fixtures().into_iter().zip(&TABLES) {
+        table.mount("/", fs).unwrap();
+        for (path, contents) in [("/deep-file", "nine directories down\n"), ("/motd", "Welcome to krabbos.\n"), ("/abs", "Hello from ext2!\n")] {
+            let file = table.open(path).unwrap();
+            let len = file.read_at(0, &mut buffer).unwrap();
+            assert_eq!(&buffer[..len], contents.as_bytes());
+        }
+        assert!(matches!(table.open("/loop"), Err(FsError::TooManyLinks)));
+        assert!(matches!(table.open("/hello.txt/x"), Err(FsError::NotADirectory)));
+
+        // The long target is in a block, the short ones in the inode.
+        let root = fs.root().unwrap();
+        let mut target = [0u8; 128];
+        let len = Ext2Fs::read_link(fs, &fs.lookup(&root, "deep-file").unwrap(), &mut target).unwrap();
+        assert_eq!((len, &target[..9]), (79, &b"./deep/a/"[..]));
+        let len = Ext2Fs::read_link(fs, &fs.lookup(&root, "motd").unwrap(), &mut target).unwrap();
+        assert_eq!(&target[..len], b"etc/motd");
+        assert!(matches!(Ext2Fs::read_link(fs, &root, &mut target), Err(Ext2Error::NotALink)));
+        assert!(matches!(Ext2Fs::read_link(fs, &fs.lookup(&root, "deep-file").unwrap(), &mut target[..10]), Err(Ext2Error::NameTooLong)));
+    }
+}
+
+#[test_case]
+fn ext2_inodes_carry_metadata() {
+    let modified = Some(DateTime { year: 2024, month: 5, day: 17, hour: 13, minute: 45, second: 30 });
+    for fs in fixtures() {
+        let hostname = fs.lookup(&fs.lookup(&fs.root().unwrap(), "etc").unwrap(), "hostname").unwrap();
+        assert_eq!((hostname.kind(), hostname.size(), hostname.mode(), hostname.links()), (NodeKind::File, 8, 0o600, 1));
+        assert_eq!((hostname.uid(), hostname.gid()), (0, 0));
+        assert_eq!(Some(hostname.modified()), modified);
+        let metadata = FileSystem::metadata(fs, NodeRef { kind: NodeKind::File, ino: hostname.number() as u64, data: 0 }).unwrap();
+        assert_eq!((metadata.size, metadata.mode, metadata.modified, metadata.accessed), (8, 0o600, modified, modified));
+        assert!(metadata.created.is_none());
+        assert_eq!(FileSystem::metadata(fs, FileSystem::root(fs)).unwrap().mode, 0o755);
+    }
+}
+
+#[test_case]
+fn ext2_refuses_what_it_cannot_read() {
+    use crate::storage::MemBlockDevice;
+    static DISK: MemBlockDevice<2048> = MemBlockDevice::new(false);
+    assert!(matches!(Ext2Fs::mount(&DISK), Err(Ext2Error::NotExt2)));
+
+    // The superblock of the 1 KiB volume, without the rest of it.
+    let mut start = [0u8; 2048];
+    fixtures()[0].device.read_blocks(0, &mut start).unwrap();
+    DISK.write_blocks(0, &start).unwrap();
+    assert!(matches!(Ext2Fs::mount(&DISK), Err(Ext2Error::Truncated)));
+    // With extents, as ext4 has them.
+    start[1024 + SB_FEATURE_INCOMPAT] |= 0x40;
+    DISK.write_blocks(0, &start).unwrap();
+    assert!(matches!(Ext2Fs::mount(&DISK), Err(Ext2Error::Unsupported(0x40))));
+
+    // 64 KiB blocks.
+    start[1024 + SB_FEATURE_INCOMPAT] &= !0x40;
+    start[1024 + SB_LOG_BLOCK_SIZE] = 6;
+    DISK.write_blocks(0, &start).unwrap();
+    assert!(matches!(Ext2Fs::mount(&DISK), Err(Ext2Error::BlockSize)));
+}
+
+#[test_case]
+fn ext2_refuses_an_inode_table_past_the_volume() {
+    use crate::storage::MemBlockDevice;
+    static DISK: MemBlockDevice<{ include_bytes!("../../fixtures/ext2-1k.img").len() }> = MemBlockDevice::new(false);
+    let source = fixtures()[0].device;
+    let mut block = [0u8; 512];
+    for lba in 0..source.num_blocks() {
+        source.read_blocks(lba, &mut block).unwrap();
+        DISK.write_blocks(lba, &block).unwrap();
+    }
+    // The descriptor of group 0, in the block after the superblock.
+    let descriptor = 2 * 1024 + GD_INODE_TABLE;
+    DISK.read_blocks(descriptor as u64 / 512, &mut block).unwrap();
+    block[descriptor % 512..descriptor % 512 + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    DISK.write_blocks(descriptor as u64 / 512, &block).unwrap();
+
+    let fs = Ext2Fs::mount(&DISK).unwrap();
+    // A few blocks into the table, which would wrap around.
+    assert!(matches!(fs.inode(11), Err(Ext2Error::Corrupt)));
+    assert!(matches!(fs.inode(2), Err(Ext2Error::Corrupt)));
+}
@@ -0,0 +1,1084 @@
+//! A read-only FAT32 driver.
+//!
+//! [`FatFs::mount`] reads the boot sector of a block device, usually a
+//! partition, and [`FatFs::open`] looks paths up in it the way Windows does:
+//! case-insensitively, by long or by 8.3 name. FAT12 and FAT16 volumes are
+//! recognized and refused with [`FatError::Unsupported`].
+//!
+//! There is no heap, so directory entries and names live in fixed buffers, and
+//! the sectors are bounced through the stack. A few FAT sectors are cached,
+//! which is all following a cluster chain needs, and so is where the last
+//! reads of a few files got to in their chains.
+
+use core::fmt;
+use bitflags::bitflags;
+use crate::{
+    storage::{u16_at, u32_at, BlockDevice, IoError},
+    sync::Mutex,
+};
+use super::{vfs::{self, FileSystem, FsError, Metadata, NodeKind, NodeRef}, DateTime, NameBuf};
+
+/// The largest sector size read.
+pub const MAX_SECTOR_SIZE: usize = 4096;
+/// The longest long name, in UTF-16 code units.
+pub const MAX_NAME_UNITS: usize = 255;
+/// The longest name in UTF-8, three bytes for each UTF-16 code unit at most.
+pub const MAX_NAME_BYTES: usize = MAX_NAME_UNITS * 3;
+/// An 8.3 name with its dot, where a byte of the OEM code page takes three.
+const SHORT_NAME_BYTES: usize = 12 * 3;
+/// FAT sectors kept in memory.
+const FAT_CACHE_LINES: usize = 4;
+/// Files whose position in their cluster chain is remembered.
+const CHAIN_CACHE_LINES: usize = 8;
+/// Where the root directory, which has no entry, is said to be. Entries are
+/// 32 byte aligned, so no entry is there.
+const ROOT_LOCATION: u64 = 1;
+
+/// Boot sector fields.
+const BPB_BYTES_PER_SECTOR: usize = 11;
+const BPB_SECTORS_PER_CLUSTER: usize = 13;
+const BPB_RESERVED_SECTORS: usize = 14;
+const BPB_FAT_COUNT: usize = 16;
+const BPB_ROOT_ENTRIES: usize = 17;
+const BPB_TOTAL_SECTORS_16: usize = 19;
+const BPB_FAT_SIZE_16: usize = 22;
+const BPB_TOTAL_SECTORS_32: usize = 32;
+const BPB_FAT_SIZE_32: usize = 36;
+const BPB_EXT_FLAGS: usize = 40;
+const BPB_VERSION: usize = 42;
+const BPB_ROOT_CLUSTER: usize = 44;
+const BPB_FS_INFO: usize = 48;
+const BPB_BOOT_SIGNATURE: usize = 66;
+const BPB_VOLUME_LABEL: usize = 71;
+const BOOT_SIGNATURE: usize = 510;
+/// The boot signature byte saying the volume ID and label follow.
+const EXTENDED_BOOT_SIGNATURE: u8 = 0x29;
+/// Extended flags: only the FAT numbered in bits 3:0 is in use, rather than
+/// all of them mirroring each other.
+const EXT_FLAGS_NO_MIRRORING: u16 = 1 << 7;
+const EXT_FLAGS_ACTIVE_FAT: u16 = 0xF;
+
+/// FSInfo sector fields and their signatures.
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: usize = 484;
+const FS_INFO_STRUCT: u32 = 0x6141_7272;
+const FS_INFO_FREE_COUNT: usize = 488;
+const FS_INFO_TRAIL_SIGNATURE: usize = 508;
+const FS_INFO_TRAIL: u32 = 0xAA55_0000;
+
+/// FAT32 entries are 28 bits, the top four are reserved.
+const FAT32_MASK: u32 = 0x0FFF_FFFF;
+const FAT32_BAD: u32 = 0x0FFF_FFF7;
+/// This and above end a chain.
+const FAT32_END: u32 = 0x0FFF_FFF8;
+/// Volumes with fewer clusters are FAT12, then up to this many FAT16.
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+
+/// Directory entry fields.
+const DIR_ENTRY_SIZE: usize = 32;
+const DIR_ATTRIBUTES: usize = 11;
+const DIR_NT_FLAGS: usize = 12;
+const DIR_CREATED_FINE: usize = 13;
+const DIR_CREATED_TIME: usize = 14;
+const DIR_CREATED_DATE: usize = 16;
+const DIR_ACCESSED_DATE: usize = 18;
+const DIR_CLUSTER_HIGH: usize = 20;
+const DIR_MODIFIED_TIME: usize = 22;
+const DIR_MODIFIED_DATE: usize = 24;
+const DIR_CLUSTER_LOW: usize = 26;
+const DIR_SIZE: usize = 28;
+/// The first name byte of the entry after the last one, and of a deleted one.
+const DIR_END: u8 = 0x00;
+const DIR_DELETED: u8 = 0xE5;
+/// A first name byte of 0xE5 is stored as this.
+const DIR_KANJI_E5: u8 = 0x05;
+/// NT flags: the base name or the extension, stored upper case, is shown lower case.
+const NT_LOWER_BASE: u8 = 1 << 3;
+const NT_LOWER_EXT: u8 = 1 << 4;
+
+/// Long name entry fields.
+const LFN_ORDER: usize = 0;
+const LFN_TYPE: usize = 12;
+const LFN_CHECKSUM: usize = 13;
+/// The order of the entry holding the end of the name has this bit set.
+const LFN_LAST: u8 = 0x40;
+const LFN_ORDER_MASK: u8 = 0x1F;
+/// Where the 13 UTF-16 code units of an entry are, in runs of 5, 6 and 2.
+const LFN_UNIT_RUNS: [(usize, usize); 3] = [(1, 5), (14, 6), (28, 2)];
+const LFN_UNITS: usize = 13;
+/// Enough entries for [`MAX_NAME_UNITS`] and the terminator.
+const LFN_MAX_ENTRIES: usize = (MAX_NAME_UNITS + 1).div_ceil(LFN_UNITS);
+
+bitflags! {
+    /// The attributes of a directory entry.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Attributes: u8 {
+        const READ_ONLY = 1 << 0;
+        const HIDDEN = 1 << 1;
+        const SYSTEM = 1 << 2;
+        /// The entry is the volume label.
+        const VOLUME_ID = 1 << 3;
+        const DIRECTORY = 1 << 4;
+        const ARCHIVE = 1 << 5;
+        /// Read-only, hidden, system and volume ID together mark a long name entry.
+        const LONG_NAME = 0x0F;
+    }
+}
+
+/// The FAT variants, by the width of their entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Io(IoError),
+    /// The boot sector is not one of a FAT volume, or does not add up.
+    NotFat,
+    /// A FAT volume of a kind this driver does not read.
+    Unsupported(FatType),
+    /// The sector size is over [`MAX_SECTOR_SIZE`] or smaller than a block of the device.
+    SectorSize,
+    /// The volume is larger than the device.
+    Truncated,
+    /// A cluster chain runs into a free, bad or missing cluster, or into a loop.
+    BadChain,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FatError::Io(err) => write!(f, "{}", err),
+            FatError::NotFat => write!(f, "not a FAT filesystem"),
+            FatError::Unsupported(kind) => write!(f, "{:?} is not supported", kind),
+            FatError::SectorSize => write!(f, "unsupported sector size"),
+            FatError::Truncated => write!(f, "filesystem larger than the device"),
+            FatError::BadChain => write!(f, "corrupt cluster chain"),
+            FatError::NotFound => write!(f, "no such file or directory"),
+            FatError::NotADirectory => write!(f, "not a directory"),
+            FatError::IsADirectory => write!(f, "is a directory"),
+        }
+    }
+}
+
+impl From<IoError> for FatError {
+    fn from(err: IoError) -> Self {
+        FatError::Io(err)
+    }
+}
+
+impl From<FatError> for FsError {
+    fn from(err: FatError) -> Self {
+        match err {
+            FatError::Io(err) => FsError::Io(err),
+            FatError::NotFound => FsError::NotFound,
+            FatError::NotADirectory => FsError::NotADirectory,
+            FatError::IsADirectory => FsError::IsADirectory,
+            _ => FsError::Corrupt,
+        }
+    }
+}
+
+/// A file or directory, as its directory entry describes it.
+#[derive(Clone)]
+pub struct DirEntry {
+    name: NameBuf<MAX_NAME_BYTES>,
+    short_name: NameBuf<SHORT_NAME_BYTES>,
+    attributes: Attributes,
+    cluster: u32,
+    size: u32,
+    /// The byte offset of the entry on the volume.
+    location: u64,
+    created: Option<DateTime>,
+    modified: Option<DateTime>,
+    accessed: Option<DateTime>,
+}
+
+impl DirEntry {
+    /// The long name, or the 8.3 name if there is none.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// The 8.3 name, like `BIGFIL~1.BIN`, lower case where the entry says so.
+    /// Bytes of the OEM code page come out as U+FFFD.
+    pub fn short_name(&self) -> &str {
+        self.short_name.as_str()
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+
+    /// The size in bytes, 0 for directories.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The timestamps, `None` where the entry leaves them out. The last access
+    /// is a date only.
+    pub fn created(&self) -> Option<DateTime> {
+        self.created
+    }
+
+    pub fn modified(&self) -> Option<DateTime> {
+        self.modified
+    }
+
+    pub fn accessed(&self) -> Option<DateTime> {
+        self.accessed
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}, {:?}, {} bytes at cluster {})", self.name(), self.short_name(), self.attributes, self.size, self.cluster)
+    }
+}
+
+/// Returns the date and time of the FAT fields `date` and `time`, `None` if
+/// the date is not set.
+fn date_time(date: u16, time: u16) -> Option<DateTime> {
+    let (month, day) = ((date >> 5 & 0xF) as u8, (date & 0x1F) as u8);
+    if month == 0 || day == 0 {
+        return None;
+    }
+    Some(DateTime {
+        year: 1980 + (date >> 9),
+        month,
+        day,
+        hour: (time >> 11) as u8,
+        minute: (time >> 5 & 0x3F) as u8,
+        second: (time & 0x1F) as u8 * 2,
+    })
+}
+
+/// The checksum of an 8.3 name that its long name entries carry.
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name[..11].iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Appends `bytes` of the OEM code page to `name`, without the spaces they
+/// are padded with.
+fn push_padded<const N: usize>(name: &mut NameBuf<N>, bytes: &[u8], lower: bool) {
+    let len = bytes.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1);
+    for &byte in &bytes[..len] {
+        name.push(match byte {
+            byte if !byte.is_ascii() => char::REPLACEMENT_CHARACTER,
+            byte if lower => byte.to_ascii_lowercase() as char,
+            byte => byte as char,
+        });
+    }
+}
+
+/// Formats the 8.3 name of the entry `raw`.
+fn short_name(raw: &[u8]) -> NameBuf<SHORT_NAME_BYTES> {
+    let mut name = NameBuf::new();
+    let nt_flags = raw[DIR_NT_FLAGS];
+    let mut base = [0; 8];
+    base.copy_from_slice(&raw[..8]);
+    if base[0] == DIR_KANJI_E5 {
+        base[0] = DIR_DELETED;
+    }
+    push_padded(&mut name, &base, nt_flags & NT_LOWER_BASE != 0);
+    if raw[8..11].iter().any(|&byte| byte != b' ') {
+        name.push('.');
+        push_padded(&mut name, &raw[8..11], nt_flags & NT_LOWER_EXT != 0);
+    }
+    name
+}
+
+/// The long name entries seen so far in front of an 8.3 entry.
+struct LongName {
+    units: [u16; LFN_MAX_ENTRIES * LFN_UNITS],
+    /// How many entries the name takes, 0 when there is no sequence underway.
+    entries: u8,
+    /// The order of the entry expected next, 0 once the sequence is complete.
+    next: u8,
+    checksum: u8,
+}
+
+impl LongName {
+    const fn new() -> Self {
+        LongName { units: [0; LFN_MAX_ENTRIES * LFN_UNITS], entries: 0, next: 0, checksum: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.entries = 0;
+        self.next = 0;
+    }
+
+    /// Adds the long name entry `raw`. The entries come last part first, with
+    /// their order counting down to 1; one out of order drops the sequence.
+    fn push(&mut self, raw: &[u8]) {
+        let order = raw[LFN_ORDER] & LFN_ORDER_MASK;
+        if raw[LFN_TYPE] != 0 || order == 0 || order as usize > LFN_MAX_ENTRIES {
+            return self.reset();
+        }
+        if raw[LFN_ORDER] & LFN_LAST != 0 {
+            self.entries = order;
+            self.checksum = raw[LFN_CHECKSUM];
+        } else if self.entries == 0 || order != self.next || raw[LFN_CHECKSUM] != self.checksum {
+            return self.reset();
+        }
+        let offsets = LFN_UNIT_RUNS.iter().flat_map(|&(start, count)| (start..).step_by(2).take(count));
+        for (unit, at) in self.units[(order as usize - 1) * LFN_UNITS..].iter_mut().zip(offsets) {
+            *unit = u16_at(raw, at);
+        }
+        self.next = order - 1;
+    }
+
+    /// Returns the name if the sequence is complete and belongs to the 8.3
+    /// entry `raw`, and starts over.
+    fn take(&mut self, raw: &[u8]) -> Option<NameBuf<MAX_NAME_BYTES>> {
+        let complete = self.entries != 0 && self.next == 0 && self.checksum == short_name_checksum(raw);
+        let units = &self.units[..self.entries as usize * LFN_UNITS];
+        self.entries = 0;
+        self.next = 0;
+        if !complete {
+            return None;
+        }
+        let len = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+        if len == 0 || len > MAX_NAME_UNITS {
+            return None;
+        }
+        let mut name = NameBuf::new();
+        for c in char::decode_utf16(units[..len].iter().copied()) {
+            name.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+        Some(name)
+    }
+}
+
+/// Whether two names are the same, ignoring case.
+fn same_name(a: &str, b: &str) -> bool {
+    a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase))
+}
+
+struct CacheLine {
+    sector: Option<u64>,
+    data: [u8; MAX_SECTOR_SIZE],
+}
+
+/// The FAT sectors read last, replaced round robin.
+struct FatCache {
+    lines: [CacheLine; FAT_CACHE_LINES],
+    next: usize,
+}
+
+/// Where the last read of a file ended in its cluster chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ChainPosition {
+    /// The first cluster of the file, which tells files apart.
+    first: u32,
+    /// How many clusters into the chain `cluster` is.
+    index: u64,
+    cluster: u32,
+}
+
+/// The chain positions of the files read last, replaced round robin, so a
+/// sequential read carries on from the cluster the previous one stopped at.
+struct ChainCache {
+    lines: [Option<ChainPosition>; CHAIN_CACHE_LINES],
+    next: usize,
+}
+
+impl ChainCache {
+    fn remember(&mut self, position: ChainPosition) {
+        let index = match self.lines.iter().position(|line| line.is_some_and(|line| line.first == position.first)) {
+            Some(index) => index,
+            None => {
+                let index = self.next;
+                self.next = (index + 1) % CHAIN_CACHE_LINES;
+                index
+            }
+        };
+        self.lines[index] = Some(position);
+    }
+}
+
+/// A mounted FAT32 volume.
+pub struct FatFs {
+    device: &'static dyn BlockDevice,
+    bytes_per_sector: usize,
+    /// Device blocks in a sector.
+    blocks_per_sector: u64,
+    sectors_per_cluster: u64,
+    /// The first sector of the FAT in use.
+    fat_start: u64,
+    data_start: u64,
+    clusters: u32,
+    root_cluster: u32,
+    free_clusters: Option<u32>,
+    label: NameBuf<SHORT_NAME_BYTES>,
+    fat_cache: Mutex<FatCache>,
+    chain_cache: Mutex<ChainCache>,
+}
+
+impl FatFs {
+    /// Mounts the FAT32 volume on `device`.
+    pub fn mount(device: &'static dyn BlockDevice) -> Result<Self, FatError> {
+        let block_size = device.block_size();
+        if !(512..=MAX_SECTOR_SIZE).contains(&block_size) {
+            return Err(FatError::SectorSize);
+        }
+        let mut buffer = [0u8; MAX_SECTOR_SIZE];
+        let boot = &mut buffer[..block_size];
+        device.read_blocks(0, boot)?;
+        if boot[BOOT_SIGNATURE..BOOT_SIGNATURE + 2] != [0x55, 0xAA] {
+            return Err(FatError::NotFat);
+        }
+
+        let bytes_per_sector = u16_at(boot, BPB_BYTES_PER_SECTOR) as usize;
+        let sectors_per_cluster = boot[BPB_SECTORS_PER_CLUSTER] as u64;
+        let reserved = u16_at(boot, BPB_RESERVED_SECTORS) as u64;
+        let fat_count = boot[BPB_FAT_COUNT] as u64;
+        let root_entries = u16_at(boot, BPB_ROOT_ENTRIES) as u64;
+        let fat_size_16 = u16_at(boot, BPB_FAT_SIZE_16) as u64;
+        let fat_size = match fat_size_16 {
+            0 => u32_at(boot, BPB_FAT_SIZE_32) as u64,
+            size => size,
+        };
+        let total = match u16_at(boot, BPB_TOTAL_SECTORS_16) {
+            0 => u32_at(boot, BPB_TOTAL_SECTORS_32) as u64,
+            total => total as u64,
+        };
+        if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 512
+            || !sectors_per_cluster.is_power_of_two() || reserved == 0 || fat_count == 0 || fat_size == 0 {
+            return Err(FatError::NotFat);
+        }
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE as u64).div_ceil(bytes_per_sector as u64);
+        let data_start = reserved + fat_count * fat_size + root_sectors;
+        if data_start >= total {
+            return Err(FatError::NotFat);
+        }
+        let clusters = ((total - data_start) / sectors_per_cluster).min(FAT32_MASK as u64 - 1) as u32;
+
+        // By the layout of the boot sector, as Linux does, rather than by the
+        // cluster count alone, which would take a small FAT32 volume for FAT12.
+        if fat_size_16 != 0 || root_entries != 0 {
+            return Err(FatError::Unsupported(match clusters {
+                0..FAT12_MAX_CLUSTERS => FatType::Fat12,
+                FAT12_MAX_CLUSTERS..FAT16_MAX_CLUSTERS => FatType::Fat16,
+                _ => FatType::Fat32,
+            }));
+        }
+        let root_cluster = u32_at(boot, BPB_ROOT_CLUSTER);
+        if u16_at(boot, BPB_VERSION) != 0
+            || !(2..clusters as u64 + 2).contains(&(root_cluster as u64))
+            || fat_size * bytes_per_sector as u64 / 4 < clusters as u64 + 2 {
+            return Err(FatError::NotFat);
+        }
+        if bytes_per_sector > MAX_SECTOR_SIZE || bytes_per_sector < block_size {
+            return Err(FatError::SectorSize);
+        }
+        let blocks_per_sector = (bytes_per_sector / block_size) as u64;
+        if total.checked_mul(blocks_per_sector).is_none_or(|blocks| blocks > device.num_blocks()) {
+            return Err(FatError::Truncated);
+        }
+
+        let ext_flags = u16_at(boot, BPB_EXT_FLAGS);
+        let active_fat = match ext_flags & EXT_FLAGS_NO_MIRRORING {
+            0 => 0,
+            _ => (ext_flags & EXT_FLAGS_ACTIVE_FAT) as u64,
+        };
+        if active_fat >= fat_count {
+            return Err(FatError::NotFat);
+        }
+
+        let mut label = NameBuf::new();
+        if boot[BPB_BOOT_SIGNATURE] == EXTENDED_BOOT_SIGNATURE {
+            push_padded(&mut label, &boot[BPB_VOLUME_LABEL..BPB_VOLUME_LABEL + 11], false);
+        }
+
+        let fs_info_sector = u16_at(boot, BPB_FS_INFO) as u64;
+        let mut fs = FatFs {
+            device,
+            bytes_per_sector,
+            blocks_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved + active_fat * fat_size,
+            data_start,
+            clusters,
+            root_cluster,
+            free_clusters: None,
+            label,
+            fat_cache: Mutex::new("FAT_CACHE", FatCache {
+                lines: [const { CacheLine { sector: None, data: [0; MAX_SECTOR_SIZE] } }; FAT_CACHE_LINES],
+                next: 0,
+            }),
+            chain_cache: Mutex::new("FAT_CHAIN_CACHE", ChainCache { lines: [None; CHAIN_CACHE_LINES], next: 0 }),
+        };
+        if (1..reserved).contains(&fs_info_sector) {
+            let fs_info = &mut buffer[..bytes_per_sector];
+            fs.read_sectors(fs_info_sector, fs_info)?;
+            if u32_at(fs_info, 0) == FS_INFO_LEAD_SIGNATURE
+                && u32_at(fs_info, FS_INFO_STRUCT_SIGNATURE) == FS_INFO_STRUCT
+                && u32_at(fs_info, FS_INFO_TRAIL_SIGNATURE) == FS_INFO_TRAIL {
+                fs.free_clusters = Some(u32_at(fs_info, FS_INFO_FREE_COUNT)).filter(|&free| free <= clusters);
+            }
+        }
+        Ok(fs)
+    }
+
+    /// The volume label of the boot sector.
+    pub fn label(&self) -> &str {
+        self.label.as_str()
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster as usize
+    }
+
+    pub fn clusters(&self) -> u32 {
+        self.clusters
+    }
+
+    /// The free clusters as FSInfo last recorded them, which may be out of date.
+    pub fn free_clusters(&self) -> Option<u32> {
+        self.free_clusters
+    }
+
+    /// Reads the sectors from `sector` on into `buffer`, a whole number of them.
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        self.device.read_blocks(sector * self.blocks_per_sector, buffer)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<u32, FatError> {
+        match (2..self.clusters + 2).contains(&cluster) {
+            true => Ok(cluster),
+            false => Err(FatError::BadChain),
+        }
+    }
+
+    /// Returns the cluster after `cluster` in its chain, `None` at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let offset = cluster as usize * 4;
+        let sector = self.fat_start + (offset / self.bytes_per_sector) as u64;
+        let offset = offset % self.bytes_per_sector;
+        let mut cache = self.fat_cache.lock();
+        let index = match cache.lines.iter().position(|line| line.sector == Some(sector)) {
+            Some(index) => index,
+            None => {
+                let index = cache.next;
+                cache.next = (index + 1) % FAT_CACHE_LINES;
+                let line = &mut cache.lines[index];
+                line.sector = None;
+                self.read_sectors(sector, &mut line.data[..self.bytes_per_sector])?;
+                line.sector = Some(sector);
+                index
+            }
+        };
+        match u32_at(&cache.lines[index].data, offset) & FAT32_MASK {
+            FAT32_END.. => Ok(None),
+            FAT32_BAD => Err(FatError::BadChain),
+            next => self.check_cluster(next).map(Some),
+        }
+    }
+
+    /// Returns the cluster `index` clusters into the chain from `first`.
+    fn cluster_at(&self, first: u32, index: u64) -> Result<u32, FatError> {
+        if index >= self.clusters as u64 {
+            return Err(FatError::BadChain);
+        }
+        let mut cluster = self.check_cluster(first)?;
+        for _ in 0..index {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::BadChain)?;
+        }
+        Ok(cluster)
+    }
+
+    /// Returns the cluster `index` clusters into the chain of the file starting
+    /// at `first`, from where its last read stopped when that is not past it.
+    fn file_cluster(&self, first: u32, index: u64) -> Result<u32, FatError> {
+        if index >= self.clusters as u64 {
+            return Err(FatError::BadChain);
+        }
+        let known = self.chain_cache.lock().lines.iter()
+            .flatten()
+            .find(|line| line.first == first && line.index <= index)
+            .copied();
+        let Some(known) = known else {
+            return self.cluster_at(first, index);
+        };
+        let mut cluster = known.cluster;
+        for _ in known.index..index {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::BadChain)?;
+        }
+        Ok(cluster)
+    }
+
+    /// The root directory.
+    pub fn root(&self) -> DirEntry {
+        DirEntry {
+            name: NameBuf::new(),
+            short_name: NameBuf::new(),
+            attributes: Attributes::DIRECTORY,
+            cluster: self.root_cluster,
+            size: 0,
+            location: ROOT_LOCATION,
+            created: None,
+            modified: None,
+            accessed: None,
+        }
+    }
+
+    /// Returns the entry at `location` on the volume, under its 8.3 name.
+    fn entry_at(&self, location: u64) -> Result<DirEntry, FatError> {
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        let sector = &mut sector[..self.bytes_per_sector];
+        self.read_sectors(location / self.bytes_per_sector as u64, sector)?;
+        let offset = (location % self.bytes_per_sector as u64) as usize;
+        Ok(self.entry(&sector[offset..offset + DIR_ENTRY_SIZE], location, None))
+    }
+
+    /// Builds the entry of the 8.3 entry `raw`, named `long_name` if it has one.
+    fn entry(&self, raw: &[u8], location: u64, long_name: Option<NameBuf<MAX_NAME_BYTES>>) -> DirEntry {
+        let attributes = Attributes::from_bits_truncate(raw[DIR_ATTRIBUTES]);
+        let short_name = short_name(raw);
+        let name = long_name.unwrap_or_else(|| {
+            let mut name = NameBuf::new();
+            name.push_str(short_name.as_str());
+            name
+        });
+        let cluster = (u16_at(raw, DIR_CLUSTER_HIGH) as u32) << 16 | u16_at(raw, DIR_CLUSTER_LOW) as u32;
+        // `..` in a directory right under the root points to cluster 0.
+        let cluster = match (cluster, attributes.contains(Attributes::DIRECTORY)) {
+            (0, true) => self.root_cluster,
+            _ => cluster,
+        };
+        let mut created = date_time(u16_at(raw, DIR_CREATED_DATE), u16_at(raw, DIR_CREATED_TIME));
+        if let Some(created) = &mut created {
+            // In 10 ms units past the two second step.
+            created.second += raw[DIR_CREATED_FINE].min(199) / 100;
+        }
+        DirEntry {
+            name,
+            short_name,
+            attributes,
+            cluster,
+            size: u32_at(raw, DIR_SIZE),
+            location,
+            created,
+            modified: date_time(u16_at(raw, DIR_MODIFIED_DATE), u16_at(raw, DIR_MODIFIED_TIME)),
+            accessed: date_time(u16_at(raw, DIR_ACCESSED_DATE), 0),
+        }
+    }
+
+    /// Iterates over the entries of the directory `dir`, `.` and `..`
+    /// included, skipping deleted ones and the volume label.
+    pub fn read_dir(&self, dir: &DirEntry) -> Result<DirIter<'_>, FatError> {
+        self.read_dir_at(dir, 0)
+    }
+
+    /// Iterates over the entries of `dir` from the slot `position` on, which
+    /// [`DirIter::position`] returned.
+    pub fn read_dir_at(&self, dir: &DirEntry, position: u64) -> Result<DirIter<'_>, FatError> {
+        if !dir.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        let per_cluster = (self.cluster_size() / DIR_ENTRY_SIZE) as u64;
+        let skip = position / per_cluster;
+        let first = self.check_cluster(dir.cluster)?;
+        let cluster = match skip {
+            0 => Some(first),
+            _ => self.next_cluster(self.cluster_at(first, skip - 1)?)?,
+        };
+        Ok(DirIter {
+            fs: self,
+            cluster,
+            index: (position % per_cluster) as usize,
+            clusters_seen: skip as u32 + 1,
+            sector: [0; MAX_SECTOR_SIZE],
+            loaded: None,
+            long_name: LongName::new(),
+        })
+    }
+
+    /// Returns the entry named `name` in the directory `dir`.
+    pub fn lookup(&self, dir: &DirEntry, name: &str) -> Result<DirEntry, FatError> {
+        for entry in self.read_dir(dir)? {
+            let entry = entry?;
+            if same_name(entry.name(), name) || same_name(entry.short_name(), name) {
+                return Ok(entry);
+            }
+        }
+        Err(FatError::NotFound)
+    }
+
+    /// Returns the entry at `path`, relative to the root whether or not it
+    /// starts with `/`.
+    pub fn open(&self, path: &str) -> Result<DirEntry, FatError> {
+        let mut entry = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            if !entry.is_dir() {
+                return Err(FatError::NotADirectory);
+            }
+            // The root has no `..` of its own.
+            if name == ".." && entry.cluster == self.root_cluster {
+                continue;
+            }
+            entry = self.lookup(&entry, name)?;
+        }
+        Ok(entry)
+    }
+
+    /// Reads the file `file` from `offset` on into `buffer`, returning how many
+    /// bytes there were, 0 at the end of the file.
+    pub fn read(&self, file: &DirEntry, offset: u64, buffer: &mut [u8]) -> Result<usize, FatError> {
+        if file.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        let Some(left) = (file.size as u64).checked_sub(offset).filter(|&left| left != 0) else {
+            return Ok(0);
+        };
+        let len = buffer.len().min(left as usize);
+        let sector_size = self.bytes_per_sector;
+        let cluster_size = self.cluster_size() as u64;
+        let mut index = offset / cluster_size;
+        let mut cluster = self.file_cluster(file.cluster, index)?;
+        let mut bounce = [0u8; MAX_SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let in_cluster = (offset + done as u64) % cluster_size;
+            let sector = self.cluster_sector(cluster) + in_cluster / sector_size as u64;
+            let in_sector = in_cluster as usize % sector_size;
+            let count = if in_sector == 0 && len - done >= sector_size {
+                // Whole sectors go straight into the buffer, as many as the cluster has.
+                let sectors = ((len - done) / sector_size).min((cluster_size - in_cluster) as usize / sector_size);
+                self.read_sectors(sector, &mut buffer[done..done + sectors * sector_size])?;
+                sectors * sector_size
+            } else {
+                let count = (sector_size - in_sector).min(len - done);
+                self.read_sectors(sector, &mut bounce[..sector_size])?;
+                buffer[done..done + count].copy_from_slice(&bounce[in_sector..in_sector + count]);
+                count
+            };
+            done += count;
+            if done < len && (in_cluster + count as u64) == cluster_size {
+                cluster = self.next_cluster(cluster)?.ok_or(FatError::BadChain)?;
+                index += 1;
+            }
+        }
+        self.chain_cache.lock().remember(ChainPosition { first: file.cluster, index, cluster });
+        Ok(len)
+    }
+}
+
+impl FatFs {
+    /// A node keeps the cluster and the size of its file, and the location of
+    /// its entry, which has the rest.
+    fn node(entry: &DirEntry) -> NodeRef {
+        let kind = if entry.is_dir() { NodeKind::Directory } else { NodeKind::File };
+        NodeRef { kind, ino: entry.location, data: (entry.size as u64) << 32 | entry.cluster as u64 }
+    }
+
+    fn node_entry(node: NodeRef) -> DirEntry {
+        DirEntry {
+            name: NameBuf::new(),
+            short_name: NameBuf::new(),
+            attributes: match node.kind {
+                NodeKind::Directory => Attributes::DIRECTORY,
+                _ => Attributes::empty(),
+            },
+            cluster: node.data as u32,
+            size: (node.data >> 32) as u32,
+            location: node.ino,
+            created: None,
+            modified: None,
+            accessed: None,
+        }
+    }
+}
+
+impl FileSystem for FatFs {
+    fn root(&self) -> NodeRef {
+        Self::node(&FatFs::root(self))
+    }
+
+    fn lookup(&self, dir: NodeRef, name: &str) -> Result<NodeRef, FsError> {
+        Ok(Self::node(&FatFs::lookup(self, &Self::node_entry(dir), name)?))
+    }
+
+    fn read_dir(&self, dir: NodeRef, cookie: u64) -> Result<Option<(vfs::DirEntry, u64)>, FsError> {
+        let mut entries = self.read_dir_at(&Self::node_entry(dir), cookie)?;
+        match entries.next().transpose()? {
+            Some(entry) => Ok(Some((vfs::DirEntry::new(entry.name(), Self::node(&entry))?, entries.position()))),
+            None => Ok(None),
+        }
+    }
+
+    fn read_at(&self, node: NodeRef, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(self.read(&Self::node_entry(node), offset, buffer)?)
+    }
+
+    fn metadata(&self, node: NodeRef) -> Result<Metadata, FsError> {
+        let entry = match node.ino {
+            ROOT_LOCATION => FatFs::root(self),
+            location => self.entry_at(location)?,
+        };
+        let mode = match (entry.is_dir(), entry.attributes.contains(Attributes::READ_ONLY)) {
+            (true, _) => 0o755,
+            (false, true) => 0o444,
+            (false, false) => 0o644,
+        };
+        Ok(Metadata {
+            kind: node.kind,
+            size: entry.size as u64,
+            mode,
+            inode: node.ino,
+            modified: entry.modified,
+            accessed: entry.accessed,
+            created: entry.created,
+        })
+    }
+}
+
+/// The entries of a directory, see [`FatFs::read_dir`]. An error ends the
+/// iteration.
+pub struct DirIter<'a> {
+    fs: &'a FatFs,
+    /// The cluster being read, `None` once done.
+    cluster: Option<u32>,
+    /// The next entry in the cluster.
+    index: usize,
+    clusters_seen: u32,
+    sector: [u8; MAX_SECTOR_SIZE],
+    loaded: Option<u64>,
+    long_name: LongName,
+}
+
+impl DirIter<'_> {
+    /// The slot of the directory the iteration is at, to carry on from with
+    /// [`FatFs::read_dir_at`].
+    pub fn position(&self) -> u64 {
+        let per_cluster = (self.fs.cluster_size() / DIR_ENTRY_SIZE) as u64;
+        (self.clusters_seen as u64 - 1) * per_cluster + self.index as u64
+    }
+
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, FatError> {
+        let fs = self.fs;
+        let entries_per_cluster = fs.cluster_size() / DIR_ENTRY_SIZE;
+        while let Some(cluster) = self.cluster {
+            if self.index == entries_per_cluster {
+                // A directory with more clusters than the volume loops.
+                self.clusters_seen += 1;
+                if self.clusters_seen > fs.clusters {
+                    return Err(FatError::BadChain);
+                }
+                self.cluster = fs.next_cluster(cluster)?;
+                self.index = 0;
+                continue;
+            }
+            let offset = self.index * DIR_ENTRY_SIZE;
+            let sector = fs.cluster_sector(cluster) + (offset / fs.bytes_per_sector) as u64;
+            if self.loaded != Some(sector) {
+                self.loaded = None;
+                fs.read_sectors(sector, &mut self.sector[..fs.bytes_per_sector])?;
+                self.loaded = Some(sector);
+            }
+            self.index += 1;
+            let offset = offset % fs.bytes_per_sector;
+            let raw = &self.sector[offset..offset + DIR_ENTRY_SIZE];
+            let attributes = Attributes::from_bits_truncate(raw[DIR_ATTRIBUTES]);
+            match raw[0] {
+                DIR_END => break,
+                DIR_DELETED => self.long_name.reset(),
+                _ if attributes == Attributes::LONG_NAME => self.long_name.push(raw),
+                _ if attributes.contains(Attributes::VOLUME_ID) => self.long_name.reset(),
+                _ => {
+                    let location = sector * fs.bytes_per_sector as u64 + offset as u64;
+                    let long_name = self.long_name.take(raw);
+                    return Ok(Some(fs.entry(raw, location, long_name)));
+                }
+            }
+        }
+        self.cluster = None;
+        Ok(None)
+    }
+}
+
+impl Iterator for DirIter<'_> {
+    type Item = Result<DirEntry, FatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            self.cluster = None;
+        }
+        entry.transpose()
+    }
+}
+
+#[cfg(test)]
+static mut FIXTURE: [u8; include_bytes!("../../fixtures/fat32.img").len()] = *include_bytes!("../../fixtures/fat32.img");
+
+/// The volume `scripts/mkfixtures.py` builds, mounted from a RAM disk.
+#[cfg(test)]
+pub(super) fn fixture() -> &'static FatFs {
+    use spin::Once;
+    use crate::storage::ramdisk::RamDisk;
+    static DISK: Once<RamDisk> = Once::new();
+    static FS: Once<FatFs> = Once::new();
+    FS.call_once(|| {
+        // Only `DISK` ever takes the buffer, and only once.
+        let disk = DISK.call_once(|| RamDisk::new(unsafe { &mut *core::ptr::addr_of_mut!(FIXTURE) }, true).unwrap());
+        FatFs::mount(disk).unwrap()
+    })
+}
+
+/// The contents `scripts/mkfixtures.py` fills the larger files with.
+#[cfg(test)]
+pub(super) fn pattern(index: usize, seed: usize) -> u8 {
+    (index * seed + index / 251) as u8
+}
+
+#[cfg(test)]
+fn read_all<'a>(fs: &FatFs, path: &str, buffer: &'a mut [u8]) -> &'a [u8] {
+    let file = fs.open(path).unwrap();
+    let len = fs.read(&file, 0, buffer).unwrap();
+    assert_eq!(len, file.size() as usize);
+    &buffer[..len]
+}
+
+#[test_case]
+fn fat32_volume_mounts() {
+    let fs = fixture();
+    assert_eq!(fs.label(), "KRABBOSTEST");
+    assert_eq!((fs.cluster_size(), fs.clusters()), (512, 120));
+    assert_eq!(fs.free_clusters(), Some(100));
+}
+
+#[test_case]
+fn fat32_root_lists_in_order() {
+    let fs = fixture();
+    let names = ["HELLO.TXT", "readme.txt", "A long file name.txt", "Ünïcödé näme.txt", "BADSUM~1.TXT", "docs", "Fragmented file.bin"];
+    let mut entries = fs.read_dir(&fs.root()).unwrap();
+    for name in names {
+        assert_eq!(entries.next().unwrap().unwrap().name(), name);
+    }
+    assert!(entries.next().is_none());
+}
+
+#[test_case]
+fn fat32_reads_files_byte_for_byte() {
+    let fs = fixture();
+    let mut buffer = [0u8; 4096];
+    assert_eq!(read_all(fs, "/HELLO.TXT", &mut buffer), b"Hello, FAT32!\n");
+    assert_eq!(read_all(fs, "docs/nested/deep/file.txt", &mut buffer), b"three directories down\n");
+
+    // Six clusters in a row, then three scattered backwards.
+    let big = read_all(fs, "/docs/Big file.bin", &mut buffer);
+    assert_eq!(big.len(), 3000);
+    assert!(big.iter().enumerate().all(|(i, &byte)| byte == pattern(i, 7)));
+    let fragmented = fs.open("Fragmented file.bin").unwrap();
+    let mut offset = 0;
+    let mut chunk = [0u8; 100];
+    loop {
+        let len = fs.read(&fragmented, offset as u64, &mut chunk).unwrap();
+        if len == 0 {
+            break;
+        }
+        assert!(chunk[..len].iter().enumerate().all(|(i, &byte)| byte == pattern(offset + i, 13)));
+        offset += len;
+    }
+    assert_eq!(offset, 1400);
+    // From the middle of a sector to past the end.
+    assert_eq!(fs.read(&fragmented, 1390, &mut chunk), Ok(10));
+    assert_eq!(chunk[9], pattern(1399, 13));
+}
+
+#[test_case]
+fn fat32_reads_carry_on_from_the_last_cluster() {
+    let fs = fixture();
+    let fragmented = fs.open("Fragmented file.bin").unwrap();
+    let position = || fs.chain_cache.lock().lines.iter().flatten().find(|line| line.first == fragmented.cluster).copied();
+    let mut chunk = [0u8; 300];
+
+    assert_eq!(fs.read(&fragmented, 0, &mut chunk), Ok(300));
+    assert_eq!(position().map(|line| line.index), Some(0));
+    // Into the third cluster, going on from the first.
+    assert_eq!(fs.read(&fragmented, 1100, &mut chunk), Ok(300));
+    let last = position().unwrap();
+    assert_eq!((last.index, last.cluster), (2, fs.cluster_at(fragmented.cluster, 2).unwrap()));
+    assert!(chunk.iter().enumerate().all(|(i, &byte)| byte == pattern(1100 + i, 13)));
+    // Behind the remembered cluster, from the start of the chain again.
+    assert_eq!(fs.read(&fragmented, 600, &mut chunk), Ok(300));
+    assert!(chunk.iter().enumerate().all(|(i, &byte)| byte == pattern(600 + i, 13)));
+    assert_eq!(position().map(|line| line.index), Some(1));
+}
+
+#[test_case]
+fn fat32_looks_names_up_case_insensitively() {
+    let fs = fixture();
+    let mut buffer = [0u8; 64];
+    assert_eq!(read_all(fs, "a LONG file NAME.txt", &mut buffer), b"a file with a long name\n");
+    assert_eq!(read_all(fs, "alongf~1.txt", &mut buffer), b"a file with a long name\n");
+    assert_eq!(read_all(fs, "ÜNÏCÖDÉ NÄME.TXT", &mut buffer), "Grüße\n".as_bytes());
+    assert_eq!(read_all(fs, "README.TXT", &mut buffer), b"lower case through the NT flags\n");
+    assert_eq!(fs.open("DOCS/NESTED/../nested/./DEEP/FILE.TXT").unwrap().size(), 23);
+    assert_eq!(fs.open("../docs/..").unwrap().cluster, fs.root_cluster);
+
+    // A long name whose checksum does not match its 8.3 entry is not one.
+    assert!(matches!(fs.open("never shown.txt"), Err(FatError::NotFound)));
+    assert_eq!(read_all(fs, "badsum~1.txt", &mut buffer), b"bad checksum\n");
+    assert!(matches!(fs.open("gone.txt"), Err(FatError::NotFound)));
+    assert!(matches!(fs.open("HELLO.TXT/x"), Err(FatError::NotADirectory)));
+    assert!(matches!(fs.read(&fs.open("docs").unwrap(), 0, &mut buffer), Err(FatError::IsADirectory)));
+}
+
+#[test_case]
+fn fat32_entries_carry_timestamps() {
+    let fs = fixture();
+    let hello = fs.open("hello.txt").unwrap();
+    let at = |year, month, day, hour, minute, second| Some(DateTime { year, month, day, hour, minute, second });
+    assert_eq!(hello.modified(), at(2024, 5, 17, 13, 45, 30));
+    assert_eq!(hello.created(), at(2023, 1, 2, 3, 4, 4));
+    assert_eq!(hello.accessed(), at(2024, 6, 1, 0, 0, 0));
+    assert_eq!(hello.short_name(), "HELLO.TXT");
+    assert!(hello.attributes().contains(Attributes::ARCHIVE) && !hello.is_dir());
+}
+
+#[test_case]
+fn fat12_and_fat16_are_refused() {
+    use crate::storage::MemBlockDevice;
+    static DISK: MemBlockDevice<{ 16 * 512 }> = MemBlockDevice::new(false);
+    assert!(matches!(FatFs::mount(&DISK), Err(FatError::NotFat)));
+
+    // What mkfs.fat makes of a floppy: 512 byte sectors, 224 root entries.
+    let mut boot = [0u8; 512];
+    boot[BPB_BYTES_PER_SECTOR..BPB_BYTES_PER_SECTOR + 2].copy_from_slice(&512u16.to_le_bytes());
+    boot[BPB_SECTORS_PER_CLUSTER] = 1;
+    boot[BPB_RESERVED_SECTORS] = 1;
+    boot[BPB_FAT_COUNT] = 2;
+    boot[BPB_ROOT_ENTRIES..BPB_ROOT_ENTRIES + 2].copy_from_slice(&224u16.to_le_bytes());
+    boot[BPB_TOTAL_SECTORS_16..BPB_TOTAL_SECTORS_16 + 2].copy_from_slice(&2880u16.to_le_bytes());
+    boot[BPB_FAT_SIZE_16..BPB_FAT_SIZE_16 + 2].copy_from_slice(&9u16.to_le_bytes());
+    boot[BOOT_SIGNATURE..].copy_from_slice(&[0x55, 0xAA]);
+    DISK.write_blocks(0, &boot).unwrap();
+    assert!(matches!(FatFs::mount(&DISK), Err(FatError::Unsupported(FatType::Fat12))));
+
+    // 16 KiB clusters on 512 MiB: FAT16 by its cluster count.
+    boot[BPB_SECTORS_PER_CLUSTER] = 32;
+    boot[BPB_ROOT_ENTRIES..BPB_ROOT_ENTRIES + 2].copy_from_slice(&512u16.to_le_bytes());
+    boot[BPB_TOTAL_SECTORS_16..BPB_TOTAL_SECTORS_16 + 2].fill(0);
+    boot[BPB_TOTAL_SECTORS_32..BPB_TOTAL_SECTORS_32 + 4].copy_from_slice(&(1u32 << 20).to_le_bytes());
+    boot[BPB_FAT_SIZE_16..BPB_FAT_SIZE_16 + 2].copy_from_slice(&128u16.to_le_bytes());
+    DISK.write_blocks(0, &boot).unwrap();
+    assert!(matches!(FatFs::mount(&DISK), Err(FatError::Unsupported(FatType::Fat16))));
+}
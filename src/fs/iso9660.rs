@@ -0,0 +1,1164 @@
+//! A read-only ISO 9660 driver, for CD images like a bootable ISO of the
+//! kernel.
+//!
+//! [`IsoFs::mount`] reads the volume descriptors from sector 16 on and takes
+//! the tree of the primary one. When its root carries the Rock Ridge SP entry,
+//! names, modes, times and symbolic links come from the Rock Ridge entries of
+//! the directory records, continuation areas included. Without them, names
+//! are the upper case ones of the records without their `;1` version, and are
+//! looked up whatever their case.
+//!
+//! Some of the format is only reported: a Joliet supplementary descriptor
+//! shows in [`IsoFs::joliet`] but its tree is not read, and a file recorded in
+//! more than one extent is listed with its whole size but fails to read with
+//! [`IsoError::MultiExtent`]. Directories mkisofs relocates to keep the tree
+//! eight levels deep are found where it moved them.
+//!
+//! Sectors are 2048 bytes, and the blocks of the device must divide them: a
+//! CD-ROM, or an image on a RAM disk of either block size.
+
+use core::fmt;
+use spin::Once;
+use crate::{println, storage::{self, u16_at, u32_at, BlockDevice, IoError}};
+use super::{vfs::{self, FileSystem, FsError, Metadata, NodeKind, NodeRef}, DateTime, NameBuf};
+
+/// The size of a sector, the logical block size of every volume read.
+pub const SECTOR_SIZE: usize = 2048;
+/// The longest Rock Ridge name, in bytes on the volume.
+pub const MAX_NAME: usize = 255;
+/// The longest name in UTF-8, where each byte that is not UTF-8 takes three.
+pub const MAX_NAME_BYTES: usize = MAX_NAME * 3;
+
+static ROOT: Once<IsoFs> = Once::new();
+
+/// The volume descriptors start after the 32 KiB of the system area, and end
+/// with a terminator. Past this many, the rest are not looked at.
+const FIRST_DESCRIPTOR: u64 = 16;
+const MAX_DESCRIPTORS: u64 = 32;
+const STANDARD_ID: &[u8] = b"CD001";
+const TYPE_PRIMARY: u8 = 1;
+const TYPE_SUPPLEMENTARY: u8 = 2;
+const TYPE_TERMINATOR: u8 = 255;
+
+/// Volume descriptor fields.
+const VD_TYPE: usize = 0;
+const VD_STANDARD_ID: usize = 1;
+const VD_VOLUME_ID: usize = 40;
+const VD_VOLUME_SPACE: usize = 80;
+const VD_ESCAPES: usize = 88;
+const VD_BLOCK_SIZE: usize = 128;
+const VD_ROOT: usize = 156;
+const VD_CREATED: usize = 813;
+/// The escape sequences of the Joliet UCS-2 levels 1 to 3.
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+/// Directory record fields. Numbers are recorded twice, little then big
+/// endian.
+const DR_LEN: usize = 0;
+const DR_EXT_ATTR_LEN: usize = 1;
+const DR_EXTENT: usize = 2;
+const DR_SIZE: usize = 10;
+const DR_RECORDED: usize = 18;
+const DR_FLAGS: usize = 25;
+const DR_NAME_LEN: usize = 32;
+const DR_NAME: usize = 33;
+const ROOT_RECORD_LEN: usize = 34;
+const FLAG_DIRECTORY: u8 = 0x02;
+/// Associated files, like the resource forks of Mac files, share the name of
+/// the file they go with.
+const FLAG_ASSOCIATED: u8 = 0x04;
+/// More records of the same file follow, each for another extent of it.
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// System Use Sharing Protocol entries: a signature, their length and a version.
+const SUSP_HEADER: usize = 4;
+const SP_CHECK: &[u8] = &[0xBE, 0xEF];
+const SP_SKIP: usize = 6;
+const CE_BLOCK: usize = 4;
+const CE_OFFSET: usize = 12;
+const CE_LENGTH: usize = 20;
+/// Continuation areas followed for one record before taking it for a loop.
+const MAX_CONTINUATIONS: usize = 8;
+
+/// Rock Ridge entries: the POSIX mode, links and owners, the name, the target
+/// of a symbolic link and the times.
+const PX_MODE: usize = 4;
+const PX_LINKS: usize = 12;
+const PX_UID: usize = 20;
+const PX_GID: usize = 28;
+const PX_LEN: usize = 36;
+const NM_FLAGS: usize = 4;
+const NM_NAME: usize = 5;
+/// The name of `.` and `..`, which are named without NM.
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT: u8 = 0x04;
+const SL_COMPONENTS: usize = 5;
+/// Component flags: it goes on in the next one, or stands for `.`, `..` or the root.
+const SL_CONTINUE: u8 = 0x01;
+const SL_CURRENT: u8 = 0x02;
+const SL_PARENT: u8 = 0x04;
+const SL_ROOT: u8 = 0x08;
+const TF_FLAGS: usize = 4;
+const TF_STAMPS: usize = 5;
+const TF_CREATION: u8 = 0x01;
+const TF_MODIFY: u8 = 0x02;
+const TF_ACCESS: u8 = 0x04;
+/// The times are 17 byte volume descriptor dates rather than 7 byte ones.
+const TF_LONG_FORM: u8 = 0x80;
+
+/// The type bits of the mode.
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoError {
+    Io(IoError),
+    /// No primary volume descriptor from sector 16 on.
+    NotIso9660,
+    /// The logical blocks are not [`SECTOR_SIZE`] bytes, or the blocks of the
+    /// device do not divide them.
+    BlockSize,
+    /// The volume is larger than the device.
+    Truncated,
+    /// The file is recorded in more than one extent, which is not read.
+    MultiExtent,
+    /// A record points outside the volume, or its numbers do not add up.
+    Corrupt,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NotALink,
+    NameTooLong,
+}
+
+impl fmt::Display for IsoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IsoError::Io(err) => write!(f, "{}", err),
+            IsoError::NotIso9660 => write!(f, "not an ISO 9660 filesystem"),
+            IsoError::BlockSize => write!(f, "unsupported block size"),
+            IsoError::Truncated => write!(f, "filesystem larger than the device"),
+            IsoError::MultiExtent => write!(f, "file recorded in more than one extent"),
+            IsoError::Corrupt => write!(f, "filesystem corrupt"),
+            IsoError::NotFound => write!(f, "no such file or directory"),
+            IsoError::NotADirectory => write!(f, "not a directory"),
+            IsoError::IsADirectory => write!(f, "is a directory"),
+            IsoError::NotALink => write!(f, "not a symbolic link"),
+            IsoError::NameTooLong => write!(f, "file name too long"),
+        }
+    }
+}
+
+impl From<IoError> for IsoError {
+    fn from(err: IoError) -> Self {
+        IsoError::Io(err)
+    }
+}
+
+impl From<IsoError> for FsError {
+    fn from(err: IsoError) -> Self {
+        match err {
+            IsoError::Io(err) => FsError::Io(err),
+            IsoError::NotFound => FsError::NotFound,
+            IsoError::NotADirectory => FsError::NotADirectory,
+            IsoError::IsADirectory => FsError::IsADirectory,
+            IsoError::NotALink => FsError::NotALink,
+            IsoError::NameTooLong => FsError::NameTooLong,
+            _ => FsError::Corrupt,
+        }
+    }
+}
+
+/// Reads the both-endian `u16` at `at`, `None` when its halves disagree.
+fn both_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    let value = u16_at(bytes, at);
+    (value.swap_bytes() == u16_at(bytes, at + 2)).then_some(value)
+}
+
+/// Reads the both-endian `u32` at `at`, `None` when its halves disagree.
+fn both_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let value = u32_at(bytes, at);
+    (value.swap_bytes() == u32_at(bytes, at + 4)).then_some(value)
+}
+
+/// The UTC time of `local`, which is `offset` quarters of an hour ahead of it.
+fn to_utc(local: DateTime, offset: i8) -> DateTime {
+    let seconds = local.to_unix() as i64 - offset as i64 * 15 * 60;
+    DateTime::from_unix(seconds.max(0) as u64)
+}
+
+/// Reads the 7 byte date of a directory record: the years since 1900, month,
+/// day, hour, minute and second, then the offset from UTC in quarters of an
+/// hour. All zeroes is no date.
+fn record_date(raw: &[u8]) -> Option<DateTime> {
+    if raw[..6].iter().all(|&byte| byte == 0) {
+        return None;
+    }
+    let local = DateTime { year: 1900 + raw[0] as u16, month: raw[1], day: raw[2], hour: raw[3], minute: raw[4], second: raw[5] };
+    Some(to_utc(local, raw[6] as i8))
+}
+
+/// Reads the 17 byte date of a volume descriptor: "YYYYMMDDHHMMSS" and
+/// hundredths of a second in digits, then the offset from UTC. All zero digits
+/// is no date.
+fn volume_date(raw: &[u8]) -> Option<DateTime> {
+    let number = |at: usize, len: usize| {
+        raw[at..at + len].iter().try_fold(0u16, |number, &digit| digit.is_ascii_digit().then(|| number * 10 + (digit - b'0') as u16))
+    };
+    let year = number(0, 4).filter(|&year| year != 0)?;
+    let local = DateTime {
+        year,
+        month: number(4, 2)? as u8,
+        day: number(6, 2)? as u8,
+        hour: number(8, 2)? as u8,
+        minute: number(10, 2)? as u8,
+        second: number(12, 2)? as u8,
+    };
+    Some(to_utc(local, raw[16] as i8))
+}
+
+/// Checks the directory record at the start of `raw`, returning its length, 0
+/// for the padding at the end of a sector.
+fn record_len(raw: &[u8]) -> Result<usize, IsoError> {
+    let len = raw[DR_LEN] as usize;
+    if len == 0 {
+        return Ok(0);
+    }
+    // The identifier is at least one byte, `.` and `..` have theirs too.
+    if len < DR_NAME + 1 || len > raw.len() || DR_NAME + raw[DR_NAME_LEN] as usize > len {
+        return Err(IsoError::Corrupt);
+    }
+    Ok(len)
+}
+
+/// Names the record whose identifier is `identifier` without Rock Ridge: `.`
+/// and `..` for the records of a directory and its parent, else the
+/// identifier without its version and the dot of a name without extension.
+fn iso_name<const N: usize>(identifier: &[u8], name: &mut NameBuf<N>) {
+    let name_bytes: &[u8] = match identifier {
+        [0] => b".",
+        [1] => b"..",
+        _ => {
+            let identifier = &identifier[..identifier.iter().rposition(|&byte| byte == b';').unwrap_or(identifier.len())];
+            identifier.strip_suffix(b".").unwrap_or(identifier)
+        }
+    };
+    name.push_lossy(name_bytes);
+}
+
+/// Appends `bytes` to `target` at `len`, false if they do not fit.
+fn append(target: &mut [u8], len: &mut usize, bytes: &[u8]) -> bool {
+    let Some(room) = target.get_mut(*len..*len + bytes.len()) else {
+        return false;
+    };
+    room.copy_from_slice(bytes);
+    *len += bytes.len();
+    true
+}
+
+/// A file, directory or symbolic link, as its directory record and the Rock
+/// Ridge entries in it describe it.
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    /// The byte of the volume the record is at.
+    position: u64,
+    /// The first sector of the data, past the extended attribute record.
+    extent: u32,
+    size: u64,
+    flags: u8,
+    recorded: Option<DateTime>,
+    /// The mode from the PX entry, type bits included.
+    mode: Option<u32>,
+    links: u32,
+    uid: u32,
+    gid: u32,
+    created: Option<DateTime>,
+    modified: Option<DateTime>,
+    accessed: Option<DateTime>,
+    /// Whether an SL entry makes it a symbolic link.
+    symlink: bool,
+}
+
+impl Record {
+    /// A number no other node of the volume has: the byte of the record, or
+    /// of its `.` record for a directory.
+    pub fn ino(&self) -> u64 {
+        match self.is_dir() {
+            true => self.extent as u64 * SECTOR_SIZE as u64,
+            false => self.position,
+        }
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        if self.symlink || self.mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+            NodeKind::Symlink
+        } else if self.flags & FLAG_DIRECTORY != 0 {
+            NodeKind::Directory
+        } else {
+            NodeKind::File
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind() == NodeKind::Directory
+    }
+
+    /// The permission bits, like 0o644. Without Rock Ridge, everything can
+    /// be read and directories searched.
+    pub fn mode(&self) -> u32 {
+        match self.mode {
+            Some(mode) => mode & !S_IFMT,
+            None if self.is_dir() => 0o555,
+            None => 0o444,
+        }
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// The size, of every extent together for a file in more than one.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn links(&self) -> u32 {
+        self.links
+    }
+
+    /// Whether the file is recorded in more than one extent, see
+    /// [`IsoError::MultiExtent`].
+    pub fn is_multi_extent(&self) -> bool {
+        self.flags & FLAG_MULTI_EXTENT != 0
+    }
+
+    /// When the record was written.
+    pub fn recorded(&self) -> Option<DateTime> {
+        self.recorded
+    }
+
+    /// The last change of the contents, from Rock Ridge or else the record.
+    pub fn modified(&self) -> Option<DateTime> {
+        self.modified.or(self.recorded)
+    }
+
+    pub fn accessed(&self) -> Option<DateTime> {
+        self.accessed
+    }
+
+    pub fn created(&self) -> Option<DateTime> {
+        self.created
+    }
+}
+
+/// An entry of a directory.
+#[derive(Clone)]
+pub struct DirEntry {
+    name: NameBuf<MAX_NAME_BYTES>,
+    record: Record,
+}
+
+impl DirEntry {
+    /// The name, with U+FFFD for bytes that are not UTF-8.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn record(&self) -> &Record {
+        &self.record
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (extent {}, {:?})", self.name(), self.record.extent, self.record.kind())
+    }
+}
+
+/// A mounted ISO 9660 volume.
+pub struct IsoFs {
+    device: &'static dyn BlockDevice,
+    /// Device blocks per sector.
+    blocks_per_sector: u64,
+    sectors: u32,
+    /// The bytes the SP entry of the root says to skip at the start of each
+    /// System Use field, `None` without Rock Ridge.
+    rock_ridge: Option<u8>,
+    joliet: Option<u8>,
+    root: Record,
+    label: NameBuf<32>,
+    created: Option<DateTime>,
+}
+
+impl IsoFs {
+    /// Reads the volume descriptors on `device`, a CD-ROM or an image of one.
+    pub fn mount(device: &'static dyn BlockDevice) -> Result<Self, IsoError> {
+        let block_size = device.block_size();
+        if block_size == 0 || SECTOR_SIZE % block_size != 0 {
+            return Err(IsoError::BlockSize);
+        }
+        let blocks_per_sector = (SECTOR_SIZE / block_size) as u64;
+        let device_sectors = (device.num_blocks() / blocks_per_sector).min(u32::MAX as u64) as u32;
+        let mut fs = IsoFs {
+            device,
+            blocks_per_sector,
+            sectors: device_sectors,
+            rock_ridge: None,
+            joliet: None,
+            root: Record {
+                position: 0, extent: 0, size: 0, flags: FLAG_DIRECTORY, recorded: None, mode: None,
+                links: 1, uid: 0, gid: 0, created: None, modified: None, accessed: None, symlink: false,
+            },
+            label: NameBuf::new(),
+            created: None,
+        };
+
+        let mut primary = None;
+        let mut descriptor = [0u8; SECTOR_SIZE];
+        for sector in FIRST_DESCRIPTOR..(FIRST_DESCRIPTOR + MAX_DESCRIPTORS).min(device_sectors as u64) {
+            fs.read_sector(sector, &mut descriptor)?;
+            if &descriptor[VD_STANDARD_ID..VD_STANDARD_ID + STANDARD_ID.len()] != STANDARD_ID {
+                break;
+            }
+            match descriptor[VD_TYPE] {
+                TYPE_PRIMARY if primary.is_none() => primary = Some(descriptor),
+                TYPE_SUPPLEMENTARY if fs.joliet.is_none() => {
+                    let escapes = &descriptor[VD_ESCAPES..];
+                    fs.joliet = JOLIET_ESCAPES.iter().position(|&escape| escapes.starts_with(escape)).map(|level| level as u8 + 1);
+                }
+                TYPE_TERMINATOR => break,
+                _ => {},
+            }
+        }
+        let primary = primary.ok_or(IsoError::NotIso9660)?;
+        let sectors = both_u32(&primary, VD_VOLUME_SPACE).ok_or(IsoError::NotIso9660)?;
+        match both_u16(&primary, VD_BLOCK_SIZE) {
+            Some(size) if size as usize == SECTOR_SIZE => {},
+            Some(_) => return Err(IsoError::BlockSize),
+            None => return Err(IsoError::NotIso9660),
+        }
+        if sectors > device_sectors {
+            return Err(IsoError::Truncated);
+        }
+        fs.sectors = sectors;
+        let volume_id = &primary[VD_VOLUME_ID..VD_VOLUME_ID + 32];
+        fs.label.push_lossy(&volume_id[..volume_id.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1)]);
+        fs.created = volume_date(&primary[VD_CREATED..VD_CREATED + 17]);
+
+        // The root as the descriptor has it, then as its `.` record does, with
+        // the SP entry that says whether the volume has Rock Ridge.
+        let root = fs.parse_record(&primary[VD_ROOT..VD_ROOT + ROOT_RECORD_LEN], 0, None)?;
+        if !root.is_dir() {
+            return Err(IsoError::Corrupt);
+        }
+        let mut raw = [0u8; u8::MAX as usize];
+        let len = fs.read_raw_record(root.ino(), &mut raw)?;
+        let system_use = raw.get(DR_NAME + 1..len).ok_or(IsoError::Corrupt)?;
+        if system_use.len() > SP_SKIP && system_use.starts_with(b"SP") && &system_use[SUSP_HEADER..SP_SKIP] == SP_CHECK {
+            fs.rock_ridge = Some(system_use[SP_SKIP]);
+        }
+        fs.root = fs.record(root.ino())?;
+        Ok(fs)
+    }
+
+    pub fn label(&self) -> &str {
+        self.label.as_str()
+    }
+
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    /// When the volume was made.
+    pub fn created(&self) -> Option<DateTime> {
+        self.created
+    }
+
+    pub fn rock_ridge(&self) -> bool {
+        self.rock_ridge.is_some()
+    }
+
+    /// The UCS-2 level of the Joliet tree, if the volume has one. It is not
+    /// read: names come from Rock Ridge or the primary tree.
+    pub fn joliet(&self) -> Option<u8> {
+        self.joliet
+    }
+
+    pub fn root(&self) -> Record {
+        self.root
+    }
+
+    /// Reads sector `sector` of the volume into `buffer`, which is one sector long.
+    fn read_sector(&self, sector: u64, buffer: &mut [u8]) -> Result<(), IsoError> {
+        if sector >= self.sectors as u64 {
+            return Err(IsoError::Corrupt);
+        }
+        self.device.read_blocks(sector * self.blocks_per_sector, buffer)?;
+        Ok(())
+    }
+
+    /// Copies the directory record at byte `position` of the volume into
+    /// `raw`, returning its length, 0 for the padding at the end of a sector.
+    fn read_raw_record(&self, position: u64, raw: &mut [u8; u8::MAX as usize]) -> Result<usize, IsoError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        self.read_sector(position / SECTOR_SIZE as u64, &mut sector)?;
+        let sector = &sector[(position % SECTOR_SIZE as u64) as usize..];
+        let len = record_len(sector)?;
+        raw[..len].copy_from_slice(&sector[..len]);
+        Ok(len)
+    }
+
+    /// Reads the record at byte `position` of the volume, with the extents
+    /// after the first one of a file in more than one.
+    fn record(&self, position: u64) -> Result<Record, IsoError> {
+        let mut raw = [0u8; u8::MAX as usize];
+        let len = match self.read_raw_record(position, &mut raw)? {
+            0 => return Err(IsoError::Corrupt),
+            len => len,
+        };
+        let mut record = self.parse_record(&raw[..len], position, None)?;
+        // The records of the other extents follow in the same directory, the
+        // last one without the flag.
+        let (mut at, mut flags) = (position + len as u64, record.flags);
+        while flags & FLAG_MULTI_EXTENT != 0 {
+            match self.read_raw_record(at, &mut raw)? {
+                0 => at = (at + 1).next_multiple_of(SECTOR_SIZE as u64),
+                len => {
+                    let section = self.parse_record(&raw[..len], at, None)?;
+                    record.size += section.size;
+                    (at, flags) = (at + len as u64, section.flags);
+                }
+            }
+        }
+        Ok(record)
+    }
+
+    /// Calls `visit` with each System Use entry of the directory record `raw`,
+    /// following its continuation areas. Entries without Rock Ridge are not
+    /// looked at.
+    fn system_use(&self, raw: &[u8], mut visit: impl FnMut(&[u8]) -> Result<(), IsoError>) -> Result<(), IsoError> {
+        let Some(skip) = self.rock_ridge else {
+            return Ok(());
+        };
+        let name_len = raw[DR_NAME_LEN] as usize;
+        let start = DR_NAME + name_len + (name_len % 2 == 0) as usize + skip as usize;
+        let mut area = [0u8; SECTOR_SIZE];
+        let mut entries = raw.get(start..).unwrap_or(&[]);
+        for _ in 0..=MAX_CONTINUATIONS {
+            let mut continuation = None;
+            // A record padded to an even length leaves a byte behind.
+            while entries.len() >= SUSP_HEADER {
+                let len = entries[2] as usize;
+                if len < SUSP_HEADER || len > entries.len() {
+                    return Err(IsoError::Corrupt);
+                }
+                let entry = &entries[..len];
+                match &entry[..2] {
+                    b"CE" if len >= CE_LENGTH + 8 => {
+                        let field = |at| both_u32(entry, at).ok_or(IsoError::Corrupt);
+                        continuation = Some((field(CE_BLOCK)?, field(CE_OFFSET)? as usize, field(CE_LENGTH)? as usize));
+                    }
+                    b"ST" => break,
+                    _ => visit(entry)?,
+                }
+                entries = &entries[len..];
+            }
+            let Some((sector, offset, len)) = continuation else {
+                return Ok(());
+            };
+            if offset + len > SECTOR_SIZE {
+                return Err(IsoError::Corrupt);
+            }
+            self.read_sector(sector as u64, &mut area)?;
+            entries = &area[offset..offset + len];
+        }
+        Err(IsoError::Corrupt)
+    }
+
+    /// Parses the directory record `raw`, which is at byte `position` of the
+    /// volume, and its name into `name`.
+    fn parse_record(&self, raw: &[u8], position: u64, mut name: Option<&mut NameBuf<MAX_NAME_BYTES>>) -> Result<Record, IsoError> {
+        let extent = both_u32(raw, DR_EXTENT).and_then(|extent| extent.checked_add(raw[DR_EXT_ATTR_LEN] as u32));
+        let mut record = Record {
+            position,
+            extent: extent.ok_or(IsoError::Corrupt)?,
+            size: both_u32(raw, DR_SIZE).ok_or(IsoError::Corrupt)? as u64,
+            flags: raw[DR_FLAGS],
+            recorded: record_date(&raw[DR_RECORDED..DR_RECORDED + 7]),
+            mode: None,
+            links: 1,
+            uid: 0,
+            gid: 0,
+            created: None,
+            modified: None,
+            accessed: None,
+            symlink: false,
+        };
+        let mut named = false;
+        self.system_use(raw, |entry| {
+            let field = |at| both_u32(entry, at).ok_or(IsoError::Corrupt);
+            match &entry[..2] {
+                b"PX" if entry.len() >= PX_LEN => {
+                    record.mode = Some(field(PX_MODE)?);
+                    record.links = field(PX_LINKS)?;
+                    record.uid = field(PX_UID)?;
+                    record.gid = field(PX_GID)?;
+                }
+                b"NM" if entry.len() > NM_FLAGS && entry[NM_FLAGS] & (NM_CURRENT | NM_PARENT) == 0 => {
+                    if let Some(name) = name.as_deref_mut() {
+                        name.push_lossy(&entry[NM_NAME..]);
+                    }
+                    named = true;
+                }
+                b"SL" => record.symlink = true,
+                b"TF" if entry.len() > TF_FLAGS => {
+                    let flags = entry[TF_FLAGS];
+                    let len = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+                    let mut stamps = entry[TF_STAMPS..].chunks_exact(len);
+                    for (flag, time) in [(TF_CREATION, &mut record.created), (TF_MODIFY, &mut record.modified), (TF_ACCESS, &mut record.accessed)] {
+                        if flags & flag != 0 {
+                            let stamp = stamps.next().ok_or(IsoError::Corrupt)?;
+                            *time = if len == 17 { volume_date(stamp) } else { record_date(stamp) };
+                        }
+                    }
+                }
+                _ => {},
+            }
+            Ok(())
+        })?;
+        if let Some(name) = name.filter(|_| !named) {
+            iso_name(&raw[DR_NAME..DR_NAME + raw[DR_NAME_LEN] as usize], name);
+        }
+        Ok(record)
+    }
+
+    /// Reads the data of `record` from `offset` on into `buffer`, returning
+    /// how many bytes there were, 0 at the end.
+    fn read_data(&self, record: &Record, offset: u64, buffer: &mut [u8]) -> Result<usize, IsoError> {
+        let Some(left) = record.size.checked_sub(offset).filter(|&left| left != 0) else {
+            return Ok(0);
+        };
+        let len = buffer.len().min(left.min(usize::MAX as u64) as usize);
+        let sector_size = SECTOR_SIZE as u64;
+        let mut bounce = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let in_sector = (position % sector_size) as usize;
+            let count = (SECTOR_SIZE - in_sector).min(len - done);
+            let sector = record.extent as u64 + position / sector_size;
+            let out = &mut buffer[done..done + count];
+            if count == SECTOR_SIZE {
+                self.read_sector(sector, out)?;
+            } else {
+                self.read_sector(sector, &mut bounce)?;
+                out.copy_from_slice(&bounce[in_sector..in_sector + count]);
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Reads the file `record` from `offset` on into `buffer`, returning how
+    /// many bytes there were, 0 at the end of the file.
+    pub fn read(&self, record: &Record, offset: u64, buffer: &mut [u8]) -> Result<usize, IsoError> {
+        match record.kind() {
+            NodeKind::File if record.is_multi_extent() => Err(IsoError::MultiExtent),
+            NodeKind::File => self.read_data(record, offset, buffer),
+            NodeKind::Directory => Err(IsoError::IsADirectory),
+            NodeKind::Symlink => Err(IsoError::NotFound),
+        }
+    }
+
+    /// Writes where the symbolic link `record` points into `target`,
+    /// returning the length.
+    pub fn read_link(&self, record: &Record, target: &mut [u8]) -> Result<usize, IsoError> {
+        if record.kind() != NodeKind::Symlink {
+            return Err(IsoError::NotALink);
+        }
+        let mut raw = [0u8; u8::MAX as usize];
+        let len = match self.read_raw_record(record.position, &mut raw)? {
+            0 => return Err(IsoError::Corrupt),
+            len => len,
+        };
+        // The components of every SL entry, a slash between two unless the
+        // first goes on in the second.
+        let (mut target_len, mut slash) = (0, false);
+        self.system_use(&raw[..len], |entry| {
+            if &entry[..2] != b"SL" {
+                return Ok(());
+            }
+            let mut components = entry.get(SL_COMPONENTS..).unwrap_or(&[]);
+            while let [flags, len, rest @ ..] = components {
+                let (flags, text) = (*flags, rest.get(..*len as usize).ok_or(IsoError::Corrupt)?);
+                components = &rest[text.len()..];
+                let fits = if flags & SL_ROOT != 0 {
+                    append(target, &mut target_len, b"/")
+                } else {
+                    let text: &[u8] = if flags & SL_CURRENT != 0 {
+                        b"."
+                    } else if flags & SL_PARENT != 0 {
+                        b".."
+                    } else {
+                        text
+                    };
+                    (!slash || append(target, &mut target_len, b"/")) && append(target, &mut target_len, text)
+                };
+                if !fits {
+                    return Err(IsoError::NameTooLong);
+                }
+                slash = flags & (SL_ROOT | SL_CONTINUE) == 0;
+            }
+            Ok(())
+        })?;
+        Ok(target_len)
+    }
+
+    /// Iterates over the entries of the directory `dir`, `.` and `..` included.
+    pub fn read_dir(&self, dir: &Record) -> Result<DirIter<'_>, IsoError> {
+        self.read_dir_at(dir, 0)
+    }
+
+    /// Iterates over the entries of `dir` from byte `position` on, which
+    /// [`DirIter::position`] returned.
+    pub fn read_dir_at(&self, dir: &Record, position: u64) -> Result<DirIter<'_>, IsoError> {
+        if !dir.is_dir() {
+            return Err(IsoError::NotADirectory);
+        }
+        Ok(DirIter { fs: self, dir: *dir, position, sector: [0; SECTOR_SIZE], loaded: None })
+    }
+
+    /// Returns the record named `name` in the directory `dir`, whatever the
+    /// case of the name without Rock Ridge.
+    pub fn lookup(&self, dir: &Record, name: &str) -> Result<Record, IsoError> {
+        for entry in self.read_dir(dir)? {
+            let entry = entry?;
+            let found = match self.rock_ridge {
+                Some(_) => entry.name() == name,
+                None => entry.name().eq_ignore_ascii_case(name),
+            };
+            if found {
+                return Ok(entry.record);
+            }
+        }
+        Err(IsoError::NotFound)
+    }
+}
+
+impl fmt::Debug for IsoFs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ISO 9660 {:?}: {} sectors{}", self.label(), self.sectors, if self.rock_ridge() { ", Rock Ridge" } else { "" })?;
+        if let Some(level) = self.joliet {
+            write!(f, ", Joliet level {} not read", level)?;
+        }
+        Ok(())
+    }
+}
+
+/// The entries of a directory, see [`IsoFs::read_dir`]. An error ends the
+/// iteration.
+pub struct DirIter<'a> {
+    fs: &'a IsoFs,
+    dir: Record,
+    /// The byte of the directory the next record starts at.
+    position: u64,
+    sector: [u8; SECTOR_SIZE],
+    /// The sector of the directory in `sector`.
+    loaded: Option<u64>,
+}
+
+impl DirIter<'_> {
+    /// Where the iteration is in the directory, to carry on from with
+    /// [`IsoFs::read_dir_at`].
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Reads the next record, named into `name` when there is one.
+    fn next_record(&mut self, name: Option<&mut NameBuf<MAX_NAME_BYTES>>) -> Result<Option<Record>, IsoError> {
+        let fs = self.fs;
+        let sector_size = SECTOR_SIZE as u64;
+        while self.position < self.dir.size {
+            let index = self.position / sector_size;
+            let sector = self.dir.extent as u64 + index;
+            if self.loaded != Some(index) {
+                self.loaded = None;
+                fs.read_sector(sector, &mut self.sector)?;
+                self.loaded = Some(index);
+            }
+            // Records never cross a sector, the rest of which is zeroes.
+            let offset = (self.position % sector_size) as usize;
+            let raw = &self.sector[offset..];
+            let len = record_len(raw)?;
+            if len == 0 {
+                self.position = (index + 1) * sector_size;
+                continue;
+            }
+            self.position += len as u64;
+            return fs.parse_record(&raw[..len], sector * sector_size + offset as u64, name).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, IsoError> {
+        loop {
+            let mut name = NameBuf::new();
+            let Some(mut record) = self.next_record(Some(&mut name))? else {
+                return Ok(None);
+            };
+            // The records of the other extents of a file follow its first
+            // one, the last without the flag.
+            let mut flags = record.flags;
+            while flags & FLAG_MULTI_EXTENT != 0 {
+                let section = self.next_record(None)?.ok_or(IsoError::Corrupt)?;
+                record.size += section.size;
+                flags = section.flags;
+            }
+            if record.flags & FLAG_ASSOCIATED == 0 {
+                return Ok(Some(DirEntry { name, record }));
+            }
+        }
+    }
+}
+
+impl Iterator for DirIter<'_> {
+    type Item = Result<DirEntry, IsoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            self.position = self.dir.size;
+        }
+        entry.transpose()
+    }
+}
+
+/// A node keeps the byte its record is at, the one of its `.` record for a
+/// directory, whose Rock Ridge entries are the ones of the directory itself.
+impl FileSystem for IsoFs {
+    fn root(&self) -> NodeRef {
+        NodeRef { kind: NodeKind::Directory, ino: self.root.ino(), data: 0 }
+    }
+
+    fn lookup(&self, dir: NodeRef, name: &str) -> Result<NodeRef, FsError> {
+        let record = IsoFs::lookup(self, &self.record(dir.ino)?, name)?;
+        Ok(NodeRef { kind: record.kind(), ino: record.ino(), data: 0 })
+    }
+
+    fn read_dir(&self, dir: NodeRef, cookie: u64) -> Result<Option<(vfs::DirEntry, u64)>, FsError> {
+        let mut entries = self.read_dir_at(&self.record(dir.ino)?, cookie)?;
+        let Some(entry) = entries.next().transpose()? else {
+            return Ok(None);
+        };
+        let node = NodeRef { kind: entry.record.kind(), ino: entry.record.ino(), data: 0 };
+        Ok(Some((vfs::DirEntry::new(entry.name(), node)?, entries.position())))
+    }
+
+    fn read_at(&self, node: NodeRef, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match node.kind {
+            NodeKind::Symlink => Ok(0),
+            _ => Ok(self.read(&self.record(node.ino)?, offset, buffer)?),
+        }
+    }
+
+    fn metadata(&self, node: NodeRef) -> Result<Metadata, FsError> {
+        let record = self.record(node.ino)?;
+        Ok(Metadata {
+            kind: record.kind(),
+            size: record.size,
+            mode: record.mode(),
+            inode: node.ino,
+            modified: record.modified(),
+            accessed: record.accessed,
+            created: record.created,
+        })
+    }
+
+    fn read_link(&self, link: NodeRef, target: &mut [u8]) -> Result<usize, FsError> {
+        Ok(IsoFs::read_link(self, &self.record(link.ino)?, target)?)
+    }
+}
+
+/// Mounts the volume on `ram0`, if it holds one, as the root filesystem.
+pub fn init() {
+    let Some(disk) = storage::device("ram0") else {
+        return;
+    };
+    match IsoFs::mount(disk) {
+        Ok(fs) => {
+            let root = ROOT.call_once(|| fs);
+            match vfs::mount("/", root) {
+                Ok(()) => println!("ram0: {:?}, mounted as the root", root),
+                Err(err) => println!("ram0: cannot mount the volume at /: {}", err),
+            }
+        }
+        Err(IsoError::NotIso9660) => {},
+        Err(err) => println!("ram0: {}", err),
+    }
+}
+
+#[cfg(test)]
+static mut FIXTURE: [u8; include_bytes!("../../fixtures/iso9660.iso").len()] = *include_bytes!("../../fixtures/iso9660.iso");
+
+/// The volume `scripts/mkfixtures.py` builds, on a RAM disk with the 2048
+/// byte blocks of a CD-ROM.
+#[cfg(test)]
+fn fixture() -> &'static IsoFs {
+    use crate::storage::ramdisk::RamDisk;
+    static DISK: Once<RamDisk> = Once::new();
+    static FS: Once<IsoFs> = Once::new();
+    FS.call_once(|| {
+        // Only `DISK` ever takes the buffer, and only once.
+        let disk = DISK.call_once(|| {
+            RamDisk::with_block_size(unsafe { &mut *core::ptr::addr_of_mut!(FIXTURE) }, SECTOR_SIZE, true).unwrap()
+        });
+        IsoFs::mount(disk).unwrap()
+    })
+}
+
+/// Copies the first `sectors` sectors of the fixture to `disk`, and
+/// `change` what it wants of them on the way.
+#[cfg(test)]
+fn copy_fixture(disk: &dyn BlockDevice, sectors: u64, mut change: impl FnMut(u64, &mut [u8])) {
+    let mut sector = [0u8; SECTOR_SIZE];
+    let blocks_per_sector = (SECTOR_SIZE / disk.block_size()) as u64;
+    for index in 0..sectors {
+        fixture().read_sector(index, &mut sector).unwrap();
+        change(index, &mut sector);
+        disk.write_blocks(index * blocks_per_sector, &sector).unwrap();
+    }
+}
+
+#[cfg(test)]
+fn read_all<'a>(fs: &IsoFs, path: &str, buffer: &'a mut [u8]) -> &'a [u8] {
+    let mut record = fs.root();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        record = fs.lookup(&record, name).unwrap();
+    }
+    let len = fs.read(&record, 0, buffer).unwrap();
+    assert_eq!(len as u64, record.size());
+    &buffer[..len]
+}
+
+#[test_case]
+fn iso9660_volume_mounts() {
+    let fs = fixture();
+    assert_eq!((fs.label(), fs.sectors()), ("KRABBOS", 102));
+    assert!(fs.rock_ridge());
+    assert_eq!(fs.joliet(), Some(3));
+    assert_eq!(fs.created(), Some(DateTime { year: 2024, month: 5, day: 17, hour: 13, minute: 45, second: 30 }));
+    let root = fs.root();
+    assert_eq!((root.kind(), root.mode(), root.links()), (NodeKind::Directory, 0o755, 6));
+
+    // Both halves of both-endian numbers, and dates east and west of UTC.
+    assert_eq!(both_u32(&[1, 2, 0, 0, 0, 0, 2, 1], 0), Some(0x201));
+    assert_eq!(both_u32(&[1, 2, 0, 0, 0, 0, 1, 2], 0), None);
+    let new_year_eve = Some(DateTime { year: 2023, month: 12, day: 31, hour: 23, minute: 30, second: 0 });
+    assert_eq!(record_date(&[124, 1, 1, 0, 30, 0, 4]), new_year_eve);
+    assert_eq!(record_date(&[123, 12, 31, 18, 30, 0, -20i8 as u8]), new_year_eve);
+    assert_eq!(record_date(&[0; 7]), None);
+    assert_eq!(volume_date(b"2024010100300000\x04"), new_year_eve);
+    assert_eq!(volume_date(b"0000000000000000\0"), None);
+}
+
+#[test_case]
+fn iso9660_reads_files() {
+    use super::fat::pattern;
+    let fs = fixture();
+    let mut buffer = [0u8; 4096];
+    assert_eq!(read_all(fs, "/hello.txt", &mut buffer), b"Hello from ISO 9660!\n");
+    assert_eq!(read_all(fs, "deep/a/b/c/file.txt", &mut buffer), b"four directories down\n");
+    assert_eq!(read_all(fs, "files/file-number-27.txt", &mut buffer), b"file 27\n");
+
+    // Across sectors, in odd chunks.
+    let big = fs.lookup(&fs.root(), "big.bin").unwrap();
+    let mut offset = 0;
+    loop {
+        let len = fs.read(&big, offset as u64, &mut buffer[..1000]).unwrap();
+        if len == 0 {
+            break;
+        }
+        assert!(buffer[..len].iter().enumerate().all(|(i, &byte)| byte == pattern(offset + i, 7)));
+        offset += len;
+    }
+    assert_eq!(offset, 20000);
+
+    // Listed whole, but not read.
+    let split = fs.lookup(&fs.root(), "split.bin").unwrap();
+    assert!(split.is_multi_extent());
+    assert_eq!(split.size(), SECTOR_SIZE as u64 + 100);
+    assert_eq!(fs.read(&split, 0, &mut buffer), Err(IsoError::MultiExtent));
+    let node = FileSystem::lookup(fs, FileSystem::root(fs), "split.bin").unwrap();
+    assert_eq!(FileSystem::metadata(fs, node).unwrap().size, SECTOR_SIZE as u64 + 100);
+
+    let docs = fs.lookup(&fs.root(), "docs").unwrap();
+    assert_eq!(fs.read(&docs, 0, &mut buffer), Err(IsoError::IsADirectory));
+    assert_eq!(fs.lookup(&docs, "missing").unwrap_err(), IsoError::NotFound);
+}
+
+#[test_case]
+fn iso9660_lists_rock_ridge_names() {
+    let fs = fixture();
+    let names = [".", "..", "abs", "big.bin", "deep", "docs", "etc", "files", "hello.txt", "loop", "motd", "README", "split.bin"];
+    let mut entries = fs.read_dir(&fs.root()).unwrap();
+    for name in names {
+        assert_eq!(entries.next().unwrap().unwrap().name(), name);
+    }
+    assert!(entries.next().is_none());
+
+    // Kept whole in a continuation area.
+    let docs = fs.lookup(&fs.root(), "docs").unwrap();
+    let entry = fs.read_dir(&docs).unwrap().nth(2).unwrap().unwrap();
+    assert_eq!(entry.name().len(), 188);
+    assert!(entry.name().starts_with("A file name much too long for ISO 9660, ") && entry.name().ends_with(" kept whole by Rock Ridge.txt"));
+    let mut buffer = [0u8; 16];
+    assert_eq!(fs.read(&fs.lookup(&docs, entry.name()).unwrap(), 0, &mut buffer), Ok(10));
+    assert_eq!(&buffer[..10], b"long name\n");
+
+    // Over three sectors, from where it stopped.
+    let files = fs.lookup(&fs.root(), "files").unwrap();
+    assert_eq!(files.size(), 3 * SECTOR_SIZE as u64);
+    let mut entries = fs.read_dir(&files).unwrap();
+    assert_eq!(entries.nth(2).unwrap().unwrap().name(), "file-number-00.txt");
+    assert_eq!(entries.nth(26).unwrap().unwrap().name(), "file-number-27.txt");
+    let mut rest = fs.read_dir_at(&files, entries.position()).unwrap();
+    assert_eq!(rest.next().unwrap().unwrap().name(), "file-number-28.txt");
+    assert_eq!(rest.count(), 11);
+
+    let hello = fs.lookup(&fs.root(), "hello.txt").unwrap();
+    assert!(matches!(fs.read_dir(&hello), Err(IsoError::NotADirectory)));
+    assert_eq!(fs.lookup(&fs.root(), "HELLO.TXT").unwrap_err(), IsoError::NotFound);
+}
+
+#[test_case]
+fn iso9660_follows_symbolic_links_through_the_vfs() {
+    use super::vfs::Vfs;
+    static TABLE: Vfs = Vfs::new();
+    let fs = fixture();
+    TABLE.mount("/", fs).unwrap();
+    let mut buffer = [0u8; 64];
+    for (path, contents) in [("/motd", "Welcome to krabbos.\n"), ("/abs", "Hello from ISO 9660!\n"), ("/deep/a/up", "Hello from ISO 9660!\n")] {
+        let file = TABLE.open(path).unwrap();
+        let len = file.read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], contents.as_bytes());
+    }
+    assert!(matches!(TABLE.open("/loop"), Err(FsError::TooManyLinks)));
+    assert!(matches!(TABLE.open("/hello.txt/x"), Err(FsError::NotADirectory)));
+    assert!(matches!(TABLE.open("/split.bin").unwrap().read_at(0, &mut buffer), Err(FsError::Corrupt)));
+
+    let root = fs.root();
+    let mut target = [0u8; 32];
+    for (link, expected) in [("motd", "etc/motd"), ("abs", "/hello.txt"), ("loop", "loop")] {
+        let len = IsoFs::read_link(fs, &fs.lookup(&root, link).unwrap(), &mut target).unwrap();
+        assert_eq!(&target[..len], expected.as_bytes());
+    }
+    let up = fs.lookup(&fs.lookup(&fs.lookup(&root, "deep").unwrap(), "a").unwrap(), "up").unwrap();
+    let len = IsoFs::read_link(fs, &up, &mut target).unwrap();
+    assert_eq!(&target[..len], b"../../hello.txt");
+    assert_eq!(IsoFs::read_link(fs, &up, &mut target[..10]), Err(IsoError::NameTooLong));
+    assert_eq!(IsoFs::read_link(fs, &root, &mut target), Err(IsoError::NotALink));
+}
+
+#[test_case]
+fn iso9660_records_carry_metadata() {
+    let fs = fixture();
+    let modified = Some(DateTime { year: 2024, month: 5, day: 17, hour: 13, minute: 45, second: 30 });
+    let accessed = Some(DateTime { year: 2024, month: 6, day: 1, hour: 8, minute: 0, second: 0 });
+    let hostname = fs.lookup(&fs.lookup(&fs.root(), "etc").unwrap(), "hostname").unwrap();
+    assert_eq!((hostname.kind(), hostname.size(), hostname.mode(), hostname.links()), (NodeKind::File, 8, 0o600, 1));
+    assert_eq!((hostname.uid(), hostname.gid()), (0, 0));
+    assert_eq!((hostname.recorded(), hostname.modified(), hostname.accessed()), (modified, modified, accessed));
+    let node = NodeRef { kind: NodeKind::File, ino: hostname.ino(), data: 0 };
+    let metadata = FileSystem::metadata(fs, node).unwrap();
+    assert_eq!((metadata.size, metadata.mode, metadata.modified, metadata.accessed), (8, 0o600, modified, accessed));
+    assert!(metadata.created.is_none());
+
+    // A directory is the same node through its parent and its own `.`.
+    let etc = FileSystem::lookup(fs, FileSystem::root(fs), "etc").unwrap();
+    let dot = FileSystem::read_dir(fs, etc, 0).unwrap().unwrap().0;
+    assert_eq!((dot.name(), dot.node()), (".", etc));
+    assert_eq!(FileSystem::metadata(fs, etc).unwrap().mode, 0o755);
+    let motd = FileSystem::lookup(fs, FileSystem::root(fs), "motd").unwrap();
+    assert_eq!(FileSystem::metadata(fs, motd).unwrap().kind, NodeKind::Symlink);
+}
+
+#[test_case]
+fn iso9660_falls_back_to_iso_names_without_rock_ridge() {
+    use crate::storage::MemBlockDevice;
+    // 512 byte blocks, and an SP entry that is not one.
+    static DISK: MemBlockDevice<{ 102 * SECTOR_SIZE }> = MemBlockDevice::new(false);
+    let root = fixture().root().extent as u64;
+    copy_fixture(&DISK, 102, |index, sector| {
+        if index == root {
+            sector[DR_NAME + 1] = b'X';
+        }
+    });
+    let fs = IsoFs::mount(&DISK).unwrap();
+    assert!(!fs.rock_ridge());
+    let names = [".", "..", "ABS", "BIG.BIN", "DEEP", "DOCS", "ETC", "FILES", "HELLO.TXT", "LOOP", "MOTD", "README", "SPLIT.BIN"];
+    let mut entries = fs.read_dir(&fs.root()).unwrap();
+    for name in names {
+        assert_eq!(entries.next().unwrap().unwrap().name(), name);
+    }
+    assert!(entries.next().is_none());
+
+    let mut buffer = [0u8; 64];
+    assert_eq!(read_all(&fs, "/hello.txt", &mut buffer), b"Hello from ISO 9660!\n");
+    assert_eq!(read_all(&fs, "/Files/FILE_027.txt", &mut buffer), b"file 27\n");
+    assert_eq!(read_all(&fs, "/docs/a_file_n.txt", &mut buffer), b"long name\n");
+    let motd = fs.lookup(&fs.root(), "motd").unwrap();
+    assert_eq!((motd.kind(), motd.size(), motd.mode()), (NodeKind::File, 0, 0o444));
+    assert_eq!(fs.root().mode(), 0o555);
+    let recorded = Some(DateTime { year: 2024, month: 5, day: 17, hour: 13, minute: 45, second: 30 });
+    assert_eq!((motd.modified(), motd.accessed()), (recorded, None));
+}
+
+#[test_case]
+fn iso9660_refuses_what_it_cannot_read() {
+    use crate::storage::MemBlockDevice;
+    static DISK: MemBlockDevice<{ 20 * SECTOR_SIZE }, SECTOR_SIZE> = MemBlockDevice::new(false);
+    static LARGE_BLOCKS: MemBlockDevice<{ 20 * SECTOR_SIZE }, 4096> = MemBlockDevice::new(true);
+    assert!(matches!(IsoFs::mount(&DISK), Err(IsoError::NotIso9660)));
+    assert!(matches!(IsoFs::mount(&LARGE_BLOCKS), Err(IsoError::BlockSize)));
+
+    // The descriptors, without the rest of the volume.
+    copy_fixture(&DISK, 20, |_, _| {});
+    assert!(matches!(IsoFs::mount(&DISK), Err(IsoError::Truncated)));
+    // Sectors of 512 bytes.
+    copy_fixture(&DISK, 20, |index, sector| {
+        if index == FIRST_DESCRIPTOR {
+            sector[VD_VOLUME_SPACE..VD_VOLUME_SPACE + 8].copy_from_slice(&[20, 0, 0, 0, 0, 0, 0, 20]);
+            sector[VD_BLOCK_SIZE..VD_BLOCK_SIZE + 4].copy_from_slice(&[0, 2, 2, 0]);
+        }
+    });
+    assert!(matches!(IsoFs::mount(&DISK), Err(IsoError::BlockSize)));
+    // A root whose halves disagree.
+    copy_fixture(&DISK, 20, |index, sector| {
+        if index == FIRST_DESCRIPTOR {
+            sector[VD_VOLUME_SPACE..VD_VOLUME_SPACE + 8].copy_from_slice(&[20, 0, 0, 0, 0, 0, 0, 20]);
+            sector[VD_ROOT + DR_EXTENT + 7] ^= 1;
+        }
+    });
+    assert!(matches!(IsoFs::mount(&DISK), Err(IsoError::Corrupt)));
+}
+
+#[test_case]
+fn iso9660_refuses_a_root_record_without_a_name() {
+    use crate::storage::MemBlockDevice;
+    static DISK: MemBlockDevice<{ 102 * SECTOR_SIZE }> = MemBlockDevice::new(false);
+    let root = fixture().root().extent as u64;
+    // The `.` record of the root, 33 bytes with an empty identifier, then none at all.
+    for (len, name_len) in [(DR_NAME as u8, 0), (0, 0)] {
+        copy_fixture(&DISK, 102, |index, sector| {
+            if index == root {
+                sector[DR_LEN] = len;
+                sector[DR_NAME_LEN] = name_len;
+            }
+        });
+        assert!(matches!(IsoFs::mount(&DISK), Err(IsoError::Corrupt)));
+    }
+}
@@ -0,0 +1,129 @@
+//! Filesystems, read from the block devices in [`crate::storage`].
+
+pub mod ext2;
+pub mod fat;
+pub mod iso9660;
+pub mod ramfs;
+pub mod ustar;
+pub mod vfs;
+
+use core::{fmt, str};
+
+/// A date and time as a filesystem stores it, without a time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The UTC date and time `seconds` after the Unix epoch.
+    pub fn from_unix(seconds: u64) -> Self {
+        let (days, time) = (seconds / 86_400, seconds % 86_400);
+        // Howard Hinnant's civil_from_days, over 400 year eras from 0000-03-01.
+        let days = days + 719_468;
+        let (era, day_of_era) = (days / 146_097, days % 146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = era * 400 + year_of_era + (month <= 2) as u64;
+        DateTime {
+            year: year.min(u16::MAX as u64) as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * month_index + 2) / 5 + 1) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// The seconds from the Unix epoch to this date and time taken as UTC,
+    /// 0 for the ones before it.
+    pub fn to_unix(&self) -> u64 {
+        // Howard Hinnant's days_from_civil, shifted an era later so that the
+        // year a March starts never goes below 0.
+        let (month, year) = (self.month as u64, self.year as u64 + 400 - (self.month <= 2) as u64);
+        let (era, year_of_era) = (year / 400, year % 400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + (self.day as u64).saturating_sub(1);
+        let day_of_era = 365 * year_of_era + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let Some(days) = (era * 146_097 + day_of_era).checked_sub(146_097 + 719_468) else {
+            return 0;
+        };
+        days * 86_400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// A name or path in UTF-8, in a buffer of `N` bytes.
+#[derive(Clone, Copy)]
+pub(crate) struct NameBuf<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> NameBuf<N> {
+    pub(crate) const fn new() -> Self {
+        NameBuf { bytes: [0; N], len: 0 }
+    }
+
+    /// Appends `c`, false if it does not fit.
+    pub(crate) fn push(&mut self, c: char) -> bool {
+        let mut bytes = [0; 4];
+        self.push_str(c.encode_utf8(&mut bytes))
+    }
+
+    /// Appends `s`, false if it does not fit.
+    pub(crate) fn push_str(&mut self, s: &str) -> bool {
+        let end = self.len + s.len();
+        let Some(room) = self.bytes.get_mut(self.len..end) else {
+            return false;
+        };
+        room.copy_from_slice(s.as_bytes());
+        self.len = end;
+        true
+    }
+
+    /// Appends `bytes` as UTF-8, with U+FFFD for what is not.
+    pub(crate) fn push_lossy(&mut self, bytes: &[u8]) {
+        for chunk in bytes.utf8_chunks() {
+            self.push_str(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                self.push(char::REPLACEMENT_CHARACTER);
+            }
+        }
+    }
+
+    /// Cuts the name down to its first `len` bytes, which must end on a char boundary.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        assert!(self.as_str().is_char_boundary(len));
+        self.len = self.len.min(len);
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+#[test_case]
+fn unix_time_to_date_time() {
+    let at = |year, month, day, hour, minute, second| DateTime { year, month, day, hour, minute, second };
+    assert_eq!(DateTime::from_unix(0), at(1970, 1, 1, 0, 0, 0));
+    assert_eq!(DateTime::from_unix(951_782_400), at(2000, 2, 29, 0, 0, 0));
+    assert_eq!(DateTime::from_unix(1_715_953_530), at(2024, 5, 17, 13, 45, 30));
+    assert_eq!(DateTime::from_unix(4_102_444_799), at(2099, 12, 31, 23, 59, 59));
+    for seconds in [0, 951_782_400, 1_715_953_530, 4_102_444_799] {
+        assert_eq!(DateTime::from_unix(seconds).to_unix(), seconds);
+    }
+    assert_eq!(at(1980, 1, 1, 0, 0, 0).to_unix(), 315_532_800);
+    assert_eq!(at(1969, 12, 31, 23, 59, 59).to_unix(), 0);
+}
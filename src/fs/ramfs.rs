@@ -0,0 +1,249 @@
+//! A filesystem in memory, the one the kernel mounts at `/tmp`.
+//!
+//! The root is the only directory, and its files take their data from a pool
+//! of [`BLOCK_SIZE`] byte blocks as they grow and give it back as they are
+//! truncated. A block is only taken once written to: the ones past a hole
+//! read as zeros. Nothing is ever removed, so a file keeps its slot, which is
+//! its inode and its cookie in the directory, until the kernel stops.
+
+use crate::{println, sync::Mutex};
+use super::{vfs::{self, DirEntry, FileSystem, FsError, Metadata, NodeKind, NodeRef}, NameBuf};
+
+/// Files in the filesystem.
+pub const MAX_FILES: usize = 32;
+/// The longest file name.
+pub const MAX_NAME: usize = 64;
+pub const BLOCK_SIZE: usize = 512;
+/// Blocks in the pool, shared by all the files.
+pub const BLOCKS: usize = 256;
+/// Blocks of one file, so it holds 16 KiB at the most.
+const FILE_BLOCKS: usize = 32;
+const MAX_FILE_SIZE: u64 = (FILE_BLOCKS * BLOCK_SIZE) as u64;
+
+static TMP: RamFs = RamFs::new();
+
+struct RamFile {
+    name: NameBuf<MAX_NAME>,
+    size: u64,
+    /// Indices into the pool, `None` for the blocks never written.
+    blocks: [Option<u16>; FILE_BLOCKS],
+}
+
+struct Pool {
+    files: [Option<RamFile>; MAX_FILES],
+    data: [[u8; BLOCK_SIZE]; BLOCKS],
+    used: [bool; BLOCKS],
+}
+
+pub struct RamFs {
+    pool: Mutex<Pool>,
+}
+
+impl RamFs {
+    pub const fn new() -> Self {
+        RamFs {
+            pool: Mutex::new("RAMFS", Pool {
+                files: [const { None }; MAX_FILES],
+                data: [[0; BLOCK_SIZE]; BLOCKS],
+                used: [false; BLOCKS],
+            }),
+        }
+    }
+
+    /// The blocks of the pool no file holds.
+    pub fn free_blocks(&self) -> usize {
+        self.pool.lock().used.iter().filter(|&&used| !used).count()
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        RamFs::new()
+    }
+}
+
+/// The slot of the file `node`.
+fn slot(node: NodeRef) -> Result<usize, FsError> {
+    match node.kind {
+        NodeKind::Directory => Err(FsError::IsADirectory),
+        _ => Ok(node.ino as usize - 1),
+    }
+}
+
+fn file_node(slot: usize) -> NodeRef {
+    NodeRef { kind: NodeKind::File, ino: slot as u64 + 1, data: 0 }
+}
+
+impl FileSystem for RamFs {
+    fn root(&self) -> NodeRef {
+        NodeRef { kind: NodeKind::Directory, ino: 0, data: 0 }
+    }
+
+    fn lookup(&self, _dir: NodeRef, name: &str) -> Result<NodeRef, FsError> {
+        let pool = self.pool.lock();
+        pool.files.iter().position(|file| file.as_ref().is_some_and(|file| file.name.as_str() == name))
+            .map(file_node)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self, _dir: NodeRef, cookie: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let pool = self.pool.lock();
+        let start = (cookie as usize).min(MAX_FILES);
+        let Some(slot) = pool.files[start..].iter().position(Option::is_some).map(|index| start + index) else {
+            return Ok(None);
+        };
+        let entry = DirEntry::new(pool.files[slot].as_ref().unwrap().name.as_str(), file_node(slot))?;
+        Ok(Some((entry, slot as u64 + 1)))
+    }
+
+    fn read_at(&self, node: NodeRef, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let pool = self.pool.lock();
+        let file = pool.files[slot(node)?].as_ref().unwrap();
+        let len = file.size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let mut done = 0;
+        while done < len {
+            let at = offset as usize + done;
+            let (index, start) = (at / BLOCK_SIZE, at % BLOCK_SIZE);
+            let count = (BLOCK_SIZE - start).min(len - done);
+            let dst = &mut buffer[done..done + count];
+            match file.blocks[index] {
+                Some(block) => dst.copy_from_slice(&pool.data[block as usize][start..start + count]),
+                None => dst.fill(0),
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, node: NodeRef, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        let mut pool = self.pool.lock();
+        let Pool { files, data, used } = &mut *pool;
+        let file = files[slot(node)?].as_mut().unwrap();
+        let len = MAX_FILE_SIZE.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let mut done = 0;
+        while done < len {
+            let at = offset as usize + done;
+            let (index, start) = (at / BLOCK_SIZE, at % BLOCK_SIZE);
+            let count = (BLOCK_SIZE - start).min(len - done);
+            let block = match file.blocks[index] {
+                Some(block) => block as usize,
+                None => {
+                    let Some(block) = used.iter().position(|&used| !used) else {
+                        break;
+                    };
+                    used[block] = true;
+                    data[block].fill(0);
+                    file.blocks[index] = Some(block as u16);
+                    block
+                }
+            };
+            data[block][start..start + count].copy_from_slice(&buffer[done..done + count]);
+            done += count;
+        }
+        if done == 0 && !buffer.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        file.size = file.size.max(offset + done as u64);
+        Ok(done)
+    }
+
+    fn create(&self, _dir: NodeRef, name: &str) -> Result<NodeRef, FsError> {
+        let mut file = RamFile { name: NameBuf::new(), size: 0, blocks: [None; FILE_BLOCKS] };
+        if !file.name.push_str(name) {
+            return Err(FsError::NameTooLong);
+        }
+        let mut pool = self.pool.lock();
+        if pool.files.iter().flatten().any(|file| file.name.as_str() == name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let slot = pool.files.iter().position(Option::is_none).ok_or(FsError::NoSpace)?;
+        pool.files[slot] = Some(file);
+        Ok(file_node(slot))
+    }
+
+    fn truncate(&self, node: NodeRef, size: u64) -> Result<(), FsError> {
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        let mut pool = self.pool.lock();
+        let Pool { files, data, used } = &mut *pool;
+        let file = files[slot(node)?].as_mut().unwrap();
+        let kept = (size as usize).div_ceil(BLOCK_SIZE);
+        for block in file.blocks[kept..].iter_mut().filter_map(Option::take) {
+            used[block as usize] = false;
+        }
+        // What a later write past the end leaves of the last block reads as zeros.
+        if let Some(block) = file.blocks.get(size as usize / BLOCK_SIZE).copied().flatten() {
+            data[block as usize][size as usize % BLOCK_SIZE..].fill(0);
+        }
+        file.size = size;
+        Ok(())
+    }
+
+    fn metadata(&self, node: NodeRef) -> Result<Metadata, FsError> {
+        let (size, mode) = match node.kind {
+            NodeKind::Directory => (0, 0o777),
+            _ => (self.pool.lock().files[slot(node)?].as_ref().unwrap().size, 0o644),
+        };
+        Ok(Metadata { kind: node.kind, size, mode, inode: node.ino, modified: None, accessed: None, created: None })
+    }
+}
+
+/// Mounts the ramfs at `/tmp`, if the root filesystem has a directory there.
+pub fn init() {
+    match vfs::mount("/tmp", &TMP) {
+        Ok(()) => println!("tmp: {} KiB ramfs mounted at /tmp", BLOCKS * BLOCK_SIZE / 1024),
+        // No root, or nowhere to mount it.
+        Err(FsError::NotMounted | FsError::NotFound) => {},
+        Err(err) => println!("tmp: cannot mount the ramfs at /tmp: {}", err),
+    }
+}
+
+/// The ramfs [`init`] mounts.
+pub fn tmp() -> &'static RamFs {
+    &TMP
+}
+
+#[test_case]
+fn ramfs_files_grow_and_shrink() {
+    static FS: RamFs = RamFs::new();
+    let root = FS.root();
+    let file = FS.create(root, "notes").unwrap();
+    assert_eq!(FS.create(root, "notes"), Err(FsError::AlreadyExists));
+    assert_eq!(FS.lookup(root, "notes"), Ok(file));
+    assert_eq!(FS.lookup(root, "other"), Err(FsError::NotFound));
+
+    // Across a block boundary, with a hole before it.
+    let mut buffer = [0xAAu8; 1200];
+    assert_eq!(FS.write_at(file, 1020, b"hello, ramfs"), Ok(12));
+    assert_eq!(FS.metadata(file).unwrap().size, 1032);
+    assert_eq!(FS.free_blocks(), BLOCKS - 2);
+    assert_eq!(FS.read_at(file, 0, &mut buffer), Ok(1032));
+    assert!(buffer[..1020].iter().all(|&byte| byte == 0));
+    assert_eq!(&buffer[1020..1032], b"hello, ramfs");
+    assert_eq!(FS.read_at(file, 1032, &mut buffer), Ok(0));
+
+    // Cut in the middle of the text, giving back a block, then grown back over zeros.
+    FS.truncate(file, 1022).unwrap();
+    assert_eq!(FS.free_blocks(), BLOCKS - 1);
+    FS.truncate(file, 1032).unwrap();
+    assert_eq!(FS.read_at(file, 1020, &mut buffer), Ok(12));
+    assert_eq!(&buffer[..12], b"he\0\0\0\0\0\0\0\0\0\0");
+    FS.truncate(file, 0).unwrap();
+    assert_eq!(FS.free_blocks(), BLOCKS);
+
+    // A file holds so much, and the writes that reach past it come up short.
+    assert_eq!(FS.write_at(file, MAX_FILE_SIZE - 4, b"too long"), Ok(4));
+    assert_eq!(FS.write_at(file, MAX_FILE_SIZE, b"x"), Err(FsError::NoSpace));
+    assert_eq!(FS.truncate(file, MAX_FILE_SIZE + 1), Err(FsError::NoSpace));
+
+    let other = FS.create(root, "other").unwrap();
+    let mut names = (0..).scan(0, |cookie, _| FS.read_dir(root, *cookie).unwrap().map(|(entry, next)| {
+        *cookie = next;
+        entry
+    }));
+    assert_eq!(names.next().map(|entry| entry.node()), Some(file));
+    assert_eq!(names.next().map(|entry| entry.node()), Some(other));
+    assert!(names.next().is_none());
+    assert_eq!(FS.create(root, core::str::from_utf8(&[b'x'; MAX_NAME + 1]).unwrap()), Err(FsError::NameTooLong));
+}
@@ -0,0 +1,915 @@
+//! USTAR archives, the format of the initrd.
+//!
+//! [`UstarFs::mount`] reads every header of the archive once and indexes the
+//! entries by a hash of their path. [`UstarFs::open`] then takes a binary
+//! search and one header read per path component, and [`UstarFs::read`] reads
+//! the data, which follows its header, straight from the device. A header that
+//! does not check out ends the archive with a warning; the entries before it
+//! stay usable.
+//!
+//! Regular files, directories and symbolic links are indexed. Hard links are
+//! taken for symbolic links to the absolute path of their target, and other
+//! entries, like devices or pax headers, are skipped. A directory without an
+//! entry of its own can be opened but is not listed in its parent.
+
+use core::fmt;
+use spin::Once;
+use crate::{println, storage::{self, BlockDevice, IoError}};
+use super::{vfs::{self, FileSystem, FsError, Metadata, NodeKind, NodeRef}, DateTime, NameBuf};
+
+/// Archives are made of 512 byte blocks, and so must the device be.
+pub const BLOCK_SIZE: usize = 512;
+/// The most entries indexed, an archive with more is cut short.
+pub const MAX_ENTRIES: usize = 1024;
+/// The most directories remembered, for the ones without an entry of their own.
+const MAX_DIRECTORIES: usize = 256;
+/// The longest path of an entry: the prefix, a slash and the name.
+pub const MAX_PATH: usize = 256;
+/// The longest link target.
+pub const MAX_LINK_TARGET: usize = 100;
+/// The longest path while symbolic links in it are replaced by their targets.
+const MAX_RESOLVED_PATH: usize = 2 * MAX_PATH;
+/// Symbolic links followed in one lookup before it is taken for a loop, the
+/// least POSIX allows for SYMLOOP_MAX.
+const MAX_SYMLINKS: usize = 8;
+
+/// Header fields.
+const NAME: usize = 0;
+const MODE: usize = 100;
+const SIZE: usize = 124;
+const MTIME: usize = 136;
+const CHECKSUM: usize = 148;
+const TYPEFLAG: usize = 156;
+const LINK_NAME: usize = 157;
+const MAGIC: usize = 257;
+const PREFIX: usize = 345;
+/// POSIX archives end the magic with a NUL and put the start of long paths
+/// in the prefix; GNU ones end it with a space and use the field otherwise.
+const MAGIC_USTAR: &[u8] = b"ustar";
+const MAGIC_POSIX: &[u8] = b"ustar\0";
+
+const TYPE_FILE: u8 = b'0';
+/// Regular files of archives older than POSIX.
+const TYPE_FILE_OLD: u8 = 0;
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIRECTORY: u8 = b'5';
+/// A regular file that some systems would store contiguously.
+const TYPE_CONTIGUOUS: u8 = b'7';
+
+/// The FNV-1a hash of no bytes, which is the hash of the root's path.
+const HASH_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+/// The inode of the nodes of directories only implied by paths, with their
+/// position in the list of directories in the low bits. Other nodes have the
+/// block after their header, and the root 0.
+const IMPLIED_INODE: u64 = 1 << 63;
+
+static ROOT: Once<UstarFs> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UstarError {
+    Io(IoError),
+    /// The device does not have [`BLOCK_SIZE`] byte blocks.
+    BlockSize,
+    /// The first block is not a USTAR header.
+    NotUstar,
+    /// A header changed since the archive was mounted.
+    Malformed,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NameTooLong,
+    /// Symbolic links lead on for more than [`MAX_SYMLINKS`] steps.
+    TooManyLinks,
+}
+
+impl fmt::Display for UstarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UstarError::Io(err) => write!(f, "{}", err),
+            UstarError::BlockSize => write!(f, "blocks are not {} bytes", BLOCK_SIZE),
+            UstarError::NotUstar => write!(f, "not a USTAR archive"),
+            UstarError::Malformed => write!(f, "malformed header"),
+            UstarError::NotFound => write!(f, "no such file or directory"),
+            UstarError::NotADirectory => write!(f, "not a directory"),
+            UstarError::IsADirectory => write!(f, "is a directory"),
+            UstarError::NameTooLong => write!(f, "path too long"),
+            UstarError::TooManyLinks => write!(f, "too many levels of symbolic links"),
+        }
+    }
+}
+
+impl From<IoError> for UstarError {
+    fn from(err: IoError) -> Self {
+        UstarError::Io(err)
+    }
+}
+
+impl From<UstarError> for FsError {
+    fn from(err: UstarError) -> Self {
+        match err {
+            UstarError::Io(err) => FsError::Io(err),
+            UstarError::NotFound => FsError::NotFound,
+            UstarError::NotADirectory => FsError::NotADirectory,
+            UstarError::IsADirectory => FsError::IsADirectory,
+            UstarError::NameTooLong => FsError::NameTooLong,
+            UstarError::TooManyLinks => FsError::TooManyLinks,
+            _ => FsError::Corrupt,
+        }
+    }
+}
+
+/// Why a header was not taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderError {
+    Checksum,
+    Magic,
+    /// A numeric field is neither octal nor base-256.
+    Number(&'static str),
+    /// A path is not UTF-8 or too long.
+    Name,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::Checksum => write!(f, "bad checksum"),
+            HeaderError::Magic => write!(f, "no USTAR magic"),
+            HeaderError::Number(field) => write!(f, "malformed {} field", field),
+            HeaderError::Name => write!(f, "malformed path"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// A file, directory or symbolic link of the archive.
+#[derive(Clone)]
+pub struct Entry {
+    /// The path from the root, without a leading or trailing slash.
+    path: NameBuf<MAX_PATH>,
+    kind: EntryKind,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    target: NameBuf<{ MAX_LINK_TARGET + 1 }>,
+    /// The block the data starts at.
+    data: u64,
+}
+
+impl Entry {
+    fn directory(path: &str) -> Self {
+        let mut entry = Entry {
+            path: NameBuf::new(),
+            kind: EntryKind::Directory,
+            mode: 0o755,
+            size: 0,
+            mtime: 0,
+            target: NameBuf::new(),
+            data: 0,
+        };
+        entry.path.push_str(path);
+        entry
+    }
+
+    /// The path from the root of the archive, `""` for the root itself.
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    /// The last component of the path.
+    pub fn name(&self) -> &str {
+        let path = self.path();
+        path.rsplit_once('/').map_or(path, |(_, name)| name)
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+
+    /// The permission bits.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The modification time in seconds since the Unix epoch.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn modified(&self) -> DateTime {
+        DateTime::from_unix(self.mtime)
+    }
+
+    /// Where a symbolic link points.
+    pub fn link_target(&self) -> Option<&str> {
+        (self.kind == EntryKind::Symlink).then(|| self.target.as_str())
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "/{} ({:?}, {:o}, {} bytes)", self.path(), self.kind, self.mode, self.size)
+    }
+}
+
+/// Parses the octal number in `field`, which may be padded with spaces in
+/// front and ends at a NUL or a space, or the big endian base-256 number GNU
+/// tar writes when octal does not fit.
+fn number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        // Negative numbers start with 0xFF and are of no use here.
+        if field[0] != 0x80 {
+            return None;
+        }
+        return field[1..].iter().try_fold(0u64, |value, &byte| value.checked_mul(256)?.checked_add(byte as u64));
+    }
+    let mut value = 0u64;
+    let mut ended = false;
+    for &byte in field.iter().skip_while(|&&byte| byte == b' ') {
+        match byte {
+            b'0'..=b'7' if !ended => value = value.checked_mul(8)?.checked_add((byte - b'0') as u64)?,
+            0 | b' ' => ended = true,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// The field at `at`, up to its first NUL.
+fn text(block: &[u8], at: usize, len: usize) -> Result<&str, HeaderError> {
+    let field = &block[at..at + len];
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(len);
+    core::str::from_utf8(&field[..len]).map_err(|_| HeaderError::Name)
+}
+
+/// Whether the checksum of the header `block` matches. It is the sum of the
+/// bytes with the checksum field taken for spaces, which some old tars
+/// computed over signed bytes.
+fn checksum_matches(block: &[u8]) -> bool {
+    let Some(stored) = number(&block[CHECKSUM..CHECKSUM + 8]) else {
+        return false;
+    };
+    let (mut unsigned, mut signed) = (0u64, 0i64);
+    for (i, &byte) in block.iter().enumerate() {
+        let byte = if (CHECKSUM..CHECKSUM + 8).contains(&i) { b' ' } else { byte };
+        unsigned += byte as u64;
+        signed += byte as i8 as i64;
+    }
+    stored == unsigned || stored as i64 == signed
+}
+
+/// Appends `path` to `out` component by component, dropping empty and `.`
+/// ones and taking `..` back out.
+fn push_path<const N: usize>(out: &mut NameBuf<N>, path: &str) -> Result<(), UstarError> {
+    for component in path.split('/') {
+        match component {
+            "" | "." => {},
+            ".." => {
+                let parent = out.as_str().rfind('/').unwrap_or(0);
+                out.truncate(parent);
+            }
+            _ => {
+                let fits = (out.as_str().is_empty() || out.push('/')) && out.push_str(component);
+                if !fits {
+                    return Err(UstarError::NameTooLong);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// FNV-1a, which the index is sorted by.
+fn path_hash(path: &str) -> u64 {
+    extend_hash(HASH_OFFSET, path)
+}
+
+/// The hash of a path made of the one `hash` is of followed by `more`.
+fn extend_hash(hash: u64, more: &str) -> u64 {
+    more.bytes().fold(hash, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+/// Parses the header `block` at block `lba`, `None` for an entry of a kind
+/// that is not indexed.
+fn parse_header(block: &[u8], lba: u64) -> Result<Option<Entry>, HeaderError> {
+    if !checksum_matches(block) {
+        return Err(HeaderError::Checksum);
+    }
+    if !block[MAGIC..].starts_with(MAGIC_USTAR) {
+        return Err(HeaderError::Magic);
+    }
+    let mode = number(&block[MODE..MODE + 8]).ok_or(HeaderError::Number("mode"))?;
+    let size = number(&block[SIZE..SIZE + 12]).ok_or(HeaderError::Number("size"))?;
+    let mtime = number(&block[MTIME..MTIME + 12]).ok_or(HeaderError::Number("mtime"))?;
+    let kind = match block[TYPEFLAG] {
+        TYPE_FILE | TYPE_FILE_OLD | TYPE_CONTIGUOUS => EntryKind::File,
+        TYPE_DIRECTORY => EntryKind::Directory,
+        TYPE_SYMLINK | TYPE_HARD_LINK => EntryKind::Symlink,
+        _ => return Ok(None),
+    };
+
+    let mut path = NameBuf::new();
+    if block[MAGIC..].starts_with(MAGIC_POSIX) {
+        push_path(&mut path, text(block, PREFIX, 155)?).map_err(|_| HeaderError::Name)?;
+    }
+    push_path(&mut path, text(block, NAME, 100)?).map_err(|_| HeaderError::Name)?;
+    let mut target = NameBuf::new();
+    if block[TYPEFLAG] == TYPE_HARD_LINK {
+        target.push('/');
+    }
+    target.push_str(text(block, LINK_NAME, MAX_LINK_TARGET)?);
+    let size = match kind {
+        EntryKind::File => size,
+        _ => 0,
+    };
+    Ok(Some(Entry { path, kind, mode: (mode & 0o7777) as u32, size, mtime, target, data: lba + 1 }))
+}
+
+/// An indexed entry: the hashes of its path and of the path of its parent,
+/// and the block of its header.
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    hash: u64,
+    parent: u64,
+    header: u64,
+}
+
+/// A mounted USTAR archive.
+pub struct UstarFs {
+    device: &'static dyn BlockDevice,
+    /// The entries in the order of the archive.
+    index: [IndexEntry; MAX_ENTRIES],
+    /// Positions in `index`, sorted by hash and then by position.
+    by_hash: [u16; MAX_ENTRIES],
+    len: usize,
+    /// The hashes of the directories the paths of the entries go through.
+    directories: [u64; MAX_DIRECTORIES],
+    directory_count: usize,
+}
+
+impl UstarFs {
+    /// Indexes the archive on `device`. An archive without entries is taken
+    /// for something else.
+    pub fn mount(device: &'static dyn BlockDevice) -> Result<Self, UstarError> {
+        if device.block_size() != BLOCK_SIZE {
+            return Err(UstarError::BlockSize);
+        }
+        let mut fs = UstarFs {
+            device,
+            index: [IndexEntry { hash: 0, parent: 0, header: 0 }; MAX_ENTRIES],
+            by_hash: [0; MAX_ENTRIES],
+            len: 0,
+            directories: [0; MAX_DIRECTORIES],
+            directory_count: 0,
+        };
+        let mut too_many_directories = false;
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut lba = 0;
+        while lba < device.num_blocks() {
+            device.read_blocks(lba, &mut block)?;
+            // The end of the archive.
+            if block.iter().all(|&byte| byte == 0) {
+                match lba {
+                    0 => return Err(UstarError::NotUstar),
+                    _ => break,
+                }
+            }
+            let header = match parse_header(&block, lba) {
+                Ok(header) => header,
+                Err(_) if lba == 0 => return Err(UstarError::NotUstar),
+                Err(err) => {
+                    println!("ustar: block {}: {}, ignoring the rest of the archive", lba, err);
+                    break;
+                }
+            };
+            let size = number(&block[SIZE..SIZE + 12]).unwrap_or(0);
+            let end = (lba + 1).checked_add(size.div_ceil(BLOCK_SIZE as u64));
+            let Some(end) = end.filter(|&end| end <= device.num_blocks()) else {
+                println!("ustar: block {}: data past the end of the device, ignoring the rest of the archive", lba);
+                break;
+            };
+            if let Some(entry) = header {
+                if fs.len == MAX_ENTRIES {
+                    println!("ustar: more than {} entries, ignoring the rest of the archive", MAX_ENTRIES);
+                    break;
+                }
+                let path = entry.path();
+                fs.index[fs.len] = IndexEntry { hash: path_hash(path), parent: path_hash(parent(path)), header: lba };
+                fs.by_hash[fs.len] = fs.len as u16;
+                fs.len += 1;
+                // Once a directory is known, so are the ones above it.
+                let mut dir = parent(path);
+                while !dir.is_empty() && !fs.directories[..fs.directory_count].contains(&path_hash(dir)) {
+                    if fs.directory_count == MAX_DIRECTORIES {
+                        too_many_directories = true;
+                        break;
+                    }
+                    fs.directories[fs.directory_count] = path_hash(dir);
+                    fs.directory_count += 1;
+                    dir = parent(dir);
+                }
+            }
+            lba = end;
+        }
+        if too_many_directories {
+            println!("ustar: more than {} directories, the rest are only found through their own entries", MAX_DIRECTORIES);
+        }
+        let index = &fs.index;
+        fs.by_hash[..fs.len].sort_unstable_by_key(|&position| (index[position as usize].hash, position));
+        Ok(fs)
+    }
+
+    /// How many entries were indexed.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn read_entry(&self, header: u64) -> Result<Entry, UstarError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        self.device.read_blocks(header, &mut block)?;
+        parse_header(&block, header).ok().flatten().ok_or(UstarError::Malformed)
+    }
+
+    /// Returns the last entry in the archive whose path hashes to `hash` and
+    /// that `matches`.
+    fn find_hash(&self, hash: u64, matches: impl Fn(&Entry) -> bool) -> Result<Option<Entry>, UstarError> {
+        let by_hash = &self.by_hash[..self.len];
+        let end = by_hash.partition_point(|&position| self.index[position as usize].hash <= hash);
+        for &position in by_hash[..end].iter().rev() {
+            let indexed = self.index[position as usize];
+            if indexed.hash != hash {
+                break;
+            }
+            let entry = self.read_entry(indexed.header)?;
+            if matches(&entry) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The position of the directory implied by paths whose path hashes to `hash`.
+    fn implied_directory(&self, hash: u64) -> Option<usize> {
+        self.directories[..self.directory_count].iter().position(|&directory| directory == hash)
+    }
+
+    /// Returns the entry at the normalized `path`, without following links.
+    /// The last entry of a path that is in the archive more than once wins.
+    fn find(&self, path: &str) -> Result<Option<Entry>, UstarError> {
+        if path.is_empty() {
+            return Ok(Some(Entry::directory("")));
+        }
+        let hash = path_hash(path);
+        if let Some(entry) = self.find_hash(hash, |entry| entry.path() == path)? {
+            return Ok(Some(entry));
+        }
+        // A directory only implied by the paths in it.
+        Ok(self.implied_directory(hash).map(|_| Entry::directory(path)))
+    }
+
+    /// Looks `path` up from the root, following the symbolic links on the
+    /// way and, with `follow_last`, the one it ends at.
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<Entry, UstarError> {
+        let mut pending = NameBuf::<MAX_RESOLVED_PATH>::new();
+        push_path(&mut pending, path)?;
+        let mut links = 0;
+        'restart: loop {
+            let current = pending;
+            let path = current.as_str();
+            let mut entry = Entry::directory("");
+            let ends = path.match_indices('/').map(|(end, _)| end).chain(Some(path.len()));
+            for end in ends.filter(|&end| end != 0) {
+                if !entry.is_dir() {
+                    return Err(UstarError::NotADirectory);
+                }
+                let (prefix, rest) = path.split_at(end);
+                entry = self.find(prefix)?.ok_or(UstarError::NotFound)?;
+                if entry.kind == EntryKind::Symlink && (!rest.is_empty() || follow_last) {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(UstarError::TooManyLinks);
+                    }
+                    // Relative targets start from the directory of the link.
+                    pending = NameBuf::new();
+                    let target = entry.target.as_str();
+                    if !target.starts_with('/') {
+                        push_path(&mut pending, parent(prefix))?;
+                    }
+                    push_path(&mut pending, target)?;
+                    push_path(&mut pending, rest)?;
+                    continue 'restart;
+                }
+            }
+            return Ok(entry);
+        }
+    }
+
+    /// Returns the entry at `path`, following symbolic links.
+    pub fn open(&self, path: &str) -> Result<Entry, UstarError> {
+        self.resolve(path, true)
+    }
+
+    /// Returns the entry at `path`, which is the link itself if it ends at a
+    /// symbolic link.
+    pub fn open_link(&self, path: &str) -> Result<Entry, UstarError> {
+        self.resolve(path, false)
+    }
+
+    /// Iterates over the entries in the directory `dir`, in the order of the archive.
+    pub fn read_dir<'a>(&'a self, dir: &Entry) -> Result<DirIter<'a>, UstarError> {
+        if !dir.is_dir() {
+            return Err(UstarError::NotADirectory);
+        }
+        Ok(DirIter { fs: self, dir: dir.path, hash: path_hash(dir.path()), next: 0 })
+    }
+
+    /// Reads the file `file` from `offset` on into `buffer`, returning how many
+    /// bytes there were, 0 at the end of the file.
+    pub fn read(&self, file: &Entry, offset: u64, buffer: &mut [u8]) -> Result<usize, UstarError> {
+        if file.is_dir() {
+            return Err(UstarError::IsADirectory);
+        }
+        self.read_data(file.data, file.size, offset, buffer)
+    }
+
+    /// Reads the `size` bytes from block `data` on, like [`UstarFs::read`].
+    fn read_data(&self, data: u64, size: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, UstarError> {
+        let Some(left) = size.checked_sub(offset).filter(|&left| left != 0) else {
+            return Ok(0);
+        };
+        let len = buffer.len().min(left as usize);
+        let mut bounce = [0u8; BLOCK_SIZE];
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let lba = data + position / BLOCK_SIZE as u64;
+            let in_block = (position % BLOCK_SIZE as u64) as usize;
+            let whole = (len - done) / BLOCK_SIZE;
+            if in_block == 0 && whole != 0 {
+                self.device.read_blocks(lba, &mut buffer[done..done + whole * BLOCK_SIZE])?;
+                done += whole * BLOCK_SIZE;
+            } else {
+                let count = (BLOCK_SIZE - in_block).min(len - done);
+                self.device.read_blocks(lba, &mut bounce)?;
+                buffer[done..done + count].copy_from_slice(&bounce[in_block..in_block + count]);
+                done += count;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl UstarFs {
+    /// A file node keeps the block its data starts at and its size.
+    /// Directories and links keep the hash of their path instead, which their
+    /// children are found by: a path component costs one header read, as with
+    /// [`UstarFs::open`].
+    fn node(entry: &Entry) -> NodeRef {
+        match entry.kind {
+            EntryKind::File => NodeRef { kind: NodeKind::File, ino: entry.data, data: entry.size },
+            EntryKind::Directory => NodeRef { kind: NodeKind::Directory, ino: entry.data, data: path_hash(entry.path()) },
+            EntryKind::Symlink => NodeRef { kind: NodeKind::Symlink, ino: entry.data, data: path_hash(entry.path()) },
+        }
+    }
+}
+
+impl FileSystem for UstarFs {
+    fn root(&self) -> NodeRef {
+        NodeRef { kind: NodeKind::Directory, ino: 0, data: HASH_OFFSET }
+    }
+
+    fn lookup(&self, dir: NodeRef, name: &str) -> Result<NodeRef, FsError> {
+        let hash = match dir.ino {
+            0 => path_hash(name),
+            _ => extend_hash(extend_hash(dir.data, "/"), name),
+        };
+        let found = self.find_hash(hash, |entry| entry.name() == name && path_hash(parent(entry.path())) == dir.data)?;
+        match (found, self.implied_directory(hash)) {
+            (Some(entry), _) => Ok(Self::node(&entry)),
+            (None, Some(position)) => {
+                Ok(NodeRef { kind: NodeKind::Directory, ino: IMPLIED_INODE | position as u64, data: hash })
+            }
+            (None, None) => Err(FsError::NotFound),
+        }
+    }
+
+    fn read_dir(&self, dir: NodeRef, cookie: u64) -> Result<Option<(vfs::DirEntry, u64)>, FsError> {
+        for position in cookie as usize..self.len {
+            let indexed = self.index[position];
+            if indexed.parent != dir.data {
+                continue;
+            }
+            let entry = self.read_entry(indexed.header)?;
+            if !entry.path().is_empty() {
+                return Ok(Some((vfs::DirEntry::new(entry.name(), Self::node(&entry))?, position as u64 + 1)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_at(&self, node: NodeRef, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        match node.kind {
+            NodeKind::File => Ok(self.read_data(node.ino, node.data, offset, buffer)?),
+            NodeKind::Directory => Err(FsError::IsADirectory),
+            NodeKind::Symlink => Ok(0),
+        }
+    }
+
+    fn metadata(&self, node: NodeRef) -> Result<Metadata, FsError> {
+        if node.ino == 0 || node.ino & IMPLIED_INODE != 0 {
+            let entry = Entry::directory("");
+            return Ok(Metadata {
+                kind: NodeKind::Directory,
+                size: 0,
+                mode: entry.mode,
+                inode: node.ino,
+                modified: None,
+                accessed: None,
+                created: None,
+            });
+        }
+        let entry = self.read_entry(node.ino - 1)?;
+        Ok(Metadata {
+            kind: node.kind,
+            size: entry.size,
+            mode: entry.mode,
+            inode: node.ino,
+            modified: Some(entry.modified()),
+            accessed: None,
+            created: None,
+        })
+    }
+
+    fn read_link(&self, link: NodeRef, target: &mut [u8]) -> Result<usize, FsError> {
+        if link.kind != NodeKind::Symlink {
+            return Err(FsError::NotALink);
+        }
+        let entry = self.read_entry(link.ino - 1)?;
+        let link_target = entry.target.as_str().as_bytes();
+        let target = target.get_mut(..link_target.len()).ok_or(FsError::NameTooLong)?;
+        target.copy_from_slice(link_target);
+        Ok(link_target.len())
+    }
+}
+
+/// The entries of a directory, see [`UstarFs::read_dir`]. An error ends the
+/// iteration.
+pub struct DirIter<'a> {
+    fs: &'a UstarFs,
+    dir: NameBuf<MAX_PATH>,
+    hash: u64,
+    /// The next position in the index.
+    next: usize,
+}
+
+impl Iterator for DirIter<'_> {
+    type Item = Result<Entry, UstarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.fs.len {
+            let indexed = self.fs.index[self.next];
+            self.next += 1;
+            if indexed.parent != self.hash {
+                continue;
+            }
+            match self.fs.read_entry(indexed.header) {
+                Ok(entry) if parent(entry.path()) != self.dir.as_str() || entry.path().is_empty() => {},
+                Ok(entry) => return Some(Ok(entry)),
+                Err(err) => {
+                    self.next = self.fs.len;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Mounts the archive on `ram0`, if it holds one, as the root filesystem.
+pub fn init() {
+    let Some(disk) = storage::device("ram0") else {
+        return;
+    };
+    match UstarFs::mount(disk) {
+        Ok(fs) => {
+            let root = ROOT.call_once(|| fs);
+            match vfs::mount("/", root) {
+                Ok(()) => println!("ram0: USTAR archive with {} entries, mounted as the root", root.len()),
+                Err(err) => println!("ram0: cannot mount the archive at /: {}", err),
+            }
+        }
+        // A CD image has 2048 byte blocks, and no archive.
+        Err(UstarError::NotUstar | UstarError::BlockSize) => {},
+        Err(err) => println!("ram0: {}", err),
+    }
+}
+
+/// The archive [`init`] mounted.
+pub fn root() -> Option<&'static UstarFs> {
+    ROOT.get()
+}
+
+#[cfg(test)]
+static mut FIXTURE: [u8; include_bytes!("../../fixtures/initrd.tar").len()] = *include_bytes!("../../fixtures/initrd.tar");
+
+/// A block device that counts the reads of the one under it.
+#[cfg(test)]
+struct CountingDisk {
+    disk: Once<storage::ramdisk::RamDisk>,
+    reads: core::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl BlockDevice for CountingDisk {
+    fn block_size(&self) -> usize {
+        self.disk.get().unwrap().block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.disk.get().unwrap().num_blocks()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        self.reads.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+        self.disk.get().unwrap().read_blocks(lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError> {
+        self.disk.get().unwrap().write_blocks(lba, buffer)
+    }
+}
+
+#[cfg(test)]
+static DISK: CountingDisk = CountingDisk { disk: Once::new(), reads: core::sync::atomic::AtomicUsize::new(0) };
+
+/// The archive `scripts/mkfixtures.py` builds, mounted from a RAM disk.
+#[cfg(test)]
+pub(crate) fn fixture() -> &'static UstarFs {
+    use crate::storage::ramdisk::RamDisk;
+    static FS: Once<UstarFs> = Once::new();
+    FS.call_once(|| {
+        // Only `DISK` ever takes the buffer, and only once.
+        DISK.disk.call_once(|| RamDisk::new(unsafe { &mut *core::ptr::addr_of_mut!(FIXTURE) }, true).unwrap());
+        UstarFs::mount(&DISK).unwrap()
+    })
+}
+
+#[cfg(test)]
+fn read_all<'a>(fs: &UstarFs, path: &str, buffer: &'a mut [u8]) -> &'a [u8] {
+    let file = fs.open(path).unwrap();
+    let len = fs.read(&file, 0, buffer).unwrap();
+    assert_eq!(len as u64, file.size());
+    &buffer[..len]
+}
+
+#[test_case]
+fn ustar_reads_files_and_metadata() {
+    let fs = fixture();
+    // Seven entries, a long path in its directory, 300 files in theirs, two links and `tmp`.
+    assert_eq!(fs.len(), 7 + 2 + 1 + 300 + 2 + 1);
+    let mut buffer = [0u8; 4096];
+    assert_eq!(read_all(fs, "/hello.txt", &mut buffer), b"Hello from the initrd!\n");
+    let long_dir = "deep/a-directory-with-a-rather-long-name/a-directory-with-a-rather-long-name/a-directory-with-a-rather-long-name";
+    let long_path = crate::pci::ids::StackString::format(format_args!("{}/and-a-file-at-the-end-of-it.txt", long_dir));
+    assert_eq!(read_all(fs, long_path.as_str(), &mut buffer), b"long path\n");
+
+    // Over six blocks, read whole and from the middle of a block past the end.
+    let big = read_all(fs, "big.bin", &mut buffer);
+    assert!(big.iter().enumerate().all(|(i, &byte)| byte == (i * 11 + i / 251) as u8));
+    let file = fs.open("big.bin").unwrap();
+    assert_eq!(fs.read(&file, 2900, &mut buffer[..500]), Ok(100));
+    assert_eq!(buffer[99], (2999 * 11 + 2999 / 251) as u8);
+    assert_eq!(fs.read(&file, 3000, &mut buffer), Ok(0));
+
+    let hostname = fs.open("etc/hostname").unwrap();
+    assert_eq!((hostname.name(), hostname.kind(), hostname.mode()), ("hostname", EntryKind::File, 0o600));
+    assert_eq!(hostname.modified(), DateTime { year: 2024, month: 5, day: 17, hour: 13, minute: 45, second: 30 });
+    let etc = fs.open("./etc/").unwrap();
+    assert_eq!((etc.kind(), etc.mode()), (EntryKind::Directory, 0o755));
+    assert!(matches!(fs.read(&etc, 0, &mut buffer), Err(UstarError::IsADirectory)));
+    assert!(matches!(fs.open("etc/passwd"), Err(UstarError::NotFound)));
+    assert!(matches!(fs.open("hello.txt/x"), Err(UstarError::NotADirectory)));
+}
+
+#[test_case]
+fn ustar_follows_symlinks_and_lists_directories() {
+    let fs = fixture();
+    let mut buffer = [0u8; 64];
+    assert_eq!(read_all(fs, "motd", &mut buffer), b"Welcome to krabbos.\n");
+    assert_eq!(read_all(fs, "etc/greeting", &mut buffer), b"Hello from the initrd!\n");
+    assert_eq!(read_all(fs, "data/123.txt", &mut buffer), b"file 123\n");
+    assert_eq!(read_all(fs, "files/../etc/../hello.txt", &mut buffer), b"Hello from the initrd!\n");
+    assert_eq!(fs.open_link("motd").unwrap().link_target(), Some("etc/motd"));
+    assert!(matches!(fs.open("loop"), Err(UstarError::TooManyLinks)));
+    // Only implied by the long path.
+    assert!(fs.open("deep").unwrap().is_dir());
+
+    let mut names = fs.read_dir(&fs.open("etc").unwrap()).unwrap().map(|entry| entry.unwrap());
+    for name in ["motd", "hostname", "greeting"] {
+        assert_eq!(names.next().unwrap().name(), name);
+    }
+    assert!(names.next().is_none());
+    assert_eq!(fs.read_dir(&fs.open("data").unwrap()).unwrap().count(), 300);
+    let root = fs.read_dir(&fs.open("/").unwrap()).unwrap().filter(|entry| entry.as_ref().unwrap().path() == "files");
+    assert_eq!(root.count(), 1);
+}
+
+#[test_case]
+fn ustar_lookups_read_one_header_per_component() {
+    let fs = fixture();
+    let mut buffer = [0u8; 16];
+    let reads = DISK.reads.load(core::sync::atomic::Ordering::Relaxed);
+    for i in 0..300 {
+        let path = crate::pci::ids::StackString::format(format_args!("files/{:03}.txt", i));
+        let content = crate::pci::ids::StackString::format(format_args!("file {}\n", i));
+        assert_eq!(read_all(fs, path.as_str(), &mut buffer), content.as_str().as_bytes());
+    }
+    // `files`, `files/NNN.txt` and the data block.
+    assert_eq!(DISK.reads.load(core::sync::atomic::Ordering::Relaxed) - reads, 300 * 3);
+}
+
+#[cfg(feature = "ramdisk")]
+#[test_case]
+fn initrd_is_the_root() {
+    let root = root().expect("the initrd is not mounted");
+    assert_eq!(root.len(), fixture().len());
+    assert!(root.open("etc/motd").is_ok());
+}
+
+/// A header of a regular file named `name` with `size` as its size field.
+#[cfg(test)]
+fn test_header(name: &str, size: &[u8; 12], signed_checksum: bool) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[NAME..name.len()].copy_from_slice(name.as_bytes());
+    block[MODE..MODE + 8].copy_from_slice(b"0000644\0");
+    block[SIZE..SIZE + 12].copy_from_slice(size);
+    block[MTIME..MTIME + 12].copy_from_slice(b"00000000000\0");
+    block[TYPEFLAG] = TYPE_FILE;
+    block[MAGIC..MAGIC + 8].copy_from_slice(b"ustar\x0000");
+    block[CHECKSUM..CHECKSUM + 8].fill(b' ');
+    let mut sum = match signed_checksum {
+        true => block.iter().map(|&byte| byte as i8 as i64).sum::<i64>() as u64,
+        false => block.iter().map(|&byte| byte as u64).sum(),
+    };
+    for digit in block[CHECKSUM..CHECKSUM + 6].iter_mut().rev() {
+        *digit = b'0' + (sum & 7) as u8;
+        sum >>= 3;
+    }
+    block[CHECKSUM + 6] = 0;
+    block
+}
+
+#[test_case]
+fn malformed_headers_end_the_archive() {
+    use crate::storage::MemBlockDevice;
+    static DISK: MemBlockDevice<{ 8 * BLOCK_SIZE }> = MemBlockDevice::new(false);
+    assert!(matches!(UstarFs::mount(&DISK), Err(UstarError::NotUstar)));
+
+    let mut data = [0u8; BLOCK_SIZE];
+    data[..5].copy_from_slice(b"hello");
+    DISK.write_blocks(0, &test_header("good.txt", b"00000000005\0", false)).unwrap();
+    DISK.write_blocks(1, &data).unwrap();
+    // Old tars summed signed bytes, which differs for bytes over 0x7F.
+    DISK.write_blocks(2, &test_header("café.txt", b"00000000005\0", true)).unwrap();
+    DISK.write_blocks(3, &data).unwrap();
+    DISK.write_blocks(4, &test_header("bad.txt", b"0000000x005\0", false)).unwrap();
+    DISK.write_blocks(5, &data).unwrap();
+    let fs = UstarFs::mount(&DISK).unwrap();
+    assert_eq!(fs.len(), 2);
+    let mut buffer = [0u8; 8];
+    assert_eq!(read_all(&fs, "café.txt", &mut buffer), b"hello");
+    assert!(matches!(fs.open("bad.txt"), Err(UstarError::NotFound)));
+
+    // Data running past the end of the device, then a checksum that is off.
+    DISK.write_blocks(4, &test_header("cut.txt", b"00000010000\0", false)).unwrap();
+    assert_eq!(UstarFs::mount(&DISK).unwrap().len(), 2);
+    let mut header = test_header("sum.txt", b"00000000005\0", false);
+    header[CHECKSUM + 5] ^= 1;
+    DISK.write_blocks(4, &header).unwrap();
+    assert_eq!(UstarFs::mount(&DISK).unwrap().len(), 2);
+    assert_eq!(number(b"\x80\0\0\0\0\0\0\0\0\x01\x00\x00"), Some(0x10000));
+    assert_eq!(number(b"  755 \0"), Some(0o755));
+    assert_eq!(number(b"\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF"), None);
+}
@@ -0,0 +1,628 @@
+//! The virtual filesystem: one tree of absolute paths over the mounted
+//! filesystems.
+//!
+//! A [`FileSystem`] hands out [`NodeRef`]s, small handles to its files and
+//! directories, and answers questions about them. [`Vfs::mount`] attaches one
+//! at a path, and a path is looked up in the filesystem mounted at its longest
+//! prefix. `.` and `..` are taken out of a path before it is looked up, so `..`
+//! goes back up through a mount point but never above the root, and symbolic
+//! links are followed from the root again with their target in place.
+//!
+//! A filesystem with open files or with filesystems mounted under it cannot be
+//! unmounted: [`Vfs::unmount`] fails with [`FsError::Busy`] until they are
+//! closed.
+//!
+//! Filesystems are read-only unless they implement [`FileSystem::write_at`],
+//! [`FileSystem::create`] and [`FileSystem::truncate`], like [`super::ramfs`].
+
+use core::{fmt, ptr};
+use crate::{storage::IoError, sync::Mutex};
+use super::{DateTime, NameBuf};
+
+/// The most filesystems mounted at once.
+pub const MAX_MOUNTS: usize = 16;
+/// The longest path, once normalized.
+pub const MAX_PATH: usize = 512;
+/// The longest name in a directory, which FAT long names can reach in UTF-8.
+pub const MAX_NAME: usize = 255 * 3;
+/// The longest path of a mount point.
+const MAX_MOUNT_PATH: usize = 64;
+/// Symbolic links followed in one lookup before it is taken for a loop.
+pub const MAX_SYMLINKS: usize = 8;
+
+static VFS: Vfs = Vfs::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    Io(IoError),
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    NotALink,
+    /// Symbolic links lead on for more than [`MAX_SYMLINKS`] steps.
+    TooManyLinks,
+    /// The path is not absolute, or goes above the root.
+    InvalidPath,
+    NameTooLong,
+    ReadOnly,
+    /// There is a file at the path already.
+    AlreadyExists,
+    /// The filesystem ran out of room for data or files.
+    NoSpace,
+    /// The filesystem does not add up, like a broken cluster chain.
+    Corrupt,
+    /// Files are open on the filesystem, or others are mounted under it.
+    Busy,
+    NotMounted,
+    AlreadyMounted,
+    /// All [`MAX_MOUNTS`] mount points are taken.
+    TooManyMounts,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::Io(err) => write!(f, "{}", err),
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::NotADirectory => write!(f, "not a directory"),
+            FsError::IsADirectory => write!(f, "is a directory"),
+            FsError::NotALink => write!(f, "not a symbolic link"),
+            FsError::TooManyLinks => write!(f, "too many levels of symbolic links"),
+            FsError::InvalidPath => write!(f, "invalid path"),
+            FsError::NameTooLong => write!(f, "file name too long"),
+            FsError::ReadOnly => write!(f, "read-only filesystem"),
+            FsError::AlreadyExists => write!(f, "file exists"),
+            FsError::NoSpace => write!(f, "no space left on device"),
+            FsError::Corrupt => write!(f, "filesystem corrupt"),
+            FsError::Busy => write!(f, "filesystem busy"),
+            FsError::NotMounted => write!(f, "not mounted"),
+            FsError::AlreadyMounted => write!(f, "already mounted"),
+            FsError::TooManyMounts => write!(f, "too many mounted filesystems"),
+        }
+    }
+}
+
+impl From<IoError> for FsError {
+    fn from(err: IoError) -> Self {
+        FsError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// A file, directory or symbolic link of a filesystem. What `ino` and `data`
+/// hold is up to the filesystem, which picks what it needs to find the node
+/// again without reading it: a FAT file keeps its cluster and size there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeRef {
+    pub kind: NodeKind,
+    pub ino: u64,
+    pub data: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: NodeKind,
+    pub size: u64,
+    /// The permission bits, like 0o644.
+    pub mode: u32,
+    /// A number no other node of the filesystem has.
+    pub inode: u64,
+    /// The timestamps the filesystem keeps.
+    pub modified: Option<DateTime>,
+    pub accessed: Option<DateTime>,
+    pub created: Option<DateTime>,
+}
+
+/// An entry of a directory.
+#[derive(Clone)]
+pub struct DirEntry {
+    name: NameBuf<MAX_NAME>,
+    node: NodeRef,
+}
+
+impl DirEntry {
+    pub fn new(name: &str, node: NodeRef) -> Result<Self, FsError> {
+        let mut entry = DirEntry { name: NameBuf::new(), node };
+        match entry.name.push_str(name) {
+            true => Ok(entry),
+            false => Err(FsError::NameTooLong),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        self.node.kind
+    }
+
+    pub fn node(&self) -> NodeRef {
+        self.node
+    }
+}
+
+impl fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:?})", self.name(), self.node.kind)
+    }
+}
+
+/// A filesystem as the VFS sees it. Nodes passed in are ones the filesystem
+/// handed out.
+pub trait FileSystem: Sync {
+    fn root(&self) -> NodeRef;
+
+    /// Returns the node named `name` in the directory `dir`. `name` is never
+    /// `.` or `..`.
+    fn lookup(&self, dir: NodeRef, name: &str) -> Result<NodeRef, FsError>;
+
+    /// Returns the entry of `dir` at `cookie`, 0 for the first one, with the
+    /// cookie of the entry after it, or `None` past the last one.
+    fn read_dir(&self, dir: NodeRef, cookie: u64) -> Result<Option<(DirEntry, u64)>, FsError>;
+
+    /// Reads from `offset` on into `buffer`, returning how many bytes there
+    /// were, 0 at the end of the file.
+    fn read_at(&self, node: NodeRef, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes `buffer` at `offset`, growing the file as needed, and returns
+    /// how many bytes went, fewer if the filesystem filled up.
+    fn write_at(&self, _node: NodeRef, _offset: u64, _buffer: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Adds an empty file named `name` to the directory `dir`, which has
+    /// nothing by that name, and returns it.
+    fn create(&self, _dir: NodeRef, _name: &str) -> Result<NodeRef, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Cuts the file `node` down to `size` bytes, or extends it with zeros.
+    fn truncate(&self, _node: NodeRef, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn metadata(&self, node: NodeRef) -> Result<Metadata, FsError>;
+
+    /// Writes where the symbolic link `link` points into `target`, returning
+    /// the length.
+    fn read_link(&self, _link: NodeRef, _target: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::NotALink)
+    }
+}
+
+type PathBuf = NameBuf<MAX_PATH>;
+
+/// Appends the components of `path` to the normalized absolute path `out`,
+/// where the root is `""`, dropping `.` and taking `..` back out.
+fn push_path<const N: usize>(out: &mut NameBuf<N>, path: &str) -> Result<(), FsError> {
+    for component in path.split('/') {
+        match component {
+            "" | "." => {},
+            ".." => {
+                let parent = out.as_str().rfind('/').ok_or(FsError::InvalidPath)?;
+                out.truncate(parent);
+            }
+            _ => {
+                if !(out.push('/') && out.push_str(component)) {
+                    return Err(FsError::NameTooLong);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Normalizes the absolute `path`.
+fn normalize<const N: usize>(path: &str) -> Result<NameBuf<N>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut out = NameBuf::new();
+    push_path(&mut out, path)?;
+    Ok(out)
+}
+
+#[derive(Clone, Copy)]
+struct Mount {
+    /// Normalized, `""` for the root.
+    path: NameBuf<MAX_MOUNT_PATH>,
+    fs: &'static dyn FileSystem,
+    /// Files and directories open on the filesystem.
+    open: usize,
+}
+
+impl Mount {
+    /// Whether the normalized `path` is at or under the mount point.
+    fn covers(&self, path: &str) -> bool {
+        let mount = self.path.as_str();
+        path.strip_prefix(mount).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Whether the mount point is under the normalized `path`.
+    fn is_under(&self, path: &str) -> bool {
+        self.path.as_str().strip_prefix(path).is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+/// A mount table and the paths through it. The kernel has one, see [`mount`].
+pub struct Vfs {
+    mounts: Mutex<[Option<Mount>; MAX_MOUNTS]>,
+}
+
+/// Where a path led: the mount it is on and the node.
+#[derive(Clone, Copy)]
+struct Resolved {
+    slot: usize,
+    fs: &'static dyn FileSystem,
+    node: NodeRef,
+}
+
+impl Vfs {
+    pub const fn new() -> Self {
+        Vfs { mounts: Mutex::new("VFS_MOUNTS", [None; MAX_MOUNTS]) }
+    }
+
+    /// Mounts `fs` at `path`, which must be the root or an existing directory.
+    pub fn mount(&self, path: &str, fs: &'static dyn FileSystem) -> Result<(), FsError> {
+        let path: NameBuf<MAX_MOUNT_PATH> = normalize(path)?;
+        if !path.as_str().is_empty() && self.resolve(path.as_str(), true)?.node.kind != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let mut mounts = self.mounts.lock();
+        if mounts.iter().flatten().any(|mount| mount.path.as_str() == path.as_str()) {
+            return Err(FsError::AlreadyMounted);
+        }
+        let slot = mounts.iter_mut().find(|slot| slot.is_none()).ok_or(FsError::TooManyMounts)?;
+        *slot = Some(Mount { path, fs, open: 0 });
+        Ok(())
+    }
+
+    /// Unmounts the filesystem at `path`, unless it is busy.
+    pub fn unmount(&self, path: &str) -> Result<(), FsError> {
+        let path: NameBuf<MAX_MOUNT_PATH> = normalize(path).map_err(|_| FsError::NotMounted)?;
+        let path = path.as_str();
+        let mut mounts = self.mounts.lock();
+        let slot = mounts.iter().position(|mount| mount.is_some_and(|mount| mount.path.as_str() == path))
+            .ok_or(FsError::NotMounted)?;
+        let nested = mounts.iter().flatten().any(|mount| mount.is_under(path));
+        if mounts[slot].is_some_and(|mount| mount.open != 0) || nested {
+            return Err(FsError::Busy);
+        }
+        mounts[slot] = None;
+        Ok(())
+    }
+
+    /// Returns the mount the normalized `path` is on, the one at its longest
+    /// prefix, and the length of that prefix.
+    fn find_mount(&self, path: &str) -> Result<(usize, &'static dyn FileSystem, usize), FsError> {
+        let mounts = self.mounts.lock();
+        mounts.iter().enumerate()
+            .filter_map(|(slot, mount)| mount.filter(|mount| mount.covers(path)).map(|mount| (slot, mount)))
+            .max_by_key(|(_, mount)| mount.path.as_str().len())
+            .map(|(slot, mount)| (slot, mount.fs, mount.path.as_str().len()))
+            .ok_or(FsError::NotMounted)
+    }
+
+    /// Looks the absolute `path` up, following the symbolic links on the way
+    /// and, with `follow_last`, the one it ends at.
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<Resolved, FsError> {
+        let mut pending: PathBuf = normalize(path)?;
+        let mut links = 0;
+        'restart: loop {
+            let current = pending;
+            let path = current.as_str();
+            let (slot, fs, mount_len) = self.find_mount(path)?;
+            let mut node = fs.root();
+            let mut end = mount_len;
+            while end < path.len() {
+                let start = end + 1;
+                end = path[start..].find('/').map_or(path.len(), |len| start + len);
+                if node.kind != NodeKind::Directory {
+                    return Err(FsError::NotADirectory);
+                }
+                node = fs.lookup(node, &path[start..end])?;
+                if node.kind == NodeKind::Symlink && (end < path.len() || follow_last) {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(FsError::TooManyLinks);
+                    }
+                    let mut buffer = [0u8; MAX_PATH];
+                    let len = fs.read_link(node, &mut buffer)?;
+                    let target = core::str::from_utf8(&buffer[..len]).map_err(|_| FsError::InvalidPath)?;
+                    // Relative targets start from the directory of the link.
+                    pending = PathBuf::new();
+                    if !target.starts_with('/') {
+                        push_path(&mut pending, &path[..start - 1])?;
+                    }
+                    push_path(&mut pending, target)?;
+                    push_path(&mut pending, &path[end..])?;
+                    continue 'restart;
+                }
+            }
+            return Ok(Resolved { slot, fs, node });
+        }
+    }
+
+    /// Opens the file or directory at the absolute `path`, following symbolic links.
+    pub fn open(&'static self, path: &str) -> Result<File, FsError> {
+        let resolved = self.resolve(path, true)?;
+        self.open_resolved(resolved)
+    }
+
+    /// Creates an empty file at the absolute `path` and opens it. The
+    /// directory it goes in must exist, and hold nothing by its name, not even
+    /// a symbolic link.
+    pub fn create(&'static self, path: &str) -> Result<File, FsError> {
+        let path: PathBuf = normalize(path)?;
+        // The root is there already.
+        let (parent, name) = path.as_str().rsplit_once('/').ok_or(FsError::AlreadyExists)?;
+        let dir = self.resolve(if parent.is_empty() { "/" } else { parent }, true)?;
+        if dir.node.kind != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        match dir.fs.lookup(dir.node, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {},
+            Err(err) => return Err(err),
+        }
+        let node = dir.fs.create(dir.node, name)?;
+        self.open_resolved(Resolved { node, ..dir })
+    }
+
+    /// Opens the node a path led to, counting it on its mount.
+    fn open_resolved(&'static self, resolved: Resolved) -> Result<File, FsError> {
+        let mut mounts = self.mounts.lock();
+        // Unmounted while the path was looked up.
+        let mount = mounts[resolved.slot].as_mut()
+            .filter(|mount| ptr::addr_eq(mount.fs, resolved.fs))
+            .ok_or(FsError::NotMounted)?;
+        mount.open += 1;
+        Ok(File { vfs: self, slot: resolved.slot, fs: resolved.fs, node: resolved.node })
+    }
+
+    pub fn metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        let resolved = self.resolve(path, true)?;
+        resolved.fs.metadata(resolved.node)
+    }
+
+    /// The metadata of the node at `path`, of the symbolic link itself when it is one.
+    pub fn symlink_metadata(&self, path: &str) -> Result<Metadata, FsError> {
+        let resolved = self.resolve(path, false)?;
+        resolved.fs.metadata(resolved.node)
+    }
+
+    /// Lists the directory at `path`, without `.` and `..`.
+    pub fn read_dir(&'static self, path: &str) -> Result<ReadDir, FsError> {
+        let dir = self.open(path)?;
+        if dir.node.kind != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(ReadDir { dir, cookie: Some(0) })
+    }
+}
+
+/// An open file or directory, which keeps its filesystem mounted.
+pub struct File {
+    vfs: &'static Vfs,
+    slot: usize,
+    fs: &'static dyn FileSystem,
+    node: NodeRef,
+}
+
+impl File {
+    pub fn kind(&self) -> NodeKind {
+        self.node.kind
+    }
+
+    pub fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if self.node.kind == NodeKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        self.fs.read_at(self.node, offset, buffer)
+    }
+
+    pub fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, FsError> {
+        if self.node.kind == NodeKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        self.fs.write_at(self.node, offset, buffer)
+    }
+
+    /// Sets the size of the file, see [`FileSystem::truncate`].
+    pub fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if self.node.kind == NodeKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        self.fs.truncate(self.node, size)
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, FsError> {
+        self.fs.metadata(self.node)
+    }
+
+    /// Returns the entry of the directory at `cookie`, with the cookie of the
+    /// next one, see [`FileSystem::read_dir`]. `.` and `..` come back too.
+    pub fn read_dir_at(&self, cookie: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        if self.node.kind != NodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        self.fs.read_dir(self.node, cookie)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Some(mount) = self.vfs.mounts.lock()[self.slot].as_mut() {
+            mount.open -= 1;
+        }
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "File({:?})", self.node)
+    }
+}
+
+/// The entries of a directory, see [`Vfs::read_dir`]. An error ends the
+/// iteration.
+pub struct ReadDir {
+    dir: File,
+    /// `None` once done.
+    cookie: Option<u64>,
+}
+
+impl Iterator for ReadDir {
+    type Item = Result<DirEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let result = self.dir.read_dir_at(self.cookie?);
+            self.cookie = None;
+            match result {
+                Ok(Some((entry, next))) => {
+                    self.cookie = Some(next);
+                    if entry.name() != "." && entry.name() != ".." {
+                        return Some(Ok(entry));
+                    }
+                }
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Mounts `fs` at `path` in the kernel's tree, see [`Vfs::mount`].
+pub fn mount(path: &str, fs: &'static dyn FileSystem) -> Result<(), FsError> {
+    VFS.mount(path, fs)
+}
+
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    VFS.unmount(path)
+}
+
+pub fn open(path: &str) -> Result<File, FsError> {
+    VFS.open(path)
+}
+
+pub fn create(path: &str) -> Result<File, FsError> {
+    VFS.create(path)
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    VFS.metadata(path)
+}
+
+pub fn symlink_metadata(path: &str) -> Result<Metadata, FsError> {
+    VFS.symlink_metadata(path)
+}
+
+pub fn read_dir(path: &str) -> Result<ReadDir, FsError> {
+    VFS.read_dir(path)
+}
+
+#[cfg(test)]
+fn read_all<'a>(vfs: &'static Vfs, path: &str, buffer: &'a mut [u8]) -> &'a [u8] {
+    let file = vfs.open(path).unwrap();
+    let len = file.read_at(0, buffer).unwrap();
+    &buffer[..len]
+}
+
+/// The initrd fixture at the root, with the FAT32 one over its `/files`.
+#[cfg(test)]
+fn mount_fixtures(vfs: &Vfs) {
+    vfs.mount("/", super::ustar::fixture()).unwrap();
+    vfs.mount("/files", super::fat::fixture()).unwrap();
+}
+
+#[test_case]
+fn vfs_resolves_paths_across_mounts() {
+    static TABLE: Vfs = Vfs::new();
+    mount_fixtures(&TABLE);
+    let mut buffer = [0u8; 64];
+    assert_eq!(read_all(&TABLE, "/hello.txt", &mut buffer), b"Hello from the initrd!\n");
+    assert_eq!(read_all(&TABLE, "/files/hello.txt", &mut buffer), b"Hello, FAT32!\n");
+    // Through a link on the initrd to the FAT volume, looked up case-insensitively there.
+    assert_eq!(read_all(&TABLE, "/data/DOCS/nested/deep/file.txt", &mut buffer), b"three directories down\n");
+    assert_eq!(read_all(&TABLE, "/files/docs/../../etc/./motd", &mut buffer), b"Welcome to krabbos.\n");
+    assert_eq!(read_all(&TABLE, "/etc/greeting", &mut buffer), b"Hello from the initrd!\n");
+    assert_eq!(TABLE.metadata("/deep").unwrap().kind, NodeKind::Directory);
+
+    assert!(matches!(TABLE.open("/.."), Err(FsError::InvalidPath)));
+    assert!(matches!(TABLE.open("hello.txt"), Err(FsError::InvalidPath)));
+    assert!(matches!(TABLE.open("/loop"), Err(FsError::TooManyLinks)));
+    assert!(matches!(TABLE.open("/etc/motd/x"), Err(FsError::NotADirectory)));
+    assert!(matches!(TABLE.open("/etc/passwd"), Err(FsError::NotFound)));
+    // Under the FAT volume now.
+    assert!(matches!(TABLE.open("/files/000.txt"), Err(FsError::NotFound)));
+
+    let etc = TABLE.open("/etc").unwrap();
+    assert_eq!(etc.read_at(0, &mut buffer), Err(FsError::IsADirectory));
+    assert_eq!(TABLE.open("/hello.txt").unwrap().write_at(0, b"hi"), Err(FsError::ReadOnly));
+}
+
+#[test_case]
+fn vfs_lists_directories_and_reads_metadata() {
+    static TABLE: Vfs = Vfs::new();
+    mount_fixtures(&TABLE);
+    let mut names = TABLE.read_dir("/etc").unwrap().map(|entry| entry.unwrap());
+    for name in ["motd", "hostname", "greeting"] {
+        assert_eq!(names.next().unwrap().name(), name);
+    }
+    assert!(names.next().is_none());
+    // `deep` is only implied, so not listed.
+    assert_eq!(TABLE.read_dir("/").unwrap().count(), 8);
+    // The FAT root spans two clusters, and `docs` starts with `.` and `..`.
+    let mut names = TABLE.read_dir("/data").unwrap().map(|entry| entry.unwrap());
+    assert_eq!(names.next().unwrap().name(), "HELLO.TXT");
+    assert_eq!(names.last().unwrap().name(), "Fragmented file.bin");
+    let mut names = TABLE.read_dir("/files/docs").unwrap().map(|entry| entry.unwrap());
+    assert_eq!(names.next().unwrap().name(), "Big file.bin");
+    assert_eq!(names.next().unwrap().kind(), NodeKind::Directory);
+    assert!(names.next().is_none());
+    assert!(matches!(TABLE.read_dir("/hello.txt"), Err(FsError::NotADirectory)));
+
+    let modified = Some(DateTime { year: 2024, month: 5, day: 17, hour: 13, minute: 45, second: 30 });
+    let hostname = TABLE.metadata("/etc/hostname").unwrap();
+    assert_eq!((hostname.kind, hostname.size, hostname.mode, hostname.modified), (NodeKind::File, 8, 0o600, modified));
+    assert_ne!(hostname.inode, TABLE.metadata("/etc/motd").unwrap().inode);
+    let hello = TABLE.metadata("/files/hello.txt").unwrap();
+    assert_eq!((hello.kind, hello.size, hello.mode, hello.modified), (NodeKind::File, 14, 0o644, modified));
+    assert!(hello.created.is_some());
+    assert_eq!(TABLE.metadata("/files/docs").unwrap().mode, 0o755);
+}
+
+#[test_case]
+fn vfs_refuses_to_unmount_busy_filesystems() {
+    static TABLE: Vfs = Vfs::new();
+    assert!(matches!(TABLE.open("/hello.txt"), Err(FsError::NotMounted)));
+    let (initrd, fat) = (super::ustar::fixture(), super::fat::fixture());
+    TABLE.mount("/", initrd).unwrap();
+    assert_eq!(TABLE.mount("/hello.txt", fat), Err(FsError::NotADirectory));
+    assert_eq!(TABLE.mount("/nowhere", fat), Err(FsError::NotFound));
+    TABLE.mount("/files", fat).unwrap();
+    assert_eq!(TABLE.mount("/files/", fat), Err(FsError::AlreadyMounted));
+
+    let file = TABLE.open("/files/hello.txt").unwrap();
+    assert_eq!(TABLE.unmount("/files"), Err(FsError::Busy));
+    drop(file);
+    let dir = TABLE.read_dir("/files/docs").unwrap();
+    assert_eq!(TABLE.unmount("/files"), Err(FsError::Busy));
+    drop(dir);
+    assert_eq!(TABLE.unmount("/"), Err(FsError::Busy));
+    TABLE.unmount("/files").unwrap();
+    assert_eq!(TABLE.unmount("/files"), Err(FsError::NotMounted));
+
+    let mut buffer = [0u8; 16];
+    assert_eq!(read_all(&TABLE, "/files/000.txt", &mut buffer), b"file 0\n");
+    TABLE.unmount("/").unwrap();
+    assert!(matches!(TABLE.open("/hello.txt"), Err(FsError::NotMounted)));
+}
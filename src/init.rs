@@ -0,0 +1,97 @@
+//! The phases `kernel_main` goes through, logged to the screen and the serial
+//! port as they start and end.
+//!
+//! The current phase is kept for the panic handler, so a panic during boot
+//! tells what was being set up. [`crate::tables::port::PostCode`] marks the
+//! same milestones for when nothing can be printed.
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use crate::{cpu::{rdtsc, tsc_frequency}, println, serial_println};
+
+const PHASES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum InitPhase {
+    /// The VGA writer, nothing else is set up.
+    Early,
+    Gdt,
+    Idt,
+    /// The PICs and the PIT, and interrupts enabled.
+    Pic,
+    /// The page tables, the PAT and the frame allocator.
+    Memory,
+    /// Empty for now, there is no heap allocator yet.
+    Heap,
+    /// ACPI, the PS/2 mouse, the other CPUs, PCI and the drivers.
+    Devices,
+    /// Booted, running the tests or idling.
+    Ready,
+}
+
+impl InitPhase {
+    const ALL: [InitPhase; PHASES] = [
+        InitPhase::Early,
+        InitPhase::Gdt,
+        InitPhase::Idt,
+        InitPhase::Pic,
+        InitPhase::Memory,
+        InitPhase::Heap,
+        InitPhase::Devices,
+        InitPhase::Ready,
+    ];
+
+    fn from_u8(phase: u8) -> Self {
+        Self::ALL[phase as usize]
+    }
+}
+
+static CURRENT_PHASE: AtomicU8 = AtomicU8::new(InitPhase::Early as u8);
+/// The TSC when each phase started, 0 until it does.
+static PHASE_STARTS: [AtomicU64; PHASES] = [const { AtomicU64::new(0) }; PHASES];
+
+/// Ends the current phase and starts `phase`, which must come later.
+pub fn init_phase(phase: InitPhase) {
+    let now = rdtsc();
+    let previous = current_init_phase();
+    assert!(phase >= previous && PHASE_STARTS[phase as usize].load(Ordering::Relaxed) == 0,
+        "init phase {:?} entered during {:?}", phase, previous);
+
+    let started = PHASE_STARTS[previous as usize].load(Ordering::Relaxed);
+    if started != 0 {
+        let cycles = now - started;
+        match tsc_frequency() {
+            Some(hz) => {
+                println!("[init] {:?} done in {} us", previous, cycles * 1_000_000 / hz);
+                serial_println!("[init] {:?} done in {} us", previous, cycles * 1_000_000 / hz);
+            },
+            None => {
+                println!("[init] {:?} done in {} cycles", previous, cycles);
+                serial_println!("[init] {:?} done in {} cycles", previous, cycles);
+            },
+        }
+    }
+    println!("[init] {:?}", phase);
+    serial_println!("[init] {:?}", phase);
+    PHASE_STARTS[phase as usize].store(now, Ordering::Relaxed);
+    CURRENT_PHASE.store(phase as u8, Ordering::Relaxed);
+}
+
+/// Returns the phase entered last.
+pub fn current_init_phase() -> InitPhase {
+    InitPhase::from_u8(CURRENT_PHASE.load(Ordering::Relaxed))
+}
+
+#[test_case]
+fn init_phases_in_order() {
+    assert!(InitPhase::ALL.windows(2).all(|pair| pair[0] < pair[1]));
+    for phase in InitPhase::ALL {
+        assert_eq!(InitPhase::from_u8(phase as u8), phase);
+    }
+
+    // The tests run once `kernel_main` entered the last phase.
+    assert_eq!(current_init_phase(), InitPhase::Ready);
+    let starts = PHASE_STARTS.each_ref().map(|start| start.load(Ordering::Relaxed));
+    assert!(starts.iter().all(|&start| start != 0), "phase skipped: {:?}", starts);
+    assert!(starts.windows(2).all(|pair| pair[0] < pair[1]), "phases out of order: {:?}", starts);
+}
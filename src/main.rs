@@ -1,6 +1,7 @@
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
 #![feature(const_trait_impl)]
+#![feature(step_trait)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![no_std]
@@ -10,41 +11,170 @@ mod vga;
 mod tables;
 mod pic;
 mod memory;
+mod sync;
+mod syscall;
+mod serial;
+mod tty;
+mod cpu;
+mod boot;
+mod pci;
+mod acpi;
+mod power;
+mod drivers;
+mod net;
+mod smp;
+mod init;
+mod shell;
+mod storage;
+mod fs;
+mod process;
 
 use core::{panic::PanicInfo, arch::asm};
-use pic::timer::init_pit;
-use tables::{idt::load_idt, port::Port, gdt::load_gdt};
+use pic::timer::{init_pit, PitMode};
+use tables::{idt::load_idt, port::{Port, PostCode}, gdt::load_gdt};
 use bootloader::{BootInfo, entry_point};
-use memory::paging::{active_level_4_table, PageTable};
+use memory::{paging::{active_level_4_table, PageTable}, MemoryRegion};
+use init::{init_phase, InitPhase};
 
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static  BootInfo) -> ! {
+    let memory_map = boot::memory_map(boot_info);
+    unsafe { kernel_main_raw(boot_info.physical_memory_offset, memory_map.as_ptr(), memory_map.len()) }
+}
+
+/// Kernel entry that does not depend on the `bootloader` crate, for other bootloaders
+/// or a Multiboot2 stub to call.
+///
+/// ## Safety
+///
+/// The caller must have set up:
+/// - long mode with interrupts disabled and a stack of a few pages at least
+/// - paging mapping the kernel image and the complete physical memory at
+///   `phys_mem_offset`, using 4KiB, 2MiB or 1GiB pages
+/// - `memory_map_len` valid [`MemoryRegion`]s at `memory_map`, which stay in
+///   place forever and whose `Usable` regions are really free, i.e. do not
+///   hold the kernel, its stack, the page tables or the memory map itself
+#[no_mangle]
+pub unsafe extern "C" fn kernel_main_raw(
+    phys_mem_offset: u64,
+    memory_map: *const MemoryRegion,
+    memory_map_len: usize,
+) -> ! {
+    post_code!(PostCode::KernelEntry);
+    vga::init_vga();
     println!("Hello, World from krabbos!");
+    init_phase(InitPhase::Early);
 
+    init_phase(InitPhase::Gdt);
     load_gdt();
+    post_code!(PostCode::GdtLoaded);
+    init_phase(InitPhase::Idt);
     load_idt();
+    post_code!(PostCode::IdtLoaded);
+    tables::segments::enable_fsgsbase();
+    init_phase(InitPhase::Pic);
     unsafe { 
         pic::PICS.lock().initialize();
-        init_pit(50);
+        init_pit(50, PitMode::SquareWave);
+        post_code!(PostCode::PicsReady);
 
         // Sets interrupts
         asm!( "sti", options(preserves_flags, nostack) );
     };
+    // Takes about 100ms, not worth it for every test run.
+    #[cfg(not(test))]
+    pic::timer::calibrate_pit();
 
-    let phys_mem_offset = boot_info.physical_memory_offset;
+    init_phase(InitPhase::Memory);
     let level4_table = unsafe { active_level_4_table(phys_mem_offset) };
+    println!("L4 table: {}/512 entries present", level4_table.count_present());
     for (i, entry) in level4_table.iter().enumerate() {
         if !entry.is_unused() {
             println!("L4 Entry {}: {:?}", i, entry);
         }
     }
+    memory::pat::init();
+    unsafe { memory::init(phys_mem_offset, core::slice::from_raw_parts(memory_map, memory_map_len)); }
+    post_code!(PostCode::PagingReady);
+    process::init();
+    // Nothing to do until there is a heap allocator, the phase keeps its place in the log.
+    init_phase(InitPhase::Heap);
+
+    init_phase(InitPhase::Devices);
+
+    // bootloader 0.9 does not pass the RSDP address, so it is searched for.
+    if let Err(err) = unsafe { acpi::init(phys_mem_offset, None) } {
+        println!("ACPI: {}", err);
+    }
+    if let Some(madt) = acpi::madt() {
+        println!("ACPI: {} CPUs, {} I/O APICs, IRQ 0 at GSI {}",
+            madt.cpu_list().count(), madt.io_apics().count(), madt.irq_route(0).gsi);
+    }
+    // The FADT says whether there is an 8042 to talk to.
+    pic::init_ps2();
+    // Nor the SMBIOS entry point.
+    if let Err(err) = unsafe { drivers::smbios::init(phys_mem_offset, None) } {
+        println!("SMBIOS: {}", err);
+    }
+    match smp::boot_aps() {
+        Ok(cpus) => println!("SMP: {} CPUs online", cpus),
+        Err(err) => println!("SMP: {}", err),
+    }
+    // The PIT tests need IRQ 0 from the PIT, so the switches are left to the
+    // HPET and LAPIC timer tests.
+    #[cfg(not(test))]
+    match pic::hpet::init(pic::timer::tick_rate()) {
+        Ok(hpet) => println!("HPET: {} comparators at {} MHz", hpet.comparators(), hpet.frequency() / 1_000_000),
+        Err(err) => println!("HPET: {}, staying on the PIT", err),
+    }
+    #[cfg(not(test))]
+    match pic::lapic::timer::init(pic::timer::tick_rate()) {
+        Ok(mode) => println!("LAPIC timer: {:?} mode", mode),
+        Err(err) => println!("LAPIC timer: {}, staying on the PIT", err),
+    }
+
+    // Seeds the generator, so there is something to report.
+    cpu::random::u64();
+    println!("random: {}", cpu::random::health());
+
+    pci::print_devices(false);
+    drivers::ata::init();
+    drivers::ahci::init();
+    drivers::virtio::blk::init();
+    storage::ramdisk::init();
+    storage::partition::init();
+    fs::ustar::init();
+    fs::iso9660::init();
+    fs::ramfs::init();
+    if let Some(mac) = drivers::e1000::init() {
+        net::init(mac);
+    }
+    // The VGA tests read the text buffer, so they run in text mode.
+    #[cfg(not(test))]
+    match drivers::vbe::init(1024, 768) {
+        Ok(()) => drivers::vbe::demo(),
+        Err(err) => println!("VBE: {}, staying in text mode", err),
+    }
+
+    post_code!(PostCode::BootDone);
+    init_phase(InitPhase::Ready);
 
     #[cfg(test)]
     test_main();
 
+    // The kernel does nothing else after boot: answer the network and the
+    // shell, clean up after the processes, then let them run or sleep until
+    // the next interrupt.
+    let mut shell = shell::Shell::new();
+    shell.prompt(&mut shell::Console);
     loop {
-        unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)); }
+        net::poll();
+        net::dhcp::renew_if_due();
+        storage::cache::flush_due();
+        shell.poll(&mut shell::Console, &mut shell::Console);
+        process::reap();
+        process::scheduler::idle();
     }
 }
 
@@ -63,25 +193,78 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 }
 
 /// This function is called on panic.
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // The panic may come from a deadlock on the writer itself; its holder never runs again.
+    unsafe { vga::VGA_WRITER.force_unlock(); }
     println!("{}", info);
+    println!("interrupt depth: {}", tables::interrupt_depth());
+    println!("init phase: {:?}", init::current_init_phase());
+    serial_println!("{}", info);
+    serial_println!("init phase: {:?}", init::current_init_phase());
     loop {}
 }
 
+/// Reports the failing test on both outputs and exits QEMU with the failure code.
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    unsafe {
+        vga::VGA_WRITER.force_unlock();
+        serial::SERIAL1.force_unlock();
+    }
+    println!("[failed]\n{}", info);
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}", info);
+    serial_println!("interrupt depth: {}", tables::interrupt_depth());
+    serial_println!("init phase: {:?}\n", init::current_init_phase());
+    serial_println!("TEST_SUITE:FAIL");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// A test that reports its name and result on the VGA screen and the serial port.
+#[cfg(test)]
+pub trait Testable {
+    fn run(&self);
+}
 
 #[cfg(test)]
-pub fn test_runner(tests: &[&dyn Fn()]) {
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        let name = core::any::type_name::<T>();
+        print!("{}... ", name);
+        serial_print!("{}...\t", name);
+        self();
+        println!("[ok]");
+        serial_println!("[ok]");
+    }
+}
+
+/// Runs every test, then writes `TEST_SUITE:OK` to the serial port for the host
+/// side (see `scripts/run-tests.sh`) before exiting QEMU. A failing test ends in the
+/// panic handler, which writes `TEST_SUITE:FAIL` instead.
+#[cfg(test)]
+pub fn test_runner(tests: &[&dyn Testable]) {
     println!("Running {} tests", tests.len());
+    serial_println!("Running {} tests", tests.len());
     for test in tests {
-        test();
+        test.run();
+    }
+    serial_println!("TEST_SUITE:OK");
+    if cfg!(feature = "acpi_shutdown") {
+        power::shutdown();
     }
     exit_qemu(QemuExitCode::Success);
 }
 
 #[test_case]
 fn trivial_assertion() {
-    print!("This is a trivial assertion to test the test custom framework... ");
     assert_eq!(1, 1);
-    println!("[ok]");
+}
+
+#[test_case]
+fn test_output_marker() {
+    assert!(serial::SERIAL1.lock().is_initialized());
 }
@@ -0,0 +1,1076 @@
+//! Virtual and physical address arithmetic, page table indices and the page
+//! and frame types.
+//!
+//! Nothing here touches the hardware, so the module also builds for the host,
+//! see `host-tests`.
+
+use core::fmt;
+use core::iter::Step;
+use core::marker::PhantomData;
+use core::ops::{Add, AddAssign, RangeInclusive, Sub, SubAssign};
+
+/// The number of entries in a page table.
+pub(crate) const ENTRY_COUNT: usize = 512;
+const ADDRESS_SPACE_SIZE: u64 = 0x1_0000_0000_0000;
+
+/// Returns whether bits 48 to 63 of `addr` are copies of bit 47, as the CPU
+/// requires of every virtual address it uses.
+pub const fn is_canonical(addr: u64) -> bool {
+    canonicalize(addr) == addr
+}
+
+/// Sign extends bit 47 of `addr` into bits 48 to 63.
+pub const fn canonicalize(addr: u64) -> u64 {
+    // By doing the right shift as a signed operation (on a i64), it will
+    // sign extend the value, repeating the leftmost bit.
+    ((addr << 16) as i64 >> 16) as u64
+}
+
+/// The lower and the higher half of the address space, the two canonical ranges.
+pub const fn canonical_range() -> [RangeInclusive<u64>; 2] {
+    [0..=0x0000_7FFF_FFFF_FFFF, 0xFFFF_8000_0000_0000..=u64::MAX]
+}
+
+pub trait VirtAddr {
+    fn page_offset(self) -> PageOffset;
+    fn p4_index(self) -> PageTableIndex;
+    fn p3_index(self) -> PageTableIndex;
+    fn p2_index(self) -> PageTableIndex;
+    fn p1_index(self) -> PageTableIndex;
+    fn page_table_index(self, level: PageTableLevel) -> PageTableIndex;
+    fn new_virt_truncate(addr: u64) -> u64;
+    fn forward_checked_u64(start: Self, count: u64) -> Option<Self> where Self: Sized;
+    fn forward_checked_impl(start: Self, count: usize) -> Option<Self> where Self: Sized;
+}
+
+impl VirtAddr for u64 {
+    #[inline]
+    fn page_offset(self) -> PageOffset {
+        PageOffset::new_truncate(self as u16)
+    }
+    #[inline]
+    fn p1_index(self) -> PageTableIndex {
+        PageTableIndex::new_truncate((self >> 12) as u16)
+    }
+
+    /// Returns the 9-bit level 2 page table index.
+    #[inline]
+    fn p2_index(self) -> PageTableIndex {
+        PageTableIndex::new_truncate((self >> 12 >> 9) as u16)
+    }
+
+    /// Returns the 9-bit level 3 page table index.
+    #[inline]
+    fn p3_index(self) -> PageTableIndex {
+        PageTableIndex::new_truncate((self >> 12 >> 9 >> 9) as u16)
+    }
+
+    /// Returns the 9-bit level 4 page table index.
+    #[inline]
+    fn p4_index(self) -> PageTableIndex {
+        PageTableIndex::new_truncate((self >> 12 >> 9 >> 9 >> 9) as u16)
+    }
+    #[inline]
+    fn new_virt_truncate(addr: u64) -> u64 {
+        canonicalize(addr)
+    }
+
+    fn page_table_index(self, level: PageTableLevel) -> PageTableIndex {
+        match level {
+            PageTableLevel::One => self.p1_index(),
+            PageTableLevel::Two => self.p2_index(),
+            PageTableLevel::Three => self.p3_index(),
+            PageTableLevel::Four => self.p4_index(),
+        } 
+    }
+
+    #[inline]
+    fn forward_checked_impl(start: Self, count: usize) -> Option<Self> {
+        Self::forward_checked_u64(start, u64::try_from(count).ok()?)
+    }
+
+    /// An implementation of forward_checked that takes u64 instead of usize.
+    ///
+    /// Counts from the end of the lower half on at the start of the higher
+    /// half. Returns `None` for a non-canonical `start`, or when the count
+    /// goes past the end of the higher half.
+    #[inline]
+    fn forward_checked_u64(start: Self, count: u64) -> Option<Self> {
+        if !is_canonical(start) {
+            return None;
+        }
+        // Dropping the sign extension makes the two halves contiguous, and
+        // sign extending again jumps the gap.
+        let position = (start & (ADDRESS_SPACE_SIZE - 1)).checked_add(count)?;
+        if position >= ADDRESS_SPACE_SIZE {
+            return None;
+        }
+        Some(canonicalize(position))
+    }
+}
+
+pub trait PhysAddr {
+    fn is_aligned(&self, value: u64) -> bool;
+    fn align_down(&self, value: u64) -> Self;
+    fn align_up(&self, value: u64) -> Self;
+    fn new_truncate(addr: u64) -> Self;
+}
+
+impl PhysAddr for u64 {
+    fn is_aligned(&self, value: u64) -> bool {
+        self.align_down(value) == *self
+    }
+
+    fn align_down(&self, value: u64) -> Self {
+        assert!(value.is_power_of_two(), "`align` must be a power of two");
+        *self & !(value - 1)
+    }
+
+    /// Panics if the result does not fit in a `u64`.
+    fn align_up(&self, value: u64) -> Self {
+        assert!(value.is_power_of_two(), "`align` must be a power of two");
+        let mask = value - 1;
+        match *self & mask {
+            0 => *self,
+            _ => (*self | mask).checked_add(1).expect("attempt to align up with overflow"),
+        }
+    }
+
+    fn new_truncate(addr: u64) -> Self {
+        addr % (1 << 52)
+    }
+}
+
+/// A 9-bit index into a page table.
+///
+/// Can be used to select one of the 512 entries of a page table.
+///
+/// Guaranteed to only ever contain 0..512.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageTableIndex(u16);
+
+impl PageTableIndex {
+    /// Creates a new index from the given `u16`. Panics if the given value is >=512.
+    #[inline]
+    pub const fn new(index: u16) -> Self {
+        assert!((index as usize) < ENTRY_COUNT);
+        Self(index)
+    }
+
+    /// Creates a new index from the given `u16`. Throws away bits if the value is >=512.
+    #[inline]
+    pub const fn new_truncate(index: u16) -> Self {
+        Self(index % ENTRY_COUNT as u16)
+    }
+
+    #[inline]
+    pub(crate) const fn into_u64(self) -> u64 {
+        self.0 as u64
+    }
+}
+
+impl From<PageTableIndex> for u16 {
+    #[inline]
+    fn from(index: PageTableIndex) -> Self {
+        index.0
+    }
+}
+
+impl From<PageTableIndex> for u32 {
+    #[inline]
+    fn from(index: PageTableIndex) -> Self {
+        u32::from(index.0)
+    }
+}
+
+impl From<PageTableIndex> for u64 {
+    #[inline]
+    fn from(index: PageTableIndex) -> Self {
+        index.into_u64()
+    }
+}
+
+impl From<PageTableIndex> for usize {
+    #[inline]
+    fn from(index: PageTableIndex) -> Self {
+        usize::from(index.0)
+    }
+}
+
+/// A 12-bit offset into a 4KiB Page.
+///
+/// This type is returned by the `VirtAddr::page_offset` method.
+///
+/// Guaranteed to only ever contain 0..4096.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageOffset(u16);
+
+impl PageOffset {
+    /// Creates a new offset from the given `u16`. Panics if the passed value is >=4096.
+    #[inline]
+    pub fn new(offset: u16) -> Self {
+        assert!(offset < (1 << 12));
+        Self(offset)
+    }
+
+    /// Creates a new offset from the given `u16`. Throws away bits if the value is >=4096.
+    #[inline]
+    pub const fn new_truncate(offset: u16) -> Self {
+        Self(offset % (1 << 12))
+    }
+}
+
+impl From<PageOffset> for u16 {
+    #[inline]
+    fn from(offset: PageOffset) -> Self {
+        offset.0
+    }
+}
+
+impl From<PageOffset> for u32 {
+    #[inline]
+    fn from(offset: PageOffset) -> Self {
+        u32::from(offset.0)
+    }
+}
+
+impl From<PageOffset> for u64 {
+    #[inline]
+    fn from(offset: PageOffset) -> Self {
+        u64::from(offset.0)
+    }
+}
+
+impl From<PageOffset> for usize {
+    #[inline]
+    fn from(offset: PageOffset) -> Self {
+        usize::from(offset.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A value between 1 and 4.
+pub enum PageTableLevel {
+    /// Represents the level for a page table.
+    One = 1,
+    /// Represents the level for a page directory.
+    Two,
+    /// Represents the level for a page-directory pointer.
+    Three,
+    /// Represents the level for a page-map level-4.
+    Four,
+}
+
+impl PageTableLevel {
+    /// Returns the next lower level or `None` for level 1
+    pub const fn next_lower_level(self) -> Option<Self> {
+        match self {
+            PageTableLevel::Four => Some(PageTableLevel::Three),
+            PageTableLevel::Three => Some(PageTableLevel::Two),
+            PageTableLevel::Two => Some(PageTableLevel::One),
+            PageTableLevel::One => None,
+        }
+    }
+
+    /// Returns the next higher level or `None` for level 4
+    pub const fn next_higher_level(self) -> Option<Self> {
+        match self {
+            PageTableLevel::Four => None,
+            PageTableLevel::Three => Some(PageTableLevel::Four),
+            PageTableLevel::Two => Some(PageTableLevel::Three),
+            PageTableLevel::One => Some(PageTableLevel::Two),
+        }
+    }
+
+    /// Returns the alignment for the address space described by a table of this level.
+    pub const fn table_address_space_alignment(self) -> u64 {
+        1u64 << (self as u8 * 9 + 12)
+    }
+
+    /// Returns the alignment for the address space described by an entry in a table of this level.
+    pub const fn entry_address_space_alignment(self) -> u64 {
+        1u64 << (((self as u8 - 1) * 9) + 12)
+    }
+}
+
+/// The page sizes in bytes, for code that does not go through [`PageSize`].
+pub const PAGE_SIZE_4K: u64 = 0x1000;
+pub const PAGE_SIZE_2M: u64 = 0x20_0000;
+pub const PAGE_SIZE_1G: u64 = 0x4000_0000;
+
+/// Returns the offset of `addr` within the page of `size` bytes holding it,
+/// e.g. [`PAGE_SIZE_2M`] for an address translated through a huge page.
+#[inline]
+pub const fn huge_page_offset(addr: u64, size: u64) -> u64 {
+    debug_assert!(size.is_power_of_two(), "page size must be a power of two");
+    addr & (size - 1)
+}
+
+/// Trait for abstracting over the three possible page sizes on x86_64, 4KiB, 2MiB, 1GiB.
+pub trait PageSize: Copy + Eq + PartialOrd + Ord {
+    /// The page size in bytes.
+    const SIZE: u64;
+
+    /// A string representation of the page size for debug output.
+    const DEBUG_STR: &'static str;
+}
+
+/// This trait is implemented for 4KiB and 2MiB pages, but not for 1GiB pages.
+pub trait NotGiantPageSize: PageSize {}
+
+/// A standard 4KiB page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Size4KiB {}
+
+/// A “huge” 2MiB page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Size2MiB {}
+
+/// A “giant” 1GiB page.
+///
+/// (Only available on newer x86_64 CPUs.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Size1GiB {}
+
+impl PageSize for Size4KiB {
+    const SIZE: u64 = 4096;
+    const DEBUG_STR: &'static str = "4KiB";
+}
+
+impl NotGiantPageSize for Size4KiB {}
+
+impl PageSize for Size2MiB {
+    const SIZE: u64 = Size4KiB::SIZE * 512;
+    const DEBUG_STR: &'static str = "2MiB";
+}
+
+impl NotGiantPageSize for Size2MiB {}
+
+impl PageSize for Size1GiB {
+    const SIZE: u64 = Size2MiB::SIZE * 512;
+    const DEBUG_STR: &'static str = "1GiB";
+}
+
+const _: () = assert!(Size4KiB::SIZE == PAGE_SIZE_4K);
+const _: () = assert!(Size2MiB::SIZE == PAGE_SIZE_2M);
+const _: () = assert!(Size1GiB::SIZE == PAGE_SIZE_1G);
+
+/// A virtual memory page.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(C)]
+pub struct Page<S: PageSize = Size4KiB> {
+    start_address: u64,
+    size: PhantomData<S>,
+}
+
+impl<S: PageSize> Page<S> {
+    /// The page size in bytes.
+    pub const SIZE: u64 = S::SIZE;
+
+    /// Returns the page that starts at the given virtual address.
+    ///
+    /// Returns an error if the address is not correctly aligned (i.e. is not a valid page start).
+    #[inline]
+    pub fn from_start_address(address: u64) -> Result<Self, AddressNotAligned> {
+        if !address.is_aligned(S::SIZE) {
+            return Err(AddressNotAligned);
+        }
+        Ok(Page::containing_address(address))
+    }
+
+    /// Returns the page that starts at the given virtual address.
+    ///
+    /// ## Safety
+    ///
+    /// The address must be correctly aligned.
+    #[inline]
+    pub unsafe fn from_start_address_unchecked(start_address: u64) -> Self {
+        Page {
+            start_address,
+            size: PhantomData,
+        }
+    }
+
+    /// Returns the page that contains the given virtual address.
+    #[inline]
+    pub fn containing_address(address: u64) -> Self {
+        Page {
+            start_address: address.align_down(S::SIZE),
+            size: PhantomData,
+        }
+    }
+
+    /// Returns the start address of the page.
+    #[inline]
+    pub fn start_address(self) -> u64 {
+        self.start_address
+    }
+
+    /// Returns the size the page (4KB, 2MB or 1GB).
+    #[inline]
+    pub fn size(self) -> u64 {
+        S::SIZE
+    }
+
+    /// Returns the level 4 page table index of this page.
+    #[inline]
+    pub fn p4_index(self) -> PageTableIndex {
+        self.start_address().p4_index()
+    }
+
+    /// Returns the level 3 page table index of this page.
+    #[inline]
+    pub fn p3_index(self) -> PageTableIndex {
+        self.start_address().p3_index()
+    }
+
+    /// Returns the table index of this page at the specified level.
+    #[inline]
+    pub fn page_table_index(self, level: PageTableLevel) -> PageTableIndex {
+        self.start_address().page_table_index(level)
+    }
+
+    /// Returns a range of pages, exclusive `end`.
+    #[inline]
+    pub fn range(start: Self, end: Self) -> PageRange<S> {
+        PageRange { start, end }
+    }
+
+    /// Returns a range of pages, inclusive `end`.
+    #[inline]
+    pub fn range_inclusive(start: Self, end: Self) -> PageRangeInclusive<S> {
+        PageRangeInclusive { start, end }
+    }
+
+    /// Returns the range of `count` pages from `start` on.
+    #[inline]
+    pub fn range_from_count(start: Self, count: u64) -> PageRange<S> {
+        PageRange { start, end: start + count }
+    }
+
+    /// Returns the range of `count` pages from `start` on, `count` must not be 0
+    /// since an inclusive range cannot be empty.
+    #[inline]
+    pub fn range_inclusive_from_count(start: Self, count: u64) -> PageRangeInclusive<S> {
+        assert!(count > 0, "an inclusive page range holds at least one page");
+        PageRangeInclusive { start, end: start + (count - 1) }
+    }
+}
+
+impl<S: NotGiantPageSize> Page<S> {
+    /// Returns the level 2 page table index of this page.
+    #[inline]
+    pub fn p2_index(self) -> PageTableIndex {
+        self.start_address().p2_index()
+    }
+}
+
+impl Page<Size1GiB> {
+    /// Returns the 1GiB page starting at `address`, an error if it is not 1GiB aligned.
+    ///
+    /// Only defined for `Page<Size1GiB>`, so a page of another size cannot be built
+    /// by picking the wrong helper.
+    #[inline]
+    pub fn from_start_address_1gib(address: u64) -> Result<Self, AddressNotAligned> {
+        Self::from_start_address(address)
+    }
+
+    /// Returns the 1GiB memory page with the specified page table indices.
+    #[inline]
+    pub fn from_page_table_indices_1gib(
+        p4_index: PageTableIndex,
+        p3_index: PageTableIndex,
+    ) -> Self {
+        let mut addr = 0;
+        addr |= p4_index.into_u64() << 39;
+        addr |= p3_index.into_u64() << 30;
+        Page::containing_address(u64::new_virt_truncate(addr))
+    }
+}
+
+impl Page<Size2MiB> {
+    /// Returns the 2MiB page starting at `address`, an error if it is not 2MiB aligned.
+    ///
+    /// Only defined for `Page<Size2MiB>`, see `Page::from_start_address_1gib`.
+    #[inline]
+    pub fn from_start_address_2mib(address: u64) -> Result<Self, AddressNotAligned> {
+        Self::from_start_address(address)
+    }
+
+    /// Returns the 2MiB memory page with the specified page table indices.
+    #[inline]
+    pub fn from_page_table_indices_2mib(
+        p4_index: PageTableIndex,
+        p3_index: PageTableIndex,
+        p2_index: PageTableIndex,
+    ) -> Self {
+        let mut addr = 0;
+        addr |= p4_index.into_u64() << 39;
+        addr |= p3_index.into_u64() << 30;
+        addr |= p2_index.into_u64() << 21;
+        Page::containing_address(u64::new_virt_truncate(addr))
+    }
+}
+
+impl Page<Size4KiB> {
+    /// Returns the 4KiB memory page with the specified page table indices.
+    #[inline]
+    pub fn from_page_table_indices(
+        p4_index: PageTableIndex,
+        p3_index: PageTableIndex,
+        p2_index: PageTableIndex,
+        p1_index: PageTableIndex,
+    ) -> Self {
+        let mut addr = 0;
+        addr |= p4_index.into_u64() << 39;
+        addr |= p3_index.into_u64() << 30;
+        addr |= p2_index.into_u64() << 21;
+        addr |= p1_index.into_u64() << 12;
+        Page::containing_address(u64::new_virt_truncate(addr))
+    }
+
+    /// Returns the level 1 page table index of this page.
+    #[inline]
+    pub fn p1_index(self) -> PageTableIndex {
+        self.start_address.p1_index()
+    }
+}
+
+impl<S: PageSize> fmt::Debug for Page<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Page[{}]({:#x})",
+            S::DEBUG_STR,
+            self.start_address()
+        ))
+    }
+}
+
+impl<S: PageSize> Add<u64> for Page<S> {
+    type Output = Self;
+    #[inline]
+    fn add(self, rhs: u64) -> Self::Output {
+        Page::containing_address(self.start_address() + rhs * S::SIZE)
+    }
+}
+
+impl<S: PageSize> AddAssign<u64> for Page<S> {
+    #[inline]
+    fn add_assign(&mut self, rhs: u64) {
+        *self = *self + rhs;
+    }
+}
+
+impl<S: PageSize> Sub<u64> for Page<S> {
+    type Output = Self;
+    #[inline]
+    fn sub(self, rhs: u64) -> Self::Output {
+        Page::containing_address(self.start_address() - rhs * S::SIZE)
+    }
+}
+
+impl<S: PageSize> SubAssign<u64> for Page<S> {
+    #[inline]
+    fn sub_assign(&mut self, rhs: u64) {
+        *self = *self - rhs;
+    }
+}
+
+impl<S: PageSize> Sub<Self> for Page<S> {
+    type Output = u64;
+    #[inline]
+    fn sub(self, rhs: Self) -> Self::Output {
+        (self.start_address - rhs.start_address) / S::SIZE
+    }
+}
+
+/// Lets `start..end` iterate over pages. Steps are counted in the address space
+/// without the canonical gap, so a range that leaves the lower half continues
+/// at the start of the higher half.
+impl<S: PageSize> Step for Page<S> {
+    fn steps_between(start: &Self, end: &Self) -> (usize, Option<usize>) {
+        if start > end {
+            return (0, None);
+        }
+        // Dropping the sign extension makes the two halves contiguous.
+        let distance = (end.start_address & (ADDRESS_SPACE_SIZE - 1))
+            - (start.start_address & (ADDRESS_SPACE_SIZE - 1));
+        match usize::try_from(distance / S::SIZE) {
+            Ok(steps) => (steps, Some(steps)),
+            Err(_) => (usize::MAX, None),
+        }
+    }
+
+    fn forward_checked(start: Self, count: usize) -> Option<Self> {
+        let bytes = u64::try_from(count).ok()?.checked_mul(S::SIZE)?;
+        let address = u64::forward_checked_u64(start.start_address, bytes)?;
+        Some(Page::containing_address(address))
+    }
+
+    fn backward_checked(start: Self, count: usize) -> Option<Self> {
+        let bytes = u64::try_from(count).ok()?.checked_mul(S::SIZE)?;
+        let address = (start.start_address & (ADDRESS_SPACE_SIZE - 1)).checked_sub(bytes)?;
+        Some(Page::containing_address(canonicalize(address)))
+    }
+}
+
+/// A range of pages with exclusive upper bound.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct PageRange<S: PageSize = Size4KiB> {
+    /// The start of the range, inclusive.
+    pub start: Page<S>,
+    /// The end of the range, exclusive.
+    pub end: Page<S>,
+}
+
+impl<S: PageSize> PageRange<S> {
+    /// Returns wether this range contains no pages.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Returns the number of pages in the range.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        if !self.is_empty() {
+            self.end - self.start
+        } else {
+            0
+        }
+    }
+
+    /// Returns the size in bytes of all pages within the range.
+    ///
+    /// A range over the whole address space is 2^64 bytes, one too many for a
+    /// `u64`: debug builds panic, release builds wrap, see
+    /// [`checked_size`](Self::checked_size).
+    #[inline]
+    pub fn size(&self) -> u64 {
+        debug_assert!(self.checked_size().is_some(), "size of {:?} overflows u64", self);
+        S::SIZE.wrapping_mul(self.len())
+    }
+
+    /// Returns the size in bytes of all pages within the range, `None` if it
+    /// does not fit in a `u64`.
+    #[inline]
+    pub fn checked_size(&self) -> Option<u64> {
+        S::SIZE.checked_mul(self.len())
+    }
+}
+
+impl<S: PageSize> Iterator for PageRange<S> {
+    type Item = Page<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start < self.end {
+            let page = self.start;
+            self.start += 1;
+            Some(page)
+        } else {
+            None
+        }
+    }
+}
+
+impl PageRange<Size2MiB> {
+    /// Converts the range of 2MiB pages to a range of 4KiB pages.
+    #[inline]
+    pub fn as_4kib_page_range(self) -> PageRange<Size4KiB> {
+        PageRange {
+            start: Page::containing_address(self.start.start_address()),
+            end: Page::containing_address(self.end.start_address()),
+        }
+    }
+}
+
+impl<S: PageSize> fmt::Debug for PageRange<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageRange")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+/// A range of pages with inclusive upper bound.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct PageRangeInclusive<S: PageSize = Size4KiB> {
+    /// The start of the range, inclusive.
+    pub start: Page<S>,
+    /// The end of the range, inclusive.
+    pub end: Page<S>,
+}
+
+impl<S: PageSize> PageRangeInclusive<S> {
+    /// Returns whether this range contains no pages.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start > self.end
+    }
+
+    /// Returns the number of frames in the range.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        if !self.is_empty() {
+            self.end - self.start + 1
+        } else {
+            0
+        }
+    }
+
+    /// Returns the size in bytes of all frames within the range.
+    ///
+    /// A range over the whole address space is 2^64 bytes, one too many for a
+    /// `u64`: debug builds panic, release builds wrap, see
+    /// [`checked_size`](Self::checked_size).
+    #[inline]
+    pub fn size(&self) -> u64 {
+        debug_assert!(self.checked_size().is_some(), "size of {:?} overflows u64", self);
+        S::SIZE.wrapping_mul(self.len())
+    }
+
+    /// Returns the size in bytes of all frames within the range, `None` if it
+    /// does not fit in a `u64`.
+    #[inline]
+    pub fn checked_size(&self) -> Option<u64> {
+        S::SIZE.checked_mul(self.len())
+    }
+}
+
+impl<S: PageSize> Iterator for PageRangeInclusive<S> {
+    type Item = Page<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start <= self.end {
+            let page = self.start;
+
+            // If the end of the inclusive range is the maximum page possible for size S,
+            // incrementing start until it is greater than the end will cause an integer overflow.
+            // So instead, in that case we decrement end rather than incrementing start.
+            let max_page_addr = u64::MAX - (S::SIZE - 1);
+            if self.start.start_address() < max_page_addr {
+                self.start += 1;
+            } else {
+                self.end -= 1;
+            }
+            Some(page)
+        } else {
+            None
+        }
+    }
+}
+
+impl<S: PageSize> fmt::Debug for PageRangeInclusive<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageRangeInclusive")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+/// The given address was not sufficiently aligned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressNotAligned;
+
+impl fmt::Display for AddressNotAligned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the given address was not sufficiently aligned")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(C)]
+pub struct PhysFrame<S: PageSize = Size4KiB> {
+    // TODO: Make private when our minimum supported stable Rust version is 1.61
+    pub(crate) start_address: u64,
+    size: PhantomData<S>,
+}
+
+impl<S: PageSize> PhysFrame<S> {
+    /// Returns the frame that starts at the given virtual address.
+    ///
+    /// Returns an error if the address is not correctly aligned (i.e. is not a valid frame start).
+    #[inline]
+    pub fn from_start_address(address: u64) -> Result<Self, AddressNotAligned> {
+        if !address.is_aligned(S::SIZE) {
+            return Err(AddressNotAligned);
+        }
+
+        // SAFETY: correct address alignment is checked above
+        Ok(unsafe { PhysFrame::from_start_address_unchecked(address) })
+    }
+
+    /// Returns the frame that starts at the given virtual address.
+    ///
+    /// ## Safety
+    ///
+    /// The address must be correctly aligned.
+    #[inline]
+    pub unsafe fn from_start_address_unchecked(start_address: u64) -> Self {
+        PhysFrame {
+            start_address,
+            size: PhantomData,
+        }
+    }
+
+    /// Returns the frame that contains the given physical address.
+    #[inline]
+    pub fn containing_address(address: u64) -> Self {
+        PhysFrame {
+            start_address: address.align_down(S::SIZE),
+            size: PhantomData,
+        }
+    }
+
+    /// Returns the start address of the frame.
+    #[inline]
+    pub fn start_address(self) -> u64 {
+        self.start_address
+    }
+
+    /// Returns the size the frame (4KB, 2MB or 1GB).
+    #[inline]
+    pub fn size(self) -> u64 {
+        S::SIZE
+    }
+
+    /// Returns a range of frames, exclusive `end`.
+    #[inline]
+    pub fn range(start: PhysFrame<S>, end: PhysFrame<S>) -> PhysFrameRange<S> {
+        PhysFrameRange { start, end }
+    }
+
+    /// Returns a range of frames, inclusive `end`.
+    #[inline]
+    pub fn range_inclusive(start: PhysFrame<S>, end: PhysFrame<S>) -> PhysFrameRangeInclusive<S> {
+        PhysFrameRangeInclusive { start, end }
+    }
+
+    /// Returns the range of `count` frames from `start` on.
+    #[inline]
+    pub fn range_from_count(start: PhysFrame<S>, count: u64) -> PhysFrameRange<S> {
+        PhysFrameRange { start, end: start + count }
+    }
+}
+
+impl<S: PageSize> fmt::Debug for PhysFrame<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "PhysFrame[{}]({:#x})",
+            S::DEBUG_STR,
+            self.start_address()
+        ))
+    }
+}
+
+impl<S: PageSize> Add<u64> for PhysFrame<S> {
+    type Output = Self;
+    #[inline]
+    fn add(self, rhs: u64) -> Self::Output {
+        PhysFrame::containing_address(self.start_address() + rhs * S::SIZE)
+    }
+}
+
+impl<S: PageSize> AddAssign<u64> for PhysFrame<S> {
+    #[inline]
+    fn add_assign(&mut self, rhs: u64) {
+        *self = *self + rhs;
+    }
+}
+
+impl<S: PageSize> Sub<u64> for PhysFrame<S> {
+    type Output = Self;
+    #[inline]
+    fn sub(self, rhs: u64) -> Self::Output {
+        PhysFrame::containing_address(self.start_address() - rhs * S::SIZE)
+    }
+}
+
+impl<S: PageSize> SubAssign<u64> for PhysFrame<S> {
+    #[inline]
+    fn sub_assign(&mut self, rhs: u64) {
+        *self = *self - rhs;
+    }
+}
+
+impl<S: PageSize> Sub<PhysFrame<S>> for PhysFrame<S> {
+    type Output = u64;
+    #[inline]
+    fn sub(self, rhs: PhysFrame<S>) -> Self::Output {
+        (self.start_address - rhs.start_address) / S::SIZE
+    }
+}
+
+/// Lets `start..end` iterate over frames.
+impl<S: PageSize> Step for PhysFrame<S> {
+    fn steps_between(start: &Self, end: &Self) -> (usize, Option<usize>) {
+        if start > end {
+            return (0, None);
+        }
+        match usize::try_from((end.start_address - start.start_address) / S::SIZE) {
+            Ok(steps) => (steps, Some(steps)),
+            Err(_) => (usize::MAX, None),
+        }
+    }
+
+    fn forward_checked(start: Self, count: usize) -> Option<Self> {
+        let bytes = u64::try_from(count).ok()?.checked_mul(S::SIZE)?;
+        let address = start.start_address.checked_add(bytes)?;
+        // Physical addresses are 52 bits wide.
+        if u64::new_truncate(address) != address {
+            return None;
+        }
+        Some(PhysFrame::containing_address(address))
+    }
+
+    fn backward_checked(start: Self, count: usize) -> Option<Self> {
+        let bytes = u64::try_from(count).ok()?.checked_mul(S::SIZE)?;
+        Some(PhysFrame::containing_address(start.start_address.checked_sub(bytes)?))
+    }
+}
+
+/// An range of physical memory frames, exclusive the upper bound.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct PhysFrameRange<S: PageSize = Size4KiB> {
+    /// The start of the range, inclusive.
+    pub start: PhysFrame<S>,
+    /// The end of the range, exclusive.
+    pub end: PhysFrame<S>,
+}
+
+impl<S: PageSize> PhysFrameRange<S> {
+    /// Returns whether the range contains no frames.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    /// Returns the number of frames in the range.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        if !self.is_empty() {
+            self.end - self.start
+        } else {
+            0
+        }
+    }
+
+    /// Returns the size in bytes of all frames within the range.
+    ///
+    /// A range over the whole address space is 2^64 bytes, one too many for a
+    /// `u64`: debug builds panic, release builds wrap, see
+    /// [`checked_size`](Self::checked_size).
+    #[inline]
+    pub fn size(&self) -> u64 {
+        debug_assert!(self.checked_size().is_some(), "size of {:?} overflows u64", self);
+        S::SIZE.wrapping_mul(self.len())
+    }
+
+    /// Returns the size in bytes of all frames within the range, `None` if it
+    /// does not fit in a `u64`.
+    #[inline]
+    pub fn checked_size(&self) -> Option<u64> {
+        S::SIZE.checked_mul(self.len())
+    }
+}
+
+impl<S: PageSize> Iterator for PhysFrameRange<S> {
+    type Item = PhysFrame<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start < self.end {
+            let frame = self.start;
+            self.start += 1;
+            Some(frame)
+        } else {
+            None
+        }
+    }
+}
+
+impl<S: PageSize> fmt::Debug for PhysFrameRange<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PhysFrameRange")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+/// An range of physical memory frames, inclusive the upper bound.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct PhysFrameRangeInclusive<S: PageSize = Size4KiB> {
+    /// The start of the range, inclusive.
+    pub start: PhysFrame<S>,
+    /// The start of the range, inclusive.
+    pub end: PhysFrame<S>,
+}
+
+impl<S: PageSize> PhysFrameRangeInclusive<S> {
+    /// Returns whether the range contains no frames.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start > self.end
+    }
+
+    /// Returns the number of frames in the range.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        if !self.is_empty() {
+            self.end - self.start + 1
+        } else {
+            0
+        }
+    }
+
+    /// Returns the size in bytes of all frames within the range.
+    ///
+    /// A range over the whole address space is 2^64 bytes, one too many for a
+    /// `u64`: debug builds panic, release builds wrap, see
+    /// [`checked_size`](Self::checked_size).
+    #[inline]
+    pub fn size(&self) -> u64 {
+        debug_assert!(self.checked_size().is_some(), "size of {:?} overflows u64", self);
+        S::SIZE.wrapping_mul(self.len())
+    }
+
+    /// Returns the size in bytes of all frames within the range, `None` if it
+    /// does not fit in a `u64`.
+    #[inline]
+    pub fn checked_size(&self) -> Option<u64> {
+        S::SIZE.checked_mul(self.len())
+    }
+}
+
+impl<S: PageSize> Iterator for PhysFrameRangeInclusive<S> {
+    type Item = PhysFrame<S>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start <= self.end {
+            let frame = self.start;
+            self.start += 1;
+            Some(frame)
+        } else {
+            None
+        }
+    }
+}
+
+impl<S: PageSize> fmt::Debug for PhysFrameRangeInclusive<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PhysFrameRangeInclusive")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
@@ -0,0 +1,279 @@
+//! Address spaces, one level 4 table each.
+//!
+//! The upper half, from [`KERNEL_HALF`] on, belongs to the kernel: a new address
+//! space copies those entries of the active level 4 table, so it shares every
+//! lower-level table of the kernel mappings, which [`crate::memory::init`]
+//! created up front. The lower half is the user half, empty at first and
+//! private to the address space.
+//!
+//! The user half helpers reach the tables through the physical memory window
+//! of [`crate::memory::init`].
+
+use core::fmt;
+use crate::memory::{
+    frame_allocator::{FrameAllocator, FrameDeallocator},
+    paging::{read_cr3, table_mut, table_ptr, table_ref, write_cr3, PageTable, PageTableEntry, PageTableFlags, PhysFrame, Size4KiB},
+    phys_mem_offset, KERNEL_HALF, USER_SPACE_END,
+};
+
+/// Flags of the tables leading to user pages; the level 1 entries restrict them.
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserMapError {
+    /// The address is not in the user half.
+    NotUser,
+    /// The page is mapped already.
+    AlreadyMapped,
+    /// No frame for a page table.
+    OutOfMemory,
+}
+
+impl fmt::Display for UserMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserMapError::NotUser => write!(f, "not a user address"),
+            UserMapError::AlreadyMapped => write!(f, "page mapped already"),
+            UserMapError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Returns the address space currently loaded in CR3.
+    pub fn current() -> Self {
+        AddressSpace { level_4_frame: PhysFrame::containing_address(read_cr3()) }
+    }
+
+    /// Allocates a level 4 table sharing the kernel half of the active one,
+    /// with an empty user half.
+    ///
+    /// Returns `None` if the allocator is out of frames.
+    ///
+    /// ## Safety
+    ///
+    /// The complete physical memory must be mapped at `phys_mem_offset`.
+    pub unsafe fn new<A>(allocator: &mut A, phys_mem_offset: u64) -> Option<Self>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let frame = allocator.allocate_frame()?;
+        let table = table_ptr(phys_mem_offset, frame);
+        unsafe {
+            table.write(PageTable::new());
+            let active = table_ref(phys_mem_offset, PhysFrame::containing_address(read_cr3()));
+            for (new, kernel) in (*table).iter_mut().zip(active.iter()).skip(KERNEL_HALF) {
+                *new = kernel.clone();
+            }
+        }
+        Some(AddressSpace { level_4_frame: frame })
+    }
+
+    /// Returns the frame of the level 4 table.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        read_cr3() == self.level_4_frame.start_address()
+    }
+
+    /// Loads this address space, returns whether CR3 was actually written.
+    ///
+    /// Switching to the address space that is already active is skipped, which
+    /// keeps the TLB when moving between users of the same address space.
+    ///
+    /// ## Safety
+    ///
+    /// The address space must map the running code and stack, which holds for
+    /// any address space created by [`AddressSpace::new`].
+    pub unsafe fn activate(&self) -> bool {
+        if self.is_active() {
+            return false;
+        }
+        unsafe { write_cr3(self.level_4_frame.start_address()); }
+        true
+    }
+
+    /// Returns the level 1 entry for the user page at `addr`, creating the
+    /// tables on the way with `allocator` if there is one.
+    ///
+    /// ## Safety
+    ///
+    /// Nothing else may access the tables of the address space meanwhile.
+    unsafe fn user_entry<'a, A>(&self, addr: u64, mut allocator: Option<&mut A>) -> Result<&'a mut PageTableEntry, UserMapError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        if addr >= USER_SPACE_END {
+            return Err(UserMapError::NotUser);
+        }
+        let offset = phys_mem_offset();
+        let mut table = unsafe { table_mut(offset, self.level_4_frame) };
+        for shift in [39, 30, 21] {
+            let entry = &mut table[((addr >> shift) & 0x1FF) as usize];
+            if entry.is_unused() {
+                let frame = allocator.as_deref_mut()
+                    .ok_or(UserMapError::OutOfMemory)?
+                    .allocate_frame()
+                    .ok_or(UserMapError::OutOfMemory)?;
+                unsafe { table_mut(offset, frame).zero(); }
+                entry.set_frame(frame.start_address(), USER_TABLE_FLAGS);
+            }
+            table = unsafe { table_mut(offset, PhysFrame::containing_address(entry.addr())) };
+        }
+        Ok(&mut table[((addr >> 12) & 0x1FF) as usize])
+    }
+
+    /// Maps the user page at `addr` to `frame`, accessible from ring 3 with `flags`.
+    ///
+    /// ## Safety
+    ///
+    /// `frame` must be unused or meant to be shared, and nothing else may
+    /// access the tables of the address space meanwhile.
+    pub unsafe fn map_user<A>(&self, addr: u64, frame: PhysFrame, flags: PageTableFlags, allocator: &mut A) -> Result<(), UserMapError>
+    where
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let entry = unsafe { self.user_entry(addr, Some(allocator))? };
+        if !entry.is_unused() {
+            return Err(UserMapError::AlreadyMapped);
+        }
+        entry.set_frame(frame.start_address(), flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE);
+        Ok(())
+    }
+
+    /// Returns the frame and the flags of the user page at `addr`, if mapped.
+    pub fn translate_user(&self, addr: u64) -> Option<(PhysFrame, PageTableFlags)> {
+        let entry = unsafe { self.user_entry::<dyn FrameAllocator<Size4KiB>>(addr, None) }.ok()?;
+        let flags = entry.flags();
+        flags.contains(PageTableFlags::PRESENT).then(|| (PhysFrame::containing_address(entry.addr()), flags))
+    }
+
+    /// Unmaps the user page at `addr` and returns its frame, flushing it from
+    /// the TLB if the address space is active. The tables stay.
+    ///
+    /// ## Safety
+    ///
+    /// Nothing may use the page any more, and nothing else may access the
+    /// tables of the address space meanwhile.
+    pub unsafe fn unmap_user(&self, addr: u64) -> Option<PhysFrame> {
+        let entry = unsafe { self.user_entry::<dyn FrameAllocator<Size4KiB>>(addr, None) }.ok()?;
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let frame = PhysFrame::containing_address(entry.addr());
+        entry.set_unused();
+        if self.is_active() {
+            flush_page(addr);
+        }
+        Some(frame)
+    }
+
+    /// Calls `f` with the address and the level 1 entry of every page mapped
+    /// in the user half, in address order.
+    ///
+    /// ## Safety
+    ///
+    /// Nothing else may access the tables of the address space meanwhile.
+    pub unsafe fn for_each_user_page(&self, mut f: impl FnMut(u64, &mut PageTableEntry)) {
+        let offset = phys_mem_offset();
+        let level_4 = unsafe { table_mut(offset, self.level_4_frame) };
+        for (i4, e4) in level_4.iter_mut().enumerate().take(KERNEL_HALF).filter(|(_, e)| !e.is_unused()) {
+            let level_3 = unsafe { table_mut(offset, PhysFrame::containing_address(e4.addr())) };
+            for (i3, e3) in level_3.iter_mut().enumerate().filter(|(_, e)| !e.is_unused()) {
+                let level_2 = unsafe { table_mut(offset, PhysFrame::containing_address(e3.addr())) };
+                for (i2, e2) in level_2.iter_mut().enumerate().filter(|(_, e)| !e.is_unused()) {
+                    let level_1 = unsafe { table_mut(offset, PhysFrame::containing_address(e2.addr())) };
+                    for (i1, e1) in level_1.iter_mut().enumerate() {
+                        if e1.flags().contains(PageTableFlags::PRESENT) {
+                            f((i4 << 39 | i3 << 30 | i2 << 21 | i1 << 12) as u64, e1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gives the tables of the user half and the level 4 table back to
+    /// `deallocator`, after `free_page` took care of the frame of each page
+    /// still mapped.
+    ///
+    /// ## Safety
+    ///
+    /// The address space must not be active on any CPU, and is not usable
+    /// afterwards.
+    pub unsafe fn destroy<D>(self, deallocator: &mut D, mut free_page: impl FnMut(&mut D, PhysFrame))
+    where
+        D: FrameDeallocator<Size4KiB> + ?Sized,
+    {
+        debug_assert!(!self.is_active(), "destroying the active address space");
+        unsafe { self.for_each_user_page(|_, entry| free_page(deallocator, PhysFrame::containing_address(entry.addr()))); }
+        let offset = phys_mem_offset();
+        let level_4 = unsafe { table_ref(offset, self.level_4_frame) };
+        for e4 in level_4.iter().take(KERNEL_HALF).filter(|e| !e.is_unused()) {
+            let l3_frame = PhysFrame::containing_address(e4.addr());
+            for e3 in unsafe { table_ref(offset, l3_frame) }.iter().filter(|e| !e.is_unused()) {
+                let l2_frame = PhysFrame::containing_address(e3.addr());
+                for e2 in unsafe { table_ref(offset, l2_frame) }.iter().filter(|e| !e.is_unused()) {
+                    unsafe { deallocator.deallocate_frame(PhysFrame::containing_address(e2.addr())); }
+                }
+                unsafe { deallocator.deallocate_frame(l2_frame); }
+            }
+            unsafe { deallocator.deallocate_frame(l3_frame); }
+        }
+        unsafe { deallocator.deallocate_frame(self.level_4_frame); }
+    }
+}
+
+/// Drops the TLB entry of the page at `addr`.
+pub fn flush_page(addr: u64) {
+    unsafe { core::arch::asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags)); }
+}
+
+#[test_case]
+fn activating_current_address_space_keeps_cr3() {
+    let current = AddressSpace::current();
+    assert!(current.is_active());
+    assert!(!unsafe { current.activate() });
+    assert_eq!(read_cr3(), current.level_4_frame().start_address());
+}
+
+#[test_case]
+fn new_address_space_shares_only_the_kernel_half() {
+    use crate::memory::FRAME_ALLOCATOR;
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let space = unsafe { AddressSpace::new(allocator, phys_mem_offset()) }.unwrap();
+    let table = unsafe { table_ref(phys_mem_offset(), space.level_4_frame()) };
+    let kernel = unsafe { table_ref(phys_mem_offset(), AddressSpace::current().level_4_frame()) };
+    assert!(table.iter().take(KERNEL_HALF).all(PageTableEntry::is_unused));
+    assert!(table.iter().zip(kernel.iter()).skip(KERNEL_HALF).all(|(new, kernel)| new.addr() == kernel.addr()));
+
+    let frame = allocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::WRITABLE;
+    assert_eq!(unsafe { space.map_user(0x40_0000, frame, flags, allocator) }, Ok(()));
+    assert_eq!(unsafe { space.map_user(0x40_0000, frame, flags, allocator) }, Err(UserMapError::AlreadyMapped));
+    assert_eq!(unsafe { space.map_user(USER_SPACE_END, frame, flags, allocator) }, Err(UserMapError::NotUser));
+    let (mapped, mapped_flags) = space.translate_user(0x40_0000).unwrap();
+    assert_eq!(mapped, frame);
+    assert!(mapped_flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    assert_eq!(space.translate_user(0x40_1000), None);
+    // Not seen from the kernel tables.
+    assert!(AddressSpace::current().translate_user(0x40_0000).is_none_or(|(other, _)| other != frame));
+
+    let free = allocator.free_frames();
+    let mut pages = 0;
+    unsafe { space.destroy(allocator, |allocator, frame| { pages += 1; allocator.deallocate_frame(frame) }); }
+    assert_eq!(pages, 1);
+    // The page, its level 3, 2 and 1 tables and the level 4 table.
+    assert_eq!(allocator.free_frames(), free + 5);
+}
@@ -0,0 +1,239 @@
+//! Frames shared between address spaces, and copy-on-write.
+//!
+//! A frame mapped in one address space has no entry in [`SHARED_FRAMES`]; a
+//! frame mapped in several has one, counting the mappings. [`release`] is how
+//! a mapping gives up its frame, which goes back to the allocator with the
+//! last one.
+//!
+//! Writable pages shared by [`clone_user_half`] lose `WRITABLE` and get
+//! [`COPY_ON_WRITE`] instead. The first write to one faults, and
+//! [`resolve_write_fault`] gives the writer a copy of its own, or the frame
+//! itself once nobody else maps it any more. Pages that were read-only to
+//! begin with, like code, are shared as they are.
+
+use crate::{
+    memory::{
+        address_space::{flush_page, AddressSpace, UserMapError},
+        frame_allocator::{FrameAllocator, FrameDeallocator},
+        paging::{PageTableFlags, PhysFrame, Size4KiB},
+        phys_mem_offset, PAGE_SIZE_4K,
+    },
+    sync::Mutex,
+};
+
+/// Marks a user page that was writable before it was shared, in one of the
+/// bits the CPU leaves to the kernel.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// The most frames that may be shared at once.
+pub const MAX_SHARED_FRAMES: usize = 1024;
+
+/// Frames mapped more than once, with the number of mappings.
+static SHARED_FRAMES: Mutex<[Option<(PhysFrame, usize)>; MAX_SHARED_FRAMES]> =
+    Mutex::new("SHARED_FRAMES", [None; MAX_SHARED_FRAMES]);
+
+/// Counts one more mapping of `frame`. Returns `false` if [`SHARED_FRAMES`]
+/// is full and `frame` was not shared yet.
+pub fn share(frame: PhysFrame) -> bool {
+    let mut shared = SHARED_FRAMES.lock();
+    if let Some((_, count)) = shared.iter_mut().flatten().find(|(shared, _)| *shared == frame) {
+        *count += 1;
+        return true;
+    }
+    match shared.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some((frame, 2));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns how many mappings `frame` has, counting an unshared one as 1.
+pub fn references(frame: PhysFrame) -> usize {
+    SHARED_FRAMES.lock().iter().flatten().find(|(shared, _)| *shared == frame).map_or(1, |&(_, count)| count)
+}
+
+/// Gives up a mapping of `frame`, and the frame itself to `deallocator` if it
+/// was the last one.
+///
+/// ## Safety
+///
+/// The mapping must be gone, and `frame` was handed out by `deallocator`.
+pub unsafe fn release<D>(deallocator: &mut D, frame: PhysFrame)
+where
+    D: FrameDeallocator<Size4KiB> + ?Sized,
+{
+    {
+        let mut shared = SHARED_FRAMES.lock();
+        if let Some(slot) = shared.iter_mut().find(|slot| slot.is_some_and(|(shared, _)| shared == frame)) {
+            let (_, count) = slot.as_mut().unwrap();
+            *count -= 1;
+            if *count == 1 {
+                *slot = None;
+            }
+            return;
+        }
+    }
+    unsafe { deallocator.deallocate_frame(frame); }
+}
+
+/// Maps every user page of `parent` at the same address in `child`, sharing
+/// the frames. Writable pages are mapped [`COPY_ON_WRITE`] in `child`;
+/// [`write_protect_user_half`] does the same to `parent` once the clone is
+/// kept.
+///
+/// On failure, the pages mapped so far stay in `child` for its
+/// [`AddressSpace::destroy`] to [`release`], and `parent` is unchanged.
+///
+/// ## Safety
+///
+/// Nothing else may access the tables of either address space meanwhile.
+pub unsafe fn clone_user_half<A>(parent: &AddressSpace, child: &AddressSpace, allocator: &mut A) -> Result<(), UserMapError>
+where
+    A: FrameAllocator<Size4KiB> + ?Sized,
+{
+    let mut result = Ok(());
+    unsafe {
+        parent.for_each_user_page(|addr, entry| {
+            if result.is_err() {
+                return;
+            }
+            let frame = PhysFrame::containing_address(entry.addr());
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            }
+            result = child.map_user(addr, frame, flags, allocator);
+            if result.is_ok() && !share(frame) {
+                child.unmap_user(addr);
+                result = Err(UserMapError::OutOfMemory);
+            }
+        });
+    }
+    result
+}
+
+/// Turns every writable user page of `space` [`COPY_ON_WRITE`].
+///
+/// ## Safety
+///
+/// Nothing else may access the tables of the address space meanwhile.
+pub unsafe fn write_protect_user_half(space: &AddressSpace) {
+    let active = space.is_active();
+    unsafe {
+        space.for_each_user_page(|addr, entry| {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                entry.set_flags((flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE);
+                if active {
+                    flush_page(addr);
+                }
+            }
+        });
+    }
+}
+
+/// Makes the [`COPY_ON_WRITE`] page at `addr` of `space` writable, copying it
+/// if it is still shared. Returns `false` if the page is not copy-on-write,
+/// or there is no frame to copy it to.
+///
+/// ## Safety
+///
+/// Nothing else may access the tables of the address space meanwhile.
+pub unsafe fn resolve_write_fault<A>(space: &AddressSpace, addr: u64, allocator: &mut A) -> bool
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + ?Sized,
+{
+    let page = addr & !(PAGE_SIZE_4K - 1);
+    let Some((frame, flags)) = space.translate_user(page) else {
+        return false;
+    };
+    if !flags.contains(COPY_ON_WRITE) {
+        return false;
+    }
+    let own = if references(frame) == 1 {
+        frame
+    } else {
+        let Some(copy) = allocator.allocate_frame() else {
+            return false;
+        };
+        let window = |frame: PhysFrame| phys_mem_offset() + frame.start_address();
+        unsafe {
+            core::ptr::copy_nonoverlapping(window(frame) as *const u8, window(copy) as *mut u8, PAGE_SIZE_4K as usize);
+        }
+        copy
+    };
+    unsafe {
+        space.unmap_user(page);
+        // The tables are there already, nothing to allocate.
+        space.map_user(page, own, (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE, allocator)
+            .expect("page vanished while resolving a copy-on-write fault");
+        if own != frame {
+            release(allocator, frame);
+        }
+    }
+    true
+}
+
+#[test_case]
+fn shared_frames_go_back_with_the_last_mapping() {
+    use crate::memory::FRAME_ALLOCATOR;
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let frame = allocator.allocate_frame().unwrap();
+    let used = allocator.used_frames();
+    // Shared by a fork, then by a fork of the fork.
+    assert!(share(frame));
+    assert!(share(frame));
+    assert_eq!(references(frame), 3);
+    unsafe { release(allocator, frame); }
+    unsafe { release(allocator, frame); }
+    assert_eq!(references(frame), 1);
+    assert_eq!(allocator.used_frames(), used);
+    unsafe { release(allocator, frame); }
+    assert_eq!(allocator.used_frames(), used - 1);
+}
+
+#[test_case]
+fn cloned_pages_are_copied_on_the_first_write() {
+    use crate::memory::FRAME_ALLOCATOR;
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let used = allocator.used_frames();
+    let parent = unsafe { AddressSpace::new(allocator, phys_mem_offset()) }.unwrap();
+    let child = unsafe { AddressSpace::new(allocator, phys_mem_offset()) }.unwrap();
+    let (data, code) = (allocator.allocate_frame().unwrap(), allocator.allocate_frame().unwrap());
+    unsafe {
+        ((phys_mem_offset() + data.start_address()) as *mut u64).write(7);
+        parent.map_user(0x60_0000, data, PageTableFlags::WRITABLE, allocator).unwrap();
+        parent.map_user(0x40_0000, code, PageTableFlags::empty(), allocator).unwrap();
+        clone_user_half(&parent, &child, allocator).unwrap();
+        write_protect_user_half(&parent);
+    }
+    for space in [&parent, &child] {
+        let (frame, flags) = space.translate_user(0x60_0000).unwrap();
+        assert_eq!(frame, data);
+        assert!(flags.contains(COPY_ON_WRITE) && !flags.contains(PageTableFlags::WRITABLE));
+        let (frame, flags) = space.translate_user(0x40_0000).unwrap();
+        assert_eq!(frame, code);
+        assert!(!flags.intersects(COPY_ON_WRITE | PageTableFlags::WRITABLE));
+    }
+    assert!(!unsafe { resolve_write_fault(&child, 0x40_0000, allocator) });
+
+    // The child gets a copy, the parent then keeps the frame, nobody else has it.
+    assert!(unsafe { resolve_write_fault(&child, 0x60_0008, allocator) });
+    let (copy, flags) = child.translate_user(0x60_0000).unwrap();
+    assert_ne!(copy, data);
+    assert!(flags.contains(PageTableFlags::WRITABLE) && !flags.contains(COPY_ON_WRITE));
+    assert_eq!(unsafe { ((phys_mem_offset() + copy.start_address()) as *const u64).read() }, 7);
+    assert!(unsafe { resolve_write_fault(&parent, 0x60_0000, allocator) });
+    assert_eq!(parent.translate_user(0x60_0000).unwrap().0, data);
+    assert_eq!(references(data), 1);
+
+    for space in [parent, child] {
+        unsafe { space.destroy(allocator, |allocator, frame| release(allocator, frame)); }
+    }
+    assert_eq!(allocator.used_frames(), used);
+}
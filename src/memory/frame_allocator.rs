@@ -1,6 +1,7 @@
 //! Traits for abstracting away frame allocation and deallocation.
 
-use crate::memory::paging::{PageSize, PhysFrame};
+use core::fmt;
+use crate::memory::{paging::{PageSize, PhysFrame, Size4KiB}, phys_mem_offset, MemoryRegion, MemoryRegionKind};
 
 /// A trait for types that can allocate a frame of memory.
 ///
@@ -23,3 +24,181 @@ pub trait FrameDeallocator<S: PageSize> {
     /// The caller must ensure that the passed frame is unused.
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>);
 }
+
+/// The most ranges [`MemoryMapFrameAllocator::reserve`] takes.
+const MAX_RESERVED: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReserveError {
+    /// All [`MAX_RESERVED`] ranges are taken.
+    Full,
+    /// A frame of the range was handed out already.
+    InUse,
+}
+
+impl fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReserveError::Full => write!(f, "too many reserved ranges"),
+            ReserveError::InUse => write!(f, "frames already allocated"),
+        }
+    }
+}
+
+/// Hands out the usable frames of the boot memory map, one after the other,
+/// except for the reserved ranges.
+///
+/// Frames given back go on a free list, threaded through the frames themselves
+/// in the physical memory window, and are handed out again first.
+pub struct MemoryMapFrameAllocator {
+    memory_map: &'static [MemoryRegion],
+    next: usize,
+    /// Physical ranges `start..end`, page aligned, that are never handed out.
+    reserved: [(u64, u64); MAX_RESERVED],
+    reserved_len: usize,
+    /// The last frame given back, whose first word holds the one before.
+    free: Option<PhysFrame>,
+    free_len: usize,
+}
+
+impl MemoryMapFrameAllocator {
+    /// ## Safety
+    ///
+    /// Every frame marked `Usable` in `memory_map` must really be unused.
+    pub unsafe fn init(memory_map: &'static [MemoryRegion]) -> Self {
+        MemoryMapFrameAllocator {
+            memory_map,
+            next: 0,
+            reserved: [(0, 0); MAX_RESERVED],
+            reserved_len: 0,
+            free: None,
+            free_len: 0,
+        }
+    }
+
+    /// Returns the next frame of the memory map, skipping the free list, so
+    /// that frames taken one after the other are contiguous up to the end of a
+    /// region.
+    pub fn allocate_frame_in_order(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next)?;
+        self.next += 1;
+        Some(frame)
+    }
+
+    /// Returns how many frames are handed out and not given back.
+    pub fn used_frames(&self) -> usize {
+        self.next - self.free_len
+    }
+
+    /// Returns how many frames given back wait on the free list.
+    pub fn free_frames(&self) -> usize {
+        self.free_len
+    }
+
+    /// Keeps the frames of `start..end` from being handed out, for memory the
+    /// bootloader left data in, like an initial RAM disk.
+    pub fn reserve(&mut self, start: u64, end: u64) -> Result<(), ReserveError> {
+        let range = (start & !0xFFF, end.next_multiple_of(4096));
+        if self.reserved_len == MAX_RESERVED {
+            return Err(ReserveError::Full);
+        }
+        if self.usable_frames().take(self.next).any(|frame| (range.0..range.1).contains(&frame.start_address())) {
+            return Err(ReserveError::InUse);
+        }
+        // Frames before `next` that the range takes out of the list are only
+        // skipped, never handed out twice.
+        self.reserved[self.reserved_len] = range;
+        self.reserved_len += 1;
+        Ok(())
+    }
+
+    fn is_reserved(&self, addr: u64) -> bool {
+        self.reserved[..self.reserved_len].iter().any(|&(start, end)| (start..end).contains(&addr))
+    }
+
+    /// The boot memory map the frames come from.
+    pub fn memory_map(&self) -> &'static [MemoryRegion] {
+        self.memory_map
+    }
+
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.memory_map
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            // Other bootloaders may report regions that are not page aligned.
+            .map(|region| region.start.next_multiple_of(4096)..region.end & !0xFFF)
+            .flat_map(|range| range.step_by(4096))
+            .filter(|&addr| !self.is_reserved(addr))
+            .map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for MemoryMapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let Some(frame) = self.free else {
+            return self.allocate_frame_in_order();
+        };
+        let link = (phys_mem_offset() + frame.start_address()) as *const u64;
+        let next = unsafe { link.read() };
+        self.free = (next != FREE_LIST_END).then(|| PhysFrame::containing_address(next));
+        self.free_len -= 1;
+        Some(frame)
+    }
+}
+
+/// Ends the free list; frame 0 would be a valid link.
+const FREE_LIST_END: u64 = u64::MAX;
+
+impl FrameDeallocator<Size4KiB> for MemoryMapFrameAllocator {
+    /// Puts `frame` on the free list, writing the link into it through the
+    /// physical memory window set up by [`crate::memory::init`].
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let link = (phys_mem_offset() + frame.start_address()) as *mut u64;
+        unsafe { link.write(self.free.map_or(FREE_LIST_END, |free| free.start_address())); }
+        self.free = Some(frame);
+        self.free_len += 1;
+    }
+}
+
+#[test_case]
+fn reserved_frames_are_never_handed_out() {
+    static MAP: [MemoryRegion; 3] = [
+        MemoryRegion { start: 0x10_0000, end: 0x10_8000, kind: MemoryRegionKind::Usable },
+        MemoryRegion { start: 0x10_8000, end: 0x20_0000, kind: MemoryRegionKind::Reserved },
+        MemoryRegion { start: 0x20_0000, end: 0x20_4000, kind: MemoryRegionKind::Usable },
+    ];
+    // Only addresses are computed, none of the frames is touched.
+    let mut allocator = unsafe { MemoryMapFrameAllocator::init(&MAP) };
+    assert_eq!(allocator.allocate_frame().map(|frame| frame.start_address()), Some(0x10_0000));
+    assert_eq!(allocator.reserve(0x10_0800, 0x10_1000), Err(ReserveError::InUse));
+    // An image from the middle of one frame into the next.
+    assert_eq!(allocator.reserve(0x10_2800, 0x10_3001), Ok(()));
+    assert_eq!(allocator.reserve(0x20_1000, 0x20_2000), Ok(()));
+
+    let mut frames = [0; 16];
+    let mut count = 0;
+    while let Some(frame) = allocator.allocate_frame() {
+        frames[count] = frame.start_address();
+        count += 1;
+    }
+    assert_eq!(frames[..count], [0x10_1000, 0x10_5000, 0x10_6000, 0x10_7000, 0x20_0000, 0x20_2000, 0x20_3000]);
+}
+
+#[test_case]
+fn deallocated_frames_are_handed_out_again() {
+    use crate::memory::FRAME_ALLOCATOR;
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let free = allocator.free_frames();
+    let (first, second) = (allocator.allocate_frame().unwrap(), allocator.allocate_frame().unwrap());
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+    assert_eq!(allocator.free_frames(), free + 2);
+    // Last in, first out.
+    assert_eq!(allocator.allocate_frame(), Some(second));
+    assert_eq!(allocator.allocate_frame(), Some(first));
+    assert_eq!(allocator.free_frames(), free);
+}
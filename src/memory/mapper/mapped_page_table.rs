@@ -776,7 +776,7 @@ impl<P: PageTableFrameMapping> PageTableWalker<P> {
             }
         } else {
             if !insert_flags.is_empty() && !entry.flags().contains(insert_flags) {
-                entry.set_flags(entry.flags() | insert_flags);
+                entry.set_flags_bits(insert_flags);
             }
             created = false;
         }
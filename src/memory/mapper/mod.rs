@@ -9,7 +9,7 @@ use crate::memory::{
         PageRangeInclusive,
         PageTableFlags,
         Page, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
-        VirtAddr,
+        VirtAddr, is_canonical,
     },
     frame_allocator::{FrameAllocator, FrameDeallocator,},
 };
@@ -187,6 +187,8 @@ pub trait Mapper<S: PageSize> {
         Self: Sized,
         A: FrameAllocator<Size4KiB> + ?Sized,
     {
+        // The table indices would silently map the sign extended address instead.
+        assert!(is_canonical(page.start_address()), "mapping non-canonical page {:#x}", page.start_address());
         let parent_table_flags = flags
             & (PageTableFlags::PRESENT
                 | PageTableFlags::WRITABLE
@@ -383,10 +385,6 @@ pub trait Mapper<S: PageSize> {
 /// changed the mapping of a page to ensure that the TLB flush is not forgotten.
 #[derive(Debug)]
 #[must_use = "Page Table changes must be flushed or ignored."]
-#[cfg_attr(
-    not(all(feature = "instructions", target_arch = "x86_64")),
-    allow(dead_code)
-)] // FIXME
 pub struct MapperFlush<S: PageSize>(Page<S>);
 
 impl<S: PageSize> MapperFlush<S> {
@@ -400,10 +398,11 @@ impl<S: PageSize> MapperFlush<S> {
     }
 
     /// Flush the page from the TLB to ensure that the newest mapping is used.
-    #[cfg(all(feature = "instructions", target_arch = "x86_64"))]
     #[inline]
     pub fn flush(self) {
-        crate::instructions::tlb::flush(self.0.start_address());
+        unsafe {
+            core::arch::asm!("invlpg [{}]", in(reg) self.0.start_address(), options(nostack, preserves_flags));
+        }
     }
 
     /// Don't flush the TLB and silence the “must be used” warning.
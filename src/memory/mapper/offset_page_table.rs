@@ -1,6 +1,6 @@
 #![cfg(target_pointer_width = "64")]
 
-use crate::memory::{mapper::*, paging::PageTable};
+use crate::memory::{mapper::*, paging::{table_ptr, PageTable}};
 
 /// A Mapper implementation that requires that the complete physically memory is mapped at some
 /// offset in the virtual address space.
@@ -49,8 +49,80 @@ impl<'a> OffsetPageTable<'a> {
     pub fn phys_offset(&self) -> u64 {
         self.inner.page_table_frame_mapping().offset
     }
+
+    /// Maps `frame` at the virtual address equal to its physical address.
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`Mapper::map_to`].
+    pub unsafe fn identity_map(
+        &mut self,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+        let page = Page::containing_address(frame.start_address());
+        unsafe { self.map_to(page, frame, flags, frame_allocator) }
+    }
+
+    /// Identity maps the frames `start..=end`, flushing each page.
+    ///
+    /// Frames that are already identity mapped are left as they are, so a range
+    /// the bootloader mapped before, like the VGA buffer, can be passed too.
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`Mapper::map_to`].
+    pub unsafe fn identity_map_range(
+        &mut self,
+        start: PhysFrame<Size4KiB>,
+        end: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        for frame in PhysFrame::range_inclusive(start, end) {
+            match unsafe { self.identity_map(frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns where physical address `addr` is mapped, for a `T` access.
+    fn phys_ptr<T>(&self, addr: u64) -> *mut T {
+        let size = core::mem::size_of::<T>() as u64;
+        assert!(addr % core::mem::align_of::<T>() as u64 == 0, "unaligned physical access at {:#x}", addr);
+        assert!(addr.checked_add(size).is_some_and(|end| end <= MAX_PHYS_ADDR),
+            "physical access at {:#x} out of range", addr);
+        (self.phys_offset() + addr) as *mut T
+    }
+
+    /// Reads the `T` at physical address `addr` through the offset mapping.
+    ///
+    /// ## Safety
+    ///
+    /// `addr` must be backed by memory or registers where reading a `T` has no
+    /// unwanted side effects and yields a valid value.
+    pub unsafe fn read_phys<T: Copy>(&self, addr: u64) -> T {
+        unsafe { self.phys_ptr::<T>(addr).read_volatile() }
+    }
+
+    /// Writes `value` at physical address `addr` through the offset mapping.
+    ///
+    /// ## Safety
+    ///
+    /// Nothing else may be using the memory at `addr`, e.g. the kernel image
+    /// or a page table.
+    pub unsafe fn write_phys<T>(&mut self, addr: u64, value: T) {
+        unsafe { self.phys_ptr::<T>(addr).write_volatile(value) }
+    }
 }
 
+/// Physical addresses are at most 52 bits wide.
+const MAX_PHYS_ADDR: u64 = 1 << 52;
+
 #[derive(Debug)]
 struct PhysOffset {
     offset: u64,
@@ -58,8 +130,7 @@ struct PhysOffset {
 
 unsafe impl PageTableFrameMapping for PhysOffset {
     fn frame_to_pointer(&self, frame: PhysFrame) -> *mut PageTable {
-        let virt = self.offset + frame.start_address();
-        virt as *mut PageTable
+        table_ptr(self.offset, frame)
     }
 }
 
@@ -299,3 +370,43 @@ impl CleanUp for OffsetPageTable<'_> {
         unsafe { self.inner.clean_up_addr_range(range, frame_deallocator) }
     }
 }
+
+#[test_case]
+fn identity_map_translates_to_itself() {
+    use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+
+    // The I/O APIC, far from anything the kernel maps in the lower half.
+    let frame = PhysFrame::<Size4KiB>::containing_address(0xFEC0_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+
+    unsafe { mapper.identity_map(frame, flags, allocator) }.unwrap().flush();
+    assert_eq!(mapper.translate_addr(frame.start_address()), Some(frame.start_address()));
+    assert_eq!(mapper.translate_addr(frame.start_address() + 0x10), Some(frame.start_address() + 0x10));
+    // Mapping the range again keeps the existing identity mapping.
+    assert!(unsafe { mapper.identity_map_range(frame, frame, flags, allocator) }.is_ok());
+
+    let page = Page::<Size4KiB>::containing_address(frame.start_address());
+    mapper.unmap(page).unwrap().1.flush();
+    assert_eq!(mapper.translate_addr(frame.start_address()), None);
+}
+
+#[test_case]
+fn read_write_phys_round_trip() {
+    use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+
+    let frame = FRAME_ALLOCATOR.lock().as_mut().unwrap().allocate_frame().unwrap();
+    let addr = frame.start_address() + 0x18;
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    unsafe {
+        mapper.write_phys(addr, 0x0123_4567_89AB_CDEFu64);
+        assert_eq!(mapper.read_phys::<u64>(addr), 0x0123_4567_89AB_CDEF);
+        assert_eq!(mapper.read_phys::<u32>(addr + 4), 0x0123_4567);
+        // The same memory as seen through the offset mapping by hand.
+        assert_eq!(*((mapper.phys_offset() + addr) as *const u64), 0x0123_4567_89AB_CDEF);
+    }
+}
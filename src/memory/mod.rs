@@ -1,3 +1,142 @@
+pub mod addr;
 pub mod paging;
 pub mod mapper;
 pub mod frame_allocator;
+pub mod address_space;
+pub mod cow;
+pub mod pat;
+
+pub use addr::{huge_page_offset, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K};
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use frame_allocator::{FrameAllocator, MemoryMapFrameAllocator};
+use mapper::OffsetPageTable;
+use paging::{PageTableFlags, PhysFrame};
+use crate::{cpu::{cpuid, Msr}, sync::Mutex};
+
+/// Physical address of the VGA text buffer.
+const VGA_TEXT_BUFFER: u64 = 0xB8000;
+const IA32_EFER: u32 = 0xC000_0080;
+/// IA32_EFER bit enabling [`PageTableFlags::NO_EXECUTE`].
+const EFER_NXE: u64 = 1 << 11;
+/// `cpuid` leaf 0x8000_0001, EDX: the CPU has the no-execute bit.
+const CPUID_NX: u32 = 1 << 20;
+
+/// The first level 4 entry of the kernel half, from `0xFFFF_8000_0000_0000` on.
+///
+/// The kernel image, its stacks and the physical memory window are all mapped
+/// there, and the entries are shared by every [`address_space::AddressSpace`];
+/// the entries below are the user half, private to each address space.
+pub const KERNEL_HALF: usize = 256;
+/// The end of the user half.
+pub const USER_SPACE_END: u64 = 1 << 47;
+
+/// Where the physical memory is mapped, set by [`init`].
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Whether [`init`] could enable the no-execute bit.
+static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+
+/// The kernel page tables, set up by [`init`].
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new("MAPPER", None);
+/// The frame allocator, set up by [`init`].
+pub static FRAME_ALLOCATOR: Mutex<Option<MemoryMapFrameAllocator>> = Mutex::new("FRAME_ALLOCATOR", None);
+
+/// What a range of physical memory is, numbered like the Multiboot2 memory map types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MemoryRegionKind {
+    /// Free for the kernel to allocate.
+    Usable          = 1,
+    Reserved        = 2,
+    /// Holds the ACPI tables, usable once they have been read.
+    AcpiReclaimable = 3,
+    /// Must be preserved across sleep states.
+    AcpiNvs         = 4,
+    BadMemory       = 5,
+}
+
+/// A physical memory range from the boot memory map, `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    pub const EMPTY: Self = MemoryRegion { start: 0, end: 0, kind: MemoryRegionKind::Reserved };
+}
+
+/// Sets up [`MAPPER`] and [`FRAME_ALLOCATOR`] from the boot memory map.
+///
+/// ## Safety
+///
+/// Must be called once. The complete physical memory must be mapped at
+/// `physical_memory_offset`, and the `Usable` regions of `memory_map` must
+/// really be unused.
+pub unsafe fn init(physical_memory_offset: u64, memory_map: &'static [MemoryRegion]) {
+    PHYS_MEM_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+    enable_no_execute();
+    let mut mapper = unsafe { paging::init(physical_memory_offset) };
+    let mut allocator = unsafe { MemoryMapFrameAllocator::init(memory_map) };
+    // The VGA writer uses the text buffer at its physical address until it
+    // moves to the window.
+    let vga = PhysFrame::containing_address(VGA_TEXT_BUFFER);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { mapper.identity_map_range(vga, vga, flags, &mut allocator) }
+        .expect("cannot identity map the VGA buffer");
+    crate::vga::map_text_buffer(physical_memory_offset);
+
+    // Every kernel half entry gets its level 3 table now, so that mappings the
+    // kernel adds later land in tables the address spaces already share.
+    for entry in mapper.level_4_table_mut().iter_mut().skip(KERNEL_HALF).filter(|entry| entry.is_unused()) {
+        let frame = allocator.allocate_frame().expect("no frame for the kernel half tables");
+        unsafe { paging::table_mut(physical_memory_offset, frame).zero(); }
+        entry.set_frame(frame.start_address(), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Returns where the complete physical memory is mapped.
+pub fn phys_mem_offset() -> u64 {
+    PHYS_MEM_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Returns [`PageTableFlags::NO_EXECUTE`] if the CPU honors it, and no flag
+/// otherwise: the bit is reserved with EFER.NXE clear, and faults.
+pub fn no_execute() -> PageTableFlags {
+    if NO_EXECUTE.load(Ordering::Relaxed) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Sets EFER.NXE unless the bootloader did already.
+fn enable_no_execute() {
+    if cpuid(0x8000_0000, 0).eax < 0x8000_0001 || cpuid(0x8000_0001, 0).edx & CPUID_NX == 0 {
+        return;
+    }
+    let mut efer = Msr::new(IA32_EFER);
+    unsafe {
+        let value = efer.read();
+        if value & EFER_NXE == 0 {
+            efer.write(value | EFER_NXE);
+        }
+    }
+    NO_EXECUTE.store(true, Ordering::Relaxed);
+}
+
+#[test_case]
+fn kernel_runs_in_the_upper_half() {
+    let code = init as *const () as u64;
+    let stack = &code as *const u64 as u64;
+    for addr in [code, stack, phys_mem_offset()] {
+        assert!(addr >= paging::canonicalize(USER_SPACE_END), "{:#x} is in the user half", addr);
+    }
+    // Nothing of the kernel half is left to be created after boot.
+    let level_4 = unsafe { paging::table_ref(phys_mem_offset(), PhysFrame::containing_address(paging::read_cr3())) };
+    assert!(level_4.iter().skip(KERNEL_HALF).all(|entry| entry.flags().contains(PageTableFlags::PRESENT)));
+}
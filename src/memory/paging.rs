@@ -3,22 +3,40 @@
 #![cfg(target_pointer_width = "64")]
 use core::fmt;
 use core::ops::{Index, IndexMut};
-use crate::memory::mapper::OffsetPageTable;
+use core::sync::atomic::AtomicU64;
+use crate::memory::{
+    frame_allocator::FrameAllocator,
+    mapper::{MapToError, Mapper, OffsetPageTable, UnmapError},
+};
+use crate::tables::without_interrupts;
+use super::addr::ENTRY_COUNT;
+pub use super::addr::{
+    canonical_range, canonicalize, is_canonical, AddressNotAligned, Page, PageRangeInclusive, PageSize, PageTableIndex, PageTableLevel,
+    PhysAddr, PhysFrame, Size1GiB, Size2MiB, Size4KiB, VirtAddr,
+};
+use super::{huge_page_offset, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K};
 
 use bitflags::bitflags;
 
-const PAGE_4KB_SIZE: u64 = 0x1000;
-const PAGE_2MB_SIZE: u64 = 0x200000;
-const PAGE_1GB_SIZE: u64 = 0x40000000;
-const ADDRESS_SPACE_SIZE: u64 = 0x1_0000_0000_0000;
-
-fn read_cr3() -> u64 {
+pub(crate) fn read_cr3() -> u64 {
     use core::arch::asm;
     unsafe {
         let mut frame: u64;
         asm!("mov {}, cr3", out(reg) frame, options(nomem, nostack, preserves_flags));
         frame &= 0x_000f_ffff_ffff_f000;
-        frame.align_down(PAGE_4KB_SIZE)
+        frame.align_down(PAGE_SIZE_4K)
+    }
+}
+
+/// Loads `frame` as the level 4 table, flushing all non-global TLB entries.
+///
+/// ## Safety
+///
+/// `frame` must hold a valid level 4 table mapping the running code and stack.
+pub(crate) unsafe fn write_cr3(frame: u64) {
+    use core::arch::asm;
+    unsafe {
+        asm!("mov cr3, {}", in(reg) frame, options(nostack, preserves_flags));
     }
 }
 
@@ -29,10 +47,105 @@ pub unsafe fn init(physical_memory_offset: u64) -> OffsetPageTable<'static> {
 
 pub unsafe fn active_level_4_table(phys_mem_offset: u64) -> &'static mut PageTable {
     let phys = read_cr3();
-    let virt = phys + phys_mem_offset;
-    let page_table_ptr: *mut PageTable = virt as *mut PageTable;
+    if cfg!(debug_assertions) {
+        check_physical_memory_window(phys, phys_mem_offset);
+    }
+    table_mut(phys_mem_offset, PhysFrame::containing_address(phys))
+}
+
+/// Returns where the page table in `frame` is in the physical memory window at `offset`.
+pub(crate) fn table_ptr(offset: u64, frame: PhysFrame) -> *mut PageTable {
+    let virt = offset.checked_add(frame.start_address());
+    debug_assert!(virt.is_some(), "physical memory offset {:#x} overflows at frame {:#x}", offset, frame.start_address());
+    let virt = virt.unwrap_or_default();
+    debug_assert!(is_canonical(virt),
+        "page table frame {:#x} is at non-canonical {:#x}", frame.start_address(), virt);
+    virt as *mut PageTable
+}
+
+/// Returns the page table in `frame` through the physical memory window at `offset`.
+///
+/// ## Safety
+///
+/// The complete physical memory must be mapped at `offset`, `frame` must hold a
+/// page table, and nothing may change it through another reference meanwhile.
+pub unsafe fn table_ref<'a>(offset: u64, frame: PhysFrame) -> &'a PageTable {
+    unsafe { &*table_ptr(offset, frame) }
+}
 
-    &mut *page_table_ptr
+/// Mutable variant of [`table_ref`].
+///
+/// ## Safety
+///
+/// As for [`table_ref`], and there must be no other reference to the table.
+pub unsafe fn table_mut<'a>(offset: u64, frame: PhysFrame) -> &'a mut PageTable {
+    unsafe { &mut *table_ptr(offset, frame) }
+}
+
+/// Where the page fault handler resumes when a [`probe_read`] faults, 0 outside of one.
+pub(crate) static PROBE_FIXUP: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether the byte at `addr` can be read, catching the page fault if not.
+pub fn probe_read(addr: u64) -> bool {
+    // An interrupt handler faulting in the middle would take the fixup.
+    let readable: u64 = without_interrupts(|| unsafe {
+        let readable: u64;
+        core::arch::asm!(
+            "lea {tmp}, [rip + 2f]",
+            "mov [{fixup}], {tmp}",
+            "mov {tmp:l}, byte ptr [{addr}]",
+            "mov {readable:e}, 1",
+            "jmp 3f",
+            "2:",
+            "xor {readable:e}, {readable:e}",
+            "3:",
+            "mov qword ptr [{fixup}], 0",
+            fixup = in(reg) PROBE_FIXUP.as_ptr(),
+            addr = in(reg) addr,
+            tmp = out(reg) _,
+            readable = out(reg) readable,
+            options(nostack),
+        );
+        readable
+    });
+    readable != 0
+}
+
+/// Panics with a clear message unless `phys_mem_offset + l4_phys` maps the level 4
+/// table at `l4_phys`, instead of faulting on a wild pointer later.
+///
+/// The walk reads every table through the physical memory window, probing each
+/// one first, and follows 2MiB and 1GiB pages, which bootloaders like to use for
+/// the window.
+fn check_physical_memory_window(l4_phys: u64, phys_mem_offset: u64) {
+    let virt = l4_phys.wrapping_add(phys_mem_offset);
+    assert!(phys_mem_offset.is_aligned(PAGE_SIZE_4K),
+        "physical memory offset {:#x} is not page aligned", phys_mem_offset);
+    assert!(is_canonical(virt),
+        "physical memory offset {:#x} puts the level 4 table at non-canonical {:#x}", phys_mem_offset, virt);
+
+    let indexes = [virt.p4_index(), virt.p3_index(), virt.p2_index(), virt.p1_index()];
+    let page_sizes = [0, PAGE_SIZE_1G, PAGE_SIZE_2M, PAGE_SIZE_4K];
+    let mut table_phys = l4_phys;
+    for (level, (&index, &page_size)) in indexes.iter().zip(page_sizes.iter()).enumerate() {
+        let table_virt = table_phys.wrapping_add(phys_mem_offset);
+        assert!(probe_read(table_virt),
+            "physical memory offset {:#x} is wrong: level {} table {:#x} is not mapped at {:#x}",
+            phys_mem_offset, 4 - level, table_phys, table_virt);
+        let table = unsafe { table_ref(phys_mem_offset, PhysFrame::containing_address(table_phys)) };
+        let entry = &table[index];
+        assert!(entry.flags().contains(PageTableFlags::PRESENT),
+            "physical memory offset {:#x} is wrong: {:#x} is not mapped", phys_mem_offset, virt);
+        let huge = level == 1 || level == 2;
+        if level == 3 || (huge && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+            let mapped = entry.addr().align_down(page_size) + huge_page_offset(virt, page_size);
+            assert!(mapped == l4_phys,
+                "physical memory offset {:#x} is wrong: {:#x} maps {:#x} instead of the level 4 table at {:#x}",
+                phys_mem_offset, virt, mapped, l4_phys);
+            return;
+        }
+        table_phys = entry.addr().align_down(PAGE_SIZE_4K);
+    }
 }
 
 pub unsafe fn translate_addr(addr: u64, phys_mem_offset: u64) -> Option<u64> {
@@ -41,21 +154,28 @@ pub unsafe fn translate_addr(addr: u64, phys_mem_offset: u64) -> Option<u64> {
 
 pub unsafe fn inner_translate_addr(addr: u64, phys_mem_offset: u64) -> Option<u64> {
     let mut frame = read_cr3();
+    // From the top: the level 4 table at CR3 down to the level 1 table.
     let table_indexes = [
-        addr.p1_index(), addr.p2_index(), addr.p3_index(), addr.p4_index()
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
     ];
-    for &index in &table_indexes {
+    for (i, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
-        let virt = phys_mem_offset + frame;
-        let table_ptr: *const PageTable = virt as *const PageTable;
-        let table = unsafe {&*table_ptr};
+        let table = unsafe { table_ref(phys_mem_offset, PhysFrame::containing_address(frame)) };
 
         // read the page table entry and update `frame`
         let entry = &table[index];
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            Err(FrameError::HugeFrame) => {
+                // A huge entry in the level 3 table maps 1GiB, in the level 2 table 2MiB.
+                let page_size = match i {
+                    1 => PAGE_SIZE_1G,
+                    2 => PAGE_SIZE_2M,
+                    _ => panic!("huge page bit set in a level {} table", 4 - i),
+                };
+                return Some(entry.addr().align_down(page_size) + huge_page_offset(addr, page_size));
+            },
         };
     }
 
@@ -63,110 +183,63 @@ pub unsafe fn inner_translate_addr(addr: u64, phys_mem_offset: u64) -> Option<u6
     Some(frame + u64::from(addr.page_offset()))
 }
 
-pub trait VirtAddr {
-    fn page_offset(self) -> PageOffset;
-    fn p4_index(self) -> PageTableIndex;
-    fn p3_index(self) -> PageTableIndex;
-    fn p2_index(self) -> PageTableIndex;
-    fn p1_index(self) -> PageTableIndex;
-    fn page_table_index(self, level: PageTableLevel) -> PageTableIndex;
-    fn new_virt_truncate(addr: u64) -> u64;
-    fn forward_checked_u64(start: Self, count: u64) -> Option<Self> where Self: Sized;
-    fn forward_checked_impl(start: Self, count: usize) -> Option<Self> where Self: Sized;
-}
-
-impl VirtAddr for u64 {
-    #[inline]
-    fn page_offset(self) -> PageOffset {
-        PageOffset::new_truncate(self as u16)
-    }
-    #[inline]
-    fn p1_index(self) -> PageTableIndex {
-        PageTableIndex::new_truncate((self >> 12) as u16)
-    }
-
-    /// Returns the 9-bit level 2 page table index.
-    #[inline]
-    fn p2_index(self) -> PageTableIndex {
-        PageTableIndex::new_truncate((self >> 12 >> 9) as u16)
-    }
-
-    /// Returns the 9-bit level 3 page table index.
-    #[inline]
-    fn p3_index(self) -> PageTableIndex {
-        PageTableIndex::new_truncate((self >> 12 >> 9 >> 9) as u16)
-    }
-
-    /// Returns the 9-bit level 4 page table index.
-    #[inline]
-    fn p4_index(self) -> PageTableIndex {
-        PageTableIndex::new_truncate((self >> 12 >> 9 >> 9 >> 9) as u16)
-    }
-    #[inline]
-    fn new_virt_truncate(addr: u64) -> u64 {
-        // By doing the right shift as a signed operation (on a i64), it will
-        // sign extend the value, repeating the leftmost bit.
-        ((addr << 16) as i64 >> 16) as u64
-    }
-
-    fn page_table_index(self, level: PageTableLevel) -> PageTableIndex {
-        match level {
-            PageTableLevel::One => self.p1_index(),
-            PageTableLevel::Two => self.p2_index(),
-            PageTableLevel::Three => self.p3_index(),
-            PageTableLevel::Four => self.p4_index(),
-        } 
-    }
-
-    #[inline]
-    fn forward_checked_impl(start: Self, count: usize) -> Option<Self> {
-        Self::forward_checked_u64(start, u64::try_from(count).ok()?)
-    }
-
-    /// An implementation of forward_checked that takes u64 instead of usize.
-    #[inline]
-    fn forward_checked_u64(start: Self, count: u64) -> Option<Self> {
-        if count > ADDRESS_SPACE_SIZE {
-            return None;
-        }
-
-        let mut addr = start.checked_add(count)?;
-
-        match addr & 0xFFFF800000000000 {
-            0x1 => {
-                // Jump the gap by sign extending the 47th bit.
-                addr |= 0x1ffff00000000000;
-            }
-            0x2 => {
-                // Address overflow
-                return None;
-            }
-            _ => {}
+/// Maps `size` bytes of physical memory starting at `phys_start` to their place
+/// in the physical memory window, for device registers above the end of RAM.
+///
+/// Each 4KiB page is mapped with `flags` at `phys + mapper.phys_offset()` and the
+/// virtual address of `phys_start` is returned. Pages that already map the
+/// expected frame, like RAM mapped by the bootloader, are left as they are.
+///
+/// ## Safety
+///
+/// The region must not be in use as regular memory with conflicting flags,
+/// e.g. cacheable RAM mapped with `NO_CACHE`.
+pub unsafe fn map_physical_region(
+    phys_start: u64,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let virt_start = phys_start + mapper.phys_offset();
+    if size == 0 {
+        return Ok(virt_start);
+    }
+    let first = PhysFrame::<Size4KiB>::containing_address(phys_start);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys_start + size - 1);
+    for frame in PhysFrame::range_inclusive(first, last) {
+        let page = Page::containing_address(frame.start_address() + mapper.phys_offset());
+        match unsafe { mapper.map_to(page, frame, flags, frame_alloc) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {},
+            Err(err) => return Err(err),
         }
-
-        Some(addr)
     }
+    Ok(virt_start)
 }
 
-pub trait PhysAddr {
-    fn is_aligned(&self, value: u64) -> bool;
-    fn align_down(&self, value: u64) -> Self;
-    fn new_truncate(addr: u64) -> Self;
-}
-
-impl PhysAddr for u64 {
-    fn is_aligned(&self, value: u64) -> bool {
-        self.align_down(value) == *self
-    }
-
-    fn align_down(&self, value: u64) -> Self {
-        assert!(value.is_power_of_two(), "`align` must be a power of two");
-        *self & !(value - 1)
+/// Removes the pages of `size` bytes starting at `virt_start`, as mapped by
+/// [`map_physical_region`].
+///
+/// ## Safety
+///
+/// Nothing may access the region anymore. Unmapping RAM out of the physical
+/// memory window breaks every user of the window for those frames.
+pub unsafe fn unmap_physical_region(
+    virt_start: u64,
+    size: u64,
+    mapper: &mut OffsetPageTable,
+) -> Result<(), UnmapError> {
+    if size == 0 {
+        return Ok(());
     }
-
-    fn new_truncate(addr: u64) -> Self {
-        addr % (1 << 52)
+    let first = Page::<Size4KiB>::containing_address(virt_start);
+    let last = Page::<Size4KiB>::containing_address(virt_start + size - 1);
+    for page in Page::range_inclusive(first, last) {
+        let (_frame, flush) = mapper.unmap(page)?;
+        flush.flush();
     }
+    Ok(())
 }
 
 /// The error returned by the `PageTableEntry::frame` method.
@@ -179,6 +252,17 @@ pub enum FrameError {
     HugeFrame,
 }
 
+/// The error returned by the `PageTableEntry::try_set_addr` method.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageTableEntryError {
+    /// The address is not aligned to 4KiB.
+    AddressNotAligned,
+    /// The address does not fit in the 52-bit physical address space.
+    AddressTooHigh,
+    /// Some flag bits fall inside the address bits and would corrupt the address.
+    FlagsOverlapAddress,
+}
+
 /// A 64-bit page table entry.
 #[derive(Clone)]
 #[repr(transparent)]
@@ -231,15 +315,39 @@ impl PageTableEntry {
         } else if self.flags().contains(PageTableFlags::HUGE_PAGE) {
             Err(FrameError::HugeFrame)
         } else {
-            Ok(self.addr().align_down(PAGE_4KB_SIZE))
+            Ok(self.addr().align_down(PAGE_SIZE_4K))
         }
     }
 
     /// Map the entry to the specified physical address with the specified flags.
     #[inline]
     pub fn set_addr(&mut self, addr: u64, flags: PageTableFlags) {
-        assert!(addr.is_aligned(PAGE_4KB_SIZE));
+        assert!(addr.is_aligned(PAGE_SIZE_4K));
+        self.entry = addr | flags.bits();
+    }
+
+    /// Map the entry to the specified physical address with the specified flags, checking
+    /// that the address and the flags fit in their own bits of the entry.
+    ///
+    /// Returns the following errors, leaving the entry untouched:
+    ///
+    /// - `PageTableEntryError::AddressNotAligned` if `addr` is not 4KiB aligned.
+    /// - `PageTableEntryError::AddressTooHigh` if `addr` is above the 52-bit physical range.
+    /// - `PageTableEntryError::FlagsOverlapAddress` if `flags` has bits set in common with `addr`
+    ///    (only possible with flags built from raw bits).
+    #[inline]
+    pub fn try_set_addr(&mut self, addr: u64, flags: PageTableFlags) -> Result<(), PageTableEntryError> {
+        if !addr.is_aligned(PAGE_SIZE_4K) {
+            return Err(PageTableEntryError::AddressNotAligned);
+        }
+        if addr >> 52 != 0 {
+            return Err(PageTableEntryError::AddressTooHigh);
+        }
+        if addr & flags.bits() != 0 {
+            return Err(PageTableEntryError::FlagsOverlapAddress);
+        }
         self.entry = addr | flags.bits();
+        Ok(())
     }
 
     /// Map the entry to the specified physical frame with the specified flags.
@@ -254,6 +362,24 @@ impl PageTableEntry {
     pub fn set_flags(&mut self, flags: PageTableFlags) {
         self.entry = self.addr() | flags.bits();
     }
+
+    /// Sets `flags` in this entry, keeping the other flags and the address.
+    #[inline]
+    pub fn set_flags_bits(&mut self, flags: PageTableFlags) {
+        self.entry |= flags.bits();
+    }
+
+    /// Clears `flags` in this entry, keeping the other flags and the address.
+    #[inline]
+    pub fn clear_flags_bits(&mut self, flags: PageTableFlags) {
+        self.entry &= !flags.bits();
+    }
+
+    /// Flips `flags` in this entry, keeping the other flags and the address.
+    #[inline]
+    pub fn toggle_flags_bits(&mut self, flags: PageTableFlags) {
+        self.entry ^= flags.bits();
+    }
 }
 
 impl Default for PageTableEntry {
@@ -337,8 +463,26 @@ bitflags! {
     }
 }
 
-/// The number of entries in a page table.
-const ENTRY_COUNT: usize = 512;
+impl PageTableFlags {
+    /// Returns the PWT, PCD and PAT bits selecting entry `index` of the page attribute
+    /// table, see [`crate::memory::pat`].
+    ///
+    /// The PAT bit is bit 7 here, which is only right for level 1 entries. In
+    /// entries mapping a huge page bit 7 is `HUGE_PAGE` and the PAT bit is bit 12.
+    pub const fn pat_index(index: u8) -> Self {
+        let mut bits = 0;
+        if index & 1 != 0 {
+            bits |= Self::WRITE_THROUGH.bits();
+        }
+        if index & 2 != 0 {
+            bits |= Self::NO_CACHE.bits();
+        }
+        if index & 4 != 0 {
+            bits |= 1 << 7;
+        }
+        Self::from_bits_retain(bits)
+    }
+}
 
 /// Represents a page table.
 ///
@@ -404,7 +548,43 @@ impl PageTable {
     /// Checks if the page table is empty (all entries are zero).
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.iter().all(|entry| entry.is_unused())
+        self.first_present_index().is_none()
+    }
+
+    /// Returns the number of entries that are not unused.
+    #[inline]
+    pub fn count_present(&self) -> usize {
+        self.iter().fold(0, |count, entry| count + !entry.is_unused() as usize)
+    }
+
+    /// Returns the number of unused (zero) entries.
+    #[inline]
+    pub fn count_unused(&self) -> usize {
+        ENTRY_COUNT - self.count_present()
+    }
+
+    /// Returns the index of the first entry that is not unused.
+    #[inline]
+    pub fn first_present_index(&self) -> Option<usize> {
+        (0..ENTRY_COUNT).find(|&i| !self.entries[i].is_unused())
+    }
+
+    /// Returns the index of the last entry that is not unused.
+    #[inline]
+    pub fn last_present_index(&self) -> Option<usize> {
+        (0..ENTRY_COUNT).rev().find(|&i| !self.entries[i].is_unused())
+    }
+
+    /// Returns entry `index`, or `None` if `index` is not below 512.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&PageTableEntry> {
+        self.entries.get(index)
+    }
+
+    /// Returns entry `index` mutably, or `None` if `index` is not below 512.
+    #[inline]
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut PageTableEntry> {
+        self.entries.get_mut(index)
     }
 }
 
@@ -453,780 +633,201 @@ impl fmt::Debug for PageTable {
     }
 }
 
-/// A 9-bit index into a page table.
-///
-/// Can be used to select one of the 512 entries of a page table.
-///
-/// Guaranteed to only ever contain 0..512.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct PageTableIndex(u16);
-
-impl PageTableIndex {
-    /// Creates a new index from the given `u16`. Panics if the given value is >=512.
-    #[inline]
-    pub const fn new(index: u16) -> Self {
-        assert!((index as usize) < ENTRY_COUNT);
-        Self(index)
-    }
-
-    /// Creates a new index from the given `u16`. Throws away bits if the value is >=512.
-    #[inline]
-    pub const fn new_truncate(index: u16) -> Self {
-        Self(index % ENTRY_COUNT as u16)
-    }
-
-    #[inline]
-    pub(crate) const fn into_u64(self) -> u64 {
-        self.0 as u64
-    }
-}
-
-impl From<PageTableIndex> for u16 {
-    #[inline]
-    fn from(index: PageTableIndex) -> Self {
-        index.0
-    }
-}
-
-impl From<PageTableIndex> for u32 {
-    #[inline]
-    fn from(index: PageTableIndex) -> Self {
-        u32::from(index.0)
-    }
-}
-
-impl From<PageTableIndex> for u64 {
-    #[inline]
-    fn from(index: PageTableIndex) -> Self {
-        index.into_u64()
-    }
-}
-
-impl From<PageTableIndex> for usize {
-    #[inline]
-    fn from(index: PageTableIndex) -> Self {
-        usize::from(index.0)
-    }
-}
-
-/// A 12-bit offset into a 4KiB Page.
-///
-/// This type is returned by the `VirtAddr::page_offset` method.
-///
-/// Guaranteed to only ever contain 0..4096.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct PageOffset(u16);
-
-impl PageOffset {
-    /// Creates a new offset from the given `u16`. Panics if the passed value is >=4096.
-    #[inline]
-    pub fn new(offset: u16) -> Self {
-        assert!(offset < (1 << 12));
-        Self(offset)
-    }
-
-    /// Creates a new offset from the given `u16`. Throws away bits if the value is >=4096.
-    #[inline]
-    pub const fn new_truncate(offset: u16) -> Self {
-        Self(offset % (1 << 12))
-    }
-}
-
-impl From<PageOffset> for u16 {
-    #[inline]
-    fn from(offset: PageOffset) -> Self {
-        offset.0
-    }
-}
-
-impl From<PageOffset> for u32 {
-    #[inline]
-    fn from(offset: PageOffset) -> Self {
-        u32::from(offset.0)
-    }
-}
-
-impl From<PageOffset> for u64 {
-    #[inline]
-    fn from(offset: PageOffset) -> Self {
-        u64::from(offset.0)
-    }
-}
-
-impl From<PageOffset> for usize {
-    #[inline]
-    fn from(offset: PageOffset) -> Self {
-        usize::from(offset.0)
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-/// A value between 1 and 4.
-pub enum PageTableLevel {
-    /// Represents the level for a page table.
-    One = 1,
-    /// Represents the level for a page directory.
-    Two,
-    /// Represents the level for a page-directory pointer.
-    Three,
-    /// Represents the level for a page-map level-4.
-    Four,
-}
-
-impl PageTableLevel {
-    /// Returns the next lower level or `None` for level 1
-    pub const fn next_lower_level(self) -> Option<Self> {
-        match self {
-            PageTableLevel::Four => Some(PageTableLevel::Three),
-            PageTableLevel::Three => Some(PageTableLevel::Two),
-            PageTableLevel::Two => Some(PageTableLevel::One),
-            PageTableLevel::One => None,
-        }
-    }
-
-    /// Returns the next higher level or `None` for level 4
-    pub const fn next_higher_level(self) -> Option<Self> {
-        match self {
-            PageTableLevel::Four => None,
-            PageTableLevel::Three => Some(PageTableLevel::Four),
-            PageTableLevel::Two => Some(PageTableLevel::Three),
-            PageTableLevel::One => Some(PageTableLevel::Two),
-        }
-    }
-
-    /// Returns the alignment for the address space described by a table of this level.
-    pub const fn table_address_space_alignment(self) -> u64 {
-        1u64 << (self as u8 * 9 + 12)
-    }
-
-    /// Returns the alignment for the address space described by an entry in a table of this level.
-    pub const fn entry_address_space_alignment(self) -> u64 {
-        1u64 << (((self as u8 - 1) * 9) + 12)
-    }
-}
-
-
-use core::marker::PhantomData;
-use core::ops::{Add, AddAssign, Sub, SubAssign};
-
-/// Trait for abstracting over the three possible page sizes on x86_64, 4KiB, 2MiB, 1GiB.
-pub trait PageSize: Copy + Eq + PartialOrd + Ord {
-    /// The page size in bytes.
-    const SIZE: u64;
-
-    /// A string representation of the page size for debug output.
-    const DEBUG_STR: &'static str;
-}
-
-/// This trait is implemented for 4KiB and 2MiB pages, but not for 1GiB pages.
-pub trait NotGiantPageSize: PageSize {}
-
-/// A standard 4KiB page.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub enum Size4KiB {}
-
-/// A “huge” 2MiB page.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub enum Size2MiB {}
-
-/// A “giant” 1GiB page.
-///
-/// (Only available on newer x86_64 CPUs.)
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub enum Size1GiB {}
-
-impl PageSize for Size4KiB {
-    const SIZE: u64 = 4096;
-    const DEBUG_STR: &'static str = "4KiB";
-}
-
-impl NotGiantPageSize for Size4KiB {}
-
-impl PageSize for Size2MiB {
-    const SIZE: u64 = Size4KiB::SIZE * 512;
-    const DEBUG_STR: &'static str = "2MiB";
-}
-
-impl NotGiantPageSize for Size2MiB {}
-
-impl PageSize for Size1GiB {
-    const SIZE: u64 = Size2MiB::SIZE * 512;
-    const DEBUG_STR: &'static str = "1GiB";
-}
-
-/// A virtual memory page.
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-#[repr(C)]
-pub struct Page<S: PageSize = Size4KiB> {
-    start_address: u64,
-    size: PhantomData<S>,
-}
-
-impl<S: PageSize> Page<S> {
-    /// The page size in bytes.
-    pub const SIZE: u64 = S::SIZE;
-
-    /// Returns the page that starts at the given virtual address.
-    ///
-    /// Returns an error if the address is not correctly aligned (i.e. is not a valid page start).
-    #[inline]
-    pub fn from_start_address(address: u64) -> Result<Self, AddressNotAligned> {
-        if !address.is_aligned(S::SIZE) {
-            return Err(AddressNotAligned);
-        }
-        Ok(Page::containing_address(address))
-    }
-
-    /// Returns the page that starts at the given virtual address.
-    ///
-    /// #Safety
-    ///
-    /// The address must be correctly aligned.
-    #[inline]
-    pub unsafe fn from_start_address_unchecked(start_address: u64) -> Self {
-        Page {
-            start_address,
-            size: PhantomData,
-        }
-    }
-
-    /// Returns the page that contains the given virtual address.
-    #[inline]
-    pub fn containing_address(address: u64) -> Self {
-        Page {
-            start_address: address.align_down(S::SIZE),
-            size: PhantomData,
-        }
-    }
-
-    /// Returns the start address of the page.
-    #[inline]
-    pub fn start_address(self) -> u64 {
-        self.start_address
-    }
-
-    /// Returns the size the page (4KB, 2MB or 1GB).
-    #[inline]
-    pub fn size(self) -> u64 {
-        S::SIZE
-    }
-
-    /// Returns the level 4 page table index of this page.
-    #[inline]
-    pub fn p4_index(self) -> PageTableIndex {
-        self.start_address().p4_index()
-    }
-
-    /// Returns the level 3 page table index of this page.
-    #[inline]
-    pub fn p3_index(self) -> PageTableIndex {
-        self.start_address().p3_index()
-    }
-
-    /// Returns the table index of this page at the specified level.
-    #[inline]
-    pub fn page_table_index(self, level: PageTableLevel) -> PageTableIndex {
-        self.start_address().page_table_index(level)
-    }
-
-    /// Returns a range of pages, exclusive `end`.
-    #[inline]
-    pub fn range(start: Self, end: Self) -> PageRange<S> {
-        PageRange { start, end }
-    }
-
-    /// Returns a range of pages, inclusive `end`.
-    #[inline]
-    pub fn range_inclusive(start: Self, end: Self) -> PageRangeInclusive<S> {
-        PageRangeInclusive { start, end }
-    }
-}
-
-impl<S: NotGiantPageSize> Page<S> {
-    /// Returns the level 2 page table index of this page.
-    #[inline]
-    pub fn p2_index(self) -> PageTableIndex {
-        self.start_address().p2_index()
-    }
-}
-
-impl Page<Size1GiB> {
-    /// Returns the 1GiB memory page with the specified page table indices.
-    #[inline]
-    pub fn from_page_table_indices_1gib(
-        p4_index: PageTableIndex,
-        p3_index: PageTableIndex,
-    ) -> Self {
-        let mut addr = 0;
-        addr |= p4_index.into_u64() << 39;
-        addr |= p3_index.into_u64() << 30;
-        Page::containing_address(u64::new_virt_truncate(addr))
-    }
-}
-
-impl Page<Size2MiB> {
-    /// Returns the 2MiB memory page with the specified page table indices.
-    #[inline]
-    pub fn from_page_table_indices_2mib(
-        p4_index: PageTableIndex,
-        p3_index: PageTableIndex,
-        p2_index: PageTableIndex,
-    ) -> Self {
-        let mut addr = 0;
-        addr |= p4_index.into_u64() << 39;
-        addr |= p3_index.into_u64() << 30;
-        addr |= p2_index.into_u64() << 21;
-        Page::containing_address(u64::new_virt_truncate(addr))
-    }
-}
-
-impl Page<Size4KiB> {
-    /// Returns the 4KiB memory page with the specified page table indices.
-    #[inline]
-    pub fn from_page_table_indices(
-        p4_index: PageTableIndex,
-        p3_index: PageTableIndex,
-        p2_index: PageTableIndex,
-        p1_index: PageTableIndex,
-    ) -> Self {
-        let mut addr = 0;
-        addr |= p4_index.into_u64() << 39;
-        addr |= p3_index.into_u64() << 30;
-        addr |= p2_index.into_u64() << 21;
-        addr |= p1_index.into_u64() << 12;
-        Page::containing_address(u64::new_virt_truncate(addr))
-    }
-
-    /// Returns the level 1 page table index of this page.
-    #[inline]
-    pub fn p1_index(self) -> PageTableIndex {
-        self.start_address.p1_index()
-    }
-}
-
-impl<S: PageSize> fmt::Debug for Page<S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_fmt(format_args!(
-            "Page[{}]({:#x})",
-            S::DEBUG_STR,
-            self.start_address()
-        ))
-    }
-}
-
-impl<S: PageSize> Add<u64> for Page<S> {
-    type Output = Self;
-    #[inline]
-    fn add(self, rhs: u64) -> Self::Output {
-        Page::containing_address(self.start_address() + rhs * S::SIZE)
-    }
-}
-
-impl<S: PageSize> AddAssign<u64> for Page<S> {
-    #[inline]
-    fn add_assign(&mut self, rhs: u64) {
-        *self = *self + rhs;
-    }
-}
-
-impl<S: PageSize> Sub<u64> for Page<S> {
-    type Output = Self;
-    #[inline]
-    fn sub(self, rhs: u64) -> Self::Output {
-        Page::containing_address(self.start_address() - rhs * S::SIZE)
-    }
-}
-
-impl<S: PageSize> SubAssign<u64> for Page<S> {
-    #[inline]
-    fn sub_assign(&mut self, rhs: u64) {
-        *self = *self - rhs;
-    }
-}
-
-impl<S: PageSize> Sub<Self> for Page<S> {
-    type Output = u64;
-    #[inline]
-    fn sub(self, rhs: Self) -> Self::Output {
-        (self.start_address - rhs.start_address) / S::SIZE
-    }
-}
-
-/// A range of pages with exclusive upper bound.
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
-#[repr(C)]
-pub struct PageRange<S: PageSize = Size4KiB> {
-    /// The start of the range, inclusive.
-    pub start: Page<S>,
-    /// The end of the range, exclusive.
-    pub end: Page<S>,
-}
-
-impl<S: PageSize> PageRange<S> {
-    /// Returns wether this range contains no pages.
-    #[inline]
-    pub fn is_empty(&self) -> bool {
-        self.start >= self.end
-    }
-
-    /// Returns the number of pages in the range.
-    #[inline]
-    pub fn len(&self) -> u64 {
-        if !self.is_empty() {
-            self.end - self.start
-        } else {
-            0
-        }
-    }
-
-    /// Returns the size in bytes of all pages within the range.
-    #[inline]
-    pub fn size(&self) -> u64 {
-        S::SIZE * self.len()
-    }
-}
-
-impl<S: PageSize> Iterator for PageRange<S> {
-    type Item = Page<S>;
-
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.start < self.end {
-            let page = self.start;
-            self.start += 1;
-            Some(page)
-        } else {
-            None
-        }
-    }
-}
-
-impl PageRange<Size2MiB> {
-    /// Converts the range of 2MiB pages to a range of 4KiB pages.
-    #[inline]
-    pub fn as_4kib_page_range(self) -> PageRange<Size4KiB> {
-        PageRange {
-            start: Page::containing_address(self.start.start_address()),
-            end: Page::containing_address(self.end.start_address()),
-        }
-    }
-}
-
-impl<S: PageSize> fmt::Debug for PageRange<S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("PageRange")
-            .field("start", &self.start)
-            .field("end", &self.end)
-            .finish()
-    }
-}
-
-/// A range of pages with inclusive upper bound.
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
-#[repr(C)]
-pub struct PageRangeInclusive<S: PageSize = Size4KiB> {
-    /// The start of the range, inclusive.
-    pub start: Page<S>,
-    /// The end of the range, inclusive.
-    pub end: Page<S>,
-}
-
-impl<S: PageSize> PageRangeInclusive<S> {
-    /// Returns whether this range contains no pages.
-    #[inline]
-    pub fn is_empty(&self) -> bool {
-        self.start > self.end
-    }
-
-    /// Returns the number of frames in the range.
-    #[inline]
-    pub fn len(&self) -> u64 {
-        if !self.is_empty() {
-            self.end - self.start + 1
-        } else {
-            0
-        }
-    }
-
-    /// Returns the size in bytes of all frames within the range.
-    #[inline]
-    pub fn size(&self) -> u64 {
-        S::SIZE * self.len()
-    }
-}
-
-impl<S: PageSize> Iterator for PageRangeInclusive<S> {
-    type Item = Page<S>;
-
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.start <= self.end {
-            let page = self.start;
-
-            // If the end of the inclusive range is the maximum page possible for size S,
-            // incrementing start until it is greater than the end will cause an integer overflow.
-            // So instead, in that case we decrement end rather than incrementing start.
-            let max_page_addr = u64::MAX - (S::SIZE - 1);
-            if self.start.start_address() < max_page_addr {
-                self.start += 1;
-            } else {
-                self.end -= 1;
-            }
-            Some(page)
-        } else {
-            None
-        }
-    }
-}
-
-impl<S: PageSize> fmt::Debug for PageRangeInclusive<S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("PageRangeInclusive")
-            .field("start", &self.start)
-            .field("end", &self.end)
-            .finish()
-    }
-}
-
-/// The given address was not sufficiently aligned.
-#[derive(Debug)]
-pub struct AddressNotAligned;
-
-impl fmt::Display for AddressNotAligned {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "the given address was not sufficiently aligned")
-    }
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-#[repr(C)]
-pub struct PhysFrame<S: PageSize = Size4KiB> {
-    // TODO: Make private when our minimum supported stable Rust version is 1.61
-    pub(crate) start_address: u64,
-    size: PhantomData<S>,
-}
-
-impl<S: PageSize> PhysFrame<S> {
-    /// Returns the frame that starts at the given virtual address.
-    ///
-    /// Returns an error if the address is not correctly aligned (i.e. is not a valid frame start).
-    #[inline]
-    pub fn from_start_address(address: u64) -> Result<Self, AddressNotAligned> {
-        if !address.is_aligned(S::SIZE) {
-            return Err(AddressNotAligned);
-        }
-
-        // SAFETY: correct address alignment is checked above
-        Ok(unsafe { PhysFrame::from_start_address_unchecked(address) })
-    }
-
-    /// Returns the frame that starts at the given virtual address.
-    ///
-    /// ## Safety
-    ///
-    /// The address must be correctly aligned.
-    #[inline]
-    pub unsafe fn from_start_address_unchecked(start_address: u64) -> Self {
-        PhysFrame {
-            start_address,
-            size: PhantomData,
-        }
-    }
-
-    /// Returns the frame that contains the given physical address.
-    #[inline]
-    pub fn containing_address(address: u64) -> Self {
-        PhysFrame {
-            start_address: address.align_down(S::SIZE),
-            size: PhantomData,
-        }
-    }
-
-    /// Returns the start address of the frame.
-    #[inline]
-    pub fn start_address(self) -> u64 {
-        self.start_address
-    }
-
-    /// Returns the size the frame (4KB, 2MB or 1GB).
-    #[inline]
-    pub fn size(self) -> u64 {
-        S::SIZE
-    }
-
-    /// Returns a range of frames, exclusive `end`.
-    #[inline]
-    pub fn range(start: PhysFrame<S>, end: PhysFrame<S>) -> PhysFrameRange<S> {
-        PhysFrameRange { start, end }
-    }
-
-    /// Returns a range of frames, inclusive `end`.
-    #[inline]
-    pub fn range_inclusive(start: PhysFrame<S>, end: PhysFrame<S>) -> PhysFrameRangeInclusive<S> {
-        PhysFrameRangeInclusive { start, end }
-    }
-}
-
-impl<S: PageSize> fmt::Debug for PhysFrame<S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_fmt(format_args!(
-            "PhysFrame[{}]({:#x})",
-            S::DEBUG_STR,
-            self.start_address()
-        ))
-    }
-}
-
-impl<S: PageSize> Add<u64> for PhysFrame<S> {
-    type Output = Self;
-    #[inline]
-    fn add(self, rhs: u64) -> Self::Output {
-        PhysFrame::containing_address(self.start_address() + rhs * S::SIZE)
-    }
-}
-
-impl<S: PageSize> AddAssign<u64> for PhysFrame<S> {
-    #[inline]
-    fn add_assign(&mut self, rhs: u64) {
-        *self = *self + rhs;
-    }
-}
-
-impl<S: PageSize> Sub<u64> for PhysFrame<S> {
-    type Output = Self;
-    #[inline]
-    fn sub(self, rhs: u64) -> Self::Output {
-        PhysFrame::containing_address(self.start_address() - rhs * S::SIZE)
-    }
-}
-
-impl<S: PageSize> SubAssign<u64> for PhysFrame<S> {
-    #[inline]
-    fn sub_assign(&mut self, rhs: u64) {
-        *self = *self - rhs;
-    }
-}
-
-impl<S: PageSize> Sub<PhysFrame<S>> for PhysFrame<S> {
-    type Output = u64;
-    #[inline]
-    fn sub(self, rhs: PhysFrame<S>) -> Self::Output {
-        (self.start_address - rhs.start_address) / S::SIZE
-    }
-}
-
-/// An range of physical memory frames, exclusive the upper bound.
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
-#[repr(C)]
-pub struct PhysFrameRange<S: PageSize = Size4KiB> {
-    /// The start of the range, inclusive.
-    pub start: PhysFrame<S>,
-    /// The end of the range, exclusive.
-    pub end: PhysFrame<S>,
-}
-
-impl<S: PageSize> PhysFrameRange<S> {
-    /// Returns whether the range contains no frames.
-    #[inline]
-    pub fn is_empty(&self) -> bool {
-        self.start >= self.end
-    }
-
-    /// Returns the number of frames in the range.
-    #[inline]
-    pub fn len(&self) -> u64 {
-        if !self.is_empty() {
-            self.end - self.start
-        } else {
-            0
-        }
-    }
-
-    /// Returns the size in bytes of all frames within the range.
-    #[inline]
-    pub fn size(&self) -> u64 {
-        S::SIZE * self.len()
-    }
-}
-
-impl<S: PageSize> Iterator for PhysFrameRange<S> {
-    type Item = PhysFrame<S>;
-
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.start < self.end {
-            let frame = self.start;
-            self.start += 1;
-            Some(frame)
-        } else {
-            None
-        }
-    }
-}
-
-impl<S: PageSize> fmt::Debug for PhysFrameRange<S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("PhysFrameRange")
-            .field("start", &self.start)
-            .field("end", &self.end)
-            .finish()
-    }
-}
-
-/// An range of physical memory frames, inclusive the upper bound.
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
-#[repr(C)]
-pub struct PhysFrameRangeInclusive<S: PageSize = Size4KiB> {
-    /// The start of the range, inclusive.
-    pub start: PhysFrame<S>,
-    /// The start of the range, inclusive.
-    pub end: PhysFrame<S>,
-}
-
-impl<S: PageSize> PhysFrameRangeInclusive<S> {
-    /// Returns whether the range contains no frames.
-    #[inline]
-    pub fn is_empty(&self) -> bool {
-        self.start > self.end
-    }
-
-    /// Returns the number of frames in the range.
-    #[inline]
-    pub fn len(&self) -> u64 {
-        if !self.is_empty() {
-            self.end - self.start + 1
-        } else {
-            0
-        }
-    }
-
-    /// Returns the size in bytes of all frames within the range.
-    #[inline]
-    pub fn size(&self) -> u64 {
-        S::SIZE * self.len()
-    }
-}
-
-impl<S: PageSize> Iterator for PhysFrameRangeInclusive<S> {
-    type Item = PhysFrame<S>;
-
-    #[inline]
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.start <= self.end {
-            let frame = self.start;
-            self.start += 1;
-            Some(frame)
-        } else {
-            None
+#[test_case]
+fn page_table_present_counts() {
+    let flags = PageTableFlags::PRESENT;
+    let mut table = PageTable::new();
+    assert!(table.is_empty());
+    assert_eq!(table.count_present(), 0);
+    assert_eq!(table.count_unused(), 512);
+    assert_eq!(table.first_present_index(), None);
+    assert_eq!(table.last_present_index(), None);
+
+    table[42].set_addr(0x1000, flags);
+    assert!(!table.is_empty());
+    assert_eq!(table.count_present(), 1);
+    assert_eq!(table.count_unused(), 511);
+    assert_eq!(table.first_present_index(), Some(42));
+    assert_eq!(table.last_present_index(), Some(42));
+
+    for (i, entry) in table.iter_mut().enumerate().skip(1) {
+        entry.set_addr(i as u64 * 0x1000, flags);
+    }
+    table[0].set_unused();
+    assert_eq!(table.count_present(), 511);
+    assert_eq!(table.first_present_index(), Some(1));
+    assert_eq!(table.last_present_index(), Some(511));
+
+    table[0].set_addr(0, flags);
+    assert_eq!(table.count_present(), 512);
+    assert_eq!(table.count_unused(), 0);
+    assert_eq!(table.first_present_index(), Some(0));
+}
+
+#[test_case]
+fn flag_bit_helpers_keep_the_address() {
+    const ADDRESS_BITS: u64 = 0x000f_ffff_ffff_f000;
+    let addr = 0x000f_edcb_a987_6000;
+    let mut entry = PageTableEntry::new();
+    entry.set_addr(addr, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
+    entry.clear_flags_bits(PageTableFlags::WRITABLE);
+    assert_eq!(entry.flags(), PageTableFlags::PRESENT);
+    entry.set_flags_bits(PageTableFlags::DIRTY | PageTableFlags::NO_EXECUTE);
+    assert_eq!(entry.flags(), PageTableFlags::PRESENT | PageTableFlags::DIRTY | PageTableFlags::NO_EXECUTE);
+    entry.toggle_flags_bits(PageTableFlags::DIRTY | PageTableFlags::BIT_9);
+    assert_eq!(entry.flags(), PageTableFlags::PRESENT | PageTableFlags::BIT_9 | PageTableFlags::NO_EXECUTE);
+    assert_eq!(entry.addr(), addr);
+
+    // Every flag at once, set, flipped and cleared.
+    entry.set_flags_bits(PageTableFlags::all());
+    assert_eq!(entry.entry & ADDRESS_BITS, addr);
+    assert_eq!(entry.entry & !ADDRESS_BITS, PageTableFlags::all().bits());
+    entry.toggle_flags_bits(PageTableFlags::all());
+    assert_eq!(entry.entry, addr);
+    entry.clear_flags_bits(PageTableFlags::all());
+    assert_eq!(entry.entry, addr);
+}
+
+#[test_case]
+fn page_table_get_checks_bounds() {
+    let mut table = PageTable::new();
+    table[511].set_addr(0x1000, PageTableFlags::PRESENT);
+
+    assert!(table.get(0).is_some_and(PageTableEntry::is_unused));
+    assert_eq!(table.get(511).map(PageTableEntry::addr), Some(0x1000));
+    assert!(table.get(512).is_none());
+    assert!(table.get(usize::MAX).is_none());
+
+    table.get_mut(7).unwrap().set_addr(0x2000, PageTableFlags::PRESENT);
+    assert_eq!(table[7].addr(), 0x2000);
+    assert!(table.get_mut(512).is_none());
+}
+
+#[test_case]
+fn try_set_addr_rejects_overlapping_bits() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut entry = PageTableEntry::new();
+
+    assert_eq!(entry.try_set_addr(0x20_0000, flags), Ok(()));
+    assert_eq!(entry.addr(), 0x20_0000);
+    assert_eq!(entry.flags(), flags);
+
+    // A raw flag bit 21 collides with the address of the second 2MiB frame.
+    let raw_flags = PageTableFlags::from_bits_retain(flags.bits() | 1 << 21);
+    assert_eq!(entry.try_set_addr(0x20_0000, raw_flags), Err(PageTableEntryError::FlagsOverlapAddress));
+    assert_eq!(entry.try_set_addr(0x1001, flags), Err(PageTableEntryError::AddressNotAligned));
+    assert_eq!(entry.try_set_addr(1 << 52, flags), Err(PageTableEntryError::AddressTooHigh));
+    // Failed calls leave the entry as it was.
+    assert_eq!(entry.addr(), 0x20_0000);
+    assert_eq!(entry.flags(), flags);
+}
+
+#[test_case]
+fn page_from_start_address_checks_size_alignment() {
+    const KIB_4: u64 = 0x1000;
+    const MIB_2: u64 = 0x20_0000;
+    const GIB_1: u64 = 0x4000_0000;
+
+    assert_eq!(Page::<Size4KiB>::from_start_address(KIB_4).map(Page::start_address), Ok(KIB_4));
+    assert_eq!(Page::<Size4KiB>::from_start_address(KIB_4 + 8), Err(AddressNotAligned));
+
+    assert_eq!(Page::<Size2MiB>::from_start_address(MIB_2).map(Page::start_address), Ok(MIB_2));
+    assert_eq!(Page::<Size2MiB>::from_start_address(GIB_1).map(Page::start_address), Ok(GIB_1));
+    // 4KiB aligned is not enough for a 2MiB page.
+    assert_eq!(Page::<Size2MiB>::from_start_address(MIB_2 + KIB_4), Err(AddressNotAligned));
+    assert_eq!(Page::from_start_address_2mib(MIB_2 + KIB_4), Err(AddressNotAligned));
+
+    assert_eq!(Page::<Size1GiB>::from_start_address(GIB_1).map(Page::start_address), Ok(GIB_1));
+    assert_eq!(Page::<Size1GiB>::from_start_address(0).map(Page::start_address), Ok(0));
+    assert_eq!(Page::<Size1GiB>::from_start_address(GIB_1 + MIB_2), Err(AddressNotAligned));
+    assert_eq!(Page::<Size1GiB>::from_start_address(GIB_1 + KIB_4), Err(AddressNotAligned));
+    assert_eq!(Page::from_start_address_1gib(3 * GIB_1).map(Page::start_address), Ok(3 * GIB_1));
+    assert_eq!(Page::from_start_address_1gib(3 * GIB_1 + MIB_2), Err(AddressNotAligned));
+
+    assert_eq!(PhysFrame::<Size2MiB>::from_start_address(MIB_2 + KIB_4), Err(AddressNotAligned));
+    assert_eq!(PhysFrame::<Size1GiB>::from_start_address(GIB_1 + MIB_2), Err(AddressNotAligned));
+}
+
+#[test_case]
+fn physical_region_maps_at_offset() {
+    use crate::memory::{mapper::Translate, FRAME_ALLOCATOR, MAPPER};
+
+    // The I/O APIC, above the RAM the bootloader maps.
+    const PHYS: u64 = 0xFEC0_0010;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+
+    let virt = unsafe { map_physical_region(PHYS, 0x1800, flags, mapper, allocator) }.unwrap();
+    assert_eq!(virt, PHYS + mapper.phys_offset());
+    assert_eq!(mapper.translate_addr(virt), Some(PHYS));
+    assert_eq!(mapper.translate_addr(virt + 0x1000), Some(PHYS + 0x1000));
+    // Mapping it again finds the pages in place.
+    assert!(unsafe { map_physical_region(PHYS, 0x1800, flags, mapper, allocator) }.is_ok());
+
+    unsafe { unmap_physical_region(virt, 0x1800, mapper) }.unwrap();
+    assert_eq!(mapper.translate_addr(virt), None);
+    assert_eq!(mapper.translate_addr(virt + 0x1000), None);
+}
+
+#[test_case]
+fn probe_read_catches_unmapped_addresses() {
+    let value = 42u8;
+    assert!(probe_read(&value as *const u8 as u64));
+    // The first page is left unmapped to catch null pointers.
+    assert!(!probe_read(0));
+    assert_eq!(PROBE_FIXUP.load(core::sync::atomic::Ordering::Relaxed), 0);
+}
+
+#[test_case]
+fn table_ref_finds_active_level_4_table() {
+    let offset = crate::memory::MAPPER.lock().as_ref().unwrap().phys_offset();
+    let frame = PhysFrame::containing_address(read_cr3());
+    let table = unsafe { table_ref(offset, frame) };
+    assert_eq!(table as *const PageTable, table_ptr(offset, frame) as *const PageTable);
+    assert_eq!(table as *const PageTable as u64, offset + frame.start_address());
+    assert!(table.count_present() > 0);
+}
+
+#[test_case]
+fn translate_addr_walks_from_level_4() {
+    use crate::memory::{mapper::Translate, MAPPER};
+
+    static TARGET: u64 = 0x5EED;
+    let mapper = MAPPER.lock();
+    let mapper = mapper.as_ref().unwrap();
+    let offset = mapper.phys_offset();
+
+    // The walk done by hand, with the indices cut out of the address.
+    let addr = &TARGET as *const u64 as u64;
+    let mut frame = read_cr3();
+    let mut expected = None;
+    for (level, shift) in [(4, 39), (3, 30), (2, 21), (1, 12)] {
+        let table = unsafe { table_ref(offset, PhysFrame::containing_address(frame)) };
+        let entry = &table[((addr >> shift) & 0x1FF) as usize];
+        assert!(entry.flags().contains(PageTableFlags::PRESENT), "level {} entry not present", level);
+        frame = entry.addr();
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            expected = Some(frame + (addr & ((1 << shift) - 1)));
+            break;
         }
     }
-}
-
-impl<S: PageSize> fmt::Debug for PhysFrameRangeInclusive<S> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("PhysFrameRangeInclusive")
-            .field("start", &self.start)
-            .field("end", &self.end)
-            .finish()
-    }
+    assert_eq!(unsafe { translate_addr(addr, offset) }, expected);
+
+    let stack = &expected as *const Option<u64> as u64;
+    let code = read_cr3 as *const () as u64;
+    for addr in [addr, stack, code, offset + 0x10_0123] {
+        assert_eq!(unsafe { translate_addr(addr, offset) }, mapper.translate_addr(addr), "{:#x}", addr);
+    }
+    // The physical memory window and the identity mapped VGA buffer.
+    assert_eq!(unsafe { translate_addr(offset + 0x10_0123, offset) }, Some(0x10_0123));
+    assert_eq!(unsafe { translate_addr(0xB_8123, offset) }, Some(0xB_8123));
+    // The null page, and a whole level 4 slot left empty.
+    assert_eq!(unsafe { translate_addr(0, offset) }, None);
+    let level_4 = unsafe { table_ref(offset, PhysFrame::containing_address(read_cr3())) };
+    let unused = level_4.iter().position(PageTableEntry::is_unused).unwrap() as u64;
+    assert_eq!(unsafe { translate_addr(canonicalize(unused << 39 | 0x1234), offset) }, None);
 }
@@ -0,0 +1,198 @@
+//! The page attribute table, which decides the memory type of every mapping.
+//!
+//! The PWT, PCD and PAT bits of a level 1 entry together form a 3 bit index into
+//! the eight entries of the `IA32_PAT` MSR:
+//!
+//! | index | PAT | PCD | PWT | power-on type | type after [`init`] |
+//! |-------|-----|-----|-----|---------------|---------------------|
+//! | 0     | 0   | 0   | 0   | WB            | WB                  |
+//! | 1     | 0   | 0   | 1   | WT            | **WC**              |
+//! | 2     | 0   | 1   | 0   | UC-           | UC-                 |
+//! | 3     | 0   | 1   | 1   | UC            | UC                  |
+//! | 4     | 1   | 0   | 0   | WB            | WB                  |
+//! | 5     | 1   | 0   | 1   | WT            | WT                  |
+//! | 6     | 1   | 1   | 0   | UC-           | UC-                 |
+//! | 7     | 1   | 1   | 1   | UC            | UC                  |
+//!
+//! Only entry 1 changes, so plain `WRITE_THROUGH` mappings become write-combining
+//! and write-through stays available at index 5. `NO_CACHE` alone still selects
+//! UC-, and `NO_CACHE | WRITE_THROUGH` UC.
+
+use crate::{
+    cpu::{cpuid, Msr},
+    memory::{
+        frame_allocator::FrameAllocator,
+        mapper::{MapToError, Mapper, OffsetPageTable},
+        paging::{map_physical_region, read_cr3, write_cr3, Page, PageTableFlags, Size4KiB},
+    },
+};
+
+const IA32_PAT: u32 = 0x277;
+
+/// `cpuid` leaf 1, EDX: the page attribute table is supported.
+const CPUID_PAT: u32 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable    = 0,
+    WriteCombining = 1,
+    WriteThrough   = 4,
+    WriteProtected = 5,
+    WriteBack      = 6,
+    /// Uncacheable, but can be overridden by a write-combining MTRR.
+    UncachedMinus  = 7,
+}
+
+/// The PAT entry [`init`] sets to write-combining.
+pub const WRITE_COMBINING_INDEX: u8 = 1;
+
+const PAT_LAYOUT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+];
+
+pub fn is_supported() -> bool {
+    cpuid(1, 0).edx & CPUID_PAT != 0
+}
+
+/// Programs the page attribute table with the layout above.
+///
+/// Returns `false` if the CPU has no PAT, in which case index 1 keeps meaning
+/// write-through.
+pub fn init() -> bool {
+    if !is_supported() {
+        return false;
+    }
+    let value = PAT_LAYOUT.iter()
+        .enumerate()
+        .fold(0u64, |value, (i, &ty)| value | (ty as u64) << (i * 8));
+    unsafe {
+        Msr::new(IA32_PAT).write(value);
+        // Drop translations cached with the old memory types.
+        write_cr3(read_cr3());
+    }
+    true
+}
+
+/// Reads back the memory type of every PAT entry, `None` for reserved encodings.
+pub fn read() -> [Option<MemoryType>; 8] {
+    let value = unsafe { Msr::new(IA32_PAT).read() };
+    core::array::from_fn(|i| match (value >> (i * 8)) as u8 & 0x7 {
+        0 => Some(MemoryType::Uncacheable),
+        1 => Some(MemoryType::WriteCombining),
+        4 => Some(MemoryType::WriteThrough),
+        5 => Some(MemoryType::WriteProtected),
+        6 => Some(MemoryType::WriteBack),
+        7 => Some(MemoryType::UncachedMinus),
+        _ => None,
+    })
+}
+
+/// Maps a framebuffer write-combining, see [`map_physical_region`].
+///
+/// Pages that were already mapped, e.g. a framebuffer in low memory inside the
+/// bootloader's physical memory window, are switched to write-combining too.
+///
+/// ## Safety
+///
+/// [`init`] must have run, and nothing else may map the frames with another
+/// memory type.
+pub unsafe fn map_framebuffer_wc(
+    phys_start: u64,
+    size: u64,
+    mapper: &mut OffsetPageTable,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::pat_index(WRITE_COMBINING_INDEX);
+    let virt_start = unsafe { map_physical_region(phys_start, size, flags, mapper, frame_alloc)? };
+    if size == 0 {
+        return Ok(virt_start);
+    }
+    let first = Page::<Size4KiB>::containing_address(virt_start);
+    let last = Page::<Size4KiB>::containing_address(virt_start + size - 1);
+    for page in Page::range_inclusive(first, last) {
+        // Every page is mapped by now, with a level 1 entry or map_to would have failed.
+        if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+            flush.flush();
+        }
+    }
+    Ok(virt_start)
+}
+
+#[test_case]
+fn pat_has_write_combining_entry() {
+    if !is_supported() {
+        return;
+    }
+    let entries = read();
+    assert_eq!(entries[WRITE_COMBINING_INDEX as usize], Some(MemoryType::WriteCombining));
+    assert_eq!(entries[0], Some(MemoryType::WriteBack));
+    assert_eq!(entries[3], Some(MemoryType::Uncacheable));
+    assert_eq!(PageTableFlags::pat_index(WRITE_COMBINING_INDEX), PageTableFlags::WRITE_THROUGH);
+}
+
+#[test_case]
+fn framebuffer_fill_write_combining_vs_uncached() {
+    use crate::{cpu::rdtsc, memory::{mapper::{Translate, TranslateResult}, FRAME_ALLOCATOR, MAPPER}, serial_println};
+
+    const VGA_BUFFER: u64 = 0xB8000;
+    const SCREEN_CELLS: usize = 80 * 25;
+    const RUNS: usize = 3;
+
+    fn fill_cycles(buffer: *mut u16) -> u64 {
+        let mut saved = [0u16; SCREEN_CELLS];
+        for (i, cell) in saved.iter_mut().enumerate() {
+            *cell = unsafe { buffer.add(i).read_volatile() };
+        }
+        let start = rdtsc();
+        for i in 0..SCREEN_CELLS {
+            unsafe { buffer.add(i).write_volatile(0x0720); }
+        }
+        unsafe { core::arch::asm!("sfence", options(nostack, preserves_flags)); }
+        let cycles = rdtsc() - start;
+        for (i, &cell) in saved.iter().enumerate() {
+            unsafe { buffer.add(i).write_volatile(cell); }
+        }
+        cycles
+    }
+
+    // The fastest of a few runs, so one interrupt does not decide the result.
+    fn best_fill_cycles(buffer: *mut u16) -> u64 {
+        (0..RUNS).map(|_| fill_cycles(buffer)).min().unwrap()
+    }
+
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().unwrap();
+    let page = Page::<Size4KiB>::containing_address(VGA_BUFFER + mapper.phys_offset());
+    let TranslateResult::Mapped { flags: original_flags, .. } = mapper.translate(page.start_address()) else {
+        panic!("VGA buffer is not in the physical memory window");
+    };
+
+    let virt = unsafe { map_framebuffer_wc(VGA_BUFFER, 0x1000, mapper, allocator) }.unwrap();
+    let TranslateResult::Mapped { flags, .. } = mapper.translate(virt) else { unreachable!() };
+    assert!(flags.contains(PageTableFlags::WRITE_THROUGH) && !flags.contains(PageTableFlags::NO_CACHE));
+    let wc_cycles = best_fill_cycles(virt as *mut u16);
+
+    let uncached = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    unsafe { mapper.update_flags(page, uncached).unwrap().flush(); }
+    let uc_cycles = best_fill_cycles(virt as *mut u16);
+
+    unsafe { mapper.update_flags(page, original_flags).unwrap().flush(); }
+    serial_println!("full-screen fill: {} cycles write-combining, {} cycles NO_CACHE", wc_cycles, uc_cycles);
+    // Emulators may not model the memory types at all, allow a quarter of noise.
+    assert!(
+        wc_cycles <= uc_cycles + uc_cycles / 4,
+        "write-combining fill took {} cycles, NO_CACHE {}", wc_cycles, uc_cycles,
+    );
+}
@@ -0,0 +1,110 @@
+//! ARP: resolving on-link IPv4 addresses to MAC addresses, and answering
+//! for ours.
+
+use core::net::Ipv4Addr;
+use crate::sync::Mutex;
+use super::{poll_until, send_frame, Interface, NetError, BROADCAST_MAC, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+const PACKET_SIZE: usize = 28;
+
+const CACHE_SIZE: usize = 16;
+/// Requests sent before a host is given up on, and the wait for each reply.
+const REQUEST_ATTEMPTS: u32 = 3;
+const REQUEST_TIMEOUT_MS: u64 = 200;
+
+static CACHE: Mutex<ArpCache> = Mutex::new("ARP_CACHE", ArpCache::new());
+
+/// Known hosts, the oldest replaced first once full.
+struct ArpCache {
+    entries: [Option<(Ipv4Addr, [u8; 6])>; CACHE_SIZE],
+    next: usize,
+}
+
+impl ArpCache {
+    const fn new() -> Self {
+        ArpCache { entries: [None; CACHE_SIZE], next: 0 }
+    }
+
+    fn lookup(&self, ip: Ipv4Addr) -> Option<[u8; 6]> {
+        self.entries.iter().flatten().find(|(known, _)| *known == ip).map(|(_, mac)| *mac)
+    }
+
+    fn insert(&mut self, ip: Ipv4Addr, mac: [u8; 6]) {
+        if let Some(entry) = self.entries.iter_mut().flatten().find(|(known, _)| *known == ip) {
+            entry.1 = mac;
+            return;
+        }
+        self.entries[self.next] = Some((ip, mac));
+        self.next = (self.next + 1) % CACHE_SIZE;
+    }
+}
+
+/// Returns the cached MAC address of `ip`.
+pub fn lookup(ip: Ipv4Addr) -> Option<[u8; 6]> {
+    CACHE.lock().lookup(ip)
+}
+
+fn packet(op: u16, sender: ([u8; 6], Ipv4Addr), target: ([u8; 6], Ipv4Addr)) -> [u8; PACKET_SIZE] {
+    let mut packet = [0u8; PACKET_SIZE];
+    packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&op.to_be_bytes());
+    packet[8..14].copy_from_slice(&sender.0);
+    packet[14..18].copy_from_slice(&sender.1.octets());
+    packet[18..24].copy_from_slice(&target.0);
+    packet[24..28].copy_from_slice(&target.1.octets());
+    packet
+}
+
+/// Learns the sender of a received ARP packet, and answers requests for our address.
+pub(super) fn handle(interface: &Interface, packet: &[u8]) {
+    if packet.len() < PACKET_SIZE || packet[0..6] != [0x00, 0x01, 0x08, 0x00, 6, 4] {
+        return;
+    }
+    let op = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac: [u8; 6] = packet[8..14].try_into().unwrap();
+    let sender_ip = Ipv4Addr::new(packet[14], packet[15], packet[16], packet[17]);
+    let target_ip = Ipv4Addr::new(packet[24], packet[25], packet[26], packet[27]);
+    if !sender_ip.is_unspecified() {
+        CACHE.lock().insert(sender_ip, sender_mac);
+    }
+    if op == OP_REQUEST && interface.is_configured() && target_ip == interface.address {
+        let reply = self::packet(OP_REPLY, (interface.mac, interface.address), (sender_mac, sender_ip));
+        // Lost like any other frame if the ring is full, the host asks again.
+        let _ = send_frame(interface, sender_mac, ETHERTYPE_ARP, &reply);
+    }
+}
+
+/// Returns the MAC address of the on-link host `ip`, asking for it if it is
+/// not cached.
+pub fn resolve(interface: &Interface, ip: Ipv4Addr) -> Result<[u8; 6], NetError> {
+    if let Some(mac) = lookup(ip) {
+        return Ok(mac);
+    }
+    let request = packet(OP_REQUEST, (interface.mac, interface.address), ([0; 6], ip));
+    for _ in 0..REQUEST_ATTEMPTS {
+        send_frame(interface, BROADCAST_MAC, ETHERTYPE_ARP, &request)?;
+        if let Some(mac) = poll_until(REQUEST_TIMEOUT_MS, || lookup(ip)) {
+            return Ok(mac);
+        }
+    }
+    Err(NetError::Unreachable(ip))
+}
+
+#[test_case]
+fn arp_cache_replaces_the_oldest() {
+    let mut cache = ArpCache::new();
+    for i in 0..CACHE_SIZE as u8 + 1 {
+        cache.insert(Ipv4Addr::new(10, 0, 0, i), [i; 6]);
+    }
+    assert_eq!(cache.lookup(Ipv4Addr::new(10, 0, 0, 0)), None);
+    assert_eq!(cache.lookup(Ipv4Addr::new(10, 0, 0, 1)), Some([1; 6]));
+    assert_eq!(cache.lookup(Ipv4Addr::new(10, 0, 0, CACHE_SIZE as u8)), Some([CACHE_SIZE as u8; 6]));
+    cache.insert(Ipv4Addr::new(10, 0, 0, 1), [0xAA; 6]);
+    assert_eq!(cache.lookup(Ipv4Addr::new(10, 0, 0, 1)), Some([0xAA; 6]));
+}
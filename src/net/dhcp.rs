@@ -0,0 +1,481 @@
+//! A DHCP client: gets the address, netmask and gateway of the interface,
+//! and renews the lease at T1.
+//!
+//! Messages are retransmitted with exponential backoff. Lease expiry is not
+//! tracked, a renewal that fails is retried until one succeeds.
+
+use core::{fmt, net::Ipv4Addr};
+use crate::{cpu::rdtsc, pic::timer::{ms_to_ticks, ticks}, println, sync::Mutex};
+use super::{configure, interface, poll_until, udp::{UdpSocket, MAX_PAYLOAD}, NetError};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+/// Asks the server to broadcast its replies, we cannot take unicast before
+/// having an address.
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_OFFSET: usize = 240;
+/// BOOTP messages are at least this long, some servers drop shorter ones.
+const MESSAGE_SIZE: usize = 300;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+/// A lease time of all ones never expires.
+const INFINITE_LEASE: u32 = u32::MAX;
+
+/// The first wait for a reply, doubled after every retransmission.
+const INITIAL_TIMEOUT_MS: u64 = 1000;
+const ATTEMPTS: u32 = 4;
+/// The exchange starts over after a NAK at most this many times.
+const RESTARTS: u32 = 3;
+/// Wait before retrying a failed renewal.
+const RENEWAL_RETRY_SECS: u64 = 60;
+
+/// The tick at which to renew, and the lease.
+static RENEWAL: Mutex<Option<(u64, Lease)>> = Mutex::new("DHCP_RENEWAL", None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpError {
+    Truncated,
+    NotAReply,
+    BadCookie,
+    /// The option with this code runs past the message or has the wrong length.
+    MalformedOption(u8),
+    NoMessageType,
+    NoServerId,
+    /// The server refused the requested address.
+    Nak,
+    /// No reply after every retransmission.
+    Timeout,
+    Net(NetError),
+}
+
+impl fmt::Display for DhcpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DhcpError::Truncated => write!(f, "message truncated"),
+            DhcpError::NotAReply => write!(f, "not a reply"),
+            DhcpError::BadCookie => write!(f, "bad magic cookie"),
+            DhcpError::MalformedOption(code) => write!(f, "malformed option {}", code),
+            DhcpError::NoMessageType => write!(f, "no message type"),
+            DhcpError::NoServerId => write!(f, "no server identifier"),
+            DhcpError::Nak => write!(f, "address refused"),
+            DhcpError::Timeout => write!(f, "no reply from a server"),
+            DhcpError::Net(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<NetError> for DhcpError {
+    fn from(err: NetError) -> Self {
+        DhcpError::Net(err)
+    }
+}
+
+/// The options the client uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DhcpOptions {
+    pub message_type: Option<MessageType>,
+    pub server_id: Option<Ipv4Addr>,
+    /// In seconds.
+    pub lease_time: Option<u32>,
+    pub subnet_mask: Option<Ipv4Addr>,
+    /// The first router and DNS server listed.
+    pub router: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+}
+
+fn address(code: u8, data: &[u8]) -> Result<Ipv4Addr, DhcpError> {
+    <[u8; 4]>::try_from(data).map(Ipv4Addr::from).map_err(|_| DhcpError::MalformedOption(code))
+}
+
+/// The first of a list of addresses.
+fn first_address(code: u8, data: &[u8]) -> Result<Ipv4Addr, DhcpError> {
+    if data.is_empty() || data.len() % 4 != 0 {
+        return Err(DhcpError::MalformedOption(code));
+    }
+    address(code, &data[..4])
+}
+
+impl DhcpOptions {
+    /// Parses the options field, after the magic cookie. Unknown options are
+    /// skipped, and the end option may be missing.
+    pub fn parse(mut options: &[u8]) -> Result<Self, DhcpError> {
+        let mut parsed = DhcpOptions::default();
+        while let Some((&code, rest)) = options.split_first() {
+            match code {
+                OPTION_PAD => {
+                    options = rest;
+                    continue;
+                },
+                OPTION_END => break,
+                _ => {},
+            }
+            let Some((&len, rest)) = rest.split_first() else {
+                return Err(DhcpError::MalformedOption(code));
+            };
+            if rest.len() < len as usize {
+                return Err(DhcpError::MalformedOption(code));
+            }
+            let (data, rest) = rest.split_at(len as usize);
+            options = rest;
+            match code {
+                OPTION_MESSAGE_TYPE => {
+                    let [value] = data else {
+                        return Err(DhcpError::MalformedOption(code));
+                    };
+                    parsed.message_type = Some(MessageType::from_u8(*value).ok_or(DhcpError::MalformedOption(code))?);
+                },
+                OPTION_SERVER_ID => parsed.server_id = Some(address(code, data)?),
+                OPTION_LEASE_TIME => parsed.lease_time = Some(u32::from(address(code, data)?)),
+                OPTION_SUBNET_MASK => parsed.subnet_mask = Some(address(code, data)?),
+                OPTION_ROUTER => parsed.router = Some(first_address(code, data)?),
+                OPTION_DNS => parsed.dns = Some(first_address(code, data)?),
+                _ => {},
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// A reply from a server, as far as the client needs it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhcpReply {
+    pub xid: u32,
+    pub your_address: Ipv4Addr,
+    pub client_mac: [u8; 6],
+    pub message_type: MessageType,
+    pub options: DhcpOptions,
+}
+
+impl DhcpReply {
+    pub fn parse(message: &[u8]) -> Result<Self, DhcpError> {
+        if message.len() < OPTIONS_OFFSET {
+            return Err(DhcpError::Truncated);
+        }
+        if message[0] != OP_REPLY {
+            return Err(DhcpError::NotAReply);
+        }
+        if message[236..240] != MAGIC_COOKIE {
+            return Err(DhcpError::BadCookie);
+        }
+        let options = DhcpOptions::parse(&message[OPTIONS_OFFSET..])?;
+        Ok(DhcpReply {
+            xid: u32::from_be_bytes(message[4..8].try_into().unwrap()),
+            your_address: Ipv4Addr::new(message[16], message[17], message[18], message[19]),
+            client_mac: message[28..34].try_into().unwrap(),
+            message_type: options.message_type.ok_or(DhcpError::NoMessageType)?,
+            options,
+        })
+    }
+}
+
+/// A leased address and what came with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    /// In seconds.
+    pub duration: u32,
+}
+
+impl Lease {
+    fn from_ack(ack: &DhcpReply, server: Ipv4Addr) -> Result<Self, DhcpError> {
+        if ack.message_type == MessageType::Nak {
+            return Err(DhcpError::Nak);
+        }
+        Ok(Lease {
+            address: ack.your_address,
+            // Without a mask, everything else is reached through the gateway.
+            netmask: ack.options.subnet_mask.unwrap_or(Ipv4Addr::BROADCAST),
+            gateway: ack.options.router,
+            dns: ack.options.dns,
+            server: ack.options.server_id.unwrap_or(server),
+            duration: ack.options.lease_time.unwrap_or(INFINITE_LEASE),
+        })
+    }
+
+    /// T1, the time to renew at, in seconds.
+    pub fn renewal_time(&self) -> u32 {
+        self.duration / 2
+    }
+}
+
+/// What a request asks for.
+enum Request {
+    /// Selecting an offer: the offered address and the server that made it.
+    Selecting(Ipv4Addr, Ipv4Addr),
+    /// Renewing the lease on this address.
+    Renewing(Ipv4Addr),
+}
+
+/// Writes a DISCOVER, or a REQUEST when `request` is given, into `message`.
+fn build(message: &mut [u8; MESSAGE_SIZE], xid: u32, mac: [u8; 6], request: Option<Request>) {
+    message.fill(0);
+    message[0] = OP_REQUEST;
+    message[1] = HARDWARE_ETHERNET;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    match request {
+        Some(Request::Renewing(address)) => message[12..16].copy_from_slice(&address.octets()),
+        _ => message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes()),
+    }
+    message[28..34].copy_from_slice(&mac);
+    message[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut options = OPTIONS_OFFSET;
+    let mut option = |code: u8, data: &[u8]| {
+        message[options] = code;
+        message[options + 1] = data.len() as u8;
+        message[options + 2..options + 2 + data.len()].copy_from_slice(data);
+        options += 2 + data.len();
+    };
+    let message_type = if request.is_some() { MessageType::Request } else { MessageType::Discover };
+    option(OPTION_MESSAGE_TYPE, &[message_type as u8]);
+    if let Some(Request::Selecting(address, server)) = request {
+        option(OPTION_REQUESTED_ADDRESS, &address.octets());
+        option(OPTION_SERVER_ID, &server.octets());
+    }
+    option(OPTION_PARAMETERS, &[OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS, OPTION_LEASE_TIME]);
+    message[options] = OPTION_END;
+}
+
+/// Sends `message` to `server` until a reply of one of the `expected` types
+/// for `xid` comes in, doubling the wait after every attempt.
+fn exchange(socket: &UdpSocket, server: Ipv4Addr, message: &[u8], xid: u32, mac: [u8; 6],
+    expected: &[MessageType]) -> Result<DhcpReply, DhcpError> {
+    let mut buffer = [0u8; MAX_PAYLOAD];
+    let mut timeout = INITIAL_TIMEOUT_MS;
+    for _ in 0..ATTEMPTS {
+        socket.send_to(server, SERVER_PORT, message)?;
+        let reply = poll_until(timeout, || {
+            while let Some((_, _, len)) = socket.recv_from(&mut buffer) {
+                // Malformed replies and replies to other clients are ignored.
+                let Ok(reply) = DhcpReply::parse(&buffer[..len.min(MAX_PAYLOAD)]) else {
+                    continue;
+                };
+                if reply.xid == xid && reply.client_mac == mac && expected.contains(&reply.message_type) {
+                    return Some(reply);
+                }
+            }
+            None
+        });
+        if let Some(reply) = reply {
+            return Ok(reply);
+        }
+        timeout *= 2;
+    }
+    Err(DhcpError::Timeout)
+}
+
+fn new_xid(mac: [u8; 6]) -> u32 {
+    rdtsc() as u32 ^ u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]])
+}
+
+/// DISCOVER, OFFER, REQUEST and ACK.
+fn obtain(socket: &UdpSocket, mac: [u8; 6]) -> Result<Lease, DhcpError> {
+    let xid = new_xid(mac);
+    let mut message = [0u8; MESSAGE_SIZE];
+    build(&mut message, xid, mac, None);
+    let offer = exchange(socket, Ipv4Addr::BROADCAST, &message, xid, mac, &[MessageType::Offer])?;
+    let server = offer.options.server_id.ok_or(DhcpError::NoServerId)?;
+
+    build(&mut message, xid, mac, Some(Request::Selecting(offer.your_address, server)));
+    let ack = exchange(socket, Ipv4Addr::BROADCAST, &message, xid, mac, &[MessageType::Ack, MessageType::Nak])?;
+    Lease::from_ack(&ack, server)
+}
+
+/// Configures the interface with `lease` and schedules its renewal at T1.
+fn install(lease: &Lease) {
+    configure(lease.address, lease.netmask, lease.gateway);
+    schedule_renewal(lease, lease.renewal_time() as u64);
+}
+
+fn schedule_renewal(lease: &Lease, secs: u64) {
+    *RENEWAL.lock() = if lease.duration == INFINITE_LEASE {
+        None
+    } else {
+        Some((ticks() + ms_to_ticks(secs * 1000), *lease))
+    };
+}
+
+/// Gets a lease and configures the interface with it. Waits up to 15 seconds
+/// for each reply.
+pub fn run() -> Result<Lease, DhcpError> {
+    let mac = interface().ok_or(NetError::NoInterface)?.mac;
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let mut result = Err(DhcpError::Nak);
+    for _ in 0..RESTARTS {
+        result = obtain(&socket, mac);
+        if result != Err(DhcpError::Nak) {
+            break;
+        }
+    }
+    let lease = result?;
+    install(&lease);
+    Ok(lease)
+}
+
+/// Asks the server of `lease` to extend it.
+fn renew(lease: &Lease) -> Result<Lease, DhcpError> {
+    let mac = interface().ok_or(NetError::NoInterface)?.mac;
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let xid = new_xid(mac);
+    let mut message = [0u8; MESSAGE_SIZE];
+    build(&mut message, xid, mac, Some(Request::Renewing(lease.address)));
+    let ack = exchange(&socket, lease.server, &message, xid, mac, &[MessageType::Ack, MessageType::Nak])?;
+    Lease::from_ack(&ack, lease.server)
+}
+
+/// Renews the lease once T1 has passed. A refused renewal starts over with a
+/// DISCOVER, a failed one is retried later.
+pub fn renew_if_due() {
+    let Some((deadline, lease)) = *RENEWAL.lock() else {
+        return;
+    };
+    if ticks() < deadline {
+        return;
+    }
+    let result = match renew(&lease) {
+        Ok(renewed) => {
+            install(&renewed);
+            Ok(renewed)
+        },
+        Err(DhcpError::Nak) => run(),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        println!("DHCP: renewing {} failed: {}", lease.address, err);
+        schedule_renewal(&lease, RENEWAL_RETRY_SECS);
+    }
+}
+
+#[test_case]
+fn dhcp_option_parsing() {
+    assert_eq!(DhcpOptions::parse(&[]), Ok(DhcpOptions::default()));
+    // Padding, an unknown option, and anything after the end option.
+    let options = DhcpOptions::parse(&[0, 0, 53, 1, 2, 12, 3, b'k', b'o', b's', 255, 53, 9]).unwrap();
+    assert_eq!(options.message_type, Some(MessageType::Offer));
+
+    let options = DhcpOptions::parse(&[
+        1, 4, 255, 255, 255, 0,
+        3, 8, 10, 0, 2, 2, 10, 0, 2, 1,
+        6, 4, 10, 0, 2, 3,
+        51, 4, 0, 0, 0x0E, 0x10,
+        54, 4, 10, 0, 2, 2,
+    ]).unwrap();
+    assert_eq!(options.subnet_mask, Some(Ipv4Addr::new(255, 255, 255, 0)));
+    assert_eq!(options.router, Some(Ipv4Addr::new(10, 0, 2, 2)));
+    assert_eq!(options.dns, Some(Ipv4Addr::new(10, 0, 2, 3)));
+    assert_eq!(options.lease_time, Some(3600));
+    assert_eq!(options.server_id, Some(Ipv4Addr::new(10, 0, 2, 2)));
+}
+
+#[test_case]
+fn dhcp_malformed_options() {
+    // The length byte is missing, or the data runs past the end.
+    assert_eq!(DhcpOptions::parse(&[53]), Err(DhcpError::MalformedOption(53)));
+    assert_eq!(DhcpOptions::parse(&[51, 4, 0, 0]), Err(DhcpError::MalformedOption(51)));
+    assert_eq!(DhcpOptions::parse(&[12, 255]), Err(DhcpError::MalformedOption(12)));
+    // Wrong lengths for the option.
+    assert_eq!(DhcpOptions::parse(&[53, 0]), Err(DhcpError::MalformedOption(53)));
+    assert_eq!(DhcpOptions::parse(&[53, 1, 42]), Err(DhcpError::MalformedOption(53)));
+    assert_eq!(DhcpOptions::parse(&[54, 3, 10, 0, 2]), Err(DhcpError::MalformedOption(54)));
+    assert_eq!(DhcpOptions::parse(&[3, 0]), Err(DhcpError::MalformedOption(3)));
+    assert_eq!(DhcpOptions::parse(&[6, 6, 10, 0, 2, 3, 8, 8]), Err(DhcpError::MalformedOption(6)));
+
+    let mut message = [0u8; OPTIONS_OFFSET + 1];
+    assert_eq!(DhcpReply::parse(&message[..OPTIONS_OFFSET - 1]), Err(DhcpError::Truncated));
+    message[0] = OP_REPLY;
+    assert_eq!(DhcpReply::parse(&message), Err(DhcpError::BadCookie));
+    message[236..240].copy_from_slice(&MAGIC_COOKIE);
+    message[OPTIONS_OFFSET] = OPTION_END;
+    assert_eq!(DhcpReply::parse(&message), Err(DhcpError::NoMessageType));
+}
+
+#[test_case]
+fn dhcp_message_round_trip() {
+    let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    let mut message = [0u8; MESSAGE_SIZE];
+    build(&mut message, 0xDEADBEEF, mac, Some(Request::Selecting(Ipv4Addr::new(10, 0, 2, 15), Ipv4Addr::new(10, 0, 2, 2))));
+    assert_eq!(&message[OPTIONS_OFFSET..OPTIONS_OFFSET + 3], &[OPTION_MESSAGE_TYPE, 1, MessageType::Request as u8]);
+    // What a server would send back.
+    message[0] = OP_REPLY;
+    message[16..20].copy_from_slice(&[10, 0, 2, 15]);
+    message[OPTIONS_OFFSET + 2] = MessageType::Ack as u8;
+    let reply = DhcpReply::parse(&message).unwrap();
+    assert_eq!(reply.xid, 0xDEADBEEF);
+    assert_eq!(reply.client_mac, mac);
+    assert_eq!(reply.message_type, MessageType::Ack);
+    assert_eq!(reply.options.server_id, Some(Ipv4Addr::new(10, 0, 2, 2)));
+    let lease = Lease::from_ack(&reply, Ipv4Addr::new(10, 0, 2, 2)).unwrap();
+    assert_eq!(lease.address, Ipv4Addr::new(10, 0, 2, 15));
+    assert_eq!(lease.netmask, Ipv4Addr::BROADCAST);
+    assert_eq!(lease.duration, INFINITE_LEASE);
+}
+
+#[test_case]
+fn dhcp_lease_from_qemu() {
+    // QEMU user networking: the guest gets 10.0.2.15, the gateway and DHCP
+    // server is 10.0.2.2 and the DNS server 10.0.2.3. Boot already ran DHCP,
+    // this goes through the exchange again.
+    assert!(interface().is_some(), "no network interface");
+    let lease = run().unwrap();
+    assert_eq!(lease.address, Ipv4Addr::new(10, 0, 2, 15));
+    assert_eq!(lease.netmask, Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(lease.gateway, Some(Ipv4Addr::new(10, 0, 2, 2)));
+    assert_eq!(lease.dns, Some(Ipv4Addr::new(10, 0, 2, 3)));
+    assert_eq!(lease.server, Ipv4Addr::new(10, 0, 2, 2));
+    assert!(lease.renewal_time() > 0);
+
+    let interface = interface().unwrap();
+    assert_eq!(interface.address, lease.address);
+    assert!(interface.on_link(Ipv4Addr::new(10, 0, 2, 3)));
+    assert!(!interface.on_link(Ipv4Addr::new(10, 0, 3, 3)));
+    assert!(RENEWAL.lock().is_some());
+}
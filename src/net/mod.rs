@@ -0,0 +1,294 @@
+//! A minimal IPv4 stack on the e1000: Ethernet framing, ARP, IPv4 and UDP.
+//!
+//! Nothing runs in the background. Received frames wait in the driver's queue
+//! until [`poll`] dispatches them, which the blocking calls here do while they
+//! wait and the idle loop does otherwise.
+
+pub mod arp;
+pub mod dhcp;
+pub mod udp;
+
+use core::{fmt, net::Ipv4Addr, sync::atomic::{AtomicU16, Ordering}};
+use crate::{drivers::e1000::{self, MAX_FRAME}, pic::timer::{idle, ms_to_ticks, ticks}, println, sync::Mutex};
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const PROTOCOL_UDP: u8 = 17;
+pub const BROADCAST_MAC: [u8; 6] = [0xFF; 6];
+
+const ETHERNET_HEADER: usize = 14;
+const IPV4_HEADER: usize = 20;
+/// The largest IPv4 payload that fits a frame, fragments are not sent.
+pub const MAX_IPV4_PAYLOAD: usize = MAX_FRAME - ETHERNET_HEADER - IPV4_HEADER;
+const DEFAULT_TTL: u8 = 64;
+/// Don't fragment, and the mask of the more fragments flag and the offset.
+const IPV4_DF: u16 = 0x4000;
+const IPV4_FRAGMENT: u16 = 0x3FFF;
+
+/// Address, netmask and gateway to use instead of asking DHCP.
+const STATIC_CONFIG: Option<(Ipv4Addr, Ipv4Addr, Ipv4Addr)> = None;
+
+static INTERFACE: Mutex<Option<Interface>> = Mutex::new("NET_INTERFACE", None);
+static NEXT_IPV4_ID: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    NoInterface,
+    /// No MAC address for the host or the gateway towards it.
+    Unreachable(Ipv4Addr),
+    TxFull,
+    TooLarge,
+    PortInUse(u16),
+    NoSockets,
+    Timeout,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::NoInterface => write!(f, "no network interface"),
+            NetError::Unreachable(ip) => write!(f, "{} is unreachable", ip),
+            NetError::TxFull => write!(f, "transmit ring full"),
+            NetError::TooLarge => write!(f, "packet too large"),
+            NetError::PortInUse(port) => write!(f, "port {} in use", port),
+            NetError::NoSockets => write!(f, "no free sockets"),
+            NetError::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+/// The configuration of the NIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interface {
+    pub mac: [u8; 6],
+    /// 0.0.0.0 until configured.
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Interface {
+    pub fn is_configured(&self) -> bool {
+        !self.address.is_unspecified()
+    }
+
+    /// Whether `ip` is reached directly rather than through the gateway.
+    pub fn on_link(&self, ip: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_bits();
+        ip.to_bits() & mask == self.address.to_bits() & mask
+    }
+
+    pub fn subnet_broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(self.address.to_bits() | !self.netmask.to_bits())
+    }
+
+    /// Whether a packet sent to `ip` is for this host. Anything is taken
+    /// before the interface is configured, DHCP offers may be sent to the
+    /// address being offered.
+    fn accepts(&self, ip: Ipv4Addr) -> bool {
+        !self.is_configured() || ip == self.address || ip.is_broadcast() || ip == self.subnet_broadcast()
+    }
+}
+
+/// Returns the interface, if there is a NIC.
+pub fn interface() -> Option<Interface> {
+    *INTERFACE.lock()
+}
+
+/// Gives the interface an address.
+pub fn configure(address: Ipv4Addr, netmask: Ipv4Addr, gateway: Option<Ipv4Addr>) {
+    if let Some(interface) = INTERFACE.lock().as_mut() {
+        interface.address = address;
+        interface.netmask = netmask;
+        interface.gateway = gateway;
+    }
+}
+
+/// Adds `data` to the one's complement sum `sum` as big endian 16 bit words.
+/// Only the last part of a chained sum may have an odd length.
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Folds the carries of `sum` back in and complements it: the internet checksum.
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Sends `payload` in an Ethernet frame to `destination`.
+fn send_frame(interface: &Interface, destination: [u8; 6], ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+    let len = ETHERNET_HEADER + payload.len();
+    if len > MAX_FRAME {
+        return Err(NetError::TooLarge);
+    }
+    let mut frame = [0u8; MAX_FRAME];
+    frame[0..6].copy_from_slice(&destination);
+    frame[6..12].copy_from_slice(&interface.mac);
+    frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    // The NIC pads short frames.
+    frame[ETHERNET_HEADER..len].copy_from_slice(payload);
+    e1000::send(&frame[..len]).map_err(|_| NetError::TxFull)
+}
+
+/// Sends `payload` to `destination` in an IPv4 packet, resolving the next hop
+/// with ARP first if needed.
+pub fn send_ipv4(destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    let interface = interface().ok_or(NetError::NoInterface)?;
+    if payload.len() > MAX_IPV4_PAYLOAD {
+        return Err(NetError::TooLarge);
+    }
+    let mac = if destination.is_broadcast() || (interface.is_configured() && destination == interface.subnet_broadcast()) {
+        BROADCAST_MAC
+    } else if interface.on_link(destination) {
+        arp::resolve(&interface, destination)?
+    } else {
+        arp::resolve(&interface, interface.gateway.ok_or(NetError::Unreachable(destination))?)?
+    };
+
+    let len = IPV4_HEADER + payload.len();
+    let mut packet = [0u8; MAX_FRAME - ETHERNET_HEADER];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    packet[4..6].copy_from_slice(&NEXT_IPV4_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet[6..8].copy_from_slice(&IPV4_DF.to_be_bytes());
+    packet[8] = DEFAULT_TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&interface.address.octets());
+    packet[16..20].copy_from_slice(&destination.octets());
+    let checksum = checksum_finish(checksum_add(0, &packet[..IPV4_HEADER]));
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet[IPV4_HEADER..len].copy_from_slice(payload);
+    send_frame(&interface, mac, ETHERTYPE_IPV4, &packet[..len])
+}
+
+/// A received IPv4 packet.
+struct Ipv4Packet<'a> {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Checks the header of `packet`. Fragments are dropped, they are not reassembled.
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < IPV4_HEADER || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0x0F) as usize * 4;
+        let len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < IPV4_HEADER || len < header_len || len > packet.len() {
+            return None;
+        }
+        if checksum_finish(checksum_add(0, &packet[..header_len])) != 0 {
+            return None;
+        }
+        if u16::from_be_bytes([packet[6], packet[7]]) & IPV4_FRAGMENT != 0 {
+            return None;
+        }
+        Some(Ipv4Packet {
+            source: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+            destination: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+            protocol: packet[9],
+            payload: &packet[header_len..len],
+        })
+    }
+}
+
+/// Dispatches every frame the NIC received, returning how many there were.
+pub fn poll() -> usize {
+    let Some(interface) = interface() else {
+        return 0;
+    };
+    let mut frame = [0u8; MAX_FRAME];
+    let mut count = 0;
+    while let Some(len) = e1000::receive(&mut frame) {
+        count += 1;
+        let frame = &frame[..len.min(MAX_FRAME)];
+        if frame.len() < ETHERNET_HEADER {
+            continue;
+        }
+        let payload = &frame[ETHERNET_HEADER..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => arp::handle(&interface, payload),
+            ETHERTYPE_IPV4 => {
+                let Some(packet) = Ipv4Packet::parse(payload) else {
+                    continue;
+                };
+                if interface.accepts(packet.destination) && packet.protocol == PROTOCOL_UDP {
+                    udp::handle(packet.source, packet.destination, packet.payload);
+                }
+            },
+            _ => {},
+        }
+    }
+    count
+}
+
+/// Polls until `done` returns something or `ms` milliseconds pass, idling in
+/// between. Interrupts must be enabled.
+pub fn poll_until<T>(ms: u64, mut done: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = ticks() + ms_to_ticks(ms);
+    loop {
+        poll();
+        if let Some(value) = done() {
+            return Some(value);
+        }
+        if ticks() >= deadline {
+            return None;
+        }
+        idle();
+    }
+}
+
+/// Sets up the interface of the NIC with the `mac` address, and configures it
+/// statically or with DHCP.
+pub fn init(mac: [u8; 6]) {
+    *INTERFACE.lock() = Some(Interface {
+        mac,
+        address: Ipv4Addr::UNSPECIFIED,
+        netmask: Ipv4Addr::UNSPECIFIED,
+        gateway: None,
+    });
+    if let Some((address, netmask, gateway)) = STATIC_CONFIG {
+        configure(address, netmask, Some(gateway));
+        return;
+    }
+    match dhcp::run() {
+        Ok(lease) => println!("DHCP: {} netmask {}, gateway {:?}, {}s lease from {}",
+            lease.address, lease.netmask, lease.gateway, lease.duration, lease.server),
+        Err(err) => println!("DHCP: {}", err),
+    }
+}
+
+#[test_case]
+fn ipv4_header_checksum() {
+    let mut header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+        0x00, 0x00, 0xC0, 0xA8, 0x00, 0x01, 0xC0, 0xA8, 0x00, 0xC7,
+    ];
+    assert_eq!(checksum_finish(checksum_add(0, &header)), 0xB861);
+    header[10..12].copy_from_slice(&0xB861u16.to_be_bytes());
+    assert_eq!(checksum_finish(checksum_add(0, &header)), 0);
+
+    let mut packet = [0u8; 0x73];
+    packet[..IPV4_HEADER].copy_from_slice(&header);
+    let parsed = Ipv4Packet::parse(&packet).unwrap();
+    assert_eq!(parsed.source, Ipv4Addr::new(192, 168, 0, 1));
+    assert_eq!(parsed.destination, Ipv4Addr::new(192, 168, 0, 199));
+    assert_eq!(parsed.payload.len(), 0x73 - IPV4_HEADER);
+    // Shorter than its total length, or corrupted.
+    assert!(Ipv4Packet::parse(&packet[..0x72]).is_none());
+    packet[8] = 0x3F;
+    assert!(Ipv4Packet::parse(&packet).is_none());
+}
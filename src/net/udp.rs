@@ -0,0 +1,249 @@
+//! UDP: checksums, sockets and the table demultiplexing datagrams to them
+//! by port.
+
+use core::net::Ipv4Addr;
+use crate::sync::Mutex;
+use super::{checksum_add, checksum_finish, interface, poll, poll_until, send_ipv4, NetError, MAX_IPV4_PAYLOAD, PROTOCOL_UDP};
+
+const HEADER_SIZE: usize = 8;
+pub const MAX_PAYLOAD: usize = MAX_IPV4_PAYLOAD - HEADER_SIZE;
+
+const MAX_SOCKETS: usize = 8;
+/// Datagrams kept per socket until read, later ones are dropped.
+const SOCKET_QUEUE: usize = 4;
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+static SOCKETS: Mutex<SocketTable> = Mutex::new("UDP_SOCKETS", SocketTable::new());
+
+/// Sum of the pseudo header the checksum covers along with the datagram.
+fn pseudo_header_sum(source: Ipv4Addr, destination: Ipv4Addr, len: usize) -> u32 {
+    let mut header = [0u8; 12];
+    header[0..4].copy_from_slice(&source.octets());
+    header[4..8].copy_from_slice(&destination.octets());
+    header[9] = PROTOCOL_UDP;
+    header[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+    checksum_add(0, &header)
+}
+
+/// The checksum of `datagram`, header and payload, whose checksum field is zero.
+pub fn checksum(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) -> u16 {
+    let sum = checksum_finish(checksum_add(pseudo_header_sum(source, destination, datagram.len()), datagram));
+    // Zero says the sender computed no checksum, so a zero result is sent as all ones.
+    if sum == 0 { 0xFFFF } else { sum }
+}
+
+/// Whether the checksum of a received `datagram` is right or absent.
+pub fn verify_checksum(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) -> bool {
+    if datagram.len() < HEADER_SIZE {
+        return false;
+    }
+    datagram[6..8] == [0, 0]
+        || checksum_finish(checksum_add(pseudo_header_sum(source, destination, datagram.len()), datagram)) == 0
+}
+
+/// Datagrams received on a port, like the NIC's frame queue.
+struct DatagramQueue {
+    payloads: [[u8; MAX_PAYLOAD]; SOCKET_QUEUE],
+    /// Sender address and port, and the payload length.
+    senders: [(Ipv4Addr, u16, usize); SOCKET_QUEUE],
+    head: usize,
+    len: usize,
+}
+
+impl DatagramQueue {
+    const fn new() -> Self {
+        DatagramQueue {
+            payloads: [[0; MAX_PAYLOAD]; SOCKET_QUEUE],
+            senders: [(Ipv4Addr::UNSPECIFIED, 0, 0); SOCKET_QUEUE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, source: Ipv4Addr, port: u16, payload: &[u8]) {
+        if self.len == SOCKET_QUEUE {
+            return;
+        }
+        let slot = (self.head + self.len) % SOCKET_QUEUE;
+        let len = payload.len().min(MAX_PAYLOAD);
+        self.payloads[slot][..len].copy_from_slice(&payload[..len]);
+        self.senders[slot] = (source, port, len);
+        self.len += 1;
+    }
+
+    fn pop(&mut self, buffer: &mut [u8]) -> Option<(Ipv4Addr, u16, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        let (source, port, len) = self.senders[self.head];
+        let copied = len.min(buffer.len());
+        buffer[..copied].copy_from_slice(&self.payloads[self.head][..copied]);
+        self.head = (self.head + 1) % SOCKET_QUEUE;
+        self.len -= 1;
+        Some((source, port, len))
+    }
+}
+
+struct SocketTable {
+    ports: [Option<u16>; MAX_SOCKETS],
+    queues: [DatagramQueue; MAX_SOCKETS],
+    next_ephemeral: u16,
+}
+
+impl SocketTable {
+    const fn new() -> Self {
+        SocketTable {
+            ports: [None; MAX_SOCKETS],
+            queues: [const { DatagramQueue::new() }; MAX_SOCKETS],
+            next_ephemeral: FIRST_EPHEMERAL_PORT,
+        }
+    }
+
+    fn slot(&self, port: u16) -> Option<usize> {
+        self.ports.iter().position(|bound| *bound == Some(port))
+    }
+
+    /// A port from the dynamic range that is not bound.
+    fn ephemeral_port(&mut self) -> u16 {
+        loop {
+            let port = self.next_ephemeral;
+            self.next_ephemeral = self.next_ephemeral.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            // There are fewer sockets than ports, so this ends.
+            if self.slot(port).is_none() {
+                return port;
+            }
+        }
+    }
+}
+
+/// Queues a received datagram on the socket bound to its port.
+pub(super) fn handle(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if !verify_checksum(source, destination, datagram) {
+        return;
+    }
+    let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let destination_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let mut table = SOCKETS.lock();
+    if let Some(slot) = table.slot(destination_port) {
+        table.queues[slot].push(source, source_port, &datagram[HEADER_SIZE..]);
+    }
+}
+
+/// A bound UDP port, released when dropped.
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds `port`, or a free one from the dynamic range if it is 0.
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut table = SOCKETS.lock();
+        let slot = table.ports.iter().position(Option::is_none).ok_or(NetError::NoSockets)?;
+        let port = if port == 0 { table.ephemeral_port() } else { port };
+        if table.slot(port).is_some() {
+            return Err(NetError::PortInUse(port));
+        }
+        table.ports[slot] = Some(port);
+        table.queues[slot].head = 0;
+        table.queues[slot].len = 0;
+        Ok(UdpSocket { port })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, ip: Ipv4Addr, port: u16, payload: &[u8]) -> Result<(), NetError> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(NetError::TooLarge);
+        }
+        let source = interface().ok_or(NetError::NoInterface)?.address;
+        let len = HEADER_SIZE + payload.len();
+        let mut datagram = [0u8; MAX_IPV4_PAYLOAD];
+        datagram[0..2].copy_from_slice(&self.port.to_be_bytes());
+        datagram[2..4].copy_from_slice(&port.to_be_bytes());
+        datagram[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        datagram[HEADER_SIZE..len].copy_from_slice(payload);
+        let checksum = checksum(source, ip, &datagram[..len]);
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+        send_ipv4(ip, PROTOCOL_UDP, &datagram[..len])
+    }
+
+    /// Dispatches what the NIC received, then takes the oldest datagram for
+    /// this port. The payload is copied into `buffer`, truncated to its length,
+    /// and the sender address and port are returned with the payload length.
+    pub fn recv_from(&self, buffer: &mut [u8]) -> Option<(Ipv4Addr, u16, usize)> {
+        poll();
+        self.take(buffer)
+    }
+
+    /// Like [`UdpSocket::recv_from`], waiting up to `ms` milliseconds for a datagram.
+    pub fn recv_from_timeout(&self, buffer: &mut [u8], ms: u64) -> Result<(Ipv4Addr, u16, usize), NetError> {
+        poll_until(ms, || self.take(buffer)).ok_or(NetError::Timeout)
+    }
+
+    fn take(&self, buffer: &mut [u8]) -> Option<(Ipv4Addr, u16, usize)> {
+        let mut table = SOCKETS.lock();
+        let slot = table.slot(self.port)?;
+        table.queues[slot].pop(buffer)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let mut table = SOCKETS.lock();
+        if let Some(slot) = table.slot(self.port) {
+            table.ports[slot] = None;
+        }
+    }
+}
+
+#[test_case]
+fn udp_checksums() {
+    let (source, destination) = (Ipv4Addr::new(10, 0, 2, 15), Ipv4Addr::new(10, 0, 2, 2));
+    // From port 68 to 67, carrying "hi".
+    let mut datagram = [0, 68, 0, 67, 0, 10, 0, 0, b'h', b'i'];
+    assert_eq!(checksum(source, destination, &datagram), 0x7ED9);
+    datagram[6..8].copy_from_slice(&0x7ED9u16.to_be_bytes());
+    assert!(verify_checksum(source, destination, &datagram));
+    datagram[9] = b'o';
+    assert!(!verify_checksum(source, destination, &datagram));
+    // No checksum at all.
+    datagram[6..8].copy_from_slice(&[0, 0]);
+    assert!(verify_checksum(source, destination, &datagram));
+
+    // A payload whose sum makes the checksum zero, which is sent as all ones.
+    let mut datagram = [0, 68, 0, 67, 0, 10, 0, 0, 0xE7, 0x42];
+    assert_eq!(checksum(source, destination, &datagram), 0xFFFF);
+    datagram[6..8].copy_from_slice(&[0xFF, 0xFF]);
+    assert!(verify_checksum(source, destination, &datagram));
+}
+
+#[test_case]
+fn udp_port_table() {
+    let socket = UdpSocket::bind(5000).unwrap();
+    assert!(matches!(UdpSocket::bind(5000), Err(NetError::PortInUse(5000))));
+    let ephemeral = UdpSocket::bind(0).unwrap();
+    assert!(ephemeral.local_port() >= FIRST_EPHEMERAL_PORT);
+
+    // Only the bound port gets the datagram.
+    let source = Ipv4Addr::new(10, 0, 2, 2);
+    let destination = Ipv4Addr::new(10, 0, 2, 15);
+    handle(source, destination, &[0x12, 0x34, 0x13, 0x88, 0, 11, 0, 0, b'a', b'b', b'c']);
+    let mut buffer = [0u8; 8];
+    assert_eq!(socket.take(&mut buffer), Some((source, 0x1234, 3)));
+    assert_eq!(&buffer[..3], b"abc");
+    assert_eq!(socket.take(&mut buffer), None);
+    assert_eq!(ephemeral.take(&mut buffer), None);
+
+    drop(socket);
+    drop(UdpSocket::bind(5000).unwrap());
+}
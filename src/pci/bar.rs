@@ -0,0 +1,316 @@
+//! Base address registers: decoding, size probing and mapping.
+
+use core::fmt;
+use crate::{
+    memory::{
+        frame_allocator::FrameAllocator,
+        mapper::{MapToError, OffsetPageTable},
+        paging::{map_physical_region, PageTableFlags, Size4KiB},
+    },
+    tables::{port::{Port, PortRead, PortWrite}, without_interrupts},
+};
+use super::{PciDevice, CONFIG_BAR0, CONFIG_COMMAND};
+
+/// BAR bit 0: the BAR decodes I/O ports instead of memory.
+const BAR_IO: u32 = 0x1;
+/// Memory BAR bits 1-2: 0b10 for a 64-bit BAR spanning two slots.
+const BAR_MEMORY_TYPE: u32 = 0x6;
+const BAR_MEMORY_64: u32 = 0x4;
+const BAR_PREFETCHABLE: u32 = 0x8;
+const BAR_IO_ADDRESS: u32 = !0x3;
+const BAR_MEMORY_ADDRESS: u32 = !0xF;
+
+/// Command register bits enabling I/O and memory decoding.
+const COMMAND_IO_SPACE: u16 = 0x1;
+const COMMAND_MEMORY_SPACE: u16 = 0x2;
+/// Command register bit letting the device start DMA.
+const COMMAND_BUS_MASTER: u16 = 0x4;
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory32 { address: u64, size: u64, prefetchable: bool },
+    /// Takes this slot and the next one, which holds the high half of the address.
+    Memory64 { address: u64, size: u64, prefetchable: bool },
+    Io { port: u16, size: u16 },
+}
+
+impl Bar {
+    /// Returns the bus address, 0 if the firmware left the BAR unassigned.
+    pub fn address(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { address, .. } | Bar::Memory64 { address, .. } => address,
+            Bar::Io { port, .. } => port as u64,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory32 { size, .. } | Bar::Memory64 { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PciError {
+    /// The device has no BAR at this index, or it is the high half of a 64-bit BAR.
+    NoSuchBar,
+    /// The firmware did not assign the BAR an address.
+    Unassigned,
+    /// The device lacks the capability, e.g. MSI.
+    NoCapability,
+    /// More MSI-X vectors requested than the table has entries.
+    TooManyVectors,
+    Map(MapToError<Size4KiB>),
+}
+
+impl fmt::Display for PciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PciError::NoSuchBar => write!(f, "no such BAR"),
+            PciError::Unassigned => write!(f, "BAR not assigned by the firmware"),
+            PciError::NoCapability => write!(f, "capability not supported by the device"),
+            PciError::TooManyVectors => write!(f, "more vectors than MSI-X table entries"),
+            PciError::Map(err) => write!(f, "mapping the BAR failed: {:?}", err),
+        }
+    }
+}
+
+/// Device registers mapped uncached, see [`PciDevice::map_bar`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioRegion {
+    base: u64,
+    size: u64,
+}
+
+impl MmioRegion {
+    /// ## Safety
+    ///
+    /// `size` bytes at `base` must be mapped device memory.
+    pub const unsafe fn new(base: u64, size: u64) -> Self {
+        MmioRegion { base, size }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn register<T>(&self, offset: u64) -> *mut T {
+        let width = core::mem::size_of::<T>() as u64;
+        assert!(offset + width <= self.size, "register {:#x} out of the {:#x} byte region", offset, self.size);
+        assert!(offset % width == 0, "unaligned register {:#x}", offset);
+        (self.base + offset) as *mut T
+    }
+
+    /// Reads the register at byte `offset`, which must be inside the region and aligned.
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { self.register::<T>(offset).read_volatile() }
+    }
+
+    /// Writes the register at byte `offset`, which must be inside the region and aligned.
+    ///
+    /// ## Safety
+    ///
+    /// Writing device registers can make the device do anything, including DMA.
+    pub unsafe fn write<T: Copy>(&self, offset: u64, value: T) {
+        unsafe { self.register::<T>(offset).write_volatile(value) }
+    }
+}
+
+/// The I/O ports of an I/O BAR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoRegion {
+    base: u16,
+    size: u16,
+}
+
+impl IoRegion {
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn port<T>(&self, offset: u16) -> Port {
+        let width = core::mem::size_of::<T>() as u16;
+        assert!(offset + width <= self.size, "port {:#x} out of the {:#x} port region", offset, self.size);
+        Port::new(self.base + offset)
+    }
+
+    /// ## Safety
+    ///
+    /// Reading some device registers has side effects.
+    pub unsafe fn read<T: PortRead + Default>(&self, offset: u16) -> T {
+        unsafe { self.port::<T>(offset).read(T::default()) }
+    }
+
+    /// ## Safety
+    ///
+    /// Writing device registers can make the device do anything, including DMA.
+    pub unsafe fn write<T: PortWrite>(&self, offset: u16, value: T) {
+        unsafe { self.port::<T>(offset).write(value) }
+    }
+}
+
+/// A mapped BAR, memory or I/O depending on its kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarRegion {
+    Mmio(MmioRegion),
+    Io(IoRegion),
+}
+
+impl PciDevice {
+    /// Decodes BAR `index` and probes its size.
+    ///
+    /// Returns `None` if there is no such BAR, if it is the high half of a 64-bit
+    /// BAR or if the device does not implement it (size 0). A BAR the firmware
+    /// left unassigned is returned with address 0.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index >= self.bar_count() || self.is_bar_high_half(index) {
+            return None;
+        }
+        let offset = CONFIG_BAR0 + index * 4;
+        let address = self.address;
+
+        // Writing all ones reads back the size mask. Decoding is off meanwhile
+        // so the device does not answer at the bogus address.
+        without_interrupts(|| unsafe {
+            let command = address.read_u32(CONFIG_COMMAND);
+            address.write_u32(CONFIG_COMMAND, command & !((COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE) as u32));
+
+            let low = address.read_u32(offset);
+            address.write_u32(offset, u32::MAX);
+            let low_mask = address.read_u32(offset);
+            address.write_u32(offset, low);
+
+            let bar = if low & BAR_IO != 0 {
+                let mask = low_mask & BAR_IO_ADDRESS & 0xFFFF;
+                let size = (!mask).wrapping_add(1) & 0xFFFF;
+                Some(Bar::Io { port: (low & BAR_IO_ADDRESS) as u16, size: size as u16 })
+                    .filter(|_| mask != 0)
+            } else if low & BAR_MEMORY_TYPE == BAR_MEMORY_64 {
+                let high = address.read_u32(offset + 4);
+                address.write_u32(offset + 4, u32::MAX);
+                let high_mask = address.read_u32(offset + 4);
+                address.write_u32(offset + 4, high);
+
+                let mask = (high_mask as u64) << 32 | (low_mask & BAR_MEMORY_ADDRESS) as u64;
+                Some(Bar::Memory64 {
+                    address: (high as u64) << 32 | (low & BAR_MEMORY_ADDRESS) as u64,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable: low & BAR_PREFETCHABLE != 0,
+                }).filter(|_| mask != 0)
+            } else {
+                let mask = low_mask & BAR_MEMORY_ADDRESS;
+                Some(Bar::Memory32 {
+                    address: (low & BAR_MEMORY_ADDRESS) as u64,
+                    size: (!mask).wrapping_add(1) as u64,
+                    prefetchable: low & BAR_PREFETCHABLE != 0,
+                }).filter(|_| mask != 0)
+            };
+
+            address.write_u32(CONFIG_COMMAND, command);
+            bar
+        })
+    }
+
+    /// Whether slot `index` holds the high half of the 64-bit BAR before it.
+    fn is_bar_high_half(&self, index: u8) -> bool {
+        let mut slot = 0;
+        while slot < index {
+            let value = self.address.read_u32(CONFIG_BAR0 + slot * 4);
+            let is_64 = value & BAR_IO == 0 && value & BAR_MEMORY_TYPE == BAR_MEMORY_64;
+            if is_64 && slot + 1 == index {
+                return true;
+            }
+            slot += if is_64 { 2 } else { 1 };
+        }
+        false
+    }
+
+    /// Maps BAR `index` for the driver: memory BARs uncached through
+    /// [`map_physical_region`], I/O BARs as a port range.
+    ///
+    /// ## Safety
+    ///
+    /// The BAR must not overlap memory in use, which holds for what the firmware
+    /// assigned.
+    pub unsafe fn map_bar(
+        &self,
+        index: u8,
+        mapper: &mut OffsetPageTable,
+        frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<BarRegion, PciError> {
+        let bar = self.bar(index).ok_or(PciError::NoSuchBar)?;
+        if bar.address() == 0 {
+            return Err(PciError::Unassigned);
+        }
+        match bar {
+            Bar::Io { port, size } => Ok(BarRegion::Io(IoRegion { base: port, size })),
+            Bar::Memory32 { address, size, .. } | Bar::Memory64 { address, size, .. } => {
+                // PAT index 3: uncacheable.
+                let flags = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::WRITE_THROUGH;
+                let base = unsafe { map_physical_region(address, size, flags, mapper, frame_alloc) }
+                    .map_err(PciError::Map)?;
+                Ok(BarRegion::Mmio(MmioRegion { base, size }))
+            },
+        }
+    }
+
+    /// Turns on memory decoding and lets the device master the bus, which DMA needs.
+    ///
+    /// ## Safety
+    ///
+    /// The device must only be given DMA addresses the driver owns.
+    pub unsafe fn enable_bus_master(&self) {
+        // The status half is write-one-to-clear, leave it alone.
+        let command = self.address.read_u32(CONFIG_COMMAND) & 0xFFFF;
+        unsafe { self.address.write_u32(CONFIG_COMMAND, command | (COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER) as u32) }
+    }
+}
+
+#[test_case]
+fn qemu_vga_and_nic_bars() {
+    use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+    use super::devices;
+
+    // QEMU's standard VGA: 16MiB prefetchable framebuffer, 4KiB of registers in BAR 2.
+    if let Some(vga) = devices().find(|d| (d.vendor_id, d.device_id) == (0x1234, 0x1111)) {
+        let Some(Bar::Memory32 { size, prefetchable, .. }) = vga.bar(0) else {
+            panic!("VGA BAR 0 is {:?}", vga.bar(0));
+        };
+        assert_eq!(size, 16 << 20);
+        assert!(prefetchable);
+        assert_eq!(vga.bar(6), None);
+
+        let mut mapper = MAPPER.lock();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let registers = unsafe { vga.map_bar(2, mapper.as_mut().unwrap(), allocator.as_mut().unwrap()) };
+        let Ok(BarRegion::Mmio(registers)) = registers else {
+            panic!("mapping VGA BAR 2 failed");
+        };
+        assert_eq!(registers.size(), 0x1000);
+        // Bochs display interface ID register, 0xB0C0 to 0xB0C5.
+        assert_eq!(registers.read::<u16>(0x500) & 0xFFF0, 0xB0C0);
+    }
+
+    // QEMU's e1000: 128KiB of registers in BAR 0 and 64 I/O ports in BAR 1.
+    if let Some(nic) = devices().find(|d| (d.vendor_id, d.device_id) == (0x8086, 0x100e)) {
+        assert!(matches!(nic.bar(0), Some(Bar::Memory32 { size: 0x20000, .. })));
+        let Some(Bar::Io { port, size }) = nic.bar(1) else {
+            panic!("NIC BAR 1 is {:?}", nic.bar(1));
+        };
+        assert_eq!(size, 0x40);
+        assert_ne!(port, 0);
+    }
+}
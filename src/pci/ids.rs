@@ -0,0 +1,209 @@
+//! Names for PCI vendors, devices and class codes.
+//!
+//! The tables are hand-picked for what QEMU, VirtualBox and common hardware
+//! show, sorted so that lookups are binary searches. Anything else is printed
+//! as hex by the callers.
+
+use core::fmt;
+
+/// Vendor ID and name, sorted by ID.
+static VENDORS: &[(u16, &str)] = &[
+    (0x1002, "AMD/ATI"),
+    (0x1022, "AMD"),
+    (0x10de, "NVIDIA"),
+    (0x10ec, "Realtek"),
+    (0x1234, "QEMU"),
+    (0x14e4, "Broadcom"),
+    (0x15ad, "VMware"),
+    (0x1af4, "Red Hat (virtio)"),
+    (0x1b36, "Red Hat (QEMU)"),
+    (0x80ee, "VirtualBox"),
+    (0x8086, "Intel"),
+];
+
+/// Vendor and device ID and device name, sorted by vendor then device.
+static DEVICES: &[((u16, u16), &str)] = &[
+    ((0x1022, 0x2000), "79c970 [PCnet32 LANCE]"),
+    ((0x10ec, 0x8139), "RTL-8100/8101L/8139 Fast Ethernet"),
+    ((0x10ec, 0x8168), "RTL8111/8168/8411 Gigabit Ethernet"),
+    ((0x1234, 0x1111), "Standard VGA"),
+    ((0x14e4, 0x1677), "NetXtreme BCM5751 Gigabit Ethernet"),
+    ((0x15ad, 0x0405), "SVGA II Adapter"),
+    ((0x1af4, 0x1000), "Virtio network device"),
+    ((0x1af4, 0x1001), "Virtio block device"),
+    ((0x1af4, 0x1002), "Virtio memory balloon"),
+    ((0x1af4, 0x1003), "Virtio console"),
+    ((0x1af4, 0x1005), "Virtio RNG"),
+    ((0x1af4, 0x1041), "Virtio 1.0 network device"),
+    ((0x1af4, 0x1042), "Virtio 1.0 block device"),
+    ((0x1b36, 0x000d), "XHCI Host Controller"),
+    ((0x80ee, 0xbeef), "Graphics Adapter"),
+    ((0x80ee, 0xcafe), "Guest Service"),
+    ((0x8086, 0x100e), "82540EM Gigabit Ethernet"),
+    ((0x8086, 0x10d3), "82574L Gigabit Ethernet"),
+    ((0x8086, 0x1237), "440FX - 82441FX PMC [Natoma]"),
+    ((0x8086, 0x2415), "82801AA AC'97 Audio"),
+    ((0x8086, 0x24cd), "82801DB USB2 EHCI Controller"),
+    ((0x8086, 0x2918), "82801IB (ICH9) LPC Interface"),
+    ((0x8086, 0x2922), "82801IR (ICH9R) 6 port SATA [AHCI mode]"),
+    ((0x8086, 0x2930), "82801I (ICH9) SMBus Controller"),
+    ((0x8086, 0x29c0), "82G33/G31/P35/P31 DRAM Controller"),
+    ((0x8086, 0x7000), "82371SB PIIX3 ISA [Natoma/Triton II]"),
+    ((0x8086, 0x7010), "82371SB PIIX3 IDE [Natoma/Triton II]"),
+    ((0x8086, 0x7020), "82371SB PIIX3 USB [Natoma/Triton II]"),
+    ((0x8086, 0x7111), "82371AB/EB/MB PIIX4 IDE"),
+    ((0x8086, 0x7113), "82371AB/EB/MB PIIX4 ACPI"),
+];
+
+/// Class code names, by class only, sorted.
+static CLASSES: &[(u8, &str)] = &[
+    (0x00, "Unclassified device"),
+    (0x01, "Mass storage controller"),
+    (0x02, "Network controller"),
+    (0x03, "Display controller"),
+    (0x04, "Multimedia controller"),
+    (0x05, "Memory controller"),
+    (0x06, "Bridge"),
+    (0x07, "Communication controller"),
+    (0x08, "Generic system peripheral"),
+    (0x09, "Input device controller"),
+    (0x0c, "Serial bus controller"),
+    (0x0d, "Wireless controller"),
+];
+
+/// Class and subclass names, sorted.
+static SUBCLASSES: &[((u8, u8), &str)] = &[
+    ((0x00, 0x01), "VGA compatible unclassified device"),
+    ((0x01, 0x00), "SCSI storage controller"),
+    ((0x01, 0x01), "IDE interface"),
+    ((0x01, 0x05), "ATA controller"),
+    ((0x01, 0x06), "SATA controller"),
+    ((0x01, 0x08), "Non-Volatile memory controller"),
+    ((0x02, 0x00), "Ethernet controller"),
+    ((0x03, 0x00), "VGA compatible controller"),
+    ((0x04, 0x01), "Multimedia audio controller"),
+    ((0x04, 0x03), "Audio device"),
+    ((0x05, 0x00), "RAM memory"),
+    ((0x06, 0x00), "Host bridge"),
+    ((0x06, 0x01), "ISA bridge"),
+    ((0x06, 0x04), "PCI bridge"),
+    ((0x07, 0x00), "Serial controller"),
+    ((0x08, 0x80), "System peripheral"),
+    ((0x0c, 0x03), "USB controller"),
+    ((0x0c, 0x05), "SMBus"),
+];
+
+/// Programming interface names, sorted by class, subclass and interface.
+static PROG_IFS: &[((u8, u8, u8), &str)] = &[
+    ((0x01, 0x06, 0x01), "AHCI 1.0"),
+    ((0x01, 0x08, 0x02), "NVM Express"),
+    ((0x0c, 0x03, 0x00), "UHCI"),
+    ((0x0c, 0x03, 0x10), "OHCI"),
+    ((0x0c, 0x03, 0x20), "EHCI"),
+    ((0x0c, 0x03, 0x30), "XHCI"),
+];
+
+fn lookup<K: Ord + Copy>(table: &'static [(K, &'static str)], key: K) -> Option<&'static str> {
+    table.binary_search_by_key(&key, |&(k, _)| k).ok().map(|i| table[i].1)
+}
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    lookup(VENDORS, vendor_id)
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    lookup(DEVICES, (vendor_id, device_id))
+}
+
+/// A class code, printed like "SATA controller (AHCI 1.0)".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassDescription {
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+pub fn class_description(class: u8, subclass: u8, prog_if: u8) -> ClassDescription {
+    ClassDescription { class, subclass, prog_if }
+}
+
+impl fmt::Display for ClassDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match lookup(SUBCLASSES, (self.class, self.subclass)) {
+            Some(name) => f.write_str(name)?,
+            None => match lookup(CLASSES, self.class) {
+                Some(name) => write!(f, "{} [{:02x}{:02x}]", name, self.class, self.subclass)?,
+                None => write!(f, "Class [{:02x}{:02x}]", self.class, self.subclass)?,
+            },
+        }
+        if let Some(prog_if) = lookup(PROG_IFS, (self.class, self.subclass, self.prog_if)) {
+            write!(f, " ({})", prog_if)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats into a stack buffer, for comparing `Display` output in tests.
+#[cfg(test)]
+pub(crate) struct StackString {
+    buf: [u8; 256],
+    len: usize,
+}
+
+#[cfg(test)]
+impl StackString {
+    pub(crate) fn format(args: fmt::Arguments) -> Self {
+        let mut s = StackString { buf: [0; 256], len: 0 };
+        fmt::Write::write_fmt(&mut s, args).unwrap();
+        s
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl fmt::Write for StackString {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn id_tables_are_sorted() {
+    assert!(VENDORS.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(DEVICES.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(CLASSES.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(SUBCLASSES.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(PROG_IFS.windows(2).all(|w| w[0].0 < w[1].0));
+}
+
+#[test_case]
+fn class_register_decoding() {
+    // Dword 0x08 of the configuration header: class, subclass, prog-if, revision.
+    let describe = |register: u32| {
+        let [_revision, prog_if, subclass, class] = register.to_le_bytes();
+        StackString::format(format_args!("{}", class_description(class, subclass, prog_if)))
+    };
+    assert_eq!(describe(0x0106_0102).as_str(), "SATA controller (AHCI 1.0)");
+    assert_eq!(describe(0x0c03_3001).as_str(), "USB controller (XHCI)");
+    assert_eq!(describe(0x0200_0003).as_str(), "Ethernet controller");
+    assert_eq!(describe(0x0300_0002).as_str(), "VGA compatible controller");
+    assert_eq!(describe(0x0601_0000).as_str(), "ISA bridge");
+    assert_eq!(describe(0x0180_0000).as_str(), "Mass storage controller [0180]");
+    assert_eq!(describe(0x4201_0000).as_str(), "Class [4201]");
+}
+
+#[test_case]
+fn vendor_and_device_names() {
+    assert_eq!(vendor_name(0x8086), Some("Intel"));
+    assert_eq!(vendor_name(0x1af4), Some("Red Hat (virtio)"));
+    assert_eq!(vendor_name(0xdead), None);
+    assert_eq!(device_name(0x8086, 0x100e), Some("82540EM Gigabit Ethernet"));
+    assert_eq!(device_name(0x1af4, 0x1001), Some("Virtio block device"));
+    assert_eq!(device_name(0x8086, 0xdead), None);
+}
@@ -0,0 +1,213 @@
+//! PCI configuration space access through the legacy I/O ports and bus enumeration.
+
+pub mod bar;
+pub mod ids;
+pub mod msi;
+
+use core::fmt;
+use crate::{println, sync::Mutex, tables::{port::Port, without_interrupts}};
+use bar::Bar;
+use ids::{class_description, device_name, vendor_name};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+/// `CONFIG_ADDRESS` bit making the next `CONFIG_DATA` access a configuration cycle.
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// Vendor ID read from a slot without a device.
+const NO_VENDOR: u16 = 0xFFFF;
+/// Header type bit set on function 0 of a device with several functions.
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+pub const CONFIG_VENDOR_ID: u8 = 0x00;
+pub const CONFIG_COMMAND: u8 = 0x04;
+pub const CONFIG_CLASS: u8 = 0x08;
+pub const CONFIG_HEADER_TYPE: u8 = 0x0E;
+pub const CONFIG_BAR0: u8 = 0x10;
+pub const CONFIG_INTERRUPT_LINE: u8 = 0x3C;
+
+/// Serializes the address/data port pair.
+static CONFIG_PORTS: Mutex<(Port, Port)> =
+    Mutex::new("PCI_CONFIG", (Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+/// Bus, device and function of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress { bus, device: device & 0x1F, function: function & 0x7 }
+    }
+
+    fn config_address(self, offset: u8) -> u32 {
+        CONFIG_ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// Reads the configuration dword containing `offset`.
+    pub fn read_u32(self, offset: u8) -> u32 {
+        without_interrupts(|| {
+            let ports = CONFIG_PORTS.lock();
+            unsafe {
+                ports.0.write(self.config_address(offset));
+                ports.1.read(0u32)
+            }
+        })
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Writes the configuration dword containing `offset`.
+    ///
+    /// ## Safety
+    ///
+    /// Configuration writes reprogram the device, e.g. move its BARs over memory
+    /// in use.
+    pub unsafe fn write_u32(self, offset: u8, value: u32) {
+        without_interrupts(|| {
+            let ports = CONFIG_PORTS.lock();
+            unsafe {
+                ports.0.write(self.config_address(offset));
+                ports.1.write(value);
+            }
+        })
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// The identification registers of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+}
+
+impl PciDevice {
+    /// Reads the function at `address`, `None` if the slot is empty.
+    pub fn probe(address: PciAddress) -> Option<Self> {
+        let id = address.read_u32(CONFIG_VENDOR_ID);
+        if id as u16 == NO_VENDOR {
+            return None;
+        }
+        let class = address.read_u32(CONFIG_CLASS);
+        Some(PciDevice {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type: address.read_u8(CONFIG_HEADER_TYPE),
+        })
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & HEADER_MULTIFUNCTION != 0
+    }
+
+    /// Number of base address registers: 6 for a device, 2 for a PCI-to-PCI bridge.
+    pub fn bar_count(&self) -> u8 {
+        match self.header_type & !HEADER_MULTIFUNCTION {
+            0x00 => 6,
+            0x01 => 2,
+            _ => 0,
+        }
+    }
+
+    /// Prints the device like `lspci`. `verbose` adds the BARs, interrupt and
+    /// the command and status registers.
+    pub fn print(&self, verbose: bool) {
+        println!("{}", self);
+        if !verbose {
+            return;
+        }
+        let command_status = self.address.read_u32(CONFIG_COMMAND);
+        println!("    Command: {:#06x}  Status: {:#06x}", command_status as u16, (command_status >> 16) as u16);
+        let interrupt = self.address.read_u32(CONFIG_INTERRUPT_LINE);
+        println!("    Interrupt: line {} pin {}", interrupt as u8, (interrupt >> 8) as u8);
+        for capability in self.capabilities() {
+            println!("    Capabilities: [{:02x}] {}", capability.offset, capability.name());
+        }
+        for index in 0..self.bar_count() {
+            match self.bar(index) {
+                Some(Bar::Io { port, size }) => println!("    BAR{}: I/O ports at {:#x} [size={:#x}]", index, port, size),
+                Some(Bar::Memory32 { address, size, prefetchable }) | Some(Bar::Memory64 { address, size, prefetchable }) => {
+                    println!("    BAR{}: Memory at {:#x} [size={:#x}]{}", index, address, size,
+                        if prefetchable { " prefetchable" } else { "" });
+                },
+                None => {},
+            }
+        }
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: ", self.address, class_description(self.class, self.subclass, self.prog_if))?;
+        match vendor_name(self.vendor_id) {
+            Some(vendor) => write!(f, "{} ", vendor)?,
+            None => write!(f, "Vendor {:04x} ", self.vendor_id)?,
+        }
+        match device_name(self.vendor_id, self.device_id) {
+            Some(device) => write!(f, "{}", device)?,
+            None => write!(f, "Device {:04x}", self.device_id)?,
+        }
+        write!(f, " [{:04x}:{:04x}]", self.vendor_id, self.device_id)
+    }
+}
+
+/// Returns every function on every bus, by brute force over all slots.
+pub fn devices() -> impl Iterator<Item = PciDevice> {
+    (0..=255u8).flat_map(|bus| {
+        (0..32u8).flat_map(move |device| {
+            let first = PciDevice::probe(PciAddress::new(bus, device, 0));
+            let functions = match first {
+                Some(first) if first.is_multifunction() => 8,
+                Some(_) => 1,
+                None => 0,
+            };
+            (0..functions).filter_map(move |function| PciDevice::probe(PciAddress::new(bus, device, function)))
+        })
+    })
+}
+
+/// Prints every PCI function, see [`PciDevice::print`].
+pub fn print_devices(verbose: bool) {
+    for device in devices() {
+        device.print(verbose);
+    }
+}
+
+#[test_case]
+fn host_bridge_is_first_device() {
+    // QEMU's i440FX and Q35 machines both have the host bridge at 00:00.0.
+    let device = devices().next().expect("no PCI device found");
+    assert_eq!(device.address, PciAddress::new(0, 0, 0));
+    assert_eq!((device.class, device.subclass), (0x06, 0x00));
+    assert_eq!(device.vendor_id, 0x8086);
+}
@@ -0,0 +1,328 @@
+//! Capability list walking and message-signaled interrupts (MSI and MSI-X).
+//!
+//! A message-signaled interrupt is a memory write the device makes to the
+//! local APIC address range; the x86 encoding puts the destination APIC ID in
+//! the address and the vector in the data. The vector still needs an entry in
+//! the IDT, and its handler acknowledges it at the local APIC, not the PIC.
+
+use crate::memory::{frame_allocator::FrameAllocator, mapper::OffsetPageTable, paging::Size4KiB};
+use super::{
+    bar::{BarRegion, MmioRegion, PciError},
+    PciAddress, PciDevice, CONFIG_COMMAND, CONFIG_INTERRUPT_LINE,
+};
+
+/// Configuration offset of the first capability, valid if the status register
+/// has [`STATUS_CAPABILITIES`] set.
+const CONFIG_CAPABILITIES: u8 = 0x34;
+/// Status register bit 4, seen in the high half of the command dword.
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+/// Command register bit 10: the device must not assert its INTx line.
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+
+/// Capabilities live after the 64 byte standard header.
+const FIRST_CAPABILITY_OFFSET: u8 = 0x40;
+/// At most 48 capabilities fit in the rest of the configuration space, more
+/// means the list loops.
+const MAX_CAPABILITIES: u8 = 48;
+
+pub const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+pub const CAPABILITY_SATA: u8 = 0x12;
+
+/// MSI message control bits.
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0x7 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+/// MSI-X message control bits.
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+/// Low bits of the table offset register, selecting the BAR holding the table.
+const MSIX_BIR: u32 = 0x7;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Local APIC range that message writes are sent to.
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+/// An entry of the capability list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Configuration offset of the capability header.
+    pub offset: u8,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self.id {
+            CAPABILITY_POWER_MANAGEMENT => "Power Management",
+            CAPABILITY_MSI => "MSI",
+            CAPABILITY_VENDOR_SPECIFIC => "Vendor Specific",
+            CAPABILITY_PCI_EXPRESS => "PCI Express",
+            CAPABILITY_MSIX => "MSI-X",
+            CAPABILITY_SATA => "SATA",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// Iterator over the capability list, reading configuration dwords through `R`.
+pub struct Capabilities<R> {
+    read: R,
+    next: u8,
+    remaining: u8,
+}
+
+impl<R: Fn(u8) -> u32> Capabilities<R> {
+    /// Starts at the capabilities pointer of the configuration space read by `read`.
+    pub fn new(read: R) -> Self {
+        let next = if read(CONFIG_COMMAND) & STATUS_CAPABILITIES != 0 {
+            read(CONFIG_CAPABILITIES) as u8
+        } else {
+            0
+        };
+        Capabilities { read, next, remaining: MAX_CAPABILITIES }
+    }
+}
+
+impl<R: Fn(u8) -> u32> Iterator for Capabilities<R> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        if self.next < FIRST_CAPABILITY_OFFSET || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = self.next & 0xFC;
+        let header = (self.read)(offset);
+        self.next = (header >> 8) as u8;
+        Some(Capability { id: header as u8, offset })
+    }
+}
+
+/// The layout of an MSI capability, which depends on its message control register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MsiLayout {
+    offset: u8,
+    control: u16,
+}
+
+impl MsiLayout {
+    fn is_64_bit(&self) -> bool {
+        self.control & MSI_64_BIT != 0
+    }
+
+    fn has_masking(&self) -> bool {
+        self.control & MSI_PER_VECTOR_MASKING != 0
+    }
+
+    fn data_offset(&self) -> u8 {
+        self.offset + if self.is_64_bit() { 0x0C } else { 0x08 }
+    }
+
+    fn mask_offset(&self) -> u8 {
+        self.data_offset() + 4
+    }
+}
+
+/// Returns the message address and data delivering `vector` to the local APIC
+/// `apic_id`, fixed delivery and edge triggered.
+pub fn msi_message(vector: u8, apic_id: u8) -> (u64, u32) {
+    ((MSI_ADDRESS_BASE | (apic_id as u32) << 12) as u64, vector as u32)
+}
+
+/// How a device can interrupt, see [`PciDevice::interrupt_support`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptSupport {
+    MsiX,
+    Msi,
+    /// Only the INTx pin (1 for INTA#), routed to the interrupt controller
+    /// input the firmware wrote to `line`.
+    Legacy { line: u8, pin: u8 },
+    None,
+}
+
+impl PciAddress {
+    /// Rewrites the 16 bit register in the high half of the dword at `offset`,
+    /// leaving the low half, capability ID and next pointer, as read.
+    unsafe fn write_high_u16(self, offset: u8, value: u16) {
+        let low = self.read_u32(offset) & 0xFFFF;
+        unsafe { self.write_u32(offset, (value as u32) << 16 | low) }
+    }
+
+    /// Stops the device from asserting its INTx line, once it uses messages.
+    unsafe fn disable_intx(self) {
+        // The status half is write-one-to-clear, leave it alone.
+        let command = self.read_u32(CONFIG_COMMAND) & 0xFFFF;
+        unsafe { self.write_u32(CONFIG_COMMAND, command | COMMAND_INTX_DISABLE) }
+    }
+}
+
+impl PciDevice {
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> {
+        let address = self.address;
+        Capabilities::new(move |offset| address.read_u32(offset))
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+
+    /// Tells drivers which interrupt mechanism to set up, preferring MSI-X.
+    /// Without MSI they fall back to the INTx line.
+    pub fn interrupt_support(&self) -> InterruptSupport {
+        if self.find_capability(CAPABILITY_MSIX).is_some() {
+            return InterruptSupport::MsiX;
+        }
+        if self.find_capability(CAPABILITY_MSI).is_some() {
+            return InterruptSupport::Msi;
+        }
+        let interrupt = self.address.read_u32(CONFIG_INTERRUPT_LINE);
+        match (interrupt >> 8) as u8 {
+            0 => InterruptSupport::None,
+            pin => InterruptSupport::Legacy { line: interrupt as u8, pin },
+        }
+    }
+
+    /// Enables a single MSI message delivering `vector` to the local APIC `apic_id`.
+    ///
+    /// ## Safety
+    ///
+    /// `vector` must have an IDT entry whose handler acknowledges the local APIC.
+    pub unsafe fn enable_msi(&self, vector: u8, apic_id: u8) -> Result<(), PciError> {
+        let capability = self.find_capability(CAPABILITY_MSI).ok_or(PciError::NoCapability)?;
+        let address = self.address;
+        let layout = MsiLayout {
+            offset: capability.offset,
+            control: (address.read_u32(capability.offset) >> 16) as u16,
+        };
+        let (message_address, message_data) = msi_message(vector, apic_id);
+        unsafe {
+            address.write_u32(layout.offset + 4, message_address as u32);
+            if layout.is_64_bit() {
+                address.write_u32(layout.offset + 8, (message_address >> 32) as u32);
+            }
+            address.write_u32(layout.data_offset(), message_data);
+            if layout.has_masking() {
+                let mask = address.read_u32(layout.mask_offset());
+                address.write_u32(layout.mask_offset(), mask & !1);
+            }
+            // One message only: multiple message enable 0.
+            let control = (layout.control & !MSI_MULTIPLE_MESSAGE_ENABLE) | MSI_ENABLE;
+            address.write_high_u16(layout.offset, control);
+            address.disable_intx();
+        }
+        Ok(())
+    }
+
+    /// Enables MSI-X with table entry `i` delivering `vectors[i]` to the local
+    /// APIC `apic_id`, and returns the mapped BAR holding the table.
+    ///
+    /// ## Safety
+    ///
+    /// Every vector must have an IDT entry whose handler acknowledges the local
+    /// APIC, and the table BAR must be safe to map, see [`PciDevice::map_bar`].
+    pub unsafe fn enable_msix(
+        &self,
+        vectors: &[u8],
+        apic_id: u8,
+        mapper: &mut OffsetPageTable,
+        frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<MmioRegion, PciError> {
+        let capability = self.find_capability(CAPABILITY_MSIX).ok_or(PciError::NoCapability)?;
+        let address = self.address;
+        let control = (address.read_u32(capability.offset) >> 16) as u16;
+        if vectors.len() > (control & MSIX_TABLE_SIZE) as usize + 1 {
+            return Err(PciError::TooManyVectors);
+        }
+        let table = address.read_u32(capability.offset + 4);
+        let BarRegion::Mmio(region) = (unsafe { self.map_bar((table & MSIX_BIR) as u8, mapper, frame_alloc)? }) else {
+            return Err(PciError::NoSuchBar);
+        };
+        let table_offset = (table & !MSIX_BIR) as u64;
+
+        unsafe {
+            // Masked as a whole while the entries are half written.
+            address.write_high_u16(capability.offset, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+            for (i, &vector) in vectors.iter().enumerate() {
+                let entry = table_offset + i as u64 * MSIX_ENTRY_SIZE;
+                let (message_address, message_data) = msi_message(vector, apic_id);
+                region.write::<u32>(entry, message_address as u32);
+                region.write::<u32>(entry + 4, (message_address >> 32) as u32);
+                region.write::<u32>(entry + 8, message_data);
+                let vector_control = region.read::<u32>(entry + 12);
+                region.write::<u32>(entry + 12, vector_control & !MSIX_ENTRY_MASKED);
+            }
+            address.write_high_u16(capability.offset, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+            address.disable_intx();
+        }
+        Ok(region)
+    }
+}
+
+/// Configuration space of QEMU's e1000e as `lspci -xxx` shows it, only the
+/// status register and the capability list filled in.
+#[cfg(test)]
+fn e1000e_config_space() -> [u8; 256] {
+    let mut config = [0u8; 256];
+    config[0x06] = 0x10; // Status: capabilities list.
+    config[0x34] = 0xC8;
+    config[0xA0..0xAC].copy_from_slice(&[0x11, 0x00, 0x04, 0x80, 0x03, 0x00, 0x00, 0x00, 0x03, 0x20, 0x00, 0x00]);
+    config[0xC8..0xCA].copy_from_slice(&[0x01, 0xD0]);
+    config[0xD0..0xD4].copy_from_slice(&[0x05, 0xE0, 0x80, 0x00]);
+    config[0xE0..0xE2].copy_from_slice(&[0x10, 0xA0]);
+    config
+}
+
+#[cfg(test)]
+fn read_dword(config: &[u8; 256], offset: u8) -> u32 {
+    let offset = (offset & 0xFC) as usize;
+    u32::from_le_bytes(config[offset..offset + 4].try_into().unwrap())
+}
+
+#[test_case]
+fn capability_list_walk() {
+    let config = e1000e_config_space();
+    let mut capabilities = Capabilities::new(|offset| read_dword(&config, offset));
+    assert_eq!(capabilities.next(), Some(Capability { id: CAPABILITY_POWER_MANAGEMENT, offset: 0xC8 }));
+    assert_eq!(capabilities.next(), Some(Capability { id: CAPABILITY_MSI, offset: 0xD0 }));
+    assert_eq!(capabilities.next(), Some(Capability { id: CAPABILITY_PCI_EXPRESS, offset: 0xE0 }));
+    assert_eq!(capabilities.next(), Some(Capability { id: CAPABILITY_MSIX, offset: 0xA0 }));
+    assert_eq!(capabilities.next(), None);
+
+    // MSI-X: 5 entries, table and pending bits both in BAR 3.
+    let control = (read_dword(&config, 0xA0) >> 16) as u16;
+    assert_eq!((control & MSIX_TABLE_SIZE) + 1, 5);
+    assert_eq!(read_dword(&config, 0xA4) & MSIX_BIR, 3);
+    assert_eq!(read_dword(&config, 0xA8) & !MSIX_BIR, 0x2000);
+}
+
+#[test_case]
+fn capability_list_absent_or_looping() {
+    let mut config = e1000e_config_space();
+    config[0xA1] = 0xC8;
+    assert_eq!(Capabilities::new(|offset| read_dword(&config, offset)).count(), MAX_CAPABILITIES as usize);
+
+    config[0x06] = 0;
+    assert_eq!(Capabilities::new(|offset| read_dword(&config, offset)).next(), None);
+}
+
+#[test_case]
+fn msi_layout_and_message() {
+    let config = e1000e_config_space();
+    let layout = MsiLayout { offset: 0xD0, control: (read_dword(&config, 0xD0) >> 16) as u16 };
+    assert!(layout.is_64_bit() && !layout.has_masking());
+    assert_eq!(layout.data_offset(), 0xDC);
+
+    let layout = MsiLayout { offset: 0x50, control: MSI_PER_VECTOR_MASKING };
+    assert_eq!((layout.data_offset(), layout.mask_offset()), (0x58, 0x5C));
+
+    assert_eq!(msi_message(0x41, 0), (0xFEE0_0000, 0x41));
+    assert_eq!(msi_message(0x30, 3), (0xFEE0_3000, 0x30));
+}
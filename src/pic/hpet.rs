@@ -0,0 +1,359 @@
+//! The HPET as the source of the timer ticks, and of wakeups more precise
+//! than a tick.
+//!
+//! Comparator 0 runs periodically for the ticks, and comparator 1 is armed
+//! one-shot by [`sleep_ns`]. Both use the legacy replacement route, which takes
+//! IRQ 0 over from the PIT and IRQ 8 from the RTC: IRQs reach the CPU through
+//! the 8259 PICs here, and there is no I/O APIC driver to route the HPET's own
+//! GSIs with. An HPET without the route is left alone.
+//!
+//! The main counter is also a clock of its own, see [`Block::counter`].
+
+use core::{arch::asm, fmt, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+use spin::Once;
+use crate::{
+    acpi,
+    memory::{paging::{map_physical_region, PageTableFlags}, FRAME_ALLOCATOR, MAPPER},
+    pci::bar::MmioRegion,
+    pic::{mask_irq, unmask_irq, PICS},
+    sync::Mutex,
+    tables::{enter_interrupt, without_interrupts, InterruptStackFrame, RFlags},
+};
+use super::timer::{set_tick_source, tick_source, TickSource};
+
+const REGISTERS_SIZE: u64 = 0x400;
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_INTERRUPT_STATUS: u64 = 0x020;
+const REG_MAIN_COUNTER: u64 = 0x0F0;
+
+const fn reg_timer_config(comparator: u64) -> u64 {
+    0x100 + 0x20 * comparator
+}
+
+const fn reg_timer_comparator(comparator: u64) -> u64 {
+    0x108 + 0x20 * comparator
+}
+
+/// General configuration: the main counter runs, and comparators 0 and 1 take
+/// IRQ 0 and IRQ 8.
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+/// The revision ID in the low byte of the capabilities may differ from the
+/// ACPI table's, the rest of the low half must not.
+const CAPABILITIES_REVISION: u32 = 0xFF;
+
+/// Timer configuration and capabilities.
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// Set with a periodic comparator write to load the accumulator behind it.
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE: u64 = 0x1F << 9;
+const TIMER_FSB: u64 = 1 << 14;
+
+/// The longest counter period the specification allows, 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+
+const TICK_COMPARATOR: u64 = 0;
+const WAKEUP_COMPARATOR: u64 = 1;
+/// Where the legacy replacement route sends the wakeup comparator.
+pub const WAKEUP_IRQ: u8 = 8;
+
+static HPET: Once<Block> = Once::new();
+/// Set while the comparators are routed and running, see [`init`].
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Wakeup interrupts since boot.
+static WAKEUPS: AtomicU64 = AtomicU64::new(0);
+/// Held by the one [`sleep_ns`] the wakeup comparator serves at a time.
+static WAKEUP: Mutex<()> = Mutex::new("HPET_WAKEUP", ());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    NoTable,
+    /// The registers could not be mapped.
+    Unmapped,
+    /// The capabilities register does not match the ACPI table.
+    Mismatch,
+    /// The counter period is 0 or longer than 100 ns.
+    BadPeriod,
+    NoLegacyRoute,
+    /// Comparator 0 has no periodic mode.
+    NotPeriodic,
+}
+
+impl fmt::Display for HpetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HpetError::NoTable => write!(f, "no HPET table"),
+            HpetError::Unmapped => write!(f, "registers cannot be mapped"),
+            HpetError::Mismatch => write!(f, "capabilities do not match the ACPI table"),
+            HpetError::BadPeriod => write!(f, "bad counter period"),
+            HpetError::NoLegacyRoute => write!(f, "no legacy replacement route"),
+            HpetError::NotPeriodic => write!(f, "comparator 0 is not periodic"),
+        }
+    }
+}
+
+/// The mapped event timer block.
+pub struct Block {
+    registers: MmioRegion,
+    /// Femtoseconds per main counter tick.
+    period_fs: u64,
+    comparators: u8,
+    legacy_route: bool,
+    /// The smallest periodic period, in counter ticks, from the ACPI table.
+    min_tick: u64,
+}
+
+impl Block {
+    /// The main counter frequency in Hz.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    pub fn comparators(&self) -> u8 {
+        self.comparators
+    }
+
+    /// Reads the main counter, which counts up from when [`probe`] started it.
+    /// A 32-bit counter wraps after a few minutes at most.
+    pub fn counter(&self) -> u64 {
+        self.registers.read(REG_MAIN_COUNTER)
+    }
+
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FS_PER_NS as u128) as u64
+    }
+
+    /// Rounds up, so that waiting the ticks takes at least `ns`.
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        (ns as u128 * FS_PER_NS as u128).div_ceil(self.period_fs as u128) as u64
+    }
+
+    fn timer_config(&self, comparator: u64) -> u64 {
+        self.registers.read(reg_timer_config(comparator))
+    }
+
+    fn set_timer_config(&self, comparator: u64, config: u64) {
+        unsafe { self.registers.write(reg_timer_config(comparator), config); }
+    }
+
+    fn set_comparator(&self, comparator: u64, value: u64) {
+        unsafe { self.registers.write(reg_timer_comparator(comparator), value); }
+    }
+
+    fn set_config(&self, set: u64, clear: u64) {
+        let config = self.registers.read::<u64>(REG_CONFIG);
+        unsafe { self.registers.write(REG_CONFIG, (config & !clear) | set); }
+    }
+}
+
+/// Maps the HPET named by the ACPI table, checks its capabilities against the
+/// table, and starts its main counter with every comparator off.
+pub fn probe() -> Result<&'static Block, HpetError> {
+    if let Some(block) = HPET.get() {
+        return Ok(block);
+    }
+    let table = acpi::hpet().ok_or(HpetError::NoTable)?;
+    let base = {
+        let mut mapper = MAPPER.lock();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+            return Err(HpetError::Unmapped);
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        unsafe { map_physical_region(table.address, REGISTERS_SIZE, flags, mapper, allocator) }
+            .map_err(|_| HpetError::Unmapped)?
+    };
+    let registers = unsafe { MmioRegion::new(base, REGISTERS_SIZE) };
+    let capabilities = registers.read::<u64>(REG_CAPABILITIES);
+    if capabilities as u32 & !CAPABILITIES_REVISION != table.block_id & !CAPABILITIES_REVISION {
+        return Err(HpetError::Mismatch);
+    }
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err(HpetError::BadPeriod);
+    }
+    let block = Block {
+        registers,
+        period_fs,
+        comparators: table.comparators(),
+        legacy_route: table.legacy_route(),
+        min_tick: table.min_tick as u64,
+    };
+    without_interrupts(|| {
+        for comparator in 0..block.comparators as u64 {
+            block.set_timer_config(comparator, block.timer_config(comparator) & !(TIMER_ENABLE | TIMER_FSB));
+        }
+        block.set_config(CONFIG_ENABLE, CONFIG_LEGACY_ROUTE);
+    });
+    Ok(HPET.call_once(|| block))
+}
+
+/// Makes comparator 0 raise the timer ticks at `hz` Hz on IRQ 0 in place of
+/// the PIT, and readies comparator 1 for [`sleep_ns`] on IRQ 8.
+pub fn init(hz: u64) -> Result<&'static Block, HpetError> {
+    let block = probe()?;
+    if !block.legacy_route || block.comparators < 2 {
+        return Err(HpetError::NoLegacyRoute);
+    }
+    let tick_config = block.timer_config(TICK_COMPARATOR);
+    if tick_config & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::NotPeriodic);
+    }
+    let period = block.ns_to_ticks(1_000_000_000 / hz.max(1)).max(block.min_tick).max(1);
+    // Edge triggered, through the legacy route rather than a GSI or an FSB message.
+    let routing = TIMER_LEVEL_TRIGGERED | TIMER_ROUTE | TIMER_FSB | TIMER_32BIT_MODE;
+    without_interrupts(|| {
+        // The periodic comparator is loaded with the counter halted, so the
+        // first deadline is not already past by the time it is armed.
+        block.set_config(0, CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+        block.set_timer_config(TICK_COMPARATOR,
+            (tick_config & !routing) | TIMER_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET);
+        // With the value set bit, the first write is the first deadline and the
+        // second the period added after each.
+        block.set_comparator(TICK_COMPARATOR, block.counter() + period);
+        block.set_comparator(TICK_COMPARATOR, period);
+        let wakeup_config = block.timer_config(WAKEUP_COMPARATOR);
+        block.set_timer_config(WAKEUP_COMPARATOR, wakeup_config & !(routing | TIMER_ENABLE | TIMER_PERIODIC));
+        unsafe { block.registers.write(REG_INTERRUPT_STATUS, u64::MAX); }
+        block.set_config(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE, 0);
+
+        RUNNING.store(true, Ordering::Relaxed);
+        set_tick_source(TickSource::Hpet, block.ticks_to_ns(period));
+        unmask_irq(WAKEUP_IRQ);
+    });
+    Ok(block)
+}
+
+/// Stops both comparators and gives IRQ 0 back to the PIT, which kept running.
+/// The main counter keeps counting.
+pub fn stop() {
+    let Some(block) = HPET.get() else {
+        return;
+    };
+    without_interrupts(|| {
+        mask_irq(WAKEUP_IRQ);
+        for comparator in [TICK_COMPARATOR, WAKEUP_COMPARATOR] {
+            block.set_timer_config(comparator, block.timer_config(comparator) & !(TIMER_ENABLE | TIMER_PERIODIC));
+        }
+        block.set_config(0, CONFIG_LEGACY_ROUTE);
+        RUNNING.store(false, Ordering::Relaxed);
+        if tick_source() == TickSource::Hpet {
+            set_tick_source(TickSource::Pit, 0);
+        }
+    });
+}
+
+/// Returns the number of wakeup interrupts since boot.
+pub fn wakeups() -> u64 {
+    WAKEUPS.load(Ordering::Relaxed)
+}
+
+/// Waits at least `ns` nanoseconds for comparator 1, halting in between.
+/// Returns `false` right away when it cannot: before [`init`], with interrupts
+/// disabled, or while another wait holds the comparator.
+///
+/// There is no sleep queue to share the comparator yet, the waits that find
+/// it taken fall back to counting ticks.
+pub fn sleep_ns(ns: u64) -> bool {
+    let Some(block) = HPET.get() else {
+        return false;
+    };
+    if !RUNNING.load(Ordering::Relaxed) || !RFlags::read().contains(RFlags::INTERRUPT_FLAG) {
+        return false;
+    }
+    let Some(_comparator) = WAKEUP.try_lock() else {
+        return false;
+    };
+    let deadline = block.counter() + block.ns_to_ticks(ns);
+    let config = block.timer_config(WAKEUP_COMPARATOR);
+    without_interrupts(|| {
+        block.set_comparator(WAKEUP_COMPARATOR, deadline);
+        block.set_timer_config(WAKEUP_COMPARATOR, config | TIMER_ENABLE);
+    });
+    // A deadline already past when it was written raises no interrupt, so the
+    // counter is checked before each halt, with interrupts off until the `hlt`.
+    loop {
+        unsafe { asm!("cli", options(preserves_flags, nostack)); }
+        if block.counter() >= deadline {
+            unsafe { asm!("sti", options(preserves_flags, nostack)); }
+            break;
+        }
+        unsafe { asm!("sti; hlt", options(nomem, nostack)); }
+    }
+    block.set_timer_config(WAKEUP_COMPARATOR, config & !TIMER_ENABLE);
+    true
+}
+
+/// IRQ 8, from comparator 1. Edge triggered, so there is no status to clear.
+pub extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    WAKEUPS.fetch_add(1, Ordering::Relaxed);
+    unsafe { PICS.lock().notify_end_of_interrupt(32 + WAKEUP_IRQ); }
+}
+
+#[test_case]
+fn main_counter_is_monotonic() {
+    use super::timer::delay_ms;
+
+    let block = probe().expect("no HPET");
+    assert!(block.frequency() >= 10_000_000, "{} Hz", block.frequency());
+    let mut last = block.counter();
+    for _ in 0..1000 {
+        let now = block.counter();
+        assert!(now >= last, "counter went from {} to {}", last, now);
+        last = now;
+    }
+    let start = block.counter();
+    delay_ms(20);
+    let elapsed = block.ticks_to_ns(block.counter() - start);
+    assert!(elapsed >= 19_000_000, "20 ms took {} ns", elapsed);
+    assert_eq!(block.ns_to_ticks(block.ticks_to_ns(1000)), 1000);
+}
+
+#[test_case]
+fn hpet_ticks_and_wakeups() {
+    use super::timer::{tick_rate, ticks, wait_for_tick};
+
+    /// How late a wakeup may be.
+    const WAKEUP_SLACK_NS: u64 = 1_000_000;
+
+    assert_eq!(tick_source(), TickSource::Pit);
+    let block = init(tick_rate()).expect("no HPET");
+    assert_eq!(tick_source(), TickSource::Hpet);
+
+    // Ten ticks take ten periods of the main counter.
+    wait_for_tick(ticks());
+    let (start, start_counter) = (ticks(), block.counter());
+    while ticks() - start < 10 {
+        core::hint::spin_loop();
+    }
+    let elapsed = block.ticks_to_ns(block.counter() - start_counter);
+    let expected = 10 * 1_000_000_000 / tick_rate();
+    assert!(elapsed.abs_diff(expected) <= expected / 20, "10 ticks took {} ns", elapsed);
+
+    for ns in [100_000, 1_000_000, 3_000_000] {
+        let before = wakeups();
+        let start = block.counter();
+        assert!(sleep_ns(ns));
+        let slept = block.ticks_to_ns(block.counter() - start);
+        assert!(slept >= ns && slept <= ns + WAKEUP_SLACK_NS, "slept {} ns for {}", slept, ns);
+        // The interrupt may come right after the counter was seen past the deadline.
+        let grace = block.counter() + block.ns_to_ticks(WAKEUP_SLACK_NS);
+        while wakeups() == before && block.counter() < grace {
+            core::hint::spin_loop();
+        }
+        assert!(wakeups() > before, "no wakeup interrupt for {} ns", ns);
+    }
+
+    stop();
+    assert_eq!(tick_source(), TickSource::Pit);
+    assert!(!sleep_ns(1000));
+    // The PIT ticks again.
+    wait_for_tick(ticks());
+}
@@ -1,31 +1,327 @@
-use crate::{pic::PICS, tables::{port::Port, InterruptStackFrame}, print};
+use crate::{pic::PICS, sync::Mutex, tables::{enter_interrupt, port::Port, InterruptStackFrame}, tty::{self, TTY}, vga::{mirror_to_console, VGA_WRITER}};
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use spin::Mutex;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1};
+#[cfg(any(feature = "exit_hotkey", test))]
+use crate::QemuExitCode;
 
 const SCANCODE_PORT: u16 = 0x60;
+/// Lines moved by Shift+PageUp and Shift+PageDown.
+const SCROLL_STEP: usize = 12;
+/// What Ctrl+Q decodes to, with control mapped to Unicode.
+#[cfg(any(feature = "exit_hotkey", test))]
+const CTRL_Q: char = '\u{11}';
 
-pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Azerty, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(ScancodeSet1::new(),
-                layouts::Azerty, HandleControl::Ignore)
-            );
+lazy_static! {
+    // Control is mapped so that Ctrl+D reaches the tty as U+0004.
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Azerty, ScancodeSet1>> =
+        Mutex::new("KEYBOARD", Keyboard::new(ScancodeSet1::new(),
+            layouts::Azerty, HandleControl::MapLettersToUnicode)
+        );
+}
+
+static COMPOSE: Mutex<Compose> = Mutex::new("COMPOSE", Compose::new());
+static KEYS_DOWN: Mutex<KeyTracker> = Mutex::new("KEYS_DOWN", KeyTracker::new());
+/// What Ctrl+Alt+Q calls, swapped out by the tests.
+#[cfg(any(feature = "exit_hotkey", test))]
+static EXIT_HOOK: Mutex<fn(QemuExitCode)> = Mutex::new("EXIT_HOOK", crate::exit_qemu);
+
+/// What a key event means once auto-repeat is told apart from real presses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyTransition {
+    Pressed,
+    /// The keyboard repeating a key that is held down.
+    Repeated,
+    Released,
+}
+
+/// Tracks which keys are held down from the press and release events.
+///
+/// Scancode set 1 sends the make code again while a key is held, so a second
+/// `Down` without an `Up` in between is auto-repeat. Shortcuts that must fire
+/// once per press act on `Pressed` only.
+pub struct KeyTracker {
+    /// One bit per `KeyCode`.
+    down: [u64; 4],
+}
+
+impl KeyTracker {
+    pub const fn new() -> Self {
+        KeyTracker { down: [0; 4] }
     }
 
-    let mut keyboard = KEYBOARD.lock();
+    fn bit(code: KeyCode) -> (usize, u64) {
+        let index = code as usize;
+        (index / 64 % 4, 1 << (index % 64))
+    }
+
+    pub fn is_down(&self, code: KeyCode) -> bool {
+        let (word, mask) = Self::bit(code);
+        self.down[word] & mask != 0
+    }
+
+    /// Records `event` and classifies it. Keys without a release code, like
+    /// Pause, are always `Pressed`.
+    pub fn update(&mut self, event: &KeyEvent) -> KeyTransition {
+        let (word, mask) = Self::bit(event.code);
+        let was_down = self.down[word] & mask != 0;
+        match event.state {
+            KeyState::Down => {
+                self.down[word] |= mask;
+                if was_down { KeyTransition::Repeated } else { KeyTransition::Pressed }
+            },
+            KeyState::Up => {
+                self.down[word] &= !mask;
+                KeyTransition::Released
+            },
+            KeyState::SingleShot => KeyTransition::Pressed,
+        }
+    }
+}
+
+impl Default for KeyTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns whether `code` is held down right now.
+pub fn is_key_down(code: KeyCode) -> bool {
+    crate::tables::without_interrupts(|| KEYS_DOWN.lock().is_down(code))
+}
+
+/// Accent keys that change the next key instead of typing something themselves.
+///
+/// The decoder maps them to their spacing characters, e.g. the AZERTY `^` key to
+/// `'^'` and AltGr+7 to `'`'`, so they are recognized by character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadKey {
+    Acute,
+    Grave,
+    Circumflex,
+    Diaeresis,
+    Tilde,
+}
+
+impl DeadKey {
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            '´' => Some(DeadKey::Acute),
+            '`' => Some(DeadKey::Grave),
+            '^' => Some(DeadKey::Circumflex),
+            '¨' => Some(DeadKey::Diaeresis),
+            '~' => Some(DeadKey::Tilde),
+            _ => None,
+        }
+    }
+
+    /// The character the key types on its own.
+    pub fn spacing_char(self) -> char {
+        match self {
+            DeadKey::Acute => '´',
+            DeadKey::Grave => '`',
+            DeadKey::Circumflex => '^',
+            DeadKey::Diaeresis => '¨',
+            DeadKey::Tilde => '~',
+        }
+    }
+}
+
+/// Dead key (as its spacing character), base character, composed character.
+pub const COMPOSE_TABLE: &[(char, char, char)] = &[
+    ('´', 'a', 'á'), ('´', 'e', 'é'), ('´', 'i', 'í'), ('´', 'o', 'ó'), ('´', 'u', 'ú'), ('´', 'y', 'ý'),
+    ('´', 'A', 'Á'), ('´', 'E', 'É'), ('´', 'I', 'Í'), ('´', 'O', 'Ó'), ('´', 'U', 'Ú'), ('´', 'Y', 'Ý'),
+    ('`', 'a', 'à'), ('`', 'e', 'è'), ('`', 'i', 'ì'), ('`', 'o', 'ò'), ('`', 'u', 'ù'),
+    ('`', 'A', 'À'), ('`', 'E', 'È'), ('`', 'I', 'Ì'), ('`', 'O', 'Ò'), ('`', 'U', 'Ù'),
+    ('^', 'a', 'â'), ('^', 'e', 'ê'), ('^', 'i', 'î'), ('^', 'o', 'ô'), ('^', 'u', 'û'),
+    ('^', 'A', 'Â'), ('^', 'E', 'Ê'), ('^', 'I', 'Î'), ('^', 'O', 'Ô'), ('^', 'U', 'Û'),
+    ('¨', 'a', 'ä'), ('¨', 'e', 'ë'), ('¨', 'i', 'ï'), ('¨', 'o', 'ö'), ('¨', 'u', 'ü'), ('¨', 'y', 'ÿ'),
+    ('¨', 'A', 'Ä'), ('¨', 'E', 'Ë'), ('¨', 'I', 'Ï'), ('¨', 'O', 'Ö'), ('¨', 'U', 'Ü'),
+    ('~', 'a', 'ã'), ('~', 'n', 'ñ'), ('~', 'o', 'õ'),
+    ('~', 'A', 'Ã'), ('~', 'N', 'Ñ'), ('~', 'O', 'Õ'),
+];
+
+/// Returns `base` with the accent of `dead_key`, if there is such a character.
+/// A space gives the accent on its own.
+pub fn compose(dead_key: DeadKey, base: char) -> Option<char> {
+    let accent = dead_key.spacing_char();
+    if base == ' ' {
+        return Some(accent);
+    }
+    COMPOSE_TABLE.iter()
+        .find(|&&(dead, b, _)| dead == accent && b == base)
+        .map(|&(_, _, composed)| composed)
+}
+
+/// Dead key handling between the decoder and the tty.
+pub struct Compose {
+    pending_dead_key: Option<DeadKey>,
+}
+
+impl Compose {
+    pub const fn new() -> Self {
+        Compose { pending_dead_key: None }
+    }
+
+    /// Feeds a decoded character, passing what should be typed to `output`.
+    ///
+    /// A dead key is held back until the next character. If the two do not
+    /// compose, both are typed as they are.
+    pub fn feed(&mut self, c: char, mut output: impl FnMut(char)) {
+        match self.pending_dead_key.take() {
+            Some(dead_key) => match compose(dead_key, c) {
+                Some(composed) => output(composed),
+                None => {
+                    output(dead_key.spacing_char());
+                    output(c);
+                },
+            },
+            None => match DeadKey::from_char(c) {
+                Some(dead_key) => self.pending_dead_key = Some(dead_key),
+                None => output(c),
+            },
+        }
+    }
+}
+
+impl Default for Compose {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
     let port = Port::new(SCANCODE_PORT);
 
     let mut scancode: u8 = 0;
     scancode = unsafe { port.read(scancode) };
+    crate::cpu::random::add_event_jitter();
+    handle_scancode(scancode);
+
+    unsafe { PICS.lock().notify_end_of_interrupt(33); }
+}
+
+/// Replaces what Ctrl+Alt+Q calls, [`crate::exit_qemu`] by default, and
+/// returns the previous hook.
+#[cfg(any(feature = "exit_hotkey", test))]
+pub fn set_exit_hook(hook: fn(QemuExitCode)) -> fn(QemuExitCode) {
+    crate::tables::without_interrupts(|| core::mem::replace(&mut *EXIT_HOOK.lock(), hook))
+}
+
+/// Whether `key`, just pressed, makes Ctrl+Alt+Q. The Q is the key typing `q`
+/// in the layout, not the one at the QWERTY position.
+#[cfg(any(feature = "exit_hotkey", test))]
+fn is_exit_hotkey(key: DecodedKey, transition: KeyTransition, keys_down: &KeyTracker) -> bool {
+    key == DecodedKey::Unicode(CTRL_Q) && transition == KeyTransition::Pressed && keys_down.is_down(KeyCode::LAlt)
+}
+
+/// Decodes a set 1 scancode and passes the resulting character to the tty, after
+/// composing it with a preceding dead key.
+/// Shift+PageUp and Shift+PageDown scroll the screen through its history instead.
+/// With the `exit_hotkey` feature, Ctrl+Alt+Q exits QEMU with `QemuExitCode::Success`.
+///
+/// Must run with interrupts disabled, like the keyboard interrupt does.
+pub fn handle_scancode(scancode: u8) {
+    let mut keyboard = KEYBOARD.lock();
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        let _transition = KEYS_DOWN.lock().update(&key_event);
         if let Some(key) = keyboard.process_keyevent(key_event) {
+            #[cfg(any(feature = "exit_hotkey", test))]
+            if is_exit_hotkey(key, _transition, &KEYS_DOWN.lock()) {
+                let exit = *EXIT_HOOK.lock();
+                drop(keyboard);
+                exit(QemuExitCode::Success);
+                return;
+            }
+            let shifted = keyboard.get_modifiers().is_shifted();
             match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::RawKey(KeyCode::PageUp) if shifted => {
+                    let mut writer = VGA_WRITER.lock();
+                    writer.scroll_view_up(SCROLL_STEP);
+                    mirror_to_console(&writer);
+                },
+                DecodedKey::RawKey(KeyCode::PageDown) if shifted => {
+                    let mut writer = VGA_WRITER.lock();
+                    writer.scroll_view_down(SCROLL_STEP);
+                    mirror_to_console(&writer);
+                },
+                DecodedKey::Unicode(character) => {
+                    COMPOSE.lock().feed(character, |c| TTY.lock().input_char(c));
+                    tty::wake_readers();
+                },
                 DecodedKey::RawKey(_key) => {},
             }
         }
     }
+}
 
-    unsafe { PICS.lock().notify_end_of_interrupt(33); }
+#[test_case]
+fn compose_table_lookups() {
+    assert_eq!(compose(DeadKey::Circumflex, 'e'), Some('ê'));
+    assert_eq!(compose(DeadKey::Grave, 'A'), Some('À'));
+    assert_eq!(compose(DeadKey::Diaeresis, 'y'), Some('ÿ'));
+    assert_eq!(compose(DeadKey::Tilde, 'n'), Some('ñ'));
+    assert_eq!(compose(DeadKey::Acute, ' '), Some('´'));
+    assert_eq!(compose(DeadKey::Tilde, 'e'), None);
+    assert_eq!(compose(DeadKey::Circumflex, 'z'), None);
+    assert_eq!(compose(DeadKey::Grave, '1'), None);
+}
+
+#[test_case]
+fn dead_key_composes_or_falls_back() {
+    let mut compose = Compose::new();
+    let mut typed = [' '; 4];
+    let mut len = 0;
+    for c in ['^', 'o', '^', 'z', 'x'] {
+        compose.feed(c, |c| {
+            typed[len] = c;
+            len += 1;
+        });
+    }
+    assert_eq!(&typed[..len], &['ô', '^', 'z', 'x']);
+}
+
+#[test_case]
+fn extended_key_press_and_release() {
+    use pc_keyboard::layouts::Us104Key;
+
+    let mut keyboard = Keyboard::new(ScancodeSet1::new(), Us104Key, HandleControl::Ignore);
+    let mut tracker = KeyTracker::new();
+    let mut events = [None, None, None];
+    // PageUp: E0 49 pressed, then held (repeat), then E0 C9 released.
+    let mut i = 0;
+    for scancode in [0xE0, 0x49, 0xE0, 0x49, 0xE0, 0xC9] {
+        if let Ok(Some(event)) = keyboard.add_byte(scancode) {
+            assert_eq!(event.code, KeyCode::PageUp);
+            events[i] = Some((event.state, tracker.update(&event)));
+            i += 1;
+        }
+    }
+    assert_eq!(events, [
+        Some((KeyState::Down, KeyTransition::Pressed)),
+        Some((KeyState::Down, KeyTransition::Repeated)),
+        Some((KeyState::Up, KeyTransition::Released)),
+    ]);
+    assert!(!tracker.is_down(KeyCode::PageUp));
+}
+
+#[test_case]
+fn ctrl_alt_q_calls_the_exit_hook() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static EXITS: AtomicUsize = AtomicUsize::new(0);
+    fn record_exit(code: QemuExitCode) {
+        assert_eq!(code, QemuExitCode::Success);
+        EXITS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let previous = set_exit_hook(record_exit);
+    let feed = |scancodes: &[u8]| crate::tables::without_interrupts(|| scancodes.iter().for_each(|&s| handle_scancode(s)));
+    // Left Ctrl, left Alt and the AZERTY Q key (the QWERTY A position) down,
+    // Q held so that it repeats, then everything released.
+    feed(&[0x1D, 0x38, 0x1E, 0x1E, 0x9E, 0xB8, 0x9D]);
+    assert_eq!(EXITS.load(Ordering::Relaxed), 1, "the repeat must not exit again");
+    set_exit_hook(previous);
+    assert!(!is_key_down(KeyCode::LControl) && !is_key_down(KeyCode::LAlt));
+    // Ctrl+Q without Alt is a control character for the tty.
+    assert!(!is_exit_hotkey(DecodedKey::Unicode(CTRL_Q), KeyTransition::Pressed, &KeyTracker::new()));
 }
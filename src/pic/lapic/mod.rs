@@ -0,0 +1,138 @@
+//! The local APIC of each CPU: its ID, and the inter-processor interrupts
+//! starting the other CPUs.
+//!
+//! Every CPU sees its own local APIC at the same physical address, so one
+//! mapping serves them all. The IRQs of the 8259 PICs still reach the boot CPU
+//! through its local APIC in the virtual wire mode the firmware set up.
+
+pub mod timer;
+
+use spin::Once;
+use crate::{
+    acpi,
+    cpu::Msr,
+    memory::{paging::{map_physical_region, PageTableFlags}, FRAME_ALLOCATOR, MAPPER},
+    pci::bar::MmioRegion,
+    tables::{without_interrupts, InterruptStackFrame},
+};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+/// IA32_APIC_BASE: the local APIC is enabled, and the mask of its address.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+const REGISTERS_SIZE: u64 = 0x1000;
+
+const REG_ID: u64 = 0x20;
+const REG_EOI: u64 = 0xB0;
+const REG_SPURIOUS: u64 = 0xF0;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+
+/// Spurious interrupt vector register: the local APIC is software enabled.
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+/// The vector of the interrupts the local APIC raises and then withdraws.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_LEVEL_TRIGGERED: u32 = 1 << 15;
+/// Reads of the delivery status before an IPI is given up on.
+const ICR_POLLS: u32 = 100_000;
+
+static LAPIC: Once<MmioRegion> = Once::new();
+
+/// The local APIC did not accept an IPI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpiTimeout;
+
+/// Maps the local APIC registers, at the address from the MADT or from
+/// IA32_APIC_BASE without one. Returns `None` if they cannot be mapped.
+pub fn init() -> Option<&'static MmioRegion> {
+    if let Some(lapic) = LAPIC.get() {
+        return Some(lapic);
+    }
+    let address = match acpi::madt() {
+        Some(madt) => madt.local_apic_address,
+        None => unsafe { Msr::new(IA32_APIC_BASE).read() & APIC_BASE_ADDRESS },
+    };
+    let mut mapper = MAPPER.lock();
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+        return None;
+    };
+    // PAT index 3: uncacheable.
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let base = unsafe { map_physical_region(address, REGISTERS_SIZE, flags, mapper, allocator) }.ok()?;
+    Some(LAPIC.call_once(|| unsafe { MmioRegion::new(base, REGISTERS_SIZE) }))
+}
+
+fn registers() -> &'static MmioRegion {
+    LAPIC.get().expect("local APIC not mapped")
+}
+
+/// Returns the APIC ID of the current CPU.
+pub fn id() -> u8 {
+    (registers().read::<u32>(REG_ID) >> 24) as u8
+}
+
+/// Enables the local APIC of the current CPU, which INIT leaves disabled.
+///
+/// ## Safety
+///
+/// The IDT must be loaded: the local APIC may raise [`SPURIOUS_VECTOR`] from now on.
+pub unsafe fn enable() {
+    unsafe {
+        let mut base = Msr::new(IA32_APIC_BASE);
+        base.write(base.read() | APIC_BASE_ENABLE);
+        let spurious = registers().read::<u32>(REG_SPURIOUS) & !0xFF;
+        registers().write(REG_SPURIOUS, spurious | SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+}
+
+/// Acknowledges the interrupt in service at the local APIC of the current CPU.
+pub fn end_of_interrupt() {
+    unsafe { registers().write(REG_EOI, 0u32); }
+}
+
+/// Sends `command` to the local APIC `apic_id` and waits until it is delivered.
+fn send_ipi(apic_id: u8, command: u32) -> Result<(), IpiTimeout> {
+    let registers = registers();
+    // The high half must not change between the two writes.
+    without_interrupts(|| unsafe {
+        registers.write(REG_ICR_HIGH, (apic_id as u32) << 24);
+        registers.write(REG_ICR_LOW, command);
+    });
+    for _ in 0..ICR_POLLS {
+        if registers.read::<u32>(REG_ICR_LOW) & ICR_DELIVERY_PENDING == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(IpiTimeout)
+}
+
+/// Sends INIT to `apic_id`, which resets that CPU to wait for a startup IPI.
+///
+/// ## Safety
+///
+/// Whatever the CPU was running is lost.
+pub unsafe fn send_init(apic_id: u8) -> Result<(), IpiTimeout> {
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_TRIGGERED | ICR_ASSERT)?;
+    // Only CPUs older than the Pentium 4 need the deassert, the others ignore it.
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_TRIGGERED)
+}
+
+/// Sends a startup IPI to `apic_id`, which makes a CPU waiting after INIT run
+/// real mode code at physical address `page << 12`.
+///
+/// ## Safety
+///
+/// There must be code for the CPU to run at that page.
+pub unsafe fn send_startup(apic_id: u8, page: u8) -> Result<(), IpiTimeout> {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32)
+}
+
+/// Spurious interrupts are not in service, so they get no end of interrupt.
+pub extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {}
@@ -0,0 +1,241 @@
+//! The local APIC timer of the BSP as the source of the timer ticks, in place
+//! of IRQ 0 from the PIT.
+//!
+//! Its input clock is not architecturally known, so [`init`] measures it over a
+//! few PIT ticks, against the TSC when its frequency is known. A CPU with the
+//! TSC-deadline mode skips that: the timer fires when the TSC reaches a value,
+//! and the handler arms the next one. [`stop`] goes back to the PIT or the
+//! HPET, whichever was the source before, which keeps running all along.
+
+use core::{fmt, sync::atomic::{fence, AtomicU64, AtomicU8, Ordering}};
+use crate::{
+    cpu::{cpuid, rdtsc, tsc_frequency, Msr},
+    pic::timer::{set_tick_source, tick, tick_period_ns, tick_source, ticks, wait_for_tick, TickSource},
+    tables::{enter_interrupt, without_interrupts, InterruptStackFrame, RFlags},
+};
+use super::{end_of_interrupt, registers, LAPIC};
+
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_INITIAL_COUNT: u64 = 0x380;
+const REG_CURRENT_COUNT: u64 = 0x390;
+const REG_DIVIDE: u64 = 0x3E0;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// LVT timer register: masked, and the timer modes.
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+/// Divide configuration register: the timer counts every 16 bus clocks.
+const DIVIDE_BY_16: u32 = 0b0011;
+/// `cpuid` leaf 1, ECX.
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+/// PIT ticks the timer runs for while it is measured.
+const CALIBRATION_TICKS: u64 = 5;
+
+/// The vector of the local APIC timer interrupt.
+pub const TIMER_VECTOR: u8 = 0xF0;
+
+/// The timer interrupt period in nanoseconds, 0 while the timer is stopped.
+static PERIOD_NS: AtomicU64 = AtomicU64::new(0);
+/// TSC cycles between deadlines in TSC-deadline mode, 0 in periodic mode.
+static DEADLINE_STEP: AtomicU64 = AtomicU64::new(0);
+/// The TSC value the timer fires at next in TSC-deadline mode.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+/// The tick source before the timer, and its period, for [`stop`] to go back to.
+static PREVIOUS_SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+static PREVIOUS_TICK_NS: AtomicU64 = AtomicU64::new(0);
+
+/// How the timer counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Counts down from a count measured against the PIT, and reloads it.
+    Periodic,
+    /// Fires when the TSC reaches the deadline the handler keeps moving.
+    TscDeadline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LapicTimerError {
+    NoLocalApic,
+    /// The measurement counts PIT ticks.
+    InterruptsDisabled,
+    /// The timer ran out before the ticks, or did not count at all.
+    CalibrationFailed,
+}
+
+impl fmt::Display for LapicTimerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LapicTimerError::NoLocalApic => write!(f, "local APIC not mapped"),
+            LapicTimerError::InterruptsDisabled => write!(f, "interrupts disabled"),
+            LapicTimerError::CalibrationFailed => write!(f, "calibration against the timer ticks failed"),
+        }
+    }
+}
+
+/// Returns whether the CPU has the TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    cpuid(1, 0).ecx & CPUID_TSC_DEADLINE != 0
+}
+
+/// Measures the input clock of the timer, divided by 16 as set here, over
+/// [`CALIBRATION_TICKS`] ticks.
+///
+/// The PIT or the HPET must be the tick source and interrupts enabled.
+pub fn measure_frequency() -> Result<u64, LapicTimerError> {
+    let registers = LAPIC.get().ok_or(LapicTimerError::NoLocalApic)?;
+    if !RFlags::read().contains(RFlags::INTERRUPT_FLAG) {
+        return Err(LapicTimerError::InterruptsDisabled);
+    }
+    unsafe {
+        registers.write(REG_DIVIDE, DIVIDE_BY_16);
+        registers.write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    }
+    // Start right after a tick, so the measurement spans whole ticks.
+    wait_for_tick(ticks());
+    let (start_ticks, start_tsc) = without_interrupts(|| {
+        unsafe { registers.write(REG_INITIAL_COUNT, u32::MAX); }
+        (ticks(), rdtsc())
+    });
+    while ticks() - start_ticks < CALIBRATION_TICKS {
+        core::hint::spin_loop();
+    }
+    let (remaining, tsc_delta) = without_interrupts(|| {
+        (registers.read::<u32>(REG_CURRENT_COUNT), rdtsc() - start_tsc)
+    });
+    unsafe { registers.write(REG_INITIAL_COUNT, 0u32); }
+
+    let elapsed_ns = match tsc_frequency() {
+        Some(hz) => (tsc_delta as u128 * 1_000_000_000 / hz as u128) as u64,
+        None => CALIBRATION_TICKS * tick_period_ns(),
+    };
+    let counted = (u32::MAX - remaining) as u64;
+    if remaining == 0 || counted == 0 || elapsed_ns == 0 {
+        return Err(LapicTimerError::CalibrationFailed);
+    }
+    Ok((counted as u128 * 1_000_000_000 / elapsed_ns as u128) as u64)
+}
+
+/// Makes the local APIC timer of the current CPU, which must be the BSP, raise
+/// the timer ticks at `hz` Hz in place of the PIT. TSC-deadline mode is used
+/// when the CPU has it and the TSC frequency is known.
+///
+/// Interrupts must be enabled, the periodic mode is measured against the PIT.
+pub fn init(hz: u64) -> Result<TimerMode, LapicTimerError> {
+    start(hz, has_tsc_deadline())
+}
+
+/// Starts the timer at `hz` Hz, in TSC-deadline mode if `tsc_deadline` and the
+/// TSC frequency is known, and switches the tick source to it.
+fn start(hz: u64, tsc_deadline: bool) -> Result<TimerMode, LapicTimerError> {
+    let hz = hz.max(1);
+    if tick_source() != TickSource::LapicTimer {
+        PREVIOUS_SOURCE.store(tick_source() as u8, Ordering::Relaxed);
+        PREVIOUS_TICK_NS.store(tick_period_ns(), Ordering::Relaxed);
+    }
+    if let (true, Some(tsc_hz)) = (tsc_deadline, tsc_frequency()) {
+        let registers = LAPIC.get().ok_or(LapicTimerError::NoLocalApic)?;
+        let step = tsc_hz / hz;
+        without_interrupts(|| {
+            DEADLINE_STEP.store(step, Ordering::Relaxed);
+            PERIOD_NS.store(1_000_000_000 / hz, Ordering::Relaxed);
+            unsafe { registers.write(REG_LVT_TIMER, LVT_TSC_DEADLINE | TIMER_VECTOR as u32); }
+            // The mode switch must be done before the deadline write, which
+            // would otherwise be ignored.
+            fence(Ordering::SeqCst);
+            let deadline = rdtsc() + step;
+            NEXT_DEADLINE.store(deadline, Ordering::Relaxed);
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline); }
+            set_tick_source(TickSource::LapicTimer, PERIOD_NS.load(Ordering::Relaxed));
+        });
+        return Ok(TimerMode::TscDeadline);
+    }
+
+    let frequency = measure_frequency()?;
+    let count = (frequency / hz).clamp(1, u32::MAX as u64);
+    let registers = registers();
+    without_interrupts(|| {
+        DEADLINE_STEP.store(0, Ordering::Relaxed);
+        PERIOD_NS.store((count as u128 * 1_000_000_000 / frequency as u128) as u64, Ordering::Relaxed);
+        unsafe {
+            registers.write(REG_DIVIDE, DIVIDE_BY_16);
+            registers.write(REG_LVT_TIMER, LVT_PERIODIC | TIMER_VECTOR as u32);
+            registers.write(REG_INITIAL_COUNT, count as u32);
+        }
+        set_tick_source(TickSource::LapicTimer, PERIOD_NS.load(Ordering::Relaxed));
+    });
+    Ok(TimerMode::Periodic)
+}
+
+/// Stops the local APIC timer and makes the source before it the tick source again.
+pub fn stop() {
+    let Some(registers) = LAPIC.get() else {
+        return;
+    };
+    without_interrupts(|| {
+        unsafe {
+            registers.write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+            registers.write(REG_INITIAL_COUNT, 0u32);
+            if DEADLINE_STEP.load(Ordering::Relaxed) != 0 {
+                Msr::new(IA32_TSC_DEADLINE).write(0);
+            }
+        }
+        PERIOD_NS.store(0, Ordering::Relaxed);
+        DEADLINE_STEP.store(0, Ordering::Relaxed);
+        let previous = TickSource::from_u8(PREVIOUS_SOURCE.load(Ordering::Relaxed));
+        set_tick_source(previous, PREVIOUS_TICK_NS.load(Ordering::Relaxed));
+    });
+}
+
+/// Counts a tick like the PIT handler, and arms the next deadline in
+/// TSC-deadline mode. A deadline already past fires right away, so late
+/// interrupts catch up instead of drifting.
+pub extern "x86-interrupt" fn timer_handler(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    tick(PERIOD_NS.load(Ordering::Relaxed));
+    let step = DEADLINE_STEP.load(Ordering::Relaxed);
+    if step != 0 {
+        let next = NEXT_DEADLINE.fetch_add(step, Ordering::Relaxed) + step;
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(next); }
+    }
+    end_of_interrupt();
+    crate::process::scheduler::preempt(&stack_frame);
+}
+
+/// TSC cycles per tick of the current source, over `count` ticks.
+#[cfg(test)]
+fn tsc_per_tick(count: u64) -> u64 {
+    wait_for_tick(ticks());
+    let (start, start_tsc) = (ticks(), rdtsc());
+    while ticks() - start < count {
+        core::hint::spin_loop();
+    }
+    (rdtsc() - start_tsc) / count
+}
+
+#[test_case]
+fn lapic_timer_ticks_like_the_pit() {
+    use crate::pic::timer::{tick_rate, tick_source, uptime_ms};
+
+    assert_eq!(tick_source(), TickSource::Pit);
+    let hz = tick_rate();
+    let pit = tsc_per_tick(10);
+    let uptime = uptime_ms();
+    for tsc_deadline in [false, true] {
+        if tsc_deadline && (!has_tsc_deadline() || tsc_frequency().is_none()) {
+            continue;
+        }
+        let mode = start(hz, tsc_deadline).unwrap();
+        assert_eq!(mode == TimerMode::TscDeadline, tsc_deadline);
+        assert_eq!(tick_source(), TickSource::LapicTimer);
+        let lapic = tsc_per_tick(10);
+        stop();
+        assert!(lapic.abs_diff(pit) <= pit / 20, "{:?}: {} TSC cycles per tick, PIT {}", mode, lapic, pit);
+    }
+    assert_eq!(tick_source(), TickSource::Pit);
+    // The PIT ticks again once the local APIC timer is stopped.
+    wait_for_tick(ticks());
+    // The ticks counted above, less one for the calibrated tick being a bit short.
+    assert!(uptime_ms() - uptime >= 9 * 1000 / hz, "uptime went from {} to {} ms", uptime, uptime_ms());
+}
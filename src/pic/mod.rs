@@ -1,10 +1,22 @@
 pub mod timer;
+pub mod hpet;
 pub mod keyboard;
+pub mod mouse;
+pub mod speaker;
+pub mod lapic;
 
-use spin::Mutex;
-use crate::Port;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::{sync::Mutex, tables::{enter_interrupt, InterruptStackFrame}, Port};
 
-pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new_contiguous(32) });
+pub static PICS: Mutex<ChainedPics> = Mutex::new("PICS", unsafe { ChainedPics::new_contiguous(32) });
+
+/// Spurious IRQ 7 and IRQ 15 received since boot.
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// IRQ line of the master PIC the slave is chained to.
+const CASCADE_IRQ: u8 = 2;
+/// IRQs from the highest to the lowest priority, the slave's taking the place of the cascade.
+const IRQ_PRIORITY: [u8; 15] = [0, 1, 8, 9, 10, 11, 12, 13, 14, 15, 3, 4, 5, 6, 7];
 
 
 /// Command sent to begin PIC initialization.
@@ -13,6 +25,11 @@ const CMD_INIT: u8 = 0x11;
 /// Command sent to acknowledge an interrupt.
 const CMD_END_OF_INTERRUPT: u8 = 0x20;
 
+/// OCW3: the next read of the command port returns the interrupt request register.
+const CMD_READ_IRR: u8 = 0x0A;
+/// OCW3: the next read of the command port returns the in-service register.
+const CMD_READ_ISR: u8 = 0x0B;
+
 // The mode in which we want to run our PICs.
 const MODE_8086: u8 = 0x01;
 
@@ -52,6 +69,18 @@ impl Pic {
     unsafe fn write_mask(&mut self, mask: u8) {
         self.data.write(mask)
     }
+
+    /// Reads the IRQs this PIC has sent to the CPU and not seen an end of interrupt for.
+    unsafe fn read_isr(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read(0u8)
+    }
+
+    /// Reads the IRQs raised but not yet sent to the CPU.
+    unsafe fn read_irr(&mut self) -> u8 {
+        self.command.write(CMD_READ_IRR);
+        self.command.read(0u8)
+    }
 }
 
 /// A pair of chained PICs.  This is the standard setup on x86.
@@ -152,6 +181,29 @@ impl ChainedPics {
         self.write_masks(u8::MAX, u8::MAX)
     }
 
+    /// Returns the in-service registers of both PICs, master in the low byte and
+    /// slave in the high byte. A bit is set from the moment the CPU takes an IRQ
+    /// until its end of interrupt.
+    pub fn irq_in_service(&mut self) -> u16 {
+        unsafe { u16::from_le_bytes([self.pics[0].read_isr(), self.pics[1].read_isr()]) }
+    }
+
+    /// Returns the interrupt request registers of both PICs, laid out like
+    /// [`ChainedPics::irq_in_service`]: IRQs raised but not taken yet.
+    pub fn irq_requested(&mut self) -> u16 {
+        unsafe { u16::from_le_bytes([self.pics[0].read_irr(), self.pics[1].read_irr()]) }
+    }
+
+    /// Returns the in-service IRQ with the highest priority, which is the one being
+    /// handled right now when IRQs nest.
+    ///
+    /// IRQ 0 has the highest priority and IRQ 7 the lowest, with the slave's IRQs
+    /// 8 to 15 in place of the cascade on IRQ 2, which is never returned itself.
+    pub fn current_irq(&mut self) -> Option<u8> {
+        let in_service = self.irq_in_service();
+        IRQ_PRIORITY.into_iter().find(|&irq| in_service & (1 << irq) != 0)
+    }
+
     /// Do we handle this interrupt?
     pub fn handles_interrupt(&self, interrupt_id: u8) -> bool {
         self.pics.iter().any(|p| p.handles_interrupt(interrupt_id))
@@ -161,6 +213,7 @@ impl ChainedPics {
     /// interrupt.  This is tricky, because all interrupts from `pics[1]`
     /// get chained through `pics[0]`.
     pub unsafe fn notify_end_of_interrupt(&mut self, interrupt_id: u8) {
+        crate::tables::debug_assert_in_interrupt();
         if self.handles_interrupt(interrupt_id) {
             if self.pics[1].handles_interrupt(interrupt_id) {
                 self.pics[1].end_of_interrupt();
@@ -169,3 +222,128 @@ impl ChainedPics {
         }
     }
 }
+
+/// IRQ lines the firmware routes PCI interrupts to, which drivers register
+/// handlers for at run time. Each has a fixed IDT entry calling [`dispatch_irq`].
+pub const SHARED_IRQS: [u8; 4] = [5, 9, 10, 11];
+
+static IRQ_HANDLERS: Mutex<[Option<fn()>; 16]> = Mutex::new("IRQ_HANDLERS", [None; 16]);
+
+/// Calls `handler` on IRQ `irq`, which must be one of [`SHARED_IRQS`], and
+/// unmasks it. A handler already registered for the line is replaced.
+pub fn register_irq_handler(irq: u8, handler: fn()) {
+    assert!(SHARED_IRQS.contains(&irq), "IRQ {} has no shared handler", irq);
+    crate::tables::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize] = Some(handler);
+        unmask_irq(irq);
+    });
+}
+
+/// Sets up the PS/2 mouse if the FADT says there is an 8042 controller.
+/// Without one, the keyboard and mouse IRQs are masked instead, so that
+/// nothing polls its ports, and `false` is returned.
+pub fn init_ps2() -> bool {
+    if !mouse::controller_present() {
+        mask_irq(1);
+        mask_irq(12);
+        crate::println!("PS/2: no PS/2 controller");
+        return false;
+    }
+    mouse::init_ps2_mouse();
+    true
+}
+
+/// Unmasks `irq`, and the cascade for an IRQ of the slave.
+pub(crate) fn unmask_irq(irq: u8) {
+    crate::tables::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let [master, slave] = pics.read_masks();
+            if irq < 8 {
+                pics.write_masks(master & !(1 << irq), slave);
+            } else {
+                pics.write_masks(master & !(1 << CASCADE_IRQ), slave & !(1 << (irq - 8)));
+            }
+        }
+    });
+}
+
+/// Masks `irq`. The cascade is left unmasked for the other IRQs of the slave.
+pub(crate) fn mask_irq(irq: u8) {
+    crate::tables::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let [master, slave] = pics.read_masks();
+            if irq < 8 {
+                pics.write_masks(master | 1 << irq, slave);
+            } else {
+                pics.write_masks(master, slave | 1 << (irq - 8));
+            }
+        }
+    });
+}
+
+/// Runs the handler registered for `irq` and acknowledges it.
+fn dispatch_irq(irq: u8) {
+    let _depth = enter_interrupt();
+    // Copied out, the handler may take the lock to register another one.
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+    unsafe { PICS.lock().notify_end_of_interrupt(32 + irq); }
+}
+
+macro_rules! shared_irq_handler {
+    ($name:ident, $irq:literal) => {
+        pub extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            dispatch_irq($irq);
+        }
+    };
+}
+
+shared_irq_handler!(irq5_handler, 5);
+shared_irq_handler!(irq9_handler, 9);
+shared_irq_handler!(irq10_handler, 10);
+shared_irq_handler!(irq11_handler, 11);
+
+/// Returns the number of spurious IRQs since boot.
+pub fn spurious_irqs() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+/// IRQ 7, which the master PIC also raises when an IRQ disappears before the
+/// CPU acknowledged it. Such a spurious IRQ is not in service and must not get
+/// an end of interrupt.
+pub extern "x86-interrupt" fn master_spurious_handler(_stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    let mut pics = PICS.lock();
+    if pics.irq_in_service() & 1 << 7 == 0 {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    unsafe { pics.notify_end_of_interrupt(32 + 7); }
+}
+
+/// IRQ 15, the slave's counterpart of [`master_spurious_handler`]. The master
+/// did put the cascade in service for a spurious IRQ 15, so it still needs its
+/// end of interrupt.
+pub extern "x86-interrupt" fn slave_spurious_handler(_stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    let mut pics = PICS.lock();
+    if pics.irq_in_service() & 1 << 15 == 0 {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        unsafe { pics.pics[0].end_of_interrupt(); }
+        return;
+    }
+    unsafe { pics.notify_end_of_interrupt(32 + 15); }
+}
+
+#[test_case]
+fn no_irq_in_service_outside_interrupts() {
+    crate::tables::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        assert_eq!(pics.irq_in_service(), 0);
+        assert_eq!(pics.current_irq(), None);
+    });
+}
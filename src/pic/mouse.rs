@@ -0,0 +1,286 @@
+use crate::{pic::PICS, sync::Mutex, tables::{enter_interrupt, port::Port, without_interrupts, InterruptStackFrame}};
+
+/// Data port shared by the keyboard and the mouse.
+const DATA_PORT: u16 = 0x60;
+/// Status register when read, controller commands when written.
+const COMMAND_PORT: u16 = 0x64;
+
+/// Status bit: a byte is waiting on the data port.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Status bit: the controller has not taken the last byte written yet.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_ENABLE_AUX: u8 = 0xA8;
+/// The next byte written to the data port goes to the mouse instead of the keyboard.
+const CMD_WRITE_AUX: u8 = 0xD4;
+
+/// Configuration byte bit: raise IRQ 12 for mouse data.
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+/// Configuration byte bit: the mouse clock is disabled.
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+const MOUSE_ENABLE_STREAMING: u8 = 0xF4;
+const MOUSE_ACK: u8 = 0xFA;
+
+/// Status polls before the controller is considered gone.
+const STATUS_POLLS: u32 = 100_000;
+
+/// IRQ 12 is bit 4 of the slave PIC mask.
+const SLAVE_MASK_IRQ12: u8 = 1 << 4;
+/// The slave PIC is chained to IRQ 2 of the master.
+const MASTER_MASK_CASCADE: u8 = 1 << 2;
+
+/// Mouse events kept until read, the oldest are kept when it is full.
+const EVENT_CAPACITY: usize = 64;
+
+/// Packet byte 0 bits.
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+/// Always set in byte 0, used to find the start of a packet again.
+const PACKET_SYNC: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+
+static DECODER: Mutex<PacketDecoder> = Mutex::new("MOUSE_DECODER", PacketDecoder::new());
+static EVENTS: Mutex<MouseEvents> = Mutex::new("MOUSE_EVENTS", MouseEvents::new());
+
+/// A decoded mouse packet. `dy` is positive upwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    pub dx: i8,
+    pub dy: i8,
+}
+
+/// Assembles the 3-byte packets of a standard PS/2 mouse.
+pub struct PacketDecoder {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub const fn new() -> Self {
+        PacketDecoder { bytes: [0; 3], len: 0 }
+    }
+
+    /// Feeds a byte from the mouse, returning the event once a packet is complete.
+    ///
+    /// A first byte without the sync bit is dropped, so a decoder that started
+    /// in the middle of a packet catches up at the next one.
+    pub fn feed(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.len == 0 && byte & PACKET_SYNC == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.bytes.len() {
+            return None;
+        }
+        self.len = 0;
+        let [flags, x, y] = self.bytes;
+        Some(MouseEvent {
+            left: flags & PACKET_LEFT != 0,
+            right: flags & PACKET_RIGHT != 0,
+            middle: flags & PACKET_MIDDLE != 0,
+            dx: movement(x, flags & PACKET_X_SIGN != 0),
+            dy: movement(y, flags & PACKET_Y_SIGN != 0),
+        })
+    }
+}
+
+impl Default for PacketDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sign extends a 9-bit movement and clamps it to an `i8`.
+fn movement(low: u8, negative: bool) -> i8 {
+    let value = if negative { low as i16 - 0x100 } else { low as i16 };
+    value.clamp(i8::MIN as i16, i8::MAX as i16) as i8
+}
+
+/// Ring buffer of mouse events waiting to be read.
+struct MouseEvents {
+    events: [MouseEvent; EVENT_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl MouseEvents {
+    const fn new() -> Self {
+        MouseEvents {
+            events: [MouseEvent { left: false, right: false, middle: false, dx: 0, dy: 0 }; EVENT_CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, event: MouseEvent) {
+        if self.len == EVENT_CAPACITY {
+            return;
+        }
+        self.events[(self.head + self.len) % EVENT_CAPACITY] = event;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<MouseEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % EVENT_CAPACITY;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+/// Takes the oldest mouse event not read yet.
+pub fn read_event() -> Option<MouseEvent> {
+    without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// Whether the FADT says there is an 8042 PS/2 controller, or is too old to say.
+pub fn controller_present() -> bool {
+    crate::acpi::boot_flags().contains(crate::acpi::BootFlags::PS2_8042)
+}
+
+/// The PS/2 controller did not answer in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ps2Timeout;
+
+fn wait_for_write() -> Result<(), Ps2Timeout> {
+    let status = Port::new(COMMAND_PORT);
+    for _ in 0..STATUS_POLLS {
+        if unsafe { status.read(0u8) } & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Timeout)
+}
+
+fn wait_for_read() -> Result<(), Ps2Timeout> {
+    let status = Port::new(COMMAND_PORT);
+    for _ in 0..STATUS_POLLS {
+        if unsafe { status.read(0u8) } & STATUS_OUTPUT_FULL != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Timeout)
+}
+
+fn write_command(command: u8) -> Result<(), Ps2Timeout> {
+    wait_for_write()?;
+    unsafe { Port::new(COMMAND_PORT).write(command); }
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), Ps2Timeout> {
+    wait_for_write()?;
+    unsafe { Port::new(DATA_PORT).write(data); }
+    Ok(())
+}
+
+fn read_data() -> Result<u8, Ps2Timeout> {
+    wait_for_read()?;
+    Ok(unsafe { Port::new(DATA_PORT).read(0u8) })
+}
+
+/// Enables the mouse port of the PS/2 controller, puts the mouse in stream mode
+/// and unmasks IRQ 12.
+///
+/// Interrupts are disabled meanwhile, so the keyboard handler does not take the
+/// controller's answers from the data port.
+pub fn init_ps2_mouse() {
+    let result = without_interrupts(|| {
+        write_command(CMD_ENABLE_AUX)?;
+
+        write_command(CMD_READ_CONFIG)?;
+        let config = read_data()?;
+        write_command(CMD_WRITE_CONFIG)?;
+        write_data((config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED)?;
+
+        write_command(CMD_WRITE_AUX)?;
+        write_data(MOUSE_ENABLE_STREAMING)?;
+        let ack = read_data()?;
+
+        let mut pics = PICS.lock();
+        unsafe {
+            let [master, slave] = pics.read_masks();
+            pics.write_masks(master & !MASTER_MASK_CASCADE, slave & !SLAVE_MASK_IRQ12);
+        }
+        Ok::<_, Ps2Timeout>(ack)
+    });
+    match result {
+        Ok(MOUSE_ACK) => {},
+        Ok(answer) => crate::println!("PS/2 mouse: enabling streaming answered {:#x}", answer),
+        Err(Ps2Timeout) => crate::println!("PS/2 mouse: controller timed out"),
+    }
+}
+
+pub extern "x86-interrupt" fn mouse_handler(_stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    let byte = unsafe { Port::new(DATA_PORT).read(0u8) };
+    if let Some(event) = DECODER.lock().feed(byte) {
+        EVENTS.lock().push(event);
+    }
+
+    unsafe { PICS.lock().notify_end_of_interrupt(44); }
+}
+
+#[test_case]
+fn packet_decoding() {
+    let mut decoder = PacketDecoder::new();
+    assert_eq!(decoder.feed(0x09), None);
+    assert_eq!(decoder.feed(0x05), None);
+    assert_eq!(decoder.feed(0x03), Some(MouseEvent { left: true, right: false, middle: false, dx: 5, dy: 3 }));
+
+    // Both movements negative, right and middle buttons down.
+    assert_eq!(decoder.feed(0x3E), None);
+    assert_eq!(decoder.feed(0xFB), None);
+    assert_eq!(decoder.feed(0x80), Some(MouseEvent { left: false, right: true, middle: true, dx: -5, dy: -128 }));
+
+    // Movements beyond an i8 are clamped.
+    decoder.feed(0x28);
+    decoder.feed(0x00);
+    assert_eq!(decoder.feed(0x00).map(|event| event.dy), Some(-128));
+    decoder.feed(0x08);
+    decoder.feed(0xFF);
+    assert_eq!(decoder.feed(0x00).map(|event| event.dx), Some(127));
+}
+
+#[test_case]
+fn packet_decoder_resyncs() {
+    let mut decoder = PacketDecoder::new();
+    // Started mid-packet: bytes without the sync bit are dropped until one has it.
+    assert_eq!(decoder.feed(0x05), None);
+    assert_eq!(decoder.feed(0x03), None);
+    assert_eq!(decoder.feed(0x08), None);
+    assert_eq!(decoder.feed(0x01), None);
+    assert_eq!(decoder.feed(0xFF), Some(MouseEvent { dx: 1, dy: 127, ..MouseEvent::default() }));
+    assert_eq!(decoder.feed(0x0A), None);
+}
+
+#[test_case]
+fn mouse_event_ring_buffer() {
+    let mut events = MouseEvents::new();
+    assert_eq!(events.pop(), None);
+    for dx in 0..EVENT_CAPACITY as i8 + 2 {
+        events.push(MouseEvent { dx, ..MouseEvent::default() });
+    }
+    assert_eq!(events.pop().map(|event| event.dx), Some(0));
+    assert_eq!(events.pop().map(|event| event.dx), Some(1));
+    events.push(MouseEvent { dx: -1, ..MouseEvent::default() });
+    let mut last = None;
+    while let Some(event) = events.pop() {
+        last = Some(event.dx);
+    }
+    assert_eq!(last, Some(-1));
+}
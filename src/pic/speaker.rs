@@ -0,0 +1,47 @@
+//! The PC speaker, driven by the square wave of PIT counter 2.
+
+use crate::tables::{port::Port, without_interrupts};
+use super::timer::{delay_ms, pit_counter2_frequency};
+
+/// Keyboard controller port B, which gates counter 2 and the speaker.
+const PORT_B: u16 = 0x61;
+/// Port B bit 0: counter 2 gate input.
+const COUNTER_2_GATE: u8 = 1 << 0;
+/// Port B bit 1: counter 2 output reaches the speaker.
+const SPEAKER_DATA: u8 = 1 << 1;
+
+fn update_port_b(f: impl FnOnce(u8) -> u8) {
+    without_interrupts(|| {
+        let port = Port::new(PORT_B);
+        unsafe {
+            let value = port.read(0u8);
+            port.write(f(value));
+        }
+    })
+}
+
+/// Starts a tone of `frequency_hz` until [`speaker_off`].
+pub fn speaker_on(frequency_hz: u64) {
+    pit_counter2_frequency(frequency_hz);
+    update_port_b(|value| value | COUNTER_2_GATE | SPEAKER_DATA);
+}
+
+pub fn speaker_off() {
+    update_port_b(|value| value & !(COUNTER_2_GATE | SPEAKER_DATA));
+}
+
+/// Plays `frequency_hz` for `duration_ms` milliseconds.
+///
+/// Waits with [`delay_ms`], so interrupts must be enabled.
+pub fn speaker_beep(frequency_hz: u64, duration_ms: u64) {
+    speaker_on(frequency_hz);
+    delay_ms(duration_ms);
+    speaker_off();
+}
+
+#[test_case]
+fn beep_leaves_speaker_off() {
+    speaker_beep(880, 10);
+    let value = unsafe { Port::new(PORT_B).read(0u8) };
+    assert_eq!(value & (COUNTER_2_GATE | SPEAKER_DATA), 0);
+}
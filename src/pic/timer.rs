@@ -1,24 +1,393 @@
-use crate::{pic::PICS, tables::{port::Port, InterruptStackFrame}};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use crate::{cpu::{rdtsc, tsc_frequency}, pic::PICS, sync::Mutex, tables::{enter_interrupt, port::Port, without_interrupts, InterruptStackFrame, RFlags}};
 
 const PIT_CTRL_WORD: u16 = 0x43;
 const PIT_COUNTER_0: u16 = 0x40;
+const PIT_COUNTER_2: u16 = 0x42;
 const CLOCK_RATE: u64 = 1193180;
 
-pub extern "x86-interrupt" fn pit_handler(_stack_frame: InterruptStackFrame) {
+/// Counter 0 is loaded with this for the calibration, about 55ms at the nominal rate.
+const CALIBRATION_COUNT: u16 = 0xFFFF;
+
+/// Timer interrupts received since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// The interrupt frequency requested from `init_pit`.
+static TICK_RATE: AtomicU64 = AtomicU64::new(0);
+/// The counter 0 mode requested from `init_pit`.
+static TICK_MODE: AtomicU8 = AtomicU8::new(PitMode::SquareWave as u8);
+/// The PIT input clock as measured by `calibrate_pit`, `CLOCK_RATE` until then.
+static ACTUAL_PIT_FREQ: AtomicU64 = AtomicU64::new(CLOCK_RATE);
+/// Timer interrupts that arrived while the CPU was halted in `idle`.
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Set while the CPU is halted in `idle`.
+static IDLE: AtomicBool = AtomicBool::new(false);
+/// What raises the timer interrupts, a `TickSource`.
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+/// The tick period of the local APIC timer or the HPET in nanoseconds, while
+/// one of them is the source.
+static SOURCE_TICK_NS: AtomicU64 = AtomicU64::new(0);
+/// The time the ticks since boot add up to, in nanoseconds.
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+/// Run in every timer interrupt, see [`set_tick_hook`].
+static TICK_HOOK: Mutex<Option<fn()>> = Mutex::new("TICK_HOOK", None);
+
+/// Where the timer interrupts counted by [`ticks`] come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickSource {
+    /// IRQ 0, from counter 0 of the PIT as programmed by [`init_pit`].
+    Pit        = 0,
+    /// The local APIC timer of the BSP, see [`super::lapic::timer`].
+    LapicTimer = 1,
+    /// IRQ 0, from comparator 0 of the HPET, see [`super::hpet`].
+    Hpet       = 2,
+}
+
+impl TickSource {
+    pub(crate) fn from_u8(source: u8) -> Self {
+        match source {
+            0 => TickSource::Pit,
+            1 => TickSource::LapicTimer,
+            _ => TickSource::Hpet,
+        }
+    }
+}
+
+/// Counts a timer interrupt lasting `period_ns`, from whichever source, wakes
+/// the threads whose sleep is over and runs the tick hook.
+pub(crate) fn tick(period_ns: u64) {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    UPTIME_NS.fetch_add(period_ns, Ordering::Relaxed);
+    if IDLE.load(Ordering::Relaxed) {
+        IDLE_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    crate::process::scheduler::wake_sleepers(ticks);
+    if let Some(hook) = *TICK_HOOK.lock() {
+        hook();
+    }
+}
+
+/// Makes `hook` run in every timer interrupt, after the tick is counted, and
+/// returns the previous one.
+pub fn set_tick_hook(hook: Option<fn()>) -> Option<fn()> {
+    without_interrupts(|| core::mem::replace(&mut *TICK_HOOK.lock(), hook))
+}
+
+/// IRQ 0, from the PIT or from the HPET taking its place.
+pub extern "x86-interrupt" fn pit_handler(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    tick(tick_period_ns());
     unsafe { PICS.lock().notify_end_of_interrupt(32); }
+    crate::process::scheduler::preempt(&stack_frame);
+}
+
+/// Returns the source of the timer ticks.
+pub fn tick_source() -> TickSource {
+    TickSource::from_u8(TICK_SOURCE.load(Ordering::Relaxed))
+}
+
+/// Returns the length of a tick of the current source in nanoseconds.
+pub(crate) fn tick_period_ns() -> u64 {
+    match tick_source() {
+        TickSource::Pit => pit_tick_ns(),
+        _ => SOURCE_TICK_NS.load(Ordering::Relaxed),
+    }
+}
+
+/// Makes the ticks come from `source`. The local APIC timer or the HPET must
+/// already be running with a period of `tick_ns`, which the PIT ignores.
+///
+/// IRQ 0 is masked while the local APIC timer is the source, so no time is
+/// counted twice. Deadlines taken in ticks before the switch are not converted.
+pub(crate) fn set_tick_source(source: TickSource, tick_ns: u64) {
+    without_interrupts(|| {
+        SOURCE_TICK_NS.store(tick_ns, Ordering::Relaxed);
+        let mut pics = PICS.lock();
+        let [master, slave] = unsafe { pics.read_masks() };
+        let master = match source {
+            TickSource::Pit | TickSource::Hpet => master & !1,
+            TickSource::LapicTimer => master | 1,
+        };
+        unsafe { pics.write_masks(master, slave); }
+        TICK_SOURCE.store(source as u8, Ordering::Relaxed);
+    });
+}
+
+/// Operating modes of a PIT counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PitMode {
+    /// Interrupt on terminal count: the output goes high once the count runs out.
+    OneShot           = 0,
+    /// Hardware retriggerable one-shot, started by the gate input.
+    HardwareRetrigger = 1,
+    /// Pulses low for one clock every `divisor` clocks.
+    RateGenerator     = 2,
+    /// Square wave with a period of `divisor` clocks.
+    SquareWave        = 3,
+}
+
+impl PitMode {
+    fn from_u8(mode: u8) -> Self {
+        match mode {
+            0 => PitMode::OneShot,
+            1 => PitMode::HardwareRetrigger,
+            2 => PitMode::RateGenerator,
+            _ => PitMode::SquareWave,
+        }
+    }
+
+    /// The control word selecting `counter` (0 to 2) in this mode, binary
+    /// counting, loaded LSB then MSB.
+    fn control_word(self, counter: u8) -> u8 {
+        //  counter  | RD or LD LSB then MSB |  mode  | Binary counter
+        (counter & 0x3) << 6 | 0b11 << 4 | (self as u8) << 1
+    }
 }
 
-pub fn init_pit(frequency: u64) {
+/// Programs counter 0 to raise IRQ 0 at `frequency` Hz in `mode`, which should
+/// be periodic: `SquareWave` or `RateGenerator`.
+pub fn init_pit(frequency: u64, mode: PitMode) {
+    TICK_RATE.store(frequency, Ordering::Relaxed);
+    TICK_MODE.store(mode as u8, Ordering::Relaxed);
     let divisor = CLOCK_RATE / frequency;
-    let port = Port::new(PIT_CTRL_WORD);
-	//    00                 11                      011                         0
-	// Counter 0 | RD or LD LSB then MSB | Mode 3: Square Wave Generator | Binary counter
-    unsafe { port.write(0b110110u8); }
+    // Another access to counter 0 between the three writes, such as the
+    // calibration in an interrupt, would load the wrong halves of the count.
+    without_interrupts(|| {
+        unsafe { Port::new(PIT_CTRL_WORD).write(mode.control_word(0)); }
+        write_counter_0(divisor as u16);
+    });
+}
+
+/// Programs counter 2, which drives the PC speaker, to a square wave of
+/// `freq_hz`. Counter 0 and the timer interrupt are left alone.
+pub fn pit_counter2_frequency(freq_hz: u64) {
+    let divisor = (CLOCK_RATE / freq_hz.max(1)).clamp(1, u16::MAX as u64) as u16;
+    without_interrupts(|| unsafe {
+        Port::new(PIT_CTRL_WORD).write(PitMode::SquareWave.control_word(2));
+        let port = Port::new(PIT_COUNTER_2);
+        port.write(divisor as u8);
+        port.write((divisor >> 8) as u8);
+    });
+}
+
+/// Loads `count` into counter 0, LSB then MSB. Callers disable interrupts
+/// around it, together with the control word written before.
+fn write_counter_0(count: u16) {
     let port = Port::new(PIT_COUNTER_0);
-    let lsb: u8 = (divisor & 0xFF) as u8;
-    let msb: u8 = ((divisor >> 8) &0xFF) as u8;
+    let lsb: u8 = (count & 0xFF) as u8;
+    let msb: u8 = ((count >> 8) &0xFF) as u8;
     unsafe {
         port.write(lsb);
         port.write(msb);
     }
 }
+
+/// Reads the current value of counter 0 through a counter latch command.
+#[cfg(test)]
+fn read_counter_0() -> u16 {
+    without_interrupts(|| unsafe {
+        // Counter 0, latch command.
+        Port::new(PIT_CTRL_WORD).write(0u8);
+        let port = Port::new(PIT_COUNTER_0);
+        let lsb = port.read(0u8);
+        let msb = port.read(0u8);
+        u16::from_le_bytes([lsb, msb])
+    })
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since the timer was started, in milliseconds.
+pub fn uptime_ms() -> u64 {
+    UPTIME_NS.load(Ordering::Relaxed) / 1_000_000
+}
+
+/// Returns the interrupt frequency requested from `init_pit`.
+pub fn tick_rate() -> u64 {
+    TICK_RATE.load(Ordering::Relaxed)
+}
+
+/// The length of a PIT tick in nanoseconds, from the calibrated PIT clock.
+pub(crate) fn pit_tick_ns() -> u64 {
+    let tick_rate = TICK_RATE.load(Ordering::Relaxed);
+    if tick_rate == 0 {
+        return 0;
+    }
+    (CLOCK_RATE / tick_rate) * 1_000_000_000 / ACTUAL_PIT_FREQ.load(Ordering::Relaxed)
+}
+
+/// Returns the number of timer interrupts that found the CPU idle.
+pub fn idle_ticks() -> u64 {
+    IDLE_TICKS.load(Ordering::Relaxed)
+}
+
+/// Waits for the next interrupt, counting the time as idle, see
+/// [`crate::cpu::idle::idle_once`].
+pub fn idle() {
+    IDLE.store(true, Ordering::Relaxed);
+    crate::cpu::idle::idle_once();
+    IDLE.store(false, Ordering::Relaxed);
+}
+
+/// Measures the PIT input clock against the TSC.
+///
+/// Returns `CLOCK_RATE` if the TSC frequency is unknown or interrupts are
+/// disabled, since there is nothing to measure against then.
+pub fn measure_pit_frequency() -> u64 {
+    let Some(tsc_freq) = tsc_frequency() else {
+        return CLOCK_RATE;
+    };
+    match time_one_shot() {
+        Some(tsc_delta) => (CALIBRATION_COUNT as u128 * tsc_freq as u128 / tsc_delta.max(1) as u128) as u64,
+        None => CLOCK_RATE,
+    }
+}
+
+/// Measures the TSC frequency in Hz against the PIT, for CPUs whose `cpuid`
+/// does not report it. Returns `None` if interrupts are disabled.
+pub fn measure_tsc_frequency() -> Option<u64> {
+    let tsc_delta = time_one_shot()?;
+    Some((tsc_delta as u128 * ACTUAL_PIT_FREQ.load(Ordering::Relaxed) as u128 / CALIBRATION_COUNT as u128) as u64)
+}
+
+/// Returns the TSC cycles taken by `CALIBRATION_COUNT` PIT clocks.
+///
+/// Counter 0 is run once in mode 0 (interrupt on terminal count) and the TSC is
+/// read when it starts and when its IRQ arrives, then the periodic timer of
+/// `init_pit` is restored. Returns `None` if interrupts are disabled.
+fn time_one_shot() -> Option<u64> {
+    if !RFlags::read().contains(RFlags::INTERRUPT_FLAG) {
+        return None;
+    }
+
+    // Start right after a tick, so no periodic IRQ is pending when switching modes.
+    wait_for_tick(ticks());
+    let (start_ticks, start_tsc) = without_interrupts(|| {
+        unsafe { Port::new(PIT_CTRL_WORD).write(PitMode::OneShot.control_word(0)); }
+        write_counter_0(CALIBRATION_COUNT);
+        (ticks(), rdtsc())
+    });
+    wait_for_tick(start_ticks);
+    let tsc_delta = rdtsc() - start_tsc;
+    init_pit(TICK_RATE.load(Ordering::Relaxed), PitMode::from_u8(TICK_MODE.load(Ordering::Relaxed)));
+    Some(tsc_delta)
+}
+
+/// Measures the PIT input clock and uses it for `delay_ms` from now on.
+pub fn calibrate_pit() -> u64 {
+    let frequency = measure_pit_frequency();
+    ACTUAL_PIT_FREQ.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// Spins until the tick count moves past `since`. A one-shot count only raises
+/// one IRQ, so `hlt` could miss it and sleep forever.
+pub(crate) fn wait_for_tick(since: u64) {
+    while ticks() == since {
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns how many timer interrupts to wait for at least `ms` milliseconds to pass.
+///
+/// The tick length comes from the calibrated PIT clock rather than the nominal
+/// one, or from the local APIC timer or HPET period while one is the source.
+/// Rounds up, plus one tick since the current one is already partly over.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    if tick_source() != TickSource::Pit {
+        return (ms * 1_000_000).div_ceil(SOURCE_TICK_NS.load(Ordering::Relaxed).max(1)) + 1;
+    }
+    let tick_rate = TICK_RATE.load(Ordering::Relaxed);
+    if tick_rate == 0 {
+        return 0;
+    }
+    let divisor = CLOCK_RATE / tick_rate;
+    let actual_freq = ACTUAL_PIT_FREQ.load(Ordering::Relaxed);
+    (ms * actual_freq).div_ceil(divisor * 1000) + 1
+}
+
+/// Waits at least `ms` milliseconds, on an HPET wakeup when there is one to
+/// be had, else counting timer interrupts and idling in between.
+///
+/// Interrupts must be enabled.
+pub fn delay_ms(ms: u64) {
+    if super::hpet::sleep_ns(ms * 1_000_000) {
+        return;
+    }
+    let wait = ms_to_ticks(ms);
+    let start = ticks();
+    while ticks() - start < wait {
+        idle();
+    }
+}
+
+/// Waits at least `us` microseconds, spinning on the TSC when its frequency is
+/// known and falling back to [`delay_ms`] otherwise.
+pub fn delay_us(us: u64) {
+    match tsc_frequency() {
+        Some(hz) => {
+            let end = rdtsc() + (hz * us).div_ceil(1_000_000);
+            while rdtsc() < end {
+                core::hint::spin_loop();
+            }
+        },
+        None => delay_ms(us.div_ceil(1000)),
+    }
+}
+
+#[test_case]
+fn measured_pit_frequency_is_close_to_nominal() {
+    let frequency = measure_pit_frequency();
+    assert!(frequency.abs_diff(CLOCK_RATE) <= CLOCK_RATE / 20, "measured {} Hz", frequency);
+}
+
+#[test_case]
+fn tsc_calibrated_against_pit_matches_tick_rate() {
+    const TICKS_MEASURED: u64 = 20;
+    let tsc_hz = measure_tsc_frequency().expect("interrupts are enabled");
+    if let Some(reported) = tsc_frequency() {
+        assert!(tsc_hz.abs_diff(reported) <= reported / 20, "measured {} Hz, cpuid reports {} Hz", tsc_hz, reported);
+    }
+
+    // The periodic ticks, timed with the TSC as calibrated by the one-shot count.
+    wait_for_tick(ticks());
+    let (start_ticks, start_tsc) = (ticks(), rdtsc());
+    while ticks() - start_ticks < TICKS_MEASURED {
+        core::hint::spin_loop();
+    }
+    let tsc_delta = rdtsc() - start_tsc;
+    let measured_rate = TICKS_MEASURED * tsc_hz / tsc_delta.max(1);
+    let expected_rate = 1_000_000_000 / pit_tick_ns();
+    assert!(
+        measured_rate.abs_diff(expected_rate) <= expected_rate / 20,
+        "ticks at {} Hz, expected {} Hz", measured_rate, expected_rate,
+    );
+}
+
+#[test_case]
+fn pit_control_words() {
+    // The words init_pit and the calibration used to write by hand.
+    assert_eq!(PitMode::SquareWave.control_word(0), 0b0011_0110);
+    assert_eq!(PitMode::OneShot.control_word(0), 0b0011_0000);
+    assert_eq!(PitMode::RateGenerator.control_word(0), 0b0011_0100);
+    assert_eq!(PitMode::SquareWave.control_word(2), 0b1011_0110);
+}
+
+#[test_case]
+fn init_pit_sequence_is_atomic() {
+    let rate = TICK_RATE.load(Ordering::Relaxed);
+    let mode = PitMode::from_u8(TICK_MODE.load(Ordering::Relaxed));
+    let divisor = (CLOCK_RATE / rate) as u16;
+    assert!(RFlags::read().contains(RFlags::INTERRUPT_FLAG));
+    for _ in 0..100 {
+        init_pit(rate, mode);
+        // A write landing between LSB and MSB would leave a count above the divisor.
+        assert!(read_counter_0() <= divisor, "counter 0 above {}", divisor);
+    }
+    assert!(RFlags::read().contains(RFlags::INTERRUPT_FLAG));
+    wait_for_tick(ticks());
+}
@@ -0,0 +1,98 @@
+//! Turning the machine off.
+
+use crate::{acpi, tables::{port::Port, DescriptorTablePointer}};
+
+/// PM1 control: the sleep type to enter, bits 12:10.
+const SLP_TYP_SHIFT: u16 = 10;
+/// PM1 control: enter the sleep state in SLP_TYP.
+const SLP_EN: u16 = 1 << 13;
+/// PM1 control: the system is in ACPI mode.
+const SCI_EN: u16 = 1 << 0;
+/// Polls of PM1a control waiting for ACPI mode after writing `ACPI_ENABLE`.
+const ACPI_ENABLE_POLLS: usize = 1_000_000;
+
+/// The 8042 status port, and the command pulsing the CPU reset line.
+const PS2_STATUS: u16 = 0x64;
+const PS2_STATUS_INPUT_FULL: u8 = 1 << 1;
+const PS2_PULSE_RESET: u8 = 0xFE;
+/// The reset control register of the chipset: a full reset once both bits are set.
+const RESET_CONTROL: u16 = 0xCF9;
+const RESET_SYSTEM: u8 = 1 << 1;
+const RESET_CPU: u8 = 1 << 2;
+/// Polls of the 8042 status waiting for it to take a command.
+const PS2_POLLS: usize = 100_000;
+
+/// Ports QEMU (0x604) and Bochs or older QEMU (0xB004) power off on with this value.
+const LEGACY_POWEROFF: [(u16, u16); 2] = [(0x604, 0x2000), (0xB004, 0x2000)];
+
+/// Enters S5 with the sleep types of the DSDT's `\_S5` object. Returns if the
+/// ACPI tables are missing or the write did not take.
+fn acpi_poweroff() {
+    let (Some(fadt), Some(dsdt)) = (acpi::fadt(), acpi::dsdt()) else {
+        return;
+    };
+    let Some((slp_typ_a, slp_typ_b)) = acpi::fadt::s5_sleep_types(dsdt.data()) else {
+        return;
+    };
+    if fadt.pm1a_control == 0 {
+        return;
+    }
+    unsafe {
+        let pm1a = Port::new(fadt.pm1a_control);
+        if pm1a.read(0u16) & SCI_EN == 0 && fadt.smi_command != 0 && fadt.acpi_enable != 0 {
+            Port::new(fadt.smi_command).write(fadt.acpi_enable);
+            for _ in 0..ACPI_ENABLE_POLLS {
+                if pm1a.read(0u16) & SCI_EN != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        pm1a.write((slp_typ_a as u16) << SLP_TYP_SHIFT | SLP_EN);
+        if fadt.pm1b_control != 0 {
+            Port::new(fadt.pm1b_control).write((slp_typ_b as u16) << SLP_TYP_SHIFT | SLP_EN);
+        }
+    }
+}
+
+/// Powers the machine off through ACPI, then the emulator-specific ports, and
+/// in test builds the `isa-debug-exit` device. Halts forever if all fail.
+pub fn shutdown() -> ! {
+    unsafe { core::arch::asm!("cli", options(nomem, nostack)); }
+    acpi_poweroff();
+    for (port, value) in LEGACY_POWEROFF {
+        unsafe { Port::new(port).write(value); }
+    }
+    #[cfg(test)]
+    crate::exit_qemu(crate::QemuExitCode::Success);
+    loop {
+        unsafe { core::arch::asm!("hlt", options(nomem, nostack, preserves_flags)); }
+    }
+}
+
+/// Resets the machine through the 8042 if the FADT lists one, then the reset
+/// control register of the chipset, and at last with a triple fault.
+pub fn reboot() -> ! {
+    unsafe { core::arch::asm!("cli", options(nomem, nostack)); }
+    if crate::pic::mouse::controller_present() {
+        let status = Port::new(PS2_STATUS);
+        for _ in 0..PS2_POLLS {
+            if unsafe { status.read(0u8) } & PS2_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        unsafe { status.write(PS2_PULSE_RESET); }
+    }
+    unsafe {
+        let reset = Port::new(RESET_CONTROL);
+        reset.write(RESET_SYSTEM);
+        reset.write(RESET_SYSTEM | RESET_CPU);
+    }
+    // With an empty IDT the breakpoint faults, and so does the double fault.
+    let empty = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { core::arch::asm!("lidt [{}]", "int3", in(reg) &empty, options(readonly, nostack)); }
+    loop {
+        unsafe { core::arch::asm!("hlt", options(nomem, nostack, preserves_flags)); }
+    }
+}
@@ -0,0 +1,193 @@
+//! Just enough of ELF64 to load static x86_64 executables: the file header and
+//! the `PT_LOAD` program headers. Everything else, sections and symbols
+//! included, is ignored.
+
+use core::fmt;
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+/// The size of the file header and of a program header.
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// File header fields.
+const E_IDENT_CLASS: usize = 4;
+const E_IDENT_DATA: usize = 5;
+const E_TYPE: usize = 16;
+const E_MACHINE: usize = 18;
+const E_ENTRY: usize = 24;
+const E_PHOFF: usize = 32;
+const E_PHENTSIZE: usize = 54;
+const E_PHNUM: usize = 56;
+
+/// Program header fields.
+const P_TYPE: usize = 0;
+const P_FLAGS: usize = 4;
+const P_OFFSET: usize = 8;
+const P_VADDR: usize = 16;
+const P_FILESZ: usize = 32;
+const P_MEMSZ: usize = 40;
+
+const PT_LOAD: u32 = 1;
+/// Segment permissions.
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Shorter than the headers say.
+    Truncated,
+    NotElf,
+    /// Not a little endian ELF64 x86_64 executable.
+    Unsupported,
+    /// A segment with more bytes in the file than in memory, or one wrapping around.
+    BadSegment,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "truncated ELF file"),
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported => write!(f, "not an x86_64 ELF64 executable"),
+            ElfError::BadSegment => write!(f, "bad ELF segment"),
+        }
+    }
+}
+
+fn bytes_at<const N: usize>(bytes: &[u8], at: usize) -> Option<[u8; N]> {
+    bytes.get(at..at + N)?.try_into().ok()
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    bytes_at(bytes, at).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    bytes_at(bytes, at).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    bytes_at(bytes, at).map(u64::from_le_bytes)
+}
+
+/// A `PT_LOAD` segment: `data` goes at `vaddr`, and the rest up to `mem_size`
+/// is zeroed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a> {
+    pub vaddr: u64,
+    pub mem_size: u64,
+    /// `PF_*` bits.
+    pub flags: u32,
+    pub data: &'a [u8],
+}
+
+/// An executable checked by [`Elf::parse`].
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    image: &'a [u8],
+    entry: u64,
+    program_headers: usize,
+    count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the file header and every `PT_LOAD` program header of `image`.
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        let header: [u8; HEADER_SIZE] = bytes_at(image, 0).ok_or(ElfError::Truncated)?;
+        if header[..4] != MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        let field16 = |at| u16_at(&header, at).unwrap();
+        if header[E_IDENT_CLASS] != CLASS_64 || header[E_IDENT_DATA] != DATA_LITTLE_ENDIAN
+            || field16(E_TYPE) != TYPE_EXECUTABLE || field16(E_MACHINE) != MACHINE_X86_64
+            || usize::from(field16(E_PHENTSIZE)) != PROGRAM_HEADER_SIZE
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let elf = Elf {
+            image,
+            entry: u64_at(&header, E_ENTRY).unwrap(),
+            program_headers: usize::try_from(u64_at(&header, E_PHOFF).unwrap()).map_err(|_| ElfError::Truncated)?,
+            count: field16(E_PHNUM).into(),
+        };
+        let end = elf.count.checked_mul(PROGRAM_HEADER_SIZE).and_then(|size| size.checked_add(elf.program_headers));
+        if end.is_none_or(|end| end > image.len()) {
+            return Err(ElfError::Truncated);
+        }
+        for segment in elf.segments() {
+            segment?;
+        }
+        Ok(elf)
+    }
+
+    /// Returns the address execution starts at.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Returns the `PT_LOAD` segments, in file order.
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment<'a>, ElfError>> + '_ {
+        (0..self.count)
+            .map(|i| &self.image[self.program_headers + i * PROGRAM_HEADER_SIZE..][..PROGRAM_HEADER_SIZE])
+            .filter(|header| u32_at(header, P_TYPE) == Some(PT_LOAD))
+            .map(|header| self.segment(header))
+    }
+
+    fn segment(&self, header: &[u8]) -> Result<Segment<'a>, ElfError> {
+        let field = |at| u64_at(header, at).unwrap();
+        let (offset, vaddr, file_size, mem_size) = (field(P_OFFSET), field(P_VADDR), field(P_FILESZ), field(P_MEMSZ));
+        if file_size > mem_size || vaddr.checked_add(mem_size).is_none() {
+            return Err(ElfError::BadSegment);
+        }
+        let data = usize::try_from(offset).ok()
+            .zip(usize::try_from(file_size).ok())
+            .and_then(|(offset, size)| self.image.get(offset..offset.checked_add(size)?))
+            .ok_or(ElfError::Truncated)?;
+        Ok(Segment { vaddr, mem_size, flags: u32_at(header, P_FLAGS).unwrap(), data })
+    }
+}
+
+#[test_case]
+fn elf_hello_has_code_and_data_segments() {
+    let elf = Elf::parse(super::HELLO).unwrap();
+    assert_eq!(elf.entry(), 0x40_1000);
+    let mut segments = elf.segments().map(Result::unwrap);
+    let code = segments.find(|segment| segment.flags & PF_X != 0).unwrap();
+    assert_eq!(code.flags & PF_W, 0);
+    assert!((code.vaddr..code.vaddr + code.mem_size).contains(&elf.entry()));
+    assert!(elf.segments().map(Result::unwrap).any(|segment| segment.flags == PF_R | PF_W));
+}
+
+#[test_case]
+fn elf_parse_rejects_broken_headers() {
+    // The headers, and no segment data.
+    let image: [u8; 4096] = super::HELLO[..4096].try_into().unwrap();
+
+    assert_eq!(Elf::parse(&image[..63]).err(), Some(ElfError::Truncated));
+    let mut broken = image;
+    broken[1] = b'F';
+    assert_eq!(Elf::parse(&broken).err(), Some(ElfError::NotElf));
+    let mut broken = image;
+    broken[E_IDENT_CLASS] = 1;
+    assert_eq!(Elf::parse(&broken).err(), Some(ElfError::Unsupported));
+    let mut broken = image;
+    broken[E_MACHINE] = 3;
+    assert_eq!(Elf::parse(&broken).err(), Some(ElfError::Unsupported));
+    // Program headers past the end of the file.
+    let mut broken = image;
+    broken[E_PHOFF..E_PHOFF + 8].copy_from_slice(&4090u64.to_le_bytes());
+    assert_eq!(Elf::parse(&broken).err(), Some(ElfError::Truncated));
+
+    // A first segment with more in the file than in memory.
+    let phoff = u64_at(&image, E_PHOFF).unwrap() as usize;
+    assert_eq!(u32_at(&image, phoff + P_TYPE), Some(PT_LOAD));
+    let mut broken = image;
+    broken[phoff + P_MEMSZ..phoff + P_MEMSZ + 8].copy_from_slice(&0u64.to_le_bytes());
+    broken[phoff + P_FILESZ..phoff + P_FILESZ + 8].copy_from_slice(&1u64.to_le_bytes());
+    assert_eq!(Elf::parse(&broken).err(), Some(ElfError::BadSegment));
+}
@@ -0,0 +1,713 @@
+//! User processes: an address space, the threads running in it and the
+//! descriptors they share.
+//!
+//! A process lives in [`PROCESSES`] from [`spawn_from_elf`] or [`fork`] until
+//! [`wait`] collects its exit code. When its last thread is gone, [`reap`] gives its
+//! address space back to the frame allocator; the entry stays until then, so
+//! the exit code is not lost.
+//!
+//! Each process knows its parent, the process that forked it or the kernel
+//! for those it spawned. The children of a process that exits go to the
+//! kernel, so no parent id ever names a process that is gone.
+//!
+//! A process has its own descriptor table, see [`with_fd_table`]: a fork gets
+//! a copy sharing the open files, and [`exit`] closes what is left.
+
+pub mod elf;
+pub mod scheduler;
+pub mod vma;
+
+use core::{fmt, mem, sync::atomic::{AtomicU32, Ordering}};
+use crate::{
+    fs::NameBuf,
+    memory::{
+        address_space::{AddressSpace, UserMapError},
+        cow,
+        frame_allocator::{FrameAllocator, FrameDeallocator, MemoryMapFrameAllocator},
+        no_execute,
+        paging::PageTableFlags,
+        phys_mem_offset, FRAME_ALLOCATOR, PAGE_SIZE_4K, USER_SPACE_END,
+    },
+    println, serial_println,
+    sync::Mutex,
+    syscall::{fd::FdTable, int80::SyscallRegisters, EFAULT, EINVAL, ENOMEM},
+};
+use elf::{Elf, ElfError, PF_W, PF_X};
+use scheduler::{CpuTime, ThreadState, Tid};
+use vma::{Vma, VmaSet, MMAP_MIN};
+
+/// A process id. Never reused.
+pub type Pid = u32;
+
+/// The kernel, which is not in the process table.
+pub const KERNEL_PID: Pid = 0;
+pub const MAX_PROCESSES: usize = 16;
+/// Threads per process.
+pub const MAX_PROCESS_THREADS: usize = 4;
+/// The exit code of a process killed by a fault, see [`segfault`], the
+/// 128 + `SIGSEGV` of a shell.
+pub const EXIT_SEGFAULT: i32 = 139;
+/// The top of the stack of the first thread, and its size.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE_4K;
+const USER_STACK_PAGES: u64 = 4;
+/// Where the areas of [`mmap`] end at the most, the bottom of the stack.
+const MMAP_END: u64 = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE_4K;
+/// The longest process name, in bytes; longer ones are cut.
+pub const PROCESS_NAME_LEN: usize = 16;
+
+pub struct Process {
+    pub pid: Pid,
+    /// The process that forked it, [`KERNEL_PID`] if spawned by the kernel or
+    /// once the parent exited.
+    parent: Pid,
+    /// The name it was spawned with, kept across forks.
+    name: NameBuf<PROCESS_NAME_LEN>,
+    /// `None` once reaped.
+    address_space: Option<AddressSpace>,
+    /// The threads left to reap.
+    threads: [Option<Tid>; MAX_PROCESS_THREADS],
+    fd_table: FdTable,
+    /// The areas of [`mmap`], shared by the threads like the address space.
+    vmas: VmaSet,
+    /// Set when the process exits, with its first thread to exit.
+    exit_code: Option<i32>,
+    /// The time of the threads reaped so far.
+    cpu_time: CpuTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    /// One of its threads is on the CPU.
+    Running,
+    Ready,
+    /// Every thread of it is off the CPU until it is woken, see
+    /// [`scheduler::sleep_until`].
+    Blocked,
+    /// Exited, waiting for [`wait`].
+    Zombie,
+}
+
+impl fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessState::Running => write!(f, "running"),
+            ProcessState::Ready => write!(f, "ready"),
+            ProcessState::Blocked => write!(f, "blocked"),
+            ProcessState::Zombie => write!(f, "zombie"),
+        }
+    }
+}
+
+/// What [`snapshot`] tells of a process.
+#[derive(Clone, Copy)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub state: ProcessState,
+    /// Pages mapped in its user half, shared ones included.
+    pub resident_pages: usize,
+    /// Of all its threads, the reaped ones included.
+    pub cpu_time: CpuTime,
+    name: NameBuf<PROCESS_NAME_LEN>,
+}
+
+impl ProcessInfo {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+}
+
+static PROCESSES: Mutex<[Option<Process>; MAX_PROCESSES]> = Mutex::new("PROCESSES", [const { None }; MAX_PROCESSES]);
+/// The descriptors of the kernel, which is not in the process table.
+static KERNEL_FD_TABLE: Mutex<FdTable> = Mutex::new("KERNEL_FD_TABLE", FdTable::new());
+static NEXT_PID: AtomicU32 = AtomicU32::new(KERNEL_PID + 1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    Elf(ElfError),
+    /// Two segments on one page, or a segment outside of the user half.
+    Map(UserMapError),
+    OutOfMemory,
+    TooManyProcesses,
+    TooManyThreads,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::Elf(err) => write!(f, "{}", err),
+            SpawnError::Map(err) => write!(f, "cannot map segment: {}", err),
+            SpawnError::OutOfMemory => write!(f, "out of memory"),
+            SpawnError::TooManyProcesses => write!(f, "too many processes"),
+            SpawnError::TooManyThreads => write!(f, "too many threads"),
+        }
+    }
+}
+
+impl From<ElfError> for SpawnError {
+    fn from(err: ElfError) -> Self {
+        SpawnError::Elf(err)
+    }
+}
+
+impl From<UserMapError> for SpawnError {
+    fn from(err: UserMapError) -> Self {
+        match err {
+            UserMapError::OutOfMemory => SpawnError::OutOfMemory,
+            err => SpawnError::Map(err),
+        }
+    }
+}
+
+/// Registers the running code as the kernel thread, see [`scheduler::init`].
+pub fn init() {
+    scheduler::init();
+}
+
+/// Starts a process called `name` running the static executable `image`,
+/// with a stack of a few pages below [`USER_STACK_TOP`], and returns its id.
+/// Its parent is the kernel.
+///
+/// Each segment is mapped on pages of its own, writable only with `PF_W`
+/// and executable only with `PF_X`.
+pub fn spawn_from_elf(name: &str, image: &[u8]) -> Result<Pid, SpawnError> {
+    let elf = Elf::parse(image)?;
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or(SpawnError::OutOfMemory)?;
+    let space = unsafe { AddressSpace::new(allocator, phys_mem_offset()) }.ok_or(SpawnError::OutOfMemory)?;
+    let loaded = load(&space, &elf, allocator).and_then(|()| {
+        let stack = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE_4K;
+        (stack..USER_STACK_TOP).step_by(PAGE_SIZE_4K as usize)
+            .try_for_each(|page| map_zeroed(&space, page, PageTableFlags::WRITABLE | no_execute(), allocator))
+    });
+    let registers = scheduler::initial_registers(elf.entry(), USER_STACK_TOP);
+    let mut short_name = NameBuf::new();
+    for c in name.chars() {
+        if !short_name.push(c) {
+            break;
+        }
+    }
+    let pid = loaded.and_then(|()| register(short_name, KERNEL_PID, space, VmaSet::new(), &registers, FdTable::new()));
+    if pid.is_err() {
+        unsafe { space.destroy(allocator, |allocator, frame| cow::release(allocator, frame)); }
+    }
+    pid
+}
+
+/// Duplicates the calling process, whose thread entered the kernel with
+/// `registers`, and returns the id of the child.
+///
+/// The child gets the name, the descriptors of the parent and its user pages: shared
+/// read-only ones stay shared, writable ones become copy-on-write in both,
+/// see [`cow`]. The pages of the areas of [`mmap`] not touched yet stay so
+/// in both. Only the calling thread is duplicated, like POSIX `fork`: the
+/// child starts with one thread, returning 0 from the system call.
+///
+/// Nothing of the parent changes when it fails.
+pub fn fork(registers: &SyscallRegisters) -> Result<Pid, SpawnError> {
+    let parent = scheduler::current_pid();
+    let parent_space = address_space(parent).expect("forking without an address space");
+    let (name, vmas, fd_table) = {
+        let processes = PROCESSES.lock();
+        let process = processes.iter().flatten().find(|process| process.pid == parent).unwrap();
+        (process.name, process.vmas, process.fd_table.fork())
+    };
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or(SpawnError::OutOfMemory)?;
+    let space = unsafe { AddressSpace::new(allocator, phys_mem_offset()) }.ok_or(SpawnError::OutOfMemory)?;
+    let mut child_registers = *registers;
+    child_registers.regs.rax = 0;
+    let pid = unsafe { cow::clone_user_half(&parent_space, &space, allocator) }
+        .map_err(SpawnError::from)
+        .and_then(|()| register(name, parent, space, vmas, &child_registers, fd_table));
+    match pid {
+        Ok(_) => unsafe { cow::write_protect_user_half(&parent_space) },
+        Err(_) => unsafe { space.destroy(allocator, |allocator, frame| cow::release(allocator, frame)) },
+    }
+    pid
+}
+
+/// Adds a process in `space` to the table, with one thread starting with
+/// `registers`.
+fn register(
+    name: NameBuf<PROCESS_NAME_LEN>,
+    parent: Pid,
+    space: AddressSpace,
+    vmas: VmaSet,
+    registers: &SyscallRegisters,
+    fd_table: FdTable,
+) -> Result<Pid, SpawnError> {
+    let mut processes = PROCESSES.lock();
+    let slot = processes.iter_mut().find(|slot| slot.is_none()).ok_or(SpawnError::TooManyProcesses)?;
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let tid = scheduler::spawn(pid, space, registers).ok_or(SpawnError::TooManyThreads)?;
+    let mut threads = [None; MAX_PROCESS_THREADS];
+    threads[0] = Some(tid);
+    *slot = Some(Process {
+        pid, parent, name, address_space: Some(space), threads, fd_table, vmas, exit_code: None, cpu_time: CpuTime::default(),
+    });
+    Ok(pid)
+}
+
+/// Maps the segments of `elf` into `space`.
+fn load(space: &AddressSpace, elf: &Elf, allocator: &mut MemoryMapFrameAllocator) -> Result<(), SpawnError> {
+    for segment in elf.segments() {
+        let segment = segment?;
+        let mut flags = PageTableFlags::empty();
+        if segment.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if segment.flags & PF_X == 0 {
+            flags |= no_execute();
+        }
+        let start = segment.vaddr & !(PAGE_SIZE_4K - 1);
+        for page in (start..segment.vaddr + segment.mem_size).step_by(PAGE_SIZE_4K as usize) {
+            map_zeroed(space, page, flags, allocator)?;
+            // The part of the segment data on this page.
+            let from = page.max(segment.vaddr) - segment.vaddr;
+            let to = (page + PAGE_SIZE_4K - segment.vaddr).min(segment.data.len() as u64);
+            if from < to {
+                let (frame, _) = space.translate_user(page).unwrap();
+                let dst = phys_mem_offset() + frame.start_address() + (segment.vaddr + from - page);
+                let data = &segment.data[from as usize..to as usize];
+                unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst as *mut u8, data.len()); }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Maps a zeroed frame at the user page `page` of `space`.
+fn map_zeroed(space: &AddressSpace, page: u64, flags: PageTableFlags, allocator: &mut MemoryMapFrameAllocator) -> Result<(), SpawnError> {
+    let frame = allocator.allocate_frame().ok_or(SpawnError::OutOfMemory)?;
+    unsafe { ((phys_mem_offset() + frame.start_address()) as *mut u8).write_bytes(0, PAGE_SIZE_4K as usize); }
+    unsafe { space.map_user(page, frame, flags, allocator) }.inspect_err(|_| unsafe { allocator.deallocate_frame(frame) })?;
+    Ok(())
+}
+
+/// Ends the calling process with `code`, all of its threads with it, those on
+/// the sleep queue included. Its descriptors are closed and its children go to
+/// the kernel.
+pub fn exit(code: i32) -> ! {
+    let pid = scheduler::current_pid();
+    let fd_table = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.iter_mut().flatten().find(|process| process.pid == pid).expect("exiting an unknown process");
+        process.exit_code.get_or_insert(code);
+        for &tid in process.threads.iter().flatten() {
+            scheduler::kill(tid);
+        }
+        let fd_table = mem::take(&mut process.fd_table);
+        for child in processes.iter_mut().flatten().filter(|process| process.parent == pid) {
+            child.parent = KERNEL_PID;
+        }
+        fd_table
+    };
+    // This thread never comes back to drop it.
+    drop(fd_table);
+    scheduler::exit_current()
+}
+
+/// Ends the calling process after its code faulted on the instruction at
+/// `rip`, accessing `addr` if the fault tells which address.
+///
+/// What the exception handlers do for faults from ring 3, page faults on
+/// memory the process may not access, protection faults, invalid opcodes,
+/// divide errors and the other faults of its code alike: only the process
+/// dies, the next thread runs. A double fault stays fatal.
+pub fn segfault(addr: Option<u64>, rip: u64) -> ! {
+    let pid = scheduler::current_pid();
+    match addr {
+        Some(addr) => {
+            println!("segfault in pid {} at addr {:#x} (rip {:#x})", pid, addr, rip);
+            serial_println!("segfault in pid {} at addr {:#x} (rip {:#x})", pid, addr, rip);
+        }
+        None => {
+            println!("segfault in pid {} at addr unknown (rip {:#x})", pid, rip);
+            serial_println!("segfault in pid {} at addr unknown (rip {:#x})", pid, rip);
+        }
+    }
+    exit(EXIT_SEGFAULT)
+}
+
+/// Frees the threads that exited, and the address spaces of the processes
+/// left without threads.
+pub fn reap() {
+    scheduler::reap();
+    let mut spaces = [None; MAX_PROCESSES];
+    for (process, space) in PROCESSES.lock().iter_mut().flatten().zip(&mut spaces) {
+        let pid = process.pid;
+        for thread in process.threads.iter_mut().filter(|tid| tid.is_some_and(|tid| !scheduler::is_alive(tid, pid))) {
+            process.cpu_time += scheduler::cpu_time(thread.take().unwrap());
+        }
+        if process.threads.iter().all(Option::is_none) {
+            *space = process.address_space.take();
+        }
+    }
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        for space in spaces.into_iter().flatten() {
+            unsafe { space.destroy(allocator, |allocator, frame| cow::release(allocator, frame)); }
+        }
+    }
+}
+
+/// Waits for the process `pid` to exit and be reaped, removes it and
+/// returns its exit code. `None` if there is no such process.
+pub fn wait(pid: Pid) -> Option<i32> {
+    loop {
+        reap();
+        {
+            let mut processes = PROCESSES.lock();
+            let slot = processes.iter_mut().find(|slot| slot.as_ref().is_some_and(|process| process.pid == pid))?;
+            if slot.as_ref().is_some_and(|process| process.address_space.is_none()) {
+                return slot.take().unwrap().exit_code;
+            }
+        }
+        scheduler::idle();
+    }
+}
+
+/// Returns the parent of the process `pid`, `None` if there is no such
+/// process. The kernel is its own parent.
+pub fn parent(pid: Pid) -> Option<Pid> {
+    if pid == KERNEL_PID {
+        return Some(KERNEL_PID);
+    }
+    PROCESSES.lock().iter().flatten().find(|process| process.pid == pid).map(|process| process.parent)
+}
+
+/// Returns what there is to know of every process in the table, by pid.
+pub fn snapshot() -> [Option<ProcessInfo>; MAX_PROCESSES] {
+    let mut infos = [None; MAX_PROCESSES];
+    for (process, info) in PROCESSES.lock().iter().flatten().zip(&mut infos) {
+        let mut cpu_time = process.cpu_time;
+        let mut state = ProcessState::Blocked;
+        for &tid in process.threads.iter().flatten() {
+            cpu_time += scheduler::cpu_time(tid);
+            state = match (state, scheduler::state(tid)) {
+                (ProcessState::Running, _) | (_, Some(ThreadState::Running)) => ProcessState::Running,
+                (ProcessState::Blocked, Some(ThreadState::Blocked)) => ProcessState::Blocked,
+                _ => ProcessState::Ready,
+            };
+        }
+        if process.exit_code.is_some() {
+            state = ProcessState::Zombie;
+        }
+        let mut resident_pages = 0;
+        if let Some(space) = &process.address_space {
+            // The kernel is not preempted, no thread of the process runs meanwhile.
+            unsafe { space.for_each_user_page(|_, _| resident_pages += 1); }
+        }
+        *info = Some(ProcessInfo { pid: process.pid, parent: process.parent, state, resident_pages, cpu_time, name: process.name });
+    }
+    infos.sort_unstable_by_key(|info| info.as_ref().map_or(Pid::MAX, |info| info.pid));
+    infos
+}
+
+/// Returns the address space of the process `pid`, while it has one.
+pub fn address_space(pid: Pid) -> Option<AddressSpace> {
+    PROCESSES.lock().iter().flatten().find(|process| process.pid == pid).and_then(|process| process.address_space)
+}
+
+/// Runs `f` on the descriptor table of the calling process, or on the
+/// kernel's own. The process table stays locked until `f` returns, so `f`
+/// must not touch user memory.
+pub fn with_fd_table<T>(f: impl FnOnce(&mut FdTable) -> T) -> T {
+    let pid = scheduler::current_pid();
+    if pid == KERNEL_PID {
+        return f(&mut KERNEL_FD_TABLE.lock());
+    }
+    let mut processes = PROCESSES.lock();
+    let process = processes.iter_mut().flatten().find(|process| process.pid == pid).expect("running an unknown process");
+    f(&mut process.fd_table)
+}
+
+/// Handles a fault of the calling process on the user page at `addr`, on a
+/// write if `write`, returns whether it may retry the access.
+///
+/// The first touch of a page of one of its areas maps a zeroed frame there,
+/// if the area allows the access; the first write to a copy-on-write page
+/// copies it. Any other fault is the process's own.
+pub fn resolve_fault(addr: u64, write: bool) -> bool {
+    let pid = scheduler::current_pid();
+    let (space, vma) = {
+        let processes = PROCESSES.lock();
+        let Some(process) = processes.iter().flatten().find(|process| process.pid == pid) else {
+            return false;
+        };
+        let Some(space) = process.address_space else {
+            return false;
+        };
+        (space, process.vmas.find(addr))
+    };
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let Some(allocator) = allocator.as_mut() else {
+        return false;
+    };
+    let page = addr & !(PAGE_SIZE_4K - 1);
+    match space.translate_user(page) {
+        Some((_, flags)) => write && flags.contains(cow::COPY_ON_WRITE) && unsafe { cow::resolve_write_fault(&space, addr, allocator) },
+        None => vma
+            .filter(|vma| vma.flags.contains(PageTableFlags::PRESENT))
+            .filter(|vma| !write || vma.flags.contains(PageTableFlags::WRITABLE))
+            .is_some_and(|vma| map_zeroed(&space, page, vma.flags, allocator).is_ok()),
+    }
+}
+
+/// Checks that the calling process may read, or also write if `write`, the
+/// `len` bytes at `addr`, failing with `EFAULT` otherwise. Pages of its
+/// areas get mapped, and copy-on-write pages to write to get copied, first.
+///
+/// The kernel passes its own buffers to the system calls, so it may access
+/// anything.
+pub fn check_user_access(addr: u64, len: u64, write: bool) -> Result<(), i64> {
+    let pid = scheduler::current_pid();
+    if pid == KERNEL_PID || len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).filter(|&end| end <= USER_SPACE_END).ok_or(EFAULT)?;
+    let space = address_space(pid).ok_or(EFAULT)?;
+    let mut needed = PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        needed |= PageTableFlags::WRITABLE;
+    }
+    for page in (addr & !(PAGE_SIZE_4K - 1)..end).step_by(PAGE_SIZE_4K as usize) {
+        let faults = space.translate_user(page).is_none_or(|(_, flags)| write && flags.contains(cow::COPY_ON_WRITE));
+        if faults && !resolve_fault(page, write) {
+            return Err(EFAULT);
+        }
+        if !space.translate_user(page).is_some_and(|(_, flags)| flags.contains(needed)) {
+            return Err(EFAULT);
+        }
+    }
+    Ok(())
+}
+
+/// Reserves an area of `len` bytes, rounded up to pages, in the user half of
+/// the calling process, and returns where it starts. Its pages get `flags`
+/// on the first touch, see [`resolve_fault`].
+///
+/// The area starts at `hint` if it is page aligned and nothing is mapped or
+/// reserved there, and wherever there is room otherwise. Fails with `ENOMEM`
+/// without room or a slot for the area.
+pub fn mmap(hint: u64, len: u64, flags: PageTableFlags) -> Result<u64, i64> {
+    let len = len.checked_next_multiple_of(PAGE_SIZE_4K).ok_or(ENOMEM)?;
+    let pid = scheduler::current_pid();
+    let mut processes = PROCESSES.lock();
+    let process = processes.iter_mut().flatten().find(|process| process.pid == pid).ok_or(EINVAL)?;
+    let space = process.address_space.ok_or(EINVAL)?;
+    let hint_is_free = |start: u64| {
+        let end = start.checked_add(len).filter(|&end| end <= MMAP_END);
+        start >= MMAP_MIN && start.is_multiple_of(PAGE_SIZE_4K) && end.is_some_and(|end| {
+            process.vmas.is_free(start, end)
+                && (start..end).step_by(PAGE_SIZE_4K as usize).all(|page| space.translate_user(page).is_none())
+        })
+    };
+    let start = match hint_is_free(hint) {
+        true => hint,
+        false => process.vmas.free_range(len, MMAP_END).ok_or(ENOMEM)?,
+    };
+    match process.vmas.insert(Vma { start, end: start + len, flags }) {
+        true => Ok(start),
+        false => Err(ENOMEM),
+    }
+}
+
+/// Unmaps the pages of `addr..addr + len`, rounded up to pages, that the
+/// areas of the calling process cover, and gives their frames back. The
+/// areas shrink, split or go. Pages outside of the areas stay.
+///
+/// Fails with `EINVAL` if `addr` is not page aligned or the range leaves
+/// the user half, and with `ENOMEM` when an area splits and there is no slot
+/// for the second half.
+pub fn munmap(addr: u64, len: u64) -> Result<(), i64> {
+    let end = addr.checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE_4K))
+        .filter(|&end| end <= USER_SPACE_END && addr.is_multiple_of(PAGE_SIZE_4K))
+        .ok_or(EINVAL)?;
+    let pid = scheduler::current_pid();
+    let (space, vmas) = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.iter_mut().flatten().find(|process| process.pid == pid).ok_or(EINVAL)?;
+        let vmas = process.vmas;
+        if !process.vmas.remove(addr, end) {
+            return Err(ENOMEM);
+        }
+        (process.address_space.ok_or(EINVAL)?, vmas)
+    };
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or(ENOMEM)?;
+    for page in (addr..end).step_by(PAGE_SIZE_4K as usize).filter(|&page| vmas.find(page).is_some()) {
+        if let Some(frame) = unsafe { space.unmap_user(page) } {
+            unsafe { cow::release(allocator, frame); }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+static HELLO: &[u8] = include_bytes!("../../fixtures/hello.elf");
+#[cfg(test)]
+static PEEK: &[u8] = include_bytes!("../../fixtures/peek.elf");
+#[cfg(test)]
+static FORK: &[u8] = include_bytes!("../../fixtures/fork.elf");
+#[cfg(test)]
+static PIDS: &[u8] = include_bytes!("../../fixtures/pids.elf");
+#[cfg(test)]
+static ILLEGAL: &[u8] = include_bytes!("../../fixtures/illegal.elf");
+#[cfg(test)]
+static PRIVILEGED: &[u8] = include_bytes!("../../fixtures/privileged.elf");
+#[cfg(test)]
+static MMAP: &[u8] = include_bytes!("../../fixtures/mmap.elf");
+#[cfg(test)]
+static COPY: &[u8] = include_bytes!("../../fixtures/copy.elf");
+#[cfg(test)]
+static SLEEP: &[u8] = include_bytes!("../../fixtures/sleep.elf");
+#[cfg(test)]
+static READ: &[u8] = include_bytes!("../../fixtures/read.elf");
+#[cfg(test)]
+static DIV0: &[u8] = include_bytes!("../../fixtures/div0.elf");
+
+#[cfg(test)]
+fn info(pid: Pid) -> Option<ProcessInfo> {
+    snapshot().into_iter().flatten().find(|info| info.pid == pid)
+}
+
+#[test_case]
+fn processes_run_isolated_from_each_other() {
+    let used = FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames();
+    let switches = scheduler::address_space_switches();
+    let first = spawn_from_elf("hello", HELLO).unwrap();
+    let second = spawn_from_elf("hello", HELLO).unwrap();
+    let peek = spawn_from_elf("peek", PEEK).unwrap();
+
+    // The same addresses, each process with its own frames.
+    let (first_space, second_space) = (address_space(first).unwrap(), address_space(second).unwrap());
+    assert_ne!(first_space.level_4_frame(), second_space.level_4_frame());
+    let code = first_space.translate_user(0x40_1000).unwrap();
+    assert_ne!(code.0, second_space.translate_user(0x40_1000).unwrap().0);
+    assert!(!code.1.contains(PageTableFlags::WRITABLE));
+    assert!(address_space(peek).unwrap().translate_user(0x40_0000).is_none());
+
+    assert_eq!(wait(first), Some(0));
+    assert_eq!(wait(second), Some(0));
+    assert_eq!(wait(peek), Some(EXIT_SEGFAULT));
+    assert_eq!(wait(peek), None);
+    // Each hello gave the CPU away between its rounds, to another process.
+    assert!(scheduler::address_space_switches() - switches >= 6);
+    assert!(AddressSpace::current().is_active());
+    assert_eq!(FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames(), used);
+}
+
+#[test_case]
+fn forked_processes_write_their_own_copies() {
+    let used = FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames();
+    let parent = spawn_from_elf("fork", FORK).unwrap();
+    // Each exits with the pid of its child once its copy and its parent checked out.
+    let child = wait(parent).unwrap();
+    assert!(child > 0, "parent exited with {}", child);
+    let child = child as Pid;
+    // Left to the kernel by its parent, with the name it was spawned with.
+    let info = info(child).unwrap();
+    assert_eq!((info.parent, info.name()), (KERNEL_PID, "fork"));
+    let grandchild = wait(child).unwrap();
+    assert!(grandchild > 0, "child exited with {}", grandchild);
+    assert_eq!(self::info(grandchild as Pid).unwrap().parent, KERNEL_PID);
+    assert_eq!(wait(grandchild as Pid), Some(42));
+    assert_eq!(FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames(), used);
+}
+
+#[test_case]
+fn faulting_processes_die_alone() {
+    let used = FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames();
+    let hello = spawn_from_elf("hello", HELLO).unwrap();
+    // A page fault, an invalid opcode, a protection fault and a divide error.
+    let crashes = [
+        spawn_from_elf("peek", PEEK),
+        spawn_from_elf("illegal", ILLEGAL),
+        spawn_from_elf("privileged", PRIVILEGED),
+        spawn_from_elf("div0", DIV0),
+    ];
+    for pid in crashes {
+        assert_eq!(wait(pid.unwrap()), Some(EXIT_SEGFAULT));
+    }
+    // The other process and the shell, on the boot thread, go on.
+    assert_eq!(wait(hello), Some(0));
+    assert_eq!(crate::shell::execute("ps", &mut crate::shell::Console), Ok(()));
+    assert_eq!(FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames(), used);
+}
+
+#[test_case]
+fn mapped_memory_is_zeroed_until_unmapped() {
+    let used = FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames();
+    let hello = spawn_from_elf("hello", HELLO).unwrap();
+    // Every check passed, and the touch after unmapping killed it.
+    let pid = spawn_from_elf("mmap", MMAP).unwrap();
+    assert_eq!(wait(pid), Some(EXIT_SEGFAULT));
+    assert_eq!(wait(hello), Some(0));
+    assert_eq!(FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames(), used);
+}
+
+#[test_case]
+fn sleeping_processes_let_others_run() {
+    use crate::pic::timer;
+    let start = timer::ticks();
+    let sleeper = spawn_from_elf("sleep", SLEEP).unwrap();
+    let hello = spawn_from_elf("hello", HELLO).unwrap();
+    // hello goes through its rounds while the other one is on the sleep queue.
+    assert_eq!(wait(hello), Some(0));
+    assert_eq!(info(sleeper).map(|info| info.state), Some(ProcessState::Blocked));
+    assert_eq!(crate::shell::execute("ps", &mut crate::shell::Console), Ok(()));
+    assert_eq!(wait(sleeper), Some(0));
+    assert!(timer::ticks() - start >= timer::ms_to_ticks(50), "woke after {} ticks", timer::ticks() - start);
+}
+
+#[test_case]
+fn blocked_readers_let_others_run() {
+    use crate::{pic::keyboard::handle_scancode, tables::without_interrupts, tty::TTY};
+    // Set 1 make and break codes of R and Enter.
+    const TYPED: [u8; 4] = [0x13, 0x93, 0x1C, 0x9C];
+    without_interrupts(|| TTY.lock().flush());
+    let reader = spawn_from_elf("read", READ).unwrap();
+    let hello = spawn_from_elf("hello", HELLO).unwrap();
+    // hello goes through its rounds while the other one waits for a line.
+    assert_eq!(wait(hello), Some(0));
+    assert_eq!(info(reader).map(|info| info.state), Some(ProcessState::Blocked));
+    without_interrupts(|| TYPED.iter().for_each(|&scancode| handle_scancode(scancode)));
+    assert_eq!(wait(reader), Some(2));
+}
+
+#[test_case]
+fn processes_copy_files_to_tmp() {
+    use crate::{fs::vfs, syscall::fd};
+    fd::mount_fixture();
+    let (used, open) = (FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames(), fd::open_files());
+    let pid = spawn_from_elf("copy", COPY).unwrap();
+    assert_eq!(wait(pid), Some(0));
+    // The descriptor it left open went with it.
+    assert_eq!(fd::open_files(), open);
+    let mut buffer = [0u8; 64];
+    let len = vfs::open("/tmp/copy").unwrap().read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"HELLO FROM THE INITRD!\n");
+    assert_eq!(FRAME_ALLOCATOR.lock().as_ref().unwrap().used_frames(), used);
+}
+
+#[test_case]
+fn pids_stay_the_same_across_system_calls() {
+    let pid = spawn_from_elf("pids", PIDS).unwrap();
+    let info = info(pid).unwrap();
+    assert_eq!((info.parent, info.state, info.name()), (KERNEL_PID, ProcessState::Ready, "pids"));
+    assert!(info.resident_pages > 0);
+    assert_eq!(wait(pid), Some(pid as i32));
+}
+
+#[test_case]
+fn spawn_rejects_bad_images() {
+    assert_eq!(spawn_from_elf("hello", &HELLO[..40]), Err(SpawnError::Elf(ElfError::Truncated)));
+    assert_eq!(spawn_from_elf("zeros", &[0; 64]), Err(SpawnError::Elf(ElfError::NotElf)));
+}
+
+#[test_case]
+fn kernel_passes_user_access_checks() {
+    assert_eq!(check_user_access(0xFFFF_8000_0000_0000, 8, true), Ok(()));
+}
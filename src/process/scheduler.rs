@@ -0,0 +1,478 @@
+//! Threads and the round-robin scheduler switching between them.
+//!
+//! The boot thread, running `kernel_main` and the shell, is thread 0 and the
+//! only kernel thread. Every other thread runs a user process and has its own
+//! kernel stack, which interrupts and system calls from ring 3 land on.
+//!
+//! Kernel code is never preempted: a thread only leaves the CPU in
+//! [`yield_now`], [`idle`], [`sleep_until`], [`WaitQueue::wait_until`] or
+//! [`exit_current`], never while holding a lock. A thread interrupted in ring
+//! 3 has no kernel state to protect, so the timer ticks switch away from it,
+//! see [`preempt`].
+//!
+//! A thread in [`sleep_until`] is blocked on the sleep queue, off the CPU,
+//! until the timer tick reaching its deadline readies it again, see
+//! [`wake_sleepers`]. One waiting for something else blocks on a
+//! [`WaitQueue`], until whoever makes it happen wakes the queue. The boot
+//! thread never blocks, as nothing would be left to run.
+//!
+//! Switching loads the stack of the next thread into the TSS as RSP0, always,
+//! and its address space into CR3, only when it belongs to another process:
+//! threads of one process, and the kernel between its own threads, keep the
+//! TLB. Each thread also keeps its own interrupt depth, since it may leave the
+//! CPU from inside a handler.
+//!
+//! The time each thread spends on the CPU is charged to ring 3 or to the
+//! kernel at the boundaries between them the kernel sees: the `int 0x80` gate,
+//! the timer ticks preempting ring 3, and the switches. Faults and the other
+//! interrupts from ring 3 count as user time.
+//!
+//! Only the BSP schedules; the APs stay parked. There is no FPU or SSE state
+//! to save, the kernel does not use them and neither may user programs yet.
+
+use core::{
+    arch::global_asm,
+    mem::size_of,
+    ptr::{self, addr_of},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+use crate::{
+    cpu::{idle::tsc_hz, rdtsc},
+    memory::address_space::AddressSpace,
+    sync::Mutex,
+    syscall::{int80::{int80_return, SyscallRegisters}, GeneralRegisters},
+    tables::{swap_interrupt_depth, tss::TSS, without_interrupts, InterruptStackFrame, InterruptStackFrameValue, RFlags},
+};
+use super::{Pid, KERNEL_PID};
+
+/// Index of a thread in the thread table.
+pub type Tid = usize;
+
+pub const MAX_THREADS: usize = 32;
+/// `kernel_main`, on the boot stack.
+pub const BOOT_THREAD: Tid = 0;
+const KERNEL_STACK_SIZE: usize = 32 * 1024;
+/// What [`switch_context`] keeps on the stack of a thread that left the CPU:
+/// six callee-saved registers, RFLAGS and the return address.
+const SWITCH_FRAME: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Off the CPU until [`wake`] readies it, see [`sleep_until`] and
+    /// [`WaitQueue`].
+    Blocked,
+    /// Never runs again; the reaper frees its slot once it left the CPU.
+    Dead,
+}
+
+/// Where a thread spent its time on the CPU, see [`charge`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ring {
+    User = 0,
+    Kernel = 1,
+}
+
+/// The time a thread or a process spent on the CPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTime {
+    pub user_ns: u64,
+    pub kernel_ns: u64,
+}
+
+impl core::ops::AddAssign for CpuTime {
+    fn add_assign(&mut self, other: Self) {
+        self.user_ns += other.user_ns;
+        self.kernel_ns += other.kernel_ns;
+    }
+}
+
+struct Thread {
+    /// The process the thread runs, [`KERNEL_PID`] for the boot thread.
+    pid: Pid,
+    state: ThreadState,
+    /// Loaded into CR3 while it runs.
+    address_space: AddressSpace,
+    /// Loaded as RSP0 while it runs.
+    stack_top: u64,
+    /// The interrupt depth it left the CPU at.
+    interrupt_depth: usize,
+}
+
+#[repr(C, align(16))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+
+/// The kernel stacks of the user threads, by thread. The boot thread has its own.
+static mut KERNEL_STACKS: [KernelStack; MAX_THREADS] = [const { KernelStack([0; KERNEL_STACK_SIZE]) }; MAX_THREADS];
+
+static THREADS: Mutex<[Option<Thread>; MAX_THREADS]> = Mutex::new("THREADS", [const { None }; MAX_THREADS]);
+/// The stack pointer of each thread that left the CPU, written by
+/// [`switch_context`] outside of the `THREADS` lock.
+static SAVED_RSP: [AtomicU64; MAX_THREADS] = [const { AtomicU64::new(0) }; MAX_THREADS];
+static CURRENT: AtomicUsize = AtomicUsize::new(BOOT_THREAD);
+/// The process of the current thread.
+static CURRENT_PID: AtomicU32 = AtomicU32::new(KERNEL_PID);
+/// See [`enter_syscall`].
+static SYSCALL_REGISTERS: [AtomicPtr<SyscallRegisters>; MAX_THREADS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_THREADS];
+/// The TSC cycles each thread spent in each [`Ring`], and the TSC when it
+/// was last charged.
+static CPU_CYCLES: [[AtomicU64; 2]; MAX_THREADS] = [const { [const { AtomicU64::new(0) }; 2] }; MAX_THREADS];
+static LAST_CHARGE: [AtomicU64; MAX_THREADS] = [const { AtomicU64::new(0) }; MAX_THREADS];
+/// The sleep queue: the tick each thread blocked in [`sleep_until`] waits for.
+static SLEEPERS: Mutex<[Option<u64>; MAX_THREADS]> = Mutex::new("SLEEPERS", [None; MAX_THREADS]);
+/// CR3 writes made by switches, for the tests.
+static ADDRESS_SPACE_SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    /// Saves the callee-saved registers and RFLAGS on the stack, stores the
+    /// stack pointer at `save`, and returns into the thread whose stack
+    /// pointer is `load`. Returns when a switch comes back to the caller.
+    fn switch_context(save: *mut u64, load: u64);
+}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "pushfq",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "popfq",
+    "ret",
+);
+
+/// Registers the running code as the boot thread, in the kernel address space.
+pub fn init() {
+    let mut threads = THREADS.lock();
+    assert!(threads[BOOT_THREAD].is_none(), "scheduler initialized twice");
+    threads[BOOT_THREAD] = Some(Thread {
+        pid: KERNEL_PID,
+        state: ThreadState::Running,
+        address_space: AddressSpace::current(),
+        stack_top: TSS.privilege_stack_top(),
+        interrupt_depth: 0,
+    });
+}
+
+/// Returns the running thread.
+pub fn current() -> Tid {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+/// Returns the process of the running thread, [`KERNEL_PID`] for the kernel.
+pub fn current_pid() -> Pid {
+    CURRENT_PID.load(Ordering::Relaxed)
+}
+
+/// Returns the top of the kernel stack of `tid`.
+fn kernel_stack_top(tid: Tid) -> u64 {
+    unsafe { addr_of!(KERNEL_STACKS[tid]) as u64 + KERNEL_STACK_SIZE as u64 }
+}
+
+/// Charges the time since the running thread was last charged to `ring`.
+pub fn charge(ring: Ring) {
+    let tid = current();
+    let now = rdtsc();
+    let since = LAST_CHARGE[tid].swap(now, Ordering::Relaxed);
+    CPU_CYCLES[tid][ring as usize].fetch_add(now.saturating_sub(since), Ordering::Relaxed);
+}
+
+/// Returns the time `tid` spent on the CPU. It stays there once the thread
+/// is reaped, until its slot is taken again.
+pub fn cpu_time(tid: Tid) -> CpuTime {
+    let ns = |ring: Ring| (CPU_CYCLES[tid][ring as usize].load(Ordering::Relaxed) as u128 * 1_000_000_000 / tsc_hz() as u128) as u64;
+    CpuTime { user_ns: ns(Ring::User), kernel_ns: ns(Ring::Kernel) }
+}
+
+/// Records `registers` as those the running thread entered the `int 0x80`
+/// gate from ring 3 with, until the returned guard is dropped, and charges
+/// the time until then to ring 3 and the time until the drop to the kernel.
+pub fn enter_syscall(registers: *mut SyscallRegisters) -> SyscallGuard {
+    let tid = current();
+    charge(Ring::User);
+    SyscallGuard { tid, previous: SYSCALL_REGISTERS[tid].swap(registers, Ordering::Relaxed) }
+}
+
+/// Returns the registers the running thread entered the `int 0x80` gate from
+/// ring 3 with, if it is in such a system call.
+pub fn syscall_registers() -> Option<*mut SyscallRegisters> {
+    let registers = SYSCALL_REGISTERS[current()].load(Ordering::Relaxed);
+    (!registers.is_null()).then_some(registers)
+}
+
+pub struct SyscallGuard {
+    tid: Tid,
+    previous: *mut SyscallRegisters,
+}
+
+impl Drop for SyscallGuard {
+    fn drop(&mut self) {
+        SYSCALL_REGISTERS[self.tid].store(self.previous, Ordering::Relaxed);
+        charge(Ring::Kernel);
+    }
+}
+
+/// The registers a new thread of a process starts with in ring 3: all zero,
+/// at `entry` with the stack at `stack`.
+pub fn initial_registers(entry: u64, stack: u64) -> SyscallRegisters {
+    SyscallRegisters { regs: GeneralRegisters::default(), frame: InterruptStackFrameValue::user(entry, stack) }
+}
+
+/// Creates a thread of `pid` in `address_space`, ready to return to ring 3
+/// with `registers` as if from a system call. Returns `None` when every slot
+/// is taken.
+pub fn spawn(pid: Pid, address_space: AddressSpace, registers: &SyscallRegisters) -> Option<Tid> {
+    without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let tid = (BOOT_THREAD + 1..MAX_THREADS).find(|&tid| threads[tid].is_none())?;
+        let stack_top = kernel_stack_top(tid);
+        // The registers where the CPU and the gate would have left them, RSP0
+        // being the stack top, then a switch frame returning to the gate's exit.
+        let registers_at = stack_top - size_of::<SyscallRegisters>() as u64;
+        let frame_at = registers_at - (SWITCH_FRAME * 8) as u64;
+        let mut frame = [0u64; SWITCH_FRAME];
+        // Interrupts stay off until `iretq` loads the user RFLAGS.
+        frame[SWITCH_FRAME - 2] = RFlags::empty().bits() | 0x2;
+        frame[SWITCH_FRAME - 1] = int80_return as *const () as u64;
+        unsafe {
+            (registers_at as *mut SyscallRegisters).write(*registers);
+            (frame_at as *mut [u64; SWITCH_FRAME]).write(frame);
+        }
+        SAVED_RSP[tid].store(frame_at, Ordering::Relaxed);
+        // Left over by a thread that exited in a system call.
+        SYSCALL_REGISTERS[tid].store(ptr::null_mut(), Ordering::Relaxed);
+        for cycles in &CPU_CYCLES[tid] {
+            cycles.store(0, Ordering::Relaxed);
+        }
+        threads[tid] = Some(Thread { pid, state: ThreadState::Ready, address_space, stack_top, interrupt_depth: 0 });
+        Some(tid)
+    })
+}
+
+/// Marks `tid` dead and takes it off the sleep queue, unless it is the
+/// running thread, which leaves through [`exit_current`] instead.
+pub fn kill(tid: Tid) {
+    without_interrupts(|| {
+        if tid == current() {
+            return;
+        }
+        if let Some(thread) = THREADS.lock()[tid].as_mut() {
+            thread.state = ThreadState::Dead;
+        }
+        SLEEPERS.lock()[tid] = None;
+    });
+}
+
+/// Readies `tid` if it is blocked.
+fn wake(tid: Tid) {
+    without_interrupts(|| {
+        if let Some(thread) = THREADS.lock()[tid].as_mut().filter(|thread| thread.state == ThreadState::Blocked) {
+            thread.state = ThreadState::Ready;
+        }
+    });
+}
+
+/// Returns the state of `tid`, `None` if there is no such thread.
+pub fn state(tid: Tid) -> Option<ThreadState> {
+    without_interrupts(|| THREADS.lock()[tid].as_ref().map(|thread| thread.state))
+}
+
+/// Returns whether `tid` is a thread of `pid` that was not reaped yet.
+pub fn is_alive(tid: Tid, pid: Pid) -> bool {
+    without_interrupts(|| THREADS.lock()[tid].as_ref().is_some_and(|thread| thread.pid == pid))
+}
+
+/// Frees the slots of the dead threads that left the CPU, and returns how
+/// many it freed.
+pub fn reap() -> usize {
+    without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let current = current();
+        let mut reaped = 0;
+        for (tid, slot) in threads.iter_mut().enumerate() {
+            if tid != current && slot.as_ref().is_some_and(|thread| thread.state == ThreadState::Dead) {
+                *slot = None;
+                reaped += 1;
+            }
+        }
+        reaped
+    })
+}
+
+/// Returns whether a thread other than the running one is ready.
+pub fn others_ready() -> bool {
+    without_interrupts(|| {
+        let current = current();
+        THREADS.lock().iter().enumerate()
+            .any(|(tid, thread)| tid != current && thread.as_ref().is_some_and(|thread| thread.state == ThreadState::Ready))
+    })
+}
+
+/// Gives the CPU to the next ready thread, if there is one, and returns once
+/// the current thread gets it back.
+pub fn yield_now() {
+    switch_away(ThreadState::Ready);
+}
+
+/// Lets the other threads run, or waits for the next interrupt if none is
+/// ready. What the boot thread does whenever it has nothing to do.
+pub fn idle() {
+    if others_ready() {
+        yield_now();
+    } else {
+        crate::pic::timer::idle();
+    }
+}
+
+/// Blocks the running thread on the sleep queue until the timer tick count
+/// reaches `deadline`, letting the other threads run meanwhile.
+pub fn sleep_until(deadline: u64) {
+    let tid = current();
+    assert_ne!(tid, BOOT_THREAD, "the boot thread cannot sleep");
+    // With interrupts off, no tick comes between the check and the switch.
+    without_interrupts(|| {
+        while crate::pic::timer::ticks() < deadline {
+            SLEEPERS.lock()[tid] = Some(deadline);
+            switch_away(ThreadState::Blocked);
+        }
+    });
+}
+
+/// Readies the threads of the sleep queue whose deadline is `now` or past.
+/// Every timer tick calls it.
+pub fn wake_sleepers(now: u64) {
+    without_interrupts(|| {
+        let mut sleepers = SLEEPERS.lock();
+        for (tid, deadline) in sleepers.iter_mut().enumerate() {
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                *deadline = None;
+                wake(tid);
+            }
+        }
+    });
+}
+
+/// Threads blocked until something they wait for happens, which whoever makes
+/// it happen tells with [`WaitQueue::wake_all`].
+pub struct WaitQueue {
+    waiting: [AtomicBool; MAX_THREADS],
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiting: [const { AtomicBool::new(false) }; MAX_THREADS] }
+    }
+
+    /// Returns what `ready` returns once it is `Some`, blocking the running
+    /// thread on the queue in between. `ready` runs with interrupts disabled,
+    /// so a wakeup from an interrupt handler cannot come between a check and
+    /// the block. The boot thread cannot block and idles between its checks
+    /// instead, see [`idle`].
+    pub fn wait_until<T>(&self, mut ready: impl FnMut() -> Option<T>) -> T {
+        let tid = current();
+        loop {
+            let value = without_interrupts(|| {
+                let value = ready();
+                if value.is_none() && tid != BOOT_THREAD {
+                    self.waiting[tid].store(true, Ordering::Relaxed);
+                    switch_away(ThreadState::Blocked);
+                }
+                value
+            });
+            match value {
+                Some(value) => return value,
+                None if tid == BOOT_THREAD => idle(),
+                None => {},
+            }
+        }
+    }
+
+    /// Readies the threads on the queue, to check what they wait for again.
+    ///
+    /// A thread killed while on the queue stays dead, and leaves the queue
+    /// here. Should its slot go to a new thread in the meantime, that one is
+    /// only woken early: every waiter checks again before going on.
+    pub fn wake_all(&self) {
+        for (tid, waiting) in self.waiting.iter().enumerate() {
+            if waiting.swap(false, Ordering::Relaxed) {
+                wake(tid);
+            }
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Ends the running thread. The reaper frees its stack later, from another one.
+pub fn exit_current() -> ! {
+    assert_ne!(current(), BOOT_THREAD, "the boot thread cannot exit");
+    switch_away(ThreadState::Dead);
+    unreachable!("dead thread scheduled again");
+}
+
+/// Called at the end of the timer handlers, once the interrupt is
+/// acknowledged: a thread interrupted in ring 3 gives the CPU to the next one.
+pub fn preempt(stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment.0 & 3 == 3 {
+        charge(Ring::User);
+        yield_now();
+    }
+}
+
+/// Returns how many times switching threads wrote CR3.
+pub fn address_space_switches() -> u64 {
+    ADDRESS_SPACE_SWITCHES.load(Ordering::Relaxed)
+}
+
+/// Switches to the next ready thread after the current one, leaving it in
+/// `state`. Returns right away if there is none and the current one stays
+/// ready.
+fn switch_away(state: ThreadState) {
+    without_interrupts(|| {
+        let previous = current();
+        charge(Ring::Kernel);
+        let (save, load) = {
+            let mut threads = THREADS.lock();
+            let next = (1..=MAX_THREADS)
+                .map(|i| (previous + i) % MAX_THREADS)
+                .find(|&tid| threads[tid].as_ref().is_some_and(|thread| thread.state == ThreadState::Ready));
+            let next = match next {
+                Some(next) => next,
+                None if state == ThreadState::Ready => return,
+                // The boot thread is always there to go to.
+                None => panic!("no thread left to run"),
+            };
+            let thread = threads[next].as_mut().unwrap();
+            thread.state = ThreadState::Running;
+            let (pid, depth) = (thread.pid, thread.interrupt_depth);
+            unsafe { TSS.set_privilege_stack_top(thread.stack_top); }
+            if pid != current_pid() && unsafe { thread.address_space.activate() } {
+                ADDRESS_SPACE_SWITCHES.fetch_add(1, Ordering::Relaxed);
+            }
+            let thread = threads[previous].as_mut().expect("running thread not in the table");
+            thread.state = state;
+            thread.interrupt_depth = swap_interrupt_depth(depth);
+            CURRENT.store(next, Ordering::Relaxed);
+            CURRENT_PID.store(pid, Ordering::Relaxed);
+            LAST_CHARGE[next].store(rdtsc(), Ordering::Relaxed);
+            (SAVED_RSP[previous].as_ptr(), SAVED_RSP[next].load(Ordering::Relaxed))
+        };
+        unsafe { switch_context(save, load); }
+    });
+}
@@ -0,0 +1,156 @@
+//! The areas of the user half a process reserved with `mmap`.
+//!
+//! An area only records its range and the flags its pages get. Nothing is
+//! mapped until the process touches a page, when the page fault handler maps
+//! a zeroed frame there, see [`super::resolve_fault`]. The segments of the
+//! executable and the stack are mapped up front and have no area.
+
+use crate::memory::paging::PageTableFlags;
+
+/// Areas per process.
+pub const MAX_VMAS: usize = 32;
+/// Where [`VmaSet::free_range`] starts looking, far above the executables.
+pub const MMAP_BASE: u64 = 0x10_0000_0000;
+/// The lowest address an area may start at, so that null pointers and small
+/// offsets from them keep faulting.
+pub const MMAP_MIN: u64 = 0x1_0000;
+
+/// The pages `start..end`, both page aligned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    /// What the pages get mapped with; without `PRESENT`, they may not be
+    /// accessed at all.
+    pub flags: PageTableFlags,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// The areas of a process, in no particular order, never overlapping.
+#[derive(Debug, Clone, Copy)]
+pub struct VmaSet {
+    areas: [Option<Vma>; MAX_VMAS],
+}
+
+impl VmaSet {
+    pub const fn new() -> Self {
+        VmaSet { areas: [None; MAX_VMAS] }
+    }
+
+    /// Returns the area `addr` is in.
+    pub fn find(&self, addr: u64) -> Option<Vma> {
+        self.areas.iter().flatten().find(|vma| vma.contains(addr)).copied()
+    }
+
+    /// Returns whether no area has a page in `start..end`.
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        !self.areas.iter().flatten().any(|vma| vma.overlaps(start, end))
+    }
+
+    /// Returns the lowest start of `len` free bytes from [`MMAP_BASE`], ending
+    /// at `limit` at the most.
+    pub fn free_range(&self, len: u64, limit: u64) -> Option<u64> {
+        let mut start = MMAP_BASE;
+        loop {
+            let end = start.checked_add(len).filter(|&end| end <= limit)?;
+            match self.areas.iter().flatten().filter(|vma| vma.overlaps(start, end)).map(|vma| vma.end).max() {
+                Some(after) => start = after,
+                None => return Some(start),
+            }
+        }
+    }
+
+    /// Adds `vma`, which must not overlap another area. Returns `false` if
+    /// all [`MAX_VMAS`] slots are taken.
+    pub fn insert(&mut self, vma: Vma) -> bool {
+        debug_assert!(self.is_free(vma.start, vma.end));
+        match self.areas.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(vma);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the pages `start..end` out of the areas, shrinking the ones it
+    /// cuts into and splitting the one it cuts in two. Returns `false`,
+    /// changing nothing, if there is no slot for the second half of a split.
+    pub fn remove(&mut self, start: u64, end: u64) -> bool {
+        // Only an area around the whole range splits, and then no other one overlaps it.
+        let split = self.areas.iter().flatten().any(|vma| vma.start < start && end < vma.end);
+        if split && self.areas.iter().all(Option::is_some) {
+            return false;
+        }
+        let mut second_half = None;
+        for slot in &mut self.areas {
+            let Some(vma) = slot.filter(|vma| vma.overlaps(start, end)) else {
+                continue;
+            };
+            let below = (vma.start < start).then_some(Vma { end: start, ..vma });
+            let above = (end < vma.end).then_some(Vma { start: end, ..vma });
+            *slot = below.or(above);
+            if below.is_some() {
+                second_half = above;
+            }
+        }
+        if let Some(vma) = second_half {
+            self.insert(vma);
+        }
+        true
+    }
+}
+
+#[test_case]
+fn free_ranges_go_around_areas() {
+    let flags = PageTableFlags::PRESENT;
+    let mut vmas = VmaSet::new();
+    assert_eq!(vmas.free_range(0x2000, u64::MAX), Some(MMAP_BASE));
+    assert!(vmas.insert(Vma { start: MMAP_BASE, end: MMAP_BASE + 0x1000, flags }));
+    assert!(vmas.insert(Vma { start: MMAP_BASE + 0x2000, end: MMAP_BASE + 0x3000, flags }));
+    // The hole between the two is one page.
+    assert_eq!(vmas.free_range(0x1000, u64::MAX), Some(MMAP_BASE + 0x1000));
+    assert_eq!(vmas.free_range(0x2000, u64::MAX), Some(MMAP_BASE + 0x3000));
+    assert_eq!(vmas.free_range(0x2000, MMAP_BASE + 0x4000), None);
+    assert!(!vmas.is_free(MMAP_BASE + 0x800, MMAP_BASE + 0x1800));
+    assert!(vmas.is_free(MMAP_BASE + 0x1000, MMAP_BASE + 0x2000));
+}
+
+#[test_case]
+fn removing_pages_shrinks_and_splits_areas() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut vmas = VmaSet::new();
+    vmas.insert(Vma { start: 0x10_0000, end: 0x20_0000, flags });
+    // A hole in the middle, then the bottom page.
+    assert!(vmas.remove(0x18_0000, 0x18_1000));
+    assert_eq!(vmas.find(0x17_f000), Some(Vma { start: 0x10_0000, end: 0x18_0000, flags }));
+    assert_eq!(vmas.find(0x18_0000), None);
+    assert_eq!(vmas.find(0x18_1000), Some(Vma { start: 0x18_1000, end: 0x20_0000, flags }));
+    assert!(vmas.remove(0x0f_0000, 0x10_1000));
+    assert_eq!(vmas.find(0x10_1000).map(|vma| vma.start), Some(0x10_1000));
+    // Across both halves and the hole.
+    assert!(vmas.remove(0x10_0000, 0x20_0000));
+    assert!(vmas.is_free(0, u64::MAX));
+
+    // Splitting needs a free slot.
+    let mut vmas = VmaSet::new();
+    for i in 0..MAX_VMAS as u64 {
+        assert!(vmas.insert(Vma { start: i * 0x4000, end: i * 0x4000 + 0x3000, flags }));
+    }
+    assert!(!vmas.insert(Vma { start: 0x100_0000, end: 0x100_1000, flags }));
+    assert!(!vmas.remove(0x1000, 0x2000));
+    assert_eq!(vmas.find(0x1000).map(|vma| vma.end), Some(0x3000));
+    assert!(vmas.remove(0x4000, 0x7000));
+    assert!(vmas.remove(0x1000, 0x2000));
+    assert_eq!(vmas.find(0x0000).map(|vma| vma.end), Some(0x1000));
+    assert_eq!(vmas.find(0x2000).map(|vma| vma.start), Some(0x2000));
+}
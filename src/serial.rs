@@ -0,0 +1,207 @@
+//! 16550 UART driver for the legacy COM ports.
+//!
+//! QEMU forwards COM1 to the host (`-serial stdio`), which is how test results
+//! leave a headless run.
+
+use core::fmt;
+use lazy_static::lazy_static;
+use crate::{sync::Mutex, tables::port::Port};
+
+/// I/O base of COM1.
+const COM1: u16 = 0x3F8;
+
+/// Line status register: the transmitter holding register is empty.
+const LSR_THR_EMPTY: u8 = 0x20;
+/// Line control register: divisor latch access bit.
+const LCR_DLAB: u8 = 0x80;
+/// Line control register: 8 data bits, no parity, one stop bit.
+const LCR_8N1: u8 = 0x03;
+/// Modem control register: DTR, RTS and OUT2 (OUT2 gates the IRQ line).
+const MCR_NORMAL: u8 = 0x0B;
+/// Modem control register: loopback mode with RTS, OUT1 and OUT2 set.
+const MCR_LOOPBACK: u8 = 0x1E;
+/// Byte sent through the loopback during initialization.
+const LOOPBACK_PROBE: u8 = 0xAE;
+
+/// Where [`AnsiStripper`] is within an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Text,
+    /// After ESC.
+    Escape,
+    /// After `ESC [`, until the final byte in `0x40..=0x7E`.
+    Csi,
+}
+
+/// Drops ANSI escape sequences from a byte stream, so captured logs stay greppable.
+#[derive(Debug, Clone, Copy)]
+pub struct AnsiStripper {
+    state: EscapeState,
+}
+
+impl AnsiStripper {
+    pub const fn new() -> Self {
+        AnsiStripper { state: EscapeState::Text }
+    }
+
+    /// Returns `byte` if it is text, `None` if it belongs to an escape sequence.
+    pub fn filter(&mut self, byte: u8) -> Option<u8> {
+        match (self.state, byte) {
+            (EscapeState::Text, 0x1B) => self.state = EscapeState::Escape,
+            (EscapeState::Text, _) => return Some(byte),
+            (EscapeState::Escape, b'[') => self.state = EscapeState::Csi,
+            // Two byte sequences like `ESC c`.
+            (EscapeState::Escape, _) => self.state = EscapeState::Text,
+            (EscapeState::Csi, 0x40..=0x7E) => self.state = EscapeState::Text,
+            (EscapeState::Csi, _) => {},
+        }
+        None
+    }
+}
+
+pub struct SerialPort {
+    /// Data register, divisor latch low byte while DLAB is set.
+    data: Port,
+    /// Interrupt enable register, divisor latch high byte while DLAB is set.
+    int_en: Port,
+    fifo_ctrl: Port,
+    line_ctrl: Port,
+    modem_ctrl: Port,
+    line_sts: Port,
+    initialized: bool,
+    /// Strip ANSI escapes from written text, see [`SerialPort::set_no_color`].
+    no_color: bool,
+    stripper: AnsiStripper,
+}
+
+impl SerialPort {
+    /// Creates a port for the UART at I/O base `base`, which must be initialized with
+    /// [`SerialPort::init`] before use. Test builds start in no-color mode.
+    pub const fn new(base: u16) -> Self {
+        Self {
+            data: Port::new(base),
+            int_en: Port::new(base + 1),
+            fifo_ctrl: Port::new(base + 2),
+            line_ctrl: Port::new(base + 3),
+            modem_ctrl: Port::new(base + 4),
+            line_sts: Port::new(base + 5),
+            initialized: false,
+            no_color: cfg!(test),
+            stripper: AnsiStripper::new(),
+        }
+    }
+
+    /// Programs the UART for 38400 baud 8N1 with FIFOs and no interrupts.
+    ///
+    /// The chip is checked with a loopback byte first; returns `false` and leaves the
+    /// port unusable if it does not come back, e.g. when there is no UART at all.
+    pub fn init(&mut self) -> bool {
+        unsafe {
+            self.int_en.write(0x00u8);
+            // Divisor 3: 115200 / 3 = 38400 baud.
+            self.line_ctrl.write(LCR_DLAB);
+            self.data.write(0x03u8);
+            self.int_en.write(0x00u8);
+            self.line_ctrl.write(LCR_8N1);
+            // Enable and clear both FIFOs, 14 byte threshold.
+            self.fifo_ctrl.write(0xC7u8);
+
+            self.modem_ctrl.write(MCR_LOOPBACK);
+            self.data.write(LOOPBACK_PROBE);
+            if self.data.read(0u8) != LOOPBACK_PROBE {
+                return false;
+            }
+            self.modem_ctrl.write(MCR_NORMAL);
+        }
+        self.initialized = true;
+        true
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    /// Makes formatted output drop ANSI color and cursor escapes, for logs
+    /// captured by CI. [`SerialPort::send`] still sends every byte.
+    pub fn set_no_color(&mut self, no_color: bool) {
+        self.no_color = no_color;
+        self.stripper = AnsiStripper::new();
+    }
+
+    pub fn is_no_color(&self) -> bool {
+        self.no_color
+    }
+
+    /// Sends `byte`, waiting for the transmitter to be ready. Does nothing if the port
+    /// failed to initialize.
+    pub fn send(&mut self, byte: u8) {
+        if !self.initialized {
+            return;
+        }
+        unsafe {
+            while self.line_sts.read(0u8) & LSR_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if !self.no_color {
+                self.send(byte);
+            } else if let Some(byte) = self.stripper.filter(byte) {
+                self.send(byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        let mut port = SerialPort::new(COM1);
+        port.init();
+        Mutex::new("SERIAL1", port)
+    };
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use crate::tables::without_interrupts;
+
+    without_interrupts(|| {
+        SERIAL1.lock().write_fmt(args).unwrap();
+    });
+}
+
+#[test_case]
+fn no_color_strips_escapes() {
+    assert!(SERIAL1.lock().is_no_color());
+
+    let mut stripper = AnsiStripper::new();
+    let mut out = [0u8; 64];
+    let mut len = 0;
+    for &byte in b"\x1b[1;32m[ok]\x1b[0m \x1b[2Jplain\x1bc text" {
+        if let Some(byte) = stripper.filter(byte) {
+            out[len] = byte;
+            len += 1;
+        }
+    }
+    assert_eq!(&out[..len], b"[ok] plain text");
+    assert!(!out[..len].windows(2).any(|w| w == b"\x1b["));
+}
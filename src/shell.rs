@@ -0,0 +1,531 @@
+//! A command shell on the console.
+//!
+//! Lines come from the tty, which already echoes and edits them, and each is
+//! split on spaces into a fixed array of words: the first names a built-in,
+//! the rest are its arguments. [`Shell`] only buffers the line being typed,
+//! so `kernel_main` can poll it between the network and the `hlt`;
+//! [`run_shell`] is the loop for when nothing else needs to run.
+
+use core::fmt::{self, Write};
+use crate::{
+    fs::{vfs::{self, FsError, Metadata, NodeKind, MAX_PATH}, NameBuf},
+    memory::{MemoryRegionKind, FRAME_ALLOCATOR, MAPPER},
+    pic::{self, timer},
+    print, process, tables::without_interrupts, tty::TTY,
+    vga::{self, VGAColor},
+};
+
+/// Longest line a command can be, the tty's own limit.
+const LINE_CAPACITY: usize = 256;
+/// Most words on a line, the command included.
+const MAX_WORDS: usize = 8;
+const PROMPT: &str = "> ";
+
+/// Where the shell reads its lines from, with the semantics of [`crate::tty::Tty::read`]:
+/// `None` when nothing is there yet, `Some(0)` for end of file.
+pub trait Input {
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize>;
+}
+
+/// The keyboard through the tty for input, the screen for output.
+pub struct Console;
+
+impl Input for Console {
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        without_interrupts(|| TTY.lock().read(buf))
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellError {
+    UnknownCommand,
+    TooManyWords,
+    LineTooLong,
+    NotUtf8,
+    /// The arguments do not fit the usage of the built-in.
+    Usage(&'static str),
+    /// [`crate::memory::init`] has not run.
+    NoMemory,
+    Fs(FsError),
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShellError::UnknownCommand => write!(f, "unknown command, try help"),
+            ShellError::TooManyWords => write!(f, "more than {} words", MAX_WORDS),
+            ShellError::LineTooLong => write!(f, "line longer than {} bytes", LINE_CAPACITY),
+            ShellError::NotUtf8 => write!(f, "line is not UTF-8"),
+            ShellError::Usage(usage) => write!(f, "usage: {}", usage),
+            ShellError::NoMemory => write!(f, "memory not set up"),
+            ShellError::Fs(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<FsError> for ShellError {
+    fn from(err: FsError) -> Self {
+        ShellError::Fs(err)
+    }
+}
+
+struct Builtin {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&[&str], &mut dyn Write) -> Result<(), ShellError>,
+}
+
+const BUILTINS: [Builtin; 13] = [
+    Builtin { name: "help", usage: "help", help: "list the commands", run: help },
+    Builtin { name: "mem", usage: "mem", help: "print the boot memory map", run: mem },
+    Builtin { name: "pt", usage: "pt", help: "print the present level 4 page table entries", run: pt },
+    Builtin { name: "irq", usage: "irq", help: "print the timer and IRQ counters", run: irq },
+    Builtin { name: "idle", usage: "idle", help: "print the idle periods by C-state", run: idle },
+    Builtin { name: "sensors", usage: "sensors", help: "print the CPU temperature and frequency", run: sensors },
+    Builtin { name: "uptime", usage: "uptime", help: "print the time since boot", run: uptime },
+    Builtin { name: "ps", usage: "ps", help: "list the processes", run: ps },
+    Builtin { name: "ls", usage: "ls [path]", help: "list a directory, the root by default", run: ls },
+    Builtin { name: "cat", usage: "cat <path>", help: "print a file", run: cat },
+    Builtin { name: "clear", usage: "clear", help: "clear the screen", run: clear },
+    Builtin { name: "color", usage: "color <fg> <bg>", help: "repaint the screen, colors by name or number", run: color },
+    Builtin { name: "reboot", usage: "reboot", help: "reset the machine", run: reboot },
+];
+
+/// Splits `line` on spaces into `words`, returning how many there are.
+pub fn split<'a>(line: &'a str, words: &mut [&'a str; MAX_WORDS]) -> Result<usize, ShellError> {
+    let mut count = 0;
+    for word in line.split(' ').filter(|word| !word.is_empty()) {
+        *words.get_mut(count).ok_or(ShellError::TooManyWords)? = word;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Runs one command line, writing what it prints to `out`. An empty line does nothing.
+pub fn execute(line: &str, out: &mut dyn Write) -> Result<(), ShellError> {
+    let mut words = [""; MAX_WORDS];
+    let count = split(line.trim_end_matches(['\n', '\r']), &mut words)?;
+    let Some((&name, args)) = words[..count].split_first() else {
+        return Ok(());
+    };
+    let builtin = BUILTINS.iter().find(|builtin| builtin.name == name).ok_or(ShellError::UnknownCommand)?;
+    (builtin.run)(args, out)
+}
+
+/// The line being typed, run when its newline comes in.
+pub struct Shell {
+    line: [u8; LINE_CAPACITY],
+    len: usize,
+    /// The line outgrew `line`, the rest of it is dropped.
+    overflow: bool,
+}
+
+impl Shell {
+    pub const fn new() -> Self {
+        Shell { line: [0; LINE_CAPACITY], len: 0, overflow: false }
+    }
+
+    pub fn prompt(&self, out: &mut dyn Write) {
+        let _ = out.write_str(PROMPT);
+    }
+
+    /// Takes typed bytes, running each line they complete and prompting for the next.
+    pub fn feed(&mut self, bytes: &[u8], out: &mut dyn Write) {
+        for &byte in bytes {
+            if byte != b'\n' {
+                match self.line.get_mut(self.len) {
+                    Some(slot) => {
+                        *slot = byte;
+                        self.len += 1;
+                    },
+                    None => self.overflow = true,
+                }
+                continue;
+            }
+            let result = match (self.overflow, core::str::from_utf8(&self.line[..self.len])) {
+                (true, _) => Err(ShellError::LineTooLong),
+                (false, Ok(line)) => execute(line, out),
+                (false, Err(_)) => Err(ShellError::NotUtf8),
+            };
+            if let Err(err) = result {
+                let _ = writeln!(out, "{}", err);
+            }
+            (self.len, self.overflow) = (0, false);
+            self.prompt(out);
+        }
+    }
+
+    /// Feeds what `input` has until it runs dry. End of file drops the line
+    /// being typed and prompts again.
+    pub fn poll(&mut self, input: &mut dyn Input, out: &mut dyn Write) {
+        let mut buf = [0u8; 64];
+        while let Some(count) = input.read(&mut buf) {
+            if count == 0 {
+                (self.len, self.overflow) = (0, false);
+                let _ = writeln!(out);
+                self.prompt(out);
+                continue;
+            }
+            self.feed(&buf[..count], out);
+        }
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads and runs commands from the keyboard forever, halting in between.
+pub fn run_shell() -> ! {
+    let mut shell = Shell::new();
+    shell.prompt(&mut Console);
+    loop {
+        shell.poll(&mut Console, &mut Console);
+        unsafe { core::arch::asm!("hlt", options(nomem, nostack, preserves_flags)); }
+    }
+}
+
+fn no_args(args: &[&str], usage: &'static str) -> Result<(), ShellError> {
+    if args.is_empty() { Ok(()) } else { Err(ShellError::Usage(usage)) }
+}
+
+fn help(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "help")?;
+    for builtin in &BUILTINS {
+        let _ = writeln!(out, "  {:<16}{}", builtin.usage, builtin.help);
+    }
+    Ok(())
+}
+
+fn mem(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "mem")?;
+    let memory_map = FRAME_ALLOCATOR.lock().as_ref().ok_or(ShellError::NoMemory)?.memory_map();
+    let mut usable = 0;
+    for region in memory_map {
+        let size = region.end.saturating_sub(region.start);
+        if region.kind == MemoryRegionKind::Usable {
+            usable += size;
+        }
+        let _ = writeln!(out, "{:#012x}-{:#012x} {:>9} KiB {:?}", region.start, region.end, size / 1024, region.kind);
+    }
+    let _ = writeln!(out, "{} MiB usable", usable / (1024 * 1024));
+    Ok(())
+}
+
+fn pt(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "pt")?;
+    let mapper = MAPPER.lock();
+    let table = mapper.as_ref().ok_or(ShellError::NoMemory)?.level_4_table();
+    for (index, entry) in table.iter().enumerate().filter(|(_, entry)| !entry.is_unused()) {
+        // Each entry covers 512 GiB, the upper half sign-extended.
+        let start = ((index as u64) << 55) as i64 >> 16;
+        let _ = writeln!(out, "L4 {:>3} {:#018x} {:?}", index, start, entry);
+    }
+    Ok(())
+}
+
+fn irq(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "irq")?;
+    let _ = writeln!(out, "timer: {} ticks, {} idle, from the {:?}", timer::ticks(), timer::idle_ticks(), timer::tick_source());
+    let _ = writeln!(out, "spurious IRQs: {}", pic::spurious_irqs());
+    let _ = writeln!(out, "HPET wakeups: {}", pic::hpet::wakeups());
+    Ok(())
+}
+
+fn idle(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "idle")?;
+    let stats = crate::cpu::idle::stats();
+    let mwait = if crate::cpu::idle::mwait_info().is_some() { "mwait" } else { "hlt" };
+    let _ = writeln!(out, "{} idle periods on {}, {} ms in all, the last {} us",
+        stats.periods, mwait, stats.idle_ns / 1_000_000, stats.last_ns / 1000);
+    for (state, &count) in stats.states.iter().enumerate().filter(|(_, &count)| count != 0) {
+        let _ = match state {
+            0 => writeln!(out, "  hlt: {}", count),
+            state => writeln!(out, "  C{}: {}", state, count),
+        };
+    }
+    Ok(())
+}
+
+fn sensors(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "sensors")?;
+    let snapshot = crate::cpu::thermal::snapshot();
+    let _ = match (snapshot.temperature, snapshot.tj_max) {
+        (Some(temperature), Some(tj_max)) => writeln!(out, "temperature: {} C, throttling at {} C", temperature, tj_max),
+        _ => writeln!(out, "temperature: unknown"),
+    };
+    for (name, hz) in [("base", snapshot.base_hz), ("current", snapshot.current_hz), ("effective", snapshot.effective_hz)] {
+        let _ = match hz {
+            Some(hz) => writeln!(out, "{} frequency: {} MHz", name, hz / 1_000_000),
+            None => writeln!(out, "{} frequency: unknown", name),
+        };
+    }
+    let _ = writeln!(out, "throttled {} times, PROCHOT {} times", snapshot.thermal_events, snapshot.prochot_events);
+    Ok(())
+}
+
+fn uptime(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "uptime")?;
+    let ms = timer::uptime_ms();
+    let _ = writeln!(out, "up {}.{:03} s", ms / 1000, ms % 1000);
+    Ok(())
+}
+
+fn ps(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "ps")?;
+    let _ = writeln!(out, "  PID  PPID STATE      MEM   USER ms    SYS ms NAME");
+    for info in process::snapshot().into_iter().flatten() {
+        let memory = HumanSize((info.resident_pages as u64) * crate::memory::PAGE_SIZE_4K);
+        let _ = writeln!(out, "{:>5} {:>5} {:<7} {} {:>9} {:>9} {}", info.pid, info.parent, info.state, memory,
+            info.cpu_time.user_ns / 1_000_000, info.cpu_time.kernel_ns / 1_000_000, info.name());
+    }
+    Ok(())
+}
+
+/// `path` from the root, where there is no working directory to start from.
+fn absolute(path: &str) -> Result<NameBuf<MAX_PATH>, ShellError> {
+    let mut absolute = NameBuf::new();
+    if (path.starts_with('/') || absolute.push('/')) && absolute.push_str(path) {
+        Ok(absolute)
+    } else {
+        Err(ShellError::Fs(FsError::NameTooLong))
+    }
+}
+
+/// A size in bytes, or in KiB to TiB with a decimal under 10, rounded up
+/// like `ls -h` does, six characters wide.
+struct HumanSize(u64);
+
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some((unit, scale)) = [("T", 1u64 << 40), ("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)].into_iter()
+            .find(|&(_, scale)| self.0 >= scale) else {
+            return write!(f, "{:>5}B", self.0);
+        };
+        let tenths = (self.0 as u128 * 10).div_ceil(scale as u128);
+        match tenths < 100 {
+            true => write!(f, "{:>3}.{}{}", tenths / 10, tenths % 10, unit),
+            false => write!(f, "{:>5}{}", self.0.div_ceil(scale), unit),
+        }
+    }
+}
+
+/// Writes a line of `ls`: the kind and permissions, the size, when the node
+/// was modified and `name`, with `/` after directories and `@` after links.
+fn write_listing(out: &mut dyn Write, name: &str, metadata: &Metadata) {
+    let (kind, suffix) = match metadata.kind {
+        NodeKind::File => ('-', ""),
+        NodeKind::Directory => ('d', "/"),
+        NodeKind::Symlink => ('l', "@"),
+    };
+    let _ = write!(out, "{}", kind);
+    for bit in (0..9).rev() {
+        let _ = out.write_char(if metadata.mode & (1 << bit) == 0 { '-' } else { ['x', 'w', 'r'][bit % 3] });
+    }
+    let _ = match metadata.modified {
+        Some(date) => write!(out, " {} {:04}-{:02}-{:02} {:02}:{:02}", HumanSize(metadata.size),
+            date.year, date.month, date.day, date.hour, date.minute),
+        None => write!(out, " {} {:>16}", HumanSize(metadata.size), "-"),
+    };
+    let _ = writeln!(out, "  {}{}", name, suffix);
+}
+
+fn ls(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    let path = match args {
+        [] => absolute("/")?,
+        [path] => absolute(path)?,
+        _ => return Err(ShellError::Usage("ls [path]")),
+    };
+    let metadata = vfs::metadata(path.as_str())?;
+    if metadata.kind != NodeKind::Directory {
+        write_listing(out, path.as_str(), &metadata);
+        return Ok(());
+    }
+    for entry in vfs::read_dir(path.as_str())? {
+        let entry = entry?;
+        let mut child = path;
+        if !path.as_str().ends_with('/') {
+            child.push('/');
+        }
+        if !child.push_str(entry.name()) {
+            return Err(ShellError::Fs(FsError::NameTooLong));
+        }
+        write_listing(out, entry.name(), &vfs::symlink_metadata(child.as_str())?);
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+    let &[path] = args else {
+        return Err(ShellError::Usage("cat <path>"));
+    };
+    let file = vfs::open(absolute(path)?.as_str())?;
+    let mut buffer = [0u8; 512];
+    // The start of a character the last read cut, kept at the start of `buffer`.
+    let (mut offset, mut kept) = (0, 0);
+    loop {
+        let len = file.read_at(offset, &mut buffer[kept..])?;
+        offset += len as u64;
+        let end = kept + len;
+        let valid = match core::str::from_utf8(&buffer[..end]) {
+            Err(err) if err.error_len().is_none() && len != 0 => err.valid_up_to(),
+            _ => end,
+        };
+        for chunk in buffer[..valid].utf8_chunks() {
+            let _ = out.write_str(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                let _ = out.write_char(char::REPLACEMENT_CHARACTER);
+            }
+        }
+        buffer.copy_within(valid..end, 0);
+        kept = end - valid;
+        if len == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn clear(args: &[&str], _out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "clear")?;
+    vga::clear_screen();
+    Ok(())
+}
+
+fn color(args: &[&str], _out: &mut dyn Write) -> Result<(), ShellError> {
+    const USAGE: &str = "color <fg> <bg>";
+    let parse = |arg: &str| VGAColor::from_name(arg)
+        .or_else(|| arg.parse::<u8>().ok().filter(|&value| value < 16).map(VGAColor::from_u8));
+    let &[fg, bg] = args else {
+        return Err(ShellError::Usage(USAGE));
+    };
+    let (Some(fg), Some(bg)) = (parse(fg), parse(bg)) else {
+        return Err(ShellError::Usage(USAGE));
+    };
+    vga::set_colors(fg, bg);
+    Ok(())
+}
+
+fn reboot(args: &[&str], _out: &mut dyn Write) -> Result<(), ShellError> {
+    no_args(args, "reboot")?;
+    crate::power::reboot()
+}
+
+/// What the shell printed, for the tests.
+#[cfg(test)]
+struct Transcript {
+    buf: [u8; 1024],
+    len: usize,
+}
+
+#[cfg(test)]
+impl Transcript {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for Transcript {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Hands out canned chunks of typed input, then nothing.
+#[cfg(test)]
+struct Script<'a> {
+    chunks: &'a [&'a [u8]],
+}
+
+#[cfg(test)]
+impl Input for Script<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let (chunk, rest) = self.chunks.split_first()?;
+        self.chunks = rest;
+        buf[..chunk.len()].copy_from_slice(chunk);
+        Some(chunk.len())
+    }
+}
+
+#[test_case]
+fn split_into_words() {
+    let mut words = [""; MAX_WORDS];
+    assert_eq!(split("  color  red   blue ", &mut words), Ok(3));
+    assert_eq!(words[..3], ["color", "red", "blue"]);
+    assert_eq!(split("", &mut words), Ok(0));
+    assert_eq!(split("a b c d e f g h i", &mut words), Err(ShellError::TooManyWords));
+}
+
+#[test_case]
+fn commands_from_a_script() {
+    let mut shell = Shell::new();
+    let mut out = Transcript { buf: [0; 1024], len: 0 };
+    // A command split across reads, a blank line, and end of file in the middle of one.
+    let mut input = Script { chunks: &[b"upt", b"ime\n", b"\n", b"frobnicate\nhelp\n", b"color red\n", b"irq x\nmem", b"", b"pt x\n"] };
+    shell.poll(&mut input, &mut out);
+    let mut lines = out.as_str().split('\n');
+    assert!(lines.next().unwrap().starts_with("up "));
+    assert_eq!(lines.next(), Some("> > unknown command, try help"));
+    assert_eq!(lines.next(), Some(">   help            list the commands"));
+    assert_eq!(lines.by_ref().take(BUILTINS.len() - 1).count(), BUILTINS.len() - 1);
+    assert_eq!(lines.next(), Some("> usage: color <fg> <bg>"));
+    assert_eq!(lines.next(), Some("> usage: irq"));
+    assert_eq!(lines.next(), Some("> "));
+    assert_eq!(lines.next(), Some("> usage: pt"));
+    assert_eq!(lines.next(), Some("> "));
+    assert_eq!(lines.next(), None);
+}
+
+#[test_case]
+fn too_long_and_not_utf8() {
+    let mut shell = Shell::new();
+    let mut out = Transcript { buf: [0; 1024], len: 0 };
+    shell.feed(&[b'x'; LINE_CAPACITY + 1], &mut out);
+    shell.feed(b"\n\xFF\nuptime extra\n", &mut out);
+    assert_eq!(out.as_str(), "line longer than 256 bytes\n> line is not UTF-8\n> usage: uptime\n> ");
+}
+
+#[test_case]
+fn ls_and_cat_read_through_the_vfs() {
+    crate::syscall::fd::mount_fixture();
+    let mut out = Transcript { buf: [0; 1024], len: 0 };
+    execute("cat etc/motd", &mut out).unwrap();
+    execute("ls /etc", &mut out).unwrap();
+    execute("ls /hello.txt", &mut out).unwrap();
+    let mut lines = out.as_str().split('\n');
+    assert_eq!(lines.next(), Some("Welcome to krabbos."));
+    assert_eq!(lines.next(), Some("-rw-r--r--    20B 2024-05-17 13:45  motd"));
+    assert_eq!(lines.next(), Some("-rw-------     8B 2024-05-17 13:45  hostname"));
+    assert_eq!(lines.next(), Some("lrwxrwxrwx     0B 2024-05-17 13:45  greeting@"));
+    assert_eq!(lines.next(), Some("-rw-r--r--    23B 2024-05-17 13:45  /hello.txt"));
+    assert_eq!(lines.next(), Some(""));
+
+    let mut out = Transcript { buf: [0; 1024], len: 0 };
+    execute("ls", &mut out).unwrap();
+    assert!(out.as_str().lines().any(|line| line.starts_with("drwxr-xr-x") && line.ends_with("  etc/")));
+    assert_eq!(execute("cat /etc", &mut out), Err(ShellError::Fs(FsError::IsADirectory)));
+    assert_eq!(execute("ls /nowhere", &mut out), Err(ShellError::Fs(FsError::NotFound)));
+    assert_eq!(execute("cat", &mut out), Err(ShellError::Usage("cat <path>")));
+}
+
+#[test_case]
+fn sizes_read_like_ls_h() {
+    let mut out = Transcript { buf: [0; 1024], len: 0 };
+    for size in [0, 1023, 1024, 1536, 10 * 1024 - 1, 280 << 10, (6 << 20) + 1, 5 << 40] {
+        let _ = write!(out, "{}|", HumanSize(size));
+    }
+    assert_eq!(out.as_str(), "    0B| 1023B|  1.0K|  1.5K|   10K|  280K|  6.1M|  5.0T|");
+}
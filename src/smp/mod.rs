@@ -0,0 +1,247 @@
+//! Starting the application processors (APs) the MADT lists, next to the
+//! bootstrap processor (BSP) running `kernel_main`.
+//!
+//! The APs are started one at a time, since they share the trampoline and its
+//! mailbox: INIT, then a startup IPI sent twice as the MP specification asks,
+//! then a wait for the AP to report online. An AP that does not is reported and
+//! sent INIT again, so it cannot wake up later on the stack of the next one.
+//!
+//! Each CPU finds its [`PerCpu`] block through its GS base. Started APs only
+//! park: nothing schedules work on them and no interrupt is routed to them.
+
+pub mod trampoline;
+
+use core::{arch::asm, fmt, ptr::addr_of, sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering}};
+use spin::Once;
+use crate::{
+    acpi,
+    memory::{mapper::MapToError, paging::Size4KiB, PAGE_SIZE_4K},
+    pic::{lapic, timer::{delay_ms, delay_us, idle, ms_to_ticks, ticks}},
+    println,
+    tables::{
+        gdt::{add_tss, load_gdt_for_cpu, MAX_CPUS},
+        idt::load_idt,
+        segments::{Segment64, GS},
+        tss::TaskStateSegment,
+    },
+};
+use trampoline::Trampoline;
+
+const STACK_SIZE: usize = 0x4000;
+/// The double fault stack of each AP, the size of the BSP's.
+const IST_STACK_SIZE: usize = PAGE_SIZE_4K as usize * 5;
+/// How long an AP gets to report online once started.
+const AP_START_TIMEOUT_MS: u64 = 100;
+
+/// Set once the BSP has a GS base pointing at its block, only the BSP runs before.
+static PER_CPU_READY: AtomicBool = AtomicBool::new(false);
+static PER_CPU: [PerCpu; MAX_CPUS] = PerCpu::blocks();
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut AP_STACKS: [Stack; MAX_CPUS] = [const { Stack([0; STACK_SIZE]) }; MAX_CPUS];
+static mut AP_IST_STACKS: [IstStack; MAX_CPUS] = [const { IstStack([0; IST_STACK_SIZE]) }; MAX_CPUS];
+static AP_TSS: [Once<TaskStateSegment>; MAX_CPUS] = [const { Once::new() }; MAX_CPUS];
+
+#[derive(Debug)]
+pub enum SmpError {
+    NoMadt,
+    NoLocalApic,
+    NoMemory,
+    /// No free frame below 1 MiB for the trampoline.
+    NoLowMemory,
+    /// CR3 is above 4 GiB, out of reach of the trampoline's 32-bit loads.
+    PageTablesTooHigh,
+    Map(MapToError<Size4KiB>),
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmpError::NoMadt => write!(f, "no MADT"),
+            SmpError::NoLocalApic => write!(f, "cannot map the local APIC"),
+            SmpError::NoMemory => write!(f, "memory management not initialized"),
+            SmpError::NoLowMemory => write!(f, "no free page below 1 MiB for the trampoline"),
+            SmpError::PageTablesTooHigh => write!(f, "kernel page tables above 4 GiB"),
+            SmpError::Map(err) => write!(f, "mapping the trampoline failed: {:?}", err),
+        }
+    }
+}
+
+/// What belongs to one CPU, reached through its GS base.
+#[repr(C)]
+pub struct PerCpu {
+    /// The address of the block itself, so that `gs:0` gives it.
+    this: AtomicU64,
+    index: usize,
+    apic_id: AtomicU8,
+    online: AtomicBool,
+}
+
+impl PerCpu {
+    const fn blocks() -> [PerCpu; MAX_CPUS] {
+        let mut blocks = [const { PerCpu { this: AtomicU64::new(0), index: 0, apic_id: AtomicU8::new(0), online: AtomicBool::new(false) } }; MAX_CPUS];
+        let mut i = 0;
+        while i < MAX_CPUS {
+            blocks[i].index = i;
+            i += 1;
+        }
+        blocks
+    }
+
+    /// The index of the CPU, 0 for the BSP and then in MADT order.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+/// Returns the block of the current CPU.
+pub fn current() -> &'static PerCpu {
+    if !PER_CPU_READY.load(Ordering::Acquire) {
+        return &PER_CPU[0];
+    }
+    let this: u64;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags)); }
+    unsafe { &*(this as *const PerCpu) }
+}
+
+/// Returns the index of the current CPU, see [`PerCpu::index`].
+pub fn cpu_index() -> usize {
+    current().index
+}
+
+/// Returns how many CPUs are running, the BSP included.
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire).max(1)
+}
+
+/// Returns the blocks of the running CPUs.
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    PER_CPU.iter().filter(|cpu| cpu.is_online())
+}
+
+/// Points the GS base of the current CPU, which is `cpu`, at its block.
+///
+/// ## Safety
+///
+/// Must run once on each CPU, with the local APIC mapped.
+unsafe fn set_per_cpu(cpu: usize) {
+    let block = &PER_CPU[cpu];
+    block.this.store(block as *const PerCpu as u64, Ordering::Relaxed);
+    unsafe { GS::write_base(block as *const PerCpu as u64); }
+    block.apic_id.store(lapic::id(), Ordering::Relaxed);
+}
+
+fn report_online(cpu: usize) {
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+    PER_CPU[cpu].online.store(true, Ordering::Release);
+}
+
+/// Sets up the TSS of the AP `cpu`, with its own double fault stack.
+fn ap_tss(cpu: usize) -> &'static TaskStateSegment {
+    AP_TSS[cpu].call_once(|| {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[0] = unsafe { addr_of!(AP_IST_STACKS[cpu]) as u64 } + IST_STACK_SIZE as u64;
+        tss
+    })
+}
+
+/// Where the trampoline leaves an AP, on its own stack with interrupts disabled.
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    // Before anything takes a lock, which asks what CPU it runs on.
+    unsafe { set_per_cpu(cpu); }
+    load_gdt_for_cpu(cpu);
+    load_idt();
+    // The PAT is per CPU, the mappings expect the same layout everywhere.
+    crate::memory::pat::init();
+    unsafe { lapic::enable(); }
+    report_online(cpu);
+    // Parked until there is a scheduler. NMIs and INIT still get through.
+    loop {
+        unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)); }
+    }
+}
+
+/// Starts the AP `apic_id` as CPU `cpu` and waits for it to report online.
+fn start_ap(trampoline: &mut Trampoline, cpu: usize, apic_id: u8) -> Result<(), &'static str> {
+    add_tss(cpu, ap_tss(cpu)).map_err(|_| "no room in the GDT for its TSS")?;
+    let stack_top = unsafe { addr_of!(AP_STACKS[cpu]) as u64 } + STACK_SIZE as u64;
+    unsafe { trampoline.prepare(stack_top, cpu); }
+
+    let online = || PER_CPU[cpu].is_online();
+    unsafe {
+        lapic::send_init(apic_id).map_err(|_| "INIT not delivered")?;
+        delay_ms(10);
+        for _ in 0..2 {
+            lapic::send_startup(apic_id, trampoline.startup_page()).map_err(|_| "startup IPI not delivered")?;
+            delay_us(200);
+            if online() {
+                return Ok(());
+            }
+        }
+    }
+    let deadline = ticks() + ms_to_ticks(AP_START_TIMEOUT_MS);
+    while !online() {
+        if ticks() >= deadline {
+            // Back to waiting for a startup IPI, out of the way of the next AP.
+            let _ = unsafe { lapic::send_init(apic_id) };
+            return Err("did not report online");
+        }
+        idle();
+    }
+    Ok(())
+}
+
+/// Starts every enabled CPU of the MADT beyond the BSP, and returns how many
+/// CPUs are online then. Interrupts must be enabled, the waits count timer ticks.
+pub fn boot_aps() -> Result<usize, SmpError> {
+    let madt = acpi::madt().ok_or(SmpError::NoMadt)?;
+    lapic::init().ok_or(SmpError::NoLocalApic)?;
+    if !PER_CPU_READY.load(Ordering::Acquire) {
+        unsafe { set_per_cpu(0); }
+        report_online(0);
+        PER_CPU_READY.store(true, Ordering::Release);
+    }
+    let bsp = lapic::id();
+
+    let mut trampoline = Trampoline::install(ap_entry)?;
+    let mut next = 1;
+    for cpu in madt.cpu_list().filter(|cpu| cpu.enabled && cpu.apic_id != bsp) {
+        if next == MAX_CPUS {
+            println!("SMP: only {} CPUs supported, APIC ID {} and up left alone", MAX_CPUS, cpu.apic_id);
+            break;
+        }
+        match start_ap(&mut trampoline, next, cpu.apic_id) {
+            Ok(()) => next += 1,
+            Err(reason) => println!("SMP: CPU with APIC ID {} {}", cpu.apic_id, reason),
+        }
+    }
+    Ok(online_cpus())
+}
+
+#[test_case]
+fn all_cpus_online() {
+    // `test-args` starts QEMU with 4 CPUs.
+    let madt_cpus = acpi::madt().unwrap().cpu_list().filter(|cpu| cpu.enabled).count();
+    assert_eq!(madt_cpus, 4);
+    assert_eq!(online_cpus(), 4);
+    assert_eq!(cpus().count(), 4);
+    for cpu in cpus() {
+        assert_eq!(cpus().filter(|other| other.apic_id() == cpu.apic_id()).count(), 1,
+            "APIC ID {} twice", cpu.apic_id());
+    }
+    assert_eq!(current().index(), 0);
+    assert_eq!(current().apic_id(), lapic::id());
+}
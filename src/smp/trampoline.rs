@@ -0,0 +1,212 @@
+//! The code an application processor starts in: a startup IPI leaves it in
+//! real mode at the start of a page below 1 MiB, from where the trampoline goes
+//! straight to long mode with the kernel's page tables and calls the entry
+//! point recorded in its mailbox.
+//!
+//! The blob is position independent. Real mode code addresses the mailbox
+//! relative to CS, long mode code relative to the page address kept in RBX, and
+//! the mailbox holds every absolute address, filled in by [`Trampoline::install`].
+
+use core::{arch::{asm, global_asm}, mem::offset_of, ptr::addr_of};
+use crate::{
+    cpu::Msr,
+    memory::{
+        frame_allocator::FrameAllocator,
+        paging::{read_cr3, PageTableFlags},
+        FRAME_ALLOCATOR, MAPPER,
+    },
+};
+use super::SmpError;
+
+/// Where the mailbox is in the trampoline page, after the code.
+const MAILBOX_OFFSET: usize = 0xF00;
+/// Startup IPIs can only point below 1 MiB.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+const IA32_EFER: u32 = 0xC000_0080;
+/// IA32_EFER bit the CPU sets itself once in long mode.
+const EFER_LMA: u64 = 1 << 10;
+/// CR4 bit that may only be set in long mode.
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// 64-bit code and flat data, the selectors 0x08 and 0x10 of the trampoline's GDT.
+const TRAMPOLINE_GDT: [u64; 3] = [0, 0x00AF_9A00_0000_FFFF, 0x00CF_9200_0000_FFFF];
+const TRAMPOLINE_CS: u16 = 0x08;
+
+/// What the BSP hands the AP it starts, read by the trampoline.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Mailbox {
+    gdt: [u64; 3],
+    /// `lgdt` operand: the limit and linear address of `gdt`.
+    gdt_limit: u16,
+    gdt_base: u32,
+    /// `jmp far` operand: the linear address of the 64-bit code and its selector.
+    long_mode_offset: u32,
+    long_mode_cs: u16,
+    /// Control registers and IA32_EFER of the BSP.
+    cr0: u32,
+    cr3: u32,
+    cr4: u32,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+global_asm!(
+    ".section .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    movzx ebx, ax",
+    "    shl ebx, 4",
+    "    lgdt [{gdt_pointer}]",
+    "    mov eax, [{cr4}]",
+    "    mov cr4, eax",
+    "    mov eax, [{cr3}]",
+    "    mov cr3, eax",
+    "    mov ecx, {efer_msr}",
+    "    mov eax, [{efer}]",
+    "    mov edx, [{efer} + 4]",
+    "    wrmsr",
+    // Protection and paging at once, which enters long mode since EFER.LME is set.
+    "    mov eax, [{cr0}]",
+    "    mov cr0, eax",
+    // jmp far dword [long_mode_pointer], a m16:32 operand the assembler would not take in 16-bit code.
+    "    .byte 0x66, 0xFF, 0x2E",
+    "    .word {long_mode_pointer}",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "    xor eax, eax",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov fs, ax",
+    "    mov gs, ax",
+    "    mov rsp, [rbx + {stack_top}]",
+    "    mov rdi, [rbx + {cpu}]",
+    "    mov rax, [rbx + {entry}]",
+    "    call rax",
+    "    ud2",
+    "ap_trampoline_end:",
+    ".text",
+    gdt_pointer = const MAILBOX_OFFSET + offset_of!(Mailbox, gdt_limit),
+    long_mode_pointer = const MAILBOX_OFFSET + offset_of!(Mailbox, long_mode_offset),
+    cr0 = const MAILBOX_OFFSET + offset_of!(Mailbox, cr0),
+    cr3 = const MAILBOX_OFFSET + offset_of!(Mailbox, cr3),
+    cr4 = const MAILBOX_OFFSET + offset_of!(Mailbox, cr4),
+    efer = const MAILBOX_OFFSET + offset_of!(Mailbox, efer),
+    efer_msr = const IA32_EFER,
+    stack_top = const MAILBOX_OFFSET + offset_of!(Mailbox, stack_top),
+    cpu = const MAILBOX_OFFSET + offset_of!(Mailbox, cpu),
+    entry = const MAILBOX_OFFSET + offset_of!(Mailbox, entry),
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The trampoline code, as assembled into the kernel image.
+fn code() -> &'static [u8] {
+    unsafe {
+        let start = addr_of!(ap_trampoline_start);
+        let len = addr_of!(ap_trampoline_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe { asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags)); }
+    cr0
+}
+
+fn read_cr4() -> u64 {
+    let cr4: u64;
+    unsafe { asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags)); }
+    cr4
+}
+
+/// The trampoline copied to an identity mapped page below 1 MiB.
+pub struct Trampoline {
+    page: u64,
+    mailbox: Mailbox,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to a free page below 1 MiB, identity mapped so that
+    /// it keeps running once the AP turns paging on, and fills in the mailbox
+    /// for `entry`.
+    ///
+    /// The page is taken from the frame allocator, which hands out the low
+    /// frames first: this must run early, before they are all gone.
+    pub fn install(entry: extern "C" fn(usize) -> !) -> Result<Self, SmpError> {
+        let code = code();
+        assert!(code.len() <= MAILBOX_OFFSET, "AP trampoline overlaps its mailbox");
+        let cr3 = read_cr3();
+        if cr3 >= 1 << 32 {
+            return Err(SmpError::PageTablesTooHigh);
+        }
+
+        let mut mapper = MAPPER.lock();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let (Some(mapper), Some(allocator)) = (mapper.as_mut(), allocator.as_mut()) else {
+            return Err(SmpError::NoMemory);
+        };
+        let frame = loop {
+            let frame = allocator.allocate_frame().ok_or(SmpError::NoLowMemory)?;
+            // Frame 0 is skipped, it would make the mailbox look like null pointers.
+            match frame.start_address() {
+                0 => continue,
+                address if address >= LOW_MEMORY_END => return Err(SmpError::NoLowMemory),
+                _ => break frame,
+            }
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.identity_map_range(frame, frame, flags, allocator) }.map_err(SmpError::Map)?;
+
+        let page = frame.start_address();
+        let long_mode = addr_of!(ap_trampoline_long_mode) as u64 - addr_of!(ap_trampoline_start) as u64;
+        let mailbox = Mailbox {
+            gdt: TRAMPOLINE_GDT,
+            gdt_limit: (size_of_val(&TRAMPOLINE_GDT) - 1) as u16,
+            gdt_base: (page as usize + MAILBOX_OFFSET + offset_of!(Mailbox, gdt)) as u32,
+            long_mode_offset: (page + long_mode) as u32,
+            long_mode_cs: TRAMPOLINE_CS,
+            cr0: read_cr0() as u32,
+            cr3: cr3 as u32,
+            cr4: (read_cr4() & !CR4_PCIDE) as u32,
+            efer: unsafe { Msr::new(IA32_EFER).read() } & !EFER_LMA,
+            stack_top: 0,
+            entry: entry as usize as u64,
+            cpu: 0,
+        };
+        unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), page as *mut u8, code.len()); }
+        Ok(Trampoline { page, mailbox })
+    }
+
+    /// The startup IPI operand pointing at the trampoline.
+    pub fn startup_page(&self) -> u8 {
+        (self.page >> 12) as u8
+    }
+
+    /// Makes the next AP started take `stack_top` as its stack and `cpu` as its index.
+    ///
+    /// ## Safety
+    ///
+    /// No AP may be running the trampoline: the one started before must have
+    /// reached the entry point, or been sent INIT again.
+    pub unsafe fn prepare(&mut self, stack_top: u64, cpu: usize) {
+        self.mailbox.stack_top = stack_top;
+        self.mailbox.cpu = cpu as u64;
+        unsafe { ((self.page as usize + MAILBOX_OFFSET) as *mut Mailbox).write_volatile(self.mailbox); }
+    }
+}
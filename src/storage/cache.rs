@@ -0,0 +1,483 @@
+//! A cache of block device contents in memory.
+//!
+//! [`BlockCache`] wraps a [`BlockDevice`] and is one itself. It holds the
+//! device in runs of [`BUFFER_SIZE`] bytes, in `N` buffers of its own since
+//! there is no heap, found through a hash table by the number of their run.
+//! A read that misses loads the whole run, taking the buffer used least
+//! recently.
+//!
+//! With [`WritePolicy::WriteThrough`] a write reaches the device before it
+//! returns, and updates the buffer if the run is cached. With
+//! [`WritePolicy::WriteBack`] it only dirties the buffer, which is written out
+//! when it is evicted, by [`BlockCache::sync`] or [`BlockDevice::flush`], or
+//! by [`flush_due`] once it has been dirty for [`WRITE_BACK_DELAY_MS`].
+//!
+//! The table is behind one lock, held to find a buffer and pin it so that it
+//! is not evicted, and each buffer behind a lock of its own, held while it is
+//! loaded, written out or copied. Two users of one run wait for each other,
+//! users of different runs do not, except for the eviction of a dirty buffer,
+//! which writes it out before the table is unlocked. With every buffer pinned,
+//! requests go straight to the device. Interrupt handlers must not use a
+//! cache.
+
+use core::fmt;
+use crate::{pic::timer::{ms_to_ticks, ticks}, println, sync::Mutex};
+use super::{check_request, BlockDevice, IoError, RegisterError};
+
+/// The bytes of a buffer, a run of whole blocks.
+pub const BUFFER_SIZE: usize = 4096;
+/// How long a dirty buffer of a write-back cache waits for [`flush_due`].
+pub const WRITE_BACK_DELAY_MS: u64 = 5000;
+/// The most caches [`flush_due`] looks after.
+pub const MAX_CACHES: usize = 8;
+/// Hash buckets of the table, a power of two.
+const BUCKETS: usize = 64;
+/// The end of a bucket's chain.
+const NONE: u16 = u16::MAX;
+
+static PERIODIC: Mutex<[Option<&'static dyn Periodic>; MAX_CACHES]> = Mutex::new("BLOCK_CACHES", [None; MAX_CACHES]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    WriteThrough,
+    WriteBack,
+}
+
+/// What to do about a run that is not in its buffer yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Miss {
+    /// Read it from the device.
+    Load,
+    /// Nothing, the caller writes all of it.
+    Overwrite,
+    /// Leave the run uncached.
+    Skip,
+}
+
+/// The table's record of a buffer.
+#[derive(Clone, Copy)]
+struct Slot {
+    run: u64,
+    mapped: bool,
+    /// The next slot in the bucket of `run`.
+    next: u16,
+    /// Users between [`BlockCache::pin`] and [`BlockCache::unpin`].
+    pins: u32,
+    last_used: u64,
+    /// Written to since it was mapped, so maybe dirty: clean buffers are
+    /// evicted first.
+    written: bool,
+}
+
+impl Slot {
+    const FREE: Slot = Slot { run: 0, mapped: false, next: NONE, pins: 0, last_used: 0, written: false };
+}
+
+struct Table<const N: usize> {
+    buckets: [u16; BUCKETS],
+    slots: [Slot; N],
+    /// Counts the uses of the cache, for `last_used`.
+    clock: u64,
+}
+
+fn bucket(run: u64) -> usize {
+    (run.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - BUCKETS.trailing_zeros())) as usize
+}
+
+impl<const N: usize> Table<N> {
+    fn find(&self, run: u64) -> Option<usize> {
+        let mut slot = self.buckets[bucket(run)];
+        while slot != NONE {
+            if self.slots[slot as usize].run == run {
+                return Some(slot as usize);
+            }
+            slot = self.slots[slot as usize].next;
+        }
+        None
+    }
+
+    fn insert(&mut self, slot: usize, run: u64) {
+        let head = &mut self.buckets[bucket(run)];
+        self.slots[slot] = Slot { run, mapped: true, next: *head, ..self.slots[slot] };
+        *head = slot as u16;
+    }
+
+    fn remove(&mut self, slot: usize) {
+        let head = &mut self.buckets[bucket(self.slots[slot].run)];
+        if *head as usize == slot {
+            *head = self.slots[slot].next;
+        } else {
+            let mut previous = *head as usize;
+            while self.slots[previous].next as usize != slot {
+                previous = self.slots[previous].next as usize;
+            }
+            self.slots[previous].next = self.slots[slot].next;
+        }
+        self.slots[slot].mapped = false;
+    }
+}
+
+struct Buffer {
+    /// The run the data is of, `None` until it is loaded.
+    run: Option<u64>,
+    /// The tick the data first differed from the device at.
+    dirty_since: Option<u64>,
+    data: [u8; BUFFER_SIZE],
+}
+
+/// A cache over `device` with `N` buffers. Devices whose blocks do not divide
+/// [`BUFFER_SIZE`] are passed through uncached.
+pub struct BlockCache<const N: usize> {
+    device: &'static dyn BlockDevice,
+    policy: WritePolicy,
+    table: Mutex<Table<N>>,
+    buffers: [Mutex<Buffer>; N],
+}
+
+impl<const N: usize> BlockCache<N> {
+    pub const fn new(device: &'static dyn BlockDevice, policy: WritePolicy) -> Self {
+        assert!(N != 0 && N < NONE as usize);
+        BlockCache {
+            device,
+            policy,
+            table: Mutex::new("BLOCK_CACHE", Table { buckets: [NONE; BUCKETS], slots: [Slot::FREE; N], clock: 0 }),
+            buffers: [const { Mutex::new("BLOCK_CACHE_BUFFER", Buffer { run: None, dirty_since: None, data: [0; BUFFER_SIZE] }) }; N],
+        }
+    }
+
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    /// Blocks in a run, 0 when the device is not cached.
+    fn blocks_per_run(&self) -> u64 {
+        match self.device.block_size() {
+            0 => 0,
+            size if BUFFER_SIZE % size == 0 => (BUFFER_SIZE / size) as u64,
+            _ => 0,
+        }
+    }
+
+    /// The bytes of `run` on the device: all of the buffer, but for a last
+    /// run cut short by the end of the device.
+    fn run_len(&self, run: u64) -> usize {
+        let blocks = self.device.num_blocks() - run * self.blocks_per_run();
+        blocks.min(self.blocks_per_run()) as usize * self.device.block_size()
+    }
+
+    /// Finds the buffer of `run`, or on a miss takes the least recently used
+    /// one for it unless `miss` is [`Miss::Skip`], and pins it. `None` when
+    /// the run is left uncached, or every buffer is pinned.
+    fn pin(&self, run: u64, miss: Miss, write: bool) -> Result<Option<usize>, IoError> {
+        let mut table = self.table.lock();
+        table.clock += 1;
+        let clock = table.clock;
+        let slot = match table.find(run) {
+            Some(slot) => slot,
+            None if miss == Miss::Skip => return Ok(None),
+            None => {
+                let victim = (0..N).filter(|&slot| table.slots[slot].pins == 0)
+                    .min_by_key(|&slot| (table.slots[slot].written, table.slots[slot].last_used));
+                let Some(victim) = victim else {
+                    return Ok(None);
+                };
+                if table.slots[victim].mapped {
+                    // Written out before the run can be looked up again, so
+                    // nothing reads it from the device in the meantime.
+                    let mut buffer = self.buffers[victim].lock();
+                    self.write_out(&mut buffer)?;
+                    buffer.run = None;
+                    drop(buffer);
+                    table.remove(victim);
+                }
+                table.slots[victim].written = false;
+                table.insert(victim, run);
+                victim
+            }
+        };
+        let entry = &mut table.slots[slot];
+        entry.pins += 1;
+        entry.last_used = clock;
+        entry.written |= write;
+        Ok(Some(slot))
+    }
+
+    fn unpin(&self, slot: usize) {
+        self.table.lock().slots[slot].pins -= 1;
+    }
+
+    /// Runs `f` on the buffer of `run`, see [`BlockCache::pin`], loading the
+    /// run first if `miss` says so. `None` if the run is not cached.
+    fn with_buffer<T>(&self, run: u64, miss: Miss, write: bool, f: impl FnOnce(&mut Buffer) -> T) -> Result<Option<T>, IoError> {
+        let Some(slot) = self.pin(run, miss, write)? else {
+            return Ok(None);
+        };
+        let result = (|| {
+            let mut buffer = self.buffers[slot].lock();
+            if buffer.run != Some(run) {
+                match miss {
+                    Miss::Load => {
+                        let start = run * self.blocks_per_run();
+                        let len = self.run_len(run);
+                        self.device.read_blocks(start, &mut buffer.data[..len])?;
+                    }
+                    Miss::Overwrite => {},
+                    // Mapped, but loading it failed.
+                    Miss::Skip => return Ok(None),
+                }
+                buffer.run = Some(run);
+            }
+            Ok(Some(f(&mut buffer)))
+        })();
+        self.unpin(slot);
+        result
+    }
+
+    /// Writes `buffer` to the device if it is dirty.
+    fn write_out(&self, buffer: &mut Buffer) -> Result<(), IoError> {
+        if let (Some(run), Some(_)) = (buffer.run, buffer.dirty_since) {
+            self.device.write_blocks(run * self.blocks_per_run(), &buffer.data[..self.run_len(run)])?;
+            buffer.dirty_since = None;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty buffer to the device.
+    pub fn sync(&self) -> Result<(), IoError> {
+        for buffer in &self.buffers {
+            self.write_out(&mut buffer.lock())?;
+        }
+        Ok(())
+    }
+
+    /// Makes [`flush_due`] write the dirty buffers out once they are old enough.
+    pub fn flush_periodically(&'static self) -> Result<(), RegisterError> {
+        let mut caches = PERIODIC.lock();
+        let slot = caches.iter_mut().find(|slot| slot.is_none()).ok_or(RegisterError::Full)?;
+        *slot = Some(self);
+        Ok(())
+    }
+
+    /// Calls `transfer` with each piece of the `len` bytes from `lba` on that
+    /// lies in one run: its block, the run, the offset in the run and the
+    /// range of the piece in the request.
+    fn split(&self, lba: u64, len: usize, mut transfer: impl FnMut(u64, u64, usize, core::ops::Range<usize>) -> Result<(), IoError>) -> Result<(), IoError> {
+        let (block_size, blocks_per_run) = (self.device.block_size(), self.blocks_per_run());
+        let mut done = 0;
+        while done < len {
+            let block = lba + (done / block_size) as u64;
+            let offset = (block % blocks_per_run) as usize * block_size;
+            let count = (BUFFER_SIZE - offset).min(len - done);
+            transfer(block, block / blocks_per_run, offset, done..done + count)?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> BlockDevice for BlockCache<N> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        check_request(self, lba, buffer.len())?;
+        if self.blocks_per_run() == 0 {
+            return self.device.read_blocks(lba, buffer);
+        }
+        self.split(lba, buffer.len(), |block, run, offset, range| {
+            let out = &mut buffer[range];
+            let cached = self.with_buffer(run, Miss::Load, false, |cached| {
+                out.copy_from_slice(&cached.data[offset..offset + out.len()]);
+            })?;
+            match cached {
+                Some(()) => Ok(()),
+                None => self.device.read_blocks(block, out),
+            }
+        })
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError> {
+        check_request(self, lba, buffer.len())?;
+        if self.blocks_per_run() == 0 {
+            return self.device.write_blocks(lba, buffer);
+        }
+        self.split(lba, buffer.len(), |block, run, offset, range| {
+            let data = &buffer[range];
+            let cached = match self.policy {
+                // The device first, so that the buffer never holds what it does not.
+                WritePolicy::WriteThrough => self.with_buffer(run, Miss::Skip, false, |cached| {
+                    self.device.write_blocks(block, data)?;
+                    cached.data[offset..offset + data.len()].copy_from_slice(data);
+                    Ok(())
+                })?,
+                WritePolicy::WriteBack => {
+                    let whole = offset == 0 && data.len() == self.run_len(run);
+                    let miss = if whole { Miss::Overwrite } else { Miss::Load };
+                    self.with_buffer(run, miss, true, |cached| {
+                        cached.data[offset..offset + data.len()].copy_from_slice(data);
+                        cached.dirty_since.get_or_insert_with(ticks);
+                        Ok(())
+                    })?
+                }
+            };
+            cached.unwrap_or_else(|| self.device.write_blocks(block, data))
+        })
+    }
+
+    fn flush(&self) -> Result<(), IoError> {
+        self.sync()?;
+        self.device.flush()
+    }
+}
+
+impl<const N: usize> fmt::Debug for BlockCache<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BlockCache({} buffers, {:?})", N, self.policy)
+    }
+}
+
+/// A cache [`flush_due`] looks after.
+trait Periodic: Sync {
+    /// Writes out the buffers dirty since before `before`, skipping those in use.
+    fn flush_older(&self, before: u64) -> Result<(), IoError>;
+}
+
+impl<const N: usize> Periodic for BlockCache<N> {
+    fn flush_older(&self, before: u64) -> Result<(), IoError> {
+        for buffer in &self.buffers {
+            let Some(mut buffer) = buffer.try_lock() else {
+                continue;
+            };
+            if buffer.dirty_since.is_some_and(|since| since <= before) {
+                self.write_out(&mut buffer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes out the buffers of the caches registered with
+/// [`BlockCache::flush_periodically`] that have been dirty for
+/// [`WRITE_BACK_DELAY_MS`]. The main loop calls it.
+pub fn flush_due() {
+    let Some(before) = ticks().checked_sub(ms_to_ticks(WRITE_BACK_DELAY_MS)) else {
+        return;
+    };
+    let caches = *PERIODIC.lock();
+    for cache in caches.into_iter().flatten() {
+        if let Err(err) = cache.flush_older(before) {
+            println!("block cache: write-back failed: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+use super::MemBlockDevice;
+
+/// Whether `run` has a buffer in `cache`.
+#[cfg(test)]
+fn is_cached<const N: usize>(cache: &BlockCache<N>, run: u64) -> bool {
+    cache.table.lock().find(run).is_some()
+}
+
+#[test_case]
+fn cache_reads_and_writes_through() {
+    // Nine runs, the last one of two blocks.
+    static DISK: MemBlockDevice<{ 66 * 512 }> = MemBlockDevice::new(false);
+    static CACHE: BlockCache<4> = BlockCache::new(&DISK, WritePolicy::WriteThrough);
+    let mut blocks = [0u8; 3 * 512];
+    for (i, byte) in blocks.iter_mut().enumerate() {
+        *byte = (i * 7) as u8;
+    }
+    DISK.write_blocks(7, &blocks).unwrap();
+    let mut read = [0u8; 3 * 512];
+    // Across the first two runs.
+    CACHE.read_blocks(7, &mut read).unwrap();
+    assert!(read == blocks);
+    assert!(is_cached(&CACHE, 0) && is_cached(&CACHE, 1));
+
+    CACHE.write_blocks(8, &[0xAB; 512]).unwrap();
+    DISK.read_blocks(8, &mut read[..512]).unwrap();
+    assert!(read[..512].iter().all(|&byte| byte == 0xAB));
+    CACHE.read_blocks(8, &mut read[..512]).unwrap();
+    assert!(read[..512].iter().all(|&byte| byte == 0xAB));
+    // Uncached runs are not loaded to be written through.
+    CACHE.write_blocks(24, &[1; 512]).unwrap();
+    assert!(!is_cached(&CACHE, 3));
+
+    CACHE.write_blocks(65, &[2; 512]).unwrap();
+    CACHE.read_blocks(64, &mut read[..1024]).unwrap();
+    assert!(read[..512].iter().all(|&byte| byte == 0) && read[512..1024].iter().all(|&byte| byte == 2));
+    assert_eq!(CACHE.read_blocks(65, &mut read[..1024]), Err(IoError::OutOfRange));
+}
+
+#[test_case]
+fn write_back_cache_writes_dirty_buffers_out() {
+    static DISK: MemBlockDevice<{ 32 * 512 }> = MemBlockDevice::new(false);
+    static CACHE: BlockCache<2> = BlockCache::new(&DISK, WritePolicy::WriteBack);
+    let mut read = [0u8; 512];
+    CACHE.write_blocks(1, &[3; 512]).unwrap();
+    CACHE.read_blocks(1, &mut read).unwrap();
+    assert!(read.iter().all(|&byte| byte == 3));
+    DISK.read_blocks(1, &mut read).unwrap();
+    assert!(read.iter().all(|&byte| byte == 0), "written before the cache was synced");
+
+    // Both dirty, so run 0 is the least recently used one.
+    CACHE.write_blocks(9, &[7; 512]).unwrap();
+    CACHE.read_blocks(16, &mut read).unwrap();
+    assert!(!is_cached(&CACHE, 0) && is_cached(&CACHE, 1) && is_cached(&CACHE, 2));
+    DISK.read_blocks(1, &mut read).unwrap();
+    assert!(read.iter().all(|&byte| byte == 3), "evicted without being written out");
+    // Clean buffers go before dirty ones, however recently used.
+    CACHE.read_blocks(24, &mut read).unwrap();
+    assert!(is_cached(&CACHE, 1) && !is_cached(&CACHE, 2));
+    CACHE.read_blocks(1, &mut read).unwrap();
+    assert!(read.iter().all(|&byte| byte == 3));
+
+    CACHE.write_blocks(25, &[4; 512]).unwrap();
+    CACHE.flush().unwrap();
+    DISK.read_blocks(25, &mut read).unwrap();
+    assert!(read.iter().all(|&byte| byte == 4));
+    DISK.read_blocks(9, &mut read).unwrap();
+    assert!(read.iter().all(|&byte| byte == 7));
+    assert!(CACHE.buffers.iter().all(|buffer| buffer.lock().dirty_since.is_none()));
+}
+
+#[test_case]
+fn cache_locks_buffers_one_at_a_time() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use crate::pic::timer::{idle, set_tick_hook};
+    static DISK: MemBlockDevice<{ 16 * 512 }> = MemBlockDevice::new(false);
+    static CACHE: BlockCache<2> = BlockCache::new(&DISK, WritePolicy::WriteBack);
+    static READ: AtomicBool = AtomicBool::new(false);
+    fn read_run_1() {
+        let mut read = [0u8; 512];
+        if !READ.load(Ordering::Relaxed) && CACHE.read_blocks(8, &mut read).is_ok() {
+            READ.store(read.iter().all(|&byte| byte == 6), Ordering::Relaxed);
+        }
+    }
+    DISK.write_blocks(8, &[6; 512]).unwrap();
+    CACHE.write_blocks(0, &[5; 512]).unwrap();
+
+    // Run 0 in use, as by a task in the middle of a copy, while a timer
+    // interrupt, the other task, reads run 1.
+    let slot = CACHE.pin(0, Miss::Load, false).unwrap().unwrap();
+    let held = CACHE.buffers[slot].lock();
+    let previous = set_tick_hook(Some(read_run_1));
+    let deadline = ticks() + 3;
+    while !READ.load(Ordering::Relaxed) && ticks() < deadline {
+        idle();
+    }
+    set_tick_hook(previous);
+    drop(held);
+    CACHE.unpin(slot);
+    assert!(READ.load(Ordering::Relaxed), "run 1 was not read while run 0 was in use");
+    let mut read = [0u8; 512];
+    CACHE.read_blocks(0, &mut read).unwrap();
+    assert!(read.iter().all(|&byte| byte == 5));
+}
@@ -0,0 +1,212 @@
+//! Block devices and the registry drivers put them in.
+//!
+//! A driver registers each disk it finds under a name like `ata0` or
+//! `virtio0`, and the code mounting filesystems looks disks up by that name.
+//! There is no heap, so devices live in statics and are registered by
+//! reference.
+
+pub mod cache;
+pub mod partition;
+pub mod ramdisk;
+
+use core::fmt;
+use crate::sync::Mutex;
+
+/// The most devices the registry holds.
+pub const MAX_DEVICES: usize = 16;
+
+/// What a block device read or write failed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoError {
+    Timeout,
+    /// The device reported an error, with a code specific to the driver, like
+    /// the ATA error register.
+    DeviceError { code: u32 },
+    /// The blocks are past the end of the device.
+    OutOfRange,
+    ReadOnly,
+    /// The buffer is not a whole number of blocks.
+    BadBuffer,
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoError::Timeout => write!(f, "device timed out"),
+            IoError::DeviceError { code } => write!(f, "device error {:#x}", code),
+            IoError::OutOfRange => write!(f, "block out of range"),
+            IoError::ReadOnly => write!(f, "device is read-only"),
+            IoError::BadBuffer => write!(f, "buffer is not a whole number of blocks"),
+        }
+    }
+}
+
+/// A disk read and written in whole blocks.
+pub trait BlockDevice: Sync {
+    /// The block size in bytes.
+    fn block_size(&self) -> usize;
+
+    fn num_blocks(&self) -> u64;
+
+    /// Reads the blocks from `lba` on into `buffer`, whose length must be a
+    /// multiple of the block size, see [`check_request`].
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError>;
+
+    /// Writes `buffer` to the blocks from `lba` on, see [`BlockDevice::read_blocks`].
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError>;
+
+    /// Makes the blocks written so far reach the medium. Devices without a
+    /// write cache have nothing to do.
+    fn flush(&self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+/// Checks that `len` bytes from `lba` are whole blocks on `device`, which
+/// every [`BlockDevice`] does before a transfer.
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), IoError> {
+    if len % device.block_size() != 0 {
+        return Err(IoError::BadBuffer);
+    }
+    match lba.checked_add((len / device.block_size()) as u64) {
+        Some(end) if end <= device.num_blocks() => Ok(()),
+        _ => Err(IoError::OutOfRange),
+    }
+}
+
+/// Little endian fields of on-disk structures.
+pub(crate) fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+pub(crate) fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+pub(crate) fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// All [`MAX_DEVICES`] slots are taken.
+    Full,
+    NameTaken,
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegisterError::Full => write!(f, "too many block devices"),
+            RegisterError::NameTaken => write!(f, "device name already registered"),
+        }
+    }
+}
+
+/// A registered device and its name.
+#[derive(Clone, Copy)]
+pub struct Device {
+    pub name: &'static str,
+    pub device: &'static dyn BlockDevice,
+}
+
+impl fmt::Debug for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} blocks of {} bytes", self.name, self.device.num_blocks(), self.device.block_size())
+    }
+}
+
+static DEVICES: Mutex<[Option<Device>; MAX_DEVICES]> = Mutex::new("STORAGE_DEVICES", [None; MAX_DEVICES]);
+
+/// Makes `device` known as `name`.
+pub fn register_device(name: &'static str, device: &'static dyn BlockDevice) -> Result<(), RegisterError> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().flatten().any(|registered| registered.name == name) {
+        return Err(RegisterError::NameTaken);
+    }
+    let slot = devices.iter_mut().find(|slot| slot.is_none()).ok_or(RegisterError::Full)?;
+    *slot = Some(Device { name, device });
+    Ok(())
+}
+
+/// Returns the registered devices, in the order they were registered.
+pub fn devices() -> impl Iterator<Item = Device> {
+    let devices = *DEVICES.lock();
+    devices.into_iter().flatten()
+}
+
+/// Returns the device registered as `name`.
+pub fn device(name: &str) -> Option<&'static dyn BlockDevice> {
+    devices().find(|device| device.name == name).map(|device| device.device)
+}
+
+/// A block device over a buffer in memory, `N` bytes of `BLOCK_SIZE` byte blocks.
+pub struct MemBlockDevice<const N: usize, const BLOCK_SIZE: usize = 512> {
+    data: Mutex<[u8; N]>,
+    read_only: bool,
+}
+
+impl<const N: usize, const BLOCK_SIZE: usize> MemBlockDevice<N, BLOCK_SIZE> {
+    pub const fn new(read_only: bool) -> Self {
+        assert!(BLOCK_SIZE != 0 && N % BLOCK_SIZE == 0);
+        MemBlockDevice { data: Mutex::new("MEM_BLOCK_DEVICE", [0; N]), read_only }
+    }
+}
+
+impl<const N: usize, const BLOCK_SIZE: usize> BlockDevice for MemBlockDevice<N, BLOCK_SIZE> {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (N / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        check_request(self, lba, buffer.len())?;
+        let start = lba as usize * BLOCK_SIZE;
+        buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError> {
+        if self.read_only {
+            return Err(IoError::ReadOnly);
+        }
+        check_request(self, lba, buffer.len())?;
+        let start = lba as usize * BLOCK_SIZE;
+        self.data.lock()[start..start + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn mem_block_device_checks_requests() {
+    let disk = MemBlockDevice::<{ 4 * 512 }>::new(false);
+    let mut blocks = [0u8; 2 * 512];
+    for (i, byte) in blocks.iter_mut().enumerate() {
+        *byte = (i * 7) as u8;
+    }
+    disk.write_blocks(2, &blocks).unwrap();
+    let mut read_back = [0u8; 2 * 512];
+    disk.read_blocks(2, &mut read_back).unwrap();
+    assert!(read_back == blocks);
+    disk.read_blocks(1, &mut read_back[..512]).unwrap();
+    assert!(read_back[..512].iter().all(|&byte| byte == 0));
+
+    assert_eq!(disk.read_blocks(3, &mut read_back), Err(IoError::OutOfRange));
+    assert_eq!(disk.read_blocks(u64::MAX, &mut read_back), Err(IoError::OutOfRange));
+    assert_eq!(disk.write_blocks(0, &blocks[..100]), Err(IoError::BadBuffer));
+    assert_eq!(MemBlockDevice::<512>::new(true).write_blocks(0, &blocks[..512]), Err(IoError::ReadOnly));
+}
+
+#[test_case]
+fn registry_looks_devices_up_by_name() {
+    static DISK: MemBlockDevice<{ 2 * 1024 }, 1024> = MemBlockDevice::new(true);
+    assert_eq!(register_device("test-mem0", &DISK), Ok(()));
+    assert_eq!(register_device("test-mem0", &DISK), Err(RegisterError::NameTaken));
+    let disk = device("test-mem0").expect("registered device not found");
+    assert_eq!((disk.block_size(), disk.num_blocks()), (1024, 2));
+    assert!(devices().any(|device| device.name == "test-mem0"));
+    assert!(device("test-mem1").is_none());
+}
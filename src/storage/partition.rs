@@ -0,0 +1,628 @@
+//! MBR and GPT partition tables, and partitions as block devices of their own.
+//!
+//! [`register_partitions`] reads the table of a registered disk and registers
+//! each partition on it as a [`PartitionDevice`], named after the disk: `ata0p1`
+//! is the first entry of the table on `ata0`. A protective MBR, one with an
+//! entry of type 0xEE, leads to the GPT. When the primary GPT header or its
+//! entries fail their CRC, the backup header in the last block is used.
+
+use core::{fmt, ptr::addr_of_mut};
+use crate::{println, sync::Mutex};
+use super::{check_request, register_device, u16_at, u32_at, u64_at, BlockDevice, IoError, RegisterError};
+
+/// The largest block size tables are read in.
+const MAX_BLOCK_SIZE: usize = 4096;
+
+const MBR_SIGNATURE: usize = 510;
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// The boot indicator is one of these, anything else is boot code of a
+/// partition boot sector, like the one of a FAT volume without partitions.
+const MBR_INACTIVE: u8 = 0x00;
+const MBR_ACTIVE: u8 = 0x80;
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Header fields, from the start of the block.
+const GPT_HEADER_SIZE: usize = 12;
+const GPT_HEADER_CRC: usize = 16;
+const GPT_MY_LBA: usize = 24;
+const GPT_FIRST_USABLE: usize = 40;
+const GPT_LAST_USABLE: usize = 48;
+const GPT_ENTRIES_LBA: usize = 72;
+const GPT_ENTRY_COUNT: usize = 80;
+const GPT_ENTRY_SIZE: usize = 84;
+const GPT_ENTRIES_CRC: usize = 88;
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+/// Entries past these are ignored, real tables have 128.
+const GPT_MAX_ENTRIES: u32 = 4096;
+/// Entry fields.
+const ENTRY_TYPE: usize = 0;
+const ENTRY_UNIQUE: usize = 16;
+const ENTRY_FIRST_LBA: usize = 32;
+const ENTRY_LAST_LBA: usize = 40;
+const ENTRY_NAME: usize = 56;
+/// UTF-16 code units in a partition name.
+pub const NAME_UNITS: usize = 36;
+
+/// The most partitions registered over all disks.
+pub const MAX_PARTITIONS: usize = 32;
+/// The longest device name of a partition, the disk name and `p<number>`.
+const DEVICE_NAME_CAPACITY: usize = 24;
+
+/// The partition devices, the first `NEXT_PARTITION` of them registered.
+/// Only the slot at `NEXT_PARTITION` is written, with that lock held.
+static mut PARTITIONS: [Option<PartitionDevice>; MAX_PARTITIONS] = [const { None }; MAX_PARTITIONS];
+static NEXT_PARTITION: Mutex<usize> = Mutex::new("NEXT_PARTITION", 0);
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The CRC-32 of GPT, Ethernet and zlib, computed over bytes fed in pieces.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = CRC32_TABLE[(self.0 as u8 ^ byte) as usize] ^ self.0 >> 8;
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+/// A GUID in its on-disk layout, the first three fields little endian.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const ZERO: Guid = Guid([0; 16]);
+    pub const EFI_SYSTEM: Guid = Guid::new(0xC12A_7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+    pub const BASIC_DATA: Guid = Guid::new(0xEBD0_A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+    pub const LINUX_FILESYSTEM: Guid = Guid::new(0x0FC6_3DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+
+    /// The GUID written as `data1-data2-data3-data4`.
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let (a, b, c) = (data1.to_le_bytes(), data2.to_le_bytes(), data3.to_le_bytes());
+        Guid([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1],
+            data4[0], data4[1], data4[2], data4[3], data4[4], data4[5], data4[6], data4[7]])
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(f, "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-", u32_at(g, 0), u16_at(g, 4), u16_at(g, 6), g[8], g[9])?;
+        g[10..].iter().try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The system ID byte of an MBR entry.
+    Mbr(u8),
+    Gpt { type_guid: Guid, unique_guid: Guid, name: [u16; NAME_UNITS] },
+}
+
+/// A partition, in blocks of the disk it is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// The entry in the table, from 1. Empty entries keep their number.
+    pub number: u32,
+    pub start: u64,
+    pub blocks: u64,
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    /// The GPT partition name, empty for MBR partitions.
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        let units: &[u16] = match &self.kind {
+            PartitionKind::Gpt { name, .. } => name,
+            PartitionKind::Mbr(_) => &[],
+        };
+        char::decode_utf16(units.iter().copied().take_while(|&unit| unit != 0))
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+/// Which table the partitions came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Mbr,
+    Gpt,
+    /// The GPT, from the backup header as the primary one failed its checks.
+    GptBackup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    Io(IoError),
+    /// Block 0 holds no MBR.
+    NoTable,
+    /// Neither GPT header, with its entries, passed the checks.
+    BadGpt,
+    /// The blocks are smaller than an MBR or larger than [`MAX_BLOCK_SIZE`].
+    BlockSize,
+    /// All [`MAX_PARTITIONS`] are taken.
+    TooMany,
+    Register(RegisterError),
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionError::Io(err) => write!(f, "{}", err),
+            PartitionError::NoTable => write!(f, "no partition table"),
+            PartitionError::BadGpt => write!(f, "both GPT headers are corrupt"),
+            PartitionError::BlockSize => write!(f, "unsupported block size"),
+            PartitionError::TooMany => write!(f, "too many partitions"),
+            PartitionError::Register(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<IoError> for PartitionError {
+    fn from(err: IoError) -> Self {
+        PartitionError::Io(err)
+    }
+}
+
+impl From<RegisterError> for PartitionError {
+    fn from(err: RegisterError) -> Self {
+        PartitionError::Register(err)
+    }
+}
+
+/// Whether `blocks` blocks from `start` lie on `disk`.
+fn on_disk(disk: &dyn BlockDevice, start: u64, blocks: u64) -> bool {
+    start.checked_add(blocks).is_some_and(|end| end <= disk.num_blocks())
+}
+
+/// Reads the partition table of `disk`, calling `found` with each partition
+/// that lies on the disk.
+pub fn scan(disk: &dyn BlockDevice, mut found: impl FnMut(Partition)) -> Result<TableKind, PartitionError> {
+    let block_size = disk.block_size();
+    if !(512..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err(PartitionError::BlockSize);
+    }
+    let mut buffer = [0u8; MAX_BLOCK_SIZE];
+    let block = &mut buffer[..block_size];
+    disk.read_blocks(0, block)?;
+    if block[MBR_SIGNATURE..MBR_SIGNATURE + 2] != [0x55, 0xAA] {
+        return Err(PartitionError::NoTable);
+    }
+    let entries = block[MBR_ENTRIES..MBR_SIGNATURE].chunks_exact(MBR_ENTRY_SIZE);
+    if entries.clone().any(|entry| entry[0] != MBR_INACTIVE && entry[0] != MBR_ACTIVE) {
+        return Err(PartitionError::NoTable);
+    }
+    if entries.clone().any(|entry| entry[4] == MBR_TYPE_PROTECTIVE) {
+        return scan_gpt(disk, found);
+    }
+    for (number, entry) in (1..).zip(entries) {
+        let (kind, start, blocks) = (entry[4], u32_at(entry, 8) as u64, u32_at(entry, 12) as u64);
+        if kind != MBR_TYPE_EMPTY && blocks != 0 && on_disk(disk, start, blocks) {
+            found(Partition { number, start, blocks, kind: PartitionKind::Mbr(kind) });
+        }
+    }
+    Ok(TableKind::Mbr)
+}
+
+/// The fields of a GPT header that checked out.
+struct GptHeader {
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    entries: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// Reads the GPT header at `lba`, `None` if it fails a check.
+fn read_gpt_header(disk: &dyn BlockDevice, lba: u64, block: &mut [u8]) -> Result<Option<GptHeader>, IoError> {
+    disk.read_blocks(lba, block)?;
+    let size = u32_at(block, GPT_HEADER_SIZE) as usize;
+    if &block[..8] != GPT_SIGNATURE || !(GPT_MIN_HEADER_SIZE..=block.len()).contains(&size) {
+        return Ok(None);
+    }
+    let stored_crc = u32_at(block, GPT_HEADER_CRC);
+    block[GPT_HEADER_CRC..GPT_HEADER_CRC + 4].fill(0);
+    let header = GptHeader {
+        first_usable: u64_at(block, GPT_FIRST_USABLE),
+        last_usable: u64_at(block, GPT_LAST_USABLE),
+        entries_lba: u64_at(block, GPT_ENTRIES_LBA),
+        entries: u32_at(block, GPT_ENTRY_COUNT).min(GPT_MAX_ENTRIES),
+        entry_size: u32_at(block, GPT_ENTRY_SIZE) as usize,
+        entries_crc: u32_at(block, GPT_ENTRIES_CRC),
+    };
+    // Entries are 128 bytes times a power of two, and none spans two blocks.
+    let entry_size_ok = header.entry_size >= GPT_MIN_ENTRY_SIZE && header.entry_size.is_power_of_two()
+        && header.entry_size <= block.len();
+    let array_blocks = (header.entries as u64 * header.entry_size as u64).div_ceil(block.len() as u64);
+    let valid = crc32(&block[..size]) == stored_crc
+        && u64_at(block, GPT_MY_LBA) == lba
+        && entry_size_ok
+        && on_disk(disk, header.entries_lba, array_blocks);
+    Ok(valid.then_some(header))
+}
+
+/// Calls `f` with each entry of the array `header` points to.
+fn for_each_gpt_entry(disk: &dyn BlockDevice, header: &GptHeader, block: &mut [u8], mut f: impl FnMut(&[u8])) -> Result<(), IoError> {
+    let per_block = block.len() / header.entry_size;
+    for index in 0..header.entries as usize {
+        if index % per_block == 0 {
+            disk.read_blocks(header.entries_lba + (index / per_block) as u64, block)?;
+        }
+        let offset = index % per_block * header.entry_size;
+        f(&block[offset..offset + header.entry_size]);
+    }
+    Ok(())
+}
+
+fn scan_gpt(disk: &dyn BlockDevice, mut found: impl FnMut(Partition)) -> Result<TableKind, PartitionError> {
+    let mut buffer = [0u8; MAX_BLOCK_SIZE];
+    let block = &mut buffer[..disk.block_size()];
+    let backup_lba = disk.num_blocks().saturating_sub(1);
+    for (lba, kind) in [(1, TableKind::Gpt), (backup_lba, TableKind::GptBackup)] {
+        let Some(header) = read_gpt_header(disk, lba, block)? else {
+            continue;
+        };
+        let mut crc = Crc32::new();
+        for_each_gpt_entry(disk, &header, block, |entry| crc.update(entry))?;
+        if crc.finish() != header.entries_crc {
+            continue;
+        }
+        let mut number = 0;
+        for_each_gpt_entry(disk, &header, block, |entry| {
+            number += 1;
+            let type_guid = Guid(entry[ENTRY_TYPE..ENTRY_TYPE + 16].try_into().unwrap());
+            let (first, last) = (u64_at(entry, ENTRY_FIRST_LBA), u64_at(entry, ENTRY_LAST_LBA));
+            if type_guid == Guid::ZERO || first > last || first < header.first_usable || last > header.last_usable
+                || !on_disk(disk, first, last - first + 1) {
+                return;
+            }
+            let mut name = [0; NAME_UNITS];
+            for (i, unit) in name.iter_mut().enumerate() {
+                *unit = u16_at(entry, ENTRY_NAME + 2 * i);
+            }
+            let unique_guid = Guid(entry[ENTRY_UNIQUE..ENTRY_UNIQUE + 16].try_into().unwrap());
+            found(Partition {
+                number,
+                start: first,
+                blocks: last - first + 1,
+                kind: PartitionKind::Gpt { type_guid, unique_guid, name },
+            });
+        })?;
+        return Ok(kind);
+    }
+    Err(PartitionError::BadGpt)
+}
+
+/// The device name of a partition.
+struct DeviceName {
+    bytes: [u8; DEVICE_NAME_CAPACITY],
+    len: usize,
+}
+
+impl fmt::Write for DeviceName {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// A partition as a block device, which offsets and bounds the I/O to the
+/// disk it is on.
+pub struct PartitionDevice {
+    disk: &'static dyn BlockDevice,
+    partition: Partition,
+    name: DeviceName,
+}
+
+impl PartitionDevice {
+    /// The partition `partition` of `disk`, named after the disk `disk_name`.
+    /// Names too long are cut short.
+    pub fn new(disk_name: &str, disk: &'static dyn BlockDevice, partition: Partition) -> Self {
+        let mut name = DeviceName { bytes: [0; DEVICE_NAME_CAPACITY], len: 0 };
+        let _ = fmt::write(&mut name, format_args!("{}p{}", disk_name, partition.number));
+        PartitionDevice { disk, partition, name }
+    }
+
+    pub fn partition(&self) -> &Partition {
+        &self.partition
+    }
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name.bytes[..self.name.len]).unwrap_or("")
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.partition.blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        check_request(self, lba, buffer.len())?;
+        self.disk.read_blocks(self.partition.start + lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError> {
+        check_request(self, lba, buffer.len())?;
+        self.disk.write_blocks(self.partition.start + lba, buffer)
+    }
+
+    fn flush(&self) -> Result<(), IoError> {
+        self.disk.flush()
+    }
+}
+
+/// Registers each partition on the disk registered as `disk_name`, returning
+/// the kind of table and how many partitions there were.
+pub fn register_partitions(disk_name: &'static str, disk: &'static dyn BlockDevice) -> Result<(TableKind, usize), PartitionError> {
+    let mut count = 0;
+    let mut error = None;
+    let table = scan(disk, |partition| {
+        if error.is_some() {
+            return;
+        }
+        let mut next = NEXT_PARTITION.lock();
+        if *next == MAX_PARTITIONS {
+            error = Some(PartitionError::TooMany);
+            return;
+        }
+        // Nothing refers to the slots from `next` on, not even after a failed
+        // registration, so the slot can be written and taken only on success.
+        let device: &'static PartitionDevice = unsafe {
+            let slot = &mut *addr_of_mut!(PARTITIONS[*next]);
+            slot.insert(PartitionDevice::new(disk_name, disk, partition))
+        };
+        match register_device(device.name(), device) {
+            Ok(()) => {
+                *next += 1;
+                count += 1;
+            },
+            Err(err) => error = Some(err.into()),
+        }
+    })?;
+    match error {
+        Some(err) => Err(err),
+        None => Ok((table, count)),
+    }
+}
+
+/// Registers the partitions of every disk registered so far.
+pub fn init() {
+    for disk in super::devices() {
+        match register_partitions(disk.name, disk.device) {
+            Ok((table, count)) => println!("{}: {:?} with {} partitions", disk.name, table, count),
+            Err(PartitionError::NoTable) => {},
+            Err(err) => println!("{}: {}", disk.name, err),
+        }
+    }
+}
+
+#[cfg(test)]
+const TEST_BLOCKS: u64 = 64;
+#[cfg(test)]
+type TestDisk = super::MemBlockDevice<{ TEST_BLOCKS as usize * 512 }>;
+
+#[cfg(test)]
+fn write_mbr(disk: &dyn BlockDevice, entries: &[(u8, u32, u32)]) {
+    let mut block = [0u8; 512];
+    for (entry, &(kind, start, blocks)) in block[MBR_ENTRIES..].chunks_exact_mut(MBR_ENTRY_SIZE).zip(entries) {
+        entry[4] = kind;
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    }
+    block[MBR_SIGNATURE..].copy_from_slice(&[0x55, 0xAA]);
+    disk.write_blocks(0, &block).unwrap();
+}
+
+/// Writes a GPT of four entries in one block, with the partitions `root` in
+/// blocks 3 to 30 and `EFI` in 31 to 61, and its backup.
+#[cfg(test)]
+fn write_gpt(disk: &dyn BlockDevice) {
+    write_mbr(disk, &[(MBR_TYPE_PROTECTIVE, 1, TEST_BLOCKS as u32 - 1)]);
+    let mut entries = [0u8; 512];
+    for (entry, (type_guid, first, last, name)) in entries.chunks_exact_mut(128)
+        .zip([(Guid::LINUX_FILESYSTEM, 3u64, 30u64, "root"), (Guid::EFI_SYSTEM, 31, 61, "EFI")]) {
+        entry[ENTRY_TYPE..ENTRY_TYPE + 16].copy_from_slice(&type_guid.0);
+        entry[ENTRY_UNIQUE..ENTRY_UNIQUE + 16].fill(first as u8);
+        entry[ENTRY_FIRST_LBA..ENTRY_FIRST_LBA + 8].copy_from_slice(&first.to_le_bytes());
+        entry[ENTRY_LAST_LBA..ENTRY_LAST_LBA + 8].copy_from_slice(&last.to_le_bytes());
+        for (i, unit) in name.encode_utf16().enumerate() {
+            entry[ENTRY_NAME + 2 * i..ENTRY_NAME + 2 * i + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let last = TEST_BLOCKS - 1;
+    for (my_lba, alternate_lba, entries_lba) in [(1, last, 2), (last, 1, last - 1)] {
+        let mut header = [0u8; 512];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[GPT_HEADER_SIZE..GPT_HEADER_SIZE + 4].copy_from_slice(&(GPT_MIN_HEADER_SIZE as u32).to_le_bytes());
+        header[GPT_MY_LBA..GPT_MY_LBA + 8].copy_from_slice(&my_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[GPT_FIRST_USABLE..GPT_FIRST_USABLE + 8].copy_from_slice(&3u64.to_le_bytes());
+        header[GPT_LAST_USABLE..GPT_LAST_USABLE + 8].copy_from_slice(&(last - 2).to_le_bytes());
+        header[GPT_ENTRIES_LBA..GPT_ENTRIES_LBA + 8].copy_from_slice(&entries_lba.to_le_bytes());
+        header[GPT_ENTRY_COUNT..GPT_ENTRY_COUNT + 4].copy_from_slice(&4u32.to_le_bytes());
+        header[GPT_ENTRY_SIZE..GPT_ENTRY_SIZE + 4].copy_from_slice(&128u32.to_le_bytes());
+        header[GPT_ENTRIES_CRC..GPT_ENTRIES_CRC + 4].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let crc = crc32(&header[..GPT_MIN_HEADER_SIZE]);
+        header[GPT_HEADER_CRC..GPT_HEADER_CRC + 4].copy_from_slice(&crc.to_le_bytes());
+        disk.write_blocks(my_lba, &header).unwrap();
+        disk.write_blocks(entries_lba, &entries).unwrap();
+    }
+}
+
+/// Flips a bit of byte `offset` of block `lba`.
+#[cfg(test)]
+fn corrupt(disk: &dyn BlockDevice, lba: u64, offset: usize) {
+    let mut block = [0u8; 512];
+    disk.read_blocks(lba, &mut block).unwrap();
+    block[offset] ^= 1;
+    disk.write_blocks(lba, &block).unwrap();
+}
+
+#[cfg(test)]
+fn scan_all(disk: &dyn BlockDevice) -> (Result<TableKind, PartitionError>, [Option<Partition>; 4]) {
+    let mut partitions = [None; 4];
+    let mut count = 0;
+    let table = scan(disk, |partition| {
+        partitions[count] = Some(partition);
+        count += 1;
+    });
+    (table, partitions)
+}
+
+#[test_case]
+fn crc32_check_value() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    let mut crc = Crc32::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.finish(), 0xCBF4_3926);
+    assert_eq!(crc32(&[]), 0);
+}
+
+#[test_case]
+fn mbr_partitions_are_offset_and_bounded() {
+    static DISK: TestDisk = TestDisk::new(false);
+    // The last entry runs past the end of the disk.
+    write_mbr(&DISK, &[(0x83, 4, 10), (MBR_TYPE_EMPTY, 0, 0), (0x0C, 20, 40), (0x83, 60, 10)]);
+    let (table, partitions) = scan_all(&DISK);
+    assert_eq!(table, Ok(TableKind::Mbr));
+    assert_eq!(partitions[0], Some(Partition { number: 1, start: 4, blocks: 10, kind: PartitionKind::Mbr(0x83) }));
+    assert_eq!(partitions[1], Some(Partition { number: 3, start: 20, blocks: 40, kind: PartitionKind::Mbr(0x0C) }));
+    assert_eq!(partitions[2], None);
+    assert_eq!(partitions[0].unwrap().name().count(), 0);
+
+    assert_eq!(register_partitions("test-mbr0", &DISK), Ok((TableKind::Mbr, 2)));
+    let second = super::device("test-mbr0p3").expect("partition not registered");
+    assert_eq!(second.num_blocks(), 40);
+    let block = [0xC3u8; 512];
+    second.write_blocks(39, &block).unwrap();
+    let mut read_back = [0u8; 512];
+    DISK.read_blocks(59, &mut read_back).unwrap();
+    assert!(read_back == block);
+    assert_eq!(second.read_blocks(40, &mut read_back), Err(IoError::OutOfRange));
+    assert_eq!(second.read_blocks(0, &mut read_back[..100]), Err(IoError::BadBuffer));
+}
+
+#[test_case]
+fn failed_registration_keeps_the_partition_slot() {
+    static DISK: TestDisk = TestDisk::new(false);
+    write_mbr(&DISK, &[(0x83, 4, 10)]);
+    assert_eq!(register_partitions("test-mbr1", &DISK), Ok((TableKind::Mbr, 1)));
+    let next = *NEXT_PARTITION.lock();
+    // The names are taken now.
+    assert_eq!(
+        register_partitions("test-mbr1", &DISK),
+        Err(PartitionError::Register(RegisterError::NameTaken)),
+    );
+    assert_eq!(*NEXT_PARTITION.lock(), next);
+    assert_eq!(super::device("test-mbr1p1").map(|device| device.num_blocks()), Some(10));
+}
+
+#[test_case]
+fn boot_sector_without_partitions_is_no_table() {
+    static DISK: TestDisk = TestDisk::new(false);
+    // A FAT boot sector has the signature too, but boot code where the entries would be.
+    write_mbr(&DISK, &[(0x83, 4, 10)]);
+    corrupt(&DISK, 0, MBR_ENTRIES);
+    assert_eq!(scan_all(&DISK).0, Err(PartitionError::NoTable));
+    DISK.write_blocks(0, &[0; 512]).unwrap();
+    assert_eq!(scan_all(&DISK).0, Err(PartitionError::NoTable));
+}
+
+#[test_case]
+fn gpt_partitions() {
+    static DISK: TestDisk = TestDisk::new(false);
+    write_gpt(&DISK);
+    let (table, partitions) = scan_all(&DISK);
+    assert_eq!(table, Ok(TableKind::Gpt));
+    let root = partitions[0].expect("no first partition");
+    assert_eq!((root.number, root.start, root.blocks), (1, 3, 28));
+    assert!(root.name().eq("root".chars()));
+    let PartitionKind::Gpt { type_guid, unique_guid, .. } = root.kind else {
+        panic!("not a GPT partition");
+    };
+    assert_eq!(type_guid, Guid::LINUX_FILESYSTEM);
+    assert_eq!(unique_guid, Guid([3; 16]));
+    let efi = partitions[1].expect("no second partition");
+    assert_eq!((efi.number, efi.start, efi.blocks), (2, 31, 31));
+    assert!(efi.name().eq("EFI".chars()));
+    assert_eq!(partitions[2], None);
+
+    let guid = crate::pci::ids::StackString::format(format_args!("{}", Guid::EFI_SYSTEM));
+    assert_eq!(guid.as_str(), "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+}
+
+#[test_case]
+fn corrupt_primary_gpt_falls_back_to_backup() {
+    static DISK: TestDisk = TestDisk::new(false);
+    write_gpt(&DISK);
+    // The header CRC catches a changed first usable block.
+    corrupt(&DISK, 1, GPT_FIRST_USABLE);
+    let (table, partitions) = scan_all(&DISK);
+    assert_eq!(table, Ok(TableKind::GptBackup));
+    assert_eq!(partitions[0].map(|root| (root.start, root.blocks)), Some((3, 28)));
+    assert!(partitions[1].is_some());
+
+    // The entries CRC catches a changed entry.
+    write_gpt(&DISK);
+    corrupt(&DISK, 2, ENTRY_LAST_LBA);
+    assert_eq!(scan_all(&DISK).0, Ok(TableKind::GptBackup));
+
+    corrupt(&DISK, TEST_BLOCKS - 1, GPT_ENTRY_COUNT);
+    assert_eq!(scan_all(&DISK).0, Err(PartitionError::BadGpt));
+}
@@ -0,0 +1,270 @@
+//! A RAM disk holding a filesystem image, registered as `ram0`.
+//!
+//! The image either comes with the kernel, built with the `ramdisk` feature
+//! and `KRABBOS_RAMDISK` naming the file to embed, or is left in memory by the
+//! bootloader and handed to [`init_from_region`]. The `bootloader` crate has
+//! no way to load one, but a Multiboot2 stub calling `kernel_main_raw` could
+//! pass a module. Writes change the copy in memory only. A CD image gets the
+//! 2048 byte blocks of a CD-ROM, anything else 512 byte ones.
+
+use core::fmt;
+use spin::Once;
+use crate::{
+    memory::{frame_allocator::ReserveError, FRAME_ALLOCATOR, MAPPER},
+    println,
+    sync::Mutex,
+};
+use super::{check_request, register_device, BlockDevice, IoError, RegisterError};
+
+pub const BLOCK_SIZE: usize = 512;
+/// The block size of CD images, the sector size of ISO 9660.
+pub const CD_BLOCK_SIZE: usize = 2048;
+
+#[cfg(feature = "ramdisk")]
+const IMAGE: &[u8] = include_bytes!(env!("KRABBOS_RAMDISK"));
+#[cfg(feature = "ramdisk")]
+const IMAGE_BLOCK_SIZE: usize = block_size_of(IMAGE);
+/// The embedded image, padded with zeroes to whole blocks, in writable memory.
+#[cfg(feature = "ramdisk")]
+static mut IMAGE_BLOCKS: [u8; IMAGE.len().next_multiple_of(IMAGE_BLOCK_SIZE)] = padded(IMAGE);
+
+static RAM0: Once<RamDisk> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamDiskError {
+    /// The memory is not a whole number of blocks.
+    Length,
+    /// The block size is not a power of two from 512 to 4096 bytes.
+    BlockSize,
+    /// The frames of the image could not be taken from the frame allocator.
+    Reserve(ReserveError),
+    /// Memory management is not set up.
+    NoMemory,
+    Register(RegisterError),
+}
+
+impl fmt::Display for RamDiskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RamDiskError::Length => write!(f, "image is not a whole number of blocks"),
+            RamDiskError::BlockSize => write!(f, "unsupported block size"),
+            RamDiskError::Reserve(err) => write!(f, "cannot reserve the image: {}", err),
+            RamDiskError::NoMemory => write!(f, "no memory management"),
+            RamDiskError::Register(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<ReserveError> for RamDiskError {
+    fn from(err: ReserveError) -> Self {
+        RamDiskError::Reserve(err)
+    }
+}
+
+impl From<RegisterError> for RamDiskError {
+    fn from(err: RegisterError) -> Self {
+        RamDiskError::Register(err)
+    }
+}
+
+/// A block device over memory the kernel owns for good.
+pub struct RamDisk {
+    data: Mutex<&'static mut [u8]>,
+    block_size: usize,
+    blocks: u64,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// A RAM disk of [`BLOCK_SIZE`] byte blocks over `data`, which must be a
+    /// whole number of them. A read-only one fails writes with
+    /// [`IoError::ReadOnly`].
+    pub fn new(data: &'static mut [u8], read_only: bool) -> Result<Self, RamDiskError> {
+        Self::with_block_size(data, BLOCK_SIZE, read_only)
+    }
+
+    /// A RAM disk of `block_size` byte blocks over `data`, like
+    /// [`CD_BLOCK_SIZE`] for a CD image.
+    pub fn with_block_size(data: &'static mut [u8], block_size: usize, read_only: bool) -> Result<Self, RamDiskError> {
+        if !block_size.is_power_of_two() || !(512..=4096).contains(&block_size) {
+            return Err(RamDiskError::BlockSize);
+        }
+        if data.len() % block_size != 0 {
+            return Err(RamDiskError::Length);
+        }
+        let blocks = (data.len() / block_size) as u64;
+        Ok(RamDisk { data: Mutex::new("RAM_DISK", data), block_size, blocks, read_only })
+    }
+
+    /// A RAM disk over the `len` bytes at physical address `start`, whose frames
+    /// are reserved so that the frame allocator never hands them out. The image
+    /// is padded with zeroes to whole blocks, of the size [`block_size_of`]
+    /// picks for it.
+    ///
+    /// ## Safety
+    ///
+    /// The memory must hold nothing but the image up to the end of its last
+    /// frame, and nothing else may use it.
+    pub unsafe fn from_region(start: u64, len: u64, read_only: bool) -> Result<Self, RamDiskError> {
+        let offset = MAPPER.lock().as_ref().ok_or(RamDiskError::NoMemory)?.phys_offset();
+        let image = unsafe { core::slice::from_raw_parts((start + offset) as *const u8, len as usize) };
+        let block_size = block_size_of(image);
+        let padded = len.next_multiple_of(block_size as u64);
+        FRAME_ALLOCATOR.lock().as_mut().ok_or(RamDiskError::NoMemory)?.reserve(start, start + padded)?;
+        let data = unsafe { core::slice::from_raw_parts_mut((start + offset) as *mut u8, padded as usize) };
+        data[len as usize..].fill(0);
+        Self::with_block_size(data, block_size, read_only)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+        check_request(self, lba, buffer.len())?;
+        let start = lba as usize * self.block_size;
+        buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), IoError> {
+        if self.read_only {
+            return Err(IoError::ReadOnly);
+        }
+        check_request(self, lba, buffer.len())?;
+        let start = lba as usize * self.block_size;
+        self.data.lock()[start..start + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+/// The block size for `image`: [`CD_BLOCK_SIZE`] when it holds an ISO 9660
+/// volume descriptor at sector 16, else [`BLOCK_SIZE`].
+pub const fn block_size_of(image: &[u8]) -> usize {
+    const ID: &[u8] = b"CD001";
+    let at = 16 * CD_BLOCK_SIZE + 1;
+    if image.len() < at + ID.len() {
+        return BLOCK_SIZE;
+    }
+    let mut i = 0;
+    while i < ID.len() {
+        if image[at + i] != ID[i] {
+            return BLOCK_SIZE;
+        }
+        i += 1;
+    }
+    CD_BLOCK_SIZE
+}
+
+#[cfg(feature = "ramdisk")]
+const fn padded<const N: usize>(image: &[u8]) -> [u8; N] {
+    let mut blocks = [0; N];
+    let mut i = 0;
+    while i < image.len() {
+        blocks[i] = image[i];
+        i += 1;
+    }
+    blocks
+}
+
+/// Registers `disk` as `ram0`, unless there is one already.
+fn register(disk: RamDisk) -> Result<&'static RamDisk, RamDiskError> {
+    let mut created = false;
+    let ram0 = RAM0.call_once(|| {
+        created = true;
+        disk
+    });
+    if !created {
+        return Err(RamDiskError::Register(RegisterError::NameTaken));
+    }
+    register_device("ram0", ram0)?;
+    println!("ram0: {} KiB{}{}", ram0.blocks * ram0.block_size as u64 / 1024,
+             if ram0.block_size == CD_BLOCK_SIZE { ", CD image" } else { "" },
+             if ram0.read_only { ", read-only" } else { "" });
+    Ok(ram0)
+}
+
+/// Registers the image at physical `start..start + len` as `ram0`, see
+/// [`RamDisk::from_region`].
+///
+/// ## Safety
+///
+/// As for [`RamDisk::from_region`].
+pub unsafe fn init_from_region(start: u64, len: u64, read_only: bool) -> Result<&'static RamDisk, RamDiskError> {
+    register(unsafe { RamDisk::from_region(start, len, read_only)? })
+}
+
+/// Registers the image embedded with the `ramdisk` feature as `ram0`, if
+/// the kernel has one.
+pub fn init() -> Option<&'static RamDisk> {
+    #[cfg(feature = "ramdisk")]
+    {
+        // Only `RAM0` ever takes the buffer, and only once.
+        let data = unsafe { &mut *core::ptr::addr_of_mut!(IMAGE_BLOCKS) };
+        match RamDisk::with_block_size(data, IMAGE_BLOCK_SIZE, false).and_then(register) {
+            Ok(ram0) => return Some(ram0),
+            Err(err) => println!("ram0: {}", err),
+        }
+    }
+    None
+}
+
+#[test_case]
+fn ram_disk_reads_writes_and_refuses() {
+    static mut DATA: [u8; 4 * BLOCK_SIZE] = [0; 4 * BLOCK_SIZE];
+    static mut READ_ONLY: [u8; BLOCK_SIZE] = [0x77; BLOCK_SIZE];
+    static mut UNEVEN: [u8; 1000] = [0; 1000];
+    let disk = RamDisk::new(unsafe { &mut *core::ptr::addr_of_mut!(DATA) }, false).unwrap();
+    assert_eq!(disk.num_blocks(), 4);
+    let blocks = [0x5Au8; 2 * BLOCK_SIZE];
+    disk.write_blocks(2, &blocks).unwrap();
+    let mut read_back = [0u8; 2 * BLOCK_SIZE];
+    disk.read_blocks(2, &mut read_back).unwrap();
+    assert!(read_back == blocks);
+    assert_eq!(disk.read_blocks(3, &mut read_back), Err(IoError::OutOfRange));
+    assert_eq!(disk.write_blocks(0, &blocks[..10]), Err(IoError::BadBuffer));
+
+    let disk = RamDisk::new(unsafe { &mut *core::ptr::addr_of_mut!(READ_ONLY) }, true).unwrap();
+    assert_eq!(disk.write_blocks(0, &blocks[..BLOCK_SIZE]), Err(IoError::ReadOnly));
+    disk.read_blocks(0, &mut read_back[..BLOCK_SIZE]).unwrap();
+    assert!(read_back[..BLOCK_SIZE].iter().all(|&byte| byte == 0x77));
+
+    assert!(matches!(RamDisk::new(unsafe { &mut *core::ptr::addr_of_mut!(UNEVEN) }, false), Err(RamDiskError::Length)));
+}
+
+#[test_case]
+fn ram_disks_take_other_block_sizes() {
+    static mut DATA: [u8; 3 * CD_BLOCK_SIZE] = [0; 3 * CD_BLOCK_SIZE];
+    let data = unsafe { &mut *core::ptr::addr_of_mut!(DATA) };
+    assert_eq!(block_size_of(data), BLOCK_SIZE);
+    let disk = RamDisk::with_block_size(data, CD_BLOCK_SIZE, false).unwrap();
+    assert_eq!((disk.block_size(), disk.num_blocks()), (CD_BLOCK_SIZE, 3));
+    let block = [0x3Cu8; CD_BLOCK_SIZE];
+    disk.write_blocks(2, &block).unwrap();
+    let mut read_back = [0u8; CD_BLOCK_SIZE];
+    disk.read_blocks(2, &mut read_back).unwrap();
+    assert!(read_back == block);
+    assert_eq!(disk.read_blocks(0, &mut read_back[..BLOCK_SIZE]), Err(IoError::BadBuffer));
+
+    static mut ODD: [u8; 3000] = [0; 3000];
+    assert!(matches!(RamDisk::with_block_size(unsafe { &mut *core::ptr::addr_of_mut!(ODD) }, 1000, true), Err(RamDiskError::BlockSize)));
+    assert_eq!(block_size_of(include_bytes!("../../fixtures/iso9660.iso")), CD_BLOCK_SIZE);
+    assert_eq!(block_size_of(include_bytes!("../../fixtures/initrd.tar")), BLOCK_SIZE);
+}
+
+#[test_case]
+fn frames_in_use_cannot_back_a_ram_disk() {
+    // The first usable frame went to the page tables long ago, an initrd
+    // reported there must not be trusted.
+    let first = FRAME_ALLOCATOR.lock().as_ref().unwrap().memory_map().iter()
+        .find(|region| region.kind == crate::memory::MemoryRegionKind::Usable)
+        .unwrap().start.next_multiple_of(4096);
+    let result = unsafe { RamDisk::from_region(first, BLOCK_SIZE as u64, true) };
+    assert!(matches!(result, Err(RamDiskError::Reserve(ReserveError::InUse))));
+}
@@ -0,0 +1,195 @@
+//! Spinlock wrapper with a deadlock-detecting debug mode.
+//!
+//! When a lock is already held by the current CPU and the acquiring code runs
+//! with interrupts disabled (an interrupt handler, or a `cli` section), nothing
+//! can ever release it: the holder is the code we interrupted. Plain
+//! `spin::Mutex` spins forever in that case and the machine silently freezes.
+//! With `debug_assertions` enabled this wrapper records the caller and the CPU
+//! that took the lock and panics with `deadlock on <NAME>` instead. A lock held
+//! by another CPU is waited for, that CPU releases it eventually.
+
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+    panic::Location,
+};
+#[cfg(debug_assertions)]
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::tables::RFlags;
+
+pub struct Mutex<T> {
+    name: &'static str,
+    inner: spin::Mutex<T>,
+    /// Where the lock was last acquired, null when unlocked.
+    #[cfg(debug_assertions)]
+    owner: AtomicPtr<Location<'static>>,
+    /// The index of the CPU holding the lock.
+    #[cfg(debug_assertions)]
+    owner_cpu: AtomicUsize,
+}
+
+/// Returned by [`Mutex::deadlock_check`] when acquiring the lock could never succeed.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadlock {
+    /// The name the lock was created with.
+    pub name: &'static str,
+    /// The caller currently holding the lock, if known.
+    pub owner: Option<&'static Location<'static>>,
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadlock on {}", self.name)?;
+        if let Some(owner) = self.owner {
+            write!(f, " (held since {})", owner)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Mutex {
+            name,
+            inner: spin::Mutex::new(value),
+            #[cfg(debug_assertions)]
+            owner: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(debug_assertions)]
+            owner_cpu: AtomicUsize::new(0),
+        }
+    }
+
+    /// Spins until the lock is acquired.
+    ///
+    /// In debug builds this panics instead of spinning when the lock is held and
+    /// interrupts are disabled, see [`Mutex::deadlock_check`].
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let caller = Location::caller();
+        loop {
+            if let Some(guard) = self.try_lock_from(caller) {
+                return guard;
+            }
+            #[cfg(debug_assertions)]
+            if let Err(deadlock) = self.deadlock_check() {
+                panic!("{}", deadlock);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Acquires the lock if it is free.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.try_lock_from(Location::caller())
+    }
+
+    fn try_lock_from(&self, _caller: &'static Location<'static>) -> Option<MutexGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+        #[cfg(debug_assertions)]
+        {
+            self.owner.store(_caller as *const _ as *mut _, Ordering::Relaxed);
+            self.owner_cpu.store(crate::smp::cpu_index(), Ordering::Relaxed);
+        }
+        Some(MutexGuard { lock: self, inner })
+    }
+
+    /// Returns an error if the lock is held by the current CPU while interrupts
+    /// are disabled.
+    ///
+    /// The holder can then only be the code we interrupted (or the caller
+    /// itself), so spinning would never end. Without `debug_assertions` the
+    /// holding CPU is not recorded and any holder counts.
+    pub fn deadlock_check(&self) -> Result<(), Deadlock> {
+        #[cfg(debug_assertions)]
+        let held_here = self.owner_cpu.load(Ordering::Relaxed) == crate::smp::cpu_index();
+        #[cfg(not(debug_assertions))]
+        let held_here = true;
+        if self.inner.is_locked() && held_here && !RFlags::read().contains(RFlags::INTERRUPT_FLAG) {
+            return Err(Deadlock { name: self.name, owner: self.owner() });
+        }
+        Ok(())
+    }
+
+    /// Returns where the lock was acquired if it is currently held.
+    ///
+    /// Always `None` without `debug_assertions`.
+    pub fn owner(&self) -> Option<&'static Location<'static>> {
+        #[cfg(debug_assertions)]
+        {
+            let owner = self.owner.load(Ordering::Relaxed);
+            // SAFETY: only ever set from `Location::caller()`, which is 'static.
+            unsafe { owner.as_ref() }
+        }
+        #[cfg(not(debug_assertions))]
+        None
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Releases the lock regardless of who holds it.
+    ///
+    /// ## Safety
+    ///
+    /// Only meant for the panic path, where the holder will never run again.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(ptr::null_mut(), Ordering::Relaxed);
+        unsafe { self.inner.force_unlock(); }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // The inner guard is dropped right after this, still inside the critical section.
+        #[cfg(debug_assertions)]
+        self.lock.owner.store(ptr::null_mut(), Ordering::Relaxed);
+        #[cfg(not(debug_assertions))]
+        let _ = self.lock;
+    }
+}
+
+#[test_case]
+fn relock_with_interrupts_disabled_is_detected() {
+    use core::arch::asm;
+
+    let lock = Mutex::new("TEST_LOCK", 0u8);
+    let int_enabled = RFlags::read().contains(RFlags::INTERRUPT_FLAG);
+    let guard = lock.lock();
+    unsafe { asm!("cli", options(preserves_flags, nostack)); }
+    let result = lock.deadlock_check();
+    if int_enabled {
+        unsafe { asm!("sti", options(preserves_flags, nostack)); }
+    }
+    drop(guard);
+    let deadlock = result.unwrap_err();
+    assert_eq!(deadlock.name, "TEST_LOCK");
+    #[cfg(debug_assertions)]
+    assert!(deadlock.owner.is_some());
+    assert!(lock.deadlock_check().is_ok());
+}
@@ -0,0 +1,88 @@
+//! Legacy far `call` gate system call entry.
+//!
+//! Old user code enters the kernel with `call far sel:0` through a DPL 3 call
+//! gate in the GDT, see [`crate::tables::gdt::kernel_call_gate`]. The register
+//! convention is the one of the `int 0x80` gate. The CPU ignores the offset of
+//! the far pointer and only pushes the return CS:RIP (plus SS:RSP when coming
+//! from ring 3), so RFLAGS is not saved and the gate returns with `retfq`.
+
+use core::arch::global_asm;
+use super::GeneralRegisters;
+
+extern "C" {
+    /// Entry point installed in the call gate, not callable from Rust.
+    pub fn call_gate_entry();
+}
+
+// Nothing aligns the stack on a far call, so the handler runs on an aligned copy
+// of RSP kept in RBP, which is saved with the other registers.
+global_asm!(
+    ".global call_gate_entry",
+    "call_gate_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "mov rbp, rsp",
+    "and rsp, -16",
+    "cld",
+    "call {handler}",
+    "mov rsp, rbp",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "retfq",
+    handler = sym call_gate_handler,
+);
+
+extern "C" fn call_gate_handler(regs: &mut GeneralRegisters) {
+    super::dispatch_registers(regs);
+}
+
+#[test_case]
+fn far_call_through_gate_dispatches() {
+    use core::arch::asm;
+    use crate::tables::gdt::kernel_call_gate;
+
+    /// The `m16:32` operand of a far call, the offset is ignored for call gates.
+    #[repr(C, packed)]
+    struct FarPointer {
+        offset: u32,
+        selector: u16,
+    }
+
+    let target = FarPointer { offset: 0, selector: kernel_call_gate().0 };
+    let ret: u64;
+    unsafe {
+        asm!(
+            "call fword ptr [{target}]",
+            target = in(reg) &target,
+            inlateout("rax") u64::MAX => ret,
+        );
+    }
+    assert_eq!(ret as i64, -super::ENOSYS);
+}
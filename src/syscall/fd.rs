@@ -0,0 +1,730 @@
+//! File descriptors over the VFS, and the system calls opening and moving
+//! through files.
+//!
+//! A descriptor refers to an open file in a table all descriptor tables share,
+//! which keeps the offset and the access mode: the descriptors [`sys_dup`]
+//! returns, and the ones a forked table holds, move through the file together.
+//! The system calls use the [`FdTable`] of the calling process, see
+//! [`process::with_fd_table`], and read and write through a [`FileRef`] once
+//! they let go of it. Descriptors 0 to 2 are the console, see [`super::io`],
+//! and stay out of it.
+//!
+//! On a directory, the offset is the cookie of the filesystem for the next
+//! entry, see [`vfs::FileSystem::read_dir`], and [`sys_getdents64`] moves it.
+//!
+//! Files are created, written and truncated where the filesystem allows it,
+//! the ramfs at `/tmp`; elsewhere, that fails with `EROFS`.
+
+use core::mem;
+use bitflags::bitflags;
+use crate::{fs::vfs::{self, DirEntry, File, FsError, NodeKind, MAX_NAME, MAX_PATH}, process, sync::Mutex};
+use super::{copy_from_user, copy_path_from_user, copy_to_user, EBADF, EBUSY, EEXIST, EFAULT, EINVAL, EIO, EISDIR,
+    ELOOP, EMFILE, ENAMETOOLONG, ENFILE, ENOENT, ENOSPC, ENOTDIR, EROFS};
+
+/// Descriptors in a table, the console ones included.
+pub const MAX_FDS: usize = 32;
+/// Files open at once, over all the tables.
+pub const MAX_OPEN_FILES: usize = 64;
+/// The lowest descriptor of a file, past the console ones.
+pub const FIRST_FILE_FD: u64 = 3;
+
+/// `lseek` bases.
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// `d_type` of the records of `getdents64`.
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+
+/// Bytes moved between a file and the caller at a time, bounded by the kernel stack.
+const IO_CHUNK: usize = 512;
+/// A `getdents64` record before its name: `d_ino`, `d_off`, `d_reclen` and `d_type`.
+const DIRENT_HEADER: usize = 19;
+/// The longest record, with the longest name, its NUL and the padding.
+const DIRENT_MAX: usize = (DIRENT_HEADER + MAX_NAME + 1).next_multiple_of(8);
+
+bitflags! {
+    /// The flags of `open`, with the values Linux gives them on x86_64.
+    #[repr(transparent)]
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub struct OpenFlags: u64 {
+        /// Write only. Without it or [`OpenFlags::RDWR`], the file is read only.
+        const WRONLY = 0o1;
+        const RDWR = 0o2;
+        /// Creates the file if there is none.
+        const CREAT = 0o100;
+        /// With [`OpenFlags::CREAT`], fails if there is a file already.
+        const EXCL = 0o200;
+        /// Empties the file.
+        const TRUNC = 0o1000;
+        /// Fails unless the path leads to a directory.
+        const DIRECTORY = 0o200000;
+    }
+}
+
+impl OpenFlags {
+    fn readable(self) -> bool {
+        !self.contains(OpenFlags::WRONLY)
+    }
+
+    fn writable(self) -> bool {
+        self.intersects(OpenFlags::WRONLY | OpenFlags::RDWR)
+    }
+}
+
+/// The errno of `err`.
+pub fn errno(err: FsError) -> i64 {
+    match err {
+        FsError::NotFound | FsError::NotMounted => ENOENT,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::IsADirectory => EISDIR,
+        FsError::TooManyLinks => ELOOP,
+        FsError::NameTooLong => ENAMETOOLONG,
+        FsError::ReadOnly => EROFS,
+        FsError::AlreadyExists => EEXIST,
+        FsError::NoSpace => ENOSPC,
+        FsError::Busy | FsError::AlreadyMounted => EBUSY,
+        FsError::NotALink | FsError::InvalidPath => EINVAL,
+        FsError::Io(_) | FsError::Corrupt | FsError::TooManyMounts => EIO,
+    }
+}
+
+/// A file as opened, which the descriptors to it share.
+struct OpenFile {
+    file: File,
+    offset: u64,
+    flags: OpenFlags,
+    /// The descriptors and [`FileRef`]s referring to it, over all the tables.
+    refs: usize,
+}
+
+/// Reads and writes go through with the table locked, one at a time: the
+/// kernel is not preempted, so no other caller runs in the meantime.
+static OPEN_FILES: Mutex<[Option<OpenFile>; MAX_OPEN_FILES]> = Mutex::new("OPEN_FILES", [const { None }; MAX_OPEN_FILES]);
+
+/// The descriptors of a caller, each the index of an open file.
+pub struct FdTable {
+    fds: [Option<usize>; MAX_FDS],
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        FdTable { fds: [None; MAX_FDS] }
+    }
+
+    /// The open file `fd` refers to.
+    fn get(&self, fd: u64) -> Result<usize, i64> {
+        usize::try_from(fd).ok().and_then(|fd| self.fds.get(fd)).copied().flatten().ok_or(EBADF)
+    }
+
+    /// Gives the open file `file` the lowest free descriptor, taking over a reference to it.
+    fn install(&mut self, file: usize) -> Result<u64, i64> {
+        match self.fds.iter().skip(FIRST_FILE_FD as usize).position(Option::is_none) {
+            Some(index) => {
+                let fd = FIRST_FILE_FD as usize + index;
+                self.fds[fd] = Some(file);
+                Ok(fd as u64)
+            }
+            None => {
+                release(&mut OPEN_FILES.lock(), file);
+                Err(EMFILE)
+            }
+        }
+    }
+
+    /// Opens the file at the absolute `path`, returning its descriptor.
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<u64, i64> {
+        if flags.contains(OpenFlags::WRONLY | OpenFlags::RDWR) || !OpenFlags::all().contains(flags) {
+            return Err(EINVAL);
+        }
+        let file = match vfs::open(path) {
+            Ok(_) if flags.contains(OpenFlags::CREAT | OpenFlags::EXCL) => return Err(EEXIST),
+            Ok(file) => file,
+            Err(FsError::NotFound) if flags.contains(OpenFlags::CREAT) => vfs::create(path).map_err(errno)?,
+            Err(err) => return Err(errno(err)),
+        };
+        match file.kind() {
+            NodeKind::Directory if flags.writable() => return Err(EISDIR),
+            NodeKind::Directory => {},
+            _ if flags.contains(OpenFlags::DIRECTORY) => return Err(ENOTDIR),
+            _ if flags.contains(OpenFlags::TRUNC) && flags.writable() => file.truncate(0).map_err(errno)?,
+            _ => {},
+        }
+        let mut files = OPEN_FILES.lock();
+        let slot = files.iter().position(Option::is_none).ok_or(ENFILE)?;
+        files[slot] = Some(OpenFile { file, offset: 0, flags, refs: 1 });
+        drop(files);
+        self.install(slot)
+    }
+
+    pub fn close(&mut self, fd: u64) -> Result<(), i64> {
+        let file = self.get(fd)?;
+        self.fds[fd as usize] = None;
+        release(&mut OPEN_FILES.lock(), file);
+        Ok(())
+    }
+
+    /// Returns a new descriptor to the file `fd` refers to, sharing its offset.
+    pub fn dup(&mut self, fd: u64) -> Result<u64, i64> {
+        let file = self.get(fd)?;
+        OPEN_FILES.lock()[file].as_mut().unwrap().refs += 1;
+        self.install(file)
+    }
+
+    /// Returns a reference to the file `fd` refers to, which keeps it open
+    /// even once `fd` is closed.
+    pub fn file(&self, fd: u64) -> Result<FileRef, i64> {
+        let file = self.get(fd)?;
+        OPEN_FILES.lock()[file].as_mut().unwrap().refs += 1;
+        Ok(FileRef(file))
+    }
+
+    /// Returns a copy of the table for a forked caller, whose descriptors
+    /// share the open files with these.
+    pub fn fork(&self) -> FdTable {
+        let mut files = OPEN_FILES.lock();
+        for &file in self.fds.iter().flatten() {
+            files[file].as_mut().unwrap().refs += 1;
+        }
+        FdTable { fds: self.fds }
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        FdTable::new()
+    }
+}
+
+/// Closes the descriptors left, as when their caller exits.
+impl Drop for FdTable {
+    fn drop(&mut self) {
+        let mut files = OPEN_FILES.lock();
+        for file in mem::take(&mut self.fds).into_iter().flatten() {
+            release(&mut files, file);
+        }
+    }
+}
+
+/// An open file, held on to without a descriptor table, see [`FdTable::file`].
+pub struct FileRef(usize);
+
+impl FileRef {
+    /// Reads from the offset on into `buffer`, returning how many bytes there
+    /// were, 0 at the end of the file.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, i64> {
+        let mut files = OPEN_FILES.lock();
+        let file = files[self.0].as_mut().unwrap();
+        if !file.flags.readable() {
+            return Err(EBADF);
+        }
+        let len = file.file.read_at(file.offset, buffer).map_err(errno)?;
+        file.offset += len as u64;
+        Ok(len)
+    }
+
+    /// Writes `buffer` at the offset, returning how many bytes went.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, i64> {
+        let mut files = OPEN_FILES.lock();
+        let file = files[self.0].as_mut().unwrap();
+        if !file.flags.writable() {
+            return Err(EBADF);
+        }
+        let len = file.file.write_at(file.offset, buffer).map_err(errno)?;
+        file.offset += len as u64;
+        Ok(len)
+    }
+
+    /// Hands the entries of the directory from the offset on to `take`, each
+    /// with the cookie of the next one, until `take` turns one down or there
+    /// are no more. The offset moves past the entries taken, and past
+    /// `.` and `..`, which are left out.
+    pub fn read_dir(&self, mut take: impl FnMut(&DirEntry, u64) -> bool) -> Result<(), i64> {
+        let mut files = OPEN_FILES.lock();
+        let file = files[self.0].as_mut().unwrap();
+        if file.file.kind() != NodeKind::Directory {
+            return Err(ENOTDIR);
+        }
+        while let Some((entry, next)) = file.file.read_dir_at(file.offset).map_err(errno)? {
+            if entry.name() != "." && entry.name() != ".." && !take(&entry, next) {
+                break;
+            }
+            file.offset = next;
+        }
+        Ok(())
+    }
+
+    /// Moves the offset to `offset` from `whence`, one of the `SEEK_*` bases,
+    /// and returns it. The offset may go past the end of a file; a
+    /// directory has no end to seek from.
+    pub fn seek(&self, offset: i64, whence: u64) -> Result<u64, i64> {
+        let mut files = OPEN_FILES.lock();
+        let file = files[self.0].as_mut().unwrap();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => file.offset,
+            SEEK_END if file.file.kind() == NodeKind::Directory => return Err(EINVAL),
+            SEEK_END => file.file.metadata().map_err(errno)?.size,
+            _ => return Err(EINVAL),
+        };
+        let offset = i64::try_from(base).ok().and_then(|base| base.checked_add(offset))
+            .and_then(|offset| u64::try_from(offset).ok())
+            .ok_or(EINVAL)?;
+        file.offset = offset;
+        Ok(offset)
+    }
+}
+
+impl Drop for FileRef {
+    fn drop(&mut self) {
+        release(&mut OPEN_FILES.lock(), self.0);
+    }
+}
+
+/// Drops a reference to the open file `file`, closing it with the last one.
+fn release(files: &mut [Option<OpenFile>; MAX_OPEN_FILES], file: usize) {
+    let open = files[file].as_mut().unwrap();
+    open.refs -= 1;
+    if open.refs == 0 {
+        files[file] = None;
+    }
+}
+
+/// The file the descriptor `fd` of the caller refers to.
+fn file(fd: u64) -> Result<FileRef, i64> {
+    process::with_fd_table(|table| table.file(fd))
+}
+
+/// Reads up to `len` bytes from the file `fd` into the caller's `buf`, see [`super::io::sys_read`].
+pub fn read_file(fd: u64, buf: u64, len: u64) -> i64 {
+    let file = match file(fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let count = (len - done).min(IO_CHUNK as u64) as usize;
+        let read = match file.read(&mut chunk[..count]) {
+            Ok(read) => read,
+            Err(errno) => return -errno,
+        };
+        let Some(dst) = buf.checked_add(done) else {
+            return -EFAULT;
+        };
+        if let Err(errno) = unsafe { copy_to_user(dst, &chunk[..read]) } {
+            return -errno;
+        }
+        done += read as u64;
+        if read < count {
+            break;
+        }
+    }
+    done as i64
+}
+
+/// Writes `len` bytes from the caller's `buf` to the file `fd`, see [`super::io::sys_write`].
+pub fn write_file(fd: u64, buf: u64, len: u64) -> i64 {
+    let file = match file(fd) {
+        Ok(file) => file,
+        Err(errno) => return -errno,
+    };
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let count = (len - done).min(IO_CHUNK as u64) as usize;
+        let Some(src) = buf.checked_add(done) else {
+            return -EFAULT;
+        };
+        if let Err(errno) = unsafe { copy_from_user(&mut chunk[..count], src) } {
+            return -errno;
+        }
+        let written = match file.write(&chunk[..count]) {
+            Ok(written) => written,
+            // What went before the error still counts.
+            Err(_) if done != 0 => break,
+            Err(errno) => return -errno,
+        };
+        done += written as u64;
+        if written < count {
+            break;
+        }
+    }
+    done as i64
+}
+
+/// `getdents64(fd, dirp, count)`: fills the `count` bytes at `dirp` with the
+/// entries of the directory `fd`, from its offset on, as `linux_dirent64`
+/// records: `d_ino`, `d_off` the offset of the entry after, `d_reclen`,
+/// `d_type` and the name with a NUL, padded to 8 bytes.
+///
+/// Returns the bytes filled, 0 past the last entry. A record is never cut:
+/// the call stops before the first that does not fit, which the next call
+/// starts with, and fails with `EINVAL` if even that one does not. Offsets
+/// are filesystem cookies, so an entry added or removed while the directory
+/// is read through may or may not be listed, and the others are listed once.
+pub fn sys_getdents64(args: &[u64; 6]) -> i64 {
+    let [fd, dirp, count, ..] = *args;
+    let mut done = 0;
+    let mut result = Ok(());
+    let listed = file(fd).and_then(|file| file.read_dir(|entry, next| {
+        let name = entry.name().as_bytes();
+        let len = (DIRENT_HEADER + name.len() + 1).next_multiple_of(8);
+        if done + len as u64 > count {
+            if done == 0 {
+                result = Err(EINVAL);
+            }
+            return false;
+        }
+        let mut record = [0u8; DIRENT_MAX];
+        record[0..8].copy_from_slice(&entry.node().ino.to_le_bytes());
+        record[8..16].copy_from_slice(&next.to_le_bytes());
+        record[16..18].copy_from_slice(&(len as u16).to_le_bytes());
+        record[18] = match entry.kind() {
+            NodeKind::File => DT_REG,
+            NodeKind::Directory => DT_DIR,
+            NodeKind::Symlink => DT_LNK,
+        };
+        record[DIRENT_HEADER..DIRENT_HEADER + name.len()].copy_from_slice(name);
+        let copied = dirp.checked_add(done).ok_or(EFAULT)
+            .and_then(|dst| unsafe { copy_to_user(dst, &record[..len]) });
+        if let Err(errno) = copied {
+            result = Err(errno);
+            return false;
+        }
+        done += len as u64;
+        true
+    }));
+    match listed.and(result) {
+        Ok(()) => done as i64,
+        Err(errno) => -errno,
+    }
+}
+
+/// `open(path, flags, mode)`: opens the file at the absolute `path`, see
+/// [`OpenFlags`], and returns its descriptor. The filesystem picks the
+/// permissions of a new file, so `mode` is left alone.
+pub fn sys_open(args: &[u64; 6]) -> i64 {
+    let [path, flags, ..] = *args;
+    let mut buffer = [0u8; MAX_PATH];
+    let path = match unsafe { copy_path_from_user(&mut buffer, path) } {
+        Ok(path) => path,
+        Err(errno) => return -errno,
+    };
+    let Some(flags) = OpenFlags::from_bits(flags) else {
+        return -EINVAL;
+    };
+    match process::with_fd_table(|table| table.open(path, flags)) {
+        Ok(fd) => fd as i64,
+        Err(errno) => -errno,
+    }
+}
+
+/// `close(fd)`.
+pub fn sys_close(args: &[u64; 6]) -> i64 {
+    match process::with_fd_table(|table| table.close(args[0])) {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}
+
+/// `lseek(fd, offset, whence)`: moves the offset of `fd`, see [`FileRef::seek`].
+pub fn sys_lseek(args: &[u64; 6]) -> i64 {
+    let [fd, offset, whence, ..] = *args;
+    match file(fd).and_then(|file| file.seek(offset as i64, whence)) {
+        Ok(offset) => offset as i64,
+        Err(errno) => -errno,
+    }
+}
+
+/// `dup(fd)`: returns the lowest free descriptor, to the same file as `fd`.
+pub fn sys_dup(args: &[u64; 6]) -> i64 {
+    match process::with_fd_table(|table| table.dup(args[0])) {
+        Ok(fd) => fd as i64,
+        Err(errno) => -errno,
+    }
+}
+
+/// Mounts the initrd fixture as the root of the kernel's tree, and the ramfs
+/// at its `/tmp`, unless they already are.
+#[cfg(test)]
+pub(crate) fn mount_fixture() {
+    match vfs::mount("/", crate::fs::ustar::fixture()) {
+        Ok(()) | Err(FsError::AlreadyMounted) => {},
+        Err(err) => panic!("cannot mount the initrd fixture: {}", err),
+    }
+    match vfs::mount("/tmp", crate::fs::ramfs::tmp()) {
+        Ok(()) | Err(FsError::AlreadyMounted) => {},
+        Err(err) => panic!("cannot mount the ramfs: {}", err),
+    }
+}
+
+/// A directory of [`NUMBERED`] empty files named by their number, in 1 to
+/// 64 digits, then a symbolic link leading nowhere.
+#[cfg(test)]
+struct Numbered;
+
+#[cfg(test)]
+const NUMBERED: u64 = 500;
+
+#[cfg(test)]
+fn numbered_name(number: u64) -> crate::pci::ids::StackString {
+    crate::pci::ids::StackString::format(format_args!("{:01$}", number, 1 + number as usize % 64))
+}
+
+#[cfg(test)]
+impl vfs::FileSystem for Numbered {
+    fn root(&self) -> vfs::NodeRef {
+        vfs::NodeRef { kind: NodeKind::Directory, ino: 0, data: 0 }
+    }
+
+    fn lookup(&self, _dir: vfs::NodeRef, name: &str) -> Result<vfs::NodeRef, FsError> {
+        if name == "dangling" {
+            return Ok(vfs::NodeRef { kind: NodeKind::Symlink, ino: NUMBERED + 1, data: 0 });
+        }
+        match name.parse() {
+            Ok(number) if number < NUMBERED && numbered_name(number).as_str() == name => {
+                Ok(vfs::NodeRef { kind: NodeKind::File, ino: number + 1, data: 0 })
+            }
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn read_dir(&self, dir: vfs::NodeRef, cookie: u64) -> Result<Option<(DirEntry, u64)>, FsError> {
+        let entry = match cookie {
+            number if number < NUMBERED => DirEntry::new(numbered_name(number).as_str(), self.lookup(dir, numbered_name(number).as_str())?)?,
+            NUMBERED => DirEntry::new("dangling", self.lookup(dir, "dangling")?)?,
+            _ => return Ok(None),
+        };
+        Ok(Some((entry, cookie + 1)))
+    }
+
+    fn read_at(&self, _node: vfs::NodeRef, _offset: u64, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn metadata(&self, node: vfs::NodeRef) -> Result<vfs::Metadata, FsError> {
+        let mode = match node.kind {
+            NodeKind::File => 0o644,
+            NodeKind::Directory => 0o755,
+            NodeKind::Symlink => 0o777,
+        };
+        Ok(vfs::Metadata { kind: node.kind, size: 0, mode, inode: node.ino, modified: None, accessed: None, created: None })
+    }
+
+    fn read_link(&self, _link: vfs::NodeRef, target: &mut [u8]) -> Result<usize, FsError> {
+        target[..8].copy_from_slice(b"/nowhere");
+        Ok(8)
+    }
+}
+
+/// Mounts [`Numbered`] over `/deep` of the initrd fixture.
+#[cfg(test)]
+pub(super) fn mount_numbered() {
+    static NUMBERED_FS: Numbered = Numbered;
+    mount_fixture();
+    match vfs::mount("/deep", &NUMBERED_FS) {
+        Ok(()) | Err(FsError::AlreadyMounted) => {},
+        Err(err) => panic!("cannot mount the numbered directory: {}", err),
+    }
+}
+
+/// The open files left, over all the tables.
+#[cfg(test)]
+pub(crate) fn open_files() -> usize {
+    OPEN_FILES.lock().iter().flatten().count()
+}
+
+#[test_case]
+fn descriptors_read_and_seek_through_files() {
+    use super::{int80::syscall3, SYS_CLOSE, SYS_DUP, SYS_LSEEK, SYS_OPEN, SYS_READ};
+    mount_fixture();
+    let open = |path: &[u8], flags: OpenFlags| syscall3(SYS_OPEN as u64, path.as_ptr() as u64, flags.bits(), 0);
+    let read = |fd: i64, buf: &mut [u8]| syscall3(SYS_READ as u64, fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64);
+    let mut buf = [0u8; 32];
+
+    let fd = open(b"/etc/motd\0", OpenFlags::empty());
+    assert!(fd >= FIRST_FILE_FD as i64);
+    assert_eq!(read(fd, &mut buf[..8]), 8);
+    assert_eq!(&buf[..8], b"Welcome ");
+    assert_eq!(read(fd, &mut buf), 12);
+    assert_eq!(read(fd, &mut buf), 0);
+    assert_eq!(syscall3(SYS_LSEEK as u64, fd as u64, -4i64 as u64, SEEK_END), 16);
+    assert_eq!(read(fd, &mut buf), 4);
+    assert_eq!(&buf[..4], b"os.\n");
+    assert_eq!(syscall3(SYS_LSEEK as u64, fd as u64, -20i64 as u64, SEEK_CUR), 0);
+    assert_eq!(syscall3(SYS_LSEEK as u64, fd as u64, -1i64 as u64, SEEK_SET), -EINVAL);
+    assert_eq!(syscall3(SYS_LSEEK as u64, fd as u64, 0, 3), -EINVAL);
+
+    // The copy moves the same offset.
+    let copy = syscall3(SYS_DUP as u64, fd as u64, 0, 0);
+    assert_eq!(copy, fd + 1);
+    assert_eq!(syscall3(SYS_LSEEK as u64, copy as u64, 11, SEEK_SET), 11);
+    assert_eq!(read(fd, &mut buf[..8]), 8);
+    assert_eq!(&buf[..8], b"krabbos.");
+    assert_eq!(syscall3(SYS_CLOSE as u64, fd as u64, 0, 0), 0);
+    assert_eq!(read(fd, &mut buf), -EBADF);
+    assert_eq!(syscall3(SYS_CLOSE as u64, fd as u64, 0, 0), -EBADF);
+    assert_eq!(read(copy, &mut buf), 1);
+    // The lowest free descriptor is taken again.
+    assert_eq!(open(b"/hello.txt\0", OpenFlags::RDWR), fd);
+    assert_eq!(read(fd, &mut buf), 23);
+    assert_eq!(syscall3(SYS_CLOSE as u64, fd as u64, 0, 0), 0);
+    assert_eq!(syscall3(SYS_CLOSE as u64, copy as u64, 0, 0), 0);
+    assert_eq!(syscall3(SYS_DUP as u64, 0, 0, 0), -EBADF);
+}
+
+#[test_case]
+fn open_reports_what_it_cannot_do() {
+    use super::{int80::syscall3, SYS_CLOSE, SYS_OPEN, SYS_READ, SYS_WRITE};
+    mount_fixture();
+    let open = |path: &[u8], flags: OpenFlags| syscall3(SYS_OPEN as u64, path.as_ptr() as u64, flags.bits(), 0);
+    assert_eq!(open(b"/nowhere\0", OpenFlags::empty()), -ENOENT);
+    assert_eq!(open(b"etc/motd\0", OpenFlags::empty()), -EINVAL);
+    assert_eq!(open(b"/etc/motd\0", OpenFlags::DIRECTORY), -ENOTDIR);
+    assert_eq!(open(b"/etc/motd/x\0", OpenFlags::empty()), -ENOTDIR);
+    assert_eq!(open(b"/etc\0", OpenFlags::WRONLY), -EISDIR);
+    assert_eq!(open(b"/loop\0", OpenFlags::empty()), -ELOOP);
+    assert_eq!(open(b"/etc/motd\0", OpenFlags::WRONLY | OpenFlags::RDWR), -EINVAL);
+    assert_eq!(syscall3(SYS_OPEN as u64, b"/etc/motd\0".as_ptr() as u64, 0o4000_0000_0000, 0), -EINVAL);
+    assert_eq!(syscall3(SYS_OPEN as u64, 0, 0, 0), -EFAULT);
+    let long = [b'/'; MAX_PATH + 1];
+    assert_eq!(open(&long, OpenFlags::empty()), -ENAMETOOLONG);
+
+    // Nothing can be created or written on the initrd.
+    assert_eq!(open(b"/etc/new\0", OpenFlags::WRONLY | OpenFlags::CREAT), -EROFS);
+    assert_eq!(open(b"/nowhere/new\0", OpenFlags::WRONLY | OpenFlags::CREAT), -ENOENT);
+    assert_eq!(open(b"/etc/motd/new\0", OpenFlags::WRONLY | OpenFlags::CREAT), -ENOTDIR);
+    assert_eq!(open(b"/etc/motd\0", OpenFlags::CREAT | OpenFlags::EXCL), -EEXIST);
+    assert_eq!(open(b"/etc/motd\0", OpenFlags::WRONLY | OpenFlags::TRUNC), -EROFS);
+    let mut buf = *b"hello";
+    let fd = open(b"/etc/motd\0", OpenFlags::WRONLY | OpenFlags::CREAT);
+    assert_eq!(syscall3(SYS_WRITE as u64, fd as u64, buf.as_ptr() as u64, 5), -EROFS);
+    assert_eq!(syscall3(SYS_READ as u64, fd as u64, buf.as_mut_ptr() as u64, 5), -EBADF);
+    assert_eq!(syscall3(SYS_CLOSE as u64, fd as u64, 0, 0), 0);
+    let fd = open(b"/etc\0", OpenFlags::DIRECTORY);
+    assert_eq!(syscall3(SYS_READ as u64, fd as u64, buf.as_mut_ptr() as u64, 5), -EISDIR);
+    assert_eq!(syscall3(SYS_WRITE as u64, fd as u64, buf.as_ptr() as u64, 5), -EBADF);
+    assert_eq!(syscall3(SYS_CLOSE as u64, fd as u64, 0, 0), 0);
+}
+
+#[test_case]
+fn files_are_created_and_truncated_on_tmp() {
+    use super::{int80::syscall3, SYS_CLOSE, SYS_LSEEK, SYS_OPEN, SYS_READ, SYS_WRITE};
+    mount_fixture();
+    let open = |path: &[u8], flags: OpenFlags| syscall3(SYS_OPEN as u64, path.as_ptr() as u64, flags.bits(), 0);
+    let read = |fd: i64, buf: &mut [u8]| syscall3(SYS_READ as u64, fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64);
+    let write = |fd: i64, buf: &[u8]| syscall3(SYS_WRITE as u64, fd as u64, buf.as_ptr() as u64, buf.len() as u64);
+    let mut buf = [0u8; 16];
+
+    assert_eq!(open(b"/tmp/fd-test\0", OpenFlags::empty()), -ENOENT);
+    let fd = open(b"/tmp/fd-test\0", OpenFlags::RDWR | OpenFlags::CREAT | OpenFlags::EXCL);
+    assert!(fd >= FIRST_FILE_FD as i64);
+    assert_eq!(write(fd, b"hello, tmp"), 10);
+    assert_eq!(syscall3(SYS_LSEEK as u64, fd as u64, 0, SEEK_SET), 0);
+    assert_eq!(read(fd, &mut buf), 10);
+    assert_eq!(&buf[..10], b"hello, tmp");
+    assert_eq!(open(b"/tmp/fd-test\0", OpenFlags::WRONLY | OpenFlags::CREAT | OpenFlags::EXCL), -EEXIST);
+
+    // Emptied by the second open, under the offset of the first.
+    let other = open(b"/tmp/fd-test\0", OpenFlags::WRONLY | OpenFlags::TRUNC);
+    assert_eq!(syscall3(SYS_LSEEK as u64, fd as u64, 0, SEEK_END), 0);
+    assert_eq!(write(other, b"bye"), 3);
+    assert_eq!(read(fd, &mut buf), 3);
+    assert_eq!(&buf[..3], b"bye");
+    assert_eq!(read(other, &mut buf), -EBADF);
+    assert_eq!(syscall3(SYS_CLOSE as u64, other as u64, 0, 0), 0);
+    assert_eq!(syscall3(SYS_CLOSE as u64, fd as u64, 0, 0), 0);
+    assert_eq!(vfs::metadata("/tmp/fd-test").map(|metadata| metadata.size), Ok(3));
+    assert_eq!(open(b"/tmp\0", OpenFlags::WRONLY | OpenFlags::CREAT), -EISDIR);
+}
+
+#[test_case]
+fn forked_tables_share_open_files() {
+    mount_fixture();
+    let before = open_files();
+    let mut table = FdTable::new();
+    let fd = table.open("/etc/motd", OpenFlags::empty()).unwrap();
+    let other = table.open("/hello.txt", OpenFlags::empty()).unwrap();
+    let child = table.fork();
+    let read = |table: &FdTable, fd, buf: &mut [u8]| table.file(fd).and_then(|file| file.read(buf));
+    let mut buf = [0u8; 8];
+    assert_eq!(read(&child, fd, &mut buf), Ok(8));
+    assert_eq!(read(&table, fd, &mut buf), Ok(8));
+    assert_eq!(&buf, b"to krabb");
+    table.close(other).unwrap();
+    assert_eq!(open_files(), before + 2);
+    drop(table);
+    assert_eq!(child.file(fd).and_then(|file| file.seek(0, SEEK_CUR)), Ok(16));
+    assert_eq!(read(&child, other, &mut buf), Ok(8));
+    // A reference outlives the descriptor, and the file is closed as the last of them goes.
+    let file = child.file(fd).unwrap();
+    drop(child);
+    assert_eq!(open_files(), before + 1);
+    assert_eq!(file.seek(0, SEEK_SET), Ok(0));
+    drop(file);
+    assert_eq!(open_files(), before);
+
+    let mut table = FdTable::new();
+    for fd in FIRST_FILE_FD..MAX_FDS as u64 {
+        assert_eq!(table.open("/hello.txt", OpenFlags::empty()), Ok(fd));
+    }
+    assert_eq!(table.open("/hello.txt", OpenFlags::empty()), Err(EMFILE));
+    assert_eq!(table.dup(FIRST_FILE_FD), Err(EMFILE));
+    assert_eq!(open_files(), before + MAX_FDS - FIRST_FILE_FD as usize);
+}
+
+#[test_case]
+fn getdents_lists_directories_across_calls() {
+    use super::{int80::syscall3, SYS_CLOSE, SYS_GETDENTS64, SYS_LSEEK, SYS_OPEN};
+    mount_numbered();
+    let open = |path: &[u8], flags: OpenFlags| syscall3(SYS_OPEN as u64, path.as_ptr() as u64, flags.bits(), 0);
+    let getdents = |fd: i64, buf: &mut [u8]| syscall3(SYS_GETDENTS64 as u64, fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64);
+    let fd = open(b"/deep\0", OpenFlags::DIRECTORY);
+    let mut buf = [0u8; 512];
+    let (mut listed, mut calls) = (0, 0);
+    loop {
+        let len = getdents(fd, &mut buf);
+        assert!(len >= 0, "getdents64 failed with {}", len);
+        if len == 0 {
+            break;
+        }
+        calls += 1;
+        let mut records = &buf[..len as usize];
+        while !records.is_empty() {
+            let ino = u64::from_le_bytes(records[0..8].try_into().unwrap());
+            let next = u64::from_le_bytes(records[8..16].try_into().unwrap());
+            let len = u16::from_le_bytes(records[16..18].try_into().unwrap()) as usize;
+            let name = &records[DIRENT_HEADER..records[DIRENT_HEADER..].iter().position(|&byte| byte == 0).unwrap() + DIRENT_HEADER];
+            let numbered = numbered_name(listed);
+            let (expected, kind) = match listed {
+                NUMBERED => ("dangling", DT_LNK),
+                _ => (numbered.as_str(), DT_REG),
+            };
+            assert_eq!((name, records[18], ino, next), (expected.as_bytes(), kind, listed + 1, listed + 1));
+            assert_eq!(len % 8, 0);
+            records = &records[len..];
+            listed += 1;
+        }
+    }
+    assert_eq!(listed, NUMBERED + 1);
+    assert!(calls > NUMBERED / 10, "{} calls", calls);
+    assert_eq!(getdents(fd, &mut buf), 0);
+
+    // Back to where an entry starts, into too small a buffer, then one it fits.
+    assert_eq!(syscall3(SYS_LSEEK as u64, fd as u64, 300, SEEK_SET), 300);
+    assert_eq!(getdents(fd, &mut buf[..16]), -EINVAL);
+    assert!(getdents(fd, &mut buf) > 0);
+    let name = numbered_name(300);
+    let end = DIRENT_HEADER + name.as_str().len();
+    assert_eq!((&buf[DIRENT_HEADER..end], buf[end]), (name.as_str().as_bytes(), 0));
+    assert_eq!(syscall3(SYS_LSEEK as u64, fd as u64, 0, SEEK_END), -EINVAL);
+    assert_eq!(syscall3(SYS_CLOSE as u64, fd as u64, 0, 0), 0);
+
+    let fd = open(b"/etc/motd\0", OpenFlags::empty());
+    assert_eq!(getdents(fd, &mut buf), -ENOTDIR);
+    assert_eq!(syscall3(SYS_CLOSE as u64, fd as u64, 0, 0), 0);
+    assert_eq!(getdents(fd, &mut buf), -EBADF);
+}
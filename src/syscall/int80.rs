@@ -0,0 +1,141 @@
+//! Legacy `int 0x80` system call gate.
+//!
+//! Calling convention (i386 style, widened to 64 bits):
+//!
+//! | register | meaning                |
+//! |----------|------------------------|
+//! | RAX      | syscall number         |
+//! | RBX      | argument 0             |
+//! | RCX      | argument 1             |
+//! | RDX      | argument 2             |
+//! | RSI      | argument 3             |
+//! | RDI      | argument 4             |
+//! | RBP      | argument 5             |
+//! | RAX      | return value on `iretq`|
+//!
+//! Every other register is preserved. The gate is a DPL 3 trap gate, so ring 3
+//! code may invoke it and interrupts stay enabled while the syscall runs.
+//!
+//! Unlike the `x86-interrupt` handlers, the entry point is an asm trampoline
+//! saving all general purpose registers into a [`SyscallRegisters`] frame,
+//! which is how the handler reads the arguments and writes the result back.
+
+use core::arch::global_asm;
+use crate::{process::scheduler, tables::{tss::TSS, InterruptStackFrameValue}};
+use super::GeneralRegisters;
+
+/// IDT vector of the gate.
+pub const INT80_VECTOR: usize = 0x80;
+
+/// The general purpose registers saved by the trampoline, followed by the frame pushed by the CPU.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallRegisters {
+    pub regs: GeneralRegisters,
+    pub frame: InterruptStackFrameValue,
+}
+
+extern "C" {
+    /// Entry point installed in the IDT, not callable from Rust.
+    pub fn int80_entry();
+    /// The way out of the gate: restores the [`SyscallRegisters`] at the stack
+    /// pointer and returns with `iretq`. New user threads start there, see
+    /// [`crate::process::scheduler`].
+    pub fn int80_return();
+}
+
+// The CPU aligns RSP to 16 bytes before pushing the 5 qword interrupt frame;
+// 15 more pushes bring it back to a 16 byte boundary for the call.
+global_asm!(
+    ".global int80_entry",
+    "int80_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call {handler}",
+    ".global int80_return",
+    "int80_return:",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    handler = sym int80_handler,
+);
+
+extern "C" fn int80_handler(regs: &mut SyscallRegisters) {
+    let _depth = crate::tables::enter_interrupt();
+    // From ring 3 the CPU switched to RSP0 and pushed the user frame right below it.
+    let _registers = (regs.frame.code_segment.0 & 3 == 3).then(|| {
+        debug_assert_eq!(&regs.frame as *const _ as u64, TSS.privilege_stack_frame_start());
+        scheduler::enter_syscall(regs)
+    });
+    super::dispatch_registers(&mut regs.regs);
+}
+
+/// Invokes the gate with up to three arguments, for tests.
+#[cfg(test)]
+pub fn syscall3(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    use core::arch::asm;
+
+    let ret: u64;
+    unsafe {
+        // RBX cannot be an asm operand, swap it in and out instead.
+        asm!(
+            "xchg {arg0}, rbx",
+            "int 0x80",
+            "xchg {arg0}, rbx",
+            arg0 = inout(reg) arg0 => _,
+            inlateout("rax") number => ret,
+            in("rcx") arg1,
+            in("rdx") arg2,
+        );
+    }
+    ret as i64
+}
+
+#[test_case]
+fn int80_unknown_syscall_returns_enosys() {
+    use core::arch::asm;
+
+    let ret: u64;
+    let rbx: u64;
+    unsafe {
+        asm!(
+            "push rbx",
+            "mov rbx, 0x1234",
+            "int 0x80",
+            "mov {rbx_out}, rbx",
+            "pop rbx",
+            rbx_out = out(reg) rbx,
+            inlateout("rax") u64::MAX => ret,
+        );
+    }
+    assert_eq!(ret as i64, -super::ENOSYS);
+    assert_eq!(rbx, 0x1234);
+}
@@ -0,0 +1,157 @@
+//! Console I/O system calls, and reads and writes to the files of [`super::fd`].
+
+use core::str;
+use crate::{print, tables::without_interrupts, tty::{self, TtyMode, TTY}};
+use super::{copy_from_user, copy_to_user, fd::{self, FIRST_FILE_FD}, EAGAIN, EBADF, EFAULT, EINVAL, ENOTTY};
+
+/// The console input.
+pub const STDIN_FILENO: u64 = 0;
+/// The console output, which standard error shares.
+pub const STDOUT_FILENO: u64 = 1;
+pub const STDERR_FILENO: u64 = 2;
+
+/// `ioctl` request returning the [`TtyMode`] of a tty.
+pub const TTY_GET_MODE: u64 = 0x5401;
+/// `ioctl` request setting the [`TtyMode`] of a tty from the argument.
+pub const TTY_SET_MODE: u64 = 0x5402;
+
+/// Bytes moved per `read` call, bounded by the kernel stack buffer.
+const READ_CHUNK: usize = 256;
+/// Bytes printed at a time by `write`.
+const WRITE_CHUNK: usize = 256;
+
+/// `read(fd, buf, len)`: reads up to `len` bytes into `buf` from a file, or
+/// waits for console input and copies up to `len` bytes of UTF-8. Returns the
+/// byte count, 0 at end of file (Ctrl+D on the console).
+pub fn sys_read(args: &[u64; 6]) -> i64 {
+    let [fd, buf, len, ..] = *args;
+    if fd >= FIRST_FILE_FD {
+        return fd::read_file(fd, buf, len);
+    }
+    if fd != STDIN_FILENO {
+        return -EBADF;
+    }
+    let mut chunk = [0u8; READ_CHUNK];
+    let len = len.min(READ_CHUNK as u64) as usize;
+    let Some(count) = tty::read_blocking(&mut chunk[..len]) else {
+        return -EAGAIN;
+    };
+    match unsafe { copy_to_user(buf, &chunk[..count]) } {
+        Ok(()) => count as i64,
+        Err(errno) => -errno,
+    }
+}
+
+/// `write(fd, buf, len)`: writes the `len` bytes at `buf` to a file, or prints
+/// them on the console, bytes that are not UTF-8 as U+FFFD. Returns the byte
+/// count.
+pub fn sys_write(args: &[u64; 6]) -> i64 {
+    let [fd, buf, len, ..] = *args;
+    if fd >= FIRST_FILE_FD {
+        return fd::write_file(fd, buf, len);
+    }
+    if fd != STDOUT_FILENO && fd != STDERR_FILENO {
+        return -EBADF;
+    }
+    let mut chunk = [0u8; WRITE_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let count = (len - done).min(WRITE_CHUNK as u64) as usize;
+        let Some(src) = buf.checked_add(done) else {
+            return -EFAULT;
+        };
+        if let Err(errno) = unsafe { copy_from_user(&mut chunk[..count], src) } {
+            return -errno;
+        }
+        // A character cut at the end of the chunk is printed with the next one.
+        let mut end = count;
+        if let Err(err) = str::from_utf8(&chunk[..count]) {
+            if err.error_len().is_none() && done + (count as u64) < len {
+                end = err.valid_up_to();
+            }
+        }
+        for part in chunk[..end].utf8_chunks() {
+            print!("{}", part.valid());
+            if !part.invalid().is_empty() {
+                print!("{}", char::REPLACEMENT_CHARACTER);
+            }
+        }
+        done += end as u64;
+    }
+    len as i64
+}
+
+/// `ioctl(fd, request, arg)`: gets or sets the console input mode.
+pub fn sys_ioctl(args: &[u64; 6]) -> i64 {
+    let [fd, request, arg, ..] = *args;
+    if fd != STDIN_FILENO {
+        return -EBADF;
+    }
+    // The keyboard interrupt feeds the tty, it must not find it locked.
+    match request {
+        TTY_GET_MODE => without_interrupts(|| TTY.lock().mode()) as i64,
+        TTY_SET_MODE => match TtyMode::try_from(arg) {
+            Ok(mode) => {
+                without_interrupts(|| TTY.lock().set_mode(mode));
+                // A line being edited may have become readable.
+                tty::wake_readers();
+                0
+            },
+            Err(()) => -EINVAL,
+        },
+        _ => -ENOTTY,
+    }
+}
+
+#[test_case]
+fn read_returns_injected_keys() {
+    use crate::pic::keyboard::handle_scancode;
+    use super::{int80::syscall3, SYS_IOCTL, SYS_READ};
+
+    // Set 1 make and break codes, the same keys on AZERTY and QWERTY.
+    const E: u8 = 0x12;
+    const R: u8 = 0x13;
+    const D: u8 = 0x20;
+    const ENTER: u8 = 0x1C;
+    const LEFT_CTRL: u8 = 0x1D;
+    const RELEASE: u8 = 0x80;
+    let inject = |scancodes: &[u8]| without_interrupts(|| {
+        for &scancode in scancodes {
+            handle_scancode(scancode);
+        }
+    });
+
+    without_interrupts(|| TTY.lock().flush());
+    let mut buf = [0u8; 8];
+    let buf_addr = buf.as_mut_ptr() as u64;
+
+    inject(&[E, E | RELEASE, R, R | RELEASE, ENTER, ENTER | RELEASE]);
+    assert_eq!(syscall3(SYS_READ as u64, STDIN_FILENO, buf_addr, 8), 3);
+    assert_eq!(&buf[..3], b"er\n");
+
+    inject(&[LEFT_CTRL, D, D | RELEASE, LEFT_CTRL | RELEASE]);
+    assert_eq!(syscall3(SYS_READ as u64, STDIN_FILENO, buf_addr, 8), 0);
+
+    assert_eq!(syscall3(SYS_IOCTL as u64, STDIN_FILENO, TTY_SET_MODE, TtyMode::Raw as u64), 0);
+    inject(&[R, R | RELEASE]);
+    assert_eq!(syscall3(SYS_READ as u64, STDIN_FILENO, buf_addr, 8), 1);
+    assert_eq!(buf[0], b'r');
+    assert_eq!(syscall3(SYS_IOCTL as u64, STDIN_FILENO, TTY_SET_MODE, TtyMode::Line as u64), 0);
+
+    assert_eq!(syscall3(SYS_READ as u64, STDOUT_FILENO, buf_addr, 8), -EBADF);
+    assert_eq!(syscall3(SYS_IOCTL as u64, STDIN_FILENO, TTY_SET_MODE, 7), -EINVAL);
+}
+
+#[test_case]
+fn write_prints_on_the_console() {
+    use super::{int80::syscall3, SYS_WRITE};
+    // `é` straddles the first two chunks.
+    let mut text = [b' '; WRITE_CHUNK + 8];
+    text[WRITE_CHUNK - 1..WRITE_CHUNK + 1].copy_from_slice("é".as_bytes());
+    text[WRITE_CHUNK + 7] = b'\n';
+    let len = text.len() as u64;
+    assert_eq!(syscall3(SYS_WRITE as u64, STDOUT_FILENO, text.as_ptr() as u64, len), len as i64);
+    assert_eq!(syscall3(SYS_WRITE as u64, STDERR_FILENO, text.as_ptr() as u64, 0), 0);
+    assert_eq!(syscall3(SYS_WRITE as u64, STDIN_FILENO, text.as_ptr() as u64, len), -EBADF);
+    assert_eq!(syscall3(SYS_WRITE as u64, STDOUT_FILENO, 0, 1), -EFAULT);
+}
@@ -0,0 +1,75 @@
+//! Memory mapping system calls, for anonymous private memory only.
+
+use crate::{
+    memory::{no_execute, paging::PageTableFlags},
+    process::{self, scheduler, KERNEL_PID},
+};
+use super::{EACCES, EINVAL};
+
+/// `mmap` protections.
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+/// `mmap` flags, matching Linux.
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+/// Not in Linux: lets a mapping be writable and executable at once, which
+/// `mmap` refuses otherwise.
+pub const MAP_WRITE_EXECUTE: u64 = 0x4000_0000;
+
+/// `mmap(hint, len, prot, flags)`: reserves `len` bytes of zeroed memory,
+/// at `hint` if it is free, and returns where, see [`process::mmap`]. The
+/// pages only get frames when first touched.
+///
+/// `flags` must be `MAP_PRIVATE | MAP_ANONYMOUS`, `MAP_WRITE_EXECUTE`
+/// aside, and the file descriptor and offset arguments are ignored. Memory
+/// both writable and executable needs `MAP_WRITE_EXECUTE`, `EACCES`
+/// otherwise. The kernel gets `EINVAL`.
+pub fn sys_mmap(args: &[u64; 6]) -> i64 {
+    let [hint, len, prot, flags, ..] = *args;
+    let anonymous = MAP_PRIVATE | MAP_ANONYMOUS;
+    if scheduler::current_pid() == KERNEL_PID || len == 0
+        || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        || flags & !(anonymous | MAP_WRITE_EXECUTE) != anonymous
+    {
+        return -EINVAL;
+    }
+    if prot & PROT_WRITE != 0 && prot & PROT_EXEC != 0 && flags & MAP_WRITE_EXECUTE == 0 {
+        return -EACCES;
+    }
+    let mut page_flags = PageTableFlags::empty();
+    if prot != 0 {
+        page_flags |= PageTableFlags::PRESENT;
+    }
+    if prot & PROT_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        page_flags |= no_execute();
+    }
+    match process::mmap(hint, len, page_flags) {
+        Ok(addr) => addr as i64,
+        Err(errno) => -errno,
+    }
+}
+
+/// `munmap(addr, len)`: gives back the pages of `addr..addr + len` that
+/// `mmap` reserved, see [`process::munmap`]. An empty range and the kernel
+/// get `EINVAL`.
+pub fn sys_munmap(args: &[u64; 6]) -> i64 {
+    if scheduler::current_pid() == KERNEL_PID || args[1] == 0 {
+        return -EINVAL;
+    }
+    match process::munmap(args[0], args[1]) {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}
+
+#[test_case]
+fn kernel_cannot_map_memory() {
+    use super::{int80::syscall3, SYS_MMAP, SYS_MUNMAP};
+
+    assert_eq!(syscall3(SYS_MMAP as u64, 0, 4096, PROT_READ), -EINVAL);
+    assert_eq!(syscall3(SYS_MUNMAP as u64, 0x10_0000_0000, 4096, 0), -EINVAL);
+}
@@ -0,0 +1,201 @@
+//! System call dispatch.
+//!
+//! Every entry path (the legacy `int 0x80` gate and the far call gate) saves the
+//! general purpose registers, decodes the syscall number and up to six
+//! arguments from them and forwards them to [`dispatch`]. The return value goes back to the caller in
+//! RAX, errors as a negated errno like on Linux.
+
+pub mod callgate;
+pub mod fd;
+pub mod int80;
+pub mod io;
+pub mod memory;
+pub mod process;
+pub mod stat;
+pub mod time;
+
+/// No such file or directory.
+pub const ENOENT: i64 = 2;
+/// Input/output error.
+pub const EIO: i64 = 5;
+/// Bad file descriptor.
+pub const EBADF: i64 = 9;
+/// Try again.
+pub const EAGAIN: i64 = 11;
+/// Out of memory.
+pub const ENOMEM: i64 = 12;
+/// Permission denied.
+pub const EACCES: i64 = 13;
+/// Bad address.
+pub const EFAULT: i64 = 14;
+/// Device or resource busy.
+pub const EBUSY: i64 = 16;
+/// File exists.
+pub const EEXIST: i64 = 17;
+/// Not a directory.
+pub const ENOTDIR: i64 = 20;
+/// Is a directory.
+pub const EISDIR: i64 = 21;
+/// Invalid argument.
+pub const EINVAL: i64 = 22;
+/// Too many open files in the system.
+pub const ENFILE: i64 = 23;
+/// Too many open files.
+pub const EMFILE: i64 = 24;
+/// Not a typewriter.
+pub const ENOTTY: i64 = 25;
+/// No space left on device.
+pub const ENOSPC: i64 = 28;
+/// Read-only file system.
+pub const EROFS: i64 = 30;
+/// File name too long.
+pub const ENAMETOOLONG: i64 = 36;
+/// Function not implemented.
+pub const ENOSYS: i64 = 38;
+/// Too many levels of symbolic links.
+pub const ELOOP: i64 = 40;
+
+/// Syscall numbers, matching Linux x86_64.
+pub const SYS_READ: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_OPEN: usize = 2;
+pub const SYS_CLOSE: usize = 3;
+pub const SYS_STAT: usize = 4;
+pub const SYS_LSTAT: usize = 6;
+pub const SYS_LSEEK: usize = 8;
+pub const SYS_MMAP: usize = 9;
+pub const SYS_MUNMAP: usize = 11;
+pub const SYS_IOCTL: usize = 16;
+pub const SYS_SCHED_YIELD: usize = 24;
+pub const SYS_DUP: usize = 32;
+pub const SYS_NANOSLEEP: usize = 35;
+pub const SYS_GETPID: usize = 39;
+pub const SYS_FORK: usize = 57;
+pub const SYS_EXIT: usize = 60;
+pub const SYS_GETPPID: usize = 110;
+pub const SYS_GETDENTS64: usize = 217;
+
+/// A system call implementation, taking the raw argument registers.
+pub type SyscallFn = fn(args: &[u64; 6]) -> i64;
+
+const SYSCALL_COUNT: usize = 218;
+
+/// Syscall table indexed by syscall number.
+static SYSCALL_TABLE: [Option<SyscallFn>; SYSCALL_COUNT] = {
+    let mut table: [Option<SyscallFn>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[SYS_READ] = Some(io::sys_read);
+    table[SYS_WRITE] = Some(io::sys_write);
+    table[SYS_OPEN] = Some(fd::sys_open);
+    table[SYS_CLOSE] = Some(fd::sys_close);
+    table[SYS_STAT] = Some(stat::sys_stat);
+    table[SYS_LSTAT] = Some(stat::sys_lstat);
+    table[SYS_LSEEK] = Some(fd::sys_lseek);
+    table[SYS_MMAP] = Some(memory::sys_mmap);
+    table[SYS_MUNMAP] = Some(memory::sys_munmap);
+    table[SYS_IOCTL] = Some(io::sys_ioctl);
+    table[SYS_SCHED_YIELD] = Some(process::sys_sched_yield);
+    table[SYS_DUP] = Some(fd::sys_dup);
+    table[SYS_NANOSLEEP] = Some(time::sys_nanosleep);
+    table[SYS_GETPID] = Some(process::sys_getpid);
+    table[SYS_FORK] = Some(process::sys_fork);
+    table[SYS_EXIT] = Some(process::sys_exit);
+    table[SYS_GETPPID] = Some(process::sys_getppid);
+    table[SYS_GETDENTS64] = Some(fd::sys_getdents64);
+    table
+};
+
+/// The general purpose registers as pushed by the entry trampolines, RAX first.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct GeneralRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// Runs the syscall described by saved registers, see the `int80` module for the
+/// convention, and stores the result in RAX.
+fn dispatch_registers(regs: &mut GeneralRegisters) {
+    let args = [regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp];
+    regs.rax = dispatch(regs.rax, &args) as u64;
+}
+
+/// Runs syscall `number` and returns the value to hand back in RAX.
+pub fn dispatch(number: u64, args: &[u64; 6]) -> i64 {
+    let handler = usize::try_from(number)
+        .ok()
+        .and_then(|n| SYSCALL_TABLE.get(n))
+        .copied()
+        .flatten();
+    match handler {
+        Some(handler) => handler(args),
+        None => -ENOSYS,
+    }
+}
+
+/// Copies from the caller supplied address `src` into `dst`.
+///
+/// Same checks as [`copy_to_user`], for reading.
+///
+/// ## Safety
+///
+/// `src..src + dst.len()` must be readable memory of the caller.
+pub unsafe fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), i64> {
+    if src == 0 || src.checked_add(dst.len() as u64).is_none() {
+        return Err(EFAULT);
+    }
+    crate::process::check_user_access(src, dst.len() as u64, false)?;
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()); }
+    Ok(())
+}
+
+/// Copies the NUL terminated path at the caller supplied address `src` into
+/// `dst` and returns it, without the NUL.
+///
+/// Fails with `ENAMETOOLONG` when no NUL comes within `dst.len()` bytes, and
+/// with `EINVAL` when the path is not UTF-8. Same checks as [`copy_from_user`]
+/// otherwise.
+///
+/// ## Safety
+///
+/// The bytes from `src` up to the NUL, or `dst.len()` of them, must be readable
+/// memory of the caller.
+pub unsafe fn copy_path_from_user(dst: &mut [u8], src: u64) -> Result<&str, i64> {
+    for i in 0..dst.len() {
+        let byte = src.checked_add(i as u64).ok_or(EFAULT)?;
+        unsafe { copy_from_user(&mut dst[i..i + 1], byte)?; }
+        if dst[i] == 0 {
+            return core::str::from_utf8(&dst[..i]).map_err(|_| EINVAL);
+        }
+    }
+    Err(ENAMETOOLONG)
+}
+
+/// Copies `src` to the caller supplied address `dst`.
+///
+/// Rejects null and wrapping ranges with `EFAULT`, and for a process also
+/// ranges it may not write to, see [`crate::process::check_user_access`].
+///
+/// ## Safety
+///
+/// `dst..dst + src.len()` must be writable memory of the caller.
+pub unsafe fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), i64> {
+    if dst == 0 || dst.checked_add(src.len() as u64).is_none() {
+        return Err(EFAULT);
+    }
+    crate::process::check_user_access(dst, src.len() as u64, true)?;
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()); }
+    Ok(())
+}
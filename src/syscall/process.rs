@@ -0,0 +1,66 @@
+//! Process related system calls.
+
+use crate::process::{self, scheduler, SpawnError, KERNEL_PID};
+use super::{EAGAIN, EINVAL, ENOMEM};
+
+/// `fork()`: duplicates the calling process, see [`process::fork`]. Returns
+/// the id of the child, and 0 in the child.
+///
+/// Only processes entering through the `int 0x80` gate can fork, which saves
+/// every register for the child; the kernel and the call gate get `EINVAL`.
+pub fn sys_fork(_args: &[u64; 6]) -> i64 {
+    let registers = scheduler::syscall_registers().filter(|_| scheduler::current_pid() != KERNEL_PID);
+    let Some(registers) = registers else {
+        return -EINVAL;
+    };
+    match process::fork(unsafe { &*registers }) {
+        Ok(pid) => pid.into(),
+        Err(SpawnError::TooManyProcesses | SpawnError::TooManyThreads) => -EAGAIN,
+        Err(_) => -ENOMEM,
+    }
+}
+
+/// `exit(code)`: ends the calling process with the low 32 bits of `code`.
+///
+/// The kernel cannot exit, it gets `EINVAL`.
+pub fn sys_exit(args: &[u64; 6]) -> i64 {
+    if scheduler::current_pid() == KERNEL_PID {
+        return -EINVAL;
+    }
+    process::exit(args[0] as i32)
+}
+
+/// `getpid()`: returns the id of the calling process, [`KERNEL_PID`] for
+/// the kernel.
+pub fn sys_getpid(_args: &[u64; 6]) -> i64 {
+    scheduler::current_pid().into()
+}
+
+/// `getppid()`: returns the id of the parent of the calling process, see
+/// [`process::parent`].
+pub fn sys_getppid(_args: &[u64; 6]) -> i64 {
+    process::parent(scheduler::current_pid()).unwrap_or(KERNEL_PID).into()
+}
+
+/// `sched_yield()`: lets the other threads run first.
+pub fn sys_sched_yield(_args: &[u64; 6]) -> i64 {
+    scheduler::yield_now();
+    0
+}
+
+#[test_case]
+fn kernel_cannot_exit_or_fork() {
+    use super::{int80::syscall3, SYS_EXIT, SYS_FORK, SYS_SCHED_YIELD};
+
+    assert_eq!(syscall3(SYS_EXIT as u64, 0, 0, 0), -EINVAL);
+    assert_eq!(syscall3(SYS_FORK as u64, 0, 0, 0), -EINVAL);
+    assert_eq!(syscall3(SYS_SCHED_YIELD as u64, 0, 0, 0), 0);
+}
+
+#[test_case]
+fn kernel_is_pid_0_and_its_own_parent() {
+    use super::{int80::syscall3, SYS_GETPID, SYS_GETPPID};
+
+    assert_eq!(syscall3(SYS_GETPID as u64, 0, 0, 0), KERNEL_PID.into());
+    assert_eq!(syscall3(SYS_GETPPID as u64, 0, 0, 0), KERNEL_PID.into());
+}
@@ -0,0 +1,140 @@
+//! File metadata system calls.
+
+use core::mem::size_of;
+use crate::fs::vfs::{self, Metadata, NodeKind, MAX_PATH};
+use super::{copy_path_from_user, copy_to_user, fd::errno, time::Timespec};
+
+/// The type bits of `st_mode`, and the types there are.
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+/// The unit of `st_blocks`.
+const BLOCK_UNIT: u64 = 512;
+
+/// The metadata of a file as `stat` returns it, laid out like the C
+/// `struct stat` of x86_64 Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_nlink: u64,
+    /// The type bits, `S_IF*`, and the permission bits.
+    pub st_mode: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pad: u32,
+    pub st_rdev: u64,
+    pub st_size: i64,
+    pub st_blksize: i64,
+    /// The space taken, in 512 byte units.
+    pub st_blocks: i64,
+    pub st_atime: Timespec,
+    pub st_mtime: Timespec,
+    pub st_ctime: Timespec,
+    reserved: [i64; 3],
+}
+
+impl Stat {
+    fn to_bytes(self) -> [u8; size_of::<Stat>()] {
+        unsafe { core::mem::transmute(self) }
+    }
+}
+
+/// Filesystems keep no owner, link count or change time for the VFS: every
+/// node has one link and belongs to root, and it changed when it was last
+/// modified. What a filesystem does not date is dated from the epoch.
+impl From<Metadata> for Stat {
+    fn from(metadata: Metadata) -> Self {
+        let time = |date: Option<crate::fs::DateTime>| Timespec { tv_sec: date.map_or(0, |date| date.to_unix() as i64), tv_nsec: 0 };
+        let kind = match metadata.kind {
+            NodeKind::File => S_IFREG,
+            NodeKind::Directory => S_IFDIR,
+            NodeKind::Symlink => S_IFLNK,
+        };
+        Stat {
+            st_ino: metadata.inode,
+            st_nlink: 1,
+            st_mode: kind | metadata.mode,
+            st_size: metadata.size as i64,
+            st_blksize: BLOCK_UNIT as i64,
+            st_blocks: metadata.size.div_ceil(BLOCK_UNIT) as i64,
+            st_atime: time(metadata.accessed.or(metadata.modified)),
+            st_mtime: time(metadata.modified),
+            st_ctime: time(metadata.modified),
+            ..Stat::default()
+        }
+    }
+}
+
+/// Copies the path at `path` from the caller, looks it up with `metadata`
+/// and copies the result to `statbuf`.
+fn stat_with(path: u64, statbuf: u64, metadata: fn(&str) -> Result<Metadata, vfs::FsError>) -> i64 {
+    let mut buffer = [0u8; MAX_PATH];
+    let path = match unsafe { copy_path_from_user(&mut buffer, path) } {
+        Ok(path) => path,
+        Err(errno) => return -errno,
+    };
+    let stat = match metadata(path) {
+        Ok(metadata) => Stat::from(metadata),
+        Err(err) => return -errno(err),
+    };
+    match unsafe { copy_to_user(statbuf, &stat.to_bytes()) } {
+        Ok(()) => 0,
+        Err(errno) => -errno,
+    }
+}
+
+/// `stat(path, statbuf)`: copies the metadata of the file at the absolute
+/// `path` to `statbuf`, a [`Stat`]. A symbolic link leading nowhere fails
+/// with `ENOENT`, as its target does.
+pub fn sys_stat(args: &[u64; 6]) -> i64 {
+    stat_with(args[0], args[1], vfs::metadata)
+}
+
+/// `lstat(path, statbuf)`: like `stat`, but of the symbolic link itself when
+/// `path` ends at one.
+pub fn sys_lstat(args: &[u64; 6]) -> i64 {
+    stat_with(args[0], args[1], vfs::symlink_metadata)
+}
+
+#[test_case]
+fn stat_copies_metadata_out() {
+    use super::{fd::mount_fixture, int80::syscall3, EFAULT, ELOOP, ENOENT, ENOTDIR, SYS_LSTAT, SYS_STAT};
+    mount_fixture();
+    let mut stat = Stat::default();
+    let call = |number: usize, path: &[u8], stat: &mut Stat| syscall3(number as u64, path.as_ptr() as u64, stat as *mut _ as u64, 0);
+
+    assert_eq!(call(SYS_STAT, b"/etc/hostname\0", &mut stat), 0);
+    assert_eq!((stat.st_mode, stat.st_size, stat.st_blocks, stat.st_nlink), (S_IFREG | 0o600, 8, 1, 1));
+    assert_eq!(stat.st_mtime, Timespec { tv_sec: 1_715_953_530, tv_nsec: 0 });
+    let hostname = stat.st_ino;
+    assert_eq!(call(SYS_STAT, b"/etc\0", &mut stat), 0);
+    assert_eq!(stat.st_mode & S_IFMT, S_IFDIR);
+    assert_ne!(stat.st_ino, hostname);
+
+    // Through the link, or of the link.
+    assert_eq!(call(SYS_STAT, b"/motd\0", &mut stat), 0);
+    assert_eq!((stat.st_mode & S_IFMT, stat.st_size), (S_IFREG, 20));
+    assert_eq!(call(SYS_LSTAT, b"/motd\0", &mut stat), 0);
+    assert_eq!(stat.st_mode, S_IFLNK | 0o777);
+    assert_eq!(call(SYS_STAT, b"/loop\0", &mut stat), -ELOOP);
+    assert_eq!(call(SYS_LSTAT, b"/loop\0", &mut stat), 0);
+
+    assert_eq!(call(SYS_STAT, b"/nowhere\0", &mut stat), -ENOENT);
+    assert_eq!(call(SYS_STAT, b"/hello.txt/x\0", &mut stat), -ENOTDIR);
+    assert_eq!(syscall3(SYS_STAT as u64, b"/etc\0".as_ptr() as u64, 0, 0), -EFAULT);
+}
+
+#[test_case]
+fn stat_fails_on_dangling_links() {
+    use super::{fd::mount_numbered, int80::syscall3, ENOENT, SYS_LSTAT, SYS_STAT};
+    mount_numbered();
+    let mut stat = Stat::default();
+    let path = b"/deep/dangling\0".as_ptr() as u64;
+    assert_eq!(syscall3(SYS_STAT as u64, path, &mut stat as *mut _ as u64, 0), -ENOENT);
+    assert_eq!(syscall3(SYS_LSTAT as u64, path, &mut stat as *mut _ as u64, 0), 0);
+    assert_eq!((stat.st_mode, stat.st_mtime), (S_IFLNK | 0o777, Timespec::default()));
+}
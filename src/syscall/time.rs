@@ -0,0 +1,91 @@
+//! Time related system calls.
+
+use core::mem::size_of;
+use crate::{pic::timer, process::scheduler, tables::RFlags};
+use super::{copy_from_user, copy_to_user, EAGAIN, EINVAL};
+
+/// Longest accepted sleep, one day. Longer requests are cut to it.
+pub const MAX_SLEEP_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// A duration as passed to `nanosleep`, laid out like the C `struct timespec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    fn to_bytes(self) -> [u8; size_of::<Timespec>()] {
+        unsafe { core::mem::transmute(self) }
+    }
+
+    fn from_bytes(bytes: [u8; size_of::<Timespec>()]) -> Self {
+        unsafe { core::mem::transmute(bytes) }
+    }
+
+    /// Returns the duration in milliseconds rounded up, so a sleep never ends early.
+    /// `None` if a field is negative or `tv_nsec` is not below one second.
+    pub fn to_ms(self) -> Option<u64> {
+        if self.tv_sec < 0 || !(0..1_000_000_000).contains(&self.tv_nsec) {
+            return None;
+        }
+        let ms = (self.tv_sec as u64).saturating_mul(1000);
+        Some(ms.saturating_add((self.tv_nsec as u64).div_ceil(1_000_000)))
+    }
+}
+
+/// `nanosleep(req, rem)`: waits for the duration at `req`, capped to `MAX_SLEEP_MS`.
+///
+/// A process blocks on the sleep queue, see [`scheduler::sleep_until`], and the
+/// other threads run meanwhile. The kernel, on the boot thread, has no one to
+/// give the CPU to and idles on `hlt` instead. There are no signals to cut a
+/// sleep short, so `rem`, when not null, always receives zero.
+pub fn sys_nanosleep(args: &[u64; 6]) -> i64 {
+    let [req, rem, ..] = *args;
+    let mut bytes = [0u8; size_of::<Timespec>()];
+    if let Err(errno) = unsafe { copy_from_user(&mut bytes, req) } {
+        return -errno;
+    }
+    let Some(ms) = Timespec::from_bytes(bytes).to_ms() else {
+        return -EINVAL;
+    };
+    if !RFlags::read().contains(RFlags::INTERRUPT_FLAG) {
+        return -EAGAIN;
+    }
+    let ms = ms.min(MAX_SLEEP_MS);
+    if scheduler::current() == scheduler::BOOT_THREAD {
+        timer::delay_ms(ms);
+    } else {
+        scheduler::sleep_until(timer::ticks() + timer::ms_to_ticks(ms));
+    }
+
+    if rem != 0 {
+        if let Err(errno) = unsafe { copy_to_user(rem, &Timespec::default().to_bytes()) } {
+            return -errno;
+        }
+    }
+    0
+}
+
+#[test_case]
+fn nanosleep_waits_idle() {
+    use super::{int80::syscall3, SYS_NANOSLEEP};
+
+    let req = Timespec { tv_sec: 0, tv_nsec: 50_000_000 };
+    let mut rem = Timespec { tv_sec: 1, tv_nsec: 1 };
+    let start = timer::ticks();
+    let idle_start = timer::idle_ticks();
+    let ret = syscall3(SYS_NANOSLEEP as u64, &req as *const _ as u64, &mut rem as *mut _ as u64, 0);
+    let elapsed = timer::ticks() - start;
+    let idle = timer::idle_ticks() - idle_start;
+
+    assert_eq!(ret, 0);
+    assert_eq!(rem, Timespec::default());
+    assert!(elapsed >= timer::ms_to_ticks(50), "slept {} ticks", elapsed);
+    // Only the tick ending the last `hlt` may find the CPU busy.
+    assert!(idle + 1 >= elapsed, "{} of {} ticks idle", idle, elapsed);
+
+    let bad = Timespec { tv_sec: 0, tv_nsec: 1_000_000_000 };
+    assert_eq!(syscall3(SYS_NANOSLEEP as u64, &bad as *const _ as u64, 0, 0), -EINVAL);
+}
@@ -1,67 +1,297 @@
-use crate::{println, tables::InterruptStackFrame};
+use core::{arch::asm, fmt, sync::atomic::Ordering};
+use bitflags::bitflags;
+use crate::{
+    cpu::{cpuid, Msr, MSR_FIXUP},
+    memory::paging::PROBE_FIXUP,
+    print_hex_dump, println,
+    tables::{enter_interrupt, InterruptStackFrame},
+};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MC0_STATUS: u32 = 0x401;
+const IA32_MC0_ADDR: u32 = 0x402;
+/// `cpuid` leaf 1, EDX: the machine-check architecture MSRs exist.
+const CPUID_MCA: u32 = 1 << 14;
+/// Banks past this are not decoded.
+const MAX_MC_BANKS: usize = 32;
+
+/// IA32_MCG_STATUS bits.
+const MCG_RIPV: u64 = 1 << 0;
+const MCG_EIPV: u64 = 1 << 1;
+const MCG_MCIP: u64 = 1 << 2;
+
+/// MCi_STATUS bits.
+const MC_VAL: u64 = 1 << 63;
+const MC_OVER: u64 = 1 << 62;
+const MC_UC: u64 = 1 << 61;
+const MC_EN: u64 = 1 << 60;
+const MC_ADDRV: u64 = 1 << 58;
+const MC_PCC: u64 = 1 << 57;
+
+/// The status of one machine-check bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct McBankStatus {
+    pub bank: u8,
+    /// The bank holds an error.
+    pub valid: bool,
+    /// Another error arrived while this one was still logged.
+    pub over: bool,
+    /// The error was not corrected.
+    pub uc: bool,
+    /// Reporting the error was enabled, i.e. it raised the exception.
+    pub enabled: bool,
+    /// The processor context may be corrupt.
+    pub pcc: bool,
+    /// Architectural MCA error code, bits 15:0.
+    pub error_code: u16,
+    /// Address of the faulting memory, when the bank has one.
+    pub address: Option<u64>,
+}
+
+impl McBankStatus {
+    /// Decodes the `MCi_STATUS` value of `bank`, without the address.
+    pub fn from_status(bank: u8, status: u64) -> Self {
+        McBankStatus {
+            bank,
+            valid: status & MC_VAL != 0,
+            over: status & MC_OVER != 0,
+            uc: status & MC_UC != 0,
+            enabled: status & MC_EN != 0,
+            pcc: status & MC_PCC != 0,
+            error_code: status as u16,
+            address: None,
+        }
+    }
+}
+
+impl fmt::Display for McBankStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bank {}: error code {:#06x}{}{}{}",
+            self.bank, self.error_code,
+            if self.uc { " uncorrected" } else { " corrected" },
+            if self.pcc { " context-corrupt" } else { "" },
+            if self.over { " overflow" } else { "" })?;
+        if let Some(address) = self.address {
+            write!(f, " at {:#x}", address)?;
+        }
+        Ok(())
+    }
+}
+
+/// Machine-check state read by [`decode_machine_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineCheckInfo {
+    /// Execution can restart at the interrupted instruction.
+    pub restart_ip_valid: bool,
+    /// The interrupted instruction caused the error.
+    pub error_ip_valid: bool,
+    pub in_progress: bool,
+    bank_count: usize,
+    banks: [McBankStatus; MAX_MC_BANKS],
+}
+
+impl MachineCheckInfo {
+    /// Every bank the processor has, valid or not.
+    pub fn banks(&self) -> &[McBankStatus] {
+        &self.banks[..self.bank_count]
+    }
+}
+
+/// Reads the global machine-check status and every bank, all empty if the
+/// processor lacks the machine-check architecture.
+pub fn decode_machine_check() -> MachineCheckInfo {
+    let mut info = MachineCheckInfo {
+        restart_ip_valid: false,
+        error_ip_valid: false,
+        in_progress: false,
+        bank_count: 0,
+        banks: [McBankStatus::default(); MAX_MC_BANKS],
+    };
+    if cpuid(1, 0).edx & CPUID_MCA == 0 {
+        return info;
+    }
+    let (capabilities, status) = unsafe { (Msr::new(IA32_MCG_CAP).read(), Msr::new(IA32_MCG_STATUS).read()) };
+    info.restart_ip_valid = status & MCG_RIPV != 0;
+    info.error_ip_valid = status & MCG_EIPV != 0;
+    info.in_progress = status & MCG_MCIP != 0;
+    info.bank_count = ((capabilities & 0xFF) as usize).min(MAX_MC_BANKS);
+    for (i, bank) in info.banks[..info.bank_count].iter_mut().enumerate() {
+        let status = unsafe { Msr::new(IA32_MC0_STATUS + 4 * i as u32).read() };
+        *bank = McBankStatus::from_status(i as u8, status);
+        // MCi_ADDR may not even exist unless ADDRV says it holds something.
+        if bank.valid && status & MC_ADDRV != 0 {
+            bank.address = Some(unsafe { Msr::new(IA32_MC0_ADDR + 4 * i as u32).read() });
+        }
+    }
+    info
+}
+
+bitflags! {
+    /// The SSE control and status register.
+    #[repr(transparent)]
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub struct Mxcsr: u32 {
+        /// Invalid operation flag.
+        const IE = 1 << 0;
+        /// Denormal operand flag.
+        const DE = 1 << 1;
+        /// Divide by zero flag.
+        const ZE = 1 << 2;
+        /// Overflow flag.
+        const OE = 1 << 3;
+        /// Underflow flag.
+        const UE = 1 << 4;
+        /// Precision (inexact result) flag.
+        const PE = 1 << 5;
+        /// Denormal operands are read as zero.
+        const DAZ = 1 << 6;
+        /// Masks of the exceptions above, set to not raise them.
+        const IM = 1 << 7;
+        const DM = 1 << 8;
+        const ZM = 1 << 9;
+        const OM = 1 << 10;
+        const UM = 1 << 11;
+        const PM = 1 << 12;
+        /// The two rounding control bits, round to nearest when both are clear.
+        const RC_DOWN = 1 << 13;
+        const RC_UP = 1 << 14;
+        /// Underflowing results are flushed to zero.
+        const FZ = 1 << 15;
+
+        /// Every exception flag.
+        const EXCEPTIONS = Self::IE.bits() | Self::DE.bits() | Self::ZE.bits()
+            | Self::OE.bits() | Self::UE.bits() | Self::PE.bits();
+        /// Every exception mask.
+        const MASKS = Self::IM.bits() | Self::DM.bits() | Self::ZM.bits()
+            | Self::OM.bits() | Self::UM.bits() | Self::PM.bits();
+    }
+}
+
+/// Reads MXCSR. Like any SSE instruction it needs CR4.OSFXSR, which a SIMD
+/// floating-point exception implies.
+pub fn read_mxcsr() -> Mxcsr {
+    let mut value: u32 = 0;
+    unsafe {
+        asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack, preserves_flags));
+    }
+    Mxcsr::from_bits_retain(value)
+}
+
+/// Writes MXCSR.
+pub fn write_mxcsr(mxcsr: Mxcsr) {
+    let value = mxcsr.bits();
+    unsafe {
+        asm!("ldmxcsr [{}]", in(reg) &value, options(nostack, readonly));
+    }
+}
+
+/// Kills the current process, see [`crate::process::segfault`], if
+/// `stack_frame` says the fault came from ring 3.
+fn kill_user_fault(stack_frame: &InterruptStackFrame, addr: Option<u64>) {
+    if stack_frame.code_segment.0 & 3 == 3 {
+        crate::process::segfault(addr, stack_frame.instruction_pointer);
+    }
+}
 
 pub extern "x86-interrupt" fn divide_error(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    kill_user_fault(&stack_frame, None);
     panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn debug(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn non_maskable_interrupt(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: NON MASKABLE INTERRUPT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn overflow(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    kill_user_fault(&stack_frame, None);
     panic!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn bound_range_exceeded(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn invalid_opcode(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    kill_user_fault(&stack_frame, Some(stack_frame.instruction_pointer));
     panic!("EXCEPTION: INVALID OP CODE\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn coprocessor_not_available(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: COPROCESSOR NOT AVAILABLE\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
+    // Fatal from ring 3 too: the handler runs on the IST 1 stack of the CPU,
+    // which switching away from here would leave in use by a dead thread.
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn invalid_tss(stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: INVALID TSS\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn segment_not_present(stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: SEGMENT NOT PRESENT\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn stack_segment_fault(stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
+    kill_user_fault(&stack_frame, None);
     panic!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}", stack_frame);
 }
 
-pub extern "x86-interrupt" fn general_protection_fault(stack_frame: InterruptStackFrame, _errcode: u64) {
+pub extern "x86-interrupt" fn general_protection_fault(mut stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
+    // A fault in `Msr::read_safe` or `write_safe` only means the MSR is not there.
+    let fixup = MSR_FIXUP.swap(0, Ordering::Relaxed);
+    if fixup != 0 {
+        unsafe { stack_frame.set_instruction_pointer(fixup).unwrap(); }
+        return;
+    }
+    kill_user_fault(&stack_frame, None);
     panic!("EXCEPTION: GPF\n{:#?}", stack_frame);
 }
 
-pub extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame, errcode: u64) {
+pub extern "x86-interrupt" fn page_fault(mut stack_frame: InterruptStackFrame, errcode: u64) {
+    let _depth = enter_interrupt();
+    // A fault in `probe_read` only means the address was not readable.
+    let fixup = PROBE_FIXUP.swap(0, Ordering::Relaxed);
+    if fixup != 0 {
+        unsafe { stack_frame.set_instruction_pointer(fixup).unwrap(); }
+        return;
+    }
     use core::arch::asm;
     use crate::print;
 
     let addr: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) addr, options(nomem, nostack, preserves_flags)); }
+    // The first touch from ring 3 of a page to map, or write to a page to copy.
+    if stack_frame.code_segment.0 & 3 == 3 && crate::process::resolve_fault(addr, errcode & 2 != 0) {
+        return;
+    }
+    kill_user_fault(&stack_frame, Some(addr));
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed address: {}", unsafe {
-        asm!("mov {}, cr2", out(reg) addr, options(nomem, nostack, preserves_flags)) ;
-        format_args!("{:#x}", addr)
-    });
+    println!("Accessed address: {:#x}", addr);
     print!("Error code:");
     if (errcode & 1) != 0 { print!(" Protection violation") } 
     if (errcode & 2) != 0 { print!(" Caused by write") }
@@ -73,42 +303,111 @@ pub extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame, errco
     println!();
     println!("{:#?}", stack_frame);
 
+    // Only the page of the faulting instruction is known to be mapped, and not
+    // even that one if fetching the instruction is what faulted.
+    let rip = stack_frame.instruction_pointer;
+    if errcode & 16 == 0 {
+        let start = rip.saturating_sub(8).max(rip & !0xFFF);
+        let end = (rip + 8).min((rip & !0xFFF) + 0x1000);
+        println!("Code at {:#x}:", start);
+        print_hex_dump!(unsafe { core::slice::from_raw_parts(start as *const u8, (end - start) as usize) });
+    }
+
     loop {
         unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)); }
     }
 }
 pub extern "x86-interrupt" fn x87_floating_point(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    // The x87 state is not switched with the threads, so a pending exception
+    // left there would fault in the next one to use it.
+    unsafe {
+        asm!("fnclex", options(nomem, nostack, preserves_flags));
+    }
+    kill_user_fault(&stack_frame, None);
     panic!("EXCEPTION: x87_floating_point\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn alignment_check(stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
+    kill_user_fault(&stack_frame, None);
     panic!("EXCEPTION: alignment_check\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn machine_check(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    let info = decode_machine_check();
+    println!("machine check: RIPV={} EIPV={} MCIP={}", info.restart_ip_valid, info.error_ip_valid, info.in_progress);
+    for bank in info.banks().iter().filter(|bank| bank.valid) {
+        println!("  {}", bank);
+    }
     panic!("EXCEPTION: machine_check\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn simd_floating_point(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: simd_floating_point\n{:#?}", stack_frame);
+    let _depth = enter_interrupt();
+    let mxcsr = read_mxcsr();
+    // Left set, the flags would raise the exception again on the next SSE instruction.
+    write_mxcsr(mxcsr & !Mxcsr::EXCEPTIONS);
+    kill_user_fault(&stack_frame, None);
+    panic!("EXCEPTION: simd_floating_point ({:?})\n{:#?}", mxcsr & Mxcsr::EXCEPTIONS, stack_frame);
 }
 
 pub extern "x86-interrupt" fn virtualization(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: virtualization\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn cp_protection_exception(stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: cp_protection_exception\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn hv_injection_exception(stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: hv_injection_exception\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn vmm_communication_exception(stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: vmm_communication_exception\n{:#?}", stack_frame);
 }
 
 pub extern "x86-interrupt" fn security_exception(stack_frame: InterruptStackFrame, _errcode: u64) {
+    let _depth = enter_interrupt();
     panic!("EXCEPTION: security_exception\n{:#?}", stack_frame);
 }
+
+#[test_case]
+fn machine_check_bank_status_decoding() {
+    // Uncorrected, context-corrupt, with an address: a memory read error (0x009F).
+    let status = McBankStatus::from_status(4, 0xB200_0000_0001_009F | MC_ADDRV);
+    assert!(status.valid && status.uc && status.enabled && status.pcc && !status.over);
+    assert_eq!(status.error_code, 0x009F);
+    assert_eq!(status.bank, 4);
+
+    // A corrected error that overflowed.
+    let status = McBankStatus::from_status(0, MC_VAL | MC_OVER | 0x0150);
+    assert!(status.valid && status.over && !status.uc && !status.pcc);
+    assert_eq!(status.error_code, 0x0150);
+
+    assert!(!McBankStatus::from_status(1, 0).valid);
+    // Reading the real banks must not fault.
+    assert!(decode_machine_check().banks().len() <= MAX_MC_BANKS);
+}
+
+#[test_case]
+fn mxcsr_decoding() {
+    // The reset value: every exception masked, no flag raised.
+    let reset = Mxcsr::from_bits_retain(0x1F80);
+    assert_eq!(reset, Mxcsr::MASKS);
+    assert!((reset & Mxcsr::EXCEPTIONS).is_empty());
+
+    // A masked division by zero that was also inexact.
+    let mxcsr = Mxcsr::from_bits_retain(0x1F80 | 0b10_0100);
+    assert_eq!(mxcsr & Mxcsr::EXCEPTIONS, Mxcsr::ZE | Mxcsr::PE);
+    assert_eq!(Mxcsr::EXCEPTIONS.bits(), 0x3F);
+    assert_eq!(Mxcsr::MASKS.bits(), 0x3F << 7);
+    assert!(Mxcsr::from_bits_retain(0x8040).contains(Mxcsr::FZ | Mxcsr::DAZ));
+    assert_eq!(Mxcsr::from_bits_retain(0x6000), Mxcsr::RC_DOWN | Mxcsr::RC_UP);
+}
@@ -1,8 +1,8 @@
 use lazy_static::lazy_static;
-use crate::tables::DescriptorTablePointer;
-use core::arch::asm;
+use crate::{sync::Mutex, tables::DescriptorTablePointer};
+use core::{arch::asm, fmt, sync::atomic::{AtomicU16, Ordering}};
 
-use super::{selectors::{Segment, SegmentSelector, CS, DS}, tss::{TaskStateSegment, TSS}};
+use super::{segments::{Segment, CS, DS}, selectors::{PrivilegeLevel, SegmentSelector}, tss::{TaskStateSegment, TSS}};
 
 const SEGMENT_LIMIT: u32 = 0xFFFFFFFF;
 const SEGMENT_BASE: u32  = 0;
@@ -47,70 +47,248 @@ const I86_GDT_GRAND_64BIT: u8 = 0x20;           //00100000
 // 4k granularity. default: none
 const I86_GDT_GRAND_4K: u8 = 0x80;			    //10000000
 
+/***	system descriptor types.	***/
+
+// 64-bit call gate
+const I86_GDT_TYPE_CALL_GATE: u8 = 0x0C;		//00001100
+
+/// Indices of the flat code and data descriptors set up by `load_gdt`.
+const KERNEL_CODE32_INDEX: u16 = 1;
+const KERNEL_CODE64_INDEX: u16 = 2;
+const KERNEL_DATA_INDEX: u16 = 3;
+const USER_CODE32_INDEX: u16 = 4;
+const USER_CODE64_INDEX: u16 = 5;
+const USER_DATA_INDEX: u16 = 6;
+
+/// First GDT index free for runtime allocated descriptors, after the TSS of CPU 0 at 7-8.
+const FIRST_FREE_INDEX: usize = 9;
+/// Index of the TSS descriptor of CPU 0.
+const BOOT_TSS_INDEX: usize = 7;
+/// Busy bit of a TSS descriptor's type, set by `ltr`.
+const I86_GDT_TYPE_TSS_BUSY: u8 = 0x02;
+
+/// CPUs that can have a TSS.
+pub const MAX_CPUS: usize = 16;
+
+/// The TSS selector of every CPU, 0 while it has none.
+static TSS_SELECTORS: [AtomicU16; MAX_CPUS] = [const { AtomicU16::new(0) }; MAX_CPUS];
+
+/// Selector of the call gate installed by `load_gdt`, see `kernel_call_gate`.
+static KERNEL_CALL_GATE: AtomicU16 = AtomicU16::new(0);
+
 lazy_static! {
-    static ref GDT: GlobalDescriptorTable = {
+    static ref GDT: Mutex<GlobalDescriptorTable> = Mutex::new("GDT", {
         let mut gdt = GlobalDescriptorTable([GDTEntry::null(); 8192]);
         // Index 0 of GDT is NULL segment
 
         // kernel Code Selector 32bits
-        gdt.0[1].set_entry(SEGMENT_BASE, SEGMENT_LIMIT, 
+        gdt.0[KERNEL_CODE32_INDEX as usize].set_entry(SEGMENT_BASE, SEGMENT_LIMIT, 
 	    I86_GDT_DESC_READWRITE | I86_GDT_DESC_EXEC_CODE | I86_GDT_DESC_CODEDATA | I86_GDT_DESC_MEMORY,
 	    I86_GDT_GRAND_4K | I86_GDT_GRAND_32BIT | I86_GDT_GRAND_LIMITHI_MASK 
         );
 
         // kernel Code Selector 64bits
-        gdt.0[2].set_entry(SEGMENT_BASE, SEGMENT_LIMIT, 
+        gdt.0[KERNEL_CODE64_INDEX as usize].set_entry(SEGMENT_BASE, SEGMENT_LIMIT, 
 	    I86_GDT_DESC_READWRITE | I86_GDT_DESC_EXEC_CODE | I86_GDT_DESC_CODEDATA | I86_GDT_DESC_MEMORY,
 	    I86_GDT_GRAND_4K | I86_GDT_GRAND_64BIT | I86_GDT_GRAND_LIMITHI_MASK 
         );
 
         // kernel Data Selector
-        gdt.0[3].set_entry(SEGMENT_BASE, SEGMENT_LIMIT,
+        gdt.0[KERNEL_DATA_INDEX as usize].set_entry(SEGMENT_BASE, SEGMENT_LIMIT,
 	    I86_GDT_DESC_READWRITE | I86_GDT_DESC_CODEDATA | I86_GDT_DESC_MEMORY,
 	    I86_GDT_GRAND_4K | I86_GDT_GRAND_32BIT | I86_GDT_GRAND_LIMITHI_MASK 
         );
 
         // user Code Selector 32bits
-        gdt.0[4].set_entry(SEGMENT_BASE, SEGMENT_LIMIT,
+        gdt.0[USER_CODE32_INDEX as usize].set_entry(SEGMENT_BASE, SEGMENT_LIMIT,
 	    I86_GDT_DESC_DPL | I86_GDT_DESC_READWRITE | I86_GDT_DESC_EXEC_CODE | I86_GDT_DESC_CODEDATA | I86_GDT_DESC_MEMORY,
 	    I86_GDT_GRAND_4K | I86_GDT_GRAND_32BIT | I86_GDT_GRAND_LIMITHI_MASK 
         );
 
         // user Code Selector 64bits
-        gdt.0[5].set_entry(SEGMENT_BASE, SEGMENT_LIMIT,
+        gdt.0[USER_CODE64_INDEX as usize].set_entry(SEGMENT_BASE, SEGMENT_LIMIT,
 	    I86_GDT_DESC_DPL | I86_GDT_DESC_READWRITE | I86_GDT_DESC_EXEC_CODE | I86_GDT_DESC_CODEDATA | I86_GDT_DESC_MEMORY,
 	    I86_GDT_GRAND_4K | I86_GDT_GRAND_64BIT | I86_GDT_GRAND_LIMITHI_MASK 
         );
 
-        // kernel Data Selector
-        gdt.0[6].set_entry(SEGMENT_BASE, SEGMENT_LIMIT,
+        // user Data Selector
+        gdt.0[USER_DATA_INDEX as usize].set_entry(SEGMENT_BASE, SEGMENT_LIMIT,
 	    I86_GDT_DESC_DPL | I86_GDT_DESC_READWRITE | I86_GDT_DESC_CODEDATA | I86_GDT_DESC_MEMORY,
 	    I86_GDT_GRAND_4K | I86_GDT_GRAND_32BIT | I86_GDT_GRAND_LIMITHI_MASK 
         );
         
-        // tss
-        gdt.set_tss(&TSS, 7);
+        // tss of CPU 0, the others get theirs from `add_tss`
+        gdt.set_tss(&TSS, BOOT_TSS_INDEX);
+        TSS_SELECTORS[0].store(SegmentSelector::new(BOOT_TSS_INDEX as u16, 0, 0).0, Ordering::Relaxed);
+
+        // legacy syscall gate, callable from ring 3
+        let gate = install_call_gate(
+            &mut gdt,
+            kernel_code64_selector(),
+            crate::as_fn_ptr!(crate::syscall::callgate::call_gate_entry),
+            3,
+        ).expect("no room for the syscall call gate");
+        KERNEL_CALL_GATE.store(gate.0, Ordering::Relaxed);
 
         gdt
-    };
+    });
 }
 
 pub fn load_gdt() {
-    GDT.load();
+    load_gdt_for_cpu(0);
+}
+
+/// Loads the GDT on the current CPU, which is `cpu`, along with its TSS.
+pub fn load_gdt_for_cpu(cpu: usize) {
+    // The table stays in the static, so it can stay loaded once the lock is released.
+    unsafe { GDT.lock().load_unchecked(); }
     unsafe {
-        CS::set_reg(SegmentSelector::new(2, 0, 0));
-        DS::set_reg(SegmentSelector::new(3, 0, 0));
-        TSS.load(SegmentSelector::new(7, 0, 0));
+        CS::set_reg(kernel_code64_selector());
+        DS::set_reg(kernel_data_selector());
+    }
+    load_tss(cpu);
+}
+
+/// Adds a descriptor for `tss` to the loaded GDT and makes it the TSS of `cpu`,
+/// for [`load_tss`] on that CPU.
+///
+/// A CPU that already has a descriptor gets it rewritten, so starting the same
+/// CPU again does not use up slots.
+pub fn add_tss(cpu: usize, tss: &'static TaskStateSegment) -> Result<SegmentSelector, GdtFull> {
+    assert!(cpu < MAX_CPUS, "CPU {} out of range", cpu);
+    let mut gdt = GDT.lock();
+    let existing = SegmentSelector(TSS_SELECTORS[cpu].load(Ordering::Relaxed));
+    if existing.0 != 0 {
+        gdt.set_tss(tss, existing.index() as usize);
+        return Ok(existing);
+    }
+    let selector = gdt.add_tss(tss)?;
+    TSS_SELECTORS[cpu].store(selector.0, Ordering::Relaxed);
+    Ok(selector)
+}
+
+/// Loads the task register with the TSS of `cpu`, which must be the current CPU.
+///
+/// `ltr` marks the descriptor busy and refuses busy ones, so the flag is
+/// cleared first: a CPU can load its TSS again, after running with another.
+pub fn load_tss(cpu: usize) {
+    let selector = SegmentSelector(TSS_SELECTORS[cpu].load(Ordering::Relaxed));
+    assert!(selector.0 != 0, "no TSS for CPU {}", cpu);
+    GDT.lock().0[selector.index() as usize].access_byte &= !I86_GDT_TYPE_TSS_BUSY;
+    unsafe { TSS.load(selector); }
+}
+
+pub fn kernel_code64_selector() -> SegmentSelector {
+    SegmentSelector::new(KERNEL_CODE64_INDEX, 0, PrivilegeLevel::Ring0 as u16)
+}
+
+pub fn kernel_data_selector() -> SegmentSelector {
+    SegmentSelector::new(KERNEL_DATA_INDEX, 0, PrivilegeLevel::Ring0 as u16)
+}
+
+/// The user selectors have RPL 3, as CS and SS must when `iretq` returns to ring 3.
+pub fn user_code32_selector() -> SegmentSelector {
+    SegmentSelector::new(USER_CODE32_INDEX, 0, PrivilegeLevel::Ring3 as u16)
+}
+
+pub fn user_code64_selector() -> SegmentSelector {
+    SegmentSelector::new(USER_CODE64_INDEX, 0, PrivilegeLevel::Ring3 as u16)
+}
+
+pub fn user_data_selector() -> SegmentSelector {
+    SegmentSelector::new(USER_DATA_INDEX, 0, PrivilegeLevel::Ring3 as u16)
+}
+
+/// Returns the selector of the DPL 3 call gate entering the syscall dispatcher,
+/// see `syscall::callgate`. Only valid after `load_gdt`.
+pub fn kernel_call_gate() -> SegmentSelector {
+    SegmentSelector(KERNEL_CALL_GATE.load(Ordering::Relaxed))
+}
+
+pub struct GlobalDescriptorTable([GDTEntry; 8192]);
+
+/// The error returned when no GDT slot is left for a new descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GdtFull;
+
+impl fmt::Display for GdtFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("no free GDT slot")
     }
 }
 
-struct GlobalDescriptorTable(pub [GDTEntry; 8192]);
+/// A 64-bit call gate, spanning two GDT slots like the TSS descriptor.
+///
+/// Unlike a code segment descriptor, it holds no base or limit: the first slot
+/// carries the target code selector and the low 32 bits of the target offset,
+/// the second slot the high 32 bits of the offset.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CallGateDescriptor {
+    offset_low:     u16,
+    selector:       SegmentSelector,
+    reserved_1:     u8,
+    // present bit, DPL and the call gate type
+    access_byte:    u8,
+    offset_mid:     u16,
+    offset_high:    u32,
+    // bits 8 to 12 must be zero, they overlap the type field of a legacy descriptor
+    reserved_2:     u32,
+}
+
+impl CallGateDescriptor {
+    pub fn new(target_cs: SegmentSelector, target_offset: u64, dpl: u8) -> Self {
+        if dpl > 3 { panic!("Panic setting dpl for CallGateDescriptor") }
+        CallGateDescriptor {
+            offset_low:  target_offset as u16,
+            selector:    target_cs,
+            reserved_1:  0,
+            access_byte: I86_GDT_DESC_MEMORY | dpl << 5 | I86_GDT_TYPE_CALL_GATE,
+            offset_mid:  (target_offset >> 16) as u16,
+            offset_high: (target_offset >> 32) as u32,
+            reserved_2:  0,
+        }
+    }
+
+    pub fn target_offset(&self) -> u64 {
+        self.offset_low as u64 | (self.offset_mid as u64) << 16 | (self.offset_high as u64) << 32
+    }
+
+    /// Returns the two GDT slots of the descriptor.
+    fn to_entries(self) -> [GDTEntry; 2] {
+        unsafe { core::mem::transmute(self) }
+    }
+}
+
+/// Inserts a call gate to `target_cs:target_offset`, callable from privilege level `dpl`
+/// and above, into the first free pair of slots of `gdt`.
+pub fn install_call_gate(
+    gdt: &mut GlobalDescriptorTable,
+    target_cs: SegmentSelector,
+    target_offset: u64,
+    dpl: u8,
+) -> Result<SegmentSelector, GdtFull> {
+    let index = gdt.free_system_slot().ok_or(GdtFull)?;
+    let [low, high] = CallGateDescriptor::new(target_cs, target_offset, dpl).to_entries();
+    gdt.0[index] = low;
+    gdt.0[index + 1] = high;
+    Ok(SegmentSelector::new(index as u16, 0, dpl as u16))
+}
 
 impl GlobalDescriptorTable {
 
-    pub fn load(&self) {
+    pub fn load(&'static self) {
+        unsafe { self.load_unchecked(); }
+    }
+
+    /// Loads the table without requiring a `'static` borrow.
+    ///
+    /// ## Safety
+    ///
+    /// The table must neither move nor be freed while it is loaded.
+    unsafe fn load_unchecked(&self) {
         unsafe {
-            let gdt = self.pointer();
+            let gdt = DescriptorTablePointer::from_raw_parts(self.0.as_ptr() as u64, core::mem::size_of_val(&self.0));
             asm!("lgdt [{}]", in(reg) &gdt, options(readonly, nostack, preserves_flags));
         }
     }
@@ -122,11 +300,14 @@ impl GlobalDescriptorTable {
         (self.0.len() * size_of::<u64>() - 1) as u16
     }
 
-    fn pointer(&self) -> DescriptorTablePointer {
-        DescriptorTablePointer {
-            base: self.0.as_ptr() as u64,
-            limit: self.limit(),
-        }
+    /// Returns the first free pair of slots for a 16-byte system descriptor.
+    ///
+    /// Runtime descriptors are allocated in pairs from `FIRST_FREE_INDEX`, so a pair
+    /// is free when its first slot is not present.
+    fn free_system_slot(&self) -> Option<usize> {
+        (FIRST_FREE_INDEX..self.0.len() - 1)
+            .step_by(2)
+            .find(|&index| self.0[index].access_byte & I86_GDT_DESC_MEMORY == 0)
     }
 
     // Sets 2 indexes of the gdt
@@ -134,6 +315,13 @@ impl GlobalDescriptorTable {
         self.0[index].set_tss_low(tss);
         self.0[index + 1].set_tss_high(tss);
     }
+
+    /// Inserts a descriptor for `tss` into the first free pair of slots.
+    pub fn add_tss(&mut self, tss: &'static TaskStateSegment) -> Result<SegmentSelector, GdtFull> {
+        let index = self.free_system_slot().ok_or(GdtFull)?;
+        self.set_tss(tss, index);
+        Ok(SegmentSelector::new(index as u16, 0, 0))
+    }
 }
 
 
@@ -181,11 +369,9 @@ impl GDTEntry {
     }
 
     unsafe fn set_tss_low_unchecked(&mut self, tss: *const TaskStateSegment) {
-        use core::mem::size_of;
-
         let ptr = tss as u64;
         let base = (ptr & 0xFFFFFFFF) as u32;
-        let limit = (size_of::<TaskStateSegment>() - 1) as u32;
+        let limit = TaskStateSegment::descriptor_limit();
         let access_byte = I86_GDT_DESC_MEMORY | I86_GDT_DESC_ACCESS | I86_GDT_DESC_EXEC_CODE;
         let granularity = I86_GDT_GRAND_64BIT | I86_GDT_GRAND_OS;
 
@@ -204,4 +390,84 @@ impl GDTEntry {
     pub fn from_u64(value: u64) ->Self {
         unsafe { core::mem::transmute_copy(&value) }
     }
+
+    pub fn descriptor_privilege_level(&self) -> PrivilegeLevel {
+        PrivilegeLevel::from_u16_truncate((self.access_byte >> 5) as u16)
+    }
+}
+
+#[test_case]
+fn call_gate_descriptor_layout() {
+    let target = 0xffff_8000_1234_5678;
+    let gate = CallGateDescriptor::new(SegmentSelector::new(2, 0, 0), target, 3);
+    assert_eq!(gate.target_offset(), target);
+    let [low, high] = gate.to_entries();
+    let (low, high): (u64, u64) = unsafe { (core::mem::transmute(low), core::mem::transmute(high)) };
+    assert_eq!(low, 0x1234_ec00_0010_5678);
+    assert_eq!(high, 0xffff_8000);
+}
+
+#[test_case]
+fn load_a_second_tss() {
+    static TEST_TSS: [TaskStateSegment; 2] = [TaskStateSegment::new(); 2];
+
+    use super::task_register;
+
+    let first = add_tss(1, &TEST_TSS[0]).unwrap();
+    let second = add_tss(2, &TEST_TSS[1]).unwrap();
+    assert_ne!(first.0, second.0);
+    assert_eq!(task_register().0, TSS_SELECTORS[0].load(Ordering::Relaxed));
+
+    load_tss(2);
+    assert_eq!(task_register().0, second.0);
+    // Back to the TSS with the interrupt stacks.
+    load_tss(0);
+    assert_eq!(task_register().index() as usize, BOOT_TSS_INDEX);
+}
+
+#[test_case]
+fn add_tss_again_reuses_the_slot() {
+    static TEST_TSS: [TaskStateSegment; 2] = [TaskStateSegment::new(); 2];
+
+    let first = add_tss(3, &TEST_TSS[0]).unwrap();
+    let free_after = GDT.lock().free_system_slot();
+    // As a failed AP start followed by a retry of the same CPU does.
+    let again = add_tss(3, &TEST_TSS[1]).unwrap();
+    assert_eq!(again.0, first.0);
+    assert_eq!(GDT.lock().free_system_slot(), free_after);
+}
+
+#[test_case]
+fn task_register_after_load_gdt() {
+    use super::{sgdt, task_register};
+
+    // `load_gdt` ran at boot.
+    assert_eq!(task_register().0, SegmentSelector::new(BOOT_TSS_INDEX as u16, 0, 0).0);
+    assert_eq!({ sgdt().base }, GDT.lock().0.as_ptr() as u64);
+}
+
+#[test_case]
+fn code_and_data_selectors() {
+    let selectors = [
+        (kernel_code64_selector(), KERNEL_CODE64_INDEX, PrivilegeLevel::Ring0, true),
+        (kernel_data_selector(), KERNEL_DATA_INDEX, PrivilegeLevel::Ring0, false),
+        (user_code32_selector(), USER_CODE32_INDEX, PrivilegeLevel::Ring3, true),
+        (user_code64_selector(), USER_CODE64_INDEX, PrivilegeLevel::Ring3, true),
+        (user_data_selector(), USER_DATA_INDEX, PrivilegeLevel::Ring3, false),
+    ];
+    let gdt = GDT.lock();
+    for (selector, index, ring, code) in selectors {
+        let entry = gdt.0[selector.index() as usize];
+        assert_eq!(selector.index(), index);
+        assert_eq!(selector.rpl(), ring, "{}", selector);
+        assert_eq!(entry.descriptor_privilege_level(), ring, "{}", selector);
+        assert_eq!(entry.access_byte & I86_GDT_DESC_EXEC_CODE != 0, code, "{}", selector);
+    }
+    // The 64-bit code segments are long mode ones, the others are not.
+    for selector in [kernel_code64_selector(), user_code64_selector(), user_code32_selector()] {
+        let long_mode = gdt.0[selector.index() as usize].granularity & I86_GDT_GRAND_64BIT != 0;
+        assert_eq!(long_mode, selector.index() != USER_CODE32_INDEX, "{}", selector);
+    }
+    assert_eq!(user_code64_selector().0, 0x2B);
+    assert_eq!(user_data_selector().0, 0x33);
 }
@@ -1,13 +1,27 @@
-use crate::tables::selectors::{Segment, SegmentSelector, CS};
+use crate::tables::{segments::{Segment, CS}, selectors::SegmentSelector};
 use crate::tables::DescriptorTablePointer;
-use core::arch::asm;
+use core::{arch::asm, fmt};
 use lazy_static::lazy_static;
+#[cfg(test)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(test)]
+use crate::tables::{enter_interrupt, interrupt_depth, InterruptStackFrame};
 
 const IDT_ENTRY_OPTION_PRESENT: u16 = 0b1000_0000_0000_0000u16;
 const IDT_ENTRY_OPTION_DPL_USER:u16 = 0b0110_0000_0000_0000u16;
 const IDT_ENTRY_OPTION_INTERRUPT_GATE:u16 = 0b0000_1110_0000_0000u16;
 const IDT_ENTRY_OPTION_TRAP_GATE: u16 = 0b0000_1111_0000_0000u16;
 
+/// An IST index outside of 1 to 7, which [`IDTEntry::set_ist_index`] rejects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidIstIndex(pub u16);
+
+impl fmt::Display for InvalidIstIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IST index {} is not between 1 and 7", self.0)
+    }
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = { 
         use crate::as_fn_ptr;
@@ -39,6 +53,27 @@ lazy_static! {
 
         idt.interrupts[0].set_entry(as_fn_ptr!(crate::pic::timer::pit_handler), None);
         idt.interrupts[1].set_entry(as_fn_ptr!(crate::pic::keyboard::keyboard_handler), None);
+        idt.interrupts[5].set_entry(as_fn_ptr!(crate::pic::irq5_handler), None);
+        idt.interrupts[7].set_entry(as_fn_ptr!(crate::pic::master_spurious_handler), None);
+        idt.interrupts[crate::pic::hpet::WAKEUP_IRQ as usize].set_entry(as_fn_ptr!(crate::pic::hpet::wakeup_handler), None);
+        idt.interrupts[9].set_entry(as_fn_ptr!(crate::pic::irq9_handler), None);
+        idt.interrupts[10].set_entry(as_fn_ptr!(crate::pic::irq10_handler), None);
+        idt.interrupts[11].set_entry(as_fn_ptr!(crate::pic::irq11_handler), None);
+        idt.interrupts[12].set_entry(as_fn_ptr!(crate::pic::mouse::mouse_handler), None);
+        idt.interrupts[15].set_entry(as_fn_ptr!(crate::pic::slave_spurious_handler), None);
+        idt.interrupts[crate::pic::lapic::timer::TIMER_VECTOR as usize - 32].set_entry(as_fn_ptr!(crate::pic::lapic::timer::timer_handler), None);
+        idt.interrupts[crate::pic::lapic::SPURIOUS_VECTOR as usize - 32].set_entry(as_fn_ptr!(crate::pic::lapic::spurious_handler), None);
+
+        idt.interrupts[crate::syscall::int80::INT80_VECTOR - 32].set_entry(
+            as_fn_ptr!(crate::syscall::int80::int80_entry),
+            Some(IDT_ENTRY_OPTION_PRESENT | IDT_ENTRY_OPTION_DPL_USER | IDT_ENTRY_OPTION_TRAP_GATE)
+        );
+
+        #[cfg(test)]
+        {
+            idt.interrupts[NESTING_OUTER_VECTOR - 32].set_entry(as_fn_ptr!(nesting_outer_handler), None);
+            idt.interrupts[NESTING_INNER_VECTOR - 32].set_entry(as_fn_ptr!(nesting_inner_handler), None);
+        }
         idt
     };
 }
@@ -74,12 +109,8 @@ impl InterruptDescriptorTable {
     }
 
 
-    fn pointer(&self) -> DescriptorTablePointer {
-        use core::mem::size_of;
-        DescriptorTablePointer {
-            base: self as *const _ as u64,
-            limit: (size_of::<Self>() - 1) as u16,
-        }
+    fn pointer(&'static self) -> DescriptorTablePointer {
+        DescriptorTablePointer::from_slice(core::slice::from_ref(self))
     }
 }
 
@@ -149,14 +180,77 @@ impl IDTEntry {
         self.options |= dpl << 13;
     }
 
+    /// Makes the CPU switch to the stack in IST slot `index` of the TSS, 1 to 7,
+    /// before calling the handler. 0 would mean no switch.
+    ///
+    /// ## Safety
+    ///
+    /// The slot must hold a valid stack that nothing else uses while the handler runs.
     #[inline]
-    pub unsafe fn set_ist_index(&mut self, index: u16) {
-        if index >= 7 { panic!("Panic setting IST index for IDTEntry") }
+    pub unsafe fn set_ist_index(&mut self, index: u16) -> Result<(), InvalidIstIndex> {
+        if !(1..=7).contains(&index) {
+            return Err(InvalidIstIndex(index));
+        }
         self.options &= !0b111u16; // bits nb 0, 1, 2
         self.options |= index;
+        Ok(())
+    }
+
+    /// The IST slot the handler runs on, 0 if it stays on the current stack.
+    pub fn stack_index(&self) -> u16 {
+        self.options & 0b111u16
     }
 
-    fn stack_index(&self) -> u16 {
-        self.options & 0b11u16 - 1
+    pub fn has_ist(&self) -> bool {
+        self.stack_index() != 0
     }
 }
+
+#[cfg(test)]
+const NESTING_OUTER_VECTOR: usize = 0xF0;
+#[cfg(test)]
+const NESTING_INNER_VECTOR: usize = 0xF1;
+/// The depth seen by the inner handler of the nesting test.
+#[cfg(test)]
+static NESTED_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+extern "x86-interrupt" fn nesting_outer_handler(_stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    unsafe { asm!("int 0xF1", options(nostack)); }
+}
+
+#[cfg(test)]
+extern "x86-interrupt" fn nesting_inner_handler(_stack_frame: InterruptStackFrame) {
+    let _depth = enter_interrupt();
+    NESTED_DEPTH.store(interrupt_depth(), Ordering::Relaxed);
+}
+
+#[test_case]
+fn ist_index_round_trip() {
+    let mut entry = IDTEntry::missing();
+    entry.set_present(true);
+    assert!(!entry.has_ist());
+    for index in 1..=7 {
+        assert_eq!(unsafe { entry.set_ist_index(index) }, Ok(()));
+        assert_eq!(entry.stack_index(), index);
+        assert!(entry.has_ist());
+        // The other option bits are left alone.
+        assert_eq!(entry.options & !0b111, IDT_ENTRY_OPTION_INTERRUPT_GATE | IDT_ENTRY_OPTION_PRESENT);
+    }
+    assert_eq!(unsafe { entry.set_ist_index(0) }, Err(InvalidIstIndex(0)));
+    assert_eq!(unsafe { entry.set_ist_index(8) }, Err(InvalidIstIndex(8)));
+    assert_eq!(entry.stack_index(), 7);
+
+    // The double fault handler runs on IST 1.
+    assert_eq!(IDT.exceptions[8].stack_index(), 1);
+    assert!(!IDT.exceptions[14].has_ist());
+}
+
+#[test_case]
+fn nested_software_interrupt_depth() {
+    assert_eq!(interrupt_depth(), 0);
+    unsafe { asm!("int 0xF0", options(nostack)); }
+    assert_eq!(NESTED_DEPTH.load(Ordering::Relaxed), 2);
+    assert_eq!(interrupt_depth(), 0);
+}
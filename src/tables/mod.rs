@@ -1,14 +1,16 @@
 pub mod idt;
 pub mod port;
 pub mod selectors;
+pub mod segments;
 pub mod gdt;
 mod exceptions;
-mod tss;
+pub mod tss;
+mod rflags;
 
-use bitflags::bitflags;
-use crate::tables::selectors::SegmentSelector;
+use crate::{memory::paging::is_canonical, tables::selectors::SegmentSelector};
 use volatile::Volatile;
-use core::{fmt, ops::Deref, arch::asm};
+pub use rflags::RFlags;
+use core::{fmt, ops::Deref, arch::asm, sync::atomic::{AtomicUsize, Ordering}};
 
 #[repr(transparent)]
 pub struct InterruptStackFrame(InterruptStackFrameValue);
@@ -50,6 +52,73 @@ impl InterruptStackFrame {
     pub unsafe fn as_mut(&mut self) -> Volatile<&mut InterruptStackFrameValue> {
         Volatile::new(&mut self.0)
     }
+
+    /// Sets the address execution resumes at when the handler returns.
+    ///
+    /// Returns an error and leaves the frame untouched if `ip` is not canonical,
+    /// which would make `iretq` raise a general protection fault.
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`InterruptStackFrame::as_mut`]: `ip` must point to code that is
+    /// valid to run in the interrupted context.
+    #[inline]
+    pub unsafe fn set_instruction_pointer(&mut self, ip: u64) -> Result<(), NonCanonicalAddress> {
+        NonCanonicalAddress::check(ip)?;
+        let mut frame = unsafe { self.as_mut() };
+        frame.map_mut(|frame| &mut frame.instruction_pointer).write(ip);
+        Ok(())
+    }
+
+    /// Sets the stack pointer restored when the handler returns.
+    ///
+    /// Returns an error and leaves the frame untouched if `sp` is not canonical.
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`InterruptStackFrame::as_mut`]: `sp` must point to a stack that is
+    /// valid for the interrupted context.
+    #[inline]
+    pub unsafe fn set_stack_pointer(&mut self, sp: u64) -> Result<(), NonCanonicalAddress> {
+        NonCanonicalAddress::check(sp)?;
+        let mut frame = unsafe { self.as_mut() };
+        frame.map_mut(|frame| &mut frame.stack_pointer).write(sp);
+        Ok(())
+    }
+
+    /// Sets the RFLAGS value restored when the handler returns, preserving the
+    /// reserved bits of the saved value.
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`RFlags::write`]: flags such as `DF` must not be set for Rust code.
+    #[inline]
+    pub unsafe fn set_cpu_flags(&mut self, flags: RFlags) {
+        let mut frame = unsafe { self.as_mut() };
+        let mut cpu_flags = frame.map_mut(|frame| &mut frame.cpu_flags);
+        let reserved = cpu_flags.read().bits() & !RFlags::all().bits();
+        cpu_flags.write(RFlags::from_bits_retain(reserved | flags.bits()));
+    }
+}
+
+/// The error returned when a non-canonical address is written to an [`InterruptStackFrame`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonCanonicalAddress(pub u64);
+
+impl NonCanonicalAddress {
+    fn check(addr: u64) -> Result<(), Self> {
+        if is_canonical(addr) {
+            Ok(())
+        } else {
+            Err(NonCanonicalAddress(addr))
+        }
+    }
+}
+
+impl fmt::Display for NonCanonicalAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "non-canonical address {:#x}", self.0)
+    }
 }
 
 impl Deref for InterruptStackFrame {
@@ -110,6 +179,12 @@ impl InterruptStackFrameValue {
         }
     }
 
+    /// A frame that `iretq`s to `entry` in 64-bit ring 3 with the stack at
+    /// `stack` and interrupts enabled.
+    pub fn user(entry: u64, stack: u64) -> Self {
+        Self::new(entry, gdt::user_code64_selector(), RFlags::INTERRUPT_FLAG, stack, gdt::user_data_selector())
+    }
+
     pub unsafe fn iretq(&self) -> ! {
         unsafe {
             core::arch::asm!(
@@ -135,74 +210,13 @@ impl fmt::Debug for InterruptStackFrameValue {
         let mut s = f.debug_struct("InterruptStackFrame");
         s.field("instruction_pointer", &format_args!("{:#x}", self.instruction_pointer));
         s.field("code_segment", &self.code_segment);
-        s.field("cpu_flags", &self.cpu_flags);
+        s.field("cpu_flags", &format_args!("{:#x} {}", self.cpu_flags.bits(), self.cpu_flags.summary()));
         s.field("stack_pointer", &format_args!("{:#x}", self.stack_pointer));
         s.field("stack_segment", &format_args!("{:#x}", self.stack_segment.0));
         s.finish()
     }
 }
 
-bitflags! {
-    /// The RFLAGS register. All bit patterns are valid representations for this type.
-    #[repr(transparent)]
-    #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
-    pub struct RFlags: u64 {
-        /// Processor feature identification flag.
-        ///
-        /// If this flag is modifiable, the CPU supports CPUID.
-        const ID = 1 << 21;
-        /// Indicates that an external, maskable interrupt is pending.
-        ///
-        /// Used when virtual-8086 mode extensions (CR4.VME) or protected-mode virtual
-        /// interrupts (CR4.PVI) are activated.
-        const VIRTUAL_INTERRUPT_PENDING = 1 << 20;
-        /// Virtual image of the INTERRUPT_FLAG bit.
-        ///
-        /// Used when virtual-8086 mode extensions (CR4.VME) or protected-mode virtual
-        /// interrupts (CR4.PVI) are activated.
-        const VIRTUAL_INTERRUPT = 1 << 19;
-        /// Enable automatic alignment checking if CR0.AM is set. Only works if CPL is 3.
-        const ALIGNMENT_CHECK = 1 << 18;
-        /// Enable the virtual-8086 mode.
-        const VIRTUAL_8086_MODE = 1 << 17;
-        /// Allows to restart an instruction following an instruction breakpoint.
-        const RESUME_FLAG = 1 << 16;
-        /// Used by `iret` in hardware task switch mode to determine if current task is nested.
-        const NESTED_TASK = 1 << 14;
-        /// The high bit of the I/O Privilege Level field.
-        ///
-        /// Specifies the privilege level required for executing I/O address-space instructions.
-        const IOPL_HIGH = 1 << 13;
-        /// The low bit of the I/O Privilege Level field.
-        ///
-        /// Specifies the privilege level required for executing I/O address-space instructions.
-        const IOPL_LOW = 1 << 12;
-        /// Set by hardware to indicate that the sign bit of the result of the last signed integer
-        /// operation differs from the source operands.
-        const OVERFLOW_FLAG = 1 << 11;
-        /// Determines the order in which strings are processed.
-        const DIRECTION_FLAG = 1 << 10;
-        /// Enable interrupts.
-        const INTERRUPT_FLAG = 1 << 9;
-        /// Enable single-step mode for debugging.
-        const TRAP_FLAG = 1 << 8;
-        /// Set by hardware if last arithmetic operation resulted in a negative value.
-        const SIGN_FLAG = 1 << 7;
-        /// Set by hardware if last arithmetic operation resulted in a zero value.
-        const ZERO_FLAG = 1 << 6;
-        /// Set by hardware if last arithmetic operation generated a carry ouf of bit 3 of the
-        /// result.
-        const AUXILIARY_CARRY_FLAG = 1 << 4;
-        /// Set by hardware if last result has an even number of 1 bits (only for some operations).
-        const PARITY_FLAG = 1 << 2;
-        /// Set by hardware if last arithmetic operation generated a carry out of the
-        /// most-significant bit of the result.
-        const CARRY_FLAG = 1;
-    }
-}
-
-
-
 impl RFlags {
     #[inline]
     pub fn read() -> RFlags {
@@ -258,6 +272,72 @@ impl RFlags {
         }
     }
 }
+
+/// Interrupt handlers currently running, counting nested ones.
+///
+/// Global for now, it must become CPU-local with SMP.
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns how many interrupt handlers are running, 0 outside of interrupts.
+pub fn interrupt_depth() -> usize {
+    INTERRUPT_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Counts the calling handler in [`interrupt_depth`] until dropped.
+///
+/// Every installed handler takes one first thing.
+pub fn enter_interrupt() -> InterruptDepthGuard {
+    INTERRUPT_DEPTH.fetch_add(1, Ordering::Relaxed);
+    InterruptDepthGuard { _private: () }
+}
+
+/// Sets the interrupt depth to `depth` and returns the one before, for the
+/// scheduler: each thread has its own, which it left in a handler or not.
+pub(crate) fn swap_interrupt_depth(depth: usize) -> usize {
+    INTERRUPT_DEPTH.swap(depth, Ordering::Relaxed)
+}
+
+pub struct InterruptDepthGuard {
+    _private: (),
+}
+
+impl Drop for InterruptDepthGuard {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Marks a path that only makes sense inside an interrupt handler, such as
+/// acknowledging an interrupt. Panics in debug builds when reached outside one.
+#[inline]
+#[track_caller]
+pub fn debug_assert_in_interrupt() {
+    debug_assert!(interrupt_depth() > 0, "handler-only path reached outside of an interrupt");
+}
+
+/// Runs `f` with interrupts disabled, restoring the previous interrupt flag afterwards.
+///
+/// Used around locks that are also taken from interrupt handlers, so the handler
+/// cannot interrupt the holder and spin on the lock forever. Also used around
+/// port sequences that must reach the device back to back, such as a PIT
+/// control word and its two count bytes.
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let int_enabled = RFlags::read().contains(RFlags::INTERRUPT_FLAG);
+
+    if int_enabled {
+        unsafe { asm!("cli", options(preserves_flags, nostack)); }
+    }
+    let ret = f();
+    if int_enabled {
+        unsafe { asm!("sti", options(preserves_flags, nostack)); }
+    }
+    ret
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed(2))]
 pub struct DescriptorTablePointer {
@@ -265,7 +345,119 @@ pub struct DescriptorTablePointer {
     pub base: u64,
 }
 
+impl DescriptorTablePointer {
+    /// Points to the table made of `entries`.
+    pub fn from_slice<T>(entries: &'static [T]) -> Self {
+        unsafe { Self::from_raw_parts(entries.as_ptr() as u64, core::mem::size_of_val(entries)) }
+    }
+
+    /// Points to the `size_bytes` long table at `base`.
+    ///
+    /// ## Safety
+    ///
+    /// The table must stay valid for as long as it is loaded with the pointer.
+    pub unsafe fn from_raw_parts(base: u64, size_bytes: usize) -> Self {
+        debug_assert!(size_bytes > 0 && size_bytes <= 1 << 16, "table size {:#x} does not fit the limit", size_bytes);
+        // `lgdt` and `lidt` take any base, the first access through it faults.
+        debug_assert!(is_canonical(base), "descriptor table at non-canonical {:#x}", base);
+        DescriptorTablePointer {
+            base,
+            limit: (size_bytes - 1) as u16,
+        }
+    }
+}
+
+/// Reads the task register: the selector of the loaded TSS.
+pub fn task_register() -> SegmentSelector {
+    let selector: u16;
+    unsafe { asm!("str {0:x}", out(reg) selector, options(nomem, nostack, preserves_flags)); }
+    SegmentSelector(selector)
+}
+
+/// Reads the GDTR: where the loaded GDT is.
+pub fn sgdt() -> DescriptorTablePointer {
+    let mut pointer = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sgdt [{}]", in(reg) &mut pointer, options(nostack, preserves_flags)); }
+    pointer
+}
+
+/// Reads the IDTR: where the loaded IDT is.
+pub fn sidt() -> DescriptorTablePointer {
+    let mut pointer = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sidt [{}]", in(reg) &mut pointer, options(nostack, preserves_flags)); }
+    pointer
+}
+
+/// Prints the GDTR, the IDTR and the task register, as loaded.
+pub fn print_descriptor_tables() {
+    let (gdt, idt) = (sgdt(), sidt());
+    crate::println!("GDT at {:#x} limit {:#x}, IDT at {:#x} limit {:#x}, TR {}",
+        { gdt.base }, { gdt.limit }, { idt.base }, { idt.limit }, task_register());
+}
+
 #[macro_export]
 macro_rules! as_fn_ptr {
     ($($arg:tt)*) => { ($($arg)* as *const () as u64) }
 }
+
+#[test_case]
+fn descriptor_table_pointer_from_slice_limits() {
+    static ONE: [u64; 1] = [0; 1];
+    static EIGHT: [u64; 8] = [0; 8];
+    static ENTRIES: [[u64; 2]; 256] = [[0; 2]; 256];
+
+    let pointer = DescriptorTablePointer::from_slice(&ONE);
+    assert_eq!({ pointer.limit }, 7);
+    assert_eq!({ pointer.base }, ONE.as_ptr() as u64);
+    assert_eq!({ DescriptorTablePointer::from_slice(&EIGHT).limit }, 63);
+    // 256 16-byte gates, the size of a full IDT.
+    assert_eq!({ DescriptorTablePointer::from_slice(&ENTRIES).limit }, 4095);
+}
+
+#[test_case]
+fn loaded_descriptor_tables() {
+    // Both tables are full size: 8192 GDT slots and 256 IDT gates.
+    let gdt = sgdt();
+    assert_eq!({ gdt.limit }, 0xFFFF);
+    assert!(is_canonical(gdt.base) && gdt.base != 0);
+    let idt = sidt();
+    assert_eq!({ idt.limit }, 4095);
+    assert!(is_canonical(idt.base) && idt.base != 0);
+    assert_eq!(task_register().ti(), selectors::TableIndicator::GDT);
+}
+
+#[test_case]
+fn stack_frame_setters_check_canonical() {
+    let flags = RFlags::INTERRUPT_FLAG;
+    let mut frame = InterruptStackFrame::new(0x1000, SegmentSelector(8), flags, 0x2000, SegmentSelector(0));
+    // Bit 1 of RFLAGS is reserved and always set.
+    unsafe { frame.as_mut().map_mut(|frame| &mut frame.cpu_flags).write(RFlags::from_bits_retain(flags.bits() | 0b10)); }
+
+    unsafe {
+        assert_eq!(frame.set_instruction_pointer(0xffff_8000_0000_1000), Ok(()));
+        assert_eq!(frame.set_stack_pointer(0x7fff_ffff_f000), Ok(()));
+        frame.set_cpu_flags(RFlags::ZERO_FLAG);
+    }
+    assert_eq!(frame.instruction_pointer, 0xffff_8000_0000_1000);
+    assert_eq!(frame.stack_pointer, 0x7fff_ffff_f000);
+    assert_eq!(frame.cpu_flags.bits(), RFlags::ZERO_FLAG.bits() | 0b10);
+
+    unsafe {
+        assert_eq!(frame.set_instruction_pointer(0x8000_0000_0000), Err(NonCanonicalAddress(0x8000_0000_0000)));
+        assert_eq!(frame.set_stack_pointer(0x0001_0000_0000_0000), Err(NonCanonicalAddress(0x0001_0000_0000_0000)));
+    }
+    assert_eq!(frame.instruction_pointer, 0xffff_8000_0000_1000);
+    assert_eq!(frame.stack_pointer, 0x7fff_ffff_f000);
+}
+
+#[test_case]
+fn stack_frame_debug_decodes_flags() {
+    use crate::pci::ids::StackString;
+
+    let flags = RFlags::from_bits_retain(0x247);
+    let frame = InterruptStackFrame::new(0x1000, SegmentSelector(8), flags, 0x2000, SegmentSelector(0));
+    let dump = StackString::format(format_args!("{:?}", frame));
+    assert_eq!(dump.as_str(), "InterruptStackFrame { instruction_pointer: 0x1000, \
+        code_segment: SegmentSelector { index: 1, rpl: Ring0, ti: GDT }, cpu_flags: 0x247 [IF CF ZF], \
+        stack_pointer: 0x2000, stack_segment: 0x0 }");
+}
@@ -1,5 +1,50 @@
 use core::arch::asm;
 
+/// Legacy POST diagnostic port. QEMU can trace writes to it and some boards
+/// show it on a two-digit display.
+const POST_PORT: u16 = 0x80;
+
+/// POST codes emitted by `post_code!` at boot milestones.
+///
+/// If the boot hangs, the last code written to port 0x80 tells which stage was reached:
+///
+/// | code   | milestone            |
+/// |--------|----------------------|
+/// | `0x01` | entered `kernel_main`|
+/// | `0x10` | GDT and TSS loaded   |
+/// | `0x20` | IDT loaded           |
+/// | `0x30` | PICs and PIT ready   |
+/// | `0x40` | paging tables read   |
+/// | `0xAA` | boot complete        |
+///
+/// Note that `ChainedPics::initialize` writes zeroes to port 0x80 as an I/O
+/// delay, so `PicsReady` is only emitted once it returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PostCode {
+    KernelEntry = 0x01,
+    GdtLoaded   = 0x10,
+    IdtLoaded   = 0x20,
+    PicsReady   = 0x30,
+    PagingReady = 0x40,
+    BootDone    = 0xAA,
+}
+
+/// Writes `code` to the POST diagnostic port.
+pub fn post_code(code: u8) {
+    unsafe { Port::new(POST_PORT).write(code); }
+}
+
+/// Writes a POST code when the `post_codes` feature is enabled, compiles to nothing otherwise.
+#[macro_export]
+macro_rules! post_code {
+    ($code:expr) => {
+        if cfg!(feature = "post_codes") {
+            $crate::tables::port::post_code($code as u8);
+        }
+    };
+}
+
 #[repr(C)]
 pub struct Port(u16);
 
@@ -16,6 +61,30 @@ impl Port {
     pub unsafe fn read<T: PortRead>(&self, value: T) -> T {
         unsafe { value.read_from_port(self.0) }
     }
+
+    /// Fills `buffer` with words read from the port, with `rep insw`.
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`Port::read`], once for every word.
+    pub unsafe fn read_u16s(&self, buffer: &mut [u16]) {
+        unsafe {
+            asm!("rep insw", in("dx") self.0, inout("rdi") buffer.as_mut_ptr() => _,
+                inout("rcx") buffer.len() => _, options(nostack, preserves_flags));
+        }
+    }
+
+    /// Writes the words of `buffer` to the port, with `rep outsw`.
+    ///
+    /// ## Safety
+    ///
+    /// Same as [`Port::write`], once for every word.
+    pub unsafe fn write_u16s(&self, buffer: &[u16]) {
+        unsafe {
+            asm!("rep outsw", in("dx") self.0, inout("rsi") buffer.as_ptr() => _,
+                inout("rcx") buffer.len() => _, options(readonly, nostack, preserves_flags));
+        }
+    }
 }
 
 pub trait PortWrite {
@@ -33,7 +102,7 @@ impl PortWrite for u8 {
 impl PortWrite for u16 {
     unsafe fn write_to_port(self, port: u16) {
         unsafe {
-            asm!("out dx, al", in("dx") port, in("ax") self, options(nomem, nostack, preserves_flags));
+            asm!("out dx, ax", in("dx") port, in("ax") self, options(nomem, nostack, preserves_flags));
         }
     }
 }
@@ -79,3 +148,28 @@ impl PortRead for u32 {
         value
     }
 }
+
+#[test_case]
+fn word_writes_carry_the_high_byte() {
+    // `out dx, ax` sends AL to the port and AH to the next one, so one word
+    // write to the VGA CRTC index port selects a register and writes it.
+    // Unlike port 0x80, the register reads back.
+    const CRTC_INDEX: u16 = 0x3D4;
+    const CRTC_DATA: u16 = 0x3D5;
+    const CURSOR_LOW: u8 = 0x0F;
+    const PATTERN: u8 = 0xA5;
+    const WORD: u16 = u16::from_le_bytes([CURSOR_LOW, PATTERN]);
+    const _: () = assert!(WORD == 0xA50F);
+
+    let (index, data) = (Port::new(CRTC_INDEX), Port::new(CRTC_DATA));
+    // The VGA writer moves the cursor from interrupt handlers as well.
+    crate::tables::without_interrupts(|| unsafe {
+        index.write(CURSOR_LOW);
+        let saved = data.read(0u8);
+        index.write(WORD);
+        index.write(CURSOR_LOW);
+        let read_back = data.read(0u8);
+        data.write(saved);
+        assert_eq!(read_back, PATTERN);
+    });
+}
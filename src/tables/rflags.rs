@@ -0,0 +1,105 @@
+//! The RFLAGS bits. Reading and writing the register is in the parent module.
+
+use core::fmt;
+use bitflags::bitflags;
+
+bitflags! {
+    /// The RFLAGS register. All bit patterns are valid representations for this type.
+    #[repr(transparent)]
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+    pub struct RFlags: u64 {
+        /// Processor feature identification flag.
+        ///
+        /// If this flag is modifiable, the CPU supports CPUID.
+        const ID = 1 << 21;
+        /// Indicates that an external, maskable interrupt is pending.
+        ///
+        /// Used when virtual-8086 mode extensions (CR4.VME) or protected-mode virtual
+        /// interrupts (CR4.PVI) are activated.
+        const VIRTUAL_INTERRUPT_PENDING = 1 << 20;
+        /// Virtual image of the INTERRUPT_FLAG bit.
+        ///
+        /// Used when virtual-8086 mode extensions (CR4.VME) or protected-mode virtual
+        /// interrupts (CR4.PVI) are activated.
+        const VIRTUAL_INTERRUPT = 1 << 19;
+        /// Enable automatic alignment checking if CR0.AM is set. Only works if CPL is 3.
+        const ALIGNMENT_CHECK = 1 << 18;
+        /// Enable the virtual-8086 mode.
+        const VIRTUAL_8086_MODE = 1 << 17;
+        /// Allows to restart an instruction following an instruction breakpoint.
+        const RESUME_FLAG = 1 << 16;
+        /// Used by `iret` in hardware task switch mode to determine if current task is nested.
+        const NESTED_TASK = 1 << 14;
+        /// The high bit of the I/O Privilege Level field.
+        ///
+        /// Specifies the privilege level required for executing I/O address-space instructions.
+        const IOPL_HIGH = 1 << 13;
+        /// The low bit of the I/O Privilege Level field.
+        ///
+        /// Specifies the privilege level required for executing I/O address-space instructions.
+        const IOPL_LOW = 1 << 12;
+        /// Set by hardware to indicate that the sign bit of the result of the last signed integer
+        /// operation differs from the source operands.
+        const OVERFLOW_FLAG = 1 << 11;
+        /// Determines the order in which strings are processed.
+        const DIRECTION_FLAG = 1 << 10;
+        /// Enable interrupts.
+        const INTERRUPT_FLAG = 1 << 9;
+        /// Enable single-step mode for debugging.
+        const TRAP_FLAG = 1 << 8;
+        /// Set by hardware if last arithmetic operation resulted in a negative value.
+        const SIGN_FLAG = 1 << 7;
+        /// Set by hardware if last arithmetic operation resulted in a zero value.
+        const ZERO_FLAG = 1 << 6;
+        /// Set by hardware if last arithmetic operation generated a carry ouf of bit 3 of the
+        /// result.
+        const AUXILIARY_CARRY_FLAG = 1 << 4;
+        /// Set by hardware if last result has an even number of 1 bits (only for some operations).
+        const PARITY_FLAG = 1 << 2;
+        /// Set by hardware if last arithmetic operation generated a carry out of the
+        /// most-significant bit of the result.
+        const CARRY_FLAG = 1;
+    }
+}
+
+impl RFlags {
+    /// The I/O privilege level, the most privileged ring allowed `in`, `out`,
+    /// `cli` and `sti`.
+    pub const fn iopl(self) -> u8 {
+        ((self.bits() >> 12) & 0b11) as u8
+    }
+
+    /// Returns the flags worth a look in a fault dump, see [`FlagSummary`].
+    pub const fn summary(self) -> FlagSummary {
+        FlagSummary(self)
+    }
+}
+
+/// Formats the interrupt flag, the arithmetic flags and a non-zero IOPL of an
+/// [`RFlags`], like `[IF CF ZF]` or `[ZF IOPL=3]`, leaving out the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagSummary(RFlags);
+
+impl fmt::Display for FlagSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMED: [(RFlags, &str); 5] = [
+            (RFlags::INTERRUPT_FLAG, "IF"),
+            (RFlags::CARRY_FLAG, "CF"),
+            (RFlags::ZERO_FLAG, "ZF"),
+            (RFlags::SIGN_FLAG, "SF"),
+            (RFlags::OVERFLOW_FLAG, "OF"),
+        ];
+        let mut separator = "";
+        f.write_str("[")?;
+        for (flag, name) in NAMED {
+            if self.0.contains(flag) {
+                write!(f, "{}{}", separator, name)?;
+                separator = " ";
+            }
+        }
+        if self.0.iopl() != 0 {
+            write!(f, "{}IOPL={}", separator, self.0.iopl())?;
+        }
+        f.write_str("]")
+    }
+}
@@ -0,0 +1,186 @@
+use core::{arch::asm, sync::atomic::{AtomicBool, Ordering}};
+use crate::cpu::{cpuid, max_leaf, Msr};
+use super::selectors::SegmentSelector;
+
+const IA32_FS_BASE: u32 = 0xC000_0100;
+const IA32_GS_BASE: u32 = 0xC000_0101;
+/// CR4 bit letting `rdfsbase` and the like run.
+const CR4_FSGSBASE: u64 = 1 << 16;
+/// `cpuid` leaf 7, EBX: the FS/GS base instructions exist.
+const CPUID_FSGSBASE: u32 = 1 << 0;
+
+/// Set once `enable_fsgsbase` turned the instructions on, the MSRs are used until then.
+static FSGSBASE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub trait Segment {
+    fn get_reg() -> SegmentSelector;
+    /// Reload the segment register. Depending on the segment, this may also
+    /// reconfigure the corresponding segment.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because the caller must ensure that `sel`
+    /// is a valid segment descriptor, and that reconfiguring the segment will
+    /// not cause undefined behavior.
+    unsafe fn set_reg(sel: SegmentSelector);
+}
+
+/// The segments whose base is still used in 64-bit mode.
+pub trait Segment64: Segment {
+    /// The MSR holding the base.
+    const BASE: Msr;
+
+    fn read_base() -> u64;
+
+    /// Sets the base address of the segment.
+    ///
+    /// ## Safety
+    ///
+    /// `base` must be canonical, and nothing may rely on the previous base.
+    unsafe fn write_base(base: u64);
+}
+
+pub struct CS;
+pub struct SS;
+pub struct DS;
+pub struct ES;
+pub struct FS;
+pub struct GS;
+
+
+macro_rules! get_reg_impl {
+    ($name:literal) => {
+        #[inline]
+        fn get_reg() -> SegmentSelector {
+            let segment: u16;
+            unsafe {
+                asm!(concat!("mov {0:x}, ", $name), out(reg) segment, options(nomem, nostack, preserves_flags));
+            }
+            SegmentSelector(segment)
+        }
+    };
+}
+
+macro_rules! segment_impl {
+    ($type:ty, $name:literal) => {
+        impl Segment for $type {
+            get_reg_impl!($name);
+
+            #[inline]
+            unsafe fn set_reg(sel: SegmentSelector) {
+                unsafe {
+                    asm!(concat!("mov ", $name, ", {0:x}"), in(reg) sel.0, options(nostack, preserves_flags));
+                }
+            }
+        }
+    };
+}
+
+macro_rules! segment64_impl {
+    ($type:ty, $name:literal, $base:expr) => {
+        impl Segment64 for $type {
+            const BASE: Msr = Msr::new($base);
+
+            #[inline]
+            fn read_base() -> u64 {
+                if !FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+                    return unsafe { Self::BASE.read() };
+                }
+                let val: u64;
+                unsafe {
+                    asm!(concat!("rd", $name, "base {}"), out(reg) val, options(nomem, nostack, preserves_flags));
+                }
+                val
+            }
+
+            #[inline]
+            unsafe fn write_base(base: u64) {
+                if !FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+                    let mut msr = Self::BASE;
+                    unsafe { msr.write(base); }
+                    return;
+                }
+                unsafe {
+                    asm!(concat!("wr", $name, "base {}"), in(reg) base, options(nostack, preserves_flags));
+                }
+            }
+        }
+    };
+}
+
+impl Segment for CS {
+    get_reg_impl!("cs");
+
+    /// Note this is special since we cannot directly move to [`CS`]; x86 requires the instruction
+    /// pointer and [`CS`] to be set at the same time. To do this, we push the new segment selector
+    /// and return value onto the stack and use a "far return" (`retfq`) to reload [`CS`] and
+    /// continue at the end of our function.
+    ///
+    /// Note we cannot use a "far call" (`lcall`) or "far jmp" (`ljmp`) to do this because then we
+    /// would only be able to jump to 32-bit instruction pointers. Only Intel implements support
+    /// for 64-bit far calls/jumps in long-mode, AMD does not.
+    #[inline]
+    unsafe fn set_reg(sel: SegmentSelector) {
+        unsafe {
+            asm!(
+                "push {sel}",
+                "lea {tmp}, [55f + rip]",
+                "push {tmp}",
+                "retfq",
+                "55:",
+                sel = in(reg) u64::from(sel.0),
+                tmp = lateout(reg) _,
+                options(preserves_flags),
+            );
+        }
+    }
+}
+
+segment_impl!(SS, "ss");
+segment_impl!(DS, "ds");
+segment_impl!(ES, "es");
+segment_impl!(FS, "fs");
+segment64_impl!(FS, "fs", IA32_FS_BASE);
+segment_impl!(GS, "gs");
+segment64_impl!(GS, "gs", IA32_GS_BASE);
+
+impl GS {
+    /// Swap `KernelGsBase` MSR and `GsBase` MSR.
+    ///
+    /// ## Safety
+    ///
+    /// This function is unsafe because the caller must ensure that the
+    /// swap operation cannot lead to undefined behavior.
+    #[inline]
+    pub unsafe fn swap() {
+        unsafe {
+            asm!("swapgs", options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// Sets CR4.FSGSBASE if the CPU has the FS/GS base instructions, so that
+/// [`Segment64`] uses them rather than the slower MSRs. Returns whether it did.
+pub fn enable_fsgsbase() -> bool {
+    if max_leaf() < 7 || cpuid(7, 0).ebx & CPUID_FSGSBASE == 0 {
+        return false;
+    }
+    unsafe {
+        let cr4: u64;
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+        asm!("mov cr4, {}", in(reg) cr4 | CR4_FSGSBASE, options(nostack, preserves_flags));
+    }
+    FSGSBASE_ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+#[test_case]
+fn fs_base_round_trip() {
+    let old = FS::read_base();
+    unsafe { FS::write_base(0xFFFF_8000_DEAD_B000); }
+    assert_eq!(FS::read_base(), 0xFFFF_8000_DEAD_B000);
+    // The MSR and the instruction see the same register.
+    assert_eq!(unsafe { FS::BASE.read() }, 0xFFFF_8000_DEAD_B000);
+    unsafe { FS::write_base(old); }
+    assert_eq!(FS::read_base(), old);
+}
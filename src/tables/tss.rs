@@ -1,21 +1,22 @@
-use core::{mem::size_of, ptr::addr_of};
+use core::{fmt, mem::{offset_of, size_of}, ptr::{addr_of, addr_of_mut}};
 use lazy_static::lazy_static;
 use core::arch::asm;
 
-use super::selectors::SegmentSelector;
+use crate::memory::PAGE_SIZE_4K;
+use super::{selectors::SegmentSelector, InterruptStackFrameValue};
 
 lazy_static! {
     pub static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[0 as usize] = {
-            const STACK_SIZE: u64 = 0x1000 * 5;
+            const STACK_SIZE: u64 = PAGE_SIZE_4K * 5;
             static mut STACK: [u8; STACK_SIZE as usize] = [0; STACK_SIZE as usize];
             let stack_start = addr_of!(STACK) as u64;
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
         tss.privilege_stack_table[0 as usize] = {
-            const STACK_SIZE: u64 = 0x1000 * 5;
+            const STACK_SIZE: u64 = PAGE_SIZE_4K * 5;
             static mut STACK: [u8; STACK_SIZE as usize] = [0; STACK_SIZE as usize];
             let stack_start = addr_of!(STACK) as u64;
             let stack_end = stack_start + STACK_SIZE;
@@ -26,10 +27,13 @@ lazy_static! {
 }
 
 
+/// Number of bytes needed for one permission bit per I/O port.
+const IO_BITMAP_SIZE: usize = 0x10000 / 8;
+
 /// In 64-bit mode the TSS holds information that is not
 /// directly related to the task-switch mechanism,
 /// but is used for stack switching when an interrupt or exception occurs.
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[repr(C, packed(4))]
 pub struct TaskStateSegment {
     reserved_1: u32,
@@ -44,28 +48,86 @@ pub struct TaskStateSegment {
     reserved_4: u16,
     /// The 16-bit offset to the I/O permission bit map from the 64-bit TSS base.
     pub iomap_base: u16,
+    /// The I/O permission bit map, one bit per port, 0 meaning ring 3 may access the port.
+    /// The CPU reads two bytes at a time, so the map ends with an all-ones byte.
+    io_bitmap: [u8; IO_BITMAP_SIZE + 1],
 }
 
 impl TaskStateSegment {
     /// Creates a new TSS with zeroed privilege and interrupt stack table and an
-    /// empty I/O-Permission Bitmap.
+    /// I/O permission bitmap denying every port.
     ///
-    /// As we always set the TSS segment limit to
-    /// `size_of::<TaskStateSegment>() - 1`, this means that `iomap_base` is
-    /// initialized to `size_of::<TaskStateSegment>()`.
+    /// The bitmap directly follows the hardware defined fields, so `iomap_base`
+    /// is initialized to its offset and the TSS segment limit, see
+    /// [`TaskStateSegment::descriptor_limit`], covers it.
     #[inline]
     pub const fn new() -> TaskStateSegment {
         TaskStateSegment {
             privilege_stack_table: [0; 3],
             interrupt_stack_table: [0; 7],
-            iomap_base: size_of::<TaskStateSegment>() as u16,
+            iomap_base: offset_of!(TaskStateSegment, io_bitmap) as u16,
             reserved_1: 0,
             reserved_2: 0,
             reserved_3: 0,
             reserved_4: 0,
+            io_bitmap: [0xFF; IO_BITMAP_SIZE + 1],
         }
     }
 
+    /// The segment limit to use in the TSS descriptor, covering the I/O permission
+    /// bitmap up to and including its terminating byte.
+    pub const fn descriptor_limit() -> u32 {
+        (offset_of!(TaskStateSegment, io_bitmap) + IO_BITMAP_SIZE) as u32
+    }
+
+    /// Returns `RSP0`, the kernel stack loaded on an interrupt from ring 3.
+    pub fn privilege_stack_top(&self) -> u64 {
+        self.privilege_stack_table[0]
+    }
+
+    /// Makes the CPU switch to the stack at `top` on the next interrupt from
+    /// ring 3, for the thread about to run.
+    ///
+    /// The CPU reads `RSP0` from memory on each privilege change, so a loaded
+    /// TSS is written in place, through a raw pointer.
+    ///
+    /// ## Safety
+    ///
+    /// `top` must be the top of a kernel stack that stays valid while it is set,
+    /// and no interrupt from ring 3 may arrive on this CPU meanwhile.
+    pub unsafe fn set_privilege_stack_top(&self, top: u64) {
+        let tss = self as *const TaskStateSegment as *mut TaskStateSegment;
+        unsafe { addr_of_mut!((*tss).privilege_stack_table).cast::<u64>().write_unaligned(top); }
+    }
+
+    /// Returns where the CPU pushes the interrupt frame when an interrupt
+    /// arrives in ring 3: below `RSP0` rounded down to 16 bytes.
+    pub fn privilege_stack_frame_start(&self) -> u64 {
+        (self.privilege_stack_top() & !0xF) - size_of::<InterruptStackFrameValue>() as u64
+    }
+
+    /// Returns the stack pointer of IST entry `index`, counted from 0 like
+    /// `interrupt_stack_table` rather than from 1 like the IDT entries.
+    pub fn ist_stack_top(&self, index: usize) -> u64 {
+        assert!(index < 7, "IST index {} out of range", index);
+        self.interrupt_stack_table[index]
+    }
+
+    /// Lets ring 3 code access `port` with `in`/`out`.
+    pub fn allow_port(&mut self, port: u16) {
+        self.io_bitmap[port as usize / 8] &= !(1 << (port % 8));
+    }
+
+    /// Makes ring 3 accesses to `port` raise a general protection fault.
+    pub fn deny_port(&mut self, port: u16) {
+        self.io_bitmap[port as usize / 8] |= 1 << (port % 8);
+    }
+
+    /// Returns whether ring 3 code may access `port`.
+    pub fn is_port_allowed(&self, port: u16) -> bool {
+        self.io_bitmap[port as usize / 8] & (1 << (port % 8)) == 0
+    }
+
     pub unsafe fn load(&self, ss: SegmentSelector) {
         unsafe {
             asm!("ltr {0:x}", in(reg) ss.0, options(nostack, preserves_flags));
@@ -73,9 +135,50 @@ impl TaskStateSegment {
     }
 }
 
+impl fmt::Debug for TaskStateSegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TaskStateSegment")
+            .field("privilege_stack_table", &{ self.privilege_stack_table })
+            .field("interrupt_stack_table", &{ self.interrupt_stack_table })
+            .field("iomap_base", &{ self.iomap_base })
+            .finish_non_exhaustive()
+    }
+}
+
 impl Default for TaskStateSegment {
     #[inline]
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[test_case]
+fn io_bitmap_allow_port() {
+    let mut tss = TaskStateSegment::new();
+    assert!(!tss.is_port_allowed(0x3F8));
+    tss.allow_port(0x3F8);
+    assert!(tss.is_port_allowed(0x3F8));
+    assert_eq!(tss.io_bitmap[0x3F8 / 8], !(1u8 << 0));
+    assert!(!tss.is_port_allowed(0x3F9));
+    tss.deny_port(0x3F8);
+    assert!(!tss.is_port_allowed(0x3F8));
+
+    let bitmap_end = tss.iomap_base as usize + IO_BITMAP_SIZE;
+    assert_eq!(TaskStateSegment::descriptor_limit() as usize, bitmap_end);
+    assert_eq!(tss.io_bitmap[IO_BITMAP_SIZE], 0xFF);
+}
+
+#[test_case]
+fn stack_top_helpers() {
+    let mut tss = TaskStateSegment::new();
+    tss.privilege_stack_table[0] = 0xFFFF_8000_0010_0000;
+    tss.interrupt_stack_table[3] = 0x20_0000;
+    assert_eq!(tss.privilege_stack_top(), 0xFFFF_8000_0010_0000);
+    assert_eq!(tss.privilege_stack_frame_start(), 0xFFFF_8000_0010_0000 - 40);
+    // The CPU aligns the stack before pushing the frame.
+    tss.privilege_stack_table[0] = 0x10_0008;
+    assert_eq!(tss.privilege_stack_frame_start(), 0x10_0000 - 40);
+    assert_eq!(tss.ist_stack_top(3), 0x20_0000);
+    assert_eq!(tss.ist_stack_top(0), 0);
+    assert_eq!(TSS.privilege_stack_top(), { TSS.privilege_stack_table }[0]);
+}
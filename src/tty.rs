@@ -0,0 +1,220 @@
+//! Console input.
+//!
+//! The keyboard interrupt feeds decoded characters to [`TTY`], where they wait
+//! until `read` picks them up. A reader finding nothing to read blocks on a
+//! wait queue, which the keyboard interrupt wakes, see [`read_blocking`]. In line mode (the default) the tty does the line
+//! editing: keys are echoed, backspace erases, and input becomes readable one
+//! line at a time when Enter is pressed. Ctrl+D makes the pending line readable
+//! without a newline, or signals end of file when the line is empty.
+//! In raw mode every key is readable immediately and nothing is echoed.
+
+use crate::{print, process::scheduler::WaitQueue, sync::Mutex, tables::RFlags};
+
+/// Bytes that can wait to be read before further input is dropped.
+const INPUT_CAPACITY: usize = 256;
+/// Longest line that can be edited in line mode.
+const LINE_CAPACITY: usize = 256;
+
+/// Ctrl+D, mapped to U+0004 by the keyboard decoder.
+const END_OF_TRANSMISSION: char = '\u{4}';
+const BACKSPACE: char = '\u{8}';
+
+pub static TTY: Mutex<Tty> = Mutex::new("TTY", Tty::new());
+/// The threads waiting in [`read_blocking`].
+static READERS: WaitQueue = WaitQueue::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum TtyMode {
+    /// Canonical mode: the kernel edits and echoes the line.
+    Line = 0,
+    /// Every key goes straight to the reader, unechoed.
+    Raw  = 1,
+}
+
+impl TryFrom<u64> for TtyMode {
+    type Error = ();
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TtyMode::Line),
+            1 => Ok(TtyMode::Raw),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct Tty {
+    mode: TtyMode,
+    /// Ring buffer of bytes ready to be read.
+    input: [u8; INPUT_CAPACITY],
+    input_head: usize,
+    input_len: usize,
+    /// The line being edited in line mode, as UTF-8.
+    line: [u8; LINE_CAPACITY],
+    line_len: usize,
+    /// Ctrl+D was pressed on an empty line, the next read returns 0.
+    eof: bool,
+}
+
+impl Tty {
+    pub const fn new() -> Self {
+        Tty {
+            mode: TtyMode::Line,
+            input: [0; INPUT_CAPACITY],
+            input_head: 0,
+            input_len: 0,
+            line: [0; LINE_CAPACITY],
+            line_len: 0,
+            eof: false,
+        }
+    }
+
+    pub fn mode(&self) -> TtyMode {
+        self.mode
+    }
+
+    /// Switches the input mode. A line being edited becomes readable as is.
+    pub fn set_mode(&mut self, mode: TtyMode) {
+        if mode == TtyMode::Raw {
+            self.flush_line();
+        }
+        self.mode = mode;
+    }
+
+    /// Feeds a character typed on the keyboard.
+    pub fn input_char(&mut self, c: char) {
+        let mut utf8 = [0u8; 4];
+        let bytes = c.encode_utf8(&mut utf8).as_bytes();
+
+        if self.mode == TtyMode::Raw {
+            self.push_input(bytes);
+            return;
+        }
+        match c {
+            '\n' => {
+                self.push_line(bytes);
+                self.flush_line();
+                print!("\n");
+            },
+            END_OF_TRANSMISSION => {
+                if self.line_len == 0 {
+                    self.eof = true;
+                }
+                self.flush_line();
+            },
+            BACKSPACE => {
+                if self.line_len > 0 {
+                    // Drop the continuation bytes of a multi-byte character too.
+                    self.line_len -= 1;
+                    while self.line_len > 0 && self.line[self.line_len] & 0xC0 == 0x80 {
+                        self.line_len -= 1;
+                    }
+                    print!("{}", BACKSPACE);
+                }
+            },
+            c => {
+                if self.push_line(bytes) && !c.is_control() {
+                    print!("{}", c);
+                }
+            },
+        }
+    }
+
+    /// Moves up to `buf.len()` readable bytes into `buf`.
+    ///
+    /// Returns `None` if nothing is readable yet, `Some(0)` once for end of file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.input_len == 0 {
+            if self.eof {
+                self.eof = false;
+                return Some(0);
+            }
+            return if buf.is_empty() { Some(0) } else { None };
+        }
+        let count = buf.len().min(self.input_len);
+        for byte in buf[..count].iter_mut() {
+            *byte = self.input[self.input_head];
+            self.input_head = (self.input_head + 1) % INPUT_CAPACITY;
+        }
+        self.input_len -= count;
+        Some(count)
+    }
+
+    /// Discards everything typed but not read yet.
+    pub fn flush(&mut self) {
+        self.input_len = 0;
+        self.line_len = 0;
+        self.eof = false;
+    }
+
+    /// Appends to the edited line, returns `false` if it is full.
+    fn push_line(&mut self, bytes: &[u8]) -> bool {
+        if self.line_len + bytes.len() > LINE_CAPACITY {
+            return false;
+        }
+        self.line[self.line_len..self.line_len + bytes.len()].copy_from_slice(bytes);
+        self.line_len += bytes.len();
+        true
+    }
+
+    fn flush_line(&mut self) {
+        let line = self.line;
+        self.push_input(&line[..self.line_len]);
+        self.line_len = 0;
+    }
+
+    /// Queues readable bytes, dropping what does not fit.
+    fn push_input(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.input_len == INPUT_CAPACITY {
+                return;
+            }
+            self.input[(self.input_head + self.input_len) % INPUT_CAPACITY] = byte;
+            self.input_len += 1;
+        }
+    }
+}
+
+impl Default for Tty {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads from [`TTY`], waiting until input is available.
+///
+/// A process blocks on the tty wait queue, and the other threads run until
+/// [`wake_readers`] tells it to look again. Returns `None` when called with
+/// interrupts disabled, since no input could ever arrive.
+///
+/// A reader killed while it waits, by another thread of its process exiting,
+/// takes nothing: the input stays for the next reader.
+pub fn read_blocking(buf: &mut [u8]) -> Option<usize> {
+    if !RFlags::read().contains(RFlags::INTERRUPT_FLAG) {
+        return None;
+    }
+    Some(READERS.wait_until(|| TTY.lock().read(buf)))
+}
+
+/// Readies the threads waiting in [`read_blocking`], once the input changed.
+pub fn wake_readers() {
+    READERS.wake_all();
+}
+
+#[test_case]
+fn line_mode_edits_before_read() {
+    let mut tty = Tty::new();
+    let mut buf = [0u8; 8];
+    tty.input_char('e');
+    tty.input_char('x');
+    tty.input_char(BACKSPACE);
+    tty.input_char('r');
+    assert_eq!(tty.read(&mut buf), None);
+    tty.input_char('\n');
+    assert_eq!(tty.read(&mut buf), Some(3));
+    assert_eq!(&buf[..3], b"er\n");
+    tty.input_char(END_OF_TRANSMISSION);
+    assert_eq!(tty.read(&mut buf), Some(0));
+    assert_eq!(tty.read(&mut buf), None);
+}
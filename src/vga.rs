@@ -1,30 +1,49 @@
 use core::fmt;
-use lazy_static::lazy_static;
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 
-use crate::tables::port::Port;
+use crate::{sync::Mutex, tables::port::Port};
 
-const   VGA_BUFFER_ADDR: *mut VGABuffer = 0xB8000 as *mut VGABuffer;
-const   VGA_BUFFER_HEIGHT: usize        = 25;
-const   VGA_BUFFER_WIDTH: usize         = 80;
+/// Physical address of the text buffer, where the bootloader identity maps it.
+const   VGA_BUFFER_PHYS: u64            = 0xB8000;
+pub const VGA_BUFFER_HEIGHT: usize     = 25;
+pub const VGA_BUFFER_WIDTH: usize      = 80;
 const   VGA_OFFSET_LOW: usize	        = 0x0F;
 const   VGA_OFFSET_HIGH: usize	        = 0x0E;
+const   VGA_CURSOR_START: u8            = 0x0A;
+const   VGA_CURSOR_END: u8              = 0x0B;
+const   VGA_CURSOR_DISABLE: u8          = 0x20;
+const   VGA_SCAN_LINE_MASK: u8          = 0x1F;
+const   VGA_SCROLLBACK_LINES: usize     = 500;
+/// Widest hex dump row that fits on a line: `oooo: ` then 3 + 1 characters per byte and `|`.
+const   HEX_DUMP_MAX_COLUMNS: usize     = (VGA_BUFFER_WIDTH - 1 - 7) / 4;
+const   HEX_DIGITS: &[u8; 16]           = b"0123456789abcdef";
+/// Widest progress bar: the brackets, a space and `100%` still fit on a line.
+const   PROGRESS_MAX_WIDTH: usize       = VGA_BUFFER_WIDTH - 7;
+/// The names of the colors, by number.
+const   COLOR_NAMES: [&str; 16]         = [
+    "black", "blue", "green", "cyan", "red", "magenta", "brown", "white",
+    "gray", "lightblue", "lightgreen", "lightcyan", "lightred", "lightmagenta", "yellow", "brightwhite",
+];
 
-lazy_static! {
-    pub static ref VGA_WRITER: Mutex<VGAWriter> = {
-        let w = Mutex::new(VGAWriter {
-            column_pos: 0,
-            row_pos: 0,
-            color_code: VGAColorCode::new(VGAColor::BrightWhite, VGAColor::Black),
-            buffer: unsafe { &mut *(VGA_BUFFER_ADDR) }
-        });
-        w.lock().update_colors(VGAColor::BrightWhite, VGAColor::Black);
-        w
-    };
+/// Usable right away; [`init_vga`] only repaints the colors of what the
+/// firmware left on screen.
+pub static VGA_WRITER: Mutex<VGAWriter> = Mutex::new("VGA_WRITER", VGAWriter::const_new());
+/// Set by [`init_vga`].
+static VGA_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
-    static ref VGA_CRTL_PORT: Mutex<Port> = Mutex::new(Port::new(0x3D4));
-    static ref VGA_DATA_PORT: Mutex<Port> = Mutex::new(Port::new(0x3D5));
-}
+static VGA_CRTL_PORT: Mutex<Port> = Mutex::new("VGA_CRTL_PORT", Port::new(0x3D4));
+static VGA_DATA_PORT: Mutex<Port> = Mutex::new("VGA_DATA_PORT", Port::new(0x3D5));
+
+/// Taken while holding `VGA_WRITER` when scrolling, never the other way around.
+static VGA_SCROLLBACK: Mutex<Scrollback> = Mutex::new("VGA_SCROLLBACK", Scrollback::new());
+
+/// Where the text buffer is mapped: identity mapped at boot, then in the physical
+/// memory window, see [`map_text_buffer`].
+static TEXT_BUFFER: AtomicPtr<VGABuffer> = AtomicPtr::new(VGA_BUFFER_PHYS as *mut VGABuffer);
+
+/// Where the writer writes while the screen is in a graphics mode, which
+/// unmaps the text buffer. Only reached through the writer.
+static mut TEXT_SHADOW: VGABuffer = VGABuffer { chars: [[VGA_BLANK; VGA_BUFFER_WIDTH]; VGA_BUFFER_HEIGHT] };
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -48,14 +67,111 @@ pub enum VGAColor {
     BrightWhite     = 15
 }
 
+impl VGAColor {
+    /// Returns the color numbered `value`, only the low 4 bits are used.
+    pub const fn from_u8(value: u8) -> Self {
+        match value & 0x0F {
+            0 => VGAColor::Black,
+            1 => VGAColor::Blue,
+            2 => VGAColor::Green,
+            3 => VGAColor::Cyan,
+            4 => VGAColor::Red,
+            5 => VGAColor::Magenta,
+            6 => VGAColor::Brown,
+            7 => VGAColor::White,
+            8 => VGAColor::Gray,
+            9 => VGAColor::LightBlue,
+            10 => VGAColor::LightGreen,
+            11 => VGAColor::LightCyan,
+            12 => VGAColor::LightRed,
+            13 => VGAColor::LightMagenta,
+            14 => VGAColor::Yellow,
+            _ => VGAColor::BrightWhite,
+        }
+    }
+
+    /// Returns the color named like the variant, `lightblue` for `LightBlue`,
+    /// in any case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let index = COLOR_NAMES.iter().position(|color| color.eq_ignore_ascii_case(name))?;
+        Some(Self::from_u8(index as u8))
+    }
+
+    /// Returns the color of ANSI foreground code 30–37, or of its bright
+    /// variant 90–97.
+    pub const fn from_ansi_fg(code: u8) -> Option<Self> {
+        match code {
+            30..=37 => Some(Self::from_ansi_index(code - 30)),
+            90..=97 => Some(Self::from_ansi_index(code - 90 + 8)),
+            _ => None,
+        }
+    }
+
+    /// Returns the color of ANSI background code 40–47, or of its bright
+    /// variant 100–107.
+    pub const fn from_ansi_bg(code: u8) -> Option<Self> {
+        match code {
+            40..=47 => Some(Self::from_ansi_index(code - 40)),
+            100..=107 => Some(Self::from_ansi_index(code - 100 + 8)),
+            _ => None,
+        }
+    }
+
+    /// Returns the ANSI foreground code of the color, 90–97 for the bright ones.
+    pub const fn to_ansi_fg(self) -> u8 {
+        let index = Self::swap_red_blue(self as u8);
+        if index < 8 { 30 + index } else { 90 + index - 8 }
+    }
+
+    /// ANSI numbers the colors red, green, blue from the low bit, VGA blue,
+    /// green, red.
+    const fn from_ansi_index(index: u8) -> Self {
+        Self::from_u8(Self::swap_red_blue(index))
+    }
+
+    const fn swap_red_blue(value: u8) -> u8 {
+        value & 0b1010 | (value & 1) << 2 | (value >> 2) & 1
+    }
+}
+
+/// The attribute byte of a character cell: background in the high nibble,
+/// foreground in the low one.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct VGAColorCode(u8);
+pub struct VGAColorCode(u8);
 
 impl VGAColorCode {
-    fn new(fg: VGAColor, bg: VGAColor) -> Self {
+    pub const fn new(fg: VGAColor, bg: VGAColor) -> Self {
         VGAColorCode((bg as u8) << 4 | (fg as u8))
     }
+
+    pub const fn foreground(self) -> VGAColor {
+        VGAColor::from_u8(self.0)
+    }
+
+    pub const fn background(self) -> VGAColor {
+        VGAColor::from_u8(self.0 >> 4)
+    }
+}
+
+/// The characters [`VGAWriter::draw_box`] draws a border with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoxStyle {
+    /// `+`, `-` and `|`.
+    Ascii,
+    /// The single line box-drawing characters of code page 437.
+    Extended,
+}
+
+impl BoxStyle {
+    /// Returns the corners, top left first and clockwise, then the
+    /// horizontal and vertical lines.
+    const fn glyphs(self) -> ([u8; 4], u8, u8) {
+        match self {
+            BoxStyle::Ascii => ([b'+'; 4], b'-', b'|'),
+            BoxStyle::Extended => ([0xDA, 0xBF, 0xD9, 0xC0], 0xC4, 0xB3),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -65,25 +181,134 @@ struct VGAChar {
     color_code: VGAColorCode,
 }
 
+const VGA_BLANK: VGAChar = VGAChar { ascii_character: b' ', color_code: VGAColorCode(0) };
+
+type VGALine = [VGAChar; VGA_BUFFER_WIDTH];
+
 #[repr(transparent)]
 struct VGABuffer {
-    chars: [[VGAChar; VGA_BUFFER_WIDTH]; VGA_BUFFER_HEIGHT]
+    chars: [VGALine; VGA_BUFFER_HEIGHT]
+}
+
+/// Lines that scrolled off the top of the screen, oldest first.
+struct Scrollback {
+    lines: [VGALine; VGA_SCROLLBACK_LINES],
+    /// Index of the oldest line in `lines`.
+    start: usize,
+    len: usize,
+    /// The live screen, saved while the view shows the history.
+    live: [VGALine; VGA_BUFFER_HEIGHT],
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Scrollback {
+            lines: [[VGA_BLANK; VGA_BUFFER_WIDTH]; VGA_SCROLLBACK_LINES],
+            start: 0,
+            len: 0,
+            live: [[VGA_BLANK; VGA_BUFFER_WIDTH]; VGA_BUFFER_HEIGHT],
+        }
+    }
+
+    /// Appends a line, dropping the oldest one when full.
+    fn push(&mut self, line: &VGALine) {
+        let end = (self.start + self.len) % VGA_SCROLLBACK_LINES;
+        self.lines[end] = *line;
+        if self.len == VGA_SCROLLBACK_LINES {
+            self.start = (self.start + 1) % VGA_SCROLLBACK_LINES;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// Returns line `index`, 0 being the oldest.
+    fn line(&self, index: usize) -> Option<&VGALine> {
+        if index >= self.len {
+            return None;
+        }
+        Some(&self.lines[(self.start + index) % VGA_SCROLLBACK_LINES])
+    }
 }
 
 pub struct VGAWriter {
     column_pos: usize,
     row_pos: usize,
+    /// How many lines the view is scrolled back into the history, 0 showing the live screen.
+    view_offset: usize,
     color_code: VGAColorCode,
-    buffer: &'static mut VGABuffer,
+    /// Written in place of bytes outside printable ASCII.
+    replacement_byte: u8,
+    /// The width of the last progress bar, for `update_progress_bar_in_place`.
+    progress_width: usize,
+    /// The text buffer at `TEXT_BUFFER`, or `TEXT_SHADOW` in a graphics
+    /// mode; a pointer rather than a reference so that the writer can be built
+    /// in a `const`.
+    buffer: *mut VGABuffer,
 }
 
+// The writer is the only user of the text buffer, and is only reached through
+// the `VGA_WRITER` lock.
+unsafe impl Send for VGAWriter {}
+
 impl VGAWriter {
+    /// The writer at the top left corner of the text buffer, white on black.
+    pub const fn const_new() -> VGAWriter {
+        VGAWriter {
+            column_pos: 0,
+            row_pos: 0,
+            view_offset: 0,
+            color_code: VGAColorCode::new(VGAColor::BrightWhite, VGAColor::Black),
+            // A filled block in code page 437.
+            replacement_byte: 0xfe,
+            progress_width: 0,
+            buffer: VGA_BUFFER_PHYS as *mut VGABuffer,
+        }
+    }
+
+    fn buffer(&mut self) -> &mut VGABuffer {
+        unsafe { &mut *self.buffer }
+    }
+
+    fn buffer_ref(&self) -> &VGABuffer {
+        unsafe { &*self.buffer }
+    }
+
+    /// Returns the character and colors at `row`, `column` of the screen.
+    pub fn char_at(&self, row: usize, column: usize) -> (u8, VGAColorCode) {
+        let vga_char = self.buffer_ref().chars[row][column];
+        (vga_char.ascii_character, vga_char.color_code)
+    }
+
+    /// Returns the row and column the next character goes to.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.row_pos, self.column_pos)
+    }
+
+    /// Moves the writer off the text buffer to a copy in RAM, for a graphics
+    /// mode that draws the text itself. What is on screen is kept.
+    pub fn detach_text_buffer(&mut self) {
+        if self.buffer == TEXT_BUFFER.load(Ordering::Relaxed) {
+            let shadow = core::ptr::addr_of_mut!(TEXT_SHADOW);
+            unsafe { core::ptr::copy_nonoverlapping(self.buffer, shadow, 1); }
+            self.buffer = shadow;
+        }
+    }
+
+    /// Moves the writer back to the text buffer, with what it wrote meanwhile.
+    pub fn attach_text_buffer(&mut self) {
+        let text_buffer = TEXT_BUFFER.load(Ordering::Relaxed);
+        if self.buffer != text_buffer {
+            unsafe { core::ptr::copy_nonoverlapping(self.buffer, text_buffer, 1); }
+            self.buffer = text_buffer;
+        }
+    }
+
     pub fn update_colors(&mut self, fg: VGAColor, bg: VGAColor) {
         let color_code: VGAColorCode = VGAColorCode::new(fg, bg);
         self.color_code = color_code;
         for x in 0..VGA_BUFFER_HEIGHT {
             for y in 0..VGA_BUFFER_WIDTH {
-                self.buffer.chars[x][y].color_code = self.color_code;
+                self.buffer().chars[x][y].color_code = color_code;
             }
         }
     }
@@ -94,22 +319,28 @@ impl VGAWriter {
                 // printable ASCII byte or newline
                 0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
                 // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+                _ => self.write_byte(self.replacement_byte),
             }
         }
     }
 
+    /// Sets the byte `write_string` shows for bytes it cannot print, `0xfe` by default.
+    pub fn set_replacement_byte(&mut self, b: u8) {
+        self.replacement_byte = b;
+    }
+
     fn write_byte(&mut self, byte: u8) {
+        // New output always shows up on the live screen.
+        self.scroll_view_to_bottom();
         match byte {
             b'\n' => self.new_line(),
             0x08 => self.del_char(),
             byte => {
                 if self.column_pos + 1 == VGA_BUFFER_WIDTH {
                     self.new_line();
-                    self.buffer.chars[self.row_pos][self.column_pos].ascii_character = byte;
-                } else {
-                    self.buffer.chars[self.row_pos][self.column_pos].ascii_character = byte;
                 }
+                let (row, column) = (self.row_pos, self.column_pos);
+                self.buffer().chars[row][column].ascii_character = byte;
                 self.column_pos += 1;
             },
         }
@@ -125,11 +356,12 @@ impl VGAWriter {
         } else if self.column_pos > 0 {
             self.column_pos -= 1;
         }
-        self.buffer.chars[self.row_pos][self.column_pos].ascii_character = 0;
+        let (row, column) = (self.row_pos, self.column_pos);
+        self.buffer().chars[row][column].ascii_character = 0;
     }
 
     fn line_empty(&self) -> bool {
-        for vga_char in self.buffer.chars[self.row_pos] {
+        for vga_char in self.buffer_ref().chars[self.row_pos] {
             if vga_char.ascii_character != b' ' && vga_char.ascii_character != 0 {
                 return false
             }
@@ -148,24 +380,332 @@ impl VGAWriter {
     }
 
     fn scroll(&mut self) {
+        VGA_SCROLLBACK.lock().push(&self.buffer_ref().chars[0]);
+        let chars = &mut self.buffer().chars;
         for x in 1..VGA_BUFFER_HEIGHT {
             for y in 0..VGA_BUFFER_WIDTH {
-                self.buffer.chars[x - 1][y] = self.buffer.chars[x][y];
+                chars[x - 1][y] = chars[x][y];
             }
         }
         for x in 0..VGA_BUFFER_WIDTH {
-            self.buffer.chars[VGA_BUFFER_HEIGHT - 1][x].ascii_character = b' ';
+            chars[VGA_BUFFER_HEIGHT - 1][x].ascii_character = b' ';
         }
     }
 
     fn set_cursor(&self, offset: usize) {
-        unsafe {
-            VGA_CRTL_PORT.lock().write(VGA_OFFSET_HIGH as u8);
-            VGA_DATA_PORT.lock().write(((offset) >> 8) as u8);
-            VGA_CRTL_PORT.lock().write(VGA_OFFSET_LOW as u8);
-            VGA_DATA_PORT.lock().write(((offset) & 0xFF) as u8);
+        write_crtc(VGA_OFFSET_HIGH as u8, ((offset) >> 8) as u8);
+        write_crtc(VGA_OFFSET_LOW as u8, ((offset) & 0xFF) as u8);
+    }
+
+    /// Sets the hardware cursor to cover the scan lines `top_scan_line..=bottom_scan_line`.
+    ///
+    /// Scan lines are relative to the character cell: 0 to 15 in 80x25 mode,
+    /// 0 to 7 in 80x50 mode. This also re-enables a disabled cursor.
+    pub fn set_cursor_shape(&mut self, top_scan_line: u8, bottom_scan_line: u8) {
+        // Keep the reserved bits of the start register and the skew bits of the end register.
+        let start = read_crtc(VGA_CURSOR_START) & !(VGA_CURSOR_DISABLE | VGA_SCAN_LINE_MASK);
+        write_crtc(VGA_CURSOR_START, start | (top_scan_line & VGA_SCAN_LINE_MASK));
+        let end = read_crtc(VGA_CURSOR_END) & !VGA_SCAN_LINE_MASK;
+        write_crtc(VGA_CURSOR_END, end | (bottom_scan_line & VGA_SCAN_LINE_MASK));
+    }
+
+    /// Scrolls the view `lines` further back into the scroll-back history.
+    ///
+    /// Only the display changes, output keeps going to the live screen and
+    /// brings the view back to it.
+    pub fn scroll_view_up(&mut self, lines: usize) {
+        let mut history = VGA_SCROLLBACK.lock();
+        if self.view_offset == 0 {
+            history.live = self.buffer_ref().chars;
+        }
+        self.view_offset = (self.view_offset + lines).min(history.len);
+        self.redraw_view(&history);
+    }
+
+    /// Scrolls the view `lines` back towards the live screen.
+    pub fn scroll_view_down(&mut self, lines: usize) {
+        if self.view_offset == 0 {
+            return;
+        }
+        let history = VGA_SCROLLBACK.lock();
+        self.view_offset = self.view_offset.saturating_sub(lines);
+        self.redraw_view(&history);
+    }
+
+    /// Shows the live screen again.
+    pub fn scroll_view_to_bottom(&mut self) {
+        if self.view_offset != 0 {
+            self.scroll_view_down(self.view_offset);
+        }
+    }
+
+    /// Draws the history followed by the saved live screen, `view_offset` lines from the bottom.
+    fn redraw_view(&mut self, history: &Scrollback) {
+        let first = history.len - self.view_offset;
+        for row in 0..VGA_BUFFER_HEIGHT {
+            let line = first + row;
+            self.buffer().chars[row] = match history.line(line) {
+                Some(line) => *line,
+                None => history.live[line - history.len],
+            };
+        }
+    }
+
+    /// Writes `value` in lowercase hex, zero-padded to at least `digits` digits.
+    pub fn write_u64_hex(&mut self, value: u64, digits: usize) {
+        let mut buf = [b'0'; 16];
+        let mut len = 0;
+        let mut rest = value;
+        while rest != 0 || len == 0 {
+            buf[len] = HEX_DIGITS[(rest & 0xF) as usize];
+            rest >>= 4;
+            len += 1;
+        }
+        self.write_repeated(b'0', digits.saturating_sub(len));
+        for &digit in buf[..len].iter().rev() {
+            self.write_byte(digit);
+        }
+    }
+
+    /// Writes `byte` `count` times.
+    pub fn write_repeated(&mut self, byte: u8, count: usize) {
+        for _ in 0..count {
+            self.write_byte(byte);
+        }
+    }
+
+    /// Writes `bytes` from `row`, `column` on, without moving the cursor or
+    /// scrolling. What does not fit on the row is left out.
+    pub fn write_at(&mut self, row: usize, column: usize, bytes: &[u8]) {
+        if row >= VGA_BUFFER_HEIGHT {
+            return;
+        }
+        let replacement_byte = self.replacement_byte;
+        let cells = self.buffer().chars[row].iter_mut().skip(column);
+        for (cell, &byte) in cells.zip(bytes) {
+            cell.ascii_character = match byte {
+                0x20..=0x7e => byte,
+                _ => replacement_byte,
+            };
+        }
+    }
+
+    /// Clears the screen to spaces in the current colors and puts the cursor
+    /// back at the top left corner.
+    pub fn clear_screen(&mut self) {
+        self.scroll_view_to_bottom();
+        for row in 0..VGA_BUFFER_HEIGHT {
+            self.cls_line(row);
+        }
+        (self.row_pos, self.column_pos) = (0, 0);
+        self.set_cursor(0);
+    }
+
+    /// Clears row `row` to spaces in the current colors. Rows past the screen
+    /// are ignored.
+    pub fn cls_line(&mut self, row: usize) {
+        let (fg, bg) = (self.color_code.foreground(), self.color_code.background());
+        self.fill_region(row, 0, row, VGA_BUFFER_WIDTH - 1, ' ', fg, bg);
+    }
+
+    /// Fills rows `top..=bottom` and columns `left..=right` with `ch` in `fg`
+    /// on `bg`, without moving the cursor. The region is clamped to the screen,
+    /// and a `ch` outside printable ASCII shows as the replacement byte.
+    #[allow(clippy::too_many_arguments)]
+    pub fn fill_region(&mut self, top: usize, left: usize, bottom: usize, right: usize, ch: char, fg: VGAColor, bg: VGAColor) {
+        let byte = match ch {
+            ' '..='~' => ch as u8,
+            _ => self.replacement_byte,
+        };
+        let cell = VGAChar { ascii_character: byte, color_code: VGAColorCode::new(fg, bg) };
+        let (bottom, right) = (bottom.min(VGA_BUFFER_HEIGHT - 1), right.min(VGA_BUFFER_WIDTH - 1));
+        for row in top..=bottom {
+            for column in left..=right {
+                self.buffer().chars[row][column] = cell;
+            }
         }
     }
+
+    /// Draws the border of the box with corners at `top`, `left` and `bottom`,
+    /// `right`, in the current colors and without touching the inside. The
+    /// box is clamped to the screen.
+    pub fn draw_box(&mut self, top: usize, left: usize, bottom: usize, right: usize, style: BoxStyle) {
+        let (bottom, right) = (bottom.min(VGA_BUFFER_HEIGHT - 1), right.min(VGA_BUFFER_WIDTH - 1));
+        if top > bottom || left > right {
+            return;
+        }
+        let ([top_left, top_right, bottom_right, bottom_left], horizontal, vertical) = style.glyphs();
+        let color_code = self.color_code;
+        let chars = &mut self.buffer().chars;
+        let mut put = |row: usize, column: usize, byte: u8| {
+            chars[row][column] = VGAChar { ascii_character: byte, color_code };
+        };
+        for column in left + 1..right {
+            put(top, column, horizontal);
+            put(bottom, column, horizontal);
+        }
+        for row in top + 1..bottom {
+            put(row, left, vertical);
+            put(row, right, vertical);
+        }
+        put(top, left, top_left);
+        put(top, right, top_right);
+        put(bottom, right, bottom_right);
+        put(bottom, left, bottom_left);
+    }
+
+    /// Writes `label [=====>    ] 42%` on the current line, the bar `width`
+    /// characters wide and `percent` clamped to 100. No newline follows, and the
+    /// returned row and column of the `[` let
+    /// [`update_progress_bar_in_place`](Self::update_progress_bar_in_place) redraw it.
+    pub fn print_progress_bar(&mut self, label: &str, percent: u8, width: usize) -> (usize, usize) {
+        self.progress_width = width.clamp(1, PROGRESS_MAX_WIDTH);
+        self.write_string(label);
+        self.write_byte(b' ');
+        // The bar goes on the next line rather than wrap in the middle.
+        if self.column_pos + self.progress_width + 7 > VGA_BUFFER_WIDTH {
+            self.new_line();
+        }
+        let start = (self.row_pos, self.column_pos);
+        let mut bar = [0u8; VGA_BUFFER_WIDTH];
+        let len = progress_bar(percent, self.progress_width, &mut bar);
+        for &byte in &bar[..len] {
+            self.write_byte(byte);
+        }
+        start
+    }
+
+    /// Redraws the bar [`print_progress_bar`](Self::print_progress_bar) wrote
+    /// at `saved_row`, `saved_col` with `percent` done, leaving the cursor where
+    /// it is. The bar keeps its width.
+    pub fn update_progress_bar_in_place(&mut self, saved_row: usize, saved_col: usize, percent: u8) {
+        let mut bar = [0u8; VGA_BUFFER_WIDTH];
+        let len = progress_bar(percent, self.progress_width.max(1), &mut bar);
+        self.write_at(saved_row, saved_col, &bar[..len]);
+    }
+
+    /// Writes a hex dump of `data` starting on a new line, `columns` bytes per row.
+    ///
+    /// Each row is the offset, the bytes in hex and, after a `|`, the bytes as
+    /// ASCII with `.` for anything not printable:
+    ///
+    /// ```text
+    /// 0000: 48 65 6c 6c 6f 0a |Hello.
+    /// ```
+    ///
+    /// `columns` is clamped to what fits on a line and at most one screen minus
+    /// a line of rows is written, the rest of `data` is left out. Nothing is
+    /// formatted through `core::fmt`, so this works in any context the writer does.
+    pub fn write_hex_dump(&mut self, data: &[u8], columns: usize) {
+        let columns = columns.clamp(1, HEX_DUMP_MAX_COLUMNS);
+        if self.column_pos != 0 {
+            self.write_byte(b'\n');
+        }
+        for (row, bytes) in data.chunks(columns).take(VGA_BUFFER_HEIGHT - 1).enumerate() {
+            self.write_u64_hex((row * columns) as u64, 4);
+            self.write_byte(b':');
+            for &byte in bytes {
+                self.write_byte(b' ');
+                self.write_u64_hex(byte as u64, 2);
+            }
+            self.write_repeated(b' ', (columns - bytes.len()) * 3 + 1);
+            self.write_byte(b'|');
+            for &byte in bytes {
+                self.write_byte(if (0x20..=0x7e).contains(&byte) { byte } else { b'.' });
+            }
+            self.write_byte(b'\n');
+        }
+    }
+
+    /// Full character cell cursor.
+    pub fn cursor_block(&mut self) {
+        self.set_cursor_shape(0, 15);
+    }
+
+    /// Classic underline cursor.
+    pub fn cursor_underline(&mut self) {
+        self.set_cursor_shape(13, 15);
+    }
+
+    /// Cursor covering the lower half of the character cell.
+    pub fn cursor_half_block(&mut self) {
+        self.set_cursor_shape(8, 15);
+    }
+}
+
+/// Returns the text of scroll-back line `index`, 0 being the oldest line still kept.
+pub fn scrollback_line(index: usize) -> Option<[u8; VGA_BUFFER_WIDTH]> {
+    crate::tables::without_interrupts(|| {
+        let history = VGA_SCROLLBACK.lock();
+        let line = history.line(index)?;
+        Some(core::array::from_fn(|i| line[i].ascii_character))
+    })
+}
+
+/// Fills `out` with `[=====>    ] 42%`, the bar `width` characters wide, and
+/// returns its length. The percentage is right-aligned so that redrawing the
+/// bar always covers the previous one.
+fn progress_bar(percent: u8, width: usize, out: &mut [u8]) -> usize {
+    let percent = percent.min(100) as usize;
+    let done = percent * width / 100;
+    out[0] = b'[';
+    for (i, byte) in out[1..=width].iter_mut().enumerate() {
+        *byte = match i.cmp(&done) {
+            core::cmp::Ordering::Less => b'=',
+            core::cmp::Ordering::Equal => b'>',
+            core::cmp::Ordering::Greater => b' ',
+        };
+    }
+    let digit = |value: usize, shown: bool| if shown { b'0' + value as u8 } else { b' ' };
+    out[width + 1..width + 7].copy_from_slice(&[
+        b']', b' ', digit(percent / 100, percent >= 100), digit(percent / 10 % 10, percent >= 10), digit(percent % 10, true), b'%',
+    ]);
+    width + 7
+}
+
+/// Paints the whole screen white on black, over the colors the firmware left.
+///
+/// Must be called once, early in boot; printing works before it too.
+pub fn init_vga() {
+    assert!(!VGA_INITIALIZED.swap(true, Ordering::AcqRel), "init_vga called twice");
+    crate::tables::without_interrupts(|| {
+        VGA_WRITER.lock().update_colors(VGAColor::BrightWhite, VGAColor::Black);
+    });
+}
+
+/// Moves the writer to the text buffer in the physical memory window at
+/// `phys_mem_offset`, which every address space maps, unlike the identity
+/// mapping in the lower half.
+pub fn map_text_buffer(phys_mem_offset: u64) {
+    let text_buffer = (phys_mem_offset + VGA_BUFFER_PHYS) as *mut VGABuffer;
+    crate::tables::without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        let old = TEXT_BUFFER.swap(text_buffer, Ordering::Relaxed);
+        if writer.buffer == old {
+            writer.buffer = text_buffer;
+        }
+    });
+}
+
+/// Returns the number of lines in the scroll-back history.
+pub fn scrollback_len() -> usize {
+    crate::tables::without_interrupts(|| VGA_SCROLLBACK.lock().len)
+}
+
+/// Writes `value` to the CRT controller register `index`.
+fn write_crtc(index: u8, value: u8) {
+    unsafe {
+        VGA_CRTL_PORT.lock().write(index);
+        VGA_DATA_PORT.lock().write(value);
+    }
+}
+
+/// Reads the CRT controller register `index`.
+fn read_crtc(index: u8) -> u8 {
+    unsafe {
+        VGA_CRTL_PORT.lock().write(index);
+        VGA_DATA_PORT.lock().read(0u8)
+    }
 }
 
 impl fmt::Write for VGAWriter {
@@ -186,21 +726,323 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Prints `label [=====>    ] 42%` on the current line, see
+/// [`VGAWriter::print_progress_bar`], and returns where the bar starts.
+#[macro_export]
+macro_rules! print_progress {
+    ($label:expr, $percent:expr, $width:expr) => ($crate::vga::_print_progress($label, $percent, $width));
+}
+
+/// Prints a hex dump of a byte slice, 16 bytes per row.
+#[macro_export]
+macro_rules! print_hex_dump {
+    ($data:expr) => ($crate::vga::_print_hex_dump($data));
+}
+
+/// Clears the screen, see [`VGAWriter::clear_screen`].
+pub fn clear_screen() {
+    crate::tables::without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writer.clear_screen();
+        mirror_to_console(&writer);
+    });
+}
+
+/// Repaints the whole screen `fg` on `bg`, and writes on in those colors.
+pub fn set_colors(fg: VGAColor, bg: VGAColor) {
+    crate::tables::without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writer.update_colors(fg, bg);
+        mirror_to_console(&writer);
+    });
+}
+
+/// Draws a box on the screen, see [`VGAWriter::draw_box`].
+pub fn draw_box(top: usize, left: usize, bottom: usize, right: usize, style: BoxStyle) {
+    crate::tables::without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writer.draw_box(top, left, bottom, right, style);
+        mirror_to_console(&writer);
+    });
+}
+
+#[doc(hidden)]
+pub fn _print_hex_dump(data: &[u8]) {
+    crate::tables::without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writer.write_hex_dump(data, 16);
+        mirror_to_console(&writer);
+    });
+}
+
+#[doc(hidden)]
+pub fn _print_progress(label: &str, percent: u8, width: usize) -> (usize, usize) {
+    crate::tables::without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        let start = writer.print_progress_bar(label, percent, width);
+        mirror_to_console(&writer);
+        start
+    })
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    use core::{fmt::Write, arch::asm};
-    use crate::tables::RFlags;
-    let int_enabled: bool = RFlags::read().contains(RFlags::INTERRUPT_FLAG);
+    use core::fmt::Write;
+    use crate::tables::without_interrupts;
+
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writer.write_fmt(args).unwrap();
+        mirror_to_console(&writer);
+    });
+}
+
+/// Draws what changed on the text screen to the framebuffer console, if the
+/// screen is in a graphics mode. Called with the writer locked after writing.
+pub fn mirror_to_console(writer: &VGAWriter) {
+    if crate::drivers::vbe::console::is_active() {
+        crate::drivers::vbe::console::render(writer);
+    }
+}
+
+#[test_case]
+fn vga_writer_usable_after_init() {
+    use core::fmt::Write;
+    use crate::tables::without_interrupts;
+
+    assert!(VGA_INITIALIZED.load(Ordering::Acquire));
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writeln!(writer).unwrap();
+        write!(writer, "vga").unwrap();
+        let (row, column) = (writer.row_pos, writer.column_pos);
+        let written = &writer.buffer_ref().chars[row][column - 3..column];
+        assert!(written.iter().zip(b"vga").all(|(c, &b)| c.ascii_character == b));
+        let white_on_black = VGAColorCode::new(VGAColor::BrightWhite, VGAColor::Black);
+        assert!(written.iter().all(|c| c.color_code == white_on_black));
+        writeln!(writer).unwrap();
+    });
+}
+
+#[test_case]
+fn replacement_byte_for_control_bytes() {
+    use crate::tables::without_interrupts;
 
-    if int_enabled {
-        unsafe {
-            asm!("cli", options(preserves_flags, nostack));
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writer.write_string("\n");
+        writer.set_replacement_byte(b'?');
+        writer.write_string("a\x07b");
+        writer.set_replacement_byte(0xfe);
+        let (row, column) = (writer.row_pos, writer.column_pos);
+        let written = &writer.buffer_ref().chars[row][column - 3..column];
+        assert!(written.iter().zip(b"a?b").all(|(c, &b)| c.ascii_character == b));
+        writer.write_string("\n");
+    });
+}
+
+#[test_case]
+fn cursor_block_programs_crtc() {
+    VGA_WRITER.lock().cursor_block();
+    assert_eq!(read_crtc(VGA_CURSOR_START) & (VGA_CURSOR_DISABLE | VGA_SCAN_LINE_MASK), 0);
+    assert_eq!(read_crtc(VGA_CURSOR_END) & VGA_SCAN_LINE_MASK, 15);
+    VGA_WRITER.lock().cursor_underline();
+}
+
+#[test_case]
+fn scrollback_keeps_scrolled_lines() {
+    use core::fmt::Write;
+    use crate::tables::without_interrupts;
+
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        for i in 0..100 {
+            writeln!(writer, "scrollback line {}", i).unwrap();
+        }
+    });
+    let expected = b"scrollback line 10 ";
+    let found = (0..scrollback_len())
+        .rev()
+        .filter_map(scrollback_line)
+        .any(|line| line.starts_with(expected));
+    assert!(found);
+
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        let live_top = writer.buffer_ref().chars[0];
+        writer.scroll_view_up(VGA_BUFFER_HEIGHT);
+        assert_eq!(writer.view_offset, VGA_BUFFER_HEIGHT);
+        writer.scroll_view_to_bottom();
+        assert_eq!(writer.buffer_ref().chars[0], live_top);
+    });
+}
+
+#[test_case]
+fn hex_dump_format() {
+    use crate::tables::without_interrupts;
+
+    let mut data = [0u8; 32];
+    data[..16].copy_from_slice(b"Hello, krabbos!\n");
+    for (i, byte) in data[16..].iter_mut().enumerate() {
+        *byte = i as u8 * 0x11;
+    }
+    let expected: [&[u8]; 2] = [
+        b"0000: 48 65 6c 6c 6f 2c 20 6b 72 61 62 62 6f 73 21 0a |Hello, krabbos!.",
+        b"0010: 00 11 22 33 44 55 66 77 88 99 aa bb cc dd ee ff |..\"3DUfw........",
+    ];
+
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writer.write_hex_dump(&data, 16);
+        assert_eq!(writer.column_pos, 0);
+        let first_row = writer.row_pos - expected.len();
+        for (i, line) in expected.iter().enumerate() {
+            for (column, &byte) in line.iter().enumerate() {
+                assert_eq!(writer.buffer_ref().chars[first_row + i][column].ascii_character, byte);
+            }
         }
+    });
+}
+
+#[test_case]
+fn color_code_round_trip() {
+    let code = VGAColorCode::new(VGAColor::Yellow, VGAColor::Blue);
+    assert_eq!(code.foreground(), VGAColor::Yellow);
+    assert_eq!(code.background(), VGAColor::Blue);
+    assert_eq!(code, VGAColorCode::new(VGAColor::Yellow, VGAColor::Blue));
+    assert_ne!(code, VGAColorCode::new(VGAColor::Blue, VGAColor::Yellow));
+    for value in 0..16 {
+        assert_eq!(VGAColor::from_u8(value) as u8, value);
+    }
+    assert_eq!(VGAColor::from_name("LightBlue"), Some(VGAColor::LightBlue));
+    assert_eq!(VGAColor::from_name("brightwhite"), Some(VGAColor::BrightWhite));
+    assert_eq!(VGAColor::from_name("light blue"), None);
+}
+
+#[test_case]
+fn ansi_color_codes() {
+    let dark = [
+        VGAColor::Black, VGAColor::Red, VGAColor::Green, VGAColor::Brown,
+        VGAColor::Blue, VGAColor::Magenta, VGAColor::Cyan, VGAColor::White,
+    ];
+    let bright = [
+        VGAColor::Gray, VGAColor::LightRed, VGAColor::LightGreen, VGAColor::Yellow,
+        VGAColor::LightBlue, VGAColor::LightMagenta, VGAColor::LightCyan, VGAColor::BrightWhite,
+    ];
+    for (i, (&dark, &bright)) in dark.iter().zip(bright.iter()).enumerate() {
+        let i = i as u8;
+        assert_eq!(VGAColor::from_ansi_fg(30 + i), Some(dark));
+        assert_eq!(VGAColor::from_ansi_fg(90 + i), Some(bright));
+        assert_eq!(VGAColor::from_ansi_bg(40 + i), Some(dark));
+        assert_eq!(VGAColor::from_ansi_bg(100 + i), Some(bright));
+        assert_eq!(dark.to_ansi_fg(), 30 + i);
+        assert_eq!(bright.to_ansi_fg(), 90 + i);
+    }
+    for code in [0, 29, 38, 39, 40, 89, 98, 255] {
+        assert_eq!(VGAColor::from_ansi_fg(code), None);
+    }
+    for code in [0, 37, 39, 48, 99, 108, 255] {
+        assert_eq!(VGAColor::from_ansi_bg(code), None);
     }
-    VGA_WRITER.lock().write_fmt(args).unwrap();
-    if int_enabled {
-        unsafe {
-            asm!("sti", options(preserves_flags, nostack));
+}
+
+#[test_case]
+fn progress_bar_format() {
+    let mut bar = [0u8; VGA_BUFFER_WIDTH];
+    let mut format = |percent: u8| {
+        let len = progress_bar(percent, 10, &mut bar);
+        core::str::from_utf8(&bar[..len]).unwrap() == match percent.min(100) {
+            0 => "[>         ]   0%",
+            42 => "[====>     ]  42%",
+            50 => "[=====>    ]  50%",
+            _ => "[==========] 100%",
         }
+    };
+    for percent in [0, 42, 50, 100, 101, 255] {
+        assert!(format(percent), "{}%", percent);
     }
 }
+
+#[test_case]
+fn progress_bar_printed_and_updated() {
+    use crate::tables::without_interrupts;
+
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        writer.write_string("\n");
+        let (row, column) = writer.print_progress_bar("disk", 50, 4);
+        let end = writer.cursor_position();
+        let line = |writer: &VGAWriter| core::array::from_fn::<u8, 15, _>(|i| writer.char_at(row, i).0);
+        assert_eq!((row, column), (end.0, 5));
+        assert_eq!(&line(&writer), b"disk [==> ]  50");
+        writer.update_progress_bar_in_place(row, column, 200);
+        assert_eq!(&line(&writer), b"disk [====] 100");
+        assert_eq!(writer.cursor_position(), end);
+        writer.write_string("\n");
+    });
+}
+
+#[test_case]
+fn fill_region_changes_only_the_region() {
+    use crate::tables::without_interrupts;
+
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        let saved = writer.buffer_ref().chars;
+        let red_on_blue = VGAColorCode::new(VGAColor::Red, VGAColor::Blue);
+        writer.fill_region(3, 10, 5, 14, '#', VGAColor::Red, VGAColor::Blue);
+        for row in 0..VGA_BUFFER_HEIGHT {
+            for column in 0..VGA_BUFFER_WIDTH {
+                let inside = (3..=5).contains(&row) && (10..=14).contains(&column);
+                let cell = writer.char_at(row, column);
+                if inside {
+                    assert_eq!(cell, (b'#', red_on_blue));
+                } else {
+                    let old = saved[row][column];
+                    assert_eq!(cell, (old.ascii_character, old.color_code), "{}, {} changed", row, column);
+                }
+            }
+        }
+
+        // Clamped to the bottom right corner, with a character VGA has not.
+        writer.fill_region(VGA_BUFFER_HEIGHT - 1, VGA_BUFFER_WIDTH - 2, 100, 100, 'é', VGAColor::Red, VGAColor::Blue);
+        assert_eq!(writer.char_at(VGA_BUFFER_HEIGHT - 1, VGA_BUFFER_WIDTH - 1), (0xfe, red_on_blue));
+        assert_eq!(writer.char_at(VGA_BUFFER_HEIGHT - 2, VGA_BUFFER_WIDTH - 1), (
+            saved[VGA_BUFFER_HEIGHT - 2][VGA_BUFFER_WIDTH - 1].ascii_character,
+            saved[VGA_BUFFER_HEIGHT - 2][VGA_BUFFER_WIDTH - 1].color_code,
+        ));
+
+        writer.cls_line(4);
+        assert!((0..VGA_BUFFER_WIDTH).all(|column| writer.char_at(4, column) == (b' ', writer.color_code)));
+        writer.cls_line(VGA_BUFFER_HEIGHT);
+        writer.buffer().chars = saved;
+    });
+}
+
+#[test_case]
+fn draw_box_draws_only_the_border() {
+    use crate::tables::without_interrupts;
+
+    without_interrupts(|| {
+        let mut writer = VGA_WRITER.lock();
+        let saved = writer.buffer_ref().chars;
+        writer.fill_region(2, 2, 6, 9, '.', VGAColor::White, VGAColor::Black);
+        writer.draw_box(2, 2, 5, 8, BoxStyle::Ascii);
+        let row = |writer: &VGAWriter, row: usize| core::array::from_fn::<u8, 8, _>(|i| writer.char_at(row, 2 + i).0);
+        assert_eq!(&row(&writer, 2), b"+-----+.");
+        assert_eq!(&row(&writer, 3), b"|.....|.");
+        assert_eq!(&row(&writer, 5), b"+-----+.");
+        assert_eq!(&row(&writer, 6), b"........");
+
+        writer.draw_box(2, 2, 5, 8, BoxStyle::Extended);
+        assert_eq!(&row(&writer, 2), &[0xDA, 0xC4, 0xC4, 0xC4, 0xC4, 0xC4, 0xBF, b'.']);
+        assert_eq!(&row(&writer, 4), &[0xB3, b'.', b'.', b'.', b'.', b'.', 0xB3, b'.']);
+        assert_eq!(&row(&writer, 5), &[0xC0, 0xC4, 0xC4, 0xC4, 0xC4, 0xC4, 0xD9, b'.']);
+
+        // Clamped to the screen edge.
+        writer.draw_box(20, 70, 30, 90, BoxStyle::Ascii);
+        assert_eq!(writer.char_at(VGA_BUFFER_HEIGHT - 1, VGA_BUFFER_WIDTH - 1).0, b'+');
+        assert_eq!(writer.char_at(22, VGA_BUFFER_WIDTH - 1).0, b'|');
+        writer.buffer().chars = saved;
+    });
+}
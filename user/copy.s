@@ -0,0 +1,120 @@
+# Copies /hello.txt of the initrd to /tmp/copy in upper case, then opens
+# the copy again and checks it against what it wrote. Exits with 0 if they
+# match; otherwise -1 for opening the original, -2 for reading it, -3 for
+# creating the copy, -4 for writing it, -5 for opening it again and -6 for
+# what it read back. The original stays open, for the exit to close.
+
+    .intel_syntax noprefix
+
+    .equ SYS_READ, 0
+    .equ SYS_WRITE, 1
+    .equ SYS_OPEN, 2
+    .equ SYS_CLOSE, 3
+    .equ SYS_EXIT, 60
+    .equ O_RDONLY, 0
+    .equ O_WRONLY, 01
+    .equ O_CREAT, 0100
+    .equ O_TRUNC, 01000
+    .equ SIZE, 64
+
+    .text
+    .global _start
+_start:
+    mov eax, SYS_OPEN
+    lea rbx, [rip + original]
+    mov ecx, O_RDONLY
+    int 0x80
+    mov r12, rax
+    mov rbx, -1
+    test rax, rax
+    js exit
+
+    mov eax, SYS_READ
+    mov rbx, r12
+    lea rcx, [rip + buffer]
+    mov edx, SIZE
+    int 0x80
+    mov r13, rax
+    mov rbx, -2
+    test rax, rax
+    jle exit
+
+    lea rdi, [rip + buffer]
+    mov rcx, r13
+1:
+    mov al, byte ptr [rdi]
+    sub al, 'a'
+    cmp al, 'z' - 'a'
+    ja 2f
+    sub byte ptr [rdi], 'a' - 'A'
+2:
+    inc rdi
+    dec rcx
+    jnz 1b
+
+    mov eax, SYS_OPEN
+    lea rbx, [rip + copy]
+    mov ecx, O_WRONLY | O_CREAT | O_TRUNC
+    mov edx, 0644
+    int 0x80
+    mov r14, rax
+    mov rbx, -3
+    test rax, rax
+    js exit
+
+    mov eax, SYS_WRITE
+    mov rbx, r14
+    lea rcx, [rip + buffer]
+    mov rdx, r13
+    int 0x80
+    mov rbx, -4
+    cmp rax, r13
+    jne exit
+    mov eax, SYS_CLOSE
+    mov rbx, r14
+    int 0x80
+
+    mov eax, SYS_OPEN
+    lea rbx, [rip + copy]
+    mov ecx, O_RDONLY
+    int 0x80
+    mov r14, rax
+    mov rbx, -5
+    test rax, rax
+    js exit
+
+    # Asks for more than there is, so a copy too long shows.
+    mov eax, SYS_READ
+    mov rbx, r14
+    lea rcx, [rip + readback]
+    mov edx, SIZE
+    int 0x80
+    mov rbx, -6
+    cmp rax, r13
+    jne exit
+    lea rsi, [rip + buffer]
+    lea rdi, [rip + readback]
+    mov rcx, r13
+    repe cmpsb
+    jne exit
+    mov eax, SYS_CLOSE
+    mov rbx, r14
+    int 0x80
+
+    xor ebx, ebx
+exit:
+    mov eax, SYS_EXIT
+    int 0x80
+    ud2
+
+    .section .rodata
+original:
+    .asciz "/hello.txt"
+copy:
+    .asciz "/tmp/copy"
+
+    .bss
+buffer:
+    .skip SIZE
+readback:
+    .skip SIZE
@@ -0,0 +1,10 @@
+# Divides by zero right away, which should only end itself.
+
+    .intel_syntax noprefix
+
+    .text
+    .global _start
+_start:
+    xor ecx, ecx
+    mov eax, 1
+    div ecx
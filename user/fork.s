@@ -0,0 +1,90 @@
+# Forks a child, which forks a grandchild. Each writes its own value to a
+# variable, shared copy-on-write after the forks, gives the CPU away for the
+# others to write theirs, and checks that its copy kept its value. All three
+# first check that their parent is the process that forked them, the kernel
+# for the first. The parent and the child exit with the pid of their child,
+# the grandchild with 42, and any of them with -1 if a check failed.
+
+    .intel_syntax noprefix
+
+    .equ SYS_SCHED_YIELD, 24
+    .equ SYS_GETPID, 39
+    .equ SYS_FORK, 57
+    .equ SYS_EXIT, 60
+    .equ SYS_GETPPID, 110
+    .equ ROUNDS, 4
+
+    .text
+    .global _start
+_start:
+    call check_parent
+    mov eax, SYS_FORK
+    int 0x80
+    test rax, rax
+    jz child
+    js failed
+    mov r12, rax
+    call settle
+    cmp qword ptr [rip + value], 1
+    jne failed
+    mov rbx, r12
+    jmp exit
+
+child:
+    call check_parent
+    mov qword ptr [rip + value], 2
+    mov eax, SYS_FORK
+    int 0x80
+    test rax, rax
+    jz grandchild
+    js failed
+    mov r12, rax
+    call settle
+    cmp qword ptr [rip + value], 2
+    jne failed
+    mov rbx, r12
+    jmp exit
+
+grandchild:
+    call check_parent
+    cmp qword ptr [rip + value], 2
+    jne failed
+    mov qword ptr [rip + value], 3
+    call settle
+    cmp qword ptr [rip + value], 3
+    jne failed
+    mov ebx, 42
+    jmp exit
+
+failed:
+    mov rbx, -1
+exit:
+    mov eax, SYS_EXIT
+    int 0x80
+    ud2
+
+# Fails unless the parent is the process whose pid is in r14, the kernel's
+# 0 to begin with, then puts the pid of the caller there for its children.
+check_parent:
+    mov eax, SYS_GETPPID
+    int 0x80
+    cmp rax, r14
+    jne failed
+    mov eax, SYS_GETPID
+    int 0x80
+    mov r14, rax
+    ret
+
+# Gives the CPU away a few times.
+settle:
+    mov r13, ROUNDS
+1:
+    mov eax, SYS_SCHED_YIELD
+    int 0x80
+    dec r13
+    jnz 1b
+    ret
+
+    .data
+value:
+    .quad 1
@@ -0,0 +1,41 @@
+# Prints a greeting three times, giving the CPU away after each, then exits
+# with 0 if its counter in .data saw every round.
+
+    .intel_syntax noprefix
+
+    .equ SYS_WRITE, 1
+    .equ SYS_SCHED_YIELD, 24
+    .equ SYS_EXIT, 60
+    .equ ROUNDS, 3
+
+    .text
+    .global _start
+_start:
+    mov r12, ROUNDS
+1:
+    mov eax, SYS_WRITE
+    mov ebx, 1
+    lea rcx, [rip + message]
+    mov edx, message_len
+    int 0x80
+    inc qword ptr [rip + counter]
+    mov eax, SYS_SCHED_YIELD
+    int 0x80
+    dec r12
+    jnz 1b
+
+    xor ebx, ebx
+    cmp qword ptr [rip + counter], ROUNDS
+    setne bl
+    mov eax, SYS_EXIT
+    int 0x80
+    ud2
+
+    .section .rodata
+message:
+    .ascii "Hello from user space!\n"
+    .equ message_len, . - message
+
+    .data
+counter:
+    .quad 0
@@ -0,0 +1,8 @@
+# Runs an undefined instruction right away, which should only end itself.
+
+    .intel_syntax noprefix
+
+    .text
+    .global _start
+_start:
+    ud2
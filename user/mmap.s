@@ -0,0 +1,100 @@
+# Maps 1 MiB at a hint, checks that it got the hint and that every page
+# reads as zeros, writes to every page, then unmaps nothing, a page in the
+# middle and the whole range, and touches the first page again. That has to kill
+# it with a segfault; any other exit is a failed check: -1 for mmap, -2 for
+# the contents, -3 for munmap, -4 for surviving the last touch, -5 for a
+# writable and executable mapping allowed without asking.
+
+    .intel_syntax noprefix
+
+    .equ SYS_MMAP, 9
+    .equ SYS_MUNMAP, 11
+    .equ SYS_EXIT, 60
+    .equ PROT_READ, 1
+    .equ PROT_WRITE, 2
+    .equ PROT_EXEC, 4
+    .equ MAP_PRIVATE, 0x02
+    .equ MAP_ANONYMOUS, 0x20
+    .equ EINVAL, 22
+    .equ EACCES, 13
+    .equ HINT, 0x2000000000
+    .equ SIZE, 0x100000
+    .equ PAGE, 0x1000
+
+    .text
+    .global _start
+_start:
+    mov eax, SYS_MMAP
+    xor ebx, ebx
+    mov ecx, PAGE
+    mov edx, PROT_READ | PROT_WRITE | PROT_EXEC
+    mov esi, MAP_PRIVATE | MAP_ANONYMOUS
+    int 0x80
+    mov rbx, -5
+    cmp rax, -EACCES
+    jne exit
+
+    mov eax, SYS_MMAP
+    movabs rbx, HINT
+    mov ecx, SIZE
+    mov edx, PROT_READ | PROT_WRITE
+    mov esi, MAP_PRIVATE | MAP_ANONYMOUS
+    int 0x80
+    mov r12, rax
+    mov rbx, -1
+    movabs rcx, HINT
+    cmp rax, rcx
+    jne exit
+
+    # Reading every qword maps every page.
+    mov rdi, r12
+    mov ecx, SIZE / 8
+    xor eax, eax
+    repe scasq
+    mov rbx, -2
+    jne exit
+
+    mov rdi, r12
+    mov ecx, SIZE / PAGE
+1:
+    mov qword ptr [rdi], rcx
+    add rdi, PAGE
+    dec ecx
+    jnz 1b
+
+    # An empty range is not a range at all.
+    mov eax, SYS_MUNMAP
+    mov rbx, r12
+    xor ecx, ecx
+    int 0x80
+    mov rbx, -3
+    cmp rax, -EINVAL
+    jne exit
+
+    mov eax, SYS_MUNMAP
+    lea rbx, [r12 + SIZE / 2]
+    mov ecx, PAGE
+    int 0x80
+    mov rbx, -3
+    test rax, rax
+    jnz exit
+    # Both halves are still there.
+    cmp qword ptr [r12 + SIZE / 2 - PAGE], SIZE / PAGE / 2 + 1
+    jne exit
+    cmp qword ptr [r12 + SIZE / 2 + PAGE], SIZE / PAGE / 2 - 1
+    jne exit
+
+    mov eax, SYS_MUNMAP
+    mov rbx, r12
+    mov ecx, SIZE
+    int 0x80
+    mov rbx, -3
+    test rax, rax
+    jnz exit
+
+    mov rax, qword ptr [r12]
+    mov rbx, -4
+exit:
+    mov eax, SYS_EXIT
+    int 0x80
+    ud2
@@ -0,0 +1,17 @@
+# Reads the first page of hello, linked below this program: the address is
+# only mapped in the address space of hello, so the read faults and the
+# kernel ends this program instead.
+
+    .intel_syntax noprefix
+
+    .equ SYS_EXIT, 60
+    .equ HELLO_BASE, 0x400000
+
+    .text
+    .global _start
+_start:
+    mov rax, qword ptr [HELLO_BASE]
+    xor ebx, ebx
+    mov eax, SYS_EXIT
+    int 0x80
+    ud2
@@ -0,0 +1,42 @@
+# Asks for its pid around a few other system calls, giving the CPU away in
+# between, and exits with it if it never changed and its parent is the
+# kernel, with -1 otherwise.
+
+    .intel_syntax noprefix
+
+    .equ SYS_SCHED_YIELD, 24
+    .equ SYS_GETPID, 39
+    .equ SYS_GETPPID, 110
+    .equ SYS_EXIT, 60
+    .equ ROUNDS, 3
+
+    .text
+    .global _start
+_start:
+    mov eax, SYS_GETPID
+    int 0x80
+    mov r12, rax
+    mov r13, ROUNDS
+1:
+    mov eax, SYS_SCHED_YIELD
+    int 0x80
+    mov eax, SYS_GETPID
+    int 0x80
+    cmp rax, r12
+    jne failed
+    dec r13
+    jnz 1b
+
+    mov eax, SYS_GETPPID
+    int 0x80
+    test rax, rax
+    jnz failed
+    mov rbx, r12
+    jmp exit
+
+failed:
+    mov rbx, -1
+exit:
+    mov eax, SYS_EXIT
+    int 0x80
+    ud2
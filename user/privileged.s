@@ -0,0 +1,9 @@
+# Tries to halt the CPU, which ring 3 may not, and should only end itself.
+
+    .intel_syntax noprefix
+
+    .text
+    .global _start
+_start:
+    hlt
+    ud2
@@ -0,0 +1,39 @@
+# Reads a line from the console and prints it back. Exits with the count
+# read returned, the negated errno if it failed.
+
+    .intel_syntax noprefix
+
+    .equ SYS_READ, 0
+    .equ SYS_WRITE, 1
+    .equ SYS_EXIT, 60
+    .equ STDIN, 0
+    .equ STDOUT, 1
+    .equ SIZE, 64
+
+    .text
+    .global _start
+_start:
+    mov eax, SYS_READ
+    mov ebx, STDIN
+    lea rcx, [rip + line]
+    mov edx, SIZE
+    int 0x80
+    mov r12, rax
+    test rax, rax
+    jle exit
+
+    mov eax, SYS_WRITE
+    mov ebx, STDOUT
+    lea rcx, [rip + line]
+    mov rdx, r12
+    int 0x80
+
+exit:
+    mov rbx, r12
+    mov eax, SYS_EXIT
+    int 0x80
+    ud2
+
+    .bss
+line:
+    .skip SIZE
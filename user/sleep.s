@@ -0,0 +1,45 @@
+# Prints, sleeps 50 ms with nanosleep, then prints again. Exits with what
+# nanosleep returned, 0 unless it failed.
+
+    .intel_syntax noprefix
+
+    .equ SYS_WRITE, 1
+    .equ SYS_NANOSLEEP, 35
+    .equ SYS_EXIT, 60
+
+    .text
+    .global _start
+_start:
+    mov eax, SYS_WRITE
+    mov ebx, 1
+    lea rcx, [rip + before]
+    mov edx, before_len
+    int 0x80
+
+    mov eax, SYS_NANOSLEEP
+    lea rbx, [rip + duration]
+    xor ecx, ecx
+    int 0x80
+    mov r12, rax
+
+    mov eax, SYS_WRITE
+    mov ebx, 1
+    lea rcx, [rip + after]
+    mov edx, after_len
+    int 0x80
+
+    mov rbx, r12
+    mov eax, SYS_EXIT
+    int 0x80
+    ud2
+
+    .section .rodata
+    .balign 8
+duration:
+    .quad 0, 50000000
+before:
+    .ascii "Going to sleep for 50 ms\n"
+    .equ before_len, . - before
+after:
+    .ascii "Awake again\n"
+    .equ after_len, . - after